@@ -2,12 +2,19 @@
 
 pub mod assistant_panel;
 pub mod assistant_settings;
+mod code_blocks;
 mod context;
+mod context_export;
 pub mod context_store;
 mod inline_assistant;
+pub mod markdown_stream;
 mod model_selector;
+mod parse_edit_stream;
 mod prompt_library;
+mod prompt_template;
 mod prompts;
+mod request_preview;
+mod request_truncation;
 mod slash_command;
 pub(crate) mod slash_command_picker;
 pub mod slash_command_settings;
@@ -21,8 +28,10 @@ use assistant_settings::AssistantSettings;
 use assistant_slash_command::SlashCommandRegistry;
 use assistant_tool::ToolRegistry;
 use client::{proto, Client};
+pub use code_blocks::{CodeBlockParser, ResponseCodeBlock};
 use command_palette_hooks::CommandPaletteFilter;
 pub use context::*;
+pub use context_export::{ExportedContext, ExportedMessage};
 use context_servers::ContextServerRegistry;
 pub use context_store::*;
 use feature_flags::FeatureFlagAppExt;
@@ -32,11 +41,16 @@ use gpui::{impl_actions, Context as _};
 use indexed_docs::IndexedDocsRegistry;
 pub(crate) use inline_assistant::*;
 use language_model::{
-    LanguageModelId, LanguageModelProviderId, LanguageModelRegistry, LanguageModelResponseMessage,
+    DebugLogSettings, LanguageModelId, LanguageModelProviderId, LanguageModelRegistry,
+    LanguageModelResponseMessage,
 };
 pub(crate) use model_selector::*;
+pub(crate) use parse_edit_stream::*;
+pub use prompt_template::{PromptContext, PromptTemplate, RenderedPrompt};
 pub use prompts::PromptBuilder;
 use prompts::PromptLoadingParams;
+pub use request_preview::{build_request_preview, RenderedRequest};
+pub use request_truncation::{fit_request_to_model, FittedRequest, MessageTooLarge};
 use semantic_index::{CloudEmbeddingProvider, SemanticDb};
 use serde::{Deserialize, Serialize};
 use settings::{update_settings_file, Settings, SettingsStore};
@@ -60,15 +74,21 @@ actions!(
         Assist,
         Split,
         CopyCode,
+        CopyCodeBlockAtCursor,
         CycleMessageRole,
         QuoteSelection,
         InsertIntoEditor,
+        InsertCodeBlockIntoEditor,
         ToggleFocus,
         InsertActivePrompt,
         DeployHistory,
         DeployPromptLibrary,
         ConfirmCommand,
         NewContext,
+        ExportContext,
+        ImportContext,
+        PreviewRequest,
+        RegenerateContextTitle,
         ToggleModelSelector,
         CycleNextInlineAssist,
         CyclePreviousInlineAssist
@@ -228,7 +248,7 @@ pub fn init(
 
     context_store::init(&client.clone().into());
     prompt_library::init(cx);
-    init_language_model_settings(cx);
+    init_language_model_settings(fs.clone(), cx);
     assistant_slash_command::init(cx);
     assistant_tool::init(cx);
     assistant_panel::init(cx);
@@ -339,8 +359,11 @@ fn register_context_server_handlers(cx: &mut AppContext) {
     .detach();
 }
 
-fn init_language_model_settings(cx: &mut AppContext) {
+fn init_language_model_settings(fs: Arc<dyn Fs>, cx: &mut AppContext) {
     update_active_language_model_from_settings(cx);
+    update_debug_log_from_settings(fs.clone(), cx);
+    cx.observe_global::<SettingsStore>(move |cx| update_debug_log_from_settings(fs.clone(), cx))
+        .detach();
 
     cx.observe_global::<SettingsStore>(update_active_language_model_from_settings)
         .detach();
@@ -372,9 +395,27 @@ fn update_active_language_model_from_settings(cx: &mut AppContext) {
             )
         })
         .collect::<Vec<_>>();
+    let fallback_providers = settings
+        .fallback_providers
+        .iter()
+        .map(|provider| LanguageModelProviderId::from(provider.clone()))
+        .collect();
     LanguageModelRegistry::global(cx).update(cx, |registry, cx| {
         registry.select_active_model(&provider_name, &model_id, cx);
         registry.select_inline_alternative_models(inline_alternatives, cx);
+        registry.set_fallback_providers(fallback_providers);
+    });
+}
+
+fn update_debug_log_from_settings(fs: Arc<dyn Fs>, cx: &mut AppContext) {
+    let settings = &AssistantSettings::get_global(cx).debug_logging;
+    let debug_log_settings = settings.enabled.then(|| DebugLogSettings {
+        log_message_content: settings.log_message_content,
+        max_file_size: settings.max_file_size_in_kb * 1024,
+        max_files: settings.max_files,
+    });
+    LanguageModelRegistry::global(cx).update(cx, |registry, _| {
+        registry.set_debug_log(fs, debug_log_settings);
     });
 }
 
@@ -1,6 +1,6 @@
 use crate::{
     assistant_settings::{AssistantDockPosition, AssistantSettings},
-    humanize_token_count,
+    build_request_preview, humanize_token_count,
     prompt_library::open_prompt_library,
     prompts::PromptBuilder,
     slash_command::{
@@ -12,11 +12,14 @@ use crate::{
     slash_command_picker,
     terminal_inline_assistant::TerminalInlineAssistant,
     Assist, CacheStatus, ConfirmCommand, Content, Context, ContextEvent, ContextId, ContextStore,
-    ContextStoreEvent, CopyCode, CycleMessageRole, DeployHistory, DeployPromptLibrary,
-    InlineAssistId, InlineAssistant, InsertDraggedFiles, InsertIntoEditor, Message, MessageId,
-    MessageMetadata, MessageStatus, ModelPickerDelegate, ModelSelector, NewContext,
-    PendingSlashCommand, PendingSlashCommandStatus, QuoteSelection, RemoteContextMetadata,
-    SavedContextMetadata, Split, ToggleFocus, ToggleModelSelector, WorkflowStepResolution,
+    ContextStoreEvent, CopyCode, CopyCodeBlockAtCursor, CycleMessageRole, DeployHistory,
+    DeployPromptLibrary, ExportContext, ExportedContext, ImportContext, InlineAssistId,
+    InlineAssistant, InsertCodeBlockIntoEditor, InsertDraggedFiles, InsertIntoEditor, Message,
+    MessageId, MessageMetadata, MessageStatus, ModelPickerDelegate, ModelSelector, NewContext,
+    PendingSlashCommand, PendingSlashCommandStatus, PendingToolUse, PreviewRequest, PromptContext,
+    QuoteSelection, RegenerateContextTitle, RemoteContextMetadata, ResponseCodeBlock,
+    SavedContextMetadata, Split, ToggleFocus, ToggleModelSelector, ToolUseConfirmation,
+    WorkflowStepResolution,
 };
 use anyhow::{anyhow, Result};
 use assistant_slash_command::{SlashCommand, SlashCommandOutputSection};
@@ -39,20 +42,20 @@ use gpui::{
     canvas, div, img, percentage, point, pulsating_between, size, Action, Animation, AnimationExt,
     AnyElement, AnyView, AppContext, AsyncWindowContext, ClipboardEntry, ClipboardItem,
     Context as _, Empty, Entity, EntityId, EventEmitter, ExternalPaths, FocusHandle, FocusableView,
-    FontWeight, InteractiveElement, IntoElement, Model, ParentElement, Pixels, ReadGlobal, Render,
-    RenderImage, SharedString, Size, StatefulInteractiveElement, Styled, Subscription, Task,
-    Transformation, UpdateGlobal, View, VisualContext, WeakView, WindowContext,
+    FontWeight, InteractiveElement, IntoElement, Model, ParentElement, PathPromptOptions, Pixels,
+    ReadGlobal, Render, RenderImage, SharedString, Size, StatefulInteractiveElement, Styled,
+    Subscription, Task, Transformation, UpdateGlobal, View, VisualContext, WeakView, WindowContext,
 };
 use indexed_docs::IndexedDocsStore;
 use language::{
-    language_settings::SoftWrap, BufferSnapshot, Capability, LanguageRegistry, LspAdapterDelegate,
-    ToOffset,
+    language_settings::SoftWrap, Buffer, BufferSnapshot, Capability, LanguageRegistry,
+    LspAdapterDelegate, ToOffset,
 };
 use language_model::{
-    provider::cloud::PROVIDER_ID, LanguageModelProvider, LanguageModelProviderId,
-    LanguageModelRegistry, Role,
+    provider::cloud::PROVIDER_ID, LanguageModelImage, LanguageModelProvider,
+    LanguageModelProviderId, LanguageModelRegistry, ProviderStatus, RequestMetrics, Role,
+    StopReason,
 };
-use language_model::{LanguageModelImage, LanguageModelToolUse};
 use multi_buffer::MultiBufferRow;
 use picker::{Picker, PickerDelegate};
 use project::lsp_store::LocalLspAdapterDelegate;
@@ -76,14 +79,14 @@ use ui::TintColor;
 use ui::{
     prelude::*,
     utils::{format_distance_from_now, DateTimeType},
-    Avatar, AvatarShape, ButtonLike, ContextMenu, Disclosure, ElevationIndex, KeyBinding, ListItem,
-    ListItemSpacing, PopoverMenu, PopoverMenuHandle, Tooltip,
+    Avatar, AvatarShape, ButtonLike, ContextMenu, Disclosure, ElevationIndex, Indicator,
+    KeyBinding, ListItem, ListItemSpacing, PopoverMenu, PopoverMenuHandle, Tooltip,
 };
 use util::{maybe, ResultExt};
 use workspace::{
     dock::{DockPosition, Panel, PanelEvent},
     item::{self, FollowableItem, Item, ItemHandle},
-    notifications::NotificationId,
+    notifications::{DetachAndPromptErr, NotificationId},
     pane::{self, SaveIntent},
     searchable::{SearchEvent, SearchableItem},
     DraggedSelection, Pane, Save, ShowConfiguration, Toast, ToggleZoom, ToolbarItemEvent,
@@ -109,9 +112,14 @@ pub fn init(cx: &mut AppContext) {
                 .register_action(ContextEditor::quote_selection)
                 .register_action(ContextEditor::insert_selection)
                 .register_action(ContextEditor::copy_code)
+                .register_action(ContextEditor::copy_code_block_at_cursor)
+                .register_action(ContextEditor::insert_code_block_into_editor)
                 .register_action(ContextEditor::insert_dragged_files)
                 .register_action(AssistantPanel::show_configuration)
-                .register_action(AssistantPanel::create_new_context);
+                .register_action(AssistantPanel::create_new_context)
+                .register_action(AssistantPanel::export_context)
+                .register_action(AssistantPanel::import_context)
+                .register_action(AssistantPanel::preview_request);
         },
     )
     .detach();
@@ -509,6 +517,7 @@ impl AssistantPanel {
                         this.ensure_authenticated(cx);
                         cx.notify()
                     }
+                    language_model::Event::ProviderStatusChanged => cx.notify(),
                     language_model::Event::AddedProvider(_)
                     | language_model::Event::RemovedProvider(_) => {
                         this.ensure_authenticated(cx);
@@ -959,6 +968,127 @@ impl AssistantPanel {
         }
     }
 
+    /// Exports the active context to a file of the user's choosing, as a Markdown transcript
+    /// for `.md` files and as importable JSON otherwise.
+    fn export_context(
+        workspace: &mut Workspace,
+        _: &ExportContext,
+        cx: &mut ViewContext<Workspace>,
+    ) {
+        let Some(context) = workspace
+            .panel::<AssistantPanel>(cx)
+            .and_then(|panel| panel.read(cx).active_context(cx))
+        else {
+            return;
+        };
+        let exported_context = context.read(cx).export(cx);
+        let fs = workspace.app_state().fs.clone();
+        let path = cx.prompt_for_new_path(paths::home_dir());
+        cx.spawn(|_, _| async move {
+            let Some(path) = path.await?? else {
+                return Ok(());
+            };
+            let contents = if path
+                .extension()
+                .map_or(false, |extension| extension == "md")
+            {
+                exported_context.to_markdown()
+            } else {
+                exported_context.to_json()?
+            };
+            fs.atomic_write(path, contents).await
+        })
+        .detach_and_prompt_err("Failed to export context", cx, |_, _| None);
+    }
+
+    fn import_context(
+        workspace: &mut Workspace,
+        _: &ImportContext,
+        cx: &mut ViewContext<Workspace>,
+    ) {
+        let Some(panel) = workspace.panel::<AssistantPanel>(cx) else {
+            return;
+        };
+        let fs = workspace.app_state().fs.clone();
+        let paths = cx.prompt_for_paths(PathPromptOptions {
+            files: true,
+            directories: false,
+            multiple: false,
+        });
+        cx.spawn(|workspace, mut cx| async move {
+            let Some(path) = paths.await??.and_then(|paths| paths.into_iter().next()) else {
+                return Ok(());
+            };
+            let exported_context = ExportedContext::from_json(&fs.load(&path).await?)?;
+            panel.update(&mut cx, |panel, cx| {
+                panel.open_exported_context(exported_context, cx)
+            })?;
+            workspace.update(&mut cx, |workspace, cx| {
+                workspace.focus_panel::<AssistantPanel>(cx);
+            })
+        })
+        .detach_and_prompt_err("Failed to import context", cx, |_, _| None);
+    }
+
+    /// Opens the request that assisting in the active context would send in a new editor, so it
+    /// can be inspected before it's sent.
+    fn preview_request(
+        workspace: &mut Workspace,
+        _: &PreviewRequest,
+        cx: &mut ViewContext<Workspace>,
+    ) {
+        let Some(context) = workspace
+            .panel::<AssistantPanel>(cx)
+            .and_then(|panel| panel.read(cx).active_context(cx))
+        else {
+            return;
+        };
+        let rendered_request = build_request_preview(&context, cx);
+        let markdown = workspace
+            .app_state()
+            .languages
+            .language_for_name("Markdown");
+        cx.spawn(|workspace, mut cx| async move {
+            let text = rendered_request.await?.to_markdown();
+            let markdown = markdown.await.log_err();
+            workspace.update(&mut cx, |workspace, cx| {
+                let buffer = cx.new_model(|cx| {
+                    let mut buffer = Buffer::local(text, cx);
+                    buffer.set_language(markdown, cx);
+                    buffer
+                });
+                let editor = cx.new_view(|cx| Editor::for_buffer(buffer, None, cx));
+                workspace.add_item_to_active_pane(Box::new(editor), None, true, cx);
+            })
+        })
+        .detach_and_prompt_err("Failed to preview request", cx, |_, _| None);
+    }
+
+    /// Opens an exported context as a new context, leaving the active one untouched.
+    fn open_exported_context(
+        &mut self,
+        exported_context: ExportedContext,
+        cx: &mut ViewContext<Self>,
+    ) {
+        let context = self
+            .context_store
+            .update(cx, |store, cx| store.import(exported_context, cx));
+        let lsp_adapter_delegate = make_lsp_adapter_delegate(&self.project, cx).log_err();
+        let assistant_panel = cx.view().downgrade();
+        let editor = cx.new_view(|cx| {
+            ContextEditor::for_context(
+                context,
+                self.fs.clone(),
+                self.workspace.clone(),
+                self.project.clone(),
+                lsp_adapter_delegate,
+                assistant_panel,
+                cx,
+            )
+        });
+        self.show_context(editor, cx);
+    }
+
     fn new_context(&mut self, cx: &mut ViewContext<Self>) -> Option<View<ContextEditor>> {
         let project = self.project.read(cx);
         if project.is_via_collab() && project.dev_server_project_id().is_none() {
@@ -1760,7 +1890,16 @@ impl ContextEditor {
     }
 
     fn send_to_model(&mut self, cx: &mut ViewContext<Self>) {
-        if let Some(user_message) = self.context.update(cx, |context, cx| context.assist(cx)) {
+        let prompt_context = self
+            .workspace
+            .upgrade()
+            .and_then(|workspace| workspace.read(cx).active_item_as::<Editor>(cx))
+            .map(|editor| PromptContext::for_editor(editor.read(cx), cx))
+            .unwrap_or_default();
+        if let Some(user_message) = self.context.update(cx, |context, cx| {
+            context.set_prompt_context(prompt_context);
+            context.assist(cx)
+        }) {
             let new_selection = {
                 let cursor = user_message
                     .start
@@ -1939,6 +2078,33 @@ impl ContextEditor {
         }
     }
 
+    fn run_tool_uses(&mut self, tool_uses: Vec<PendingToolUse>, cx: &mut ViewContext<Self>) {
+        let tool_registry = ToolRegistry::global(cx);
+        for tool_use in tool_uses {
+            if let Some(tool) = tool_registry.tool(&tool_use.name) {
+                let task = tool.run(tool_use.input, self.workspace.clone(), cx);
+
+                self.context.update(cx, |context, cx| {
+                    context.insert_tool_output(tool_use.id.clone(), task, cx);
+                });
+            }
+        }
+        // Tool uses' confirmation buttons are rendered by the editor.
+        self.editor.update(cx, |_, cx| cx.notify());
+    }
+
+    fn confirm_tool_use(
+        &mut self,
+        tool_use_id: &Arc<str>,
+        confirmation: ToolUseConfirmation,
+        cx: &mut ViewContext<Self>,
+    ) {
+        let tool_uses = self.context.update(cx, |context, cx| {
+            context.confirm_tool_use(tool_use_id, confirmation, cx)
+        });
+        self.run_tool_uses(tool_uses, cx);
+    }
+
     fn handle_context_event(
         &mut self,
         _: Model<Context>,
@@ -1961,6 +2127,11 @@ impl ContextEditor {
                     context.save(Some(Duration::from_millis(500)), self.fs.clone(), cx);
                 });
             }
+            ContextEvent::SamplingOverridesChanged => {
+                self.context.update(cx, |context, cx| {
+                    context.save(Some(Duration::from_millis(500)), self.fs.clone(), cx);
+                });
+            }
             ContextEvent::StreamedCompletion => {
                 self.editor.update(cx, |editor, cx| {
                     if let Some(scroll_position) = self.scroll_position {
@@ -2005,8 +2176,20 @@ impl ContextEditor {
                                 constrain_width: false,
                                 merge_adjacent: false,
                             };
-                            let render_trailer =
-                                move |_row, _unfold, _cx: &mut WindowContext| Empty.into_any();
+                            let render_trailer = {
+                                let context = self.context.clone();
+                                let context_editor = context_editor.clone();
+                                let tool_use_id = tool_use.id.clone();
+                                move |row, _unfold, cx: &mut WindowContext| {
+                                    render_tool_use_confirmation_trailer(
+                                        row,
+                                        &context,
+                                        &context_editor,
+                                        &tool_use_id,
+                                        cx,
+                                    )
+                                }
+                            };
 
                             let start = buffer
                                 .anchor_in_excerpt(excerpt_id, tool_use.source_range.start)
@@ -2018,20 +2201,6 @@ impl ContextEditor {
                             let buffer_row = MultiBufferRow(start.to_point(&buffer).row);
                             buffer_rows_to_fold.insert(buffer_row);
 
-                            self.context.update(cx, |context, cx| {
-                                context.insert_content(
-                                    Content::ToolUse {
-                                        range: tool_use.source_range.clone(),
-                                        tool_use: LanguageModelToolUse {
-                                            id: tool_use.id.to_string(),
-                                            name: tool_use.name.clone(),
-                                            input: tool_use.input.clone(),
-                                        },
-                                    },
-                                    cx,
-                                );
-                            });
-
                             Crease::new(
                                 start..end,
                                 placeholder,
@@ -2222,25 +2391,10 @@ impl ContextEditor {
                 }
             }
             ContextEvent::UsePendingTools => {
-                let pending_tool_uses = self
+                let tool_uses = self
                     .context
-                    .read(cx)
-                    .pending_tool_uses()
-                    .into_iter()
-                    .filter(|tool_use| tool_use.status.is_idle())
-                    .cloned()
-                    .collect::<Vec<_>>();
-
-                for tool_use in pending_tool_uses {
-                    let tool_registry = ToolRegistry::global(cx);
-                    if let Some(tool) = tool_registry.tool(&tool_use.name) {
-                        let task = tool.run(tool_use.input, self.workspace.clone(), cx);
-
-                        self.context.update(cx, |context, cx| {
-                            context.insert_tool_output(tool_use.id.clone(), task, cx);
-                        });
-                    }
-                }
+                    .update(cx, |context, cx| context.take_tool_uses_to_run(cx));
+                self.run_tool_uses(tool_uses, cx);
             }
             ContextEvent::ToolFinished {
                 tool_use_id,
@@ -2972,6 +3126,69 @@ impl ContextEditor {
                             .relative()
                             .gap_1()
                             .child(sender)
+                            .children(context.read(cx).served_by(message_id).map(|provider_name| {
+                                Label::new(format!("via {provider_name}"))
+                                    .size(LabelSize::Small)
+                                    .color(Color::Muted)
+                            }))
+                            .children(message.metrics.map(|metrics| {
+                                div()
+                                    .id("request-metrics")
+                                    .child(
+                                        Label::new(format_request_metrics(&metrics))
+                                            .size(LabelSize::Small)
+                                            .color(Color::Muted),
+                                    )
+                                    .tooltip(move |cx| {
+                                        Tooltip::with_meta(
+                                            "Response time",
+                                            None,
+                                            describe_request_metrics(&metrics),
+                                            cx,
+                                        )
+                                    })
+                            }))
+                            .when(context.read(cx).is_incomplete(message_id), |this| {
+                                this.child(
+                                    Label::new("response may be incomplete")
+                                        .size(LabelSize::Small)
+                                        .color(Color::Warning),
+                                )
+                            })
+                            .children(match context.read(cx).stop_reason(message_id) {
+                                Some(StopReason::MaxTokens) => Some(
+                                    Label::new("response reached the output limit")
+                                        .size(LabelSize::Small)
+                                        .color(Color::Warning)
+                                        .into_any_element(),
+                                ),
+                                Some(StopReason::ContentFilter) => Some(
+                                    Label::new("response was stopped by a content filter")
+                                        .size(LabelSize::Small)
+                                        .color(Color::Warning)
+                                        .into_any_element(),
+                                ),
+                                _ => None,
+                            })
+                            .when(
+                                context.read(cx).can_continue_message(message_id, cx),
+                                |this| {
+                                    this.child(
+                                        Button::new("continue-response", "Continue")
+                                            .label_size(LabelSize::Small)
+                                            .icon(IconName::Play)
+                                            .icon_size(IconSize::Small)
+                                            .on_click({
+                                                let context = context.clone();
+                                                move |_, cx| {
+                                                    context.update(cx, |context, cx| {
+                                                        context.continue_message(message_id, cx);
+                                                    });
+                                                }
+                                            }),
+                                    )
+                                },
+                            )
                             .children(match &message.cache {
                                 Some(cache) if cache.is_final_anchor => match cache.status {
                                     CacheStatus::Cached => Some(
@@ -3203,6 +3420,72 @@ impl ContextEditor {
         );
     }
 
+    /// Returns the code block around the cursor of the active context editor, which may still
+    /// be streaming in.
+    fn code_block_at_cursor(
+        workspace: &Workspace,
+        cx: &mut ViewContext<Workspace>,
+    ) -> Option<ResponseCodeBlock> {
+        let panel = workspace.panel::<AssistantPanel>(cx)?;
+        let context_editor = panel.read(cx).active_context_editor(cx)?;
+        let context_editor = context_editor.read(cx);
+        let offset = context_editor
+            .editor
+            .read(cx)
+            .selections
+            .newest::<usize>(cx)
+            .head();
+        context_editor
+            .context
+            .read(cx)
+            .code_block_for_offset(offset, cx)
+    }
+
+    fn copy_code_block_at_cursor(
+        workspace: &mut Workspace,
+        _: &CopyCodeBlockAtCursor,
+        cx: &mut ViewContext<Workspace>,
+    ) {
+        let Some(code_block) = Self::code_block_at_cursor(workspace, cx) else {
+            return;
+        };
+
+        cx.write_to_clipboard(ClipboardItem::new_string(code_block.content));
+
+        struct CopyCodeBlockToast;
+        workspace.show_toast(
+            Toast::new(
+                NotificationId::unique::<CopyCodeBlockToast>(),
+                "Code block copied to clipboard.",
+            )
+            .autohide(),
+            cx,
+        );
+    }
+
+    /// Inserts the code block around the cursor at the active editor's cursors, replacing their
+    /// selections, as a single transaction.
+    fn insert_code_block_into_editor(
+        workspace: &mut Workspace,
+        _: &InsertCodeBlockIntoEditor,
+        cx: &mut ViewContext<Workspace>,
+    ) {
+        let Some(code_block) = Self::code_block_at_cursor(workspace, cx) else {
+            return;
+        };
+        let Some(active_editor_view) = workspace
+            .active_item(cx)
+            .and_then(|item| item.act_as::<Editor>(cx))
+        else {
+            return;
+        };
+
+        active_editor_view.update(cx, |editor, cx| {
+            editor.transact(cx, |editor, cx| editor.insert(&code_block.content, cx));
+            editor.focus(cx);
+        });
+    }
+
     fn insert_dragged_files(
         workspace: &mut Workspace,
         action: &InsertDraggedFiles,
@@ -3718,6 +4001,11 @@ impl ContextEditor {
         });
     }
 
+    fn regenerate_title(&mut self, _: &RegenerateContextTitle, cx: &mut ViewContext<Self>) {
+        self.context
+            .update(cx, |context, cx| context.summarize(true, cx));
+    }
+
     fn split(&mut self, _: &Split, cx: &mut ViewContext<Self>) {
         self.context.update(cx, |context, cx| {
             let selections = self.editor.read(cx).selections.disjoint_anchors();
@@ -4395,6 +4683,7 @@ impl Render for ContextEditor {
             .capture_action(cx.listener(ContextEditor::confirm_command))
             .on_action(cx.listener(ContextEditor::assist))
             .on_action(cx.listener(ContextEditor::split))
+            .on_action(cx.listener(ContextEditor::regenerate_title))
             .size_full()
             .children(self.render_notice(cx))
             .child(
@@ -4818,9 +5107,21 @@ impl ContextEditorToolbarItem {
                 (color, token_count, max_token_count)
             }
         };
+        let omitted_message_count = context.read(cx).omitted_message_count();
+        let omitted_messages = (omitted_message_count > 0).then(|| {
+            let noun = if omitted_message_count == 1 {
+                "message"
+            } else {
+                "messages"
+            };
+            Label::new(format!("{omitted_message_count} earlier {noun} omitted"))
+                .size(LabelSize::Small)
+                .color(Color::Muted)
+        });
         Some(
             h_flex()
                 .gap_0p5()
+                .children(omitted_messages)
                 .child(
                     Label::new(humanize_token_count(token_count))
                         .size(LabelSize::Small)
@@ -4836,6 +5137,53 @@ impl ContextEditorToolbarItem {
     }
 }
 
+/// Renders the tokens used by completions since startup, with a breakdown per model in its
+/// tooltip.
+fn render_usage_since_startup(cx: &AppContext) -> Option<impl IntoElement> {
+    let usage = LanguageModelRegistry::read_global(cx).usage_since_startup();
+    if usage.total.total_tokens() == 0 {
+        return None;
+    }
+
+    let mut breakdown = "Tokens used since startup".to_string();
+    for (model_id, usage) in &usage.by_model {
+        breakdown.push_str(&format!(
+            "\n{}: {} prompt, {} completion",
+            model_id.0,
+            humanize_token_count(usage.prompt_tokens as usize),
+            humanize_token_count(usage.completion_tokens as usize),
+        ));
+    }
+    let breakdown = SharedString::from(breakdown);
+    Some(
+        div()
+            .id("usage-since-startup")
+            .child(
+                Label::new(format!(
+                    "{} used",
+                    humanize_token_count(usage.total.total_tokens() as usize)
+                ))
+                .size(LabelSize::Small)
+                .color(Color::Muted),
+            )
+            .tooltip(move |cx| Tooltip::text(breakdown.clone(), cx)),
+    )
+}
+
+fn render_provider_status(status: ProviderStatus) -> impl IntoElement {
+    let (color, description): (Color, SharedString) = match status {
+        ProviderStatus::Checking => (Color::Muted, "Checking provider…".into()),
+        ProviderStatus::Ready => (Color::Success, "Provider is ready".into()),
+        ProviderStatus::Unauthenticated => (Color::Warning, "Provider is not authenticated".into()),
+        ProviderStatus::Error(message) => (Color::Error, message),
+        ProviderStatus::Queued => (Color::Warning, "Waiting for connection…".into()),
+    };
+    div()
+        .id("provider-status")
+        .child(Indicator::dot().color(color))
+        .tooltip(move |cx| Tooltip::text(description.clone(), cx))
+}
+
 impl Render for ContextEditorToolbarItem {
     fn render(&mut self, cx: &mut ViewContext<Self>) -> impl IntoElement {
         let left_side = h_flex()
@@ -4848,6 +5196,9 @@ impl Render for ContextEditorToolbarItem {
             });
         let active_provider = LanguageModelRegistry::read_global(cx).active_provider();
         let active_model = LanguageModelRegistry::read_global(cx).active_model();
+        let provider_status = LanguageModelRegistry::read_global(cx)
+            .provider_status()
+            .clone();
         let weak_self = cx.view().downgrade();
         let right_side = h_flex()
             .gap_2()
@@ -4892,6 +5243,7 @@ impl Render for ContextEditorToolbarItem {
                                                         .size(LabelSize::Small)
                                                         .color(Color::Muted),
                                                 )
+                                                .child(render_provider_status(provider_status))
                                                 .into_any_element(),
                                             _ => Label::new("No model selected")
                                                 .size(LabelSize::Small)
@@ -4911,6 +5263,7 @@ impl Render for ContextEditorToolbarItem {
                 )
                 .with_handle(self.model_selector_menu_handle.clone()),
             )
+            .children(render_usage_since_startup(cx))
             .children(self.render_remaining_tokens(cx))
             .child(
                 PopoverMenu::new("context-editor-popover")
@@ -5422,6 +5775,50 @@ fn render_pending_slash_command_gutter_decoration(
     icon.into_any_element()
 }
 
+fn render_tool_use_confirmation_trailer(
+    row: MultiBufferRow,
+    context: &Model<Context>,
+    context_editor: &WeakView<ContextEditor>,
+    tool_use_id: &Arc<str>,
+    cx: &mut WindowContext,
+) -> AnyElement {
+    let awaiting_confirmation = context
+        .read(cx)
+        .get_tool_use_by_id(tool_use_id)
+        .map_or(false, |tool_use| tool_use.status.is_awaiting_confirmation());
+    if !awaiting_confirmation {
+        return Empty.into_any();
+    }
+
+    let button = |id: &'static str, label: &'static str, confirmation: ToolUseConfirmation| {
+        let context_editor = context_editor.clone();
+        let tool_use_id = tool_use_id.clone();
+        Button::new((id, row.0), label)
+            .label_size(LabelSize::Small)
+            .on_click(move |_, cx| {
+                context_editor
+                    .update(cx, |context_editor, cx| {
+                        context_editor.confirm_tool_use(&tool_use_id, confirmation, cx)
+                    })
+                    .ok();
+            })
+    };
+    h_flex()
+        .gap_1()
+        .child(button(
+            "allow-tool-use",
+            "Allow",
+            ToolUseConfirmation::Allow,
+        ))
+        .child(button(
+            "always-allow-tool-use",
+            "Always Allow",
+            ToolUseConfirmation::AlwaysAllow,
+        ))
+        .child(button("deny-tool-use", "Deny", ToolUseConfirmation::Deny))
+        .into_any_element()
+}
+
 fn render_docs_slash_command_trailer(
     row: MultiBufferRow,
     command: PendingSlashCommand,
@@ -5537,8 +5934,9 @@ enum TokenState {
 fn token_state(context: &Model<Context>, cx: &AppContext) -> Option<TokenState> {
     const WARNING_TOKEN_THRESHOLD: f32 = 0.8;
 
-    let model = LanguageModelRegistry::read_global(cx).active_model()?;
-    let token_count = context.read(cx).token_count()?;
+    let context = context.read(cx);
+    let model = context.model(cx)?;
+    let token_count = context.token_count()?;
     let max_token_count = model.max_token_count();
 
     let remaining_tokens = max_token_count as isize - token_count as isize;
@@ -5559,6 +5957,35 @@ fn token_state(context: &Model<Context>, cx: &AppContext) -> Option<TokenState>
     Some(token_state)
 }
 
+/// Summarizes a completion's metrics as e.g. "2.3s • 412 tokens".
+fn format_request_metrics(metrics: &RequestMetrics) -> String {
+    let duration = format!("{:.1}s", metrics.total_duration.as_secs_f32());
+    match metrics.output_tokens {
+        Some(tokens) => format!("{duration} • {tokens} tokens"),
+        None => duration,
+    }
+}
+
+fn describe_request_metrics(metrics: &RequestMetrics) -> String {
+    let mut lines = Vec::new();
+    if let Some(queue_time) = metrics.queue_time {
+        lines.push(format!("Queued for {}ms", queue_time.as_millis()));
+    }
+    if let Some(time_to_first_chunk) = metrics.time_to_first_chunk {
+        lines.push(format!(
+            "First chunk after {}ms",
+            time_to_first_chunk.as_millis()
+        ));
+    }
+    lines.push(format!(
+        "{} chunks, {} bytes in {}ms",
+        metrics.chunk_count,
+        metrics.bytes_received,
+        metrics.total_duration.as_millis()
+    ));
+    lines.join("\n")
+}
+
 fn size_for_image(data: &RenderImage, max_size: Size<Pixels>) -> Size<Pixels> {
     let image_size = data
         .size(0)
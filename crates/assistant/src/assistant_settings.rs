@@ -1,20 +1,25 @@
-use std::sync::Arc;
+use std::{path::Path, sync::Arc};
 
 use ::open_ai::Model as OpenAiModel;
 use anthropic::Model as AnthropicModel;
+use collections::BTreeMap;
 use fs::Fs;
-use gpui::{AppContext, Pixels};
+use gpui::{AppContext, Model, Pixels};
 use language_model::provider::open_ai;
 use language_model::settings::{
     AnthropicSettingsContent, AnthropicSettingsContentV1, OllamaSettingsContent,
     OpenAiSettingsContent, OpenAiSettingsContentV1, VersionedAnthropicSettingsContent,
     VersionedOpenAiSettingsContent,
 };
-use language_model::{settings::AllLanguageModelSettings, CloudModel, LanguageModel};
+use language_model::{
+    settings::AllLanguageModelSettings, CloudModel, LanguageModel, LanguageModelId,
+    LanguageModelProviderId, LanguageModelRegistry, LanguageModelRequest,
+};
 use ollama::Model as OllamaModel;
+use project::Project;
 use schemars::{schema::Schema, JsonSchema};
 use serde::{Deserialize, Serialize};
-use settings::{update_settings_file, Settings, SettingsSources};
+use settings::{update_settings_file, Settings, SettingsLocation, SettingsSources};
 
 #[derive(Copy, Clone, Default, Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
@@ -25,6 +30,105 @@ pub enum AssistantDockPosition {
     Bottom,
 }
 
+/// What to do when a context no longer fits in the model's context window.
+#[derive(Copy, Clone, Default, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextOverflowStrategy {
+    /// Omit the oldest messages until the request fits.
+    #[default]
+    DropOldest,
+    /// Fail the request.
+    Error,
+}
+
+/// How the inline assistant applies the model's response when transforming a selection.
+#[derive(Copy, Clone, Default, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InlineAssistEditFormat {
+    /// Replace the selection with the response as it streams in.
+    #[default]
+    Rewrite,
+    /// Ask for search/replace blocks and apply each one as soon as it completes.
+    SearchReplace,
+}
+
+/// Logging of the requests sent to language models, for diagnosing the assistant's responses.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DebugLoggingSettings {
+    pub enabled: bool,
+    pub log_message_content: bool,
+    pub max_file_size_in_kb: usize,
+    pub max_files: usize,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+pub struct DebugLoggingSettingsContent {
+    /// Whether to log each request sent to a language model, along with its response, to
+    /// `assistant_requests.ndjson` in Zed's logs directory.
+    ///
+    /// Default: false
+    pub enabled: Option<bool>,
+    /// Whether to log the text of messages and responses, rather than just their length.
+    ///
+    /// Default: false
+    pub log_message_content: Option<bool>,
+    /// The size in kilobytes after which the log is rotated.
+    ///
+    /// Default: 10240
+    pub max_file_size_in_kb: Option<usize>,
+    /// How many log files to keep, including the current one.
+    ///
+    /// Default: 5
+    pub max_files: Option<usize>,
+}
+
+/// Parameters that control how the model samples its response. Parameters that are unset are
+/// left to the provider's defaults.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct SamplingParameters {
+    /// How random the response is, from 0 to 2. Anthropic models accept at most 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    /// Only sample from the smallest set of tokens whose probabilities add up to this, from 0
+    /// to 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    /// The maximum number of tokens in the response, which can't exceed the model's own limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    /// Sequences that end the response when the model generates them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+}
+
+impl SamplingParameters {
+    /// Returns these parameters, falling back to `defaults` for each one that's unset.
+    pub fn or(&self, defaults: &SamplingParameters) -> SamplingParameters {
+        SamplingParameters {
+            temperature: self.temperature.or(defaults.temperature),
+            top_p: self.top_p.or(defaults.top_p),
+            max_tokens: self.max_tokens.or(defaults.max_tokens),
+            stop: self.stop.clone().or_else(|| defaults.stop.clone()),
+        }
+    }
+
+    /// Sets the parameters that are set here on the request, leaving the others untouched.
+    pub fn apply_to(&self, request: &mut LanguageModelRequest) {
+        if let Some(temperature) = self.temperature {
+            request.temperature = Some(temperature);
+        }
+        if let Some(top_p) = self.top_p {
+            request.top_p = Some(top_p);
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            request.max_tokens = Some(max_tokens);
+        }
+        if let Some(stop) = &self.stop {
+            request.stop = stop.clone();
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
 #[serde(tag = "name", rename_all = "snake_case")]
 pub enum AssistantProviderContentV1 {
@@ -60,9 +164,58 @@ pub struct AssistantSettings {
     pub default_height: Pixels,
     pub default_model: LanguageModelSelection,
     pub inline_alternatives: Vec<LanguageModelSelection>,
+    pub fallback_providers: Vec<String>,
+    pub prompt_templates: BTreeMap<String, String>,
+    pub default_prompt_template: Option<String>,
+    pub context_overflow_strategy: ContextOverflowStrategy,
+    pub inline_assist_edit_format: InlineAssistEditFormat,
+    pub max_tool_use_rounds: usize,
+    pub debug_logging: DebugLoggingSettings,
+    pub sampling: SamplingParameters,
     pub using_outdated_settings_version: bool,
 }
 
+impl AssistantSettings {
+    /// Returns the settings that apply in the project, including the overrides in its workspace
+    /// settings, or the user's settings if there's no project.
+    pub fn for_project<'a>(project: Option<&Model<Project>>, cx: &'a AppContext) -> &'a Self {
+        let worktree_id = project.and_then(|project| {
+            let worktree = project.read(cx).visible_worktrees(cx).next()?;
+            Some(worktree.read(cx).id())
+        });
+        let location = worktree_id.map(|worktree_id| SettingsLocation {
+            worktree_id,
+            path: Path::new(""),
+        });
+        Self::get(location, cx)
+    }
+
+    /// Returns the model to assist with in the project: the default model from its workspace
+    /// settings if they override the user's, or the active model otherwise.
+    pub fn model_for_project(
+        project: Option<&Model<Project>>,
+        cx: &AppContext,
+    ) -> Option<Arc<dyn LanguageModel>> {
+        let model_registry = LanguageModelRegistry::read_global(cx);
+        let default_model = &Self::for_project(project, cx).default_model;
+        if *default_model == Self::get_global(cx).default_model {
+            return model_registry.active_model();
+        }
+
+        let provider_id = LanguageModelProviderId::from(default_model.provider.clone());
+        let model_id = LanguageModelId::from(default_model.model.clone());
+        let model = model_registry.model(&provider_id, &model_id, cx);
+        if model.is_none() {
+            log::warn!(
+                "workspace default model {}/{} is not available",
+                default_model.provider,
+                default_model.model
+            );
+        }
+        model.or_else(|| model_registry.active_model())
+    }
+}
+
 /// Assistant panel settings
 #[derive(Clone, Serialize, Deserialize, Debug)]
 #[serde(untagged)]
@@ -181,7 +334,14 @@ impl AssistantSettingsContent {
                                             OpenAiSettingsContentV1 {
                                                 api_url,
                                                 low_speed_timeout_in_seconds,
+                                                max_retries: None,
+                                                stream_batch_completions: None,
                                                 available_models,
+                                                provider_flavor: None,
+                                                organization_id: None,
+                                                extra_headers: None,
+                                                api_version: None,
+                                                api_key_path: None,
                                             },
                                         ),
                                     ));
@@ -238,6 +398,14 @@ impl AssistantSettingsContent {
                             }
                         }),
                     inline_alternatives: None,
+                    fallback_providers: None,
+                    prompt_templates: None,
+                    default_prompt_template: None,
+                    context_overflow_strategy: None,
+                    inline_assist_edit_format: None,
+                    max_tool_use_rounds: None,
+                    debug_logging: None,
+                    sampling: None,
                 },
                 VersionedAssistantSettingsContent::V2(settings) => settings.clone(),
             },
@@ -257,10 +425,100 @@ impl AssistantSettingsContent {
                         .to_string(),
                 }),
                 inline_alternatives: None,
+                fallback_providers: None,
+                prompt_templates: None,
+                default_prompt_template: None,
+                context_overflow_strategy: None,
+                inline_assist_edit_format: None,
+                max_tool_use_rounds: None,
+                debug_logging: None,
+                sampling: None,
             },
         }
     }
 
+    /// Returns the names of the fields that are set here but can only be set in user settings,
+    /// because they aren't safe for a workspace to override.
+    ///
+    /// Workspaces can override the default model, the default temperature and the prompt
+    /// templates. Settings in the V1 and legacy formats can't override anything, since their
+    /// provider settings carry API URLs.
+    fn user_only_fields(&self) -> Vec<&'static str> {
+        let mut fields = Vec::new();
+        let mut check = |name, is_set: bool| {
+            if is_set {
+                fields.push(name);
+            }
+        };
+        match self {
+            AssistantSettingsContent::Versioned(VersionedAssistantSettingsContent::V2(
+                settings,
+            )) => {
+                let AssistantSettingsContentV2 {
+                    enabled,
+                    button,
+                    dock,
+                    default_width,
+                    default_height,
+                    default_model: _,
+                    inline_alternatives,
+                    fallback_providers,
+                    prompt_templates: _,
+                    default_prompt_template: _,
+                    context_overflow_strategy,
+                    inline_assist_edit_format,
+                    max_tool_use_rounds,
+                    debug_logging,
+                    sampling,
+                } = settings;
+                check("enabled", enabled.is_some());
+                check("button", button.is_some());
+                check("dock", dock.is_some());
+                check("default_width", default_width.is_some());
+                check("default_height", default_height.is_some());
+                check("inline_alternatives", inline_alternatives.is_some());
+                check("fallback_providers", fallback_providers.is_some());
+                check(
+                    "context_overflow_strategy",
+                    context_overflow_strategy.is_some(),
+                );
+                check(
+                    "inline_assist_edit_format",
+                    inline_assist_edit_format.is_some(),
+                );
+                check("max_tool_use_rounds", max_tool_use_rounds.is_some());
+                check("debug_logging", debug_logging.is_some());
+                if let Some(sampling) = sampling {
+                    check("sampling.top_p", sampling.top_p.is_some());
+                    check("sampling.max_tokens", sampling.max_tokens.is_some());
+                    check("sampling.stop", sampling.stop.is_some());
+                }
+            }
+            AssistantSettingsContent::Versioned(VersionedAssistantSettingsContent::V1(
+                settings,
+            )) => {
+                check("enabled", settings.enabled.is_some());
+                check("button", settings.button.is_some());
+                check("dock", settings.dock.is_some());
+                check("default_width", settings.default_width.is_some());
+                check("default_height", settings.default_height.is_some());
+                check("provider", settings.provider.is_some());
+            }
+            AssistantSettingsContent::Legacy(settings) => {
+                check("button", settings.button.is_some());
+                check("dock", settings.dock.is_some());
+                check("default_width", settings.default_width.is_some());
+                check("default_height", settings.default_height.is_some());
+                check(
+                    "default_open_ai_model",
+                    settings.default_open_ai_model.is_some(),
+                );
+                check("openai_api_url", settings.openai_api_url.is_some());
+            }
+        }
+        fields
+    }
+
     pub fn set_dock(&mut self, dock: AssistantDockPosition) {
         match self {
             AssistantSettingsContent::Versioned(settings) => match settings {
@@ -373,6 +631,14 @@ impl Default for VersionedAssistantSettingsContent {
             default_height: None,
             default_model: None,
             inline_alternatives: None,
+            fallback_providers: None,
+            prompt_templates: None,
+            default_prompt_template: None,
+            context_overflow_strategy: None,
+            inline_assist_edit_format: None,
+            max_tool_use_rounds: None,
+            debug_logging: None,
+            sampling: None,
         })
     }
 }
@@ -403,6 +669,33 @@ pub struct AssistantSettingsContentV2 {
     default_model: Option<LanguageModelSelection>,
     /// Additional models with which to generate alternatives when performing inline assists.
     inline_alternatives: Option<Vec<LanguageModelSelection>>,
+    /// The providers, in order, to retry a request with when the default model's provider
+    /// fails before its response starts, e.g. `["openai", "zed.dev"]`.
+    ///
+    /// Default: []
+    fallback_providers: Option<Vec<String>>,
+    /// Named system prompt templates. Templates can reference `{language}`,
+    /// `{file_path}` and `{selection}`; use `{{` and `}}` for literal braces.
+    prompt_templates: Option<BTreeMap<String, String>>,
+    /// The name of the prompt template to use for the system message.
+    default_prompt_template: Option<String>,
+    /// What to do when a context no longer fits in the model's context window.
+    ///
+    /// Default: drop_oldest
+    context_overflow_strategy: Option<ContextOverflowStrategy>,
+    /// How the inline assistant applies the model's response when transforming a selection.
+    ///
+    /// Default: rewrite
+    inline_assist_edit_format: Option<InlineAssistEditFormat>,
+    /// How many times in a row the assistant may run the tools the model asked for and send it
+    /// their results, before waiting for the user.
+    ///
+    /// Default: 10
+    max_tool_use_rounds: Option<usize>,
+    /// Logging of the requests sent to language models and their responses.
+    debug_logging: Option<DebugLoggingSettingsContent>,
+    /// The sampling parameters of completion requests, which contexts can override.
+    sampling: Option<SamplingParameters>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
@@ -506,7 +799,12 @@ impl Settings for AssistantSettings {
     ) -> anyhow::Result<Self> {
         let mut settings = AssistantSettings::default();
 
-        for value in sources.defaults_and_customizations() {
+        let user_sources = [sources.default]
+            .into_iter()
+            .chain(sources.extensions)
+            .chain(sources.user)
+            .chain(sources.release_channel);
+        for value in user_sources {
             if value.is_version_outdated() {
                 settings.using_outdated_settings_version = true;
             }
@@ -525,9 +823,73 @@ impl Settings for AssistantSettings {
             );
             merge(&mut settings.default_model, value.default_model);
             merge(&mut settings.inline_alternatives, value.inline_alternatives);
+            merge(&mut settings.fallback_providers, value.fallback_providers);
+            if let Some(prompt_templates) = value.prompt_templates {
+                settings.prompt_templates.extend(prompt_templates);
+            }
+            if let Some(default_prompt_template) = value.default_prompt_template {
+                settings.default_prompt_template = Some(default_prompt_template);
+            }
+            merge(
+                &mut settings.context_overflow_strategy,
+                value.context_overflow_strategy,
+            );
+            merge(
+                &mut settings.inline_assist_edit_format,
+                value.inline_assist_edit_format,
+            );
+            merge(&mut settings.max_tool_use_rounds, value.max_tool_use_rounds);
+            if let Some(debug_logging) = value.debug_logging {
+                let settings = &mut settings.debug_logging;
+                merge(&mut settings.enabled, debug_logging.enabled);
+                merge(
+                    &mut settings.log_message_content,
+                    debug_logging.log_message_content,
+                );
+                merge(
+                    &mut settings.max_file_size_in_kb,
+                    debug_logging.max_file_size_in_kb,
+                );
+                merge(&mut settings.max_files, debug_logging.max_files);
+            }
+            if let Some(sampling) = value.sampling {
+                settings.sampling = sampling.or(&settings.sampling);
+            }
             // merge(&mut settings.infer_context, value.infer_context); TODO re-enable this once we ship context inference
         }
 
+        // Workspace settings are committed alongside the project, so they may only override the
+        // fields that can't leak credentials or redirect requests.
+        for value in sources.project {
+            let user_only_fields = value.user_only_fields();
+            if !user_only_fields.is_empty() {
+                log::warn!(
+                    "ignoring assistant settings that can only be set in user settings: {}",
+                    user_only_fields.join(", ")
+                );
+            }
+
+            let AssistantSettingsContent::Versioned(VersionedAssistantSettingsContent::V2(value)) =
+                value
+            else {
+                continue;
+            };
+            merge(&mut settings.default_model, value.default_model.clone());
+            if let Some(prompt_templates) = value.prompt_templates.clone() {
+                settings.prompt_templates.extend(prompt_templates);
+            }
+            if let Some(default_prompt_template) = value.default_prompt_template.clone() {
+                settings.default_prompt_template = Some(default_prompt_template);
+            }
+            if let Some(temperature) = value
+                .sampling
+                .as_ref()
+                .and_then(|sampling| sampling.temperature)
+            {
+                settings.sampling.temperature = Some(temperature);
+            }
+        }
+
         Ok(settings)
     }
 }
@@ -579,6 +941,14 @@ mod tests {
                                 model: "gpt-99".into(),
                             }),
                             inline_alternatives: None,
+                            fallback_providers: None,
+                            prompt_templates: None,
+                            default_prompt_template: None,
+                            context_overflow_strategy: None,
+                            inline_assist_edit_format: None,
+                            max_tool_use_rounds: None,
+                            debug_logging: None,
+                            sampling: None,
                             enabled: None,
                             button: None,
                             dock: None,
@@ -0,0 +1,251 @@
+use crate::markdown_stream::{closes_fence, opening_fence};
+use std::ops::Range;
+
+/// A fenced code block in an assistant message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResponseCodeBlock {
+    /// The first word of the opening fence's info string.
+    pub language: Option<String>,
+    /// The block's text, without its fences or the newline before the closing fence.
+    pub content: String,
+    /// Where `content` lies in the message's text.
+    pub byte_range_in_message: Range<usize>,
+}
+
+/// Incrementally extracts the fenced code blocks of a message as its text streams in.
+///
+/// A block that hasn't been closed yet is reported with the text it has so far, except for a
+/// trailing partial line that could still turn out to be its closing fence.
+#[derive(Default)]
+pub struct CodeBlockParser {
+    /// The length of the text before `pending_line`.
+    parsed_len: usize,
+    /// The text after the last newline.
+    pending_line: String,
+    open_block: Option<OpenCodeBlock>,
+    blocks: Vec<ResponseCodeBlock>,
+}
+
+struct OpenCodeBlock {
+    fence: char,
+    fence_len: usize,
+    language: Option<String>,
+    content_start: usize,
+    /// The block's complete lines, each followed by a newline.
+    content: String,
+}
+
+impl CodeBlockParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The length of the text parsed so far.
+    pub fn text_len(&self) -> usize {
+        self.parsed_len + self.pending_line.len()
+    }
+
+    pub fn push(&mut self, chunk: &str) {
+        self.pending_line.push_str(chunk);
+        while let Some(newline_ix) = self.pending_line.find('\n') {
+            let line = self.pending_line.drain(..=newline_ix).collect::<String>();
+            self.push_line(&line[..newline_ix]);
+            self.parsed_len += line.len();
+        }
+    }
+
+    /// Treats the text after the last newline as a complete line, which closes a block if it's
+    /// a closing fence.
+    pub fn finish(&mut self) {
+        if !self.pending_line.is_empty() {
+            let line = std::mem::take(&mut self.pending_line);
+            self.push_line(&line);
+            self.parsed_len += line.len();
+        }
+    }
+
+    /// The blocks found so far, including the one that's still open.
+    pub fn blocks(&self) -> Vec<ResponseCodeBlock> {
+        let mut blocks = self.blocks.clone();
+        if let Some(open_block) = &self.open_block {
+            let mut content = open_block.content.clone();
+            let pending_line = self.pending_line.trim_end_matches('\r');
+            let may_close =
+                closes_fence(pending_line, open_block.fence, open_block.fence_len, false)
+                    != Some(false);
+            if may_close {
+                content.pop();
+            } else {
+                content.push_str(&self.pending_line);
+            }
+            blocks.push(ResponseCodeBlock {
+                language: open_block.language.clone(),
+                byte_range_in_message: open_block.content_start
+                    ..open_block.content_start + content.len(),
+                content,
+            });
+        }
+        blocks
+    }
+
+    /// Parses a complete line that starts at `parsed_len`, without its newline.
+    fn push_line(&mut self, line: &str) {
+        let trimmed_line = line.trim_end_matches('\r');
+        if let Some(open_block) = &mut self.open_block {
+            if closes_fence(trimmed_line, open_block.fence, open_block.fence_len, true)
+                == Some(true)
+            {
+                let mut open_block = self.open_block.take().unwrap();
+                open_block.content.pop();
+                self.blocks.push(ResponseCodeBlock {
+                    language: open_block.language,
+                    byte_range_in_message: open_block.content_start
+                        ..open_block.content_start + open_block.content.len(),
+                    content: open_block.content,
+                });
+            } else {
+                open_block.content.push_str(line);
+                open_block.content.push('\n');
+            }
+        } else if let Some((fence, fence_len, language)) = opening_fence(trimmed_line) {
+            self.open_block = Some(OpenCodeBlock {
+                fence,
+                fence_len,
+                language,
+                content_start: self.parsed_len + line.len() + 1,
+                content: String::new(),
+            });
+        }
+    }
+}
+
+/// Extracts the fenced code blocks of a complete message.
+pub fn parse_code_blocks(text: &str) -> Vec<ResponseCodeBlock> {
+    let mut parser = CodeBlockParser::new();
+    parser.push(text);
+    parser.finish();
+    parser.blocks()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    fn block(language: Option<&str>, content: &str, text: &str) -> ResponseCodeBlock {
+        let start = text.find(content).unwrap();
+        ResponseCodeBlock {
+            language: language.map(str::to_string),
+            content: content.to_string(),
+            byte_range_in_message: start..start + content.len(),
+        }
+    }
+
+    #[test]
+    fn test_multiple_blocks_with_the_same_language() {
+        let text = indoc! {"
+            First:
+            ```rust
+            fn one() {}
+            ```
+            Then:
+
+            ```rust
+            fn two() {}
+
+            fn three() {}
+            ```
+            ~~~
+            plain
+            ~~~"};
+        assert_eq!(
+            parse_code_blocks(text),
+            vec![
+                block(Some("rust"), "fn one() {}", text),
+                block(Some("rust"), "fn two() {}\n\nfn three() {}", text),
+                block(None, "plain", text),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_fences() {
+        let text = indoc! {"
+            ````markdown
+            Example:
+            ```rust
+            fn main() {}
+            ```
+            ````
+            ```python
+            print(\"```\")
+            ```
+        "};
+        assert_eq!(
+            parse_code_blocks(text),
+            vec![
+                block(
+                    Some("markdown"),
+                    "Example:\n```rust\nfn main() {}\n```",
+                    text
+                ),
+                block(Some("python"), "print(\"```\")", text),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unterminated_block_while_streaming() {
+        let text = "Here:\n```rust\nfn main() {\n    println!();\n}\n```\nDone.";
+        let mut parser = CodeBlockParser::new();
+        let mut streamed = String::new();
+        for chunk in [
+            "Here:\n``",
+            "`rust\nfn ma",
+            "in() {\n    println!();\n}\n`",
+            "``\nDone.",
+        ] {
+            parser.push(chunk);
+            streamed.push_str(chunk);
+            assert_eq!(parser.text_len(), streamed.len());
+
+            // Each block's content is the text in its range.
+            for block in parser.blocks() {
+                assert_eq!(
+                    &streamed[block.byte_range_in_message.clone()],
+                    block.content
+                );
+            }
+        }
+
+        let mut parser = CodeBlockParser::new();
+        parser.push("Here:\n``");
+        assert!(parser.blocks().is_empty());
+        parser.push("`rust\nfn ma");
+        assert_eq!(parser.blocks(), vec![block(Some("rust"), "fn ma", text)]);
+        parser.push("in() {\n    println!();\n}\n`");
+        assert_eq!(
+            parser.blocks(),
+            vec![block(Some("rust"), "fn main() {\n    println!();\n}", text)]
+        );
+        parser.push("``\nDone.");
+        assert_eq!(
+            parser.blocks(),
+            vec![block(Some("rust"), "fn main() {\n    println!();\n}", text)]
+        );
+
+        // A message can end in the middle of a block.
+        assert_eq!(
+            parse_code_blocks("```rust\nfn main() {"),
+            vec![block(Some("rust"), "fn main() {", "```rust\nfn main() {")]
+        );
+        assert_eq!(
+            parse_code_blocks("```\n"),
+            vec![ResponseCodeBlock {
+                language: None,
+                content: String::new(),
+                byte_range_in_message: 4..4,
+            }]
+        );
+    }
+}
@@ -2,8 +2,14 @@
 mod context_tests;
 
 use crate::{
-    prompts::PromptBuilder, slash_command::SlashCommandLine, MessageId, MessageStatus,
-    WorkflowStep, WorkflowStepEdit, WorkflowStepResolution, WorkflowSuggestionGroup,
+    assistant_settings::{AssistantSettings, SamplingParameters},
+    code_blocks::{parse_code_blocks, CodeBlockParser, ResponseCodeBlock},
+    prompt_template::{PromptContext, PromptTemplate},
+    prompts::PromptBuilder,
+    request_truncation,
+    slash_command::SlashCommandLine,
+    MessageId, MessageStatus, WorkflowStep, WorkflowStepEdit, WorkflowStepResolution,
+    WorkflowSuggestionGroup,
 };
 use anyhow::{anyhow, Context as _, Result};
 use assistant_slash_command::{
@@ -26,10 +32,11 @@ use gpui::{
 
 use language::{AnchorRangeExt, Bias, Buffer, LanguageRegistry, OffsetRangeExt, Point, ToOffset};
 use language_model::{
-    LanguageModel, LanguageModelCacheConfiguration, LanguageModelCompletionEvent,
+    coalesce_chunks, LanguageModel, LanguageModelCacheConfiguration, LanguageModelCompletionEvent,
     LanguageModelImage, LanguageModelRegistry, LanguageModelRequest, LanguageModelRequestMessage,
-    LanguageModelRequestTool, LanguageModelToolResult, LanguageModelToolUse, MessageContent, Role,
-    StopReason,
+    LanguageModelRequestTool, LanguageModelToolResult, LanguageModelToolUse, MessageContent,
+    RequestMetrics, RequestMetricsRecorder, Role, StopReason, CONTINUE_PROMPT,
+    DEFAULT_COALESCE_INTERVAL,
 };
 use open_ai::Model as OpenAiModel;
 use paths::contexts_dir;
@@ -116,6 +123,7 @@ impl ContextOperation {
                         ),
                         timestamp: id.0,
                         cache: None,
+                        metrics: None,
                     },
                     version: language::proto::deserialize_version(&insert.version),
                 })
@@ -131,6 +139,7 @@ impl ContextOperation {
                         update.timestamp.context("invalid timestamp")?,
                     ),
                     cache: None,
+                    metrics: None,
                 },
                 version: language::proto::deserialize_version(&update.version),
             }),
@@ -316,6 +325,7 @@ pub enum ContextEvent {
         tool_use_id: Arc<str>,
         output_range: Range<language::Anchor>,
     },
+    SamplingOverridesChanged,
     Operation(ContextOperation),
 }
 
@@ -353,6 +363,9 @@ pub struct MessageMetadata {
     pub(crate) timestamp: clock::Lamport,
     #[serde(skip)]
     pub cache: Option<MessageCacheMetadata>,
+    /// How the completion that produced this message performed.
+    #[serde(default)]
+    pub metrics: Option<RequestMetrics>,
 }
 
 impl From<&Message> for MessageMetadata {
@@ -362,6 +375,7 @@ impl From<&Message> for MessageMetadata {
             status: message.status.clone(),
             timestamp: message.id.0,
             cache: message.cache.clone(),
+            metrics: message.metrics,
         }
     }
 }
@@ -391,6 +405,7 @@ pub struct Message {
     pub role: Role,
     pub status: MessageStatus,
     pub cache: Option<MessageCacheMetadata>,
+    pub metrics: Option<RequestMetrics>,
 }
 
 #[derive(Debug, Clone)]
@@ -408,6 +423,7 @@ pub enum Content {
     ToolResult {
         range: Range<language::Anchor>,
         tool_use_id: Arc<str>,
+        is_error: bool,
     },
 }
 
@@ -480,6 +496,22 @@ pub struct Context {
     completion_count: usize,
     pending_completions: Vec<PendingCompletion>,
     token_count: Option<usize>,
+    omitted_message_count: usize,
+    sampling_overrides: SamplingParameters,
+    /// What the user is working on, for the placeholders of the prompt template.
+    prompt_context: PromptContext,
+    /// The providers that served the completions of messages after the active provider failed.
+    served_by: HashMap<MessageId, SharedString>,
+    /// The messages whose completions were interrupted and couldn't be resumed.
+    incomplete_messages: HashSet<MessageId>,
+    /// Why the completions of messages stopped, for those that streamed to the end.
+    stop_reasons: HashMap<MessageId, StopReason>,
+    /// The tools the user allowed to run without asking again.
+    always_allowed_tools: HashSet<String>,
+    /// How many times in a row tool results were sent to the model without the user asking.
+    tool_use_rounds: usize,
+    /// The code blocks of messages whose completions are streaming, parsed as chunks arrive.
+    code_block_parsers: HashMap<MessageId, CodeBlockParser>,
     pending_token_count: Task<Option<()>>,
     pending_save: Task<Result<()>>,
     pending_cache_warming_task: Task<Option<()>>,
@@ -579,6 +611,15 @@ impl Context {
             completion_count: Default::default(),
             pending_completions: Default::default(),
             token_count: None,
+            omitted_message_count: 0,
+            sampling_overrides: SamplingParameters::default(),
+            prompt_context: PromptContext::default(),
+            served_by: HashMap::default(),
+            code_block_parsers: HashMap::default(),
+            incomplete_messages: HashSet::default(),
+            stop_reasons: HashMap::default(),
+            always_allowed_tools: HashSet::default(),
+            tool_use_rounds: 0,
             pending_token_count: Task::ready(None),
             pending_cache_warming_task: Task::ready(None),
             _subscriptions: vec![cx.subscribe(&buffer, Self::handle_buffer_event)],
@@ -608,6 +649,7 @@ impl Context {
                 status: MessageStatus::Done,
                 timestamp: first_message_id.0,
                 cache: None,
+                metrics: None,
             },
         );
         this.message_anchors.push(message);
@@ -637,6 +679,7 @@ impl Context {
                 .as_ref()
                 .map(|summary| summary.text.clone())
                 .unwrap_or_default(),
+            sampling_overrides: self.sampling_overrides.clone(),
             slash_command_output_sections: self
                 .slash_command_output_sections
                 .iter()
@@ -660,7 +703,7 @@ impl Context {
     #[allow(clippy::too_many_arguments)]
     pub fn deserialize(
         saved_context: SavedContext,
-        path: PathBuf,
+        path: Option<PathBuf>,
         language_registry: Arc<LanguageRegistry>,
         prompt_builder: Arc<PromptBuilder>,
         project: Option<Model<Project>>,
@@ -678,7 +721,8 @@ impl Context {
             telemetry,
             cx,
         );
-        this.path = Some(path);
+        this.path = path;
+        this.sampling_overrides = saved_context.sampling_overrides.clone();
         this.buffer.update(cx, |buffer, cx| {
             buffer.set_text(saved_context.text.as_str(), cx)
         });
@@ -914,6 +958,12 @@ impl Context {
         self.project.clone()
     }
 
+    /// The assistant settings that apply to the context, including the overrides in its
+    /// project's workspace settings.
+    pub fn settings<'a>(&self, cx: &'a AppContext) -> &'a AssistantSettings {
+        AssistantSettings::for_project(self.project.as_ref(), cx)
+    }
+
     pub fn prompt_builder(&self) -> Arc<PromptBuilder> {
         self.prompt_builder.clone()
     }
@@ -1027,9 +1077,53 @@ impl Context {
         self.token_count
     }
 
+    /// The number of earlier messages that were left out of the last completion request
+    /// so that it would fit in the model's context window.
+    pub(crate) fn omitted_message_count(&self) -> usize {
+        self.omitted_message_count
+    }
+
+    /// The provider that served the message's completion, if the active provider failed before
+    /// its response started and the request fell back to another provider.
+    pub fn served_by(&self, message_id: MessageId) -> Option<SharedString> {
+        self.served_by.get(&message_id).cloned()
+    }
+
+    /// Whether the message's completion was interrupted and couldn't be resumed, so that it may
+    /// be missing its end.
+    pub fn is_incomplete(&self, message_id: MessageId) -> bool {
+        self.incomplete_messages.contains(&message_id)
+    }
+
+    /// Why the message's completion stopped, once it has streamed to the end.
+    pub fn stop_reason(&self, message_id: MessageId) -> Option<&StopReason> {
+        self.stop_reasons.get(&message_id)
+    }
+
+    /// The sampling parameters that this context uses instead of those in the settings.
+    pub fn sampling_overrides(&self) -> &SamplingParameters {
+        &self.sampling_overrides
+    }
+
+    pub fn set_sampling_overrides(
+        &mut self,
+        sampling_overrides: SamplingParameters,
+        cx: &mut ModelContext<Self>,
+    ) {
+        if self.sampling_overrides != sampling_overrides {
+            self.sampling_overrides = sampling_overrides;
+            cx.emit(ContextEvent::SamplingOverridesChanged);
+        }
+    }
+
+    /// Sets what the user is working on, which fills in the placeholders of the prompt template.
+    pub fn set_prompt_context(&mut self, prompt_context: PromptContext) {
+        self.prompt_context = prompt_context;
+    }
+
     pub(crate) fn count_remaining_tokens(&mut self, cx: &mut ModelContext<Self>) {
         let request = self.to_completion_request(cx);
-        let Some(model) = LanguageModelRegistry::read_global(cx).active_model() else {
+        let Some(model) = self.model(cx) else {
             return;
         };
         self.pending_token_count = cx.spawn(|this, mut cx| {
@@ -1179,6 +1273,7 @@ impl Context {
                 role: Role::User,
                 content: vec!["Respond only with OK, nothing else.".into()],
                 cache: false,
+                attachments: Vec::new(),
             });
             req
         };
@@ -1894,6 +1989,92 @@ impl Context {
         }
     }
 
+    /// Returns the idle tool uses that can run right away, so the caller can run them and
+    /// insert their output. Tools that require confirmation wait for the user to allow them,
+    /// unless they were always allowed, and tools that aren't registered fail right away.
+    pub fn take_tool_uses_to_run(&mut self, cx: &mut ModelContext<Self>) -> Vec<PendingToolUse> {
+        let tool_registry = ToolRegistry::global(cx);
+        let mut tool_uses_to_run = Vec::new();
+        let mut missing_tool_uses = Vec::new();
+        for tool_use in self.pending_tool_uses_by_id.values_mut() {
+            if !tool_use.status.is_idle() {
+                continue;
+            }
+            match tool_registry.tool(&tool_use.name) {
+                Some(tool)
+                    if tool.requires_confirmation()
+                        && !self.always_allowed_tools.contains(&tool_use.name) =>
+                {
+                    tool_use.status = PendingToolUseStatus::AwaitingConfirmation;
+                }
+                Some(_) => tool_uses_to_run.push(tool_use.clone()),
+                None => missing_tool_uses.push(tool_use.clone()),
+            }
+        }
+
+        for tool_use in missing_tool_uses {
+            let error = anyhow!("There is no tool named {:?}", tool_use.name);
+            self.insert_tool_output(tool_use.id, Task::ready(Err(error)), cx);
+        }
+        let buffer = self.buffer.read(cx);
+        tool_uses_to_run.sort_by(|a, b| a.source_range.start.cmp(&b.source_range.start, buffer));
+        cx.notify();
+        tool_uses_to_run
+    }
+
+    /// Answers a tool use that's awaiting confirmation, returning the tool uses that can now run.
+    /// A denied tool use fails, and the model is told that the user didn't allow it.
+    pub fn confirm_tool_use(
+        &mut self,
+        tool_use_id: &Arc<str>,
+        confirmation: ToolUseConfirmation,
+        cx: &mut ModelContext<Self>,
+    ) -> Vec<PendingToolUse> {
+        let Some(tool_use) = self
+            .pending_tool_uses_by_id
+            .get(tool_use_id)
+            .filter(|tool_use| tool_use.status.is_awaiting_confirmation())
+            .cloned()
+        else {
+            return Vec::new();
+        };
+
+        let mut tool_uses_to_run = Vec::new();
+        match confirmation {
+            ToolUseConfirmation::Allow => tool_uses_to_run.push(tool_use),
+            ToolUseConfirmation::AlwaysAllow => {
+                tool_uses_to_run.extend(
+                    self.pending_tool_uses_by_id
+                        .values()
+                        .filter(|pending| {
+                            pending.name == tool_use.name
+                                && pending.status.is_awaiting_confirmation()
+                        })
+                        .cloned(),
+                );
+                self.always_allowed_tools.insert(tool_use.name);
+            }
+            ToolUseConfirmation::Deny => {
+                let error = anyhow!("The user didn't allow this tool to run.");
+                self.insert_tool_output(tool_use.id, Task::ready(Err(error)), cx);
+            }
+        }
+
+        for tool_use in &mut tool_uses_to_run {
+            tool_use.status = PendingToolUseStatus::Idle;
+            if let Some(pending) = self.pending_tool_uses_by_id.get_mut(&tool_use.id) {
+                pending.status = PendingToolUseStatus::Idle;
+            }
+        }
+        let buffer = self.buffer.read(cx);
+        tool_uses_to_run.sort_by(|a, b| a.source_range.start.cmp(&b.source_range.start, buffer));
+        cx.notify();
+        tool_uses_to_run
+    }
+
+    /// Appends the tool's output to the context as its result. An error is sent to the model as
+    /// the result too, so it can recover. Once every tool use has a result, the results are sent
+    /// to the model.
     pub fn insert_tool_output(
         &mut self,
         tool_use_id: Arc<str>,
@@ -1904,46 +2085,52 @@ impl Context {
             let tool_use_id = tool_use_id.clone();
             async move {
                 let output = output.await;
-                this.update(&mut cx, |this, cx| match output {
-                    Ok(mut output) => {
-                        const NEWLINE: char = '\n';
+                this.update(&mut cx, |this, cx| {
+                    const NEWLINE: char = '\n';
 
-                        if !output.ends_with(NEWLINE) {
-                            output.push(NEWLINE);
+                    let (mut output, status) = match output {
+                        Ok(output) => (output, PendingToolUseStatus::Finished),
+                        Err(error) => {
+                            let error = error.to_string();
+                            (error.clone(), PendingToolUseStatus::Error(error))
                         }
+                    };
+                    let is_error = matches!(status, PendingToolUseStatus::Error(_));
+                    if !output.ends_with(NEWLINE) {
+                        output.push(NEWLINE);
+                    }
 
-                        let anchor_range = this.buffer.update(cx, |buffer, cx| {
-                            let insert_start = buffer.len().to_offset(buffer);
-                            let insert_end = insert_start;
-
-                            let start = insert_start;
-                            let end = start + output.len() - NEWLINE.len_utf8();
+                    let anchor_range = this.buffer.update(cx, |buffer, cx| {
+                        let insert_start = buffer.len().to_offset(buffer);
+                        let insert_end = insert_start;
 
-                            buffer.edit([(insert_start..insert_end, output)], None, cx);
+                        let start = insert_start;
+                        let end = start + output.len() - NEWLINE.len_utf8();
 
-                            let output_range = buffer.anchor_after(start)..buffer.anchor_after(end);
+                        buffer.edit([(insert_start..insert_end, output)], None, cx);
 
-                            output_range
-                        });
+                        let output_range = buffer.anchor_after(start)..buffer.anchor_after(end);
 
-                        this.insert_content(
-                            Content::ToolResult {
-                                range: anchor_range.clone(),
-                                tool_use_id: tool_use_id.clone(),
-                            },
-                            cx,
-                        );
+                        output_range
+                    });
 
-                        cx.emit(ContextEvent::ToolFinished {
-                            tool_use_id,
-                            output_range: anchor_range,
-                        });
-                    }
-                    Err(err) => {
-                        if let Some(tool_use) = this.pending_tool_uses_by_id.get_mut(&tool_use_id) {
-                            tool_use.status = PendingToolUseStatus::Error(err.to_string());
-                        }
+                    this.insert_content(
+                        Content::ToolResult {
+                            range: anchor_range.clone(),
+                            tool_use_id: tool_use_id.clone(),
+                            is_error,
+                        },
+                        cx,
+                    );
+                    if let Some(tool_use) = this.pending_tool_uses_by_id.get_mut(&tool_use_id) {
+                        tool_use.status = status;
                     }
+
+                    cx.emit(ContextEvent::ToolFinished {
+                        tool_use_id,
+                        output_range: anchor_range,
+                    });
+                    this.send_tool_results_if_done(cx);
                 })
                 .ok();
             }
@@ -1956,6 +2143,33 @@ impl Context {
         }
     }
 
+    /// Asks the model to respond to the tool results once every tool use has one, unless it has
+    /// already used tools as many times in a row as the settings allow.
+    fn send_tool_results_if_done(&mut self, cx: &mut ModelContext<Self>) {
+        if !self.pending_completions.is_empty()
+            || !self
+                .pending_tool_uses_by_id
+                .values()
+                .all(|tool_use| tool_use.status.is_done())
+        {
+            return;
+        }
+
+        let max_tool_use_rounds = self.settings(cx).max_tool_use_rounds;
+        if self.tool_use_rounds >= max_tool_use_rounds {
+            cx.emit(ContextEvent::ShowAssistError(
+                format!(
+                    "Stopped after {max_tool_use_rounds} rounds of tool use. \
+                    Assist again to let the model continue."
+                )
+                .into(),
+            ));
+            return;
+        }
+        self.tool_use_rounds += 1;
+        self.request_response(cx);
+    }
+
     pub fn completion_provider_changed(&mut self, cx: &mut ModelContext<Self>) {
         self.count_remaining_tokens(cx);
     }
@@ -1970,15 +2184,212 @@ impl Context {
     }
 
     pub fn assist(&mut self, cx: &mut ModelContext<Self>) -> Option<MessageAnchor> {
-        let model_registry = LanguageModelRegistry::read_global(cx);
-        let provider = model_registry.active_provider()?;
-        let model = model_registry.active_model()?;
+        self.tool_use_rounds = 0;
+        self.request_response(cx)
+    }
+
+    /// Streams a response to the conversation into a new assistant message, and queues up the
+    /// user's next reply after it.
+    fn request_response(&mut self, cx: &mut ModelContext<Self>) -> Option<MessageAnchor> {
+        let model = self.authenticated_model(cx)?;
         let last_message_id = self.get_last_valid_message_id(cx)?;
+        let request = self.to_assist_request(&model, cx);
+
+        let assistant_message = self
+            .insert_message_after(last_message_id, Role::Assistant, MessageStatus::Pending, cx)
+            .unwrap();
+
+        // Queue up the user's next reply.
+        let user_message = self
+            .insert_message_after(assistant_message.id, Role::User, MessageStatus::Done, cx)
+            .unwrap();
+
+        self.stream_completion(assistant_message.id, request, model, cx);
+        Some(user_message)
+    }
+
+    /// Replaces the messages after the given one with a new response to the conversation up to
+    /// it, canceling any response that's still streaming.
+    ///
+    /// When the message is an assistant message, or is followed by one, the new response
+    /// streams into that message in place. Messages up to it keep their ids.
+    pub fn regenerate_from(
+        &mut self,
+        message_id: MessageId,
+        cx: &mut ModelContext<Self>,
+    ) -> Option<MessageAnchor> {
+        let model = self.authenticated_model(cx)?;
+        let mut messages = self
+            .messages(cx)
+            .skip_while(|message| message.id != message_id);
+        let message = messages.next()?;
+        let assistant_message = if message.role == Role::Assistant {
+            Some(message.clone())
+        } else {
+            messages
+                .next()
+                .filter(|message| message.role == Role::Assistant)
+        };
+        drop(messages);
+
+        while self.cancel_last_assist(cx) {}
+
+        let assistant_message_id = if let Some(assistant_message) = assistant_message {
+            self.truncate_after(assistant_message.offset_range.start, cx);
+            self.update_metadata(assistant_message.id, cx, |metadata| {
+                metadata.status = MessageStatus::Pending;
+            });
+            assistant_message.id
+        } else {
+            let message_text_end = self.message_text_end(&message, cx);
+            self.truncate_after(message_text_end, cx);
+            self.insert_message_after(message_id, Role::Assistant, MessageStatus::Pending, cx)?
+                .id
+        };
+        let request = self.to_assist_request(&model, cx);
+
+        // Queue up the user's next reply.
+        let user_message = self
+            .insert_message_after(assistant_message_id, Role::User, MessageStatus::Done, cx)
+            .unwrap();
+
+        self.stream_completion(assistant_message_id, request, model, cx);
+        Some(user_message)
+    }
+
+    /// Whether the message's response stopped at the output token limit and can be continued,
+    /// which requires it to be the last message with any text.
+    pub fn can_continue_message(&self, message_id: MessageId, cx: &AppContext) -> bool {
+        if self.stop_reasons.get(&message_id) != Some(&StopReason::MaxTokens) {
+            return false;
+        }
+        !self
+            .messages(cx)
+            .skip_while(|message| message.id != message_id)
+            .skip(1)
+            .any(|message| self.message_text_end(&message, cx) > message.offset_range.start)
+    }
+
+    /// Asks the model to continue the response of an assistant message that stopped at the
+    /// output token limit, streaming the continuation onto the end of the message.
+    ///
+    /// Returns `false` without doing anything unless [`Self::can_continue_message`].
+    pub fn continue_message(&mut self, message_id: MessageId, cx: &mut ModelContext<Self>) -> bool {
+        if !self.can_continue_message(message_id, cx) {
+            return false;
+        }
+        let Some(model) = self.authenticated_model(cx) else {
+            return false;
+        };
+
+        let mut request = self.to_assist_request(&model, cx);
+        request.messages.push(LanguageModelRequestMessage {
+            role: Role::User,
+            content: vec![CONTINUE_PROMPT.into()],
+            cache: false,
+            attachments: Vec::new(),
+        });
+        self.update_metadata(message_id, cx, |metadata| {
+            metadata.status = MessageStatus::Pending;
+        });
+        self.stream_completion(message_id, request, model, cx);
+        true
+    }
+
+    /// Replaces the text of the message and regenerates the conversation from it, as in
+    /// [`Self::regenerate_from`].
+    ///
+    /// The message's images, tool uses and tool results are kept, ahead of the new text.
+    pub fn edit_message(
+        &mut self,
+        message_id: MessageId,
+        new_text: &str,
+        cx: &mut ModelContext<Self>,
+    ) -> Option<MessageAnchor> {
+        self.authenticated_model(cx)?;
+        let message = self.messages(cx).find(|message| message.id == message_id)?;
+        let message_text_end = self.message_text_end(&message, cx);
+        while self.cancel_last_assist(cx) {}
+        self.truncate_after(message_text_end, cx);
 
-        if !provider.is_authenticated(cx) {
+        let buffer = self.buffer.read(cx);
+        let text_range = message.offset_range.start..message_text_end;
+        let mut kept_ranges = self
+            .contents(cx)
+            .map(|content| match content {
+                // An image is anchored to the newline before it.
+                Content::Image { anchor, .. } => {
+                    let offset = anchor.to_offset(buffer);
+                    offset.saturating_sub(1)..offset
+                }
+                content => content.range().to_offset(buffer),
+            })
+            .filter(|range| range.start < text_range.end && range.end > text_range.start)
+            .collect::<Vec<_>>();
+        kept_ranges.push(text_range.end..text_range.end);
+
+        let mut edits = Vec::new();
+        let mut offset = text_range.start;
+        for kept_range in kept_ranges {
+            if kept_range.start > offset {
+                edits.push((offset..kept_range.start, ""));
+            }
+            offset = offset.max(kept_range.end);
+        }
+        edits.push((text_range.end..text_range.end, new_text));
+        self.buffer
+            .update(cx, |buffer, cx| buffer.edit(edits, None, cx));
+
+        self.regenerate_from(message_id, cx)
+    }
+
+    /// Returns the model that the context assists with: the default model from its workspace
+    /// settings if they override the user's, or the active model otherwise.
+    pub(crate) fn model(&self, cx: &AppContext) -> Option<Arc<dyn LanguageModel>> {
+        AssistantSettings::model_for_project(self.project.as_ref(), cx)
+    }
+
+    /// Returns the context's model, if its provider or any provider it falls back to is
+    /// authenticated.
+    pub(crate) fn authenticated_model(&self, cx: &AppContext) -> Option<Arc<dyn LanguageModel>> {
+        let model = self.model(cx)?;
+        if !LanguageModelRegistry::read_global(cx).is_model_authenticated(
+            &model.provider_id(),
+            &model.id(),
+            cx,
+        ) {
             log::info!("completion provider has no credentials");
             return None;
         }
+        Some(model)
+    }
+
+    /// Returns the offset at which the message's text ends, before the newline that separates
+    /// it from the next message.
+    fn message_text_end(&self, message: &Message, cx: &AppContext) -> usize {
+        let buffer = self.buffer.read(cx);
+        if message.offset_range.end == buffer.len() {
+            message.offset_range.end
+        } else {
+            message.offset_range.end - 1
+        }
+    }
+
+    /// Removes everything after the offset, along with the messages that start after it.
+    fn truncate_after(&mut self, offset: usize, cx: &mut ModelContext<Self>) {
+        self.buffer.update(cx, |buffer, cx| {
+            let len = buffer.len();
+            if offset < len {
+                buffer.edit([(offset..len, "")], None, cx);
+            }
+        });
+    }
+
+    pub(crate) fn to_assist_request(
+        &mut self,
+        model: &Arc<dyn LanguageModel>,
+        cx: &mut ModelContext<Self>,
+    ) -> LanguageModelRequest {
         // Compute which messages to cache, including the last one.
         self.mark_cache_anchors(&model.cache_configuration(), false, cx);
 
@@ -1997,25 +2408,52 @@ impl Context {
                 .collect();
         }
 
-        let assistant_message = self
-            .insert_message_after(last_message_id, Role::Assistant, MessageStatus::Pending, cx)
-            .unwrap();
-
-        // Queue up the user's next reply.
-        let user_message = self
-            .insert_message_after(assistant_message.id, Role::User, MessageStatus::Done, cx)
-            .unwrap();
+        request
+    }
 
+    /// Streams the response to the request into the assistant message.
+    fn stream_completion(
+        &mut self,
+        assistant_message_id: MessageId,
+        request: LanguageModelRequest,
+        model: Arc<dyn LanguageModel>,
+        cx: &mut ModelContext<Self>,
+    ) {
         let pending_completion_id = post_inc(&mut self.completion_count);
+        self.served_by.remove(&assistant_message_id);
+        self.incomplete_messages.remove(&assistant_message_id);
+        self.stop_reasons.remove(&assistant_message_id);
+        self.code_block_parsers
+            .insert(assistant_message_id, CodeBlockParser::new());
+        let context_overflow_strategy = self.settings(cx).context_overflow_strategy;
 
         let task = cx.spawn({
             |this, mut cx| async move {
-                let stream = model.stream_completion(request, &cx);
-                let assistant_message_id = assistant_message.id;
                 let mut response_latency = None;
+                let mut request_metrics = None;
                 let stream_completion = async {
+                    let fitted_request = request_truncation::fit_request_to_model(
+                        request,
+                        &model,
+                        context_overflow_strategy,
+                        &cx,
+                    )
+                    .await?;
+                    this.update(&mut cx, |this, cx| {
+                        this.omitted_message_count = fitted_request.omitted_message_count;
+                        cx.notify();
+                    })?;
+
                     let request_start = Instant::now();
-                    let mut events = stream.await?;
+                    let executor = cx.background_executor().clone();
+                    let metrics = RequestMetricsRecorder::start(executor.clone());
+                    request_metrics = Some(metrics.clone());
+                    let mut events = coalesce_chunks(
+                        metrics
+                            .measure(model.stream_completion(fitted_request.request, &cx).await?),
+                        DEFAULT_COALESCE_INTERVAL,
+                        move |interval| executor.timer(interval),
+                    );
                     let mut stop_reason = StopReason::EndTurn;
 
                     while let Some(event) = events.next().await {
@@ -2025,6 +2463,7 @@ impl Context {
                         let event = event?;
 
                         this.update(&mut cx, |this, cx| {
+                            let mut tool_use_content = None;
                             let message_ix = this
                                 .message_anchors
                                 .iter()
@@ -2041,7 +2480,21 @@ impl Context {
                                     LanguageModelCompletionEvent::Stop(reason) => {
                                         stop_reason = reason;
                                     }
+                                    LanguageModelCompletionEvent::UsageUpdate(_) => {}
+                                    LanguageModelCompletionEvent::FellBack { provider_name } => {
+                                        this.served_by
+                                            .insert(assistant_message_id, provider_name.into());
+                                    }
+                                    LanguageModelCompletionEvent::Incomplete => {
+                                        this.incomplete_messages.insert(assistant_message_id);
+                                    }
+                                    LanguageModelCompletionEvent::Queued(_) => {}
                                     LanguageModelCompletionEvent::Text(chunk) => {
+                                        if let Some(parser) =
+                                            this.code_block_parsers.get_mut(&assistant_message_id)
+                                        {
+                                            parser.push(&chunk);
+                                        }
                                         buffer.edit(
                                             [(
                                                 message_old_end_offset..message_old_end_offset,
@@ -2062,6 +2515,11 @@ impl Context {
                                         );
                                         text.push(NEWLINE);
                                         let text_len = text.len();
+                                        if let Some(parser) =
+                                            this.code_block_parsers.get_mut(&assistant_message_id)
+                                        {
+                                            parser.push(&text);
+                                        }
 
                                         buffer.edit(
                                             [(
@@ -2078,6 +2536,10 @@ impl Context {
                                         let source_range = buffer.anchor_after(start_ix)
                                             ..buffer.anchor_after(end_ix);
 
+                                        tool_use_content = Some(Content::ToolUse {
+                                            range: source_range.clone(),
+                                            tool_use: tool_use.clone(),
+                                        });
                                         let tool_use_id: Arc<str> = tool_use.id.into();
                                         this.pending_tool_uses_by_id.insert(
                                             tool_use_id.clone(),
@@ -2092,6 +2554,9 @@ impl Context {
                                     }
                                 }
                             });
+                            if let Some(content) = tool_use_content {
+                                this.insert_content(content, cx);
+                            }
 
                             cx.emit(ContextEvent::StreamedCompletion);
 
@@ -2112,6 +2577,7 @@ impl Context {
                 let result = stream_completion.await;
 
                 this.update(&mut cx, |this, cx| {
+                    this.code_block_parsers.remove(&assistant_message_id);
                     let error_message = result
                         .as_ref()
                         .err()
@@ -2121,6 +2587,10 @@ impl Context {
                         cx.emit(ContextEvent::ShowAssistError(SharedString::from(
                             error_message.clone(),
                         )));
+                        // The provider may have become unreachable, so check it right away
+                        // instead of waiting for the next periodic check.
+                        LanguageModelRegistry::global(cx)
+                            .update(cx, |registry, cx| registry.recheck_provider_status(cx));
                     }
 
                     this.update_metadata(assistant_message_id, cx, |metadata| {
@@ -2130,6 +2600,7 @@ impl Context {
                         } else {
                             metadata.status = MessageStatus::Done;
                         }
+                        metadata.metrics = request_metrics.map(|metrics| metrics.metrics());
                     });
 
                     if let Some(telemetry) = this.telemetry.as_ref() {
@@ -2144,13 +2615,10 @@ impl Context {
                     }
 
                     if let Ok(stop_reason) = result {
-                        match stop_reason {
-                            StopReason::ToolUse => {
-                                cx.emit(ContextEvent::UsePendingTools);
-                            }
-                            StopReason::EndTurn => {}
-                            StopReason::MaxTokens => {}
+                        if stop_reason == StopReason::ToolUse {
+                            cx.emit(ContextEvent::UsePendingTools);
                         }
+                        this.stop_reasons.insert(assistant_message_id, stop_reason);
                     }
                 })
                 .ok();
@@ -2159,11 +2627,9 @@ impl Context {
 
         self.pending_completions.push(PendingCompletion {
             id: pending_completion_id,
-            assistant_message_id: assistant_message.id,
+            assistant_message_id,
             _task: task,
         });
-
-        Some(user_message)
     }
 
     pub fn to_completion_request(&self, cx: &AppContext) -> LanguageModelRequest {
@@ -2185,7 +2651,19 @@ impl Context {
             tools: Vec::new(),
             stop: Vec::new(),
             temperature: None,
+            top_p: None,
+            max_tokens: None,
+            response_format: None,
         };
+        let settings = self.settings(cx);
+        self.sampling_overrides
+            .or(&settings.sampling)
+            .apply_to(&mut completion_request);
+        if let Some(template) = PromptTemplate::from_settings(settings) {
+            completion_request
+                .messages
+                .push(template.system_message(&self.prompt_context));
+        }
         for message in self.messages(cx) {
             if message.status != MessageStatus::Done {
                 continue;
@@ -2199,6 +2677,7 @@ impl Context {
                     .cache
                     .as_ref()
                     .map_or(false, |cache| cache.is_anchor),
+                attachments: Vec::new(),
             };
 
             while let Some(content) = contents.peek() {
@@ -2227,12 +2706,16 @@ impl Context {
                                 .content
                                 .push(language_model::MessageContent::ToolUse(tool_use.clone()));
                         }
-                        Content::ToolResult { tool_use_id, .. } => {
+                        Content::ToolResult {
+                            tool_use_id,
+                            is_error,
+                            ..
+                        } => {
                             request_message.content.push(
                                 language_model::MessageContent::ToolResult(
                                     LanguageModelToolResult {
                                         tool_use_id: tool_use_id.to_string(),
-                                        is_error: false,
+                                        is_error,
                                         content: collect_text_content(buffer, range.clone())
                                             .unwrap_or_default(),
                                     },
@@ -2366,6 +2849,7 @@ impl Context {
                 status,
                 timestamp: anchor.id.0,
                 cache: None,
+                metrics: None,
             };
             self.insert_message(anchor.clone(), metadata.clone(), cx);
             self.push_op(
@@ -2461,6 +2945,7 @@ impl Context {
                 status: MessageStatus::Done,
                 timestamp: suffix.id.0,
                 cache: None,
+                metrics: None,
             };
             self.insert_message(suffix.clone(), suffix_metadata.clone(), cx);
             self.push_op(
@@ -2511,6 +2996,7 @@ impl Context {
                         status: MessageStatus::Done,
                         timestamp: selection.id.0,
                         cache: None,
+                        metrics: None,
                     };
                     self.insert_message(selection.clone(), selection_metadata.clone(), cx);
                     self.push_op(
@@ -2556,11 +3042,15 @@ impl Context {
         self.message_anchors.insert(insertion_ix, new_anchor);
     }
 
+    /// Titles the context using the provider's summary model, which runs in the background once
+    /// the first response completes. Pass `replace_old` to replace an existing title.
+    ///
+    /// Titling is best-effort: if it fails, the context keeps its current title.
     pub(super) fn summarize(&mut self, replace_old: bool, cx: &mut ModelContext<Self>) {
         let Some(provider) = LanguageModelRegistry::read_global(cx).active_provider() else {
             return;
         };
-        let Some(model) = LanguageModelRegistry::read_global(cx).active_model() else {
+        let Some(model) = LanguageModelRegistry::read_global(cx).summary_model(cx) else {
             return;
         };
 
@@ -2573,60 +3063,47 @@ impl Context {
             request.messages.push(LanguageModelRequestMessage {
                 role: Role::User,
                 content: vec![
-                    "Summarize the context into a short title without punctuation.".into(),
+                    "Summarize the context into a short title of 3 to 6 words, without punctuation."
+                        .into(),
                 ],
                 cache: false,
+                attachments: Vec::new(),
             });
+            request.stop = vec!["\n".into()];
+            request.temperature = Some(0.);
+            // A title of a few words, rather than however much the user's settings allow.
+            request.max_tokens = Some(16);
 
             self.pending_summary = cx.spawn(|this, mut cx| {
                 async move {
-                    let stream = model.stream_completion_text(request, &cx);
-                    let mut messages = stream.await?;
-
-                    let mut replaced = !replace_old;
-                    while let Some(message) = messages.next().await {
-                        let text = message?;
-                        let mut lines = text.lines();
-                        this.update(&mut cx, |this, cx| {
-                            let version = this.version.clone();
-                            let timestamp = this.next_timestamp();
-                            let summary = this.summary.get_or_insert(ContextSummary::default());
-                            if !replaced && replace_old {
-                                summary.text.clear();
-                                replaced = true;
-                            }
-                            summary.text.extend(lines.next());
-                            summary.timestamp = timestamp;
-                            let operation = ContextOperation::UpdateSummary {
-                                summary: summary.clone(),
-                                version,
-                            };
-                            this.push_op(operation, cx);
-                            cx.emit(ContextEvent::SummaryChanged);
-                        })?;
-
+                    let mut chunks = model.stream_completion_text(request, &cx).await?;
+                    let mut title = String::new();
+                    while let Some(chunk) = chunks.next().await {
+                        title.push_str(&chunk?);
                         // Stop if the LLM generated multiple lines.
-                        if lines.next().is_some() {
+                        if title.contains('\n') {
                             break;
                         }
                     }
+                    let title = title.lines().next().unwrap_or_default().trim().to_string();
+                    if title.is_empty() {
+                        return Err(anyhow!("the model didn't generate a title"));
+                    }
 
                     this.update(&mut cx, |this, cx| {
                         let version = this.version.clone();
                         let timestamp = this.next_timestamp();
-                        if let Some(summary) = this.summary.as_mut() {
-                            summary.done = true;
-                            summary.timestamp = timestamp;
-                            let operation = ContextOperation::UpdateSummary {
-                                summary: summary.clone(),
-                                version,
-                            };
-                            this.push_op(operation, cx);
-                            cx.emit(ContextEvent::SummaryChanged);
-                        }
-                    })?;
-
-                    anyhow::Ok(())
+                        let summary = this.summary.get_or_insert(ContextSummary::default());
+                        summary.text = title;
+                        summary.done = true;
+                        summary.timestamp = timestamp;
+                        let operation = ContextOperation::UpdateSummary {
+                            summary: summary.clone(),
+                            version,
+                        };
+                        this.push_op(operation, cx);
+                        cx.emit(ContextEvent::SummaryChanged);
+                    })
                 }
                 .log_err()
             });
@@ -2637,6 +3114,57 @@ impl Context {
         self.messages_for_offsets([offset], cx).pop()
     }
 
+    /// Returns the fenced code blocks in the given message, including one that's still streaming
+    /// in.
+    pub fn code_blocks(&self, message_id: MessageId, cx: &AppContext) -> Vec<ResponseCodeBlock> {
+        self.messages(cx)
+            .find(|message| message.id == message_id)
+            .map_or(Vec::new(), |message| {
+                self.code_blocks_for_message(&message, cx)
+            })
+    }
+
+    /// Returns the fenced code block whose content contains the given offset, if any.
+    pub fn code_block_for_offset(
+        &self,
+        offset: usize,
+        cx: &AppContext,
+    ) -> Option<ResponseCodeBlock> {
+        let message = self.message_for_offset(offset, cx)?;
+        let offset_in_message = offset.checked_sub(message.offset_range.start)?;
+        self.code_blocks_for_message(&message, cx)
+            .into_iter()
+            .find(|block| {
+                block.byte_range_in_message.start <= offset_in_message
+                    && offset_in_message <= block.byte_range_in_message.end
+            })
+    }
+
+    fn code_blocks_for_message(
+        &self,
+        message: &Message,
+        cx: &AppContext,
+    ) -> Vec<ResponseCodeBlock> {
+        let mut range = message.offset_range.clone();
+        // Every message but the last is followed by a newline that separates it from the next.
+        if message.anchor_range.end != language::Anchor::MAX {
+            range.end = range.end.saturating_sub(1).max(range.start);
+        }
+
+        // A streaming message's blocks are already parsed, unless it's been edited since.
+        if let Some(parser) = self.code_block_parsers.get(&message.id) {
+            if parser.text_len() == range.len() {
+                return parser.blocks();
+            }
+        }
+        let text = self
+            .buffer
+            .read(cx)
+            .text_for_range(range)
+            .collect::<String>();
+        parse_code_blocks(&text)
+    }
+
     pub fn messages_for_offsets(
         &self,
         offsets: impl IntoIterator<Item = usize>,
@@ -2718,6 +3246,7 @@ impl Context {
                     role: metadata.role,
                     status: metadata.status.clone(),
                     cache: metadata.cache.clone(),
+                    metrics: metadata.metrics,
                 });
             }
             None
@@ -2864,7 +3393,13 @@ pub struct PendingToolUse {
 #[derive(Debug, Clone)]
 pub enum PendingToolUseStatus {
     Idle,
-    Running { _task: Shared<Task<()>> },
+    /// The tool requires confirmation, and the user hasn't allowed or denied it yet.
+    AwaitingConfirmation,
+    Running {
+        _task: Shared<Task<()>>,
+    },
+    Finished,
+    /// The tool failed or wasn't allowed to run. The error was sent to the model as its result.
     Error(String),
 }
 
@@ -2872,6 +3407,27 @@ impl PendingToolUseStatus {
     pub fn is_idle(&self) -> bool {
         matches!(self, PendingToolUseStatus::Idle)
     }
+
+    pub fn is_awaiting_confirmation(&self) -> bool {
+        matches!(self, PendingToolUseStatus::AwaitingConfirmation)
+    }
+
+    /// Whether the tool use has a result, which includes errors.
+    pub fn is_done(&self) -> bool {
+        matches!(
+            self,
+            PendingToolUseStatus::Finished | PendingToolUseStatus::Error(_)
+        )
+    }
+}
+
+/// The user's answer to a tool that requires confirmation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToolUseConfirmation {
+    Allow,
+    /// Allows this use, and every later use of the same tool in this context.
+    AlwaysAllow,
+    Deny,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -2889,6 +3445,8 @@ pub struct SavedContext {
     pub text: String,
     pub messages: Vec<SavedMessage>,
     pub summary: String,
+    #[serde(default)]
+    pub sampling_overrides: SamplingParameters,
     pub slash_command_output_sections:
         Vec<assistant_slash_command::SlashCommandOutputSection<usize>>,
 }
@@ -2951,6 +3509,7 @@ impl SavedContext {
                         status: message.metadata.status,
                         timestamp: message.metadata.timestamp,
                         cache: None,
+                        metrics: None,
                     },
                     version: version.clone(),
                 });
@@ -2968,6 +3527,7 @@ impl SavedContext {
                     status: metadata.status,
                     timestamp,
                     cache: None,
+                    metrics: None,
                 },
                 version: version.clone(),
             });
@@ -3064,11 +3624,13 @@ impl SavedContextV0_3_0 {
                             status: metadata.status.clone(),
                             timestamp,
                             cache: None,
+                            metrics: None,
                         },
                     })
                 })
                 .collect(),
             summary: self.summary,
+            sampling_overrides: SamplingParameters::default(),
             slash_command_output_sections: self.slash_command_output_sections,
         }
     }
@@ -1,24 +1,34 @@
 use super::{MessageCacheMetadata, WorkflowStepEdit};
 use crate::{
-    assistant_panel, prompt_library, slash_command::file_command, CacheStatus, Context,
-    ContextEvent, ContextId, ContextOperation, MessageId, MessageStatus, PromptBuilder,
+    assistant_panel,
+    assistant_settings::{AssistantSettings, SamplingParameters},
+    build_request_preview, prompt_library,
+    slash_command::file_command,
+    CacheStatus, Content, Context, ContextEvent, ContextId, ContextOperation, ExportedContext,
+    MessageId, MessageStatus, PromptBuilder, PromptContext, ToolUseConfirmation,
     WorkflowStepEditKind,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use assistant_slash_command::{
     ArgumentCompletion, SlashCommand, SlashCommandOutput, SlashCommandOutputSection,
     SlashCommandRegistry,
 };
+use assistant_tool::{Tool, ToolRegistry};
 use collections::HashSet;
 use fs::FakeFs;
-use gpui::{AppContext, Model, SharedString, Task, TestAppContext, WeakView};
+use futures::FutureExt as _;
+use gpui::{AppContext, Model, RenderImage, SharedString, Task, TestAppContext, WeakView};
 use language::{Buffer, BufferSnapshot, LanguageRegistry, LspAdapterDelegate};
-use language_model::{LanguageModelCacheConfiguration, LanguageModelRegistry, Role};
+use language_model::{
+    provider::fake::FakeLanguageModelProvider, LanguageModelCacheConfiguration,
+    LanguageModelCompletionEvent, LanguageModelRegistry, LanguageModelToolUse, MessageContent,
+    RequestMetrics, Role, StopReason, TokenUsage, CONTINUE_PROMPT,
+};
 use parking_lot::Mutex;
 use project::Project;
 use rand::prelude::*;
 use serde_json::json;
-use settings::SettingsStore;
+use settings::{Settings as _, SettingsStore};
 use std::{
     cell::RefCell,
     env,
@@ -26,8 +36,9 @@ use std::{
     path::Path,
     rc::Rc,
     sync::{atomic::AtomicBool, Arc},
+    time::Duration,
 };
-use text::{network::Network, OffsetRangeExt as _, ReplicaId};
+use text::{network::Network, OffsetRangeExt as _, ReplicaId, ToOffset as _};
 use ui::{Context as _, WindowContext};
 use unindent::Unindent;
 use util::{
@@ -42,6 +53,7 @@ fn test_inserting_and_removing_messages(cx: &mut AppContext) {
     LanguageModelRegistry::test(cx);
     cx.set_global(settings_store);
     assistant_panel::init(cx);
+    AssistantSettings::register(cx);
     let registry = Arc::new(LanguageRegistry::test(cx.background_executor().clone()));
     let prompt_builder = Arc::new(PromptBuilder::new(None).unwrap());
     let context =
@@ -168,12 +180,86 @@ fn test_inserting_and_removing_messages(cx: &mut AppContext) {
     );
 }
 
+#[gpui::test]
+fn test_export_and_import(cx: &mut AppContext) {
+    let settings_store = SettingsStore::test(cx);
+    LanguageModelRegistry::test(cx);
+    cx.set_global(settings_store);
+    assistant_panel::init(cx);
+    AssistantSettings::register(cx);
+    let registry = Arc::new(LanguageRegistry::test(cx.background_executor().clone()));
+    let prompt_builder = Arc::new(PromptBuilder::new(None).unwrap());
+    let context =
+        cx.new_model(|cx| Context::local(registry.clone(), None, None, prompt_builder.clone(), cx));
+    let buffer = context.read(cx).buffer.clone();
+
+    let user_text = "Why does `let s = \"ü\";` print ```ü```? 🦀";
+    buffer.update(cx, |buffer, cx| buffer.edit([(0..0, user_text)], None, cx));
+    let user_message_id = context.read(cx).message_anchors[0].id;
+    context.update(cx, |context, cx| {
+        context
+            .insert_message_after(user_message_id, Role::Assistant, MessageStatus::Pending, cx)
+            .unwrap()
+    });
+    // The assistant's response is still streaming, in the middle of a code block.
+    let assistant_text = "It's UTF-8:\n\n```rust\nlet s = \"ü\";";
+    buffer.update(cx, |buffer, cx| {
+        let len = buffer.len();
+        buffer.edit([(len..len, assistant_text)], None, cx)
+    });
+
+    let exported = context.read(cx).export(cx);
+    assert_eq!(
+        exported
+            .messages
+            .iter()
+            .map(|message| (message.role, message.status.clone(), message.text.as_str()))
+            .collect::<Vec<_>>(),
+        [
+            (Role::User, MessageStatus::Done, user_text),
+            (Role::Assistant, MessageStatus::Pending, assistant_text),
+        ]
+    );
+    let exported = ExportedContext::from_json(&exported.to_json().unwrap()).unwrap();
+
+    let imported = cx.new_model(|cx| {
+        Context::deserialize(
+            exported.clone().into_saved_context(),
+            None,
+            registry,
+            prompt_builder,
+            None,
+            None,
+            cx,
+        )
+    });
+    assert_ne!(imported.read(cx).id(), context.read(cx).id());
+    assert_eq!(imported.read(cx).path(), None);
+    assert_eq!(
+        imported.read(cx).buffer.read(cx).text(),
+        buffer.read(cx).text()
+    );
+    let reexported = imported.read(cx).export(cx);
+    assert_eq!(
+        reexported
+            .messages
+            .iter()
+            .map(|message| (message.role, message.status.clone(), message.text.as_str()))
+            .collect::<Vec<_>>(),
+        [
+            (Role::User, MessageStatus::Done, user_text),
+            (Role::Assistant, MessageStatus::Canceled, assistant_text),
+        ]
+    );
+}
+
 #[gpui::test]
 fn test_message_splitting(cx: &mut AppContext) {
     let settings_store = SettingsStore::test(cx);
     cx.set_global(settings_store);
     LanguageModelRegistry::test(cx);
     assistant_panel::init(cx);
+    AssistantSettings::register(cx);
     let registry = Arc::new(LanguageRegistry::test(cx.background_executor().clone()));
 
     let prompt_builder = Arc::new(PromptBuilder::new(None).unwrap());
@@ -270,6 +356,7 @@ fn test_messages_for_offsets(cx: &mut AppContext) {
     LanguageModelRegistry::test(cx);
     cx.set_global(settings_store);
     assistant_panel::init(cx);
+    AssistantSettings::register(cx);
     let registry = Arc::new(LanguageRegistry::test(cx.background_executor().clone()));
     let prompt_builder = Arc::new(PromptBuilder::new(None).unwrap());
     let context =
@@ -357,6 +444,7 @@ async fn test_slash_commands(cx: &mut TestAppContext) {
     cx.update(LanguageModelRegistry::test);
     cx.update(Project::init_settings);
     cx.update(assistant_panel::init);
+    cx.update(AssistantSettings::register);
     let fs = FakeFs::new(cx.background_executor.clone());
 
     fs.insert_tree(
@@ -487,6 +575,7 @@ async fn test_workflow_step_parsing(cx: &mut TestAppContext) {
     cx.update(LanguageModelRegistry::test);
 
     cx.update(assistant_panel::init);
+    cx.update(AssistantSettings::register);
     let registry = Arc::new(LanguageRegistry::test(cx.executor()));
 
     // Create a new context
@@ -885,6 +974,7 @@ async fn test_serialization(cx: &mut TestAppContext) {
     cx.set_global(settings_store);
     cx.update(LanguageModelRegistry::test);
     cx.update(assistant_panel::init);
+    cx.update(AssistantSettings::register);
     let registry = Arc::new(LanguageRegistry::test(cx.executor()));
     let prompt_builder = Arc::new(PromptBuilder::new(None).unwrap());
     let context =
@@ -949,6 +1039,1009 @@ async fn test_serialization(cx: &mut TestAppContext) {
     );
 }
 
+#[gpui::test]
+fn test_sampling_overrides(cx: &mut AppContext) {
+    let settings_store = SettingsStore::test(cx);
+    LanguageModelRegistry::test(cx);
+    cx.set_global(settings_store);
+    assistant_panel::init(cx);
+    AssistantSettings::register(cx);
+    SettingsStore::update_global(cx, |store, cx| {
+        store
+            .set_user_settings(
+                r#"{"assistant": {"version": "2", "sampling": {"temperature": 0.5, "max_tokens": 100}}}"#,
+                cx,
+            )
+            .unwrap();
+    });
+    let registry = Arc::new(LanguageRegistry::test(cx.background_executor().clone()));
+    let prompt_builder = Arc::new(PromptBuilder::new(None).unwrap());
+    let context =
+        cx.new_model(|cx| Context::local(registry.clone(), None, None, prompt_builder.clone(), cx));
+
+    let request = context.read(cx).to_completion_request(cx);
+    assert_eq!(request.temperature, Some(0.5));
+    assert_eq!(request.top_p, None);
+    assert_eq!(request.max_tokens, Some(100));
+    assert!(request.stop.is_empty());
+
+    // Overrides take precedence, and the settings fill in the rest.
+    context.update(cx, |context, cx| {
+        context.set_sampling_overrides(
+            SamplingParameters {
+                temperature: Some(0.),
+                stop: Some(vec!["END".into()]),
+                ..Default::default()
+            },
+            cx,
+        )
+    });
+    let request = context.read(cx).to_completion_request(cx);
+    assert_eq!(request.temperature, Some(0.));
+    assert_eq!(request.max_tokens, Some(100));
+    assert_eq!(request.stop, ["END"]);
+
+    // Overrides are saved with the context.
+    let serialized_context = context.read(cx).serialize(cx);
+    let deserialized_context = cx.new_model(|cx| {
+        Context::deserialize(
+            serialized_context,
+            Default::default(),
+            registry,
+            prompt_builder,
+            None,
+            None,
+            cx,
+        )
+    });
+    assert_eq!(
+        deserialized_context.read(cx).sampling_overrides(),
+        context.read(cx).sampling_overrides()
+    );
+}
+
+#[gpui::test]
+async fn test_workspace_settings_overrides(cx: &mut TestAppContext) {
+    let settings_store = cx.update(SettingsStore::test);
+    cx.set_global(settings_store);
+    cx.update(language::init);
+    cx.update(Project::init_settings);
+    cx.update(LanguageModelRegistry::test);
+    cx.update(assistant_panel::init);
+    cx.update(AssistantSettings::register);
+    let provider_a = FakeLanguageModelProvider::with_id("provider-a");
+    let provider_b = FakeLanguageModelProvider::with_id("provider-b");
+    cx.update(|cx| {
+        LanguageModelRegistry::global(cx).update(cx, |registry, cx| {
+            registry.register_provider(provider_a.clone(), cx);
+            registry.register_provider(provider_b.clone(), cx);
+        });
+        SettingsStore::update_global(cx, |store, cx| {
+            store
+                .set_user_settings(
+                    r#"{"assistant": {"version": "2", "sampling": {"temperature": 0.5, "max_tokens": 100}}}"#,
+                    cx,
+                )
+                .unwrap();
+        });
+    });
+
+    let fs = FakeFs::new(cx.executor());
+    fs.insert_tree(
+        "/project-a",
+        json!({
+            ".zed": {
+                "settings.json": r#"{"assistant": {
+                    "version": "2",
+                    "default_model": {"provider": "provider-a", "model": "fake"},
+                    "prompt_templates": {"review": "You review code for project A."},
+                    "default_prompt_template": "review",
+                    "sampling": {"temperature": 0.1, "max_tokens": 10},
+                    "debug_logging": {"enabled": true}
+                }}"#,
+            },
+        }),
+    )
+    .await;
+    fs.insert_tree(
+        "/project-b",
+        json!({
+            ".zed": {
+                "settings.json": r#"{"assistant": {
+                    "version": "2",
+                    "default_model": {"provider": "provider-b", "model": "fake"},
+                    "prompt_templates": {"terse": "Answer in one sentence."},
+                    "default_prompt_template": "terse"
+                }}"#,
+            },
+        }),
+    )
+    .await;
+    let project_a = Project::test(fs.clone(), [Path::new("/project-a")], cx).await;
+    let project_b = Project::test(fs.clone(), [Path::new("/project-b")], cx).await;
+    cx.run_until_parked();
+
+    // Credential and other user-only fields in workspace settings are ignored.
+    cx.update(|cx| {
+        let settings = AssistantSettings::for_project(Some(&project_a), cx);
+        assert!(!settings.debug_logging.enabled);
+        assert_eq!(settings.sampling.max_tokens, Some(100));
+    });
+
+    let registry = Arc::new(LanguageRegistry::test(cx.executor()));
+    let prompt_builder = Arc::new(PromptBuilder::new(None).unwrap());
+    let cases = [
+        (
+            Some(project_a),
+            provider_a.fake_model().unwrap(),
+            "You review code for project A.",
+            Some(0.1),
+        ),
+        (
+            Some(project_b),
+            provider_b.fake_model().unwrap(),
+            "Answer in one sentence.",
+            Some(0.5),
+        ),
+    ];
+    for (project, model, system_prompt, temperature) in cases {
+        let context = cx.new_model(|cx| {
+            Context::local(registry.clone(), project, None, prompt_builder.clone(), cx)
+        });
+        context.update(cx, |context, cx| {
+            context
+                .buffer
+                .update(cx, |buffer, cx| buffer.edit([(0..0, "Hello")], None, cx));
+            context.assist(cx).unwrap();
+        });
+        cx.run_until_parked();
+
+        let request = model.pending_completions().pop().unwrap();
+        assert_eq!(request.temperature, temperature);
+        assert_eq!(request.max_tokens, Some(100));
+        assert_eq!(request.messages.len(), 2);
+        assert_eq!(request.messages[0].role, Role::System);
+        assert_eq!(request.messages[0].string_contents(), system_prompt);
+        assert_eq!(request.messages[1].role, Role::User);
+    }
+
+    // Contexts outside of a project use the user's settings.
+    let model = cx.update(|cx| {
+        LanguageModelRegistry::read_global(cx)
+            .active_model()
+            .unwrap()
+    });
+    let context =
+        cx.new_model(|cx| Context::local(registry.clone(), None, None, prompt_builder.clone(), cx));
+    context.update(cx, |context, cx| {
+        context
+            .buffer
+            .update(cx, |buffer, cx| buffer.edit([(0..0, "Hello")], None, cx));
+        context.assist(cx).unwrap();
+    });
+    cx.run_until_parked();
+    let request = model.as_fake().pending_completions().pop().unwrap();
+    assert_eq!(request.temperature, Some(0.5));
+    assert_eq!(request.messages.len(), 1);
+    assert_eq!(request.messages[0].role, Role::User);
+}
+
+#[gpui::test]
+fn test_prompt_context(cx: &mut AppContext) {
+    let settings_store = SettingsStore::test(cx);
+    LanguageModelRegistry::test(cx);
+    cx.set_global(settings_store);
+    assistant_panel::init(cx);
+    AssistantSettings::register(cx);
+    SettingsStore::update_global(cx, |store, cx| {
+        store
+            .set_user_settings(
+                r#"{"assistant": {
+                    "version": "2",
+                    "prompt_templates": {"editing": "Editing {file_path} ({language}): {selection}"},
+                    "default_prompt_template": "editing"
+                }}"#,
+                cx,
+            )
+            .unwrap();
+    });
+    let registry = Arc::new(LanguageRegistry::test(cx.background_executor().clone()));
+    let prompt_builder = Arc::new(PromptBuilder::new(None).unwrap());
+    let context = cx.new_model(|cx| Context::local(registry, None, None, prompt_builder, cx));
+
+    // Without an editor to describe, the placeholders are left empty.
+    let request = context.read(cx).to_completion_request(cx);
+    assert_eq!(request.messages[0].role, Role::System);
+    assert_eq!(request.messages[0].string_contents(), "Editing  (): ");
+
+    context.update(cx, |context, _| {
+        context.set_prompt_context(PromptContext {
+            language: Some("Rust".into()),
+            file_path: Some("project/src/main.rs".into()),
+            selection: Some("fn main() {}".into()),
+        })
+    });
+    let request = context.read(cx).to_completion_request(cx);
+    assert_eq!(
+        request.messages[0].string_contents(),
+        "Editing project/src/main.rs (Rust): fn main() {}"
+    );
+}
+
+#[gpui::test]
+async fn test_title_generation(cx: &mut TestAppContext) {
+    let settings_store = cx.update(SettingsStore::test);
+    cx.set_global(settings_store);
+    let fake_provider = cx.update(LanguageModelRegistry::test);
+    cx.update(assistant_panel::init);
+    cx.update(AssistantSettings::register);
+    let model = cx.update(|cx| {
+        LanguageModelRegistry::read_global(cx)
+            .active_model()
+            .unwrap()
+    });
+    let summary_model = fake_provider.fake_summary_model();
+    let registry = Arc::new(LanguageRegistry::test(cx.executor()));
+    let prompt_builder = Arc::new(PromptBuilder::new(None).unwrap());
+    let context_a =
+        cx.new_model(|cx| Context::local(registry.clone(), None, None, prompt_builder.clone(), cx));
+    let context_b =
+        cx.new_model(|cx| Context::local(registry.clone(), None, None, prompt_builder.clone(), cx));
+    let title = |context: &Model<Context>, cx: &mut TestAppContext| {
+        context.read_with(cx, |context, _| {
+            context.summary().map(|summary| summary.text.clone())
+        })
+    };
+
+    // Each context requests a title from the summary model once its first response completes.
+    for (context, text) in [(&context_a, "Hello"), (&context_b, "Goodbye")] {
+        context.update(cx, |context, cx| {
+            context
+                .buffer
+                .update(cx, |buffer, cx| buffer.edit([(0..0, text)], None, cx));
+            context.assist(cx).unwrap();
+        });
+        cx.run_until_parked();
+        model
+            .as_fake()
+            .stream_last_completion_response("Hi!".into());
+        model.as_fake().end_last_completion_stream();
+        cx.run_until_parked();
+    }
+    let title_requests = summary_model.pending_completions();
+    assert_eq!(title_requests.len(), 2);
+    assert_eq!(title_requests[0].temperature, Some(0.));
+    assert_eq!(title_requests[0].max_tokens, Some(16));
+    assert!(title_requests[0]
+        .messages
+        .last()
+        .unwrap()
+        .string_contents()
+        .contains("short title"));
+
+    // Titles land on the context they were requested for, whichever order they arrive in.
+    summary_model.stream_completion_response(&title_requests[1], "Saying goodbye".into());
+    summary_model.end_completion_stream(&title_requests[1]);
+    cx.run_until_parked();
+    assert_eq!(title(&context_a, cx), None);
+    assert_eq!(title(&context_b, cx), Some("Saying goodbye".into()));
+
+    // A failed request leaves the context untitled.
+    summary_model.send_completion_error(&title_requests[0], anyhow::anyhow!("overloaded"));
+    summary_model.end_completion_stream(&title_requests[0]);
+    cx.run_until_parked();
+    assert_eq!(title(&context_a, cx), None);
+
+    // The title can be regenerated explicitly, keeping only its first line.
+    context_a.update(cx, |context, cx| context.summarize(true, cx));
+    cx.run_until_parked();
+    summary_model.stream_last_completion_response("Greeting the assistant\nBecause".into());
+    summary_model.end_last_completion_stream();
+    cx.run_until_parked();
+    assert_eq!(title(&context_a, cx), Some("Greeting the assistant".into()));
+
+    // Failing to regenerate the title keeps the previous one.
+    context_a.update(cx, |context, cx| context.summarize(true, cx));
+    cx.run_until_parked();
+    summary_model.send_last_completion_error(anyhow::anyhow!("overloaded"));
+    summary_model.end_last_completion_stream();
+    cx.run_until_parked();
+    assert_eq!(title(&context_a, cx), Some("Greeting the assistant".into()));
+    assert_eq!(title(&context_b, cx), Some("Saying goodbye".into()));
+}
+
+#[gpui::test]
+async fn test_regenerate_while_streaming(cx: &mut TestAppContext) {
+    let settings_store = cx.update(SettingsStore::test);
+    cx.set_global(settings_store);
+    cx.update(LanguageModelRegistry::test);
+    cx.update(assistant_panel::init);
+    cx.update(AssistantSettings::register);
+    let model = cx.update(|cx| {
+        LanguageModelRegistry::read_global(cx)
+            .active_model()
+            .unwrap()
+    });
+    let registry = Arc::new(LanguageRegistry::test(cx.executor()));
+    let prompt_builder = Arc::new(PromptBuilder::new(None).unwrap());
+    let context = cx.new_model(|cx| Context::local(registry, None, None, prompt_builder, cx));
+
+    context.update(cx, |context, cx| {
+        context
+            .buffer
+            .update(cx, |buffer, cx| buffer.edit([(0..0, "Hello")], None, cx));
+        context.assist(cx).unwrap();
+    });
+    cx.run_until_parked();
+    model
+        .as_fake()
+        .stream_last_completion_response("Hi!".into());
+    model.as_fake().end_last_completion_stream();
+    cx.run_until_parked();
+    context.update(cx, |context, cx| {
+        let last_message_id = context.messages(cx).last().unwrap().id;
+        context.buffer.update(cx, |buffer, cx| {
+            buffer.edit([(buffer.len()..buffer.len(), "Tell me a joke")], None, cx)
+        });
+        context.assist(cx).unwrap();
+        assert_eq!(context.messages(cx).nth(2).unwrap().id, last_message_id);
+    });
+    cx.run_until_parked();
+    model
+        .as_fake()
+        .stream_last_completion_response("Why did".into());
+    cx.run_until_parked();
+    let message_ids = cx
+        .read(|cx| messages(&context, cx))
+        .into_iter()
+        .map(|(id, _, _)| id)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        cx.read(|cx| context.read(cx).buffer.read(cx).text()),
+        "Hello\nHi!\nTell me a joke\nWhy did\n"
+    );
+
+    // Regenerating the first reply cancels the one that's streaming, and streams the new reply
+    // in its place.
+    context.update(cx, |context, cx| {
+        context.regenerate_from(message_ids[1], cx).unwrap();
+    });
+    cx.run_until_parked();
+    let request = model.as_fake().pending_completions().pop().unwrap();
+    assert_eq!(
+        request
+            .messages
+            .iter()
+            .map(|message| message.string_contents())
+            .collect::<Vec<_>>(),
+        ["Hello\n"]
+    );
+    model
+        .as_fake()
+        .stream_completion_response(&request, "Hello there!".into());
+    model.as_fake().end_completion_stream(&request);
+    cx.run_until_parked();
+
+    context.read_with(cx, |context, cx| {
+        assert_eq!(context.buffer.read(cx).text(), "Hello\nHello there!\n");
+        let messages = context
+            .messages(cx)
+            .map(|message| (message.id, message.role, message.status))
+            .collect::<Vec<_>>();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(
+            messages[0],
+            (message_ids[0], Role::User, MessageStatus::Done)
+        );
+        assert_eq!(
+            messages[1],
+            (message_ids[1], Role::Assistant, MessageStatus::Done)
+        );
+        assert_eq!(messages[2].1, Role::User);
+        assert_ne!(messages[2].0, message_ids[2]);
+    });
+}
+
+#[gpui::test]
+async fn test_code_blocks_while_streaming(cx: &mut TestAppContext) {
+    let settings_store = cx.update(SettingsStore::test);
+    cx.set_global(settings_store);
+    cx.update(LanguageModelRegistry::test);
+    cx.update(assistant_panel::init);
+    cx.update(AssistantSettings::register);
+    let model = cx.update(|cx| {
+        LanguageModelRegistry::read_global(cx)
+            .active_model()
+            .unwrap()
+    });
+    let registry = Arc::new(LanguageRegistry::test(cx.executor()));
+    let prompt_builder = Arc::new(PromptBuilder::new(None).unwrap());
+    let context = cx.new_model(|cx| Context::local(registry, None, None, prompt_builder, cx));
+
+    context.update(cx, |context, cx| {
+        context
+            .buffer
+            .update(cx, |buffer, cx| buffer.edit([(0..0, "Hello")], None, cx));
+        context.assist(cx).unwrap();
+    });
+    cx.run_until_parked();
+    let assistant_message_id = cx.read(|cx| context.read(cx).messages(cx).nth(1).unwrap().id);
+    let code_blocks = |cx: &TestAppContext| {
+        cx.read(|cx| {
+            context
+                .read(cx)
+                .code_blocks(assistant_message_id, cx)
+                .into_iter()
+                .map(|block| (block.language, block.content))
+                .collect::<Vec<_>>()
+        })
+    };
+
+    model
+        .as_fake()
+        .stream_last_completion_response("Sure:\n```rust\nfn main() {\n``".into());
+    cx.run_until_parked();
+    assert_eq!(
+        code_blocks(cx),
+        [(Some("rust".to_string()), "fn main() {".to_string())]
+    );
+
+    model
+        .as_fake()
+        .stream_last_completion_response("`\n```\nplain\n".into());
+    cx.run_until_parked();
+    assert_eq!(
+        code_blocks(cx),
+        [
+            (Some("rust".to_string()), "fn main() {".to_string()),
+            (None, "plain".to_string())
+        ]
+    );
+
+    // Once the completion is done, blocks are parsed from the message's text, which reflects
+    // edits to it.
+    model.as_fake().end_last_completion_stream();
+    cx.run_until_parked();
+    context.update(cx, |context, cx| {
+        context.buffer.update(cx, |buffer, cx| {
+            let offset = buffer.text().find("plain").unwrap();
+            buffer.edit([(offset..offset + "plain".len(), "edited")], None, cx)
+        });
+        let offset = context.buffer.read(cx).text().find("edited").unwrap();
+        let block = context.code_block_for_offset(offset, cx).unwrap();
+        assert_eq!(block.content, "edited");
+        let message_start = context.messages(cx).nth(1).unwrap().offset_range.start;
+        assert_eq!(
+            block.byte_range_in_message,
+            offset - message_start..offset - message_start + "edited".len()
+        );
+    });
+    assert_eq!(
+        code_blocks(cx),
+        [
+            (Some("rust".to_string()), "fn main() {".to_string()),
+            (None, "edited".to_string())
+        ]
+    );
+}
+
+#[gpui::test]
+async fn test_stop_reasons(cx: &mut TestAppContext) {
+    let settings_store = cx.update(SettingsStore::test);
+    cx.set_global(settings_store);
+    cx.update(LanguageModelRegistry::test);
+    cx.update(assistant_panel::init);
+    cx.update(AssistantSettings::register);
+    let model = cx.update(|cx| {
+        LanguageModelRegistry::read_global(cx)
+            .active_model()
+            .unwrap()
+    });
+    let registry = Arc::new(LanguageRegistry::test(cx.executor()));
+    let prompt_builder = Arc::new(PromptBuilder::new(None).unwrap());
+    let context = cx.new_model(|cx| Context::local(registry, None, None, prompt_builder, cx));
+
+    let type_and_assist = |cx: &mut TestAppContext| {
+        context.update(cx, |context, cx| {
+            context.buffer.update(cx, |buffer, cx| {
+                let len = buffer.len();
+                buffer.edit([(len..len, "Hello")], None, cx)
+            });
+            context.assist(cx).unwrap();
+            let messages = context.messages(cx).collect::<Vec<_>>();
+            messages[messages.len() - 2].id
+        })
+    };
+    let respond = |text: &str, stop_reason: StopReason, cx: &mut TestAppContext| {
+        cx.run_until_parked();
+        model.as_fake().stream_last_completion_response(text.into());
+        model
+            .as_fake()
+            .send_last_completion_event(LanguageModelCompletionEvent::Stop(stop_reason));
+        model.as_fake().end_last_completion_stream();
+        cx.run_until_parked();
+    };
+    let stop_state = |message_id: MessageId, cx: &TestAppContext| {
+        cx.read(|cx| {
+            let context = context.read(cx);
+            (
+                context.stop_reason(message_id).cloned(),
+                context.can_continue_message(message_id, cx),
+            )
+        })
+    };
+
+    for stop_reason in [
+        StopReason::EndTurn,
+        StopReason::ContentFilter,
+        StopReason::Unknown("recitation".into()),
+    ] {
+        let message_id = type_and_assist(cx);
+        respond("Hi", stop_reason.clone(), cx);
+        assert_eq!(stop_state(message_id, cx), (Some(stop_reason), false));
+    }
+
+    let message_id = type_and_assist(cx);
+    respond("The first half", StopReason::MaxTokens, cx);
+    assert_eq!(
+        stop_state(message_id, cx),
+        (Some(StopReason::MaxTokens), true)
+    );
+
+    // Only the last message with any text can be continued.
+    let len = cx.read(|cx| context.read(cx).buffer.read(cx).len());
+    context.update(cx, |context, cx| {
+        context
+            .buffer
+            .update(cx, |buffer, cx| buffer.edit([(len..len, "Next")], None, cx));
+    });
+    assert_eq!(
+        stop_state(message_id, cx),
+        (Some(StopReason::MaxTokens), false)
+    );
+    context.update(cx, |context, cx| {
+        context
+            .buffer
+            .update(cx, |buffer, cx| buffer.edit([(len..len + 4, "")], None, cx));
+        assert!(context.continue_message(message_id, cx));
+    });
+    cx.run_until_parked();
+    assert_eq!(stop_state(message_id, cx), (None, false));
+    let request = model.as_fake().pending_completions().pop().unwrap();
+    let [.., response, continue_prompt] = request.messages.as_slice() else {
+        panic!("expected a continuation request");
+    };
+    assert_eq!(response.role, Role::Assistant);
+    assert_eq!(response.string_contents().trim_end(), "The first half");
+    assert_eq!(continue_prompt.role, Role::User);
+    assert_eq!(continue_prompt.string_contents(), CONTINUE_PROMPT);
+
+    respond(" and the second.", StopReason::EndTurn, cx);
+    assert_eq!(
+        stop_state(message_id, cx),
+        (Some(StopReason::EndTurn), false)
+    );
+    cx.read(|cx| {
+        let context = context.read(cx);
+        let message = context
+            .messages(cx)
+            .find(|message| message.id == message_id)
+            .unwrap();
+        assert_eq!(message.status, MessageStatus::Done);
+        assert_eq!(
+            context
+                .buffer
+                .read(cx)
+                .text_for_range(message.offset_range)
+                .collect::<String>(),
+            "The first half and the second.\n"
+        );
+    });
+}
+
+#[gpui::test]
+async fn test_request_metrics(cx: &mut TestAppContext) {
+    let settings_store = cx.update(SettingsStore::test);
+    cx.set_global(settings_store);
+    cx.update(LanguageModelRegistry::test);
+    cx.update(assistant_panel::init);
+    cx.update(AssistantSettings::register);
+    let model = cx.update(|cx| {
+        LanguageModelRegistry::read_global(cx)
+            .active_model()
+            .unwrap()
+    });
+    let registry = Arc::new(LanguageRegistry::test(cx.executor()));
+    let prompt_builder = Arc::new(PromptBuilder::new(None).unwrap());
+    let context = cx.new_model(|cx| Context::local(registry, None, None, prompt_builder, cx));
+
+    let message_id = context.update(cx, |context, cx| {
+        context
+            .buffer
+            .update(cx, |buffer, cx| buffer.edit([(0..0, "Hello")], None, cx));
+        context.assist(cx).unwrap();
+        let messages = context.messages(cx).collect::<Vec<_>>();
+        messages[messages.len() - 2].id
+    });
+    let metrics = |cx: &TestAppContext| {
+        cx.read(|cx| {
+            context
+                .read(cx)
+                .messages(cx)
+                .find(|message| message.id == message_id)
+                .unwrap()
+                .metrics
+        })
+    };
+
+    cx.run_until_parked();
+    let fake_model = model.as_fake();
+    fake_model.send_last_completion_event(LanguageModelCompletionEvent::Queued(
+        Duration::from_millis(150),
+    ));
+    cx.executor().advance_clock(Duration::from_millis(400));
+    fake_model.stream_last_completion_response("Hello".into());
+    cx.run_until_parked();
+    cx.executor().advance_clock(Duration::from_millis(250));
+    fake_model.stream_last_completion_response(", world!".into());
+    fake_model.send_last_completion_event(LanguageModelCompletionEvent::UsageUpdate(TokenUsage {
+        prompt_tokens: 20,
+        completion_tokens: 3,
+    }));
+    cx.run_until_parked();
+    assert_eq!(metrics(cx), None);
+
+    cx.executor().advance_clock(Duration::from_millis(100));
+    fake_model.end_last_completion_stream();
+    cx.run_until_parked();
+    assert_eq!(
+        metrics(cx),
+        Some(RequestMetrics {
+            queue_time: Some(Duration::from_millis(150)),
+            time_to_first_chunk: Some(Duration::from_millis(400)),
+            total_duration: Duration::from_millis(750),
+            chunk_count: 2,
+            bytes_received: "Hello, world!".len(),
+            output_tokens: Some(3),
+        })
+    );
+}
+
+#[gpui::test]
+async fn test_tool_use_rounds(cx: &mut TestAppContext) {
+    let settings_store = cx.update(SettingsStore::test);
+    cx.set_global(settings_store);
+    cx.update(LanguageModelRegistry::test);
+    cx.update(assistant_panel::init);
+    cx.update(AssistantSettings::register);
+    cx.update(|cx| {
+        let tool_registry = ToolRegistry::default_global(cx);
+        tool_registry.register_tool(FakeTool::new("read_file", false));
+        tool_registry.register_tool(FakeTool::new("delete_file", true));
+    });
+    let model = cx.update(|cx| {
+        LanguageModelRegistry::read_global(cx)
+            .active_model()
+            .unwrap()
+    });
+    let registry = Arc::new(LanguageRegistry::test(cx.executor()));
+    let prompt_builder = Arc::new(PromptBuilder::new(None).unwrap());
+    let context = cx.new_model(|cx| Context::local(registry, None, None, prompt_builder, cx));
+
+    context.update(cx, |context, cx| {
+        context.buffer.update(cx, |buffer, cx| {
+            buffer.edit([(0..0, "Clean up the temp files")], None, cx)
+        });
+        context.assist(cx).unwrap();
+    });
+    let respond = |text: &str, tool_uses: &[(&str, &str)], cx: &mut TestAppContext| {
+        cx.run_until_parked();
+        assert_eq!(model.as_fake().pending_completions().len(), 1);
+        model.as_fake().stream_last_completion_response(text.into());
+        for (id, name) in tool_uses {
+            model
+                .as_fake()
+                .send_last_completion_event(LanguageModelCompletionEvent::ToolUse(
+                    LanguageModelToolUse {
+                        id: id.to_string(),
+                        name: name.to_string(),
+                        input: json!({ "path": "tmp" }),
+                    },
+                ));
+        }
+        let stop_reason = if tool_uses.is_empty() {
+            StopReason::EndTurn
+        } else {
+            StopReason::ToolUse
+        };
+        model
+            .as_fake()
+            .send_last_completion_event(LanguageModelCompletionEvent::Stop(stop_reason));
+        model.as_fake().end_last_completion_stream();
+        cx.run_until_parked();
+    };
+    let take_tool_uses_to_run = |cx: &mut TestAppContext| {
+        context.update(cx, |context, cx| {
+            context
+                .take_tool_uses_to_run(cx)
+                .into_iter()
+                .map(|tool_use| tool_use.id.to_string())
+                .collect::<Vec<_>>()
+        })
+    };
+    let insert_tool_output = |id: &str, output: Result<String>, cx: &mut TestAppContext| {
+        context.update(cx, |context, cx| {
+            context.insert_tool_output(id.into(), Task::ready(output), cx)
+        });
+    };
+    let confirm_tool_use =
+        |id: &str, confirmation: ToolUseConfirmation, cx: &mut TestAppContext| {
+            context.update(cx, |context, cx| {
+                context
+                    .confirm_tool_use(&id.into(), confirmation, cx)
+                    .into_iter()
+                    .map(|tool_use| tool_use.id.to_string())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+    // Tools that don't require confirmation run right away, and their errors are sent back to
+    // the model as results, along with the denial of a tool that does require confirmation.
+    respond(
+        "Let me look.",
+        &[("1", "read_file"), ("2", "delete_file")],
+        cx,
+    );
+    assert_eq!(take_tool_uses_to_run(cx), ["1"]);
+    assert!(context.read_with(cx, |context, _| {
+        context
+            .get_tool_use_by_id(&"2".into())
+            .unwrap()
+            .status
+            .is_awaiting_confirmation()
+    }));
+    insert_tool_output("1", Err(anyhow!("tmp doesn't exist")), cx);
+    cx.run_until_parked();
+    assert!(model.as_fake().pending_completions().is_empty());
+    assert_eq!(
+        confirm_tool_use("2", ToolUseConfirmation::Deny, cx),
+        Vec::<String>::new()
+    );
+
+    // Always allowing a tool allows its other uses too.
+    respond(
+        "Let me try again.",
+        &[("3", "delete_file"), ("4", "delete_file")],
+        cx,
+    );
+    assert_eq!(take_tool_uses_to_run(cx), Vec::<String>::new());
+    assert_eq!(
+        confirm_tool_use("3", ToolUseConfirmation::AlwaysAllow, cx),
+        ["3", "4"]
+    );
+    insert_tool_output("3", Ok("Deleted tmp/a".into()), cx);
+    insert_tool_output("4", Ok("Deleted tmp/b".into()), cx);
+
+    let request = model.as_fake().pending_completions().pop().unwrap();
+    let history = request
+        .messages
+        .iter()
+        .map(|message| {
+            let contents = message
+                .content
+                .iter()
+                .map(|content| match content {
+                    MessageContent::Text(text) => text.trim().to_string(),
+                    MessageContent::ToolUse(tool_use) => {
+                        format!("use {} {}", tool_use.id, tool_use.name)
+                    }
+                    MessageContent::ToolResult(result) => format!(
+                        "result {} (error: {}): {}",
+                        result.tool_use_id, result.is_error, result.content
+                    ),
+                    MessageContent::Image(_) => unreachable!(),
+                })
+                .collect::<Vec<_>>();
+            (message.role, contents)
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        history,
+        [
+            (Role::User, vec!["Clean up the temp files".to_string()]),
+            (
+                Role::Assistant,
+                vec![
+                    "Let me look.".into(),
+                    "use 1 read_file".into(),
+                    "use 2 delete_file".into(),
+                ]
+            ),
+            (
+                Role::User,
+                vec![
+                    "result 1 (error: true): tmp doesn't exist".into(),
+                    "result 2 (error: true): The user didn't allow this tool to run.".into(),
+                ]
+            ),
+            (
+                Role::Assistant,
+                vec![
+                    "Let me try again.".into(),
+                    "use 3 delete_file".into(),
+                    "use 4 delete_file".into(),
+                ]
+            ),
+            (
+                Role::User,
+                vec![
+                    "result 3 (error: false): Deleted tmp/a".into(),
+                    "result 4 (error: false): Deleted tmp/b".into(),
+                ]
+            ),
+        ]
+    );
+
+    // The loop ends when the model stops using tools.
+    respond("Done.", &[], cx);
+    assert!(model.as_fake().pending_completions().is_empty());
+
+    // A model that keeps using tools is stopped after the configured number of rounds.
+    cx.update(|cx| {
+        SettingsStore::update_global(cx, |store, cx| {
+            store
+                .set_user_settings(
+                    r#"{"assistant": {"version": "2", "max_tool_use_rounds": 1}}"#,
+                    cx,
+                )
+                .unwrap();
+        });
+    });
+    let errors = Rc::new(RefCell::new(Vec::new()));
+    context.update(cx, |_, cx| {
+        cx.subscribe(&context, {
+            let errors = errors.clone();
+            move |_, _, event, _| {
+                if let ContextEvent::ShowAssistError(error) = event {
+                    errors.borrow_mut().push(error.clone());
+                }
+            }
+        })
+        .detach();
+    });
+    context.update(cx, |context, cx| context.assist(cx).unwrap());
+    respond("Let me check.", &[("5", "read_file")], cx);
+    assert_eq!(take_tool_uses_to_run(cx), ["5"]);
+    insert_tool_output("5", Ok("tmp/c".into()), cx);
+    respond("Let me check again.", &[("6", "read_file")], cx);
+    assert_eq!(take_tool_uses_to_run(cx), ["6"]);
+    insert_tool_output("6", Ok("tmp/c".into()), cx);
+    cx.run_until_parked();
+    assert!(model.as_fake().pending_completions().is_empty());
+    assert_eq!(
+        *errors.borrow(),
+        [SharedString::from(
+            "Stopped after 1 rounds of tool use. Assist again to let the model continue."
+        )]
+    );
+}
+
+#[gpui::test]
+async fn test_request_preview(cx: &mut TestAppContext) {
+    let settings_store = cx.update(SettingsStore::test);
+    cx.set_global(settings_store);
+    cx.update(LanguageModelRegistry::test);
+    cx.update(assistant_panel::init);
+    cx.update(AssistantSettings::register);
+    let model = cx.update(|cx| {
+        LanguageModelRegistry::read_global(cx)
+            .active_model()
+            .unwrap()
+    });
+    let registry = Arc::new(LanguageRegistry::test(cx.executor()));
+    let prompt_builder = Arc::new(PromptBuilder::new(None).unwrap());
+    let context = cx.new_model(|cx| Context::local(registry, None, None, prompt_builder, cx));
+
+    context.update(cx, |context, cx| {
+        context.buffer.update(cx, |buffer, cx| {
+            buffer.edit([(0..0, "Hello there")], None, cx)
+        });
+        context.assist(cx).unwrap();
+    });
+    cx.run_until_parked();
+    model
+        .as_fake()
+        .stream_last_completion_response("Hi!".into());
+    model.as_fake().end_last_completion_stream();
+    cx.run_until_parked();
+    context.update(cx, |context, cx| {
+        context.buffer.update(cx, |buffer, cx| {
+            buffer.edit([(buffer.len()..buffer.len(), "Tell me a joke")], None, cx)
+        });
+    });
+
+    let preview = cx
+        .update(|cx| build_request_preview(&context, cx))
+        .await
+        .unwrap();
+    assert_eq!(preview.message_token_counts, [2, 1, 4]);
+    assert!(preview.omitted_messages.is_empty());
+    assert!(preview
+        .to_markdown()
+        .contains("## User (4 tokens)\n\nTell me a joke\n"));
+
+    // The preview is exactly the request that assisting sends.
+    context.update(cx, |context, cx| {
+        context.assist(cx).unwrap();
+    });
+    cx.run_until_parked();
+    let request = model.as_fake().pending_completions().pop().unwrap();
+    assert_eq!(preview.request, request);
+    assert_eq!(
+        serde_json::to_string(&preview.request).unwrap(),
+        serde_json::to_string(&request).unwrap()
+    );
+}
+
+#[gpui::test]
+async fn test_edit_message_with_image(cx: &mut TestAppContext) {
+    let settings_store = cx.update(SettingsStore::test);
+    cx.set_global(settings_store);
+    cx.update(LanguageModelRegistry::test);
+    cx.update(assistant_panel::init);
+    cx.update(AssistantSettings::register);
+    let model = cx.update(|cx| {
+        LanguageModelRegistry::read_global(cx)
+            .active_model()
+            .unwrap()
+    });
+    let registry = Arc::new(LanguageRegistry::test(cx.executor()));
+    let prompt_builder = Arc::new(PromptBuilder::new(None).unwrap());
+    let context = cx.new_model(|cx| Context::local(registry, None, None, prompt_builder, cx));
+
+    context.update(cx, |context, cx| {
+        context.buffer.update(cx, |buffer, cx| {
+            buffer.edit([(0..0, "Look\nat this")], None, cx)
+        });
+        let anchor = context.buffer.read(cx).anchor_before(5);
+        context.insert_content(
+            Content::Image {
+                anchor,
+                image_id: 1,
+                render_image: Arc::new(RenderImage::new(Vec::new())),
+                image: Task::ready(None).shared(),
+            },
+            cx,
+        );
+        context.assist(cx).unwrap();
+    });
+    cx.run_until_parked();
+    model
+        .as_fake()
+        .stream_last_completion_response("Nice".into());
+    model.as_fake().end_last_completion_stream();
+    cx.run_until_parked();
+    let message_1 = cx.read(|cx| messages(&context, cx))[0].0;
+
+    // The message's text is replaced, but the image is kept ahead of it.
+    context.update(cx, |context, cx| {
+        context
+            .edit_message(message_1, "What is this?", cx)
+            .unwrap();
+        assert_eq!(context.buffer.read(cx).text(), "\nWhat is this?\n\n");
+        let buffer = context.buffer.read(cx);
+        let image_offsets = context
+            .contents(cx)
+            .map(|content| content.range().start.to_offset(buffer))
+            .collect::<Vec<_>>();
+        assert_eq!(image_offsets, [1]);
+        assert_eq!(context.messages(cx).next().unwrap().id, message_1);
+    });
+    cx.run_until_parked();
+    let request = model.as_fake().pending_completions().pop().unwrap();
+    assert_eq!(request.messages[0].string_contents(), "What is this?\n");
+}
+
 #[gpui::test(iterations = 100)]
 async fn test_random_context_collaboration(cx: &mut TestAppContext, mut rng: StdRng) {
     let min_peers = env::var("MIN_PEERS")
@@ -966,6 +2059,7 @@ async fn test_random_context_collaboration(cx: &mut TestAppContext, mut rng: Std
     cx.update(LanguageModelRegistry::test);
 
     cx.update(assistant_panel::init);
+    cx.update(AssistantSettings::register);
     let slash_commands = cx.update(SlashCommandRegistry::default_global);
     slash_commands.register_command(FakeSlashCommand("cmd-1".into()), false);
     slash_commands.register_command(FakeSlashCommand("cmd-2".into()), false);
@@ -1223,6 +2317,7 @@ fn test_mark_cache_anchors(cx: &mut AppContext) {
     LanguageModelRegistry::test(cx);
     cx.set_global(settings_store);
     assistant_panel::init(cx);
+    AssistantSettings::register(cx);
     let registry = Arc::new(LanguageRegistry::test(cx.background_executor().clone()));
     let prompt_builder = Arc::new(PromptBuilder::new(None).unwrap());
     let context =
@@ -1435,3 +2530,40 @@ impl SlashCommand for FakeSlashCommand {
         }))
     }
 }
+
+struct FakeTool {
+    name: &'static str,
+    requires_confirmation: bool,
+}
+
+impl FakeTool {
+    fn new(name: &'static str, requires_confirmation: bool) -> Self {
+        Self {
+            name,
+            requires_confirmation,
+        }
+    }
+}
+
+impl Tool for FakeTool {
+    fn name(&self) -> String {
+        self.name.to_string()
+    }
+
+    fn description(&self) -> String {
+        format!("Fake tool: {}", self.name)
+    }
+
+    fn requires_confirmation(&self) -> bool {
+        self.requires_confirmation
+    }
+
+    fn run(
+        self: Arc<Self>,
+        _input: serde_json::Value,
+        _workspace: WeakView<Workspace>,
+        _cx: &mut WindowContext,
+    ) -> Task<Result<String>> {
+        Task::ready(Ok(format!("Ran fake tool: {}", self.name)))
+    }
+}
@@ -0,0 +1,233 @@
+use crate::{Context, MessageId, MessageMetadata, MessageStatus, SavedContext, SavedMessage};
+use anyhow::{anyhow, Context as _, Result};
+use chrono::{DateTime, Utc};
+use gpui::AppContext;
+use language_model::Role;
+use serde::{Deserialize, Serialize};
+
+/// A context exported for sharing or archiving outside of Zed.
+///
+/// Bump [`ExportedContext::SCHEMA_VERSION`] whenever the format changes, keeping older
+/// versions loadable.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExportedContext {
+    pub schema_version: u32,
+    pub summary: String,
+    /// The model that was active when the context was exported, as `provider/model`.
+    pub model: Option<String>,
+    pub exported_at: DateTime<Utc>,
+    pub messages: Vec<ExportedMessage>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExportedMessage {
+    pub role: Role,
+    pub status: MessageStatus,
+    pub text: String,
+}
+
+impl ExportedContext {
+    pub const SCHEMA_VERSION: u32 = 1;
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let exported_context = serde_json::from_str::<serde_json::Value>(json)?;
+        let schema_version = exported_context
+            .get("schema_version")
+            .and_then(|version| version.as_u64())
+            .ok_or_else(|| anyhow!("not an exported assistant context"))?;
+        if schema_version > Self::SCHEMA_VERSION as u64 {
+            Err(anyhow!(
+                "the context was exported by a newer version of Zed (schema version {schema_version})"
+            ))?;
+        }
+        serde_json::from_value(exported_context).context("invalid exported context")
+    }
+
+    /// Renders the context as a readable transcript, with a heading for each message.
+    pub fn to_markdown(&self) -> String {
+        let mut markdown = String::new();
+        if !self.summary.is_empty() {
+            markdown.push_str(&format!("# {}\n\n", self.summary));
+        }
+        for message in &self.messages {
+            let heading = match message.role {
+                Role::User => "User",
+                Role::Assistant => "Assistant",
+                Role::System => "System",
+            };
+            markdown.push_str(&format!("## {heading}\n\n"));
+
+            let text = message.text.trim_end();
+            if !text.is_empty() {
+                markdown.push_str(text);
+                markdown.push('\n');
+            }
+            // A message that was cut off mid-stream may leave a code block open, which would
+            // swallow the rest of the transcript.
+            let fence_count = text
+                .lines()
+                .filter(|line| line.trim_start().starts_with("```"))
+                .count();
+            if fence_count % 2 == 1 {
+                markdown.push_str("```\n");
+            }
+            markdown.push('\n');
+        }
+        markdown.truncate(markdown.trim_end().len());
+        markdown.push('\n');
+        markdown
+    }
+
+    /// Converts the export into a new, unsaved context.
+    pub(crate) fn into_saved_context(self) -> SavedContext {
+        let mut text = String::new();
+        let mut messages = Vec::new();
+        for (ix, message) in self.messages.into_iter().enumerate() {
+            if ix > 0 {
+                text.push('\n');
+            }
+            let id = MessageId(clock::Lamport {
+                replica_id: 0,
+                value: ix as u32,
+            });
+            let status = match message.status {
+                // Nothing is streaming into an imported message.
+                MessageStatus::Pending => MessageStatus::Canceled,
+                status => status,
+            };
+            messages.push(SavedMessage {
+                id,
+                start: text.len(),
+                metadata: MessageMetadata {
+                    role: message.role,
+                    status,
+                    timestamp: id.0,
+                    cache: None,
+                    metrics: None,
+                },
+            });
+            text.push_str(&message.text);
+        }
+
+        SavedContext {
+            id: None,
+            zed: "context".into(),
+            version: SavedContext::VERSION.into(),
+            text,
+            messages,
+            summary: self.summary,
+            sampling_overrides: Default::default(),
+            slash_command_output_sections: Vec::new(),
+        }
+    }
+}
+
+impl Context {
+    pub fn export(&self, cx: &AppContext) -> ExportedContext {
+        let buffer = self.buffer().read(cx);
+        let model = self
+            .model(cx)
+            .map(|model| format!("{}/{}", model.provider_id().0, model.id().0));
+        let mut messages = self.messages(cx).peekable();
+        let mut exported_messages = Vec::new();
+        while let Some(message) = messages.next() {
+            let mut text = buffer
+                .text_for_range(message.offset_range)
+                .collect::<String>();
+            // Every message but the last ends with the newline that separates it from the next.
+            if messages.peek().is_some() && text.ends_with('\n') {
+                text.pop();
+            }
+            exported_messages.push(ExportedMessage {
+                role: message.role,
+                status: message.status,
+                text,
+            });
+        }
+
+        ExportedContext {
+            schema_version: ExportedContext::SCHEMA_VERSION,
+            summary: self
+                .summary()
+                .map(|summary| summary.text.clone())
+                .unwrap_or_default(),
+            model,
+            exported_at: Utc::now(),
+            messages: exported_messages,
+        }
+    }
+
+    pub fn to_markdown(&self, cx: &AppContext) -> String {
+        self.export(cx).to_markdown()
+    }
+
+    pub fn to_json(&self, cx: &AppContext) -> Result<String> {
+        self.export(cx).to_json()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use unindent::Unindent as _;
+
+    fn exported_context() -> ExportedContext {
+        ExportedContext {
+            schema_version: ExportedContext::SCHEMA_VERSION,
+            summary: "Sorting in Rust".into(),
+            model: Some("fake/fake".into()),
+            exported_at: "2024-08-30T12:00:00Z".parse().unwrap(),
+            messages: vec![
+                ExportedMessage {
+                    role: Role::User,
+                    status: MessageStatus::Done,
+                    text: "How do I sort a `Vec`? Ünïcödé 🦀".into(),
+                },
+                ExportedMessage {
+                    role: Role::Assistant,
+                    status: MessageStatus::Pending,
+                    text: "Use `sort`:\n\n```rust\nv.sort();".into(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let exported = exported_context();
+        let json = exported.to_json().unwrap();
+        assert_eq!(ExportedContext::from_json(&json).unwrap(), exported);
+
+        let mut newer: serde_json::Value = serde_json::from_str(&json).unwrap();
+        newer["schema_version"] = (ExportedContext::SCHEMA_VERSION + 1).into();
+        assert!(ExportedContext::from_json(&newer.to_string()).is_err());
+        assert!(ExportedContext::from_json("{\"messages\": []}").is_err());
+    }
+
+    #[test]
+    fn test_markdown() {
+        assert_eq!(
+            exported_context().to_markdown(),
+            "
+            # Sorting in Rust
+
+            ## User
+
+            How do I sort a `Vec`? Ünïcödé 🦀
+
+            ## Assistant
+
+            Use `sort`:
+
+            ```rust
+            v.sort();
+            ```
+            "
+            .unindent()
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
     prompts::PromptBuilder, Context, ContextEvent, ContextId, ContextOperation, ContextVersion,
-    SavedContext, SavedContextMetadata,
+    ExportedContext, SavedContext, SavedContextMetadata,
 };
 use anyhow::{anyhow, Context as _, Result};
 use client::{proto, telemetry::Telemetry, Client, TypedEnvelope};
@@ -349,6 +349,27 @@ impl ContextStore {
         context
     }
 
+    /// Creates a new, unsaved context from an exported one.
+    pub fn import(
+        &mut self,
+        exported_context: ExportedContext,
+        cx: &mut ModelContext<Self>,
+    ) -> Model<Context> {
+        let context = cx.new_model(|cx| {
+            Context::deserialize(
+                exported_context.into_saved_context(),
+                None,
+                self.languages.clone(),
+                self.prompt_builder.clone(),
+                Some(self.project.clone()),
+                Some(self.telemetry.clone()),
+                cx,
+            )
+        });
+        self.register_context(&context, cx);
+        context
+    }
+
     pub fn create_remote_context(
         &mut self,
         cx: &mut ModelContext<Self>,
@@ -431,7 +452,7 @@ impl ContextStore {
             let context = cx.new_model(|cx| {
                 Context::deserialize(
                     saved_context,
-                    path.clone(),
+                    Some(path.clone()),
                     languages,
                     prompt_builder,
                     Some(project),
@@ -1,7 +1,10 @@
 use crate::{
-    assistant_settings::AssistantSettings, humanize_token_count, prompts::PromptBuilder,
+    assistant_settings::{AssistantSettings, InlineAssistEditFormat},
+    humanize_token_count, parse_edit_stream,
+    prompts::PromptBuilder,
     AssistantPanel, AssistantPanelEvent, CharOperation, CycleNextInlineAssist,
-    CyclePreviousInlineAssist, LineDiff, LineOperation, ModelSelector, StreamingDiff,
+    CyclePreviousInlineAssist, LineDiff, LineOperation, MalformedEdit, ModelSelector,
+    StreamingDiff,
 };
 use anyhow::{anyhow, Context as _, Result};
 use client::{telemetry::Telemetry, ErrorExt};
@@ -26,17 +29,18 @@ use futures::{
     SinkExt, Stream, StreamExt,
 };
 use gpui::{
-    anchored, deferred, point, AnyElement, AppContext, ClickEvent, EventEmitter, FocusHandle,
-    FocusableView, FontWeight, Global, HighlightStyle, Model, ModelContext, Subscription, Task,
-    TextStyle, UpdateGlobal, View, ViewContext, WeakView, WindowContext,
+    anchored, deferred, point, AnyElement, AppContext, ClickEvent, ClipboardItem, EventEmitter,
+    FocusHandle, FocusableView, FontWeight, Global, HighlightStyle, Model, ModelContext,
+    Subscription, Task, TextStyle, UpdateGlobal, View, ViewContext, WeakView, WindowContext,
 };
 use language::{Buffer, IndentKind, Point, Selection, TransactionId};
 use language_model::{
-    LanguageModel, LanguageModelRegistry, LanguageModelRequest, LanguageModelRequestMessage, Role,
+    coalesce_chunks, LanguageModel, LanguageModelRegistry, LanguageModelRequest,
+    LanguageModelRequestMessage, Role, DEFAULT_COALESCE_INTERVAL,
 };
 use multi_buffer::MultiBufferRow;
 use parking_lot::Mutex;
-use project::{CodeAction, ProjectTransaction};
+use project::{CodeAction, Project, ProjectTransaction};
 use rope::Rope;
 use settings::{Settings, SettingsStore};
 use smol::future::FutureExt;
@@ -209,8 +213,9 @@ impl InlineAssistant {
         initial_prompt: Option<String>,
         cx: &mut WindowContext,
     ) {
+        let project = project_for_workspace(workspace.as_ref(), cx);
         if let Some(telemetry) = self.telemetry.as_ref() {
-            if let Some(model) = LanguageModelRegistry::read_global(cx).active_model() {
+            if let Some(model) = AssistantSettings::model_for_project(project.as_ref(), cx) {
                 telemetry.report_assistant_event(
                     None,
                     telemetry_events::AssistantKind::Inline,
@@ -283,6 +288,7 @@ impl InlineAssistant {
                     editor.read(cx).buffer().clone(),
                     range.clone(),
                     None,
+                    project.clone(),
                     self.telemetry.clone(),
                     self.prompt_builder.clone(),
                     cx,
@@ -385,11 +391,13 @@ impl InlineAssistant {
             range.end = range.end.bias_right(&snapshot);
         }
 
+        let project = project_for_workspace(workspace.as_ref(), cx);
         let codegen = cx.new_model(|cx| {
             Codegen::new(
                 editor.read(cx).buffer().clone(),
                 range.clone(),
                 initial_transaction_id,
+                project,
                 self.telemetry.clone(),
                 self.prompt_builder.clone(),
                 cx,
@@ -762,7 +770,11 @@ impl InlineAssistant {
 
     pub fn finish_assist(&mut self, assist_id: InlineAssistId, undo: bool, cx: &mut WindowContext) {
         if let Some(telemetry) = self.telemetry.as_ref() {
-            if let Some(model) = LanguageModelRegistry::read_global(cx).active_model() {
+            if let Some(model) = self
+                .assists
+                .get(&assist_id)
+                .and_then(|assist| assist.codegen.read(cx).model(cx))
+            {
                 telemetry.report_assistant_event(
                     None,
                     telemetry_events::AssistantKind::Inline,
@@ -1452,6 +1464,11 @@ impl Render for PromptEditor {
             buttons.push(self.render_cycle_controls(cx));
         }
 
+        let model_name = codegen
+            .model(cx)
+            .map(|model| model.name().0)
+            .unwrap_or_else(|| "No model selected".into());
+
         let status = codegen.status(cx);
         buttons.extend(match status {
             CodegenStatus::Idle => {
@@ -1566,13 +1583,7 @@ impl Render for PromptEditor {
                                 .icon_color(Color::Muted)
                                 .tooltip(move |cx| {
                                     Tooltip::with_meta(
-                                        format!(
-                                            "Using {}",
-                                            LanguageModelRegistry::read_global(cx)
-                                                .active_model()
-                                                .map(|model| model.name().0)
-                                                .unwrap_or_else(|| "No model selected".into()),
-                                        ),
+                                        format!("Using {model_name}"),
                                         None,
                                         "Change Model",
                                         cx,
@@ -1614,6 +1625,9 @@ impl Render for PromptEditor {
                                     })),
                             )
                         } else {
+                            let malformed_response = error
+                                .downcast_ref::<MalformedEdit>()
+                                .map(|error| error.response.clone());
                             el.child(
                                 div()
                                     .id("error")
@@ -1624,6 +1638,24 @@ impl Render for PromptEditor {
                                             .color(Color::Error),
                                     ),
                             )
+                            .children(malformed_response.map(|response| {
+                                IconButton::new("copy-response", IconName::Copy)
+                                    .shape(IconButtonShape::Square)
+                                    .icon_size(IconSize::Small)
+                                    .tooltip(|cx| {
+                                        Tooltip::with_meta(
+                                            "Copy Response",
+                                            None,
+                                            "Apply the edits by hand",
+                                            cx,
+                                        )
+                                    })
+                                    .on_click(move |_, cx| {
+                                        cx.write_to_clipboard(ClipboardItem::new_string(
+                                            response.clone(),
+                                        ))
+                                    })
+                            }))
                         }
                     }),
             )
@@ -2006,7 +2038,7 @@ impl PromptEditor {
     }
 
     fn render_token_count(&self, cx: &mut ViewContext<Self>) -> Option<impl IntoElement> {
-        let model = LanguageModelRegistry::read_global(cx).active_model()?;
+        let model = self.codegen.read(cx).model(cx)?;
         let token_counts = self.token_counts?;
         let max_token_count = model.max_token_count();
 
@@ -2169,6 +2201,14 @@ fn set_rate_limit_notice_dismissed(is_dismissed: bool, cx: &mut AppContext) {
     })
 }
 
+/// The project of the workspace that an assist was started in, whose settings apply to it.
+fn project_for_workspace(
+    workspace: Option<&WeakView<Workspace>>,
+    cx: &AppContext,
+) -> Option<Model<Project>> {
+    Some(workspace?.upgrade()?.read(cx).project().clone())
+}
+
 struct InlineAssist {
     group_id: InlineAssistGroupId,
     range: Range<Anchor>,
@@ -2338,6 +2378,8 @@ pub struct Codegen {
     buffer: Model<MultiBuffer>,
     range: Range<Anchor>,
     initial_transaction_id: Option<TransactionId>,
+    /// The project whose settings apply, if the assist was started in a workspace.
+    project: Option<Model<Project>>,
     telemetry: Option<Arc<Telemetry>>,
     builder: Arc<PromptBuilder>,
 }
@@ -2347,6 +2389,7 @@ impl Codegen {
         buffer: Model<MultiBuffer>,
         range: Range<Anchor>,
         initial_transaction_id: Option<TransactionId>,
+        project: Option<Model<Project>>,
         telemetry: Option<Arc<Telemetry>>,
         builder: Arc<PromptBuilder>,
         cx: &mut ModelContext<Self>,
@@ -2356,6 +2399,7 @@ impl Codegen {
                 buffer.clone(),
                 range.clone(),
                 false,
+                project.clone(),
                 telemetry.clone(),
                 builder.clone(),
                 cx,
@@ -2368,6 +2412,7 @@ impl Codegen {
             buffer,
             range,
             initial_transaction_id,
+            project,
             telemetry,
             builder,
         };
@@ -2384,6 +2429,11 @@ impl Codegen {
             .push(cx.subscribe(&codegen, |_, _, event, cx| cx.emit(*event)));
     }
 
+    /// The model that the first alternative is generated with.
+    pub fn model(&self, cx: &AppContext) -> Option<Arc<dyn LanguageModel>> {
+        AssistantSettings::model_for_project(self.project.as_ref(), cx)
+    }
+
     fn active_alternative(&self) -> &Model<CodegenAlternative> {
         &self.alternatives[self.active_alternative]
     }
@@ -2444,6 +2494,7 @@ impl Codegen {
                     self.buffer.clone(),
                     self.range.clone(),
                     false,
+                    self.project.clone(),
                     self.telemetry.clone(),
                     self.builder.clone(),
                     cx,
@@ -2451,9 +2502,7 @@ impl Codegen {
             }));
         }
 
-        let primary_model = LanguageModelRegistry::read_global(cx)
-            .active_model()
-            .context("no active model")?;
+        let primary_model = self.model(cx).context("no active model")?;
 
         for (model, alternative) in iter::once(primary_model)
             .chain(alternative_models)
@@ -2539,6 +2588,7 @@ pub struct CodegenAlternative {
     status: CodegenStatus,
     generation: Task<()>,
     diff: Diff,
+    project: Option<Model<Project>>,
     telemetry: Option<Arc<Telemetry>>,
     _subscription: gpui::Subscription,
     builder: Arc<PromptBuilder>,
@@ -2573,6 +2623,7 @@ impl CodegenAlternative {
         buffer: Model<MultiBuffer>,
         range: Range<Anchor>,
         active: bool,
+        project: Option<Model<Project>>,
         telemetry: Option<Arc<Telemetry>>,
         builder: Arc<PromptBuilder>,
         cx: &mut ModelContext<Self>,
@@ -2609,6 +2660,7 @@ impl CodegenAlternative {
             status: CodegenStatus::Idle,
             generation: Task::ready(()),
             diff: Diff::default(),
+            project,
             telemetry,
             _subscription: cx.subscribe(&buffer, Self::handle_buffer_event),
             builder,
@@ -2666,7 +2718,7 @@ impl CodegenAlternative {
         assistant_panel_context: Option<LanguageModelRequest>,
         cx: &AppContext,
     ) -> BoxFuture<'static, Result<TokenCounts>> {
-        if let Some(model) = LanguageModelRegistry::read_global(cx).active_model() {
+        if let Some(model) = AssistantSettings::model_for_project(self.project.as_ref(), cx) {
             let request = self.build_request(user_prompt, assistant_panel_context.clone(), cx);
             match request {
                 Ok(request) => {
@@ -2706,20 +2758,40 @@ impl CodegenAlternative {
         self.edit_position = Some(self.range.start.bias_right(&self.snapshot));
 
         let telemetry_id = model.telemetry_id();
-        let chunks: LocalBoxFuture<Result<BoxStream<Result<String>>>> =
-            if user_prompt.trim().to_lowercase() == "delete" {
-                async { Ok(stream::empty().boxed()) }.boxed_local()
-            } else {
-                let request = self.build_request(user_prompt, assistant_panel_context, cx)?;
+        if user_prompt.trim().to_lowercase() == "delete" {
+            self.handle_stream(telemetry_id, async { Ok(stream::empty().boxed()) }, cx);
+            return Ok(());
+        }
 
-                let chunks = cx
-                    .spawn(|_, cx| async move { model.stream_completion_text(request, &cx).await });
-                async move { Ok(chunks.await?.boxed()) }.boxed_local()
-            };
-        self.handle_stream(telemetry_id, chunks, cx);
+        let uses_edit_stream = self.uses_edit_stream(cx);
+        let request = self.build_request(user_prompt, assistant_panel_context, cx)?;
+        let chunks = cx.spawn(|_, cx| async move {
+            let executor = cx.background_executor().clone();
+            let chunks = model.stream_completion_text(request, &cx).await?;
+            anyhow::Ok(coalesce_chunks(
+                chunks,
+                DEFAULT_COALESCE_INTERVAL,
+                move |interval| executor.timer(interval),
+            ))
+        });
+        let chunks: LocalBoxFuture<Result<BoxStream<Result<String>>>> =
+            async move { Ok(chunks.await?.boxed()) }.boxed_local();
+        if uses_edit_stream {
+            self.handle_edit_stream(telemetry_id, chunks, cx);
+        } else {
+            self.handle_stream(telemetry_id, chunks, cx);
+        }
         Ok(())
     }
 
+    /// Whether the model is asked for search/replace blocks rather than a rewrite of the range.
+    /// Insertions are always streamed in as-is.
+    fn uses_edit_stream(&self, cx: &AppContext) -> bool {
+        AssistantSettings::for_project(self.project.as_ref(), cx).inline_assist_edit_format
+            == InlineAssistEditFormat::SearchReplace
+            && !self.range.to_offset(&self.snapshot).is_empty()
+    }
+
     fn build_request(
         &self,
         user_prompt: String,
@@ -2753,10 +2825,14 @@ impl CodegenAlternative {
             return Err(anyhow::anyhow!("invalid transformation range"));
         };
 
-        let prompt = self
-            .builder
-            .generate_content_prompt(user_prompt, language_name, buffer, range)
-            .map_err(|e| anyhow::anyhow!("Failed to generate content prompt: {}", e))?;
+        let prompt = if self.uses_edit_stream(cx) {
+            self.builder
+                .generate_content_edits_prompt(user_prompt, language_name, buffer, range)
+        } else {
+            self.builder
+                .generate_content_prompt(user_prompt, language_name, buffer, range)
+        }
+        .map_err(|e| anyhow::anyhow!("Failed to generate content prompt: {}", e))?;
 
         let mut messages = Vec::new();
         if let Some(context_request) = assistant_panel_context {
@@ -2767,6 +2843,7 @@ impl CodegenAlternative {
             role: Role::User,
             content: vec![prompt.into()],
             cache: false,
+            attachments: Vec::new(),
         });
 
         Ok(LanguageModelRequest {
@@ -2774,6 +2851,9 @@ impl CodegenAlternative {
             tools: Vec::new(),
             stop: Vec::new(),
             temperature: None,
+            top_p: None,
+            max_tokens: None,
+            response_format: None,
         })
     }
 
@@ -3005,6 +3085,125 @@ impl CodegenAlternative {
         cx.notify();
     }
 
+    /// Applies a response made of search/replace blocks, editing the buffer as each hunk
+    /// completes.
+    ///
+    /// If the response turns out to be malformed, any hunks that were already applied are
+    /// undone and the codegen fails with a [`MalformedEdit`] holding the raw response.
+    pub fn handle_edit_stream(
+        &mut self,
+        model_telemetry_id: String,
+        stream: impl 'static + Future<Output = Result<BoxStream<'static, Result<String>>>>,
+        cx: &mut ModelContext<Self>,
+    ) {
+        let snapshot = self.snapshot.clone();
+        let selected_text = snapshot
+            .text_for_range(self.range.start..self.range.end)
+            .collect::<String>();
+        let selection_start = self.range.start.to_offset(&snapshot);
+
+        let telemetry = self.telemetry.clone();
+        self.diff = Diff::default();
+        self.status = CodegenStatus::Pending;
+        self.generation = cx.spawn(|codegen, mut cx| async move {
+            let chunks = stream.await;
+            let generate = async {
+                let (mut edits_tx, mut edits_rx) = mpsc::channel(1);
+                let parse_edits: Task<anyhow::Result<()>> =
+                    cx.background_executor().spawn(async move {
+                        let mut response_latency = None;
+                        let request_start = Instant::now();
+                        let parse = async {
+                            let chunks = chunks?.inspect(|_| {
+                                response_latency.get_or_insert_with(|| request_start.elapsed());
+                            });
+                            let edits = parse_edit_stream(selected_text, chunks);
+                            futures::pin_mut!(edits);
+                            while let Some(edits) = edits.next().await {
+                                edits_tx.send(edits?).await?;
+                            }
+                            anyhow::Ok(())
+                        };
+
+                        let result = parse.await;
+
+                        let error_message = result.as_ref().err().map(|error| error.to_string());
+                        if let Some(telemetry) = telemetry {
+                            telemetry.report_assistant_event(
+                                None,
+                                telemetry_events::AssistantKind::Inline,
+                                telemetry_events::AssistantPhase::Response,
+                                model_telemetry_id,
+                                response_latency,
+                                error_message,
+                            );
+                        }
+
+                        result
+                    });
+
+                while let Some(edits) = edits_rx.next().await {
+                    codegen.update(&mut cx, |codegen, cx| {
+                        let edits = edits
+                            .into_iter()
+                            .map(|(range, text)| {
+                                let start = selection_start + range.start;
+                                let end = selection_start + range.end;
+                                (
+                                    snapshot.anchor_after(start)..snapshot.anchor_before(end),
+                                    text,
+                                )
+                            })
+                            .collect::<Vec<_>>();
+
+                        if codegen.active {
+                            codegen.apply_edits(edits.iter().cloned(), cx);
+                            codegen.reapply_batch_diff(cx).detach();
+                        }
+                        if let Some((range, _)) = edits.last() {
+                            codegen.edit_position = Some(range.end);
+                        }
+                        codegen.edits.extend(edits);
+
+                        cx.notify();
+                    })?;
+                }
+
+                let result = parse_edits.await;
+                if result
+                    .as_ref()
+                    .is_err_and(|error| error.is::<MalformedEdit>())
+                {
+                    codegen.update(&mut cx, |codegen, cx| {
+                        codegen.undo(cx);
+                        codegen.edits.clear();
+                        codegen.diff = Diff::default();
+                    })?;
+                } else {
+                    codegen
+                        .update(&mut cx, |codegen, cx| codegen.reapply_batch_diff(cx))?
+                        .await;
+                }
+                result
+            };
+
+            let result = generate.await;
+            codegen
+                .update(&mut cx, |this, cx| {
+                    this.last_equal_ranges.clear();
+                    if let Err(error) = result {
+                        this.status = CodegenStatus::Error(error);
+                    } else {
+                        this.status = CodegenStatus::Done;
+                    }
+                    cx.emit(CodegenEvent::Finished);
+                    cx.notify();
+                })
+                .ok();
+        });
+        cx.notify();
+    }
+
     pub fn stop(&mut self, cx: &mut ModelContext<Self>) {
         self.last_equal_ranges.clear();
         if self.diff.is_empty() {
@@ -3492,7 +3691,7 @@ mod tests {
         language_settings, tree_sitter_rust, Buffer, Language, LanguageConfig, LanguageMatcher,
         Point,
     };
-    use language_model::LanguageModelRegistry;
+    use language_model::{provider::fake::FakeLanguageModel, LanguageModelRegistry};
     use rand::prelude::*;
     use serde::Serialize;
     use settings::SettingsStore;
@@ -3531,6 +3730,7 @@ mod tests {
                 range.clone(),
                 true,
                 None,
+                None,
                 prompt_builder,
                 cx,
             )
@@ -3602,6 +3802,7 @@ mod tests {
                 range.clone(),
                 true,
                 None,
+                None,
                 prompt_builder,
                 cx,
             )
@@ -3676,6 +3877,7 @@ mod tests {
                 range.clone(),
                 true,
                 None,
+                None,
                 prompt_builder,
                 cx,
             )
@@ -3749,6 +3951,7 @@ mod tests {
                 range.clone(),
                 true,
                 None,
+                None,
                 prompt_builder,
                 cx,
             )
@@ -3812,6 +4015,7 @@ mod tests {
                 range.clone(),
                 false,
                 None,
+                None,
                 prompt_builder,
                 cx,
             )
@@ -3859,6 +4063,157 @@ mod tests {
         );
     }
 
+    #[gpui::test]
+    async fn test_edit_stream_applies_hunks_as_they_complete(cx: &mut TestAppContext) {
+        let text = indoc! {"
+            fn main() {
+                let x = 0;
+                let y = 1;
+                println!(\"{x} {y}\");
+            }
+        "};
+        let (buffer, codegen, model) = init_edit_stream_test(text, cx);
+
+        // Commentary is left out of the buffer, and a hunk is only applied once it completes.
+        for chunk in [
+            "I'll rename the variables.\n\n<<<<<<< SEARCH\n    let x",
+            " = 0;\n=======\n    let a = 0;\n>>>",
+            ">>>> REPLACE\n<<<<<<< SEARCH\n    let y = 1;\n",
+        ] {
+            model.stream_last_completion_response(chunk.into());
+        }
+        cx.run_until_parked();
+        assert_eq!(
+            buffer.read_with(cx, |buffer, cx| buffer.snapshot(cx).text()),
+            indoc! {"
+                fn main() {
+                    let a = 0;
+                    let y = 1;
+                    println!(\"{x} {y}\");
+                }
+            "}
+        );
+
+        model.stream_last_completion_response(
+            "    println!(\"{x} {y}\");\n=======\n    let b = 1;\n    println!(\"{a} {b}\");\n>>>>>>> REPLACE\nDone!".into(),
+        );
+        model.end_last_completion_stream();
+        cx.run_until_parked();
+        assert_eq!(
+            buffer.read_with(cx, |buffer, cx| buffer.snapshot(cx).text()),
+            indoc! {"
+                fn main() {
+                    let a = 0;
+                    let b = 1;
+                    println!(\"{a} {b}\");
+                }
+            "}
+        );
+        assert!(codegen.read_with(cx, |codegen, _| matches!(
+            codegen.status,
+            CodegenStatus::Done
+        )));
+    }
+
+    #[gpui::test]
+    async fn test_edit_stream_with_incomplete_hunk(cx: &mut TestAppContext) {
+        let text = indoc! {"
+            fn main() {
+                let x = 0;
+                let y = 1;
+            }
+        "};
+        let (buffer, codegen, model) = init_edit_stream_test(text, cx);
+
+        let chunks = [
+            "<<<<<<< SEARCH\n    let x = 0;\n=======\n    let a = 0;\n>>>>>>> REPLACE\n",
+            "<<<<<<< SEARCH\n    let y = 1;\n=======\n    let b",
+        ];
+        for chunk in chunks {
+            model.stream_last_completion_response(chunk.into());
+        }
+        cx.run_until_parked();
+        assert_eq!(
+            buffer.read_with(cx, |buffer, cx| buffer.snapshot(cx).text()),
+            indoc! {"
+                fn main() {
+                    let a = 0;
+                    let y = 1;
+                }
+            "}
+        );
+
+        // The response ends in the middle of a hunk, so the applied hunks are rolled back and
+        // the raw response is kept.
+        model.end_last_completion_stream();
+        cx.run_until_parked();
+        assert_eq!(
+            buffer.read_with(cx, |buffer, cx| buffer.snapshot(cx).text()),
+            text
+        );
+        codegen.read_with(cx, |codegen, _| {
+            let CodegenStatus::Error(error) = &codegen.status else {
+                panic!("expected the codegen to fail");
+            };
+            assert_eq!(
+                error.downcast_ref::<MalformedEdit>().unwrap().response,
+                chunks.concat()
+            );
+        });
+    }
+
+    /// Starts transforming the lines inside `main` with search/replace blocks, returning the
+    /// model whose response drives the transformation.
+    fn init_edit_stream_test(
+        text: &str,
+        cx: &mut TestAppContext,
+    ) -> (
+        Model<MultiBuffer>,
+        Model<CodegenAlternative>,
+        Arc<FakeLanguageModel>,
+    ) {
+        cx.set_global(cx.update(SettingsStore::test));
+        cx.update(|cx| {
+            LanguageModelRegistry::test(cx);
+            language_settings::init(cx);
+            AssistantSettings::register(cx);
+            SettingsStore::update_global(cx, |store, cx| {
+                store
+                    .set_user_settings(
+                        r#"{"assistant": {"version": "2", "inline_assist_edit_format": "search_replace"}}"#,
+                        cx,
+                    )
+                    .unwrap();
+            });
+        });
+
+        let buffer =
+            cx.new_model(|cx| Buffer::local(text, cx).with_language(Arc::new(rust_lang()), cx));
+        let buffer = cx.new_model(|cx| MultiBuffer::singleton(buffer, cx));
+        let range = buffer.read_with(cx, |buffer, cx| {
+            let snapshot = buffer.snapshot(cx);
+            let last_row = snapshot.max_point().row - 2;
+            snapshot.anchor_before(Point::new(1, 0))
+                ..snapshot.anchor_after(Point::new(
+                    last_row,
+                    snapshot.line_len(MultiBufferRow(last_row)),
+                ))
+        });
+        let prompt_builder = Arc::new(PromptBuilder::new(None).unwrap());
+        let codegen = cx.new_model(|cx| {
+            CodegenAlternative::new(buffer.clone(), range, true, None, None, prompt_builder, cx)
+        });
+
+        let model = Arc::new(FakeLanguageModel::default());
+        codegen
+            .update(cx, |codegen, cx| {
+                codegen.start("rename the variables".into(), None, model.clone(), cx)
+            })
+            .unwrap();
+        cx.run_until_parked();
+        (buffer, codegen, model)
+    }
+
     #[gpui::test]
     async fn test_strip_invalid_spans_from_codeblock() {
         assert_chunks("Lorem ipsum dolor", "Lorem ipsum dolor").await;
@@ -0,0 +1,625 @@
+use anyhow::Result;
+use futures::{stream, Stream, StreamExt};
+use std::mem;
+
+/// The kind of a top-level Markdown block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BlockKind {
+    Paragraph,
+    Heading {
+        level: u8,
+    },
+    /// A fenced code block, with the first word of the fence's info string as its language.
+    CodeBlock {
+        language: Option<String>,
+    },
+    ListItem {
+        ordered: bool,
+    },
+}
+
+/// An event in the block structure of a streamed Markdown document.
+///
+/// Every block is reported as a `BlockStarted`, followed by the block's text in any number of
+/// `BlockText` events, followed by a `BlockEnded`. The text of a block is its lines joined with
+/// `\n`, without the block's markers. The lines of paragraphs, headings and list items are
+/// trimmed, while those of code blocks are kept as they are.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MarkdownEvent {
+    BlockStarted(BlockKind),
+    BlockText(String),
+    BlockEnded,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum LineKind {
+    Blank,
+    Fence {
+        fence: char,
+        fence_len: usize,
+        language: Option<String>,
+    },
+    Heading {
+        level: u8,
+        content_start: usize,
+    },
+    ListItem {
+        ordered: bool,
+        content_start: usize,
+    },
+    Text,
+}
+
+enum Block {
+    /// A paragraph, heading or list item.
+    Text { single_line: bool, has_text: bool },
+    Code {
+        fence: char,
+        fence_len: usize,
+        lines: usize,
+    },
+}
+
+/// How the rest of the current line is streamed, once its kind is known.
+#[derive(Clone, Copy)]
+enum LineMode {
+    Verbatim,
+    Trimmed { has_text: bool },
+}
+
+/// Incrementally parses Markdown into block events.
+///
+/// Only the block structure the assistant's responses use is recognized: paragraphs, ATX
+/// headings, fenced code blocks and list items. Nested lists are flattened into a sequence of
+/// list items, and anything else is treated as paragraph text.
+///
+/// A line's text is emitted as soon as it's known which block it belongs to, so code can be
+/// highlighted before its block closes.
+pub struct MarkdownStreamParser {
+    pending: String,
+    block: Option<Block>,
+    line: Option<LineMode>,
+    events: Vec<MarkdownEvent>,
+}
+
+impl MarkdownStreamParser {
+    pub fn new() -> Self {
+        Self {
+            pending: String::new(),
+            block: None,
+            line: None,
+            events: Vec::new(),
+        }
+    }
+
+    /// Parses the next chunk of the document, returning the events it completes.
+    pub fn push(&mut self, chunk: &str) -> Vec<MarkdownEvent> {
+        self.pending.push_str(chunk);
+        loop {
+            let newline_ix = self.pending.find('\n');
+            if let Some(mode) = self.line {
+                self.stream_line(mode, newline_ix);
+                if newline_ix.is_none() {
+                    break;
+                }
+                continue;
+            }
+
+            let line = &self.pending[..newline_ix.unwrap_or(self.pending.len())];
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            let complete = newline_ix.is_some();
+            let line_end = newline_ix.map_or(self.pending.len(), |ix| ix + 1);
+
+            if let Some(Block::Code {
+                fence, fence_len, ..
+            }) = &self.block
+            {
+                match closes_fence(line, *fence, *fence_len, complete) {
+                    None => break,
+                    Some(true) => {
+                        self.pending.drain(..line_end);
+                        self.end_block();
+                    }
+                    Some(false) => {
+                        if let Some(Block::Code { lines, .. }) = &mut self.block {
+                            if *lines > 0 {
+                                self.events.push(MarkdownEvent::BlockText("\n".into()));
+                            }
+                            *lines += 1;
+                        }
+                        self.line = Some(LineMode::Verbatim);
+                    }
+                }
+                continue;
+            }
+
+            let Some(kind) = classify_line(line, complete) else {
+                break;
+            };
+            match kind {
+                LineKind::Blank => {
+                    self.pending.drain(..line_end);
+                    self.end_block();
+                }
+                LineKind::Fence {
+                    fence,
+                    fence_len,
+                    language,
+                } => {
+                    self.pending.drain(..line_end);
+                    self.start_block(
+                        BlockKind::CodeBlock { language },
+                        Block::Code {
+                            fence,
+                            fence_len,
+                            lines: 0,
+                        },
+                    );
+                }
+                LineKind::Heading {
+                    level,
+                    content_start,
+                } => {
+                    self.pending.drain(..content_start);
+                    self.start_text_block(BlockKind::Heading { level }, true);
+                }
+                LineKind::ListItem {
+                    ordered,
+                    content_start,
+                } => {
+                    self.pending.drain(..content_start);
+                    self.start_text_block(BlockKind::ListItem { ordered }, false);
+                }
+                LineKind::Text => match &self.block {
+                    Some(Block::Text {
+                        single_line: false,
+                        has_text,
+                    }) => {
+                        if *has_text {
+                            self.events.push(MarkdownEvent::BlockText("\n".into()));
+                        }
+                        self.line = Some(LineMode::Trimmed { has_text: false });
+                    }
+                    _ => self.start_text_block(BlockKind::Paragraph, false),
+                },
+            }
+        }
+        mem::take(&mut self.events)
+    }
+
+    /// Parses the rest of the document, ending the block that's still open.
+    pub fn finish(mut self) -> Vec<MarkdownEvent> {
+        let mut events = Vec::new();
+        if !self.pending.is_empty() || self.line.is_some() {
+            events = self.push("\n");
+        }
+        self.end_block();
+        events.append(&mut self.events);
+        events
+    }
+
+    /// Emits as much of the current line as can't still turn out to be trailing whitespace,
+    /// finishing the line if it's complete.
+    fn stream_line(&mut self, mode: LineMode, newline_ix: Option<usize>) {
+        let available = &self.pending[..newline_ix.unwrap_or(self.pending.len())];
+        let (start, end) = match mode {
+            LineMode::Verbatim => (0, available.trim_end_matches('\r').len()),
+            LineMode::Trimmed { has_text } => {
+                let start = if has_text {
+                    0
+                } else {
+                    available.len() - available.trim_start().len()
+                };
+                (start, available.trim_end().len())
+            }
+        };
+        if start < end {
+            let text = available[start..end].to_string();
+            self.emit_text(text);
+            if let LineMode::Trimmed { .. } = mode {
+                self.line = Some(LineMode::Trimmed { has_text: true });
+            }
+        }
+
+        if let Some(newline_ix) = newline_ix {
+            self.pending.drain(..=newline_ix);
+            self.line = None;
+            if let Some(Block::Text {
+                single_line: true, ..
+            }) = self.block
+            {
+                self.end_block();
+            }
+        } else {
+            self.pending.drain(..end.max(start));
+        }
+    }
+
+    fn start_text_block(&mut self, kind: BlockKind, single_line: bool) {
+        self.start_block(
+            kind,
+            Block::Text {
+                single_line,
+                has_text: false,
+            },
+        );
+        self.line = Some(LineMode::Trimmed { has_text: false });
+    }
+
+    fn start_block(&mut self, kind: BlockKind, block: Block) {
+        self.end_block();
+        self.events.push(MarkdownEvent::BlockStarted(kind));
+        self.block = Some(block);
+    }
+
+    fn end_block(&mut self) {
+        if self.block.take().is_some() {
+            self.events.push(MarkdownEvent::BlockEnded);
+        }
+    }
+
+    fn emit_text(&mut self, text: String) {
+        if let Some(Block::Text { has_text, .. }) = &mut self.block {
+            *has_text = true;
+        }
+        self.events.push(MarkdownEvent::BlockText(text));
+    }
+}
+
+impl Default for MarkdownStreamParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Determines the kind of a line outside of code blocks, or returns `None` if more of the
+/// line is needed to tell.
+fn classify_line(line: &str, complete: bool) -> Option<LineKind> {
+    let trimmed = line.trim_start_matches([' ', '\t']);
+    let indent = line.len() - trimmed.len();
+    let Some(first_char) = trimmed.chars().next() else {
+        return complete.then_some(LineKind::Blank);
+    };
+
+    match first_char {
+        '`' | '~' if indent <= 3 => {
+            let fence_len = trimmed.len() - trimmed.trim_start_matches(first_char).len();
+            if fence_len >= 3 {
+                // The language is only known once the info string is complete.
+                return complete.then(|| LineKind::Fence {
+                    fence: first_char,
+                    fence_len,
+                    language: trimmed[fence_len..]
+                        .split_whitespace()
+                        .next()
+                        .map(str::to_string),
+                });
+            } else if fence_len == trimmed.len() && !complete {
+                return None;
+            }
+        }
+        '#' if indent <= 3 => {
+            let level = trimmed.len() - trimmed.trim_start_matches('#').len();
+            let rest = &trimmed[level..];
+            if level <= 6 {
+                if rest.is_empty() && !complete {
+                    return None;
+                } else if rest.is_empty() || rest.starts_with([' ', '\t']) {
+                    return Some(LineKind::Heading {
+                        level: level as u8,
+                        content_start: line.len() - rest.len(),
+                    });
+                }
+            }
+        }
+        '-' | '*' | '+' => return list_item(line, &trimmed[1..], false, complete),
+        '0'..='9' => {
+            let digits = trimmed.len()
+                - trimmed
+                    .trim_start_matches(|c: char| c.is_ascii_digit())
+                    .len();
+            let rest = &trimmed[digits..];
+            if digits <= 9 {
+                if rest.is_empty() && !complete {
+                    return None;
+                } else if let Some(rest) = rest.strip_prefix(['.', ')']) {
+                    return list_item(line, rest, true, complete);
+                }
+            }
+        }
+        _ => {}
+    }
+    Some(LineKind::Text)
+}
+
+/// Determines whether a line whose marker is followed by `rest` starts a list item.
+fn list_item(line: &str, rest: &str, ordered: bool, complete: bool) -> Option<LineKind> {
+    if rest.is_empty() && !complete {
+        None
+    } else if rest.is_empty() || rest.starts_with([' ', '\t']) {
+        Some(LineKind::ListItem {
+            ordered,
+            content_start: line.len() - rest.len(),
+        })
+    } else {
+        Some(LineKind::Text)
+    }
+}
+
+/// Returns the fence character, fence length and language of a complete line if it opens a
+/// code block.
+pub(crate) fn opening_fence(line: &str) -> Option<(char, usize, Option<String>)> {
+    match classify_line(line, true)? {
+        LineKind::Fence {
+            fence,
+            fence_len,
+            language,
+        } => Some((fence, fence_len, language)),
+        _ => None,
+    }
+}
+
+/// Determines whether a line closes a code block, or returns `None` if more of the line is
+/// needed to tell.
+pub(crate) fn closes_fence(
+    line: &str,
+    fence: char,
+    fence_len: usize,
+    complete: bool,
+) -> Option<bool> {
+    let trimmed = line.trim_start_matches(' ');
+    if line.len() - trimmed.len() > 3 {
+        return Some(false);
+    }
+    let rest = trimmed.trim_start_matches(fence);
+    if !rest.trim().is_empty() {
+        Some(false)
+    } else if !complete && (rest.is_empty() || trimmed.len() - rest.len() >= fence_len) {
+        None
+    } else {
+        Some(complete && trimmed.len() - rest.len() >= fence_len)
+    }
+}
+
+/// Parses a streamed Markdown response into block events, yielding each chunk's events as soon
+/// as it's been parsed.
+///
+/// The events end at the first error in the response, after ending the block that's open.
+pub fn parse(chunks: impl Stream<Item = Result<String>>) -> impl Stream<Item = MarkdownEvent> {
+    let state = Some((MarkdownStreamParser::new(), Box::pin(chunks)));
+    stream::unfold(state, |state| async move {
+        let (mut parser, mut chunks) = state?;
+        loop {
+            match chunks.next().await {
+                Some(Ok(chunk)) => {
+                    let events = parser.push(&chunk);
+                    if !events.is_empty() {
+                        return Some((events, Some((parser, chunks))));
+                    }
+                }
+                Some(Err(error)) => {
+                    log::error!("error streaming markdown: {error:?}");
+                    return Some((parser.finish(), None));
+                }
+                None => return Some((parser.finish(), None)),
+            }
+        }
+    })
+    .flat_map(stream::iter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use indoc::indoc;
+    use MarkdownEvent::*;
+
+    fn text(text: &str) -> MarkdownEvent {
+        BlockText(text.to_string())
+    }
+
+    fn code(language: Option<&str>) -> MarkdownEvent {
+        BlockStarted(BlockKind::CodeBlock {
+            language: language.map(str::to_string),
+        })
+    }
+
+    fn parse_chunks(chunks: Vec<&str>) -> Vec<MarkdownEvent> {
+        let chunks = chunks
+            .into_iter()
+            .map(|chunk| Ok(chunk.to_string()))
+            .collect::<Vec<_>>();
+        let events = parse(stream::iter(chunks))
+            .collect::<Vec<_>>()
+            .now_or_never()
+            .unwrap();
+
+        // How a block's text is split into events depends on the chunks.
+        let mut merged_events = Vec::<MarkdownEvent>::new();
+        for event in events {
+            match (merged_events.last_mut(), event) {
+                (Some(BlockText(merged_text)), BlockText(text)) => merged_text.push_str(&text),
+                (_, BlockText(text)) if text.is_empty() => {}
+                (_, event) => merged_events.push(event),
+            }
+        }
+        merged_events
+    }
+
+    fn fixtures() -> Vec<(&'static str, Vec<MarkdownEvent>)> {
+        vec![
+            (
+                indoc! {"
+                    # Heading
+
+                    A paragraph
+                    that wraps.
+
+                    - first item
+                    - second
+                      continued
+                    -
+                    10. ordered
+                    ###### Six ##
+                "},
+                vec![
+                    BlockStarted(BlockKind::Heading { level: 1 }),
+                    text("Heading"),
+                    BlockEnded,
+                    BlockStarted(BlockKind::Paragraph),
+                    text("A paragraph\nthat wraps."),
+                    BlockEnded,
+                    BlockStarted(BlockKind::ListItem { ordered: false }),
+                    text("first item"),
+                    BlockEnded,
+                    BlockStarted(BlockKind::ListItem { ordered: false }),
+                    text("second\ncontinued"),
+                    BlockEnded,
+                    BlockStarted(BlockKind::ListItem { ordered: false }),
+                    BlockEnded,
+                    BlockStarted(BlockKind::ListItem { ordered: true }),
+                    text("ordered"),
+                    BlockEnded,
+                    BlockStarted(BlockKind::Heading { level: 6 }),
+                    text("Six ##"),
+                    BlockEnded,
+                ],
+            ),
+            (
+                indoc! {r#"
+                    Intro:
+                    ```rust
+                    fn main() {
+
+                        println!("hi");
+                    }
+                    ```
+                    After.
+                "#},
+                vec![
+                    BlockStarted(BlockKind::Paragraph),
+                    text("Intro:"),
+                    BlockEnded,
+                    code(Some("rust")),
+                    text("fn main() {\n\n    println!(\"hi\");\n}"),
+                    BlockEnded,
+                    BlockStarted(BlockKind::Paragraph),
+                    text("After."),
+                    BlockEnded,
+                ],
+            ),
+            (
+                indoc! {"
+                    ~~~
+                    ```not closed
+                       ~~
+                    ~~~~
+                    ####### not a heading
+                    ##### Five
+                "},
+                vec![
+                    code(None),
+                    text("```not closed\n   ~~"),
+                    BlockEnded,
+                    BlockStarted(BlockKind::Paragraph),
+                    text("####### not a heading"),
+                    BlockEnded,
+                    BlockStarted(BlockKind::Heading { level: 5 }),
+                    text("Five"),
+                    BlockEnded,
+                ],
+            ),
+            (
+                "* star item\r\n+ plus — ünïcode\r\n\r\n```py title\r\nprint('é')",
+                vec![
+                    BlockStarted(BlockKind::ListItem { ordered: false }),
+                    text("star item"),
+                    BlockEnded,
+                    BlockStarted(BlockKind::ListItem { ordered: false }),
+                    text("plus — ünïcode"),
+                    BlockEnded,
+                    code(Some("py")),
+                    text("print('é')"),
+                    BlockEnded,
+                ],
+            ),
+            (
+                indoc! {"
+                    2024 was a year
+                    -not a list
+                    #hashtag
+                    ``inline`` code
+                    1234567890. too long
+                    ```
+                    ```"},
+                vec![
+                    BlockStarted(BlockKind::Paragraph),
+                    text("2024 was a year\n-not a list\n#hashtag\n``inline`` code\n1234567890. too long"),
+                    BlockEnded,
+                    code(None),
+                    BlockEnded,
+                ],
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_parse_split_at_every_position() {
+        for (document, expected_events) in fixtures() {
+            assert_eq!(parse_chunks(vec![document]), expected_events);
+            for split_ix in (0..=document.len()).filter(|ix| document.is_char_boundary(*ix)) {
+                let (first_chunk, second_chunk) = document.split_at(split_ix);
+                assert_eq!(
+                    parse_chunks(vec![first_chunk, second_chunk]),
+                    expected_events,
+                    "split at {split_ix} in {document:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_one_char_at_a_time() {
+        for (document, expected_events) in fixtures() {
+            let mut chunks = Vec::new();
+            let mut chars = document.char_indices().peekable();
+            while let Some((ix, _)) = chars.next() {
+                let end = chars.peek().map_or(document.len(), |(ix, _)| *ix);
+                chunks.push(&document[ix..end]);
+            }
+            assert_eq!(parse_chunks(chunks), expected_events, "{document:?}");
+        }
+    }
+
+    #[test]
+    fn test_events_are_emitted_before_blocks_end() {
+        let mut parser = MarkdownStreamParser::new();
+        assert_eq!(
+            parser.push("Some ```te"),
+            vec![BlockStarted(BlockKind::Paragraph), text("Some ```te")]
+        );
+        assert_eq!(parser.push("xt``` \n```ty"), vec![text("xt```")]);
+        assert_eq!(
+            parser.push("pescript\nconst x"),
+            vec![BlockEnded, code(Some("typescript")), text("const x")]
+        );
+        assert_eq!(parser.push(" = 1;\n``"), vec![text(" = 1;")]);
+        assert_eq!(parser.push("`\n"), vec![BlockEnded]);
+        assert!(parser.finish().is_empty());
+    }
+
+    #[test]
+    fn test_parse_stops_at_error() {
+        let chunks = vec![
+            Ok("```rust\nfn".to_string()),
+            Err(anyhow::anyhow!("connection lost")),
+            Ok(" main".to_string()),
+        ];
+        let events = parse(stream::iter(chunks))
+            .collect::<Vec<_>>()
+            .now_or_never()
+            .unwrap();
+        assert_eq!(events, vec![code(Some("rust")), text("fn"), BlockEnded]);
+    }
+}
@@ -0,0 +1,300 @@
+use anyhow::Result;
+use futures::{stream, Stream, StreamExt};
+use std::{mem, ops::Range};
+
+const SEARCH_MARKER: &str = "<<<<<<< SEARCH";
+const DIVIDER_MARKER: &str = "=======";
+const REPLACE_MARKER: &str = ">>>>>>> REPLACE";
+
+/// The response contained an edit that can't be applied to the selection.
+///
+/// The whole response is kept so the user can apply it by hand.
+#[derive(Debug, thiserror::Error)]
+#[error("couldn't apply the assistant's edits: {reason}")]
+pub struct MalformedEdit {
+    pub reason: String,
+    pub response: String,
+}
+
+enum ParserState {
+    /// Between hunks, where any commentary is ignored.
+    Outside,
+    Search {
+        search: Vec<String>,
+    },
+    Replace {
+        search: Vec<String>,
+        replace: Vec<String>,
+    },
+}
+
+/// Incrementally parses a response made of search/replace blocks into edits of the selected
+/// text.
+///
+/// Hunks must appear in the order they apply to the selection, so each hunk's search text is
+/// looked for after the end of the previous hunk's.
+pub struct EditStreamParser {
+    selected_text: String,
+    response: String,
+    pending_line: String,
+    state: ParserState,
+    search_start: usize,
+}
+
+impl EditStreamParser {
+    pub fn new(selected_text: String) -> Self {
+        Self {
+            selected_text,
+            response: String::new(),
+            pending_line: String::new(),
+            state: ParserState::Outside,
+            search_start: 0,
+        }
+    }
+
+    /// Parses the next chunk of the response, returning the edits for the hunks it completes.
+    pub fn push(&mut self, chunk: &str) -> Result<Vec<(Range<usize>, String)>, MalformedEdit> {
+        self.response.push_str(chunk);
+        self.pending_line.push_str(chunk);
+
+        let mut edits = Vec::new();
+        while let Some(newline_ix) = self.pending_line.find('\n') {
+            let line = self.pending_line.drain(..=newline_ix).collect::<String>();
+            edits.extend(self.push_line(line.trim_end_matches(['\n', '\r']))?);
+        }
+        Ok(edits)
+    }
+
+    /// Parses the rest of the response, failing if it ends in the middle of a hunk.
+    pub fn finish(mut self) -> Result<Vec<(Range<usize>, String)>, MalformedEdit> {
+        let mut edits = Vec::new();
+        if !self.pending_line.is_empty() {
+            let line = mem::take(&mut self.pending_line);
+            edits.extend(self.push_line(line.trim_end_matches('\r'))?);
+        }
+        if !matches!(self.state, ParserState::Outside) {
+            return Err(self.malformed("the response ended in the middle of an edit"));
+        }
+        Ok(edits)
+    }
+
+    fn push_line(&mut self, line: &str) -> Result<Option<(Range<usize>, String)>, MalformedEdit> {
+        let marker = line.trim_end();
+        match &mut self.state {
+            ParserState::Outside => {
+                if marker == SEARCH_MARKER {
+                    self.state = ParserState::Search { search: Vec::new() };
+                } else if marker == DIVIDER_MARKER || marker == REPLACE_MARKER {
+                    return Err(self.malformed(format!("found `{marker}` outside of an edit")));
+                }
+            }
+            ParserState::Search { search } => {
+                if marker == DIVIDER_MARKER {
+                    self.state = ParserState::Replace {
+                        search: mem::take(search),
+                        replace: Vec::new(),
+                    };
+                } else if marker == SEARCH_MARKER || marker == REPLACE_MARKER {
+                    return Err(
+                        self.malformed(format!("expected `{DIVIDER_MARKER}`, found `{marker}`"))
+                    );
+                } else {
+                    search.push(line.to_string());
+                }
+            }
+            ParserState::Replace { search, replace } => {
+                if marker == REPLACE_MARKER {
+                    let search = mem::take(search).join("\n");
+                    let replace = mem::take(replace).join("\n");
+                    self.state = ParserState::Outside;
+                    return self.resolve(search, replace).map(Some);
+                } else if marker == SEARCH_MARKER || marker == DIVIDER_MARKER {
+                    return Err(
+                        self.malformed(format!("expected `{REPLACE_MARKER}`, found `{marker}`"))
+                    );
+                } else {
+                    replace.push(line.to_string());
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn resolve(
+        &mut self,
+        search: String,
+        replace: String,
+    ) -> Result<(Range<usize>, String), MalformedEdit> {
+        if search.trim().is_empty() {
+            return Err(self.malformed("an edit doesn't say what to replace"));
+        }
+        let Some(ix) = self.selected_text[self.search_start..].find(&search) else {
+            return Err(self.malformed(format!(
+                "couldn't find the following text in the selection:\n{search}"
+            )));
+        };
+        let start = self.search_start + ix;
+        let end = start + search.len();
+        self.search_start = end;
+        Ok((start..end, replace))
+    }
+
+    fn malformed(&self, reason: impl Into<String>) -> MalformedEdit {
+        MalformedEdit {
+            reason: reason.into(),
+            response: self.response.clone(),
+        }
+    }
+}
+
+/// Parses a streamed response into edits of the selected text, yielding the edits for each
+/// hunk as soon as it completes.
+///
+/// The stream ends after the first error, which is a [`MalformedEdit`] if the response itself
+/// couldn't be parsed.
+pub fn parse_edit_stream(
+    selected_text: String,
+    chunks: impl Stream<Item = Result<String>>,
+) -> impl Stream<Item = Result<Vec<(Range<usize>, String)>>> {
+    let state = Some((EditStreamParser::new(selected_text), Box::pin(chunks)));
+    stream::unfold(state, |state| async move {
+        let (mut parser, mut chunks) = state?;
+        loop {
+            let edits = match chunks.next().await {
+                Some(Ok(chunk)) => parser.push(&chunk),
+                Some(Err(error)) => return Some((Err(error), None)),
+                None => return Some((parser.finish().map_err(Into::into), None)),
+            };
+            match edits {
+                Ok(edits) if edits.is_empty() => {}
+                Ok(edits) => return Some((Ok(edits), Some((parser, chunks)))),
+                Err(error) => return Some((Err(error.into()), None)),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use indoc::indoc;
+
+    const SELECTED_TEXT: &str = indoc! {"
+        fn one() -> u32 {
+            1
+        }
+
+        fn two() -> u32 {
+            2
+        }"};
+
+    fn parse(response: &str, chunk_size: usize) -> Result<Vec<Vec<(Range<usize>, String)>>> {
+        let chunks = response
+            .chars()
+            .collect::<Vec<_>>()
+            .chunks(chunk_size)
+            .map(|chunk| Ok(chunk.iter().collect::<String>()))
+            .collect::<Vec<_>>();
+        parse_edit_stream(SELECTED_TEXT.to_string(), stream::iter(chunks))
+            .collect::<Vec<_>>()
+            .now_or_never()
+            .unwrap()
+            .into_iter()
+            .collect()
+    }
+
+    fn apply(edits: &[(Range<usize>, String)]) -> String {
+        let mut text = SELECTED_TEXT.to_string();
+        for (range, new_text) in edits.iter().rev() {
+            text.replace_range(range.clone(), new_text);
+        }
+        text
+    }
+
+    #[test]
+    fn test_parse_edit_stream() {
+        let response = indoc! {"
+            Here are the changes:
+
+            <<<<<<< SEARCH
+                1
+            =======
+                // One.
+                1
+            >>>>>>> REPLACE
+
+            And the second function:
+            <<<<<<< SEARCH
+            fn two() -> u32 {
+                2
+            =======
+            fn two() -> u64 {
+                2
+            >>>>>>> REPLACE
+            Done!"};
+
+        for chunk_size in 1..=response.len() {
+            let batches = parse(response, chunk_size).unwrap();
+            let edits = batches.concat();
+            assert_eq!(edits.len(), 2, "chunk size: {chunk_size}");
+            assert_eq!(
+                apply(&edits),
+                indoc! {"
+                    fn one() -> u32 {
+                        // One.
+                        1
+                    }
+
+                    fn two() -> u64 {
+                        2
+                    }"},
+                "chunk size: {chunk_size}"
+            );
+        }
+
+        // Edits are yielded as soon as their hunk completes.
+        let (first_hunk, second_hunk) = response.split_at(response.find("And").unwrap());
+        let mut parser = EditStreamParser::new(SELECTED_TEXT.to_string());
+        assert_eq!(parser.push(first_hunk).unwrap().len(), 1);
+        assert_eq!(parser.push(second_hunk).unwrap().len(), 1);
+        assert!(parser.finish().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_malformed_edit_stream() {
+        for (response, expected_reason) in [
+            (
+                "<<<<<<< SEARCH\n    3\n=======\n    4\n>>>>>>> REPLACE\n",
+                "couldn't find",
+            ),
+            (
+                "<<<<<<< SEARCH\n    1\n>>>>>>> REPLACE\n",
+                "expected `=======`",
+            ),
+            ("=======\n", "outside of an edit"),
+            (
+                "<<<<<<< SEARCH\n=======\n    1\n>>>>>>> REPLACE\n",
+                "doesn't say what to replace",
+            ),
+            (
+                "<<<<<<< SEARCH\n    1\n=======\n    2",
+                "ended in the middle of an edit",
+            ),
+            // Hunks must be in order.
+            (
+                "<<<<<<< SEARCH\n    2\n=======\n>>>>>>> REPLACE\n<<<<<<< SEARCH\n    1\n=======\n>>>>>>> REPLACE\n",
+                "couldn't find",
+            ),
+        ] {
+            let error = parse(response, 3).unwrap_err();
+            let error = error.downcast_ref::<MalformedEdit>().unwrap();
+            assert!(
+                error.reason.contains(expected_reason),
+                "unexpected reason for {response:?}: {}",
+                error.reason
+            );
+            assert_eq!(error.response, response);
+        }
+    }
+}
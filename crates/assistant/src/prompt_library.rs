@@ -793,10 +793,14 @@ impl PromptLibrary {
                                         role: Role::System,
                                         content: vec![body.to_string().into()],
                                         cache: false,
+                                        attachments: Vec::new(),
                                     }],
                                     tools: Vec::new(),
                                     stop: Vec::new(),
                                     temperature: None,
+                                    top_p: None,
+                                    max_tokens: None,
+                                    response_format: None,
                                 },
                                 cx,
                             )
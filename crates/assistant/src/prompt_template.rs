@@ -0,0 +1,199 @@
+use crate::assistant_settings::AssistantSettings;
+use editor::Editor;
+use gpui::AppContext;
+use language_model::{LanguageModelRequestMessage, MessageContent, Role};
+
+/// The values that can be substituted into a [`PromptTemplate`].
+#[derive(Clone, Debug, Default)]
+pub struct PromptContext {
+    pub language: Option<String>,
+    pub file_path: Option<String>,
+    pub selection: Option<String>,
+}
+
+impl PromptContext {
+    /// Describes the editor's buffer and its newest selection.
+    pub fn for_editor(editor: &Editor, cx: &AppContext) -> Self {
+        let buffer = editor.buffer().read(cx).snapshot(cx);
+        let selection = editor.selections.newest::<usize>(cx);
+        let selected_text = buffer.text_for_range(selection.range()).collect::<String>();
+        Self {
+            language: buffer
+                .language_at(selection.head())
+                .map(|language| language.name().0.to_string()),
+            file_path: buffer
+                .file_at(selection.head())
+                .map(|file| file.full_path(cx).to_string_lossy().into_owned()),
+            selection: (!selected_text.is_empty()).then_some(selected_text),
+        }
+    }
+
+    /// Returns the value of the given placeholder, or `None` if the placeholder isn't recognized.
+    ///
+    /// Placeholders that are recognized but have no value (e.g. `{selection}` when nothing
+    /// is selected) resolve to an empty string.
+    fn resolve(&self, placeholder: &str) -> Option<&str> {
+        let value = match placeholder {
+            "language" => &self.language,
+            "file_path" => &self.file_path,
+            "selection" => &self.selection,
+            _ => return None,
+        };
+        Some(value.as_deref().unwrap_or(""))
+    }
+}
+
+/// A system prompt with `{placeholder}`s that are filled in from a [`PromptContext`].
+///
+/// Use `{{` and `}}` to produce literal braces.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PromptTemplate {
+    template: String,
+}
+
+/// The result of rendering a [`PromptTemplate`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RenderedPrompt {
+    pub text: String,
+    /// Problems found in the template, such as unknown placeholders.
+    pub diagnostics: Vec<String>,
+}
+
+impl PromptTemplate {
+    pub fn new(template: impl Into<String>) -> Self {
+        Self {
+            template: template.into(),
+        }
+    }
+
+    /// Returns the template named in the given settings as the default, if any.
+    pub fn from_settings(settings: &AssistantSettings) -> Option<Self> {
+        let name = settings.default_prompt_template.as_ref()?;
+        let Some(template) = settings.prompt_templates.get(name) else {
+            log::warn!("prompt template {name:?} is not defined");
+            return None;
+        };
+        Some(Self::new(template.clone()))
+    }
+
+    /// Renders the template, logging any diagnostics.
+    pub fn render(&self, context: &PromptContext) -> String {
+        let rendered = self.render_with_diagnostics(context);
+        for diagnostic in &rendered.diagnostics {
+            log::warn!("{diagnostic}");
+        }
+        rendered.text
+    }
+
+    /// Renders the template, collecting diagnostics instead of failing.
+    ///
+    /// Unknown placeholders render as an empty string.
+    pub fn render_with_diagnostics(&self, context: &PromptContext) -> RenderedPrompt {
+        let mut rendered = RenderedPrompt::default();
+        let mut rest = self.template.as_str();
+        while let Some(ix) = rest.find(['{', '}']) {
+            rendered.text.push_str(&rest[..ix]);
+            let brace = &rest[ix..ix + 1];
+            rest = &rest[ix + 1..];
+
+            if let Some(escaped) = rest.strip_prefix(brace) {
+                rendered.text.push_str(brace);
+                rest = escaped;
+            } else if brace == "}" {
+                rendered
+                    .diagnostics
+                    .push("unmatched `}` in prompt template".to_string());
+                rendered.text.push('}');
+            } else if let Some(end) = rest.find('}') {
+                let placeholder = &rest[..end];
+                rest = &rest[end + 1..];
+                match context.resolve(placeholder) {
+                    Some(value) => rendered.text.push_str(value),
+                    None => rendered.diagnostics.push(format!(
+                        "unknown placeholder `{{{placeholder}}}` in prompt template"
+                    )),
+                }
+            } else {
+                rendered
+                    .diagnostics
+                    .push("unterminated placeholder in prompt template".to_string());
+                rendered.text.push('{');
+            }
+        }
+        rendered.text.push_str(rest);
+        rendered
+    }
+
+    /// Renders the template into a system message to place at the start of a request.
+    pub fn system_message(&self, context: &PromptContext) -> LanguageModelRequestMessage {
+        LanguageModelRequestMessage {
+            role: Role::System,
+            content: vec![MessageContent::Text(self.render(context))],
+            cache: false,
+            attachments: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> PromptContext {
+        PromptContext {
+            language: Some("Rust".into()),
+            file_path: Some("src/main.rs".into()),
+            selection: Some("fn main() {}".into()),
+        }
+    }
+
+    #[test]
+    fn test_render_placeholders() {
+        let template = PromptTemplate::new("You are editing {file_path}, written in {language}.");
+        assert_eq!(
+            template.render_with_diagnostics(&context()),
+            RenderedPrompt {
+                text: "You are editing src/main.rs, written in Rust.".into(),
+                diagnostics: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_render_escaped_braces() {
+        let template = PromptTemplate::new("Use {{language}} literally, or {{{language}}}.");
+        assert_eq!(
+            template.render_with_diagnostics(&context()),
+            RenderedPrompt {
+                text: "Use {language} literally, or {Rust}.".into(),
+                diagnostics: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_render_unknown_and_nested_placeholders() {
+        let template = PromptTemplate::new("A{project}B{outer{language}}C");
+        let rendered = template.render_with_diagnostics(&context());
+        assert_eq!(rendered.text, "AB}C");
+        assert_eq!(
+            rendered.diagnostics,
+            vec![
+                "unknown placeholder `{project}` in prompt template".to_string(),
+                "unknown placeholder `{outer{language}` in prompt template".to_string(),
+                "unmatched `}` in prompt template".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_missing_selection() {
+        let template = PromptTemplate::new("Selected: [{selection}]");
+        let rendered = template.render_with_diagnostics(&PromptContext {
+            selection: None,
+            ..context()
+        });
+        assert_eq!(rendered.text, "Selected: []");
+        assert!(rendered.diagnostics.is_empty());
+    }
+}
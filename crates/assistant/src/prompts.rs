@@ -220,6 +220,31 @@ impl PromptBuilder {
         buffer: BufferSnapshot,
         range: Range<usize>,
     ) -> Result<String, RenderError> {
+        let context = Self::content_prompt_context(user_prompt, language_name, buffer, range);
+        self.handlebars.lock().render("content_prompt", &context)
+    }
+
+    /// Like [`Self::generate_content_prompt`], but asks for search/replace blocks that edit the
+    /// given range instead of a rewrite of it.
+    pub fn generate_content_edits_prompt(
+        &self,
+        user_prompt: String,
+        language_name: Option<&LanguageName>,
+        buffer: BufferSnapshot,
+        range: Range<usize>,
+    ) -> Result<String, RenderError> {
+        let context = Self::content_prompt_context(user_prompt, language_name, buffer, range);
+        self.handlebars
+            .lock()
+            .render("content_edits_prompt", &context)
+    }
+
+    fn content_prompt_context(
+        user_prompt: String,
+        language_name: Option<&LanguageName>,
+        buffer: BufferSnapshot,
+        range: Range<usize>,
+    ) -> ContentPromptContext {
         let content_type = match language_name.as_ref().map(|l| l.0.as_ref()) {
             None | Some("Markdown" | "Plain Text") => "text",
             Some(_) => "code",
@@ -285,7 +310,7 @@ impl PromptBuilder {
             })
             .collect();
 
-        let context = ContentPromptContext {
+        ContentPromptContext {
             content_type: content_type.to_string(),
             language_name: language_name.map(|s| s.to_string()),
             is_insert,
@@ -294,8 +319,7 @@ impl PromptBuilder {
             user_prompt,
             rewrite_section,
             diagnostic_errors,
-        };
-        self.handlebars.lock().render("content_prompt", &context)
+        }
     }
 
     pub fn generate_terminal_assistant_prompt(
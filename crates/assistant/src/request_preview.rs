@@ -0,0 +1,178 @@
+use crate::{assistant_settings::AssistantSettings, fit_request_to_model, Context, FittedRequest};
+use anyhow::{anyhow, Result};
+use gpui::{AppContext, AsyncAppContext, Model, Task};
+use language_model::{
+    LanguageModel, LanguageModelRequest, LanguageModelRequestMessage, MessageAttachment,
+    MessageContent, Role,
+};
+use settings::Settings;
+use std::sync::Arc;
+
+/// The request that assisting in a context would send, exactly as the provider would receive
+/// it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderedRequest {
+    /// The request, after truncation and with its attachments expanded.
+    pub request: LanguageModelRequest,
+    /// The number of tokens in each of the request's messages.
+    pub message_token_counts: Vec<usize>,
+    /// The messages that were omitted to make the request fit the model's context window.
+    pub omitted_messages: Vec<LanguageModelRequestMessage>,
+    /// The markers of the attachments whose bodies were omitted to make the request fit.
+    pub omitted_attachments: Vec<String>,
+}
+
+/// Assembles the request that assisting in the context would send, without sending it.
+///
+/// This goes through the same steps as [`Context::assist`], so the preview matches the request
+/// that a subsequent assist would send, as long as the context doesn't change in between.
+pub fn build_request_preview(
+    context: &Model<Context>,
+    cx: &mut AppContext,
+) -> Task<Result<RenderedRequest>> {
+    let Some((model, request)) = context.update(cx, |context, cx| {
+        let model = context.authenticated_model(cx)?;
+        let request = context.to_assist_request(&model, cx);
+        Some((model, request))
+    }) else {
+        return Task::ready(Err(anyhow!("no authenticated language model")));
+    };
+    let strategy = AssistantSettings::get_global(cx).context_overflow_strategy;
+    cx.spawn(|cx| async move {
+        let fitted_request = fit_request_to_model(request, &model, strategy, &cx).await?;
+        RenderedRequest::new(fitted_request, &model, &cx).await
+    })
+}
+
+impl RenderedRequest {
+    async fn new(
+        fitted_request: FittedRequest,
+        model: &Arc<dyn LanguageModel>,
+        cx: &AsyncAppContext,
+    ) -> Result<Self> {
+        let FittedRequest {
+            mut request,
+            omitted_messages,
+            ..
+        } = fitted_request;
+        let omitted_attachments = request
+            .messages
+            .iter()
+            .flat_map(|message| &message.attachments)
+            .filter_map(|attachment| match attachment {
+                MessageAttachment::Omitted { marker } => Some(marker.clone()),
+                _ => None,
+            })
+            .collect();
+
+        request.expand_attachments();
+        let mut message_token_counts = Vec::with_capacity(request.messages.len());
+        for message in &request.messages {
+            let message_request = LanguageModelRequest {
+                messages: vec![message.clone()],
+                ..Default::default()
+            };
+            message_token_counts.push(
+                cx.update(|cx| model.count_tokens(message_request, cx))?
+                    .await?,
+            );
+        }
+
+        Ok(Self {
+            request,
+            message_token_counts,
+            omitted_messages,
+            omitted_attachments,
+        })
+    }
+
+    /// Renders the request as Markdown, with a section for each message followed by what was
+    /// omitted.
+    pub fn to_markdown(&self) -> String {
+        let mut markdown = "# Request Preview\n\n".to_string();
+
+        let mut parameters = Vec::new();
+        if let Some(temperature) = self.request.temperature {
+            parameters.push(format!("- Temperature: {temperature}"));
+        }
+        if let Some(top_p) = self.request.top_p {
+            parameters.push(format!("- Top P: {top_p}"));
+        }
+        if let Some(max_tokens) = self.request.max_tokens {
+            parameters.push(format!("- Max tokens: {max_tokens}"));
+        }
+        if !self.request.stop.is_empty() {
+            parameters.push(format!("- Stop sequences: {:?}", self.request.stop));
+        }
+        if !self.request.tools.is_empty() {
+            let tool_names = self
+                .request
+                .tools
+                .iter()
+                .map(|tool| tool.name.as_str())
+                .collect::<Vec<_>>();
+            parameters.push(format!("- Tools: {}", tool_names.join(", ")));
+        }
+        let total_token_count = self.message_token_counts.iter().sum::<usize>();
+        parameters.push(format!("- Total tokens: {total_token_count}"));
+        markdown.push_str(&parameters.join("\n"));
+        markdown.push_str("\n\n");
+
+        for (message, token_count) in self.request.messages.iter().zip(&self.message_token_counts) {
+            let noun = if *token_count == 1 { "token" } else { "tokens" };
+            markdown.push_str(&format!(
+                "## {} ({token_count} {noun})\n\n",
+                role_heading(message.role)
+            ));
+            for content in &message.content {
+                match content {
+                    MessageContent::Text(text) => markdown.push_str(text.trim_end()),
+                    MessageContent::Image(_) => markdown.push_str("[image]"),
+                    MessageContent::ToolUse(tool_use) => markdown.push_str(&format!(
+                        "[tool use: {}]\n{}",
+                        tool_use.name,
+                        serde_json::to_string_pretty(&tool_use.input).unwrap_or_default()
+                    )),
+                    MessageContent::ToolResult(tool_result) => markdown.push_str(&format!(
+                        "[tool result: {}]\n{}",
+                        tool_result.tool_use_id,
+                        tool_result.content.trim_end()
+                    )),
+                }
+                markdown.push_str("\n\n");
+            }
+        }
+
+        if !self.omitted_messages.is_empty() {
+            markdown.push_str("## Omitted Messages\n\n");
+            for message in &self.omitted_messages {
+                let text = message.string_contents();
+                let first_line = text.lines().find(|line| !line.trim().is_empty());
+                markdown.push_str(&format!(
+                    "- {}: {}\n",
+                    role_heading(message.role),
+                    first_line.unwrap_or_default().trim()
+                ));
+            }
+            markdown.push('\n');
+        }
+        if !self.omitted_attachments.is_empty() {
+            markdown.push_str("## Omitted Attachments\n\n");
+            for marker in &self.omitted_attachments {
+                markdown.push_str(&format!("- {marker}\n"));
+            }
+        }
+
+        markdown.truncate(markdown.trim_end().len());
+        markdown.push('\n');
+        markdown
+    }
+}
+
+fn role_heading(role: Role) -> &'static str {
+    match role {
+        Role::User => "User",
+        Role::Assistant => "Assistant",
+        Role::System => "System",
+    }
+}
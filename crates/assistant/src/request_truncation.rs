@@ -0,0 +1,438 @@
+use crate::assistant_settings::ContextOverflowStrategy;
+use anyhow::Result;
+use gpui::AsyncAppContext;
+use language_model::{
+    LanguageModel, LanguageModelRequest, LanguageModelRequestMessage, MessageContent, Role,
+};
+use std::{ops::Range, sync::Arc};
+
+/// The request doesn't fit in the model's context window, even after omitting every message
+/// that may be omitted.
+#[derive(Debug, thiserror::Error)]
+#[error("message is too large for {model_name}, which accepts at most {max_token_count} tokens")]
+pub struct MessageTooLarge {
+    pub model_name: String,
+    pub max_token_count: usize,
+}
+
+/// A request that fits in the model's context window.
+#[derive(Debug)]
+pub struct FittedRequest {
+    pub request: LanguageModelRequest,
+    /// The number of earlier messages that were omitted to make the request fit.
+    pub omitted_message_count: usize,
+    /// The messages that were omitted to make the request fit, in their original order.
+    pub omitted_messages: Vec<LanguageModelRequestMessage>,
+    /// The number of attachments whose bodies were replaced with a marker to make the request
+    /// fit.
+    pub omitted_attachment_count: usize,
+}
+
+/// Makes the request fit in the model's context window according to the given strategy.
+///
+/// When dropping messages, the bodies of attachments are omitted first, and then whole turns,
+/// oldest first in both cases. A turn is a user message along with the replies to it,
+/// including any tool uses and their results, so that no reply is left without the message it
+/// answers. The leading system prompt and the latest user message's turn are always kept.
+pub async fn fit_request_to_model(
+    mut request: LanguageModelRequest,
+    model: &Arc<dyn LanguageModel>,
+    strategy: ContextOverflowStrategy,
+    cx: &AsyncAppContext,
+) -> Result<FittedRequest> {
+    let max_token_count = model.max_token_count();
+    let mut token_count = count_tokens(request.clone(), model, cx).await?;
+    if token_count <= max_token_count {
+        return Ok(FittedRequest {
+            request,
+            omitted_message_count: 0,
+            omitted_messages: Vec::new(),
+            omitted_attachment_count: 0,
+        });
+    }
+
+    let message_too_large = || MessageTooLarge {
+        model_name: model.name().0.to_string(),
+        max_token_count,
+    };
+    if strategy == ContextOverflowStrategy::Error {
+        return Err(message_too_large().into());
+    }
+
+    let system_prompt_len = request
+        .messages
+        .iter()
+        .take_while(|message| message.role == Role::System)
+        .count();
+    let latest_user_message_ix = request
+        .messages
+        .iter()
+        .rposition(|message| message.role == Role::User);
+
+    // Rather than recounting the whole request after each omission, subtract the tokens
+    // of each omitted attachment or message from the total.
+    let mut omitted_attachment_count = 0;
+    'attachments: for message in &mut request.messages[system_prompt_len..] {
+        for attachment in &mut message.attachments {
+            if token_count <= max_token_count {
+                break 'attachments;
+            }
+            let omitted_attachment = attachment.omitted();
+            if *attachment == omitted_attachment {
+                continue;
+            }
+
+            let attachment_token_count = count_text_tokens(attachment.render(), model, cx).await?;
+            let marker_token_count =
+                count_text_tokens(omitted_attachment.render(), model, cx).await?;
+            token_count = token_count
+                .saturating_sub(attachment_token_count.saturating_sub(marker_token_count));
+            *attachment = omitted_attachment;
+            omitted_attachment_count += 1;
+        }
+    }
+
+    let mut turns = Vec::<Range<usize>>::new();
+    for ix in system_prompt_len..request.messages.len() {
+        match turns.last_mut() {
+            Some(turn) if !starts_turn(&request.messages[ix]) => turn.end = ix + 1,
+            _ => turns.push(ix..ix + 1),
+        }
+    }
+
+    let mut omitted = vec![false; request.messages.len()];
+    for turn in turns {
+        if token_count <= max_token_count {
+            break;
+        }
+        if latest_user_message_ix.map_or(false, |ix| turn.contains(&ix)) {
+            continue;
+        }
+
+        let turn_request = LanguageModelRequest {
+            messages: request.messages[turn.clone()].to_vec(),
+            ..Default::default()
+        };
+        let turn_token_count = count_tokens(turn_request, model, cx).await?;
+        token_count = token_count.saturating_sub(turn_token_count);
+        omitted[turn].fill(true);
+    }
+    if token_count > max_token_count {
+        return Err(message_too_large().into());
+    }
+
+    let (omitted_messages, messages) = request
+        .messages
+        .into_iter()
+        .zip(omitted)
+        .partition::<Vec<_>, _>(|(_, omitted)| *omitted);
+    request.messages = messages.into_iter().map(|(message, _)| message).collect();
+    let omitted_messages = omitted_messages
+        .into_iter()
+        .map(|(message, _)| message)
+        .collect::<Vec<_>>();
+    Ok(FittedRequest {
+        request,
+        omitted_message_count: omitted_messages.len(),
+        omitted_messages,
+        omitted_attachment_count,
+    })
+}
+
+/// Returns whether the message starts a turn: a user message, unless it only returns the
+/// results of the tool uses before it.
+fn starts_turn(message: &LanguageModelRequestMessage) -> bool {
+    let is_tool_results = !message.content.is_empty()
+        && message
+            .content
+            .iter()
+            .all(|content| matches!(content, MessageContent::ToolResult(_)));
+    message.role == Role::User && !is_tool_results
+}
+
+/// Counts the tokens of the request as the model will see it, with its attachments expanded.
+async fn count_tokens(
+    mut request: LanguageModelRequest,
+    model: &Arc<dyn LanguageModel>,
+    cx: &AsyncAppContext,
+) -> Result<usize> {
+    request.expand_attachments();
+    cx.update(|cx| model.count_tokens(request, cx))?.await
+}
+
+async fn count_text_tokens(
+    text: String,
+    model: &Arc<dyn LanguageModel>,
+    cx: &AsyncAppContext,
+) -> Result<usize> {
+    let request = LanguageModelRequest {
+        messages: vec![LanguageModelRequestMessage {
+            role: Role::User,
+            content: vec![MessageContent::Text(text)],
+            cache: false,
+            attachments: Vec::new(),
+        }],
+        ..Default::default()
+    };
+    count_tokens(request, model, cx).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gpui::TestAppContext;
+    use language_model::{
+        provider::fake::FakeLanguageModel, LanguageModelToolResult, LanguageModelToolUse,
+        MessageAttachment,
+    };
+
+    fn message(role: Role, text: &str) -> LanguageModelRequestMessage {
+        LanguageModelRequestMessage {
+            role,
+            content: vec![MessageContent::Text(text.into())],
+            cache: false,
+            attachments: Vec::new(),
+        }
+    }
+
+    fn conversation() -> LanguageModelRequest {
+        LanguageModelRequest {
+            messages: vec![
+                message(Role::System, "you are helpful"),
+                message(Role::User, "one two three four"),
+                message(Role::Assistant, "five six seven"),
+                message(Role::User, "eight nine"),
+                message(Role::Assistant, "ten eleven"),
+                message(Role::User, "the latest question"),
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[gpui::test]
+    async fn test_drop_oldest_messages(cx: &mut TestAppContext) {
+        // The fake model counts each word as a token, so the conversation is 17 tokens long.
+        let model: Arc<dyn LanguageModel> = Arc::new(FakeLanguageModel::with_max_token_count(10));
+        let fitted = fit_request_to_model(
+            conversation(),
+            &model,
+            ContextOverflowStrategy::DropOldest,
+            &cx.to_async(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(fitted.omitted_message_count, 2);
+        assert_eq!(
+            fitted.omitted_messages,
+            vec![
+                message(Role::User, "one two three four"),
+                message(Role::Assistant, "five six seven"),
+            ]
+        );
+        assert_eq!(
+            fitted.request.messages,
+            vec![
+                message(Role::System, "you are helpful"),
+                message(Role::User, "eight nine"),
+                message(Role::Assistant, "ten eleven"),
+                message(Role::User, "the latest question"),
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_drop_whole_turns(cx: &mut TestAppContext) {
+        // Omitting the oldest user message alone would fit the conversation in 13 tokens, but
+        // the reply to it is omitted along with it.
+        let model: Arc<dyn LanguageModel> = Arc::new(FakeLanguageModel::with_max_token_count(13));
+        let fitted = fit_request_to_model(
+            conversation(),
+            &model,
+            ContextOverflowStrategy::DropOldest,
+            &cx.to_async(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            fitted.omitted_messages,
+            vec![
+                message(Role::User, "one two three four"),
+                message(Role::Assistant, "five six seven"),
+            ]
+        );
+
+        // Tool uses and their results belong to the turn of the user message that led to them.
+        let mut tool_use = message(Role::Assistant, "let me check");
+        tool_use
+            .content
+            .push(MessageContent::ToolUse(LanguageModelToolUse {
+                id: "tool-1".into(),
+                name: "weather".into(),
+                input: serde_json::json!({"city": "Paris"}),
+            }));
+        let tool_result = LanguageModelRequestMessage {
+            role: Role::User,
+            content: vec![MessageContent::ToolResult(LanguageModelToolResult {
+                tool_use_id: "tool-1".into(),
+                is_error: false,
+                content: "sunny".into(),
+            })],
+            cache: false,
+            attachments: Vec::new(),
+        };
+        let request = LanguageModelRequest {
+            messages: vec![
+                message(Role::System, "you are helpful"),
+                message(Role::User, "what is the weather"),
+                tool_use.clone(),
+                tool_result.clone(),
+                message(Role::Assistant, "it is sunny"),
+                message(Role::User, "the latest question"),
+            ],
+            ..Default::default()
+        };
+        let fitted = fit_request_to_model(
+            request,
+            &model,
+            ContextOverflowStrategy::DropOldest,
+            &cx.to_async(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            fitted.omitted_messages,
+            vec![
+                message(Role::User, "what is the weather"),
+                tool_use,
+                tool_result,
+                message(Role::Assistant, "it is sunny"),
+            ]
+        );
+        assert_eq!(
+            fitted.request.messages,
+            vec![
+                message(Role::System, "you are helpful"),
+                message(Role::User, "the latest question"),
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_omit_attachments_before_messages(cx: &mut TestAppContext) {
+        let file = MessageAttachment::File {
+            path: "a.rs".into(),
+            text: "alpha beta gamma delta epsilon".into(),
+            language: None,
+        };
+        let terminal = MessageAttachment::Terminal {
+            text: "zeta eta theta".into(),
+        };
+        let conversation = || {
+            let mut question = message(Role::User, "one two three four");
+            question.attachments = vec![file.clone()];
+            let mut latest_question = message(Role::User, "the latest question");
+            latest_question.attachments = vec![terminal.clone()];
+            LanguageModelRequest {
+                messages: vec![
+                    message(Role::System, "you are helpful"),
+                    question,
+                    message(Role::Assistant, "five six seven"),
+                    latest_question,
+                ],
+                ..Default::default()
+            }
+        };
+        let fit = |max_token_count| {
+            let model: Arc<dyn LanguageModel> =
+                Arc::new(FakeLanguageModel::with_max_token_count(max_token_count));
+            let request = conversation();
+            let cx = cx.to_async();
+            async move {
+                fit_request_to_model(request, &model, ContextOverflowStrategy::DropOldest, &cx)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        // With its attachments expanded, the conversation is 28 tokens long, and omitting
+        // the file saves 5 tokens.
+        let fitted = fit(24).await;
+        assert_eq!(fitted.omitted_attachment_count, 1);
+        assert_eq!(fitted.omitted_message_count, 0);
+        assert_eq!(fitted.request.messages[1].attachments, [file.omitted()]);
+        assert_eq!(fitted.request.messages[3].attachments, [terminal.clone()]);
+
+        // Even the latest message's attachments are omitted before any messages.
+        let fitted = fit(20).await;
+        assert_eq!(fitted.omitted_attachment_count, 2);
+        assert_eq!(fitted.omitted_message_count, 0);
+        assert_eq!(fitted.request.messages[3].attachments, [terminal.omitted()]);
+
+        // The reply to the omitted question is omitted along with it.
+        let fitted = fit(15).await;
+        assert_eq!(fitted.omitted_attachment_count, 2);
+        assert_eq!(fitted.omitted_message_count, 2);
+        let mut expanded_request = fitted.request;
+        expanded_request.expand_attachments();
+        assert_eq!(
+            expanded_request
+                .messages
+                .iter()
+                .map(|message| message.string_contents())
+                .collect::<Vec<_>>(),
+            [
+                "you are helpful",
+                "[terminal output omitted]\n\nthe latest question",
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_request_that_fits_is_unchanged(cx: &mut TestAppContext) {
+        let model: Arc<dyn LanguageModel> = Arc::new(FakeLanguageModel::with_max_token_count(17));
+        let fitted = fit_request_to_model(
+            conversation(),
+            &model,
+            ContextOverflowStrategy::Error,
+            &cx.to_async(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(fitted.omitted_message_count, 0);
+        assert_eq!(fitted.request, conversation());
+    }
+
+    #[gpui::test]
+    async fn test_message_too_large(cx: &mut TestAppContext) {
+        let model: Arc<dyn LanguageModel> = Arc::new(FakeLanguageModel::with_max_token_count(10));
+        let error = fit_request_to_model(
+            conversation(),
+            &model,
+            ContextOverflowStrategy::Error,
+            &cx.to_async(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            error
+                .downcast_ref::<MessageTooLarge>()
+                .unwrap()
+                .max_token_count,
+            10
+        );
+
+        // The system prompt and the latest user message alone are 6 tokens long.
+        let model: Arc<dyn LanguageModel> = Arc::new(FakeLanguageModel::with_max_token_count(5));
+        let error = fit_request_to_model(
+            conversation(),
+            &model,
+            ContextOverflowStrategy::DropOldest,
+            &cx.to_async(),
+        )
+        .await
+        .unwrap_err();
+        assert!(error.is::<MessageTooLarge>());
+    }
+}
@@ -213,10 +213,14 @@ async fn commands_for_summaries(
             content: vec![prompt.into()],
             // Nothing in here will benefit from caching
             cache: false,
+            attachments: Vec::new(),
         }],
         tools: Vec::new(),
         stop: Vec::new(),
         temperature: None,
+        top_p: None,
+        max_tokens: None,
+        response_format: None,
     };
 
     while let Some(current_summaries) = stack.pop() {
@@ -105,10 +105,14 @@ impl SlashCommand for ProjectSlashCommand {
                             role: language_model::Role::User,
                             content: vec![language_model::MessageContent::Text(prompt)],
                             cache: false,
+                            attachments: Vec::new(),
                         }],
                         tools: vec![],
                         stop: vec![],
                         temperature: None,
+                        top_p: None,
+                        max_tokens: None,
+                        response_format: None,
                     },
                     cx.deref_mut(),
                 )
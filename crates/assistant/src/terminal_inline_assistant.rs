@@ -1,6 +1,6 @@
 use crate::{
-    humanize_token_count, prompts::PromptBuilder, AssistantPanel, AssistantPanelEvent,
-    ModelSelector, DEFAULT_CONTEXT_LINES,
+    assistant_settings::AssistantSettings, humanize_token_count, prompts::PromptBuilder,
+    AssistantPanel, AssistantPanelEvent, ModelSelector, DEFAULT_CONTEXT_LINES,
 };
 use anyhow::{Context as _, Result};
 use client::telemetry::Telemetry;
@@ -17,8 +17,10 @@ use gpui::{
 };
 use language::Buffer;
 use language_model::{
-    LanguageModelRegistry, LanguageModelRequest, LanguageModelRequestMessage, Role,
+    apply_transforms, LanguageModel, LanguageModelRequest, LanguageModelRequestMessage, Role,
+    StripCodeFences, TrimTrailingWhitespace,
 };
+use project::Project;
 use settings::Settings;
 use std::{
     cmp,
@@ -94,7 +96,11 @@ impl TerminalInlineAssistant {
         let prompt_buffer =
             cx.new_model(|cx| Buffer::local(initial_prompt.unwrap_or_default(), cx));
         let prompt_buffer = cx.new_model(|cx| MultiBuffer::singleton(prompt_buffer, cx));
-        let codegen = cx.new_model(|_| Codegen::new(terminal, self.telemetry.clone()));
+        let project = workspace
+            .as_ref()
+            .and_then(|workspace| workspace.upgrade())
+            .map(|workspace| workspace.read(cx).project().clone());
+        let codegen = cx.new_model(|_| Codegen::new(terminal, project, self.telemetry.clone()));
 
         let prompt_editor = cx.new_view(|cx| {
             PromptEditor::new(
@@ -278,6 +284,7 @@ impl TerminalInlineAssistant {
             role: Role::User,
             content: vec![prompt.into()],
             cache: false,
+            attachments: Vec::new(),
         });
 
         Ok(LanguageModelRequest {
@@ -285,6 +292,9 @@ impl TerminalInlineAssistant {
             tools: Vec::new(),
             stop: Vec::new(),
             temperature: None,
+            top_p: None,
+            max_tokens: None,
+            response_format: None,
         })
     }
 
@@ -465,6 +475,12 @@ impl EventEmitter<PromptEditorEvent> for PromptEditor {}
 
 impl Render for PromptEditor {
     fn render(&mut self, cx: &mut ViewContext<Self>) -> impl IntoElement {
+        let model_name = self
+            .codegen
+            .read(cx)
+            .model(cx)
+            .map(|model| model.name().0)
+            .unwrap_or_else(|| "No model selected".into());
         let status = &self.codegen.read(cx).status;
         let buttons = match status {
             CodegenStatus::Idle => {
@@ -591,13 +607,7 @@ impl Render for PromptEditor {
                             .icon_color(Color::Muted)
                             .tooltip(move |cx| {
                                 Tooltip::with_meta(
-                                    format!(
-                                        "Using {}",
-                                        LanguageModelRegistry::read_global(cx)
-                                            .active_model()
-                                            .map(|model| model.name().0)
-                                            .unwrap_or_else(|| "No model selected".into()),
-                                    ),
+                                    format!("Using {model_name}"),
                                     None,
                                     "Change Model",
                                     cx,
@@ -737,7 +747,7 @@ impl PromptEditor {
 
     fn count_tokens(&mut self, cx: &mut ViewContext<Self>) {
         let assist_id = self.id;
-        let Some(model) = LanguageModelRegistry::read_global(cx).active_model() else {
+        let Some(model) = self.codegen.read(cx).model(cx) else {
             return;
         };
         self.pending_token_count = cx.spawn(|this, mut cx| async move {
@@ -885,7 +895,7 @@ impl PromptEditor {
     }
 
     fn render_token_count(&self, cx: &mut ViewContext<Self>) -> Option<impl IntoElement> {
-        let model = LanguageModelRegistry::read_global(cx).active_model()?;
+        let model = self.codegen.read(cx).model(cx)?;
         let token_count = self.token_count?;
         let max_token_count = model.max_token_count();
 
@@ -1012,6 +1022,8 @@ impl TerminalTransaction {
 
 pub struct Codegen {
     status: CodegenStatus,
+    /// The project whose settings apply, if the assist was started in a workspace.
+    project: Option<Model<Project>>,
     telemetry: Option<Arc<Telemetry>>,
     terminal: Model<Terminal>,
     generation: Task<()>,
@@ -1019,9 +1031,14 @@ pub struct Codegen {
 }
 
 impl Codegen {
-    pub fn new(terminal: Model<Terminal>, telemetry: Option<Arc<Telemetry>>) -> Self {
+    pub fn new(
+        terminal: Model<Terminal>,
+        project: Option<Model<Project>>,
+        telemetry: Option<Arc<Telemetry>>,
+    ) -> Self {
         Self {
             terminal,
+            project,
             telemetry,
             status: CodegenStatus::Idle,
             generation: Task::ready(()),
@@ -1029,8 +1046,13 @@ impl Codegen {
         }
     }
 
+    /// The model that the command is generated with.
+    pub fn model(&self, cx: &AppContext) -> Option<Arc<dyn LanguageModel>> {
+        AssistantSettings::model_for_project(self.project.as_ref(), cx)
+    }
+
     pub fn start(&mut self, prompt: LanguageModelRequest, cx: &mut ModelContext<Self>) {
-        let Some(model) = LanguageModelRegistry::read_global(cx).active_model() else {
+        let Some(model) = self.model(cx) else {
             return;
         };
 
@@ -1039,7 +1061,20 @@ impl Codegen {
         self.transaction = Some(TerminalTransaction::start(self.terminal.clone()));
         self.generation = cx.spawn(|this, mut cx| async move {
             let model_telemetry_id = model.telemetry_id();
-            let response = model.stream_completion_text(prompt, &cx).await;
+            // Models tend to wrap commands in a code block, whose fences would end up in the
+            // command line.
+            let response = model
+                .stream_completion_text(prompt, &cx)
+                .await
+                .map(|chunks| {
+                    apply_transforms(
+                        chunks,
+                        vec![
+                            Box::new(StripCodeFences::new()),
+                            Box::new(TrimTrailingWhitespace::new()),
+                        ],
+                    )
+                });
             let generate = async {
                 let (mut hunks_tx, mut hunks_rx) = mpsc::channel(1);
 
@@ -25,6 +25,12 @@ pub trait Tool: 'static + Send + Sync {
         serde_json::Value::Object(serde_json::Map::default())
     }
 
+    /// Returns whether the user has to allow each use of the tool before it runs, e.g. because
+    /// it changes files or runs commands.
+    fn requires_confirmation(&self) -> bool {
+        false
+    }
+
     /// Runs the tool with the provided input.
     fn run(
         self: Arc<Self>,
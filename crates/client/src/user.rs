@@ -2,7 +2,7 @@ use super::{proto, Client, Status, TypedEnvelope};
 use anyhow::{anyhow, Context, Result};
 use chrono::{DateTime, Utc};
 use collections::{hash_map::Entry, HashMap, HashSet};
-use feature_flags::FeatureFlagAppExt;
+use feature_flags::{FeatureFlagAppExt, OptInFeatureFlag};
 use futures::{channel::mpsc, Future, StreamExt};
 use gpui::{
     AppContext, AsyncAppContext, EventEmitter, Model, ModelContext, SharedString, SharedUri, Task,
@@ -143,6 +143,7 @@ impl UserStore {
         let (update_contacts_tx, mut update_contacts_rx) = mpsc::unbounded();
         let rpc_subscriptions = vec![
             client.add_message_handler(cx.weak_model(), Self::handle_update_plan),
+            client.add_message_handler(cx.weak_model(), Self::handle_update_flags),
             client.add_message_handler(cx.weak_model(), Self::handle_update_contacts),
             client.add_message_handler(cx.weak_model(), Self::handle_update_invite_info),
             client.add_message_handler(cx.weak_model(), Self::handle_show_contacts),
@@ -307,6 +308,18 @@ impl UserStore {
         Ok(())
     }
 
+    async fn handle_update_flags(
+        _: Model<Self>,
+        message: TypedEnvelope<proto::UpdateUserFlags>,
+        mut cx: AsyncAppContext,
+    ) -> Result<()> {
+        cx.update(|cx| {
+            let staff = cx.is_staff();
+            cx.update_flags(staff, message.payload.flags);
+        })?;
+        Ok(())
+    }
+
     fn update_contacts(
         &mut self,
         message: UpdateContacts,
@@ -719,6 +732,61 @@ impl UserStore {
         })
     }
 
+    /// Fetches the feature flags that the current user can opt into, making them available through
+    /// [`FeatureFlagAppExt::opt_in_flags`].
+    pub fn fetch_opt_in_feature_flags(&self, cx: &mut ModelContext<Self>) -> Task<Result<()>> {
+        let client = self.client.clone();
+        cx.spawn(move |_, mut cx| async move {
+            let client = client
+                .upgrade()
+                .ok_or_else(|| anyhow!("client not found"))?;
+            let response = client
+                .request(proto::GetOptInFeatureFlags {})
+                .await
+                .context("error fetching opt-in feature flags")?;
+            cx.update(|cx| {
+                cx.update_opt_in_flags(
+                    response
+                        .flags
+                        .into_iter()
+                        .map(opt_in_flag_from_proto)
+                        .collect(),
+                )
+            })
+        })
+    }
+
+    /// Opts the current user into or out of a feature flag, updating their flags with the
+    /// server's response. The server rejects flags that aren't marked as opt-in.
+    pub fn set_feature_flag_opt_in(
+        &self,
+        flag_id: u64,
+        enabled: bool,
+        cx: &mut ModelContext<Self>,
+    ) -> Task<Result<()>> {
+        let client = self.client.clone();
+        cx.spawn(move |_, mut cx| async move {
+            let client = client
+                .upgrade()
+                .ok_or_else(|| anyhow!("client not found"))?;
+            let response = client
+                .request(proto::SetFeatureFlagOptIn { flag_id, enabled })
+                .await
+                .context("error setting feature flag opt-in")?;
+            cx.update(|cx| {
+                let staff = cx.is_staff();
+                cx.update_flags(staff, response.flags);
+                cx.update_opt_in_flags(
+                    response
+                        .opt_in_flags
+                        .into_iter()
+                        .map(opt_in_flag_from_proto)
+                        .collect(),
+                );
+            })
+        })
+    }
+
     fn set_current_user_accepted_tos_at(&mut self, accepted_tos_at: Option<u64>) {
         self.accepted_tos_at = Some(
             accepted_tos_at.and_then(|timestamp| DateTime::from_timestamp(timestamp as i64, 0)),
@@ -838,3 +906,12 @@ impl Collaborator {
         })
     }
 }
+
+fn opt_in_flag_from_proto(flag: proto::OptInFeatureFlag) -> OptInFeatureFlag {
+    OptInFeatureFlag {
+        id: flag.id,
+        name: flag.name,
+        description: flag.description,
+        opted_in: flag.opted_in,
+    }
+}
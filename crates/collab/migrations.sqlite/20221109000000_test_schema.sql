@@ -297,7 +297,19 @@ CREATE UNIQUE INDEX "index_channel_buffer_collaborators_on_channel_id_connection
 CREATE TABLE "feature_flags" (
     "id" INTEGER PRIMARY KEY AUTOINCREMENT,
     "flag" TEXT NOT NULL UNIQUE,
-    "enabled_for_all" BOOLEAN NOT NULL DEFAULT false
+    "enabled_for_all" BOOLEAN NOT NULL DEFAULT false,
+    "rollout_percentage" INTEGER NOT NULL DEFAULT 0,
+    "updated_at" TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "depends_on" INTEGER REFERENCES feature_flags (id),
+    "staff_only" BOOLEAN NOT NULL DEFAULT false,
+    "value_type" TEXT NOT NULL DEFAULT 'bool',
+    "default_value" TEXT,
+    "minimum_client_version" TEXT,
+    "activate_at" TIMESTAMP,
+    "deactivate_at" TIMESTAMP,
+    "opt_in" BOOLEAN NOT NULL DEFAULT false,
+    "description" TEXT,
+    "max_users" INTEGER
 );
 
 CREATE INDEX "index_feature_flags" ON "feature_flags" ("id");
@@ -306,12 +318,16 @@ CREATE INDEX "index_feature_flags" ON "feature_flags" ("id");
 CREATE TABLE "user_features" (
     "user_id" INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
     "feature_id" INTEGER NOT NULL REFERENCES feature_flags (id) ON DELETE CASCADE,
+    "expires_at" TIMESTAMP,
+    "granted_at" TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "value_override" TEXT,
     PRIMARY KEY (user_id, feature_id)
 );
 
 CREATE UNIQUE INDEX "index_user_features_user_id_and_feature_id" ON "user_features" ("user_id", "feature_id");
 CREATE INDEX "index_user_features_on_user_id" ON "user_features" ("user_id");
 CREATE INDEX "index_user_features_on_feature_id" ON "user_features" ("feature_id");
+CREATE INDEX "index_user_features_on_expires_at" ON "user_features" ("expires_at");
 
 
 CREATE TABLE "observed_buffer_edits" (
@@ -451,3 +467,27 @@ CREATE TABLE IF NOT EXISTS processed_stripe_events (
 );
 
 CREATE INDEX "ix_processed_stripe_events_on_stripe_event_created_timestamp" ON processed_stripe_events (stripe_event_created_timestamp);
+
+CREATE TABLE IF NOT EXISTS feature_flag_audit (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    flag_id INTEGER NOT NULL,
+    flag TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    action TEXT NOT NULL,
+    actor_id INTEGER REFERENCES users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX "ix_feature_flag_audit_on_flag_id" ON feature_flag_audit (flag_id);
+
+CREATE TABLE IF NOT EXISTS feature_flag_stats (
+    flag_id INTEGER NOT NULL REFERENCES feature_flags(id) ON DELETE CASCADE,
+    date DATE NOT NULL,
+    served_count INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (flag_id, date)
+);
+
+CREATE TABLE IF NOT EXISTS flag_cache_versions (
+    user_id INTEGER PRIMARY KEY,
+    version INTEGER NOT NULL DEFAULT 0
+);
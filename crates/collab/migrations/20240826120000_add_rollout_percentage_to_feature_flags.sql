@@ -0,0 +1 @@
+alter table feature_flags add column rollout_percentage integer not null default 0;
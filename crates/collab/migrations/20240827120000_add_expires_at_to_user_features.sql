@@ -0,0 +1,3 @@
+alter table user_features add column expires_at timestamp without time zone;
+
+create index index_user_features_on_expires_at on user_features (expires_at);
@@ -0,0 +1,12 @@
+-- Entries have no foreign keys on the flag and user, so that they outlive them.
+CREATE TABLE IF NOT EXISTS feature_flag_audit (
+    id SERIAL PRIMARY KEY,
+    created_at TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT now(),
+    flag_id INTEGER NOT NULL,
+    flag TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    action TEXT NOT NULL,
+    actor_id INTEGER REFERENCES users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX "ix_feature_flag_audit_on_flag_id" ON feature_flag_audit (flag_id);
@@ -0,0 +1 @@
+alter table feature_flags add column updated_at timestamp without time zone not null default now();
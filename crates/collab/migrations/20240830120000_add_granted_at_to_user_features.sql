@@ -0,0 +1 @@
+alter table user_features add column granted_at timestamp without time zone not null default now();
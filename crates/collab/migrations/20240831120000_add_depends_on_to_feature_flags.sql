@@ -0,0 +1 @@
+alter table feature_flags add column depends_on integer references feature_flags (id);
@@ -0,0 +1 @@
+alter table feature_flags add column staff_only bool not null default false;
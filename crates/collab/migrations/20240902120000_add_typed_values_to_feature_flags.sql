@@ -0,0 +1,3 @@
+alter table feature_flags add column value_type text not null default 'bool';
+alter table feature_flags add column default_value text;
+alter table user_features add column value_override text;
@@ -0,0 +1 @@
+alter table feature_flags add column minimum_client_version text;
@@ -0,0 +1,2 @@
+alter table feature_flags add column activate_at timestamp without time zone;
+alter table feature_flags add column deactivate_at timestamp without time zone;
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS feature_flag_stats (
+    flag_id INTEGER NOT NULL REFERENCES feature_flags(id) ON DELETE CASCADE,
+    date DATE NOT NULL,
+    served_count BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (flag_id, date)
+);
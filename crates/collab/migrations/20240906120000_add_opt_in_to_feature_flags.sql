@@ -0,0 +1,2 @@
+alter table feature_flags add column opt_in boolean not null default false;
+alter table feature_flags add column description text;
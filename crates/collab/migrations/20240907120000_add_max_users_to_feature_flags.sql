@@ -0,0 +1 @@
+alter table feature_flags add column max_users integer;
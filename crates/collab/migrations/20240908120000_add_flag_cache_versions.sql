@@ -0,0 +1,6 @@
+-- Versions that flag mutations bump, so that every collab node can tell when the flags it has
+-- cached are stale. The row with a `user_id` of 0 covers changes that affect every user.
+CREATE TABLE IF NOT EXISTS flag_cache_versions (
+    user_id INTEGER PRIMARY KEY,
+    version BIGINT NOT NULL DEFAULT 0
+);
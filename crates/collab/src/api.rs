@@ -2,6 +2,7 @@ pub mod billing;
 pub mod contributors;
 pub mod events;
 pub mod extensions;
+pub mod feature_flags;
 pub mod ips_file;
 pub mod slack;
 
@@ -68,6 +69,7 @@ pub fn routes(rpc_server: Arc<rpc::Server>) -> Router<(), Body> {
         .route("/rpc_server_snapshot", get(get_rpc_server_snapshot))
         .merge(billing::router())
         .merge(contributors::router())
+        .merge(feature_flags::router())
         .layer(
             ServiceBuilder::new()
                 .layer(Extension(rpc_server))
@@ -0,0 +1,646 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    body::StreamBody,
+    extract,
+    http::{
+        header::{CONTENT_TYPE, ETAG, IF_NONE_MATCH},
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use collections::HashSet;
+use futures::stream;
+use semantic_version::SemanticVersion;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use util::ResultExt;
+
+use crate::db::{
+    feature_flag::FlagValue, feature_flag_audit, feature_flag_stats, FeatureFlagAuditId,
+    FeatureFlagWithUserCount, FlagConfig, FlagConfigDiff, FlagId, FlagInvalidation, FlagUsersPage,
+    UserFilter, UserFlag, UserFlagsWithVersion, UserId, FLAG_MAX_USERS_WARNING_PERCENTAGE,
+};
+use crate::{rpc, AppState, Error, Result};
+
+const PURGE_EXPIRED_USER_FLAGS_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const FLAG_SCHEDULE_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+const FLAG_STATS_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+const FLAG_CACHE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const FLAG_MAX_USERS_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const EXPORT_FLAG_ASSIGNMENTS_PAGE_SIZE: u64 = 1000;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/feature_flags", get(list_feature_flags))
+        .route("/feature_flags/:flag_id", delete(delete_feature_flag))
+        .route(
+            "/feature_flags/:flag_id/rollout",
+            put(set_feature_flag_rollout),
+        )
+        .route(
+            "/feature_flags/:flag_id/staff_only",
+            put(set_feature_flag_staff_only),
+        )
+        .route(
+            "/feature_flags/:flag_id/opt_in",
+            put(set_feature_flag_opt_in),
+        )
+        .route(
+            "/feature_flags/:flag_id/max_users",
+            put(set_feature_flag_max_users),
+        )
+        .route(
+            "/feature_flags/:flag_id/depends_on",
+            put(set_feature_flag_dependency),
+        )
+        .route(
+            "/feature_flags/:flag_id/minimum_client_version",
+            put(set_feature_flag_minimum_client_version),
+        )
+        .route(
+            "/feature_flags/:flag_id/schedule",
+            put(set_feature_flag_schedule),
+        )
+        .route(
+            "/feature_flags/:flag_id/audit_log",
+            get(get_feature_flag_audit_log),
+        )
+        .route("/feature_flags/:flag_id/users", get(get_feature_flag_users))
+        .route(
+            "/feature_flags/:flag_id/users/bulk_add",
+            post(add_matching_users_to_feature_flag),
+        )
+        .route(
+            "/feature_flags/:flag_id/users/bulk_remove",
+            post(remove_matching_users_from_feature_flag),
+        )
+        .route(
+            "/feature_flags/:flag_id/users/:user_id",
+            put(add_user_to_feature_flag).delete(remove_user_from_feature_flag),
+        )
+        .route(
+            "/feature_flags/:flag_id/users/:user_id/value",
+            put(set_user_feature_flag_value),
+        )
+        .route("/feature_flags/:flag_id/stats", get(get_feature_flag_stats))
+        .route("/users/:user_id/feature_flags", get(get_user_feature_flags))
+        .route("/users/:user_id/flags", get(get_user_flags_with_sources))
+        .route("/users/:user_id/flag_values", get(get_user_flag_values))
+        .route(
+            "/feature_flag_assignments/export",
+            get(export_feature_flag_assignments),
+        )
+        .route(
+            "/feature_flag_config",
+            get(export_feature_flag_config).put(apply_feature_flag_config),
+        )
+}
+
+async fn list_feature_flags(
+    Extension(app): Extension<Arc<AppState>>,
+) -> Result<Json<Vec<FeatureFlagWithUserCount>>> {
+    Ok(Json(app.db.list_feature_flags().await?))
+}
+
+async fn delete_feature_flag(
+    Extension(app): Extension<Arc<AppState>>,
+    Extension(rpc_server): Extension<Arc<rpc::Server>>,
+    extract::Path(flag_id): extract::Path<FlagId>,
+) -> Result<()> {
+    match app.db.delete_feature_flag(flag_id).await? {
+        FlagInvalidation::AllUsers => rpc_server.flags_updated_for_all_users(),
+        FlagInvalidation::Users(user_ids) => {
+            for user_id in user_ids {
+                rpc_server.user_flags_updated(user_id).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct SetFeatureFlagRolloutBody {
+    rollout_percentage: i32,
+}
+
+async fn set_feature_flag_rollout(
+    Extension(app): Extension<Arc<AppState>>,
+    Extension(rpc_server): Extension<Arc<rpc::Server>>,
+    extract::Path(flag_id): extract::Path<FlagId>,
+    extract::Json(body): extract::Json<SetFeatureFlagRolloutBody>,
+) -> Result<()> {
+    app.db
+        .set_flag_rollout(flag_id, body.rollout_percentage)
+        .await?;
+    rpc_server.flags_updated_for_all_users();
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct SetFeatureFlagStaffOnlyBody {
+    staff_only: bool,
+}
+
+async fn set_feature_flag_staff_only(
+    Extension(app): Extension<Arc<AppState>>,
+    Extension(rpc_server): Extension<Arc<rpc::Server>>,
+    extract::Path(flag_id): extract::Path<FlagId>,
+    extract::Json(body): extract::Json<SetFeatureFlagStaffOnlyBody>,
+) -> Result<()> {
+    app.db.set_flag_staff_only(flag_id, body.staff_only).await?;
+    rpc_server.flags_updated_for_all_users();
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct SetFeatureFlagOptInBody {
+    opt_in: bool,
+    description: Option<String>,
+}
+
+async fn set_feature_flag_opt_in(
+    Extension(app): Extension<Arc<AppState>>,
+    extract::Path(flag_id): extract::Path<FlagId>,
+    extract::Json(body): extract::Json<SetFeatureFlagOptInBody>,
+) -> Result<()> {
+    app.db
+        .set_flag_opt_in(flag_id, body.opt_in, body.description.as_deref())
+        .await
+}
+
+#[derive(Debug, Deserialize)]
+struct SetFeatureFlagMaxUsersBody {
+    max_users: Option<i32>,
+}
+
+async fn set_feature_flag_max_users(
+    Extension(app): Extension<Arc<AppState>>,
+    extract::Path(flag_id): extract::Path<FlagId>,
+    extract::Json(body): extract::Json<SetFeatureFlagMaxUsersBody>,
+) -> Result<()> {
+    app.db.set_flag_max_users(flag_id, body.max_users).await
+}
+
+#[derive(Debug, Deserialize)]
+struct SetFeatureFlagDependencyBody {
+    depends_on: Option<FlagId>,
+}
+
+async fn set_feature_flag_dependency(
+    Extension(app): Extension<Arc<AppState>>,
+    Extension(rpc_server): Extension<Arc<rpc::Server>>,
+    extract::Path(flag_id): extract::Path<FlagId>,
+    extract::Json(body): extract::Json<SetFeatureFlagDependencyBody>,
+) -> Result<()> {
+    app.db.set_flag_dependency(flag_id, body.depends_on).await?;
+    rpc_server.flags_updated_for_all_users();
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct SetFeatureFlagMinimumClientVersionBody {
+    minimum_client_version: Option<String>,
+}
+
+async fn set_feature_flag_minimum_client_version(
+    Extension(app): Extension<Arc<AppState>>,
+    Extension(rpc_server): Extension<Arc<rpc::Server>>,
+    extract::Path(flag_id): extract::Path<FlagId>,
+    extract::Json(body): extract::Json<SetFeatureFlagMinimumClientVersionBody>,
+) -> Result<()> {
+    if let Some(version) = body.minimum_client_version.as_deref() {
+        if SemanticVersion::from_str(version).is_err() {
+            Err(Error::http(
+                StatusCode::BAD_REQUEST,
+                format!("invalid minimum client version {version:?}"),
+            ))?;
+        }
+    }
+
+    app.db
+        .set_flag_minimum_client_version(flag_id, body.minimum_client_version.as_deref())
+        .await?;
+    rpc_server.flags_updated_for_all_users();
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct SetFeatureFlagScheduleBody {
+    activate_at: Option<DateTime<Utc>>,
+    deactivate_at: Option<DateTime<Utc>>,
+}
+
+async fn set_feature_flag_schedule(
+    Extension(app): Extension<Arc<AppState>>,
+    Extension(rpc_server): Extension<Arc<rpc::Server>>,
+    extract::Path(flag_id): extract::Path<FlagId>,
+    extract::Json(body): extract::Json<SetFeatureFlagScheduleBody>,
+) -> Result<()> {
+    if let Some((activate_at, deactivate_at)) = body.activate_at.zip(body.deactivate_at) {
+        if activate_at >= deactivate_at {
+            Err(Error::http(
+                StatusCode::BAD_REQUEST,
+                "activate_at must be before deactivate_at".into(),
+            ))?;
+        }
+    }
+
+    app.db
+        .set_flag_schedule(
+            flag_id,
+            body.activate_at.map(|activate_at| activate_at.naive_utc()),
+            body.deactivate_at
+                .map(|deactivate_at| deactivate_at.naive_utc()),
+        )
+        .await?;
+    rpc_server.flags_updated_for_all_users();
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct AddUserToFeatureFlagParams {
+    expires_at: Option<DateTime<Utc>>,
+    actor_id: Option<UserId>,
+}
+
+async fn add_user_to_feature_flag(
+    Extension(app): Extension<Arc<AppState>>,
+    Extension(rpc_server): Extension<Arc<rpc::Server>>,
+    extract::Path((flag_id, user_id)): extract::Path<(FlagId, UserId)>,
+    extract::Query(params): extract::Query<AddUserToFeatureFlagParams>,
+) -> Result<()> {
+    app.db
+        .add_user_flag(
+            user_id,
+            flag_id,
+            params.expires_at.map(|expires_at| expires_at.naive_utc()),
+            params.actor_id,
+        )
+        .await?;
+    rpc_server.user_flags_updated(user_id).await
+}
+
+#[derive(Debug, Deserialize)]
+struct SetUserFeatureFlagValueBody {
+    value: Option<FlagValue>,
+    actor_id: Option<UserId>,
+}
+
+async fn set_user_feature_flag_value(
+    Extension(app): Extension<Arc<AppState>>,
+    Extension(rpc_server): Extension<Arc<rpc::Server>>,
+    extract::Path((flag_id, user_id)): extract::Path<(FlagId, UserId)>,
+    extract::Json(body): extract::Json<SetUserFeatureFlagValueBody>,
+) -> Result<()> {
+    app.db
+        .set_user_flag_value(user_id, flag_id, body.value, body.actor_id)
+        .await?;
+    rpc_server.user_flags_updated(user_id).await
+}
+
+#[derive(Debug, Deserialize)]
+struct RemoveUserFromFeatureFlagParams {
+    actor_id: Option<UserId>,
+}
+
+async fn remove_user_from_feature_flag(
+    Extension(app): Extension<Arc<AppState>>,
+    Extension(rpc_server): Extension<Arc<rpc::Server>>,
+    extract::Path((flag_id, user_id)): extract::Path<(FlagId, UserId)>,
+    extract::Query(params): extract::Query<RemoveUserFromFeatureFlagParams>,
+) -> Result<()> {
+    app.db
+        .remove_user_flag(user_id, flag_id, params.actor_id)
+        .await?;
+    rpc_server.user_flags_updated(user_id).await
+}
+
+#[derive(Debug, Deserialize)]
+struct BulkFeatureFlagBody {
+    filter: UserFilter,
+    actor_id: Option<UserId>,
+}
+
+async fn add_matching_users_to_feature_flag(
+    Extension(app): Extension<Arc<AppState>>,
+    Extension(rpc_server): Extension<Arc<rpc::Server>>,
+    extract::Path(flag_id): extract::Path<FlagId>,
+    extract::Json(body): extract::Json<BulkFeatureFlagBody>,
+) -> Result<Json<usize>> {
+    let granted_user_ids = app
+        .db
+        .add_flag_to_users_matching(flag_id, &body.filter, body.actor_id)
+        .await?;
+    for user_id in &granted_user_ids {
+        rpc_server.user_flags_updated(*user_id).await?;
+    }
+    Ok(Json(granted_user_ids.len()))
+}
+
+async fn remove_matching_users_from_feature_flag(
+    Extension(app): Extension<Arc<AppState>>,
+    Extension(rpc_server): Extension<Arc<rpc::Server>>,
+    extract::Path(flag_id): extract::Path<FlagId>,
+    extract::Json(body): extract::Json<BulkFeatureFlagBody>,
+) -> Result<Json<usize>> {
+    let revoked_user_ids = app
+        .db
+        .remove_flag_from_users_matching(flag_id, &body.filter, body.actor_id)
+        .await?;
+    for user_id in &revoked_user_ids {
+        rpc_server.user_flags_updated(*user_id).await?;
+    }
+    Ok(Json(revoked_user_ids.len()))
+}
+
+#[derive(Debug, Deserialize)]
+struct GetFeatureFlagAuditLogParams {
+    limit: Option<u64>,
+    before: Option<FeatureFlagAuditId>,
+}
+
+async fn get_feature_flag_audit_log(
+    Extension(app): Extension<Arc<AppState>>,
+    extract::Path(flag_id): extract::Path<FlagId>,
+    extract::Query(params): extract::Query<GetFeatureFlagAuditLogParams>,
+) -> Result<Json<Vec<feature_flag_audit::Model>>> {
+    Ok(Json(
+        app.db
+            .get_flag_audit_log(flag_id, params.limit.unwrap_or(100), params.before)
+            .await?,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct GetFeatureFlagUsersParams {
+    query: Option<String>,
+    page: Option<u64>,
+    page_size: Option<u64>,
+}
+
+async fn get_feature_flag_users(
+    Extension(app): Extension<Arc<AppState>>,
+    extract::Path(flag_id): extract::Path<FlagId>,
+    extract::Query(params): extract::Query<GetFeatureFlagUsersParams>,
+) -> Result<Json<FlagUsersPage>> {
+    Ok(Json(
+        app.db
+            .get_users_for_flag(
+                flag_id,
+                params.query.as_deref(),
+                params.page.unwrap_or(0),
+                params.page_size.unwrap_or(100),
+            )
+            .await?,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct GetFeatureFlagStatsParams {
+    since: NaiveDate,
+}
+
+async fn get_feature_flag_stats(
+    Extension(app): Extension<Arc<AppState>>,
+    extract::Path(flag_id): extract::Path<FlagId>,
+    extract::Query(params): extract::Query<GetFeatureFlagStatsParams>,
+) -> Result<Json<Vec<feature_flag_stats::Model>>> {
+    Ok(Json(app.db.get_flag_stats(flag_id, params.since).await?))
+}
+
+async fn get_user_feature_flags(
+    Extension(app): Extension<Arc<AppState>>,
+    extract::Path(user_id): extract::Path<UserId>,
+) -> Result<Json<Vec<UserFlag>>> {
+    let mut flags = app.db.get_user_flags_with_sources(user_id).await?;
+    flags.sort_by(|a, b| a.flag.cmp(&b.flag));
+    app.db
+        .record_flags_served(flags.iter().map(|flag| flag.flag.as_str()));
+    Ok(Json(flags))
+}
+
+async fn get_user_flag_values(
+    Extension(app): Extension<Arc<AppState>>,
+    extract::Path(user_id): extract::Path<UserId>,
+) -> Result<Json<Vec<(String, FlagValue)>>> {
+    let values = app.db.get_user_flag_values(user_id).await?;
+    app.db
+        .record_flags_served(values.iter().map(|(flag, _)| flag.as_str()));
+    Ok(Json(values))
+}
+
+/// Returns the user's active flags, annotated with why each is active.
+///
+/// Responds with `304 Not Modified` if the `If-None-Match` header matches the
+/// current flags, so that pollers don't need to re-fetch unchanged flags.
+async fn get_user_flags_with_sources(
+    Extension(app): Extension<Arc<AppState>>,
+    extract::Path(user_id): extract::Path<UserId>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    if app.db.get_user_by_id(user_id).await?.is_none() {
+        Err(Error::http(
+            StatusCode::NOT_FOUND,
+            "user not found".to_string(),
+        ))?;
+    }
+
+    let flags = app.db.get_user_flags_with_version(user_id).await?;
+    app.db
+        .record_flags_served(flags.flags.iter().map(|flag| flag.flag.as_str()));
+    let etag = user_flags_etag(&flags)?;
+    let not_modified = headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == etag || tag.trim() == "*");
+    if not_modified {
+        Ok((StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response())
+    } else {
+        Ok(([(ETAG, etag)], Json(flags)).into_response())
+    }
+}
+
+/// The version alone doesn't change when a grant expires, so the tag also
+/// covers the flags themselves.
+fn user_flags_etag(flags: &UserFlagsWithVersion) -> Result<String> {
+    let digest = Sha256::digest(serde_json::to_vec(&flags.flags)?);
+    Ok(format!(
+        "\"{}-{}\"",
+        flags.version,
+        hex::encode(&digest[..8])
+    ))
+}
+
+/// Streams every flag assignment as newline-delimited JSON, one page at a time, so that
+/// large exports aren't buffered in memory.
+async fn export_feature_flag_assignments(Extension(app): Extension<Arc<AppState>>) -> Response {
+    // The state is the cursor of the next page, or `None` once the last page has been sent.
+    let pages = stream::try_unfold(Some(None), move |after| {
+        let app = app.clone();
+        async move {
+            let Some(after) = after else {
+                return Ok(None);
+            };
+            let assignments = app
+                .db
+                .dump_flag_assignments(after, EXPORT_FLAG_ASSIGNMENTS_PAGE_SIZE)
+                .await?;
+            let Some(last) = assignments.last() else {
+                return Ok(None);
+            };
+            let next = (assignments.len() as u64 == EXPORT_FLAG_ASSIGNMENTS_PAGE_SIZE)
+                .then_some(Some((last.flag_id, last.user_id)));
+
+            let mut chunk = Vec::new();
+            for assignment in &assignments {
+                serde_json::to_writer(&mut chunk, assignment)?;
+                chunk.push(b'\n');
+            }
+            Ok::<_, Error>(Some((chunk, next)))
+        }
+    });
+    (
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        StreamBody::new(pages),
+    )
+        .into_response()
+}
+
+async fn export_feature_flag_config(
+    Extension(app): Extension<Arc<AppState>>,
+) -> Result<Json<FlagConfig>> {
+    Ok(Json(app.db.export_flag_config().await?))
+}
+
+#[derive(Debug, Deserialize)]
+struct ApplyFeatureFlagConfigParams {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Makes the feature flags match the config, responding with what changed, or with what would
+/// change when `dry_run` is set.
+async fn apply_feature_flag_config(
+    Extension(app): Extension<Arc<AppState>>,
+    Extension(rpc_server): Extension<Arc<rpc::Server>>,
+    extract::Query(params): extract::Query<ApplyFeatureFlagConfigParams>,
+    extract::Json(config): extract::Json<FlagConfig>,
+) -> Result<Json<FlagConfigDiff>> {
+    let diff = app.db.apply_flag_config(&config, params.dry_run).await?;
+    if diff.has_changes() && !params.dry_run {
+        rpc_server.flags_updated_for_all_users();
+    }
+    Ok(Json(diff))
+}
+
+/// Periodically deletes feature flag grants that have expired.
+pub fn purge_expired_user_flags_periodically(app_state: Arc<AppState>) {
+    let executor = app_state.executor.clone();
+    executor.spawn_detached({
+        let executor = executor.clone();
+        async move {
+            loop {
+                app_state.db.purge_expired_user_flags().await.log_err();
+                executor.sleep(PURGE_EXPIRED_USER_FLAGS_INTERVAL).await;
+            }
+        }
+    });
+}
+
+/// Periodically logs a warning for each capped flag that comes close to its cap, once each time
+/// it crosses [`FLAG_MAX_USERS_WARNING_PERCENTAGE`] of it.
+pub fn warn_about_flags_over_threshold_periodically(app_state: Arc<AppState>) {
+    let executor = app_state.executor.clone();
+    executor.spawn_detached({
+        let executor = executor.clone();
+        async move {
+            let mut flags_over_threshold = HashSet::default();
+            loop {
+                if let Some(flags) = app_state.db.get_flags_over_threshold().await.log_err() {
+                    for flag in &flags {
+                        if !flags_over_threshold.contains(&flag.id) {
+                            log::warn!(
+                                "feature flag {} reaches an estimated {} users, over {}% of its \
+                                limit of {}",
+                                flag.flag,
+                                flag.estimated_user_count,
+                                FLAG_MAX_USERS_WARNING_PERCENTAGE,
+                                flag.max_users
+                            );
+                        }
+                    }
+                    // Flags that drop back below the threshold are warned about again if they
+                    // cross it again.
+                    flags_over_threshold = flags.into_iter().map(|flag| flag.id).collect();
+                }
+                executor.sleep(FLAG_MAX_USERS_CHECK_INTERVAL).await;
+            }
+        }
+    });
+}
+
+/// Periodically sends every connected user their flags whenever a flag's schedule activates or
+/// deactivates it.
+pub fn sweep_flag_schedules_periodically(app_state: Arc<AppState>, rpc_server: Arc<rpc::Server>) {
+    let executor = app_state.executor.clone();
+    executor.spawn_detached({
+        let executor = executor.clone();
+        async move {
+            let mut last_sweep = Utc::now().naive_utc();
+            loop {
+                executor.sleep(FLAG_SCHEDULE_SWEEP_INTERVAL).await;
+                let now = Utc::now().naive_utc();
+                let changed = app_state
+                    .db
+                    .flag_schedules_changed_between(last_sweep, now)
+                    .await
+                    .log_err();
+                if changed == Some(true) {
+                    rpc_server.flags_updated_for_all_users();
+                }
+                if changed.is_some() {
+                    last_sweep = now;
+                }
+            }
+        }
+    });
+}
+
+/// Periodically drops the cached flags of users whose flags were changed on another node.
+pub fn poll_flag_cache_versions_periodically(app_state: Arc<AppState>) {
+    let executor = app_state.executor.clone();
+    executor.spawn_detached({
+        let executor = executor.clone();
+        async move {
+            loop {
+                executor.sleep(FLAG_CACHE_POLL_INTERVAL).await;
+                app_state.db.poll_flag_cache_versions().await.log_err();
+            }
+        }
+    });
+}
+
+/// Periodically writes the counts of how often each flag has been served to the database.
+///
+/// The counts recorded since the last flush are lost if the process exits without calling
+/// [`Database::flush_flag_stats`](crate::db::Database::flush_flag_stats).
+pub fn flush_flag_stats_periodically(app_state: Arc<AppState>) {
+    let executor = app_state.executor.clone();
+    executor.spawn_detached({
+        let executor = executor.clone();
+        async move {
+            loop {
+                executor.sleep(FLAG_STATS_FLUSH_INTERVAL).await;
+                app_state.db.flush_flag_stats().await.log_err();
+            }
+        }
+    });
+}
@@ -3,10 +3,11 @@ mod queries;
 mod tables;
 #[cfg(test)]
 pub mod tests;
+mod user_flag_cache;
 
 use crate::{executor::Executor, Error, Result};
 use anyhow::anyhow;
-use collections::{BTreeMap, HashMap, HashSet};
+use collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use dashmap::DashMap;
 use futures::StreamExt;
 use rand::{prelude::StdRng, Rng, SeedableRng};
@@ -17,9 +18,9 @@ use rpc::{
 use sea_orm::{
     entity::prelude::*,
     sea_query::{Alias, Expr, OnConflict},
-    ActiveValue, Condition, ConnectionTrait, DatabaseConnection, DatabaseTransaction, DbErr,
-    FromQueryResult, IntoActiveModel, IsolationLevel, JoinType, QueryOrder, QuerySelect, Statement,
-    TransactionTrait,
+    AccessMode, ActiveValue, Condition, ConnectionTrait, DatabaseConnection, DatabaseTransaction,
+    DbErr, FromQueryResult, IntoActiveModel, IsolationLevel, JoinType, QueryOrder, QuerySelect,
+    Statement, TransactionTrait,
 };
 use semantic_version::SemanticVersion;
 use serde::{Deserialize, Serialize};
@@ -35,6 +36,10 @@ use std::{
 };
 use time::PrimitiveDateTime;
 use tokio::sync::{Mutex, OwnedMutexGuard};
+use user_flag_cache::{USER_FLAG_CACHE_CAPACITY, USER_FLAG_CACHE_TTL};
+
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
 
 #[cfg(test)]
 pub use tests::TestDb;
@@ -45,24 +50,39 @@ pub use queries::billing_subscriptions::{
     CreateBillingSubscriptionParams, UpdateBillingSubscriptionParams,
 };
 pub use queries::contributors::ContributorSelector;
+pub use queries::flag_config::{FlagConfig, FlagConfigDiff, FlagConfigUpdate, FlagDefinition};
 pub use queries::processed_stripe_events::CreateProcessedStripeEventParams;
+pub use queries::users::{
+    flags_for_client_version, FeatureFlagWithUserCount, FlagAssignment, FlagOverThreshold,
+    FlagUser, FlagUsersPage, OptInFlag, UserFilter, UserFlag, UserFlagSource, UserFlagsWithVersion,
+    FLAG_MAX_USERS_WARNING_PERCENTAGE,
+};
 pub use sea_orm::ConnectOptions;
 pub use tables::user::Model as User;
 pub use tables::*;
+pub use user_flag_cache::{FlagInvalidation, FlagVersions, UserFlagCache};
 
 /// Database gives you a handle that lets you access the database.
 /// It handles pooling internally.
 pub struct Database {
     options: ConnectOptions,
     pool: DatabaseConnection,
+    /// A read replica of `pool`, which read-only queries are routed to by
+    /// [`Database::read_transaction`].
+    read_replica: Option<DatabaseConnection>,
     rooms: DashMap<RoomId, Arc<Mutex<()>>>,
     projects: DashMap<ProjectId, Arc<Mutex<()>>>,
     rng: Mutex<StdRng>,
     executor: Executor,
     notification_kinds_by_id: HashMap<NotificationKindId, &'static str>,
     notification_kinds_by_name: HashMap<String, NotificationKindId>,
+    user_flag_cache: UserFlagCache,
+    /// How many times each flag has been served since the flag stats were last flushed.
+    served_flag_counts: parking_lot::Mutex<HashMap<String, u64>>,
+    #[cfg(test)]
+    query_count: AtomicUsize,
     #[cfg(test)]
-    runtime: Option<tokio::runtime::Runtime>,
+    runtime: Option<Arc<tokio::runtime::Runtime>>,
 }
 
 // The `Database` type has so many methods that its impl blocks are split into
@@ -74,13 +94,18 @@ impl Database {
         Ok(Self {
             options: options.clone(),
             pool: sea_orm::Database::connect(options).await?,
+            read_replica: None,
             rooms: DashMap::with_capacity(16384),
             projects: DashMap::with_capacity(16384),
             rng: Mutex::new(StdRng::seed_from_u64(0)),
             notification_kinds_by_id: HashMap::default(),
             notification_kinds_by_name: HashMap::default(),
+            user_flag_cache: UserFlagCache::new(USER_FLAG_CACHE_CAPACITY, USER_FLAG_CACHE_TTL),
+            served_flag_counts: Default::default(),
             executor,
             #[cfg(test)]
+            query_count: AtomicUsize::new(0),
+            #[cfg(test)]
             runtime: None,
         })
     }
@@ -89,12 +114,116 @@ impl Database {
         &self.options
     }
 
+    /// Connects to a read replica of the database, which [`Self::read_transaction`] then runs
+    /// queries against.
+    pub async fn connect_read_replica(&mut self, options: ConnectOptions) -> Result<()> {
+        self.read_replica = Some(sea_orm::Database::connect(options).await?);
+        Ok(())
+    }
+
     #[cfg(test)]
     pub fn reset(&self) {
         self.rooms.clear();
         self.projects.clear();
     }
 
+    /// The number of transactions that have been run against the database.
+    #[cfg(test)]
+    pub fn query_count(&self) -> usize {
+        self.query_count.load(SeqCst)
+    }
+
+    /// Drops the flags this node has cached for the users that the invalidation covers, and
+    /// bumps their flag versions so that other nodes drop theirs once `tx` commits and they next
+    /// poll with [`Self::poll_flag_cache_versions`].
+    async fn invalidate_user_flags(
+        &self,
+        invalidation: FlagInvalidation,
+        tx: &DatabaseTransaction,
+    ) -> Result<()> {
+        self.user_flag_cache.invalidate(&invalidation);
+        let user_ids = match invalidation {
+            FlagInvalidation::Users(user_ids) => user_ids.into_iter().collect::<BTreeSet<_>>(),
+            FlagInvalidation::AllUsers => BTreeSet::from([flag_cache_version::ALL_USERS]),
+        };
+        let user_ids = user_ids.into_iter().collect::<Vec<_>>();
+        for user_ids in user_ids.chunks(1000) {
+            flag_cache_version::Entity::insert_many(user_ids.iter().map(|user_id| {
+                flag_cache_version::ActiveModel {
+                    user_id: ActiveValue::set(*user_id),
+                    version: ActiveValue::set(1),
+                }
+            }))
+            .on_conflict(
+                OnConflict::column(flag_cache_version::Column::UserId)
+                    .value(
+                        flag_cache_version::Column::Version,
+                        Expr::cust("flag_cache_versions.version + 1"),
+                    )
+                    .to_owned(),
+            )
+            .exec_without_returning(tx)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Drops the cached flags of users whose flags were changed on any node since they were
+    /// cached. This keeps flag changes from reaching the cache's hot path, which never queries
+    /// the database.
+    pub async fn poll_flag_cache_versions(&self) -> Result<()> {
+        let cached_users = self.user_flag_cache.cached_users();
+        if cached_users.is_empty() {
+            return Ok(());
+        }
+
+        let cached_users = &cached_users;
+        let (all_users_version, user_versions) = self
+            .transaction(|tx| async move {
+                let mut all_users_version = 0;
+                let mut user_versions = HashMap::default();
+                let user_ids = std::iter::once(flag_cache_version::ALL_USERS)
+                    .chain(cached_users.iter().copied())
+                    .collect::<Vec<_>>();
+                for user_ids in user_ids.chunks(1000) {
+                    let rows = flag_cache_version::Entity::find()
+                        .filter(flag_cache_version::Column::UserId.is_in(user_ids.iter().copied()))
+                        .all(&*tx)
+                        .await?;
+                    for row in rows {
+                        if row.user_id == flag_cache_version::ALL_USERS {
+                            all_users_version = row.version;
+                        } else {
+                            user_versions.insert(row.user_id, row.version);
+                        }
+                    }
+                }
+                Ok((all_users_version, user_versions))
+            })
+            .await?;
+        self.user_flag_cache
+            .retain_current(all_users_version, &user_versions);
+        Ok(())
+    }
+
+    /// Returns the current versions of the user's flags, for checking whether their cached flags
+    /// are stale.
+    async fn flag_versions(&self, user: UserId, tx: &DatabaseTransaction) -> Result<FlagVersions> {
+        let mut versions = FlagVersions::default();
+        let rows = flag_cache_version::Entity::find()
+            .filter(flag_cache_version::Column::UserId.is_in([user, flag_cache_version::ALL_USERS]))
+            .all(tx)
+            .await?;
+        for row in rows {
+            if row.user_id == flag_cache_version::ALL_USERS {
+                versions.all_users = row.version;
+            } else {
+                versions.user = row.version;
+            }
+        }
+        Ok(versions)
+    }
+
     /// Transaction runs things in a transaction. If you want to call other methods
     /// and pass the transaction around you need to reborrow the transaction at each
     /// call site with: `&*tx`.
@@ -130,6 +259,58 @@ impl Database {
         self.run(body).await
     }
 
+    /// Runs a read-only transaction against the read replica, or against the primary if no
+    /// replica is configured or the replica can't be reached. A transaction that loses its
+    /// connection to the replica partway through is run again on the primary.
+    ///
+    /// Replicas lag behind the primary, so reads that must see a write the caller just made
+    /// belong in the transaction that made the write instead.
+    pub async fn read_transaction<F, Fut, T>(&self, f: F) -> Result<T>
+    where
+        F: Send + Fn(TransactionHandle) -> Fut,
+        Fut: Send + Future<Output = Result<T>>,
+    {
+        if let Some(read_replica) = self.read_replica.as_ref() {
+            let body = async {
+                let tx = match read_replica
+                    .begin_with_config(None, Some(AccessMode::ReadOnly))
+                    .await
+                {
+                    Ok(tx) => tx,
+                    Err(error) => {
+                        log::warn!("failed to reach the read replica, using the primary: {error}");
+                        return Ok(None);
+                    }
+                };
+
+                let mut tx = Arc::new(Some(tx));
+                let result = f(TransactionHandle(tx.clone())).await;
+                let Some(tx) = Arc::get_mut(&mut tx).and_then(|tx| tx.take()) else {
+                    return Err(anyhow!(
+                        "couldn't complete transaction because it's still in use"
+                    ))?;
+                };
+                match result {
+                    // The transaction is abandoned rather than rolled back, since its connection
+                    // is gone.
+                    Err(error) if is_connection_error(&error) => {
+                        log::warn!("lost the read replica, using the primary: {error}");
+                        Ok(None)
+                    }
+                    result => {
+                        tx.rollback().await?;
+                        result.map(Some)
+                    }
+                }
+            };
+            if let Some(result) = self.run(body).await? {
+                return Ok(result);
+            }
+        }
+
+        self.transaction(f).await
+    }
+
     pub async fn weak_transaction<F, Fut, T>(&self, f: F) -> Result<T>
     where
         F: Send + Fn(TransactionHandle) -> Fut,
@@ -353,6 +534,7 @@ impl Database {
     {
         #[cfg(test)]
         {
+            self.query_count.fetch_add(1, SeqCst);
             if let Executor::Deterministic(executor) = &self.executor {
                 executor.simulate_random_delay().await;
             }
@@ -407,6 +589,25 @@ fn is_serialization_error(error: &Error) -> bool {
     }
 }
 
+fn is_connection_error(error: &Error) -> bool {
+    match error {
+        Error::Database(DbErr::ConnectionAcquire(_)) => true,
+        Error::Database(
+            DbErr::Conn(sea_orm::RuntimeErr::SqlxError(error))
+            | DbErr::Exec(sea_orm::RuntimeErr::SqlxError(error))
+            | DbErr::Query(sea_orm::RuntimeErr::SqlxError(error)),
+        ) => matches!(
+            error,
+            sqlx::Error::Io(_)
+                | sqlx::Error::Tls(_)
+                | sqlx::Error::PoolTimedOut
+                | sqlx::Error::PoolClosed
+                | sqlx::Error::WorkerCrashed
+        ),
+        _ => false,
+    }
+}
+
 /// A handle to a [`DatabaseTransaction`].
 pub struct TransactionHandle(pub(crate) Arc<Option<DatabaseTransaction>>);
 
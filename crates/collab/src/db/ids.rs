@@ -79,6 +79,7 @@ id_type!(ChannelMemberId);
 id_type!(ContactId);
 id_type!(DevServerId);
 id_type!(ExtensionId);
+id_type!(FeatureFlagAuditId);
 id_type!(FlagId);
 id_type!(FollowerId);
 id_type!(HostedProjectId);
@@ -11,6 +11,7 @@ pub mod dev_server_projects;
 pub mod dev_servers;
 pub mod embeddings;
 pub mod extensions;
+pub mod flag_config;
 pub mod hosted_projects;
 pub mod messages;
 pub mod notifications;
@@ -0,0 +1,366 @@
+use chrono::{NaiveDateTime, Utc};
+use std::str::FromStr;
+
+use super::*;
+use crate::db::feature_flag::FlagValueType;
+
+/// The configuration of every feature flag, without the users they're granted to, for copying
+/// flags between environments.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlagConfig {
+    /// The flags, sorted by name.
+    pub flags: Vec<FlagDefinition>,
+}
+
+/// A feature flag's attributes, identified by its name rather than its ID, since IDs differ
+/// between environments.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlagDefinition {
+    pub flag: String,
+    #[serde(default)]
+    pub enabled_for_all: bool,
+    #[serde(default)]
+    pub rollout_percentage: i32,
+    #[serde(default)]
+    pub staff_only: bool,
+    #[serde(default)]
+    pub value_type: FlagValueType,
+    /// The default value of a non-boolean flag, encoded as text.
+    #[serde(default)]
+    pub default_value: Option<String>,
+    /// The name of the flag that must also be active for a user to have this flag.
+    #[serde(default)]
+    pub depends_on: Option<String>,
+    #[serde(default)]
+    pub minimum_client_version: Option<String>,
+    #[serde(default)]
+    pub activate_at: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub deactivate_at: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub opt_in: bool,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub max_users: Option<i32>,
+}
+
+/// The changes that applying a [`FlagConfig`] makes, or would make in a dry run.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FlagConfigDiff {
+    /// The flags in the config that didn't exist yet, sorted by name.
+    pub created: Vec<String>,
+    /// The existing flags whose attributes differed from the config, sorted by name.
+    pub updated: Vec<FlagConfigUpdate>,
+    /// The flags that exist but aren't in the config, sorted by name. These are never deleted.
+    pub missing_from_config: Vec<String>,
+}
+
+impl FlagConfigDiff {
+    /// Returns whether applying the config changes any flag.
+    pub fn has_changes(&self) -> bool {
+        !self.created.is_empty() || !self.updated.is_empty()
+    }
+}
+
+/// An existing flag that a [`FlagConfig`] changes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FlagConfigUpdate {
+    pub flag: String,
+    /// The names of the attributes that changed, in the order they're declared in
+    /// [`FlagDefinition`].
+    pub attributes: Vec<&'static str>,
+}
+
+impl FlagDefinition {
+    fn new(flag: feature_flag::Model, depends_on: Option<String>) -> Self {
+        Self {
+            flag: flag.flag,
+            enabled_for_all: flag.enabled_for_all,
+            rollout_percentage: flag.rollout_percentage,
+            staff_only: flag.staff_only,
+            value_type: flag.value_type,
+            default_value: flag.default_value,
+            depends_on,
+            minimum_client_version: flag.minimum_client_version,
+            activate_at: flag.activate_at,
+            deactivate_at: flag.deactivate_at,
+            opt_in: flag.opt_in,
+            description: flag.description,
+            max_users: flag.max_users,
+        }
+    }
+
+    /// Fails unless the attributes could be set through the individual flag setters.
+    fn validate(&self) -> Result<()> {
+        if !(0..=100).contains(&self.rollout_percentage) {
+            Err(anyhow!(
+                "feature flag {}: rollout percentage must be between 0 and 100",
+                self.flag
+            ))?;
+        }
+        if self.max_users.map_or(false, |max_users| max_users < 0) {
+            Err(anyhow!(
+                "feature flag {}: the maximum number of users can't be negative",
+                self.flag
+            ))?;
+        }
+        if let Some(version) = &self.minimum_client_version {
+            SemanticVersion::from_str(version).map_err(|error| {
+                anyhow!(
+                    "feature flag {}: invalid minimum client version {version:?}: {error}",
+                    self.flag
+                )
+            })?;
+        }
+        if let Some((activate_at, deactivate_at)) = self.activate_at.zip(self.deactivate_at) {
+            if activate_at >= deactivate_at {
+                Err(anyhow!(
+                    "feature flag {}: feature flags must be activated before they're deactivated",
+                    self.flag
+                ))?;
+            }
+        }
+        match (&self.default_value, self.value_type) {
+            (Some(_), FlagValueType::Bool) => Err(anyhow!(
+                "feature flag {}: boolean flags can't have a default value",
+                self.flag
+            ))?,
+            (Some(default_value), value_type) => {
+                value_type.parse(default_value).map_err(|error| {
+                    anyhow!(
+                        "feature flag {}: invalid default value {default_value:?}: {error}",
+                        self.flag
+                    )
+                })?;
+            }
+            (None, _) => {}
+        }
+        Ok(())
+    }
+
+    /// Returns the names of the attributes that differ between the two definitions.
+    fn changed_attributes(&self, new: &Self) -> Vec<&'static str> {
+        let mut attributes = Vec::new();
+        let mut check = |name: &'static str, changed: bool| {
+            if changed {
+                attributes.push(name);
+            }
+        };
+        check(
+            "enabled_for_all",
+            self.enabled_for_all != new.enabled_for_all,
+        );
+        check(
+            "rollout_percentage",
+            self.rollout_percentage != new.rollout_percentage,
+        );
+        check("staff_only", self.staff_only != new.staff_only);
+        check("value_type", self.value_type != new.value_type);
+        check("default_value", self.default_value != new.default_value);
+        check("depends_on", self.depends_on != new.depends_on);
+        check(
+            "minimum_client_version",
+            self.minimum_client_version != new.minimum_client_version,
+        );
+        check("activate_at", self.activate_at != new.activate_at);
+        check("deactivate_at", self.deactivate_at != new.deactivate_at);
+        check("opt_in", self.opt_in != new.opt_in);
+        check("description", self.description != new.description);
+        check("max_users", self.max_users != new.max_users);
+        attributes
+    }
+}
+
+impl Database {
+    /// Returns the attributes of every feature flag, sorted by name, so that exports of the same
+    /// flags are identical.
+    pub async fn export_flag_config(&self) -> Result<FlagConfig> {
+        self.read_transaction(|tx| async move {
+            let flags = feature_flag::Entity::find().all(&*tx).await?;
+            let names = flags
+                .iter()
+                .map(|flag| (flag.id, flag.flag.clone()))
+                .collect::<HashMap<_, _>>();
+            let mut flags = flags
+                .into_iter()
+                .map(|flag| {
+                    let depends_on = flag
+                        .depends_on
+                        .and_then(|depends_on| names.get(&depends_on).cloned());
+                    FlagDefinition::new(flag, depends_on)
+                })
+                .collect::<Vec<_>>();
+            flags.sort_by(|a, b| a.flag.cmp(&b.flag));
+            Ok(FlagConfig { flags })
+        })
+        .await
+    }
+
+    /// Makes the feature flags match the config, creating the flags that don't exist and updating
+    /// the attributes of those that do, all in one transaction. Flags that aren't in the config
+    /// are reported, but never deleted, and the users flags are granted to are left alone.
+    ///
+    /// With `dry_run`, nothing is changed, and the returned diff describes what would change.
+    ///
+    /// Fails without changing anything if any definition is invalid, if a flag's value type would
+    /// change, or if the dependencies would refer to a missing flag or form a cycle.
+    pub async fn apply_flag_config(
+        &self,
+        config: &FlagConfig,
+        dry_run: bool,
+    ) -> Result<FlagConfigDiff> {
+        let mut names = HashSet::default();
+        for definition in &config.flags {
+            definition.validate()?;
+            if !names.insert(definition.flag.as_str()) {
+                Err(anyhow!(
+                    "feature flag {} is defined more than once",
+                    definition.flag
+                ))?;
+            }
+        }
+
+        self.transaction(|tx| async move {
+            let existing_flags = feature_flag::Entity::find().all(&*tx).await?;
+            let existing_names = existing_flags
+                .iter()
+                .map(|flag| (flag.id, flag.flag.clone()))
+                .collect::<HashMap<_, _>>();
+            let mut existing_flags = existing_flags
+                .into_iter()
+                .map(|flag| {
+                    let depends_on = flag
+                        .depends_on
+                        .and_then(|depends_on| existing_names.get(&depends_on).cloned());
+                    (
+                        flag.flag.clone(),
+                        (flag.id, FlagDefinition::new(flag, depends_on)),
+                    )
+                })
+                .collect::<BTreeMap<_, _>>();
+
+            // Check the dependencies that the flags will have once the config is applied.
+            let mut dependencies = existing_flags
+                .iter()
+                .map(|(name, (_, existing))| (name.as_str(), existing.depends_on.as_deref()))
+                .collect::<HashMap<_, _>>();
+            for definition in &config.flags {
+                dependencies.insert(definition.flag.as_str(), definition.depends_on.as_deref());
+            }
+            for definition in &config.flags {
+                let mut visited = HashSet::default();
+                let mut dependency = definition.depends_on.as_deref();
+                while let Some(name) = dependency {
+                    if !visited.insert(name) {
+                        Err(anyhow!("feature flag dependencies can't form a cycle"))?;
+                    }
+                    dependency = *dependencies.get(name).ok_or_else(|| {
+                        anyhow!(
+                            "feature flag {} depends on {name}, which doesn't exist",
+                            definition.flag
+                        )
+                    })?;
+                }
+            }
+
+            let mut diff = FlagConfigDiff::default();
+            let mut to_write = Vec::new();
+            for definition in &config.flags {
+                match existing_flags.remove(&definition.flag) {
+                    Some((id, existing)) => {
+                        if existing.value_type != definition.value_type {
+                            Err(anyhow!(
+                                "feature flag {} can't change from {} values to {} values",
+                                definition.flag,
+                                existing.value_type.to_value(),
+                                definition.value_type.to_value()
+                            ))?;
+                        }
+                        let attributes = existing.changed_attributes(definition);
+                        if !attributes.is_empty() {
+                            diff.updated.push(FlagConfigUpdate {
+                                flag: definition.flag.clone(),
+                                attributes,
+                            });
+                            to_write.push((Some(id), definition));
+                        }
+                    }
+                    None => {
+                        diff.created.push(definition.flag.clone());
+                        to_write.push((None, definition));
+                    }
+                }
+            }
+            diff.missing_from_config = existing_flags.into_keys().collect();
+            diff.created.sort();
+            diff.updated.sort_by(|a, b| a.flag.cmp(&b.flag));
+            if dry_run {
+                return Ok(diff);
+            }
+
+            // Dependencies are set once every flag exists, since flags can depend on flags that
+            // are created after them.
+            let now = Utc::now().naive_utc();
+            let mut ids = existing_names
+                .into_iter()
+                .map(|(id, name)| (name, id))
+                .collect::<HashMap<_, _>>();
+            for (id, definition) in &to_write {
+                let mut flag = feature_flag::ActiveModel {
+                    flag: ActiveValue::set(definition.flag.clone()),
+                    enabled_for_all: ActiveValue::set(definition.enabled_for_all),
+                    rollout_percentage: ActiveValue::set(definition.rollout_percentage),
+                    updated_at: ActiveValue::set(now),
+                    staff_only: ActiveValue::set(definition.staff_only),
+                    value_type: ActiveValue::set(definition.value_type),
+                    default_value: ActiveValue::set(definition.default_value.clone()),
+                    minimum_client_version: ActiveValue::set(
+                        definition.minimum_client_version.clone(),
+                    ),
+                    activate_at: ActiveValue::set(definition.activate_at),
+                    deactivate_at: ActiveValue::set(definition.deactivate_at),
+                    opt_in: ActiveValue::set(definition.opt_in),
+                    description: ActiveValue::set(definition.description.clone()),
+                    max_users: ActiveValue::set(definition.max_users),
+                    ..Default::default()
+                };
+                match id {
+                    Some(id) => {
+                        flag.id = ActiveValue::unchanged(*id);
+                        flag.update(&*tx).await?;
+                    }
+                    None => {
+                        let id = feature_flag::Entity::insert(flag)
+                            .exec(&*tx)
+                            .await?
+                            .last_insert_id;
+                        ids.insert(definition.flag.clone(), id);
+                    }
+                }
+            }
+            for (_, definition) in &to_write {
+                let depends_on = definition
+                    .depends_on
+                    .as_ref()
+                    .map(|depends_on| ids[depends_on]);
+                feature_flag::Entity::update_many()
+                    .filter(feature_flag::Column::Id.eq(ids[&definition.flag]))
+                    .set(feature_flag::ActiveModel {
+                        depends_on: ActiveValue::set(depends_on),
+                        ..Default::default()
+                    })
+                    .exec(&*tx)
+                    .await?;
+            }
+
+            if diff.has_changes() {
+                self.invalidate_user_flags(FlagInvalidation::AllUsers, &tx)
+                    .await?;
+            }
+
+            Ok(diff)
+        })
+        .await
+    }
+}
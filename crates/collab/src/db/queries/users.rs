@@ -1,6 +1,191 @@
-use chrono::NaiveDateTime;
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use std::{mem, str::FromStr};
 
 use super::*;
+use crate::db::feature_flag::{FlagValue, FlagValueType};
+use crate::db::feature_flag_audit::FeatureFlagAuditAction;
+use sea_orm::sea_query::{Func, LikeExpr, Query, SelectStatement};
+
+/// A feature flag, along with the number of users it has been explicitly granted to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FeatureFlagWithUserCount {
+    pub id: FlagId,
+    pub flag: String,
+    pub enabled_for_all: bool,
+    pub rollout_percentage: i32,
+    pub staff_only: bool,
+    pub value_type: FlagValueType,
+    /// The default value of a non-boolean flag, encoded as text.
+    pub default_value: Option<String>,
+    pub minimum_client_version: Option<String>,
+    pub activate_at: Option<NaiveDateTime>,
+    pub deactivate_at: Option<NaiveDateTime>,
+    pub opt_in: bool,
+    pub description: Option<String>,
+    /// The most users the flag can reach, if it's capped.
+    pub max_users: Option<i32>,
+    pub user_count: usize,
+    /// The last day the flag was served to a client, as of the last flush of the flag stats.
+    pub last_served_on: Option<NaiveDate>,
+}
+
+/// The percentage of its cap that a feature flag can reach before admins are warned about it.
+pub const FLAG_MAX_USERS_WARNING_PERCENTAGE: u64 = 90;
+
+/// A capped feature flag that reaches more than [`FLAG_MAX_USERS_WARNING_PERCENTAGE`] of its cap.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FlagOverThreshold {
+    pub id: FlagId,
+    pub flag: String,
+    pub max_users: i32,
+    /// The users the flag is granted to, plus its rollout's estimated share of everyone else.
+    pub estimated_user_count: u64,
+}
+
+/// A feature flag that users can opt into themselves.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OptInFlag {
+    pub id: FlagId,
+    pub flag: String,
+    pub description: Option<String>,
+    /// Whether the user has opted into the flag.
+    pub opted_in: bool,
+}
+
+/// Why a feature flag is active for a user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserFlagSource {
+    /// The flag was granted to the user.
+    Granted,
+    /// The flag is enabled for all users.
+    EnabledForAll,
+    /// The flag is staff-only and the user is staff.
+    Staff,
+    /// The user falls within the flag's percentage rollout.
+    Rollout,
+}
+
+/// A user that a feature flag is granted to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FlagUser {
+    pub user_id: UserId,
+    pub github_login: String,
+    pub granted_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    /// Whether the user also falls within the flag's percentage rollout, and so would keep the
+    /// flag without the grant.
+    pub in_rollout: bool,
+}
+
+/// A page of the users that a feature flag is granted to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FlagUsersPage {
+    /// The number of matching users across all pages.
+    pub total_count: u64,
+    pub users: Vec<FlagUser>,
+}
+
+/// A feature flag that's active for a user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UserFlag {
+    pub flag: String,
+    pub source: UserFlagSource,
+    /// The oldest client version that the flag is sent to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minimum_client_version: Option<String>,
+}
+
+/// Returns the names of the flags that a client of the given version is new enough for.
+///
+/// Flags whose minimum client version can't be parsed are omitted, as if the client were too
+/// old for them.
+pub fn flags_for_client_version(
+    flags: &[UserFlag],
+    client_version: SemanticVersion,
+) -> Vec<String> {
+    let mut unparsable_flags = Vec::new();
+    let flags = flags
+        .iter()
+        .filter(|flag| {
+            let Some(minimum_version) = flag.minimum_client_version.as_deref() else {
+                return true;
+            };
+            match SemanticVersion::from_str(minimum_version) {
+                Ok(minimum_version) => client_version >= minimum_version,
+                Err(_) => {
+                    unparsable_flags.push(flag.flag.as_str());
+                    false
+                }
+            }
+        })
+        .map(|flag| flag.flag.clone())
+        .collect();
+    if !unparsable_flags.is_empty() {
+        tracing::warn!(
+            ?unparsable_flags,
+            "omitting feature flags with invalid minimum client versions"
+        );
+    }
+    flags
+}
+
+/// A user's active feature flags.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UserFlagsWithVersion {
+    pub flags: Vec<UserFlag>,
+    /// Increases whenever the user's flags may have changed.
+    pub version: i64,
+}
+
+/// A feature flag that has been granted to a user, as exported for analytics.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, FromQueryResult)]
+pub struct FlagAssignment {
+    pub flag_id: FlagId,
+    pub flag: String,
+    pub user_id: UserId,
+    pub github_login: String,
+    pub granted_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub enabled_for_all: bool,
+    pub rollout_percentage: i32,
+}
+
+/// Selects users for bulk feature flag changes. Every criterion that is set must match.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UserFilter {
+    pub github_login_prefix: Option<String>,
+    /// Only match users who signed up at or after this time.
+    pub created_after: Option<NaiveDateTime>,
+    /// Only match users who signed up before this time.
+    pub created_before: Option<NaiveDateTime>,
+    pub is_staff: Option<bool>,
+}
+
+impl UserFilter {
+    /// Returns a query selecting the ids of the matching users.
+    fn select_user_ids(&self) -> SelectStatement {
+        let mut condition = Condition::all();
+        if let Some(prefix) = &self.github_login_prefix {
+            condition = condition.add(user::Column::GithubLogin.starts_with(prefix));
+        }
+        if let Some(created_after) = self.created_after {
+            condition = condition.add(user::Column::CreatedAt.gte(created_after));
+        }
+        if let Some(created_before) = self.created_before {
+            condition = condition.add(user::Column::CreatedAt.lt(created_before));
+        }
+        if let Some(is_staff) = self.is_staff {
+            condition = condition.add(user::Column::Admin.eq(is_staff));
+        }
+
+        Query::select()
+            .column(user::Column::Id)
+            .from(user::Entity)
+            .cond_where(condition)
+            .to_owned()
+    }
+}
 
 impl Database {
     /// Creates a new user.
@@ -226,6 +411,22 @@ impl Database {
         .await
     }
 
+    /// Sets whether the user is staff.
+    pub async fn set_user_is_staff(&self, id: UserId, is_staff: bool) -> Result<()> {
+        self.transaction(|tx| async move {
+            user::Entity::update_many()
+                .filter(user::Column::Id.eq(id))
+                .set(user::ActiveModel {
+                    admin: ActiveValue::set(is_staff),
+                    ..Default::default()
+                })
+                .exec(&*tx)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
     /// Sets "accepted_tos_at" on the user to the given timestamp.
     pub async fn set_user_accepted_tos_at(
         &self,
@@ -246,14 +447,23 @@ impl Database {
         .await
     }
 
-    /// hard delete the user.
+    /// hard delete the user. Their feature flag assignments are deleted along with them, and
+    /// the revocations are recorded in the flags' audit logs.
     pub async fn destroy_user(&self, id: UserId) -> Result<()> {
         self.transaction(|tx| async move {
             access_token::Entity::delete_many()
                 .filter(access_token::Column::UserId.eq(id))
                 .exec(&*tx)
                 .await?;
+            let grants = user_feature::Entity::find()
+                .filter(user_feature::Column::UserId.eq(id))
+                .all(&*tx)
+                .await?;
+            self.record_flag_revocations(&grants, &tx).await?;
+            // Deleting the user cascades to their assignments.
             user::Entity::delete_by_id(id).exec(&*tx).await?;
+            self.invalidate_user_flags(FlagInvalidation::Users(vec![id]), &tx)
+                .await?;
             Ok(())
         })
         .await
@@ -298,79 +508,1479 @@ impl Database {
         result
     }
 
-    /// Returns all feature flags.
-    pub async fn list_feature_flags(&self) -> Result<Vec<feature_flag::Model>> {
-        self.transaction(|tx| async move { Ok(feature_flag::Entity::find().all(&*tx).await?) })
-            .await
+    /// Returns all feature flags, along with the number of users each has been granted to.
+    pub async fn list_feature_flags(&self) -> Result<Vec<FeatureFlagWithUserCount>> {
+        self.read_transaction(|tx| async move {
+            let user_counts = Self::flag_user_counts(&tx).await?;
+
+            let last_served_on = feature_flag_stats::Entity::find()
+                .select_only()
+                .column(feature_flag_stats::Column::FlagId)
+                .column_as(feature_flag_stats::Column::Date.max(), "last_served_on")
+                .group_by(feature_flag_stats::Column::FlagId)
+                .into_tuple::<(FlagId, NaiveDate)>()
+                .all(&*tx)
+                .await?
+                .into_iter()
+                .collect::<HashMap<_, _>>();
+
+            Ok(feature_flag::Entity::find()
+                .order_by_asc(feature_flag::Column::Id)
+                .all(&*tx)
+                .await?
+                .into_iter()
+                .map(|flag| FeatureFlagWithUserCount {
+                    user_count: user_counts.get(&flag.id).copied().unwrap_or(0),
+                    last_served_on: last_served_on.get(&flag.id).copied(),
+                    id: flag.id,
+                    flag: flag.flag,
+                    enabled_for_all: flag.enabled_for_all,
+                    rollout_percentage: flag.rollout_percentage,
+                    staff_only: flag.staff_only,
+                    value_type: flag.value_type,
+                    default_value: flag.default_value,
+                    minimum_client_version: flag.minimum_client_version,
+                    activate_at: flag.activate_at,
+                    deactivate_at: flag.deactivate_at,
+                    opt_in: flag.opt_in,
+                    description: flag.description,
+                    max_users: flag.max_users,
+                })
+                .collect())
+        })
+        .await
+    }
+
+    /// Returns the number of users each feature flag is granted to.
+    async fn flag_user_counts(tx: &DatabaseTransaction) -> Result<HashMap<FlagId, usize>> {
+        Ok(user_feature::Entity::find()
+            .select_only()
+            .column(user_feature::Column::FeatureId)
+            .column_as(user_feature::Column::UserId.count(), "user_count")
+            .group_by(user_feature::Column::FeatureId)
+            .into_tuple::<(FlagId, i64)>()
+            .all(tx)
+            .await?
+            .into_iter()
+            .map(|(flag_id, user_count)| (flag_id, user_count as usize))
+            .collect())
+    }
+
+    /// Returns the capped feature flags that reach more than
+    /// [`FLAG_MAX_USERS_WARNING_PERCENTAGE`] of their cap, sorted by name. Flags that are rolled
+    /// out to a percentage of users are estimated against the total number of users.
+    pub async fn get_flags_over_threshold(&self) -> Result<Vec<FlagOverThreshold>> {
+        self.read_transaction(|tx| async move {
+            let user_counts = Self::flag_user_counts(&tx).await?;
+            let total_user_count = user::Entity::find().count(&*tx).await?;
+
+            let mut flags = feature_flag::Entity::find()
+                .filter(feature_flag::Column::MaxUsers.is_not_null())
+                .all(&*tx)
+                .await?
+                .into_iter()
+                .filter_map(|flag| {
+                    let max_users = flag.max_users?;
+                    let granted_user_count = user_counts.get(&flag.id).copied().unwrap_or(0);
+                    let estimated_user_count =
+                        flag.estimated_user_count(granted_user_count as u64, total_user_count);
+                    (estimated_user_count * 100
+                        > max_users.max(0) as u64 * FLAG_MAX_USERS_WARNING_PERCENTAGE)
+                        .then(|| FlagOverThreshold {
+                            id: flag.id,
+                            flag: flag.flag,
+                            max_users,
+                            estimated_user_count,
+                        })
+                })
+                .collect::<Vec<_>>();
+            flags.sort_by(|a, b| a.flag.cmp(&b.flag));
+            Ok(flags)
+        })
+        .await
+    }
+
+    /// Returns up to `page_size` flag assignments, ordered by flag and then by user.
+    ///
+    /// Pass the `(flag_id, user_id)` of the last assignment of the previous page as `after` to
+    /// fetch the next page. Assignments made between pages are included in a later page if
+    /// they sort after `after`, and are never returned twice.
+    pub async fn dump_flag_assignments(
+        &self,
+        after: Option<(FlagId, UserId)>,
+        page_size: u64,
+    ) -> Result<Vec<FlagAssignment>> {
+        self.transaction(|tx| async move {
+            let mut condition = Condition::all();
+            if let Some((flag_id, user_id)) = after {
+                condition = condition.add(
+                    Condition::any()
+                        .add(user_feature::Column::FeatureId.gt(flag_id))
+                        .add(
+                            user_feature::Column::FeatureId
+                                .eq(flag_id)
+                                .and(user_feature::Column::UserId.gt(user_id)),
+                        ),
+                );
+            }
+
+            Ok(user_feature::Entity::find()
+                .select_only()
+                .column_as(user_feature::Column::FeatureId, "flag_id")
+                .column(feature_flag::Column::Flag)
+                .column(user_feature::Column::UserId)
+                .column(user::Column::GithubLogin)
+                .column(user_feature::Column::GrantedAt)
+                .column(user_feature::Column::ExpiresAt)
+                .column(feature_flag::Column::EnabledForAll)
+                .column(feature_flag::Column::RolloutPercentage)
+                .inner_join(feature_flag::Entity)
+                .inner_join(user::Entity)
+                .filter(condition)
+                .order_by_asc(user_feature::Column::FeatureId)
+                .order_by_asc(user_feature::Column::UserId)
+                .limit(page_size)
+                .into_model::<FlagAssignment>()
+                .all(&*tx)
+                .await?)
+        })
+        .await
     }
 
     /// Creates a new feature flag.
-    pub async fn create_user_flag(&self, flag: &str, enabled_for_all: bool) -> Result<FlagId> {
+    ///
+    /// A `staff_only` flag is active for all staff, in addition to the users it's granted to.
+    pub async fn create_user_flag(
+        &self,
+        flag: &str,
+        enabled_for_all: bool,
+        staff_only: bool,
+    ) -> Result<FlagId> {
         self.transaction(|tx| async move {
             let flag = feature_flag::Entity::insert(feature_flag::ActiveModel {
                 flag: ActiveValue::set(flag.to_string()),
                 enabled_for_all: ActiveValue::set(enabled_for_all),
+                staff_only: ActiveValue::set(staff_only),
                 ..Default::default()
             })
             .exec(&*tx)
             .await?
             .last_insert_id;
+            if enabled_for_all || staff_only {
+                self.invalidate_user_flags(FlagInvalidation::AllUsers, &tx)
+                    .await?;
+            }
 
             Ok(flag)
         })
         .await
     }
 
-    /// Add the given user to the feature flag
-    pub async fn add_user_flag(&self, user: UserId, flag: FlagId) -> Result<()> {
+    /// Creates a new feature flag with a value of the same type as `default_value`.
+    ///
+    /// A boolean default decides whether the flag is enabled for all users.
+    pub async fn create_user_flag_with_default(
+        &self,
+        flag: &str,
+        default_value: FlagValue,
+    ) -> Result<FlagId> {
+        let enabled_for_all = default_value == FlagValue::Bool(true);
+        self.transaction(|tx| async move {
+            let value_type = default_value.value_type();
+            let default_value = match &default_value {
+                FlagValue::Bool(_) => None,
+                value => Some(value.encode()),
+            };
+            let flag = feature_flag::Entity::insert(feature_flag::ActiveModel {
+                flag: ActiveValue::set(flag.to_string()),
+                enabled_for_all: ActiveValue::set(enabled_for_all),
+                value_type: ActiveValue::set(value_type),
+                default_value: ActiveValue::set(default_value),
+                ..Default::default()
+            })
+            .exec(&*tx)
+            .await?
+            .last_insert_id;
+            if enabled_for_all {
+                self.invalidate_user_flags(FlagInvalidation::AllUsers, &tx)
+                    .await?;
+            }
+
+            Ok(flag)
+        })
+        .await
+    }
+
+    /// Sets the percentage of users (0-100) that the feature flag is rolled out to.
+    pub async fn set_flag_rollout(&self, flag: FlagId, percentage: i32) -> Result<()> {
+        self.transaction(|tx| async move {
+            if !(0..=100).contains(&percentage) {
+                Err(anyhow!("rollout percentage must be between 0 and 100"))?;
+            }
+
+            let result = feature_flag::Entity::update_many()
+                .filter(feature_flag::Column::Id.eq(flag))
+                .set(feature_flag::ActiveModel {
+                    rollout_percentage: ActiveValue::set(percentage),
+                    updated_at: ActiveValue::set(Utc::now().naive_utc()),
+                    ..Default::default()
+                })
+                .exec(&*tx)
+                .await?;
+            if result.rows_affected == 0 {
+                Err(anyhow!("no such feature flag"))?;
+            }
+
+            self.invalidate_user_flags(FlagInvalidation::AllUsers, &tx)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Sets whether the feature flag is active for all staff. Explicit grants are kept either way.
+    pub async fn set_flag_staff_only(&self, flag: FlagId, staff_only: bool) -> Result<()> {
+        self.transaction(|tx| async move {
+            let result = feature_flag::Entity::update_many()
+                .filter(feature_flag::Column::Id.eq(flag))
+                .set(feature_flag::ActiveModel {
+                    staff_only: ActiveValue::set(staff_only),
+                    updated_at: ActiveValue::set(Utc::now().naive_utc()),
+                    ..Default::default()
+                })
+                .exec(&*tx)
+                .await?;
+            if result.rows_affected == 0 {
+                Err(anyhow!("no such feature flag"))?;
+            }
+
+            self.invalidate_user_flags(FlagInvalidation::AllUsers, &tx)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Sets the oldest client version that the feature flag is sent to. Pass `None` to send it
+    /// to clients of any version.
+    ///
+    /// Fails unless the version is a valid semantic version.
+    pub async fn set_flag_minimum_client_version(
+        &self,
+        flag: FlagId,
+        minimum_client_version: Option<&str>,
+    ) -> Result<()> {
+        if let Some(version) = minimum_client_version {
+            SemanticVersion::from_str(version)
+                .map_err(|error| anyhow!("invalid minimum client version {version:?}: {error}"))?;
+        }
+
+        self.transaction(|tx| async move {
+            let result = feature_flag::Entity::update_many()
+                .filter(feature_flag::Column::Id.eq(flag))
+                .set(feature_flag::ActiveModel {
+                    minimum_client_version: ActiveValue::set(
+                        minimum_client_version.map(str::to_string),
+                    ),
+                    updated_at: ActiveValue::set(Utc::now().naive_utc()),
+                    ..Default::default()
+                })
+                .exec(&*tx)
+                .await?;
+            if result.rows_affected == 0 {
+                Err(anyhow!("no such feature flag"))?;
+            }
+
+            self.invalidate_user_flags(FlagInvalidation::AllUsers, &tx)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Sets when the feature flag starts and stops being active. Outside of that window, the flag
+    /// is inactive for every user, regardless of how it's granted. Pass `None` for either bound
+    /// to leave that side of the window open.
+    ///
+    /// Fails unless `activate_at` is before `deactivate_at`.
+    pub async fn set_flag_schedule(
+        &self,
+        flag: FlagId,
+        activate_at: Option<NaiveDateTime>,
+        deactivate_at: Option<NaiveDateTime>,
+    ) -> Result<()> {
+        if let Some((activate_at, deactivate_at)) = activate_at.zip(deactivate_at) {
+            if activate_at >= deactivate_at {
+                Err(anyhow!(
+                    "feature flags must be activated before they're deactivated"
+                ))?;
+            }
+        }
+
+        self.transaction(|tx| async move {
+            let result = feature_flag::Entity::update_many()
+                .filter(feature_flag::Column::Id.eq(flag))
+                .set(feature_flag::ActiveModel {
+                    activate_at: ActiveValue::set(activate_at),
+                    deactivate_at: ActiveValue::set(deactivate_at),
+                    updated_at: ActiveValue::set(Utc::now().naive_utc()),
+                    ..Default::default()
+                })
+                .exec(&*tx)
+                .await?;
+            if result.rows_affected == 0 {
+                Err(anyhow!("no such feature flag"))?;
+            }
+
+            self.invalidate_user_flags(FlagInvalidation::AllUsers, &tx)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns whether any feature flag was activated or deactivated by its schedule after
+    /// `since` and up to `until`, invalidating every user's cached flags if so.
+    pub async fn flag_schedules_changed_between(
+        &self,
+        since: NaiveDateTime,
+        until: NaiveDateTime,
+    ) -> Result<bool> {
+        self.transaction(|tx| async move {
+            let changed_flags = feature_flag::Entity::find()
+                .filter(
+                    Condition::any()
+                        .add(
+                            Condition::all()
+                                .add(feature_flag::Column::ActivateAt.gt(since))
+                                .add(feature_flag::Column::ActivateAt.lte(until)),
+                        )
+                        .add(
+                            Condition::all()
+                                .add(feature_flag::Column::DeactivateAt.gt(since))
+                                .add(feature_flag::Column::DeactivateAt.lte(until)),
+                        ),
+                )
+                .count(&*tx)
+                .await?;
+            if changed_flags > 0 {
+                self.invalidate_user_flags(FlagInvalidation::AllUsers, &tx)
+                    .await?;
+            }
+            Ok(changed_flags > 0)
+        })
+        .await
+    }
+
+    /// Sets whether users can opt into the feature flag themselves, along with the description
+    /// they're shown for it. Pass `None` to keep the current description.
+    pub async fn set_flag_opt_in(
+        &self,
+        flag: FlagId,
+        opt_in: bool,
+        description: Option<&str>,
+    ) -> Result<()> {
         self.transaction(|tx| async move {
+            let result = feature_flag::Entity::update_many()
+                .filter(feature_flag::Column::Id.eq(flag))
+                .set(feature_flag::ActiveModel {
+                    opt_in: ActiveValue::set(opt_in),
+                    description: match description {
+                        Some(description) => ActiveValue::set(Some(description.to_string())),
+                        None => ActiveValue::NotSet,
+                    },
+                    updated_at: ActiveValue::set(Utc::now().naive_utc()),
+                    ..Default::default()
+                })
+                .exec(&*tx)
+                .await?;
+            if result.rows_affected == 0 {
+                Err(anyhow!("no such feature flag"))?;
+            }
+
+            self.invalidate_user_flags(FlagInvalidation::AllUsers, &tx)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Sets the most users the feature flag can reach. Pass `None` to remove the cap.
+    ///
+    /// Users the flag is already granted to keep it, even if they exceed the new cap.
+    pub async fn set_flag_max_users(&self, flag: FlagId, max_users: Option<i32>) -> Result<()> {
+        if max_users.map_or(false, |max_users| max_users < 0) {
+            Err(anyhow!("the maximum number of users can't be negative"))?;
+        }
+
+        self.transaction(|tx| async move {
+            let result = feature_flag::Entity::update_many()
+                .filter(feature_flag::Column::Id.eq(flag))
+                .set(feature_flag::ActiveModel {
+                    max_users: ActiveValue::set(max_users),
+                    updated_at: ActiveValue::set(Utc::now().naive_utc()),
+                    ..Default::default()
+                })
+                .exec(&*tx)
+                .await?;
+            if result.rows_affected == 0 {
+                Err(anyhow!("no such feature flag"))?;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Makes the feature flag depend on another flag, so that users only have it while they
+    /// also have `depends_on`. Pass `None` to remove the dependency.
+    ///
+    /// Fails if the dependency would form a cycle.
+    pub async fn set_flag_dependency(
+        &self,
+        flag: FlagId,
+        depends_on: Option<FlagId>,
+    ) -> Result<()> {
+        self.transaction(|tx| async move {
+            if let Some(depends_on) = depends_on {
+                let dependencies = feature_flag::Entity::find()
+                    .all(&*tx)
+                    .await?
+                    .into_iter()
+                    .map(|flag| (flag.id, flag.depends_on))
+                    .collect::<HashMap<_, _>>();
+                if !dependencies.contains_key(&depends_on) {
+                    Err(anyhow!("no such feature flag"))?;
+                }
+                let mut dependency = Some(depends_on);
+                while let Some(dependency_id) = dependency {
+                    if dependency_id == flag {
+                        Err(anyhow!("feature flag dependencies can't form a cycle"))?;
+                    }
+                    dependency = dependencies.get(&dependency_id).copied().flatten();
+                }
+            }
+
+            let result = feature_flag::Entity::update_many()
+                .filter(feature_flag::Column::Id.eq(flag))
+                .set(feature_flag::ActiveModel {
+                    depends_on: ActiveValue::set(depends_on),
+                    updated_at: ActiveValue::set(Utc::now().naive_utc()),
+                    ..Default::default()
+                })
+                .exec(&*tx)
+                .await?;
+            if result.rows_affected == 0 {
+                Err(anyhow!("no such feature flag"))?;
+            }
+
+            self.invalidate_user_flags(FlagInvalidation::AllUsers, &tx)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Add the given user to the feature flag, optionally only until `expires_at`.
+    ///
+    /// The grant is recorded in the flag's audit log, attributed to `actor`.
+    pub async fn add_user_flag(
+        &self,
+        user: UserId,
+        flag: FlagId,
+        expires_at: Option<NaiveDateTime>,
+        actor: Option<UserId>,
+    ) -> Result<()> {
+        self.transaction(|tx| async move {
+            self.record_flag_audit(flag, user, FeatureFlagAuditAction::Granted, actor, &tx)
+                .await?;
+
             user_feature::Entity::insert(user_feature::ActiveModel {
                 user_id: ActiveValue::set(user),
                 feature_id: ActiveValue::set(flag),
+                expires_at: ActiveValue::set(expires_at),
+                granted_at: ActiveValue::NotSet,
+                value_override: ActiveValue::NotSet,
             })
             .exec(&*tx)
             .await?;
+            self.check_flag_max_users(flag, 1, &tx).await?;
 
+            self.invalidate_user_flags(FlagInvalidation::Users(vec![user]), &tx)
+                .await?;
             Ok(())
         })
         .await
     }
 
-    /// Returns the active flags for the user.
-    pub async fn get_user_flags(&self, user: UserId) -> Result<Vec<String>> {
+    /// Opts the user into or out of a feature flag, by granting or revoking it.
+    ///
+    /// The change is recorded in the flag's audit log, attributed to the user. Fails unless the
+    /// flag is marked as opt-in, so users can't grant themselves any other flag.
+    pub async fn set_user_flag_opt_in(
+        &self,
+        user: UserId,
+        flag: FlagId,
+        enabled: bool,
+    ) -> Result<()> {
+        self.transaction(|tx| async move {
+            let Some(feature_flag) = feature_flag::Entity::find_by_id(flag).one(&*tx).await? else {
+                Err(anyhow!("no such feature flag"))?
+            };
+            if !feature_flag.opt_in {
+                Err(anyhow!(
+                    "feature flag {} can't be opted into",
+                    feature_flag.flag
+                ))?;
+            }
+
+            if enabled {
+                let rows_affected = user_feature::Entity::insert(user_feature::ActiveModel {
+                    user_id: ActiveValue::set(user),
+                    feature_id: ActiveValue::set(flag),
+                    expires_at: ActiveValue::set(None),
+                    granted_at: ActiveValue::NotSet,
+                    value_override: ActiveValue::NotSet,
+                })
+                .on_conflict(
+                    OnConflict::columns([
+                        user_feature::Column::UserId,
+                        user_feature::Column::FeatureId,
+                    ])
+                    .do_nothing()
+                    .to_owned(),
+                )
+                .exec_without_returning(&*tx)
+                .await?;
+                if rows_affected > 0 {
+                    self.record_flag_audit(
+                        flag,
+                        user,
+                        FeatureFlagAuditAction::Granted,
+                        Some(user),
+                        &tx,
+                    )
+                    .await?;
+                    self.check_flag_max_users(flag, 1, &tx).await?;
+                }
+            } else {
+                let result = user_feature::Entity::delete_many()
+                    .filter(user_feature::Column::UserId.eq(user))
+                    .filter(user_feature::Column::FeatureId.eq(flag))
+                    .exec(&*tx)
+                    .await?;
+                if result.rows_affected > 0 {
+                    self.record_flag_audit(
+                        flag,
+                        user,
+                        FeatureFlagAuditAction::Revoked,
+                        Some(user),
+                        &tx,
+                    )
+                    .await?;
+                }
+            }
+
+            self.invalidate_user_flags(FlagInvalidation::Users(vec![user]), &tx)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Sets the user's value for a non-boolean feature flag, granting them the flag if they don't
+    /// have it yet. Pass `None` to fall back to the flag's default value.
+    ///
+    /// Fails if the value's type doesn't match the flag's.
+    pub async fn set_user_flag_value(
+        &self,
+        user: UserId,
+        flag: FlagId,
+        value: Option<FlagValue>,
+        actor: Option<UserId>,
+    ) -> Result<()> {
+        self.transaction(|tx| async move {
+            let Some(feature_flag) = feature_flag::Entity::find_by_id(flag).one(&*tx).await? else {
+                Err(anyhow!("no such feature flag"))?
+            };
+            if feature_flag.value_type == FlagValueType::Bool {
+                Err(anyhow!(
+                    "boolean feature flags are granted and revoked rather than overridden"
+                ))?;
+            }
+            if let Some(value) = &value {
+                feature_flag.check_value(value)?;
+            }
+
+            let value_override = value.as_ref().map(FlagValue::encode);
+            let result = user_feature::Entity::update_many()
+                .filter(user_feature::Column::UserId.eq(user))
+                .filter(user_feature::Column::FeatureId.eq(flag))
+                .set(user_feature::ActiveModel {
+                    value_override: ActiveValue::set(value_override.clone()),
+                    ..Default::default()
+                })
+                .exec(&*tx)
+                .await?;
+            if result.rows_affected == 0 && value_override.is_some() {
+                self.record_flag_audit(flag, user, FeatureFlagAuditAction::Granted, actor, &tx)
+                    .await?;
+
+                user_feature::Entity::insert(user_feature::ActiveModel {
+                    user_id: ActiveValue::set(user),
+                    feature_id: ActiveValue::set(flag),
+                    expires_at: ActiveValue::set(None),
+                    granted_at: ActiveValue::NotSet,
+                    value_override: ActiveValue::set(value_override),
+                })
+                .exec(&*tx)
+                .await?;
+                self.check_flag_max_users(flag, 1, &tx).await?;
+            }
+
+            self.invalidate_user_flags(FlagInvalidation::Users(vec![user]), &tx)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Adds every user matching the filter to the feature flag, skipping users who already have it.
+    ///
+    /// Returns the users the flag was newly granted to, sorted by ID. Each grant is recorded in
+    /// the flag's audit log, attributed to `actor`.
+    pub async fn add_flag_to_users_matching(
+        &self,
+        flag: FlagId,
+        filter: &UserFilter,
+        actor: Option<UserId>,
+    ) -> Result<Vec<UserId>> {
+        self.transaction(|tx| async move {
+            let matching_users = filter
+                .select_user_ids()
+                .expr(Expr::value(flag))
+                // SQLite can't tell an upsert's `ON CONFLICT` apart from a join's `ON`
+                // unless the `SELECT` has a `WHERE` clause.
+                .and_where(Expr::value(true))
+                .to_owned();
+            let insert = Query::insert()
+                .into_table(user_feature::Entity)
+                .columns([
+                    user_feature::Column::UserId,
+                    user_feature::Column::FeatureId,
+                ])
+                .select_from(matching_users)
+                .map_err(|error| anyhow!(error))?
+                .on_conflict(
+                    OnConflict::columns([
+                        user_feature::Column::UserId,
+                        user_feature::Column::FeatureId,
+                    ])
+                    .do_nothing()
+                    .to_owned(),
+                )
+                .returning_col(user_feature::Column::UserId)
+                .to_owned();
+
+            let mut granted_user_ids = tx
+                .query_all(self.pool.get_database_backend().build(&insert))
+                .await?
+                .into_iter()
+                .map(|row| row.try_get::<UserId>("", "user_id"))
+                .collect::<Result<Vec<_>, _>>()?;
+            granted_user_ids.sort();
+            // Nobody is granted the flag if that would take it past its cap.
+            self.check_flag_max_users(flag, granted_user_ids.len(), &tx)
+                .await?;
+            self.record_flag_audits(
+                flag,
+                &granted_user_ids,
+                FeatureFlagAuditAction::Granted,
+                actor,
+                &tx,
+            )
+            .await?;
+
+            self.invalidate_user_flags(FlagInvalidation::Users(granted_user_ids.clone()), &tx)
+                .await?;
+            Ok(granted_user_ids)
+        })
+        .await
+    }
+
+    /// Removes every user matching the filter from the feature flag.
+    ///
+    /// Returns the users the flag was revoked from, sorted by ID. Each revocation is recorded in
+    /// the flag's audit log, attributed to `actor`.
+    pub async fn remove_flag_from_users_matching(
+        &self,
+        flag: FlagId,
+        filter: &UserFilter,
+        actor: Option<UserId>,
+    ) -> Result<Vec<UserId>> {
+        self.transaction(|tx| async move {
+            let mut revoked_user_ids = user_feature::Entity::delete_many()
+                .filter(user_feature::Column::FeatureId.eq(flag))
+                .filter(user_feature::Column::UserId.in_subquery(filter.select_user_ids()))
+                .exec_with_returning(&*tx)
+                .await?
+                .into_iter()
+                .map(|user_feature| user_feature.user_id)
+                .collect::<Vec<_>>();
+            revoked_user_ids.sort();
+            self.record_flag_audits(
+                flag,
+                &revoked_user_ids,
+                FeatureFlagAuditAction::Revoked,
+                actor,
+                &tx,
+            )
+            .await?;
+
+            self.invalidate_user_flags(FlagInvalidation::Users(revoked_user_ids.clone()), &tx)
+                .await?;
+            Ok(revoked_user_ids)
+        })
+        .await
+    }
+
+    /// Deletes any feature flag grants that have expired, recording their revocations in the
+    /// flags' audit logs.
+    pub async fn purge_expired_user_flags(&self) -> Result<()> {
+        self.transaction(|tx| async move {
+            let grants = user_feature::Entity::delete_many()
+                .filter(user_feature::Column::ExpiresAt.lte(Utc::now().naive_utc()))
+                .exec_with_returning(&*tx)
+                .await?;
+            self.record_flag_revocations(&grants, &tx).await?;
+            let user_ids = grants.into_iter().map(|grant| grant.user_id).collect();
+            self.invalidate_user_flags(FlagInvalidation::Users(user_ids), &tx)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Removes the given user from the feature flag.
+    ///
+    /// The revocation is recorded in the flag's audit log, attributed to `actor`.
+    pub async fn remove_user_flag(
+        &self,
+        user: UserId,
+        flag: FlagId,
+        actor: Option<UserId>,
+    ) -> Result<()> {
+        self.transaction(|tx| async move {
+            let result = user_feature::Entity::delete_many()
+                .filter(user_feature::Column::UserId.eq(user))
+                .filter(user_feature::Column::FeatureId.eq(flag))
+                .exec(&*tx)
+                .await?;
+            if result.rows_affected > 0 {
+                self.record_flag_audit(flag, user, FeatureFlagAuditAction::Revoked, actor, &tx)
+                    .await?;
+            }
+
+            self.invalidate_user_flags(FlagInvalidation::Users(vec![user]), &tx)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Fails if the flag, having just been granted to `granted_user_count` more users, now
+    /// reaches more users than its cap, so that the transaction granting it is rolled back.
+    async fn check_flag_max_users(
+        &self,
+        flag: FlagId,
+        granted_user_count: usize,
+        tx: &DatabaseTransaction,
+    ) -> Result<()> {
+        if granted_user_count == 0 {
+            return Ok(());
+        }
+        let Some(feature_flag) = feature_flag::Entity::find_by_id(flag).one(tx).await? else {
+            Err(anyhow!("no such feature flag"))?
+        };
+        let Some(max_users) = feature_flag.max_users else {
+            return Ok(());
+        };
+
+        let user_count = user_feature::Entity::find()
+            .inner_join(user::Entity)
+            .filter(user_feature::Column::FeatureId.eq(flag))
+            .count(tx)
+            .await?;
+        let total_user_count = user::Entity::find().count(tx).await?;
+        let estimated_user_count = feature_flag.estimated_user_count(user_count, total_user_count);
+        if estimated_user_count > max_users.max(0) as u64 {
+            Err(anyhow!(
+                "granting feature flag {} to {granted_user_count} more user(s) would take it to \
+                {estimated_user_count} users, past its limit of {max_users}",
+                feature_flag.flag
+            ))?;
+        }
+        Ok(())
+    }
+
+    async fn record_flag_audit(
+        &self,
+        flag: FlagId,
+        user: UserId,
+        action: FeatureFlagAuditAction,
+        actor: Option<UserId>,
+        tx: &DatabaseTransaction,
+    ) -> Result<()> {
+        self.record_flag_audits(flag, &[user], action, actor, tx)
+            .await
+    }
+
+    async fn record_flag_audits(
+        &self,
+        flag: FlagId,
+        users: &[UserId],
+        action: FeatureFlagAuditAction,
+        actor: Option<UserId>,
+        tx: &DatabaseTransaction,
+    ) -> Result<()> {
+        if users.is_empty() {
+            return Ok(());
+        }
+        let Some(feature_flag) = feature_flag::Entity::find_by_id(flag).one(tx).await? else {
+            Err(anyhow!("no such feature flag"))?
+        };
+
+        feature_flag_audit::Entity::insert_many(users.iter().map(|user| {
+            feature_flag_audit::ActiveModel {
+                flag_id: ActiveValue::set(flag),
+                flag: ActiveValue::set(feature_flag.flag.clone()),
+                user_id: ActiveValue::set(*user),
+                action: ActiveValue::set(action),
+                actor_id: ActiveValue::set(actor),
+                ..Default::default()
+            }
+        }))
+        .exec(tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Records the revocation of each of the given grants, which are being removed without
+    /// anyone asking for it, such as when they expire or their user is deleted.
+    async fn record_flag_revocations(
+        &self,
+        grants: &[user_feature::Model],
+        tx: &DatabaseTransaction,
+    ) -> Result<()> {
+        let mut users_by_flag = BTreeMap::<FlagId, Vec<UserId>>::default();
+        for grant in grants {
+            users_by_flag
+                .entry(grant.feature_id)
+                .or_default()
+                .push(grant.user_id);
+        }
+        for (flag, users) in users_by_flag {
+            self.record_flag_audits(flag, &users, FeatureFlagAuditAction::Revoked, None, tx)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Records that the given flags were served to a client.
+    ///
+    /// The counts are kept in memory until the next [`Self::flush_flag_stats`], so this never
+    /// waits on the database.
+    pub fn record_flags_served<'a>(&self, flags: impl IntoIterator<Item = &'a str>) {
+        let mut served_flag_counts = self.served_flag_counts.lock();
+        for flag in flags {
+            if let Some(count) = served_flag_counts.get_mut(flag) {
+                *count += 1;
+            } else {
+                served_flag_counts.insert(flag.to_string(), 1);
+            }
+        }
+    }
+
+    /// Adds the counts recorded since the last flush to today's stats for each flag.
+    ///
+    /// Counts for flags that no longer exist are dropped. If the stats can't be written, the
+    /// counts are kept for the next flush.
+    pub async fn flush_flag_stats(&self) -> Result<()> {
+        let served_flag_counts = mem::take(&mut *self.served_flag_counts.lock());
+        if served_flag_counts.is_empty() {
+            return Ok(());
+        }
+
+        let today = Utc::now().date_naive();
+        let counts = &served_flag_counts;
+        let result = self
+            .transaction(|tx| async move {
+                let stats = feature_flag::Entity::find()
+                    .filter(feature_flag::Column::Flag.is_in(counts.keys().cloned()))
+                    .all(&*tx)
+                    .await?
+                    .into_iter()
+                    .map(|flag| feature_flag_stats::ActiveModel {
+                        flag_id: ActiveValue::set(flag.id),
+                        date: ActiveValue::set(today),
+                        served_count: ActiveValue::set(counts[&flag.flag] as i64),
+                    })
+                    .collect::<Vec<_>>();
+                if stats.is_empty() {
+                    return Ok(());
+                }
+
+                feature_flag_stats::Entity::insert_many(stats)
+                    .on_conflict(
+                        OnConflict::columns([
+                            feature_flag_stats::Column::FlagId,
+                            feature_flag_stats::Column::Date,
+                        ])
+                        .value(
+                            feature_flag_stats::Column::ServedCount,
+                            Expr::cust("feature_flag_stats.served_count + excluded.served_count"),
+                        )
+                        .to_owned(),
+                    )
+                    .exec_without_returning(&*tx)
+                    .await?;
+                Ok(())
+            })
+            .await;
+
+        if result.is_err() {
+            let mut pending_counts = self.served_flag_counts.lock();
+            for (flag, count) in served_flag_counts {
+                *pending_counts.entry(flag).or_default() += count;
+            }
+        }
+        result
+    }
+
+    /// Returns how many times the feature flag was served on each day since the given date,
+    /// oldest first.
+    pub async fn get_flag_stats(
+        &self,
+        flag: FlagId,
+        since: NaiveDate,
+    ) -> Result<Vec<feature_flag_stats::Model>> {
+        self.read_transaction(|tx| async move {
+            Ok(feature_flag_stats::Entity::find()
+                .filter(
+                    feature_flag_stats::Column::FlagId
+                        .eq(flag)
+                        .and(feature_flag_stats::Column::Date.gte(since)),
+                )
+                .order_by_asc(feature_flag_stats::Column::Date)
+                .all(&*tx)
+                .await?)
+        })
+        .await
+    }
+
+    /// Returns the audit log for the feature flag, most recent first.
+    ///
+    /// Pass the id of the last entry of a page as `before` to fetch the next page.
+    pub async fn get_flag_audit_log(
+        &self,
+        flag: FlagId,
+        limit: u64,
+        before: Option<FeatureFlagAuditId>,
+    ) -> Result<Vec<feature_flag_audit::Model>> {
+        self.transaction(|tx| async move {
+            let mut query = feature_flag_audit::Entity::find()
+                .filter(feature_flag_audit::Column::FlagId.eq(flag));
+            if let Some(before) = before {
+                query = query.filter(feature_flag_audit::Column::Id.lt(before));
+            }
+
+            Ok(query
+                .order_by_desc(feature_flag_audit::Column::Id)
+                .limit(limit)
+                .all(&*tx)
+                .await?)
+        })
+        .await
+    }
+
+    /// Deletes the feature flag, removing it from every user it was granted to. The revocations
+    /// are recorded in the flag's audit log, which is kept.
+    ///
+    /// Returns the users whose flags changed: those the flag had been granted to, along with all
+    /// staff if it was staff-only, or every user if it was enabled for all or rolled out. Fails
+    /// if other flags depend on it.
+    pub async fn delete_feature_flag(&self, flag: FlagId) -> Result<FlagInvalidation> {
+        self.transaction(|tx| async move {
+            #[derive(Copy, Clone, Debug, EnumIter, DeriveColumn)]
+            enum QueryAs {
+                UserId,
+            }
+
+            let Some(feature_flag) = feature_flag::Entity::find_by_id(flag).one(&*tx).await? else {
+                Err(anyhow!("no such feature flag"))?
+            };
+
+            let dependents = feature_flag::Entity::find()
+                .filter(feature_flag::Column::DependsOn.eq(flag))
+                .order_by_asc(feature_flag::Column::Flag)
+                .all(&*tx)
+                .await?;
+            if !dependents.is_empty() {
+                let dependents = dependents
+                    .into_iter()
+                    .map(|dependent| dependent.flag)
+                    .collect::<Vec<_>>();
+                Err(anyhow!(
+                    "can't delete a feature flag that other flags depend on: {}",
+                    dependents.join(", ")
+                ))?;
+            }
+
+            let mut user_ids = user_feature::Entity::find()
+                .filter(user_feature::Column::FeatureId.eq(flag))
+                .select_only()
+                .column(user_feature::Column::UserId)
+                .into_values::<UserId, QueryAs>()
+                .all(&*tx)
+                .await?;
+            self.record_flag_audits(flag, &user_ids, FeatureFlagAuditAction::Revoked, None, &tx)
+                .await?;
+
+            user_feature::Entity::delete_many()
+                .filter(user_feature::Column::FeatureId.eq(flag))
+                .exec(&*tx)
+                .await?;
+
+            feature_flag::Entity::delete_by_id(flag).exec(&*tx).await?;
+
+            let invalidation =
+                if feature_flag.enabled_for_all || feature_flag.rollout_percentage > 0 {
+                    FlagInvalidation::AllUsers
+                } else {
+                    if feature_flag.staff_only {
+                        user_ids.extend(
+                            user::Entity::find()
+                                .filter(user::Column::Admin.eq(true))
+                                .select_only()
+                                .column_as(user::Column::Id, QueryAs::UserId)
+                                .into_values::<UserId, QueryAs>()
+                                .all(&*tx)
+                                .await?,
+                        );
+                    }
+                    user_ids.sort();
+                    user_ids.dedup();
+                    FlagInvalidation::Users(user_ids)
+                };
+            self.invalidate_user_flags(invalidation.clone(), &tx)
+                .await?;
+            Ok(invalidation)
+        })
+        .await
+    }
+
+    /// Returns the users the flag is granted to, along with all staff if the flag is staff-only,
+    /// sorted by ID.
+    ///
+    /// Users that only have the flag because it's enabled for all or rolled out to them aren't
+    /// included.
+    pub async fn get_flag_users(&self, flag: FlagId) -> Result<Vec<UserId>> {
         self.transaction(|tx| async move {
             #[derive(Copy, Clone, Debug, EnumIter, DeriveColumn)]
             enum QueryAs {
-                Flag,
+                Id,
             }
 
-            let flags_enabled_for_all = feature_flag::Entity::find()
-                .filter(feature_flag::Column::EnabledForAll.eq(true))
+            let Some(flag) = feature_flag::Entity::find_by_id(flag).one(&*tx).await? else {
+                Err(anyhow!("no such feature flag"))?
+            };
+
+            let mut user_ids = flag
+                .find_linked(feature_flag::FlaggedUsers {
+                    now: Utc::now().naive_utc(),
+                })
                 .select_only()
-                .column(feature_flag::Column::Flag)
-                .into_values::<_, QueryAs>()
+                .column(user::Column::Id)
+                .into_values::<UserId, QueryAs>()
                 .all(&*tx)
                 .await?;
+            if flag.staff_only {
+                user_ids.extend(
+                    user::Entity::find()
+                        .filter(user::Column::Admin.eq(true))
+                        .select_only()
+                        .column(user::Column::Id)
+                        .into_values::<UserId, QueryAs>()
+                        .all(&*tx)
+                        .await?,
+                );
+            }
+            user_ids.sort();
+            user_ids.dedup();
 
-            let flags_enabled_for_user = user::Model {
-                id: user,
-                ..Default::default()
+            Ok(user_ids)
+        })
+        .await
+    }
+
+    /// Returns a page of the users the flag is granted to, ordered by GitHub login, along with
+    /// the number of matching users across all pages.
+    ///
+    /// `query` case-insensitively matches any part of the users' GitHub logins. Users that only
+    /// have the flag through its rollout aren't included, as they aren't stored.
+    pub async fn get_users_for_flag(
+        &self,
+        flag: FlagId,
+        query: Option<&str>,
+        page: u64,
+        page_size: u64,
+    ) -> Result<FlagUsersPage> {
+        self.read_transaction(|tx| async move {
+            let Some(flag) = feature_flag::Entity::find_by_id(flag).one(&*tx).await? else {
+                Err(anyhow!("no such feature flag"))?
+            };
+
+            let mut condition = Condition::all()
+                .add(user_feature::Column::FeatureId.eq(flag.id))
+                .add(
+                    Condition::any()
+                        .add(user_feature::Column::ExpiresAt.is_null())
+                        .add(user_feature::Column::ExpiresAt.gt(Utc::now().naive_utc())),
+                );
+            if let Some(query) = query {
+                let pattern = format!("%{}%", escape_like(&query.to_lowercase()));
+                condition = condition.add(
+                    Expr::expr(Func::lower(Expr::col((
+                        user::Entity,
+                        user::Column::GithubLogin,
+                    ))))
+                    .like(LikeExpr::new(pattern).escape('\\')),
+                );
             }
-            .find_linked(user::UserFlags)
-            .select_only()
-            .column(feature_flag::Column::Flag)
-            .into_values::<_, QueryAs>()
-            .all(&*tx)
-            .await?;
 
-            let mut all_flags = HashSet::from_iter(flags_enabled_for_all);
-            all_flags.extend(flags_enabled_for_user);
+            let grants = user_feature::Entity::find()
+                .inner_join(user::Entity)
+                .filter(condition);
+            let total_count = grants.clone().count(&*tx).await?;
+            let users = grants
+                .select_only()
+                .column(user_feature::Column::UserId)
+                .column(user::Column::GithubLogin)
+                .column(user_feature::Column::GrantedAt)
+                .column(user_feature::Column::ExpiresAt)
+                .order_by_asc(user::Column::GithubLogin)
+                .order_by_asc(user_feature::Column::UserId)
+                .offset(page * page_size)
+                .limit(page_size)
+                .into_tuple::<(UserId, String, NaiveDateTime, Option<NaiveDateTime>)>()
+                .all(&*tx)
+                .await?
+                .into_iter()
+                .map(|(user_id, github_login, granted_at, expires_at)| FlagUser {
+                    user_id,
+                    github_login,
+                    granted_at,
+                    expires_at,
+                    in_rollout: flag.is_rolled_out_to(user_id),
+                })
+                .collect();
+
+            Ok(FlagUsersPage { total_count, users })
+        })
+        .await
+    }
+
+    /// Returns the active boolean flags for the user.
+    pub async fn get_user_flags(&self, user: UserId) -> Result<Vec<String>> {
+        self.get_user_flags_at(user, Utc::now().naive_utc()).await
+    }
+
+    /// Returns the boolean flags that are active for the user at the given time, according to
+    /// the flags' schedules.
+    pub async fn get_user_flags_at(&self, user: UserId, now: NaiveDateTime) -> Result<Vec<String>> {
+        self.read_transaction(|tx| async move {
+            Ok(self
+                .user_flags_with_sources(user, now, &tx)
+                .await?
+                .into_iter()
+                .map(|flag| flag.flag)
+                .collect())
+        })
+        .await
+    }
 
-            Ok(all_flags.into_iter().collect())
+    /// Returns the active boolean flags for the user, along with why each is active.
+    pub async fn get_user_flags_with_sources(&self, user: UserId) -> Result<Vec<UserFlag>> {
+        self.read_transaction(|tx| async move {
+            self.user_flags_with_sources(user, Utc::now().naive_utc(), &tx)
+                .await
         })
         .await
     }
 
+    /// Returns the active boolean flags for the user, as of at most [`USER_FLAG_CACHE_TTL`]
+    /// ago unless they've been changed on this node since, or on another node before this node
+    /// last ran [`Self::poll_flag_cache_versions`].
+    ///
+    /// Use [`Self::get_user_flags`] where the flags must be fresh.
+    ///
+    /// [`USER_FLAG_CACHE_TTL`]: super::user_flag_cache::USER_FLAG_CACHE_TTL
+    pub async fn get_user_flags_cached(&self, user: UserId) -> Result<Vec<UserFlag>> {
+        self.get_user_flags_through(&self.user_flag_cache, user)
+            .await
+    }
+
+    /// Returns the active boolean flags for the user from the cache, fetching and caching them
+    /// on a miss. A hit doesn't query the database.
+    pub async fn get_user_flags_through(
+        &self,
+        cache: &UserFlagCache,
+        user: UserId,
+    ) -> Result<Vec<UserFlag>> {
+        if let Some(flags) = cache.get(user) {
+            return Ok(flags);
+        }
+
+        let read_flags = |tx: TransactionHandle| async move {
+            let versions = self.flag_versions(user, &tx).await?;
+            let flags = self
+                .user_flags_with_sources(user, Utc::now().naive_utc(), &tx)
+                .await?;
+            Ok((flags, versions))
+        };
+        let (flags, versions) = if self.read_replica.is_some() {
+            // Cached flags are pushed to clients right after flag changes, so the replica's are
+            // only used once it has caught up with the primary's versions, which every change
+            // bumps.
+            let primary_versions = self
+                .transaction(|tx| async move { self.flag_versions(user, &tx).await })
+                .await?;
+            let (flags, versions) = self.read_transaction(&read_flags).await?;
+            if versions == primary_versions {
+                (flags, versions)
+            } else {
+                self.transaction(&read_flags).await?
+            }
+        } else {
+            self.transaction(&read_flags).await?
+        };
+        cache.insert(user, flags.clone(), versions);
+        Ok(flags)
+    }
+
+    /// Returns the flags that the user can opt into, sorted by name, along with whether they
+    /// have.
+    ///
+    /// Flags outside of their schedule are omitted. This reads from the primary, so that it
+    /// reflects an opt-in made just before.
+    pub async fn get_opt_in_flags(&self, user: UserId) -> Result<Vec<OptInFlag>> {
+        self.transaction(|tx| async move {
+            let now = Utc::now().naive_utc();
+            let granted_flag_ids = user_feature::Entity::find()
+                .filter(user_feature::Column::UserId.eq(user))
+                .filter(
+                    Condition::any()
+                        .add(user_feature::Column::ExpiresAt.is_null())
+                        .add(user_feature::Column::ExpiresAt.gt(now)),
+                )
+                .all(&*tx)
+                .await?
+                .into_iter()
+                .map(|grant| grant.feature_id)
+                .collect::<HashSet<_>>();
+
+            Ok(feature_flag::Entity::find()
+                .filter(feature_flag::Column::OptIn.eq(true))
+                .order_by_asc(feature_flag::Column::Flag)
+                .all(&*tx)
+                .await?
+                .into_iter()
+                .filter(|flag| flag.is_scheduled_active(now))
+                .map(|flag| OptInFlag {
+                    opted_in: granted_flag_ids.contains(&flag.id),
+                    id: flag.id,
+                    flag: flag.flag,
+                    description: flag.description,
+                })
+                .collect())
+        })
+        .await
+    }
+
+    /// Returns the value of every feature flag the user has, sorted by name.
+    ///
+    /// Boolean flags are included, as `true`, while they're active for the user. Other flags take
+    /// the user's override if they have an unexpired one, and the flag's default otherwise.
+    pub async fn get_user_flag_values(&self, user: UserId) -> Result<Vec<(String, FlagValue)>> {
+        self.read_transaction(|tx| async move {
+            let now = Utc::now().naive_utc();
+            let mut values = self
+                .user_flags_with_sources(user, now, &tx)
+                .await?
+                .into_iter()
+                .map(|flag| (flag.flag, FlagValue::Bool(true)))
+                .collect::<Vec<_>>();
+
+            let overrides = user_feature::Entity::find()
+                .filter(user_feature::Column::UserId.eq(user))
+                .filter(
+                    Condition::any()
+                        .add(user_feature::Column::ExpiresAt.is_null())
+                        .add(user_feature::Column::ExpiresAt.gt(now)),
+                )
+                .all(&*tx)
+                .await?
+                .into_iter()
+                .filter_map(|grant| Some((grant.feature_id, grant.value_override?)))
+                .collect::<HashMap<_, _>>();
+            let typed_flags = feature_flag::Entity::find()
+                .filter(feature_flag::Column::ValueType.ne(FlagValueType::Bool))
+                .all(&*tx)
+                .await?;
+            for flag in typed_flags {
+                if !flag.is_scheduled_active(now) {
+                    continue;
+                }
+                let Some(value) = overrides.get(&flag.id).or(flag.default_value.as_ref()) else {
+                    continue;
+                };
+                let value = flag.value_type.parse(value)?;
+                values.push((flag.flag, value));
+            }
+
+            values.sort_by(|a, b| a.0.cmp(&b.0));
+            Ok(values)
+        })
+        .await
+    }
+
+    /// Returns the active flags for the user, along with a version that changes whenever a flag
+    /// is granted to or revoked from the user, or a flag's rollout changes.
+    pub async fn get_user_flags_with_version(&self, user: UserId) -> Result<UserFlagsWithVersion> {
+        self.read_transaction(|tx| async move {
+            let flags = self
+                .user_flags_with_sources(user, Utc::now().naive_utc(), &tx)
+                .await?;
+
+            #[derive(Copy, Clone, Debug, EnumIter, DeriveColumn)]
+            enum QueryAs {
+                LastUpdatedAt,
+            }
+
+            let last_flag_update = feature_flag::Entity::find()
+                .select_only()
+                .column_as(
+                    feature_flag::Column::UpdatedAt.max(),
+                    QueryAs::LastUpdatedAt,
+                )
+                .into_values::<Option<NaiveDateTime>, QueryAs>()
+                .one(&*tx)
+                .await?
+                .flatten();
+            let last_user_change = feature_flag_audit::Entity::find()
+                .filter(feature_flag_audit::Column::UserId.eq(user))
+                .select_only()
+                .column_as(
+                    feature_flag_audit::Column::CreatedAt.max(),
+                    QueryAs::LastUpdatedAt,
+                )
+                .into_values::<Option<NaiveDateTime>, QueryAs>()
+                .one(&*tx)
+                .await?
+                .flatten();
+            let version = last_flag_update
+                .max(last_user_change)
+                .map_or(0, |updated_at| updated_at.and_utc().timestamp_micros());
+
+            Ok(UserFlagsWithVersion { flags, version })
+        })
+        .await
+    }
+
+    /// Returns the boolean flags that are active for the user at the given time, sorted by name.
+    ///
+    /// A flag that's active for several reasons is reported once, preferring an explicit grant
+    /// over the flag being enabled for all users, over the user being staff, over a rollout.
+    /// Reading through the transaction that granted or revoked a flag sees that change.
+    pub(crate) async fn user_flags_with_sources(
+        &self,
+        user: UserId,
+        now: NaiveDateTime,
+        tx: &DatabaseTransaction,
+    ) -> Result<Vec<UserFlag>> {
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveColumn)]
+        enum QueryAs {
+            Id,
+        }
+
+        let granted_flag_ids = user::Model {
+            id: user,
+            ..Default::default()
+        }
+        .find_linked(user::UserFlags { now })
+        .select_only()
+        .column(feature_flag::Column::Id)
+        .into_values::<FlagId, QueryAs>()
+        .all(tx)
+        .await?
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let is_staff = user::Entity::find_by_id(user)
+            .one(tx)
+            .await?
+            .map_or(false, |user| user.admin);
+
+        let all_flags = feature_flag::Entity::find()
+            .filter(feature_flag::Column::ValueType.eq(FlagValueType::Bool))
+            .all(tx)
+            .await?;
+        let dependencies = all_flags
+            .iter()
+            .map(|flag| (flag.id, flag.depends_on))
+            .collect::<HashMap<_, _>>();
+        let sources = all_flags
+            .iter()
+            .filter_map(|flag| {
+                if !flag.is_scheduled_active(now) {
+                    return None;
+                }
+                let source = if granted_flag_ids.contains(&flag.id) {
+                    UserFlagSource::Granted
+                } else if flag.enabled_for_all {
+                    UserFlagSource::EnabledForAll
+                } else if flag.staff_only && is_staff {
+                    UserFlagSource::Staff
+                } else if flag.is_rolled_out_to(user) {
+                    UserFlagSource::Rollout
+                } else {
+                    return None;
+                };
+                Some((flag.id, source))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut flags = all_flags
+            .into_iter()
+            .filter_map(|flag| {
+                let source = *sources.get(&flag.id)?;
+                // The user only has the flag if they have every flag in its dependency chain.
+                let mut dependency = flag.depends_on;
+                while let Some(dependency_id) = dependency {
+                    sources.get(&dependency_id)?;
+                    dependency = dependencies.get(&dependency_id).copied().flatten();
+                }
+                Some(UserFlag {
+                    flag: flag.flag,
+                    source,
+                    minimum_client_version: flag.minimum_client_version,
+                })
+            })
+            .collect::<Vec<_>>();
+        flags.sort_by(|a, b| a.flag.cmp(&b.flag));
+        Ok(flags)
+    }
+
     pub async fn get_users_missing_github_user_created_at(&self) -> Result<Vec<user::Model>> {
         self.transaction(|tx| async move {
             Ok(user::Entity::find()
@@ -381,3 +1991,15 @@ impl Database {
         .await
     }
 }
+
+/// Escapes the wildcards in a string matched with `LIKE`, using `\` as the escape character.
+fn escape_like(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len());
+    for c in string.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -18,6 +18,9 @@ pub mod embedding;
 pub mod extension;
 pub mod extension_version;
 pub mod feature_flag;
+pub mod feature_flag_audit;
+pub mod feature_flag_stats;
+pub mod flag_cache_version;
 pub mod follower;
 pub mod hosted_project;
 pub mod language_server;
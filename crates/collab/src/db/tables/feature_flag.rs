@@ -1,6 +1,9 @@
+use anyhow::anyhow;
 use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use crate::db::FlagId;
+use crate::db::{FlagId, UserId};
 
 #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
 #[sea_orm(table_name = "feature_flags")]
@@ -9,12 +12,159 @@ pub struct Model {
     pub id: FlagId,
     pub flag: String,
     pub enabled_for_all: bool,
+    /// The percentage of users (0-100) that have this flag enabled.
+    pub rollout_percentage: i32,
+    /// When the flag's rollout was last changed.
+    pub updated_at: DateTime,
+    /// The flag that must also be active for a user to have this flag.
+    pub depends_on: Option<FlagId>,
+    /// Whether staff have this flag without it being granted to them.
+    pub staff_only: bool,
+    /// The type of the flag's value. Boolean flags are simply active for a user or not.
+    pub value_type: FlagValueType,
+    /// The value of a non-boolean flag for users without an override, encoded as text.
+    pub default_value: Option<String>,
+    /// The oldest client version that the flag is sent to, as a semantic version.
+    pub minimum_client_version: Option<String>,
+    /// When the flag starts being active. Before then, nobody has the flag.
+    pub activate_at: Option<DateTime>,
+    /// When the flag stops being active. From then on, nobody has the flag.
+    pub deactivate_at: Option<DateTime>,
+    /// Whether users can grant themselves the flag, e.g. to join a public beta.
+    pub opt_in: bool,
+    /// What the flag enables, as shown to users who can opt into it.
+    pub description: Option<String>,
+    /// The most users the flag can reach. Grants that would take it past this are rejected.
+    pub max_users: Option<i32>,
+}
+
+impl Model {
+    /// Returns whether the given user falls within this flag's rollout.
+    pub fn is_rolled_out_to(&self, user_id: UserId) -> bool {
+        rollout_bucket(user_id, &self.flag) < self.rollout_percentage
+    }
+
+    /// Returns whether the given time falls within this flag's schedule, outside of which the
+    /// flag is inactive for every user.
+    pub fn is_scheduled_active(&self, now: DateTime) -> bool {
+        self.activate_at
+            .map_or(true, |activate_at| now >= activate_at)
+            && self
+                .deactivate_at
+                .map_or(true, |deactivate_at| now < deactivate_at)
+    }
+
+    /// Estimates how many users have this flag: the users it's granted to, plus its rollout's
+    /// share of everyone else. A flag that's enabled for all reaches every user.
+    pub fn estimated_user_count(&self, granted_user_count: u64, total_user_count: u64) -> u64 {
+        if self.enabled_for_all {
+            return total_user_count.max(granted_user_count);
+        }
+        let rolled_out_user_count = total_user_count.saturating_sub(granted_user_count)
+            * self.rollout_percentage.clamp(0, 100) as u64
+            / 100;
+        granted_user_count + rolled_out_user_count
+    }
+
+    /// Fails unless the value has the type declared for this flag.
+    pub fn check_value(&self, value: &FlagValue) -> anyhow::Result<()> {
+        if value.value_type() != self.value_type {
+            Err(anyhow!(
+                "feature flag {} takes {} values, not {} values",
+                self.flag,
+                self.value_type.to_value(),
+                value.value_type().to_value()
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+/// The type of a feature flag's value.
+#[derive(
+    Eq,
+    PartialEq,
+    Copy,
+    Clone,
+    Debug,
+    EnumIter,
+    DeriveActiveEnum,
+    Default,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+#[sea_orm(rs_type = "String", db_type = "String(StringLen::None)")]
+#[serde(rename_all = "snake_case")]
+pub enum FlagValueType {
+    #[sea_orm(string_value = "bool")]
+    #[default]
+    Bool,
+    #[sea_orm(string_value = "string")]
+    String,
+    #[sea_orm(string_value = "int")]
+    Int,
+}
+
+impl FlagValueType {
+    /// Decodes a value of this type from its text encoding.
+    pub fn parse(self, value: &str) -> anyhow::Result<FlagValue> {
+        Ok(match self {
+            FlagValueType::Bool => FlagValue::Bool(value.parse()?),
+            FlagValueType::String => FlagValue::String(value.to_string()),
+            FlagValueType::Int => FlagValue::Int(value.parse()?),
+        })
+    }
+}
+
+/// The value of a feature flag for a user.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FlagValue {
+    Bool(bool),
+    String(String),
+    Int(i64),
+}
+
+impl FlagValue {
+    pub fn value_type(&self) -> FlagValueType {
+        match self {
+            FlagValue::Bool(_) => FlagValueType::Bool,
+            FlagValue::String(_) => FlagValueType::String,
+            FlagValue::Int(_) => FlagValueType::Int,
+        }
+    }
+
+    /// Encodes the value as text, for storage.
+    pub fn encode(&self) -> String {
+        match self {
+            FlagValue::Bool(value) => value.to_string(),
+            FlagValue::String(value) => value.clone(),
+            FlagValue::Int(value) => value.to_string(),
+        }
+    }
+}
+
+/// Assigns the user to a stable bucket in `0..100` for the given flag.
+///
+/// Bucketing on the flag name as well as the user means that each flag
+/// rolls out to a different subset of users.
+pub fn rollout_bucket(user_id: UserId, flag: &str) -> i32 {
+    let digest = Sha256::new()
+        .chain_update(user_id.0.to_be_bytes())
+        .chain_update(flag.as_bytes())
+        .finalize();
+    let mut prefix = [0; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    (u64::from_be_bytes(prefix) % 100) as i32
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
     #[sea_orm(has_many = "super::user_feature::Entity")]
     UserFeature,
+    #[sea_orm(belongs_to = "Entity", from = "Column::DependsOn", to = "Column::Id")]
+    DependsOn,
 }
 
 impl Related<super::user_feature::Entity> for Entity {
@@ -25,7 +175,10 @@ impl Related<super::user_feature::Entity> for Entity {
 
 impl ActiveModelBehavior for ActiveModel {}
 
-pub struct FlaggedUsers;
+/// Links a flag to the users it's granted to whose grants haven't expired as of `now`.
+pub struct FlaggedUsers {
+    pub now: DateTime,
+}
 
 impl Linked for FlaggedUsers {
     type FromEntity = Entity;
@@ -34,7 +187,10 @@ impl Linked for FlaggedUsers {
 
     fn link(&self) -> Vec<RelationDef> {
         vec![
-            super::user_feature::Relation::Flag.def().rev(),
+            super::user_feature::unexpired(
+                super::user_feature::Relation::Flag.def().rev(),
+                self.now,
+            ),
             super::user_feature::Relation::User.def(),
         ]
     }
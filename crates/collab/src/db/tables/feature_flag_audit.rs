@@ -0,0 +1,49 @@
+use crate::db::{FeatureFlagAuditId, FlagId, UserId};
+use sea_orm::entity::prelude::*;
+use serde::Serialize;
+
+/// A record of a feature flag being granted to or revoked from a user.
+///
+/// Entries are kept after the flag or user is deleted, so the flag's name is recorded too.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "feature_flag_audit")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: FeatureFlagAuditId,
+    pub created_at: DateTime,
+    pub flag_id: FlagId,
+    pub flag: String,
+    pub user_id: UserId,
+    pub action: FeatureFlagAuditAction,
+    /// The user who made the change, if it was made on someone's behalf.
+    pub actor_id: Option<UserId>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::feature_flag::Entity",
+        from = "Column::FlagId",
+        to = "super::feature_flag::Column::Id"
+    )]
+    Flag,
+}
+
+impl Related<super::feature_flag::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Flag.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// The change made to a user's feature flags.
+#[derive(Eq, PartialEq, Copy, Clone, Debug, EnumIter, DeriveActiveEnum, Hash, Serialize)]
+#[sea_orm(rs_type = "String", db_type = "String(StringLen::None)")]
+#[serde(rename_all = "snake_case")]
+pub enum FeatureFlagAuditAction {
+    #[sea_orm(string_value = "granted")]
+    Granted,
+    #[sea_orm(string_value = "revoked")]
+    Revoked,
+}
@@ -0,0 +1,32 @@
+use crate::db::FlagId;
+use sea_orm::entity::prelude::*;
+use serde::Serialize;
+
+/// The number of times a feature flag was served to clients on a given day.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "feature_flag_stats")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub flag_id: FlagId,
+    #[sea_orm(primary_key)]
+    pub date: Date,
+    pub served_count: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::feature_flag::Entity",
+        from = "Column::FlagId",
+        to = "super::feature_flag::Column::Id"
+    )]
+    Flag,
+}
+
+impl Related<super::feature_flag::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Flag.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,21 @@
+use crate::db::UserId;
+use sea_orm::entity::prelude::*;
+
+/// A version that flag mutations bump, so that cached flags can be recognized as stale.
+///
+/// The row for [`ALL_USERS`] is bumped by changes that affect every user.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "flag_cache_versions")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub user_id: UserId,
+    pub version: i64,
+}
+
+/// The `user_id` of the version that covers every user.
+pub const ALL_USERS: UserId = UserId(0);
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -79,7 +79,10 @@ impl Related<super::user_feature::Entity> for Entity {
 
 impl ActiveModelBehavior for ActiveModel {}
 
-pub struct UserFlags;
+/// Links a user to the flags granted to them whose grants haven't expired as of `now`.
+pub struct UserFlags {
+    pub now: NaiveDateTime,
+}
 
 impl Linked for UserFlags {
     type FromEntity = Entity;
@@ -88,7 +91,10 @@ impl Linked for UserFlags {
 
     fn link(&self) -> Vec<RelationDef> {
         vec![
-            super::user_feature::Relation::User.def().rev(),
+            super::user_feature::unexpired(
+                super::user_feature::Relation::User.def().rev(),
+                self.now,
+            ),
             super::user_feature::Relation::Flag.def(),
         ]
     }
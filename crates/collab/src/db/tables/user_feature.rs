@@ -1,4 +1,5 @@
-use sea_orm::entity::prelude::*;
+use chrono::NaiveDateTime;
+use sea_orm::{entity::prelude::*, sea_query::Expr, Condition};
 
 use crate::db::{FlagId, UserId};
 
@@ -9,6 +10,11 @@ pub struct Model {
     pub user_id: UserId,
     #[sea_orm(primary_key)]
     pub feature_id: FlagId,
+    /// When the grant stops applying, if it is time-bounded.
+    pub expires_at: Option<NaiveDateTime>,
+    pub granted_at: NaiveDateTime,
+    /// The user's value for a non-boolean flag, encoded as text, in place of the flag's default.
+    pub value_override: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -40,3 +46,13 @@ impl Related<super::user::Entity> for Entity {
 }
 
 impl ActiveModelBehavior for ActiveModel {}
+
+/// Restricts a relation that joins onto `user_features` to grants that haven't expired as of
+/// `now`.
+pub(crate) fn unexpired(relation: RelationDef, now: NaiveDateTime) -> RelationDef {
+    relation.on_condition(move |_, user_features| {
+        Condition::any()
+            .add(Expr::col((user_features.clone(), Column::ExpiresAt)).is_null())
+            .add(Expr::col((user_features, Column::ExpiresAt)).gt(now))
+    })
+}
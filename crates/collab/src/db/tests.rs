@@ -24,6 +24,11 @@ use std::sync::{
     Arc,
 };
 
+const SQLITE_TEST_SCHEMA: &str = include_str!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/migrations.sqlite/20221109000000_test_schema.sql"
+));
+
 pub struct TestDb {
     pub db: Option<Arc<Database>>,
     pub connection: Option<sqlx::AnyConnection>,
@@ -44,14 +49,10 @@ impl TestDb {
             let mut db = Database::new(options, Executor::Deterministic(background))
                 .await
                 .unwrap();
-            let sql = include_str!(concat!(
-                env!("CARGO_MANIFEST_DIR"),
-                "/migrations.sqlite/20221109000000_test_schema.sql"
-            ));
             db.pool
                 .execute(sea_orm::Statement::from_string(
                     db.pool.get_database_backend(),
-                    sql,
+                    SQLITE_TEST_SCHEMA,
                 ))
                 .await
                 .unwrap();
@@ -59,7 +60,7 @@ impl TestDb {
             db
         });
 
-        db.runtime = Some(runtime);
+        db.runtime = Some(Arc::new(runtime));
 
         Self {
             db: Some(Arc::new(db)),
@@ -67,6 +68,29 @@ impl TestDb {
         }
     }
 
+    /// Like [`Self::sqlite`], but with a second SQLite database as a read replica. Nothing is
+    /// replicated to it, so tests can tell which database each query ran against.
+    pub fn sqlite_with_read_replica(background: BackgroundExecutor) -> Self {
+        let mut test_db = Self::sqlite(background);
+        let db = Arc::get_mut(test_db.db.as_mut().unwrap()).unwrap();
+        let runtime = db.runtime.take().unwrap();
+        runtime.block_on(async {
+            let mut options = ConnectOptions::new("sqlite::memory:");
+            options.max_connections(5);
+            db.connect_read_replica(options).await.unwrap();
+            let read_replica = db.read_replica.as_ref().unwrap();
+            read_replica
+                .execute(sea_orm::Statement::from_string(
+                    read_replica.get_database_backend(),
+                    SQLITE_TEST_SCHEMA,
+                ))
+                .await
+                .unwrap();
+        });
+        db.runtime = Some(runtime);
+        test_db
+    }
+
     pub fn postgres(background: BackgroundExecutor) -> Self {
         static LOCK: Mutex<()> = Mutex::new(());
 
@@ -101,7 +125,7 @@ impl TestDb {
             db
         });
 
-        db.runtime = Some(runtime);
+        db.runtime = Some(Arc::new(runtime));
 
         Self {
             db: Some(Arc::new(db)),
@@ -114,6 +138,28 @@ impl TestDb {
     }
 }
 
+impl Database {
+    /// Returns a handle to the same database with its own in-memory state, like another collab
+    /// node would have.
+    pub fn another_node(&self) -> Arc<Database> {
+        Arc::new(Database {
+            options: self.options.clone(),
+            pool: self.pool.clone(),
+            read_replica: self.read_replica.clone(),
+            rooms: DashMap::default(),
+            projects: DashMap::default(),
+            rng: tokio::sync::Mutex::new(StdRng::seed_from_u64(0)),
+            executor: self.executor.clone(),
+            notification_kinds_by_id: self.notification_kinds_by_id.clone(),
+            notification_kinds_by_name: self.notification_kinds_by_name.clone(),
+            user_flag_cache: UserFlagCache::new(USER_FLAG_CACHE_CAPACITY, USER_FLAG_CACHE_TTL),
+            served_flag_counts: Default::default(),
+            query_count: Default::default(),
+            runtime: self.runtime.clone(),
+        })
+    }
+}
+
 #[macro_export]
 macro_rules! test_both_dbs {
     ($test_name:ident, $postgres_test_name:ident, $sqlite_test_name:ident) => {
@@ -1,8 +1,17 @@
 use crate::{
-    db::{Database, NewUserParams},
+    db::{
+        feature_flag::{self, FlagValue, FlagValueType},
+        feature_flag_audit::FeatureFlagAuditAction,
+        flag_cache_version, flags_for_client_version, user_feature, Database, FlagConfig,
+        FlagConfigDiff, FlagConfigUpdate, FlagDefinition, FlagId, FlagInvalidation, NewUserParams,
+        OptInFlag, TestDb, UserFilter, UserFlag, UserFlagCache, UserFlagSource, UserId,
+    },
     test_both_dbs,
 };
+use chrono::{Duration, NaiveDate, Utc};
 use pretty_assertions::assert_eq;
+use sea_orm::{ActiveValue, EntityTrait};
+use semantic_version::SemanticVersion;
 use std::sync::Arc;
 
 test_both_dbs!(
@@ -42,14 +51,28 @@ async fn test_get_user_flags(db: &Arc<Database>) {
     const FEATURE_FLAG_TWO: &str = "cool-feature";
     const FEATURE_FLAG_THREE: &str = "feature-enabled-for-everyone";
 
-    let feature_flag_one = db.create_user_flag(FEATURE_FLAG_ONE, false).await.unwrap();
-    let feature_flag_two = db.create_user_flag(FEATURE_FLAG_TWO, false).await.unwrap();
-    db.create_user_flag(FEATURE_FLAG_THREE, true).await.unwrap();
+    let feature_flag_one = db
+        .create_user_flag(FEATURE_FLAG_ONE, false, false)
+        .await
+        .unwrap();
+    let feature_flag_two = db
+        .create_user_flag(FEATURE_FLAG_TWO, false, false)
+        .await
+        .unwrap();
+    db.create_user_flag(FEATURE_FLAG_THREE, true, false)
+        .await
+        .unwrap();
 
-    db.add_user_flag(user_1, feature_flag_one).await.unwrap();
-    db.add_user_flag(user_1, feature_flag_two).await.unwrap();
+    db.add_user_flag(user_1, feature_flag_one, None, None)
+        .await
+        .unwrap();
+    db.add_user_flag(user_1, feature_flag_two, None, None)
+        .await
+        .unwrap();
 
-    db.add_user_flag(user_2, feature_flag_one).await.unwrap();
+    db.add_user_flag(user_2, feature_flag_one, None, None)
+        .await
+        .unwrap();
 
     let mut user_1_flags = db.get_user_flags(user_1).await.unwrap();
     user_1_flags.sort();
@@ -62,3 +85,1949 @@ async fn test_get_user_flags(db: &Arc<Database>) {
     user_2_flags.sort();
     assert_eq!(user_2_flags, &[FEATURE_FLAG_ONE, FEATURE_FLAG_THREE]);
 }
+
+test_both_dbs!(
+    test_flag_rollout,
+    test_flag_rollout_postgres,
+    test_flag_rollout_sqlite
+);
+
+async fn test_flag_rollout(db: &Arc<Database>) {
+    let mut users = Vec::new();
+    for i in 0..20 {
+        let user_id = db
+            .create_user(
+                &format!("user{i}@example.com"),
+                false,
+                NewUserParams {
+                    github_login: format!("user{i}"),
+                    github_user_id: i,
+                },
+            )
+            .await
+            .unwrap()
+            .user_id;
+        users.push(user_id);
+    }
+
+    const FLAG: &str = "staged-feature";
+    let flag = db.create_user_flag(FLAG, false, false).await.unwrap();
+
+    // An explicit grant wins even when the flag is rolled out to no one.
+    let explicit_user = users[0];
+    db.add_user_flag(explicit_user, flag, None, None)
+        .await
+        .unwrap();
+
+    async fn users_with_flag(db: &Database, users: &[UserId]) -> Vec<UserId> {
+        let mut result = Vec::new();
+        for user in users {
+            if db
+                .get_user_flags(*user)
+                .await
+                .unwrap()
+                .contains(&FLAG.to_string())
+            {
+                result.push(*user);
+            }
+        }
+        result
+    }
+
+    assert_eq!(users_with_flag(db, &users).await, &[explicit_user]);
+
+    db.set_flag_rollout(flag, 100).await.unwrap();
+    assert_eq!(users_with_flag(db, &users).await, users);
+
+    db.set_flag_rollout(flag, 50).await.unwrap();
+    let expected = users
+        .iter()
+        .copied()
+        .filter(|user| *user == explicit_user || feature_flag::rollout_bucket(*user, FLAG) < 50)
+        .collect::<Vec<_>>();
+    assert!(expected.len() > 1 && expected.len() < users.len());
+    assert_eq!(users_with_flag(db, &users).await, expected);
+    // Bucketing is deterministic across lookups.
+    assert_eq!(users_with_flag(db, &users).await, expected);
+
+    assert!(db.set_flag_rollout(flag, 101).await.is_err());
+}
+
+test_both_dbs!(
+    test_remove_and_delete_flags,
+    test_remove_and_delete_flags_postgres,
+    test_remove_and_delete_flags_sqlite
+);
+
+async fn test_remove_and_delete_flags(db: &Arc<Database>) {
+    let user_1 = db
+        .create_user(
+            "user1@example.com",
+            false,
+            NewUserParams {
+                github_login: "user1".to_string(),
+                github_user_id: 1,
+            },
+        )
+        .await
+        .unwrap()
+        .user_id;
+
+    let user_2 = db
+        .create_user(
+            "user2@example.com",
+            false,
+            NewUserParams {
+                github_login: "user2".to_string(),
+                github_user_id: 2,
+            },
+        )
+        .await
+        .unwrap()
+        .user_id;
+
+    const FEATURE_FLAG_ONE: &str = "brand-new-ux";
+    const FEATURE_FLAG_TWO: &str = "cool-feature";
+
+    let feature_flag_one = db
+        .create_user_flag(FEATURE_FLAG_ONE, false, false)
+        .await
+        .unwrap();
+    let feature_flag_two = db
+        .create_user_flag(FEATURE_FLAG_TWO, false, false)
+        .await
+        .unwrap();
+
+    db.add_user_flag(user_1, feature_flag_one, None, None)
+        .await
+        .unwrap();
+    db.add_user_flag(user_1, feature_flag_two, None, None)
+        .await
+        .unwrap();
+    db.add_user_flag(user_2, feature_flag_one, None, None)
+        .await
+        .unwrap();
+
+    let user_counts = db
+        .list_feature_flags()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|flag| (flag.flag, flag.user_count))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        user_counts,
+        &[
+            (FEATURE_FLAG_ONE.to_string(), 2),
+            (FEATURE_FLAG_TWO.to_string(), 1)
+        ]
+    );
+
+    db.remove_user_flag(user_1, feature_flag_two, None)
+        .await
+        .unwrap();
+    assert_eq!(
+        db.get_user_flags(user_1).await.unwrap(),
+        &[FEATURE_FLAG_ONE]
+    );
+    assert_eq!(
+        db.get_user_flags_with_sources(user_1).await.unwrap(),
+        &[UserFlag {
+            flag: FEATURE_FLAG_ONE.into(),
+            source: UserFlagSource::Granted,
+            minimum_client_version: None,
+        }]
+    );
+
+    db.delete_feature_flag(feature_flag_one).await.unwrap();
+    assert_eq!(
+        db.get_user_flags(user_1).await.unwrap(),
+        Vec::<String>::new()
+    );
+    assert_eq!(
+        db.get_user_flags(user_2).await.unwrap(),
+        Vec::<String>::new()
+    );
+
+    let user_counts = db
+        .list_feature_flags()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|flag| (flag.flag, flag.user_count))
+        .collect::<Vec<_>>();
+    assert_eq!(user_counts, &[(FEATURE_FLAG_TWO.to_string(), 0)]);
+
+    assert!(db.delete_feature_flag(feature_flag_one).await.is_err());
+}
+
+test_both_dbs!(
+    test_expiring_user_flags,
+    test_expiring_user_flags_postgres,
+    test_expiring_user_flags_sqlite
+);
+
+async fn test_expiring_user_flags(db: &Arc<Database>) {
+    let user = db
+        .create_user(
+            "user1@example.com",
+            false,
+            NewUserParams {
+                github_login: "user1".to_string(),
+                github_user_id: 1,
+            },
+        )
+        .await
+        .unwrap()
+        .user_id;
+
+    const TRIAL_FLAG: &str = "trial-feature";
+    const PERMANENT_FLAG: &str = "permanent-feature";
+
+    let trial_flag = db.create_user_flag(TRIAL_FLAG, false, false).await.unwrap();
+    let permanent_flag = db
+        .create_user_flag(PERMANENT_FLAG, false, false)
+        .await
+        .unwrap();
+
+    let now = Utc::now().naive_utc();
+    db.add_user_flag(user, trial_flag, Some(now + Duration::days(30)), None)
+        .await
+        .unwrap();
+    db.add_user_flag(user, permanent_flag, None, None)
+        .await
+        .unwrap();
+
+    let mut flags = db.get_user_flags(user).await.unwrap();
+    flags.sort();
+    assert_eq!(flags, &[PERMANENT_FLAG, TRIAL_FLAG]);
+
+    // Expiry is checked against the time the caller passes in.
+    let later = now + Duration::days(31);
+    let flags = db
+        .transaction(|tx| async move { db.user_flags_with_sources(user, later, &tx).await })
+        .await
+        .unwrap();
+    assert_eq!(flag_names(flags), [PERMANENT_FLAG]);
+
+    // Simulate the trial ending by moving its expiration into the past.
+    db.remove_user_flag(user, trial_flag, None).await.unwrap();
+    db.add_user_flag(user, trial_flag, Some(now - Duration::seconds(1)), None)
+        .await
+        .unwrap();
+
+    assert_eq!(db.get_user_flags(user).await.unwrap(), &[PERMANENT_FLAG]);
+    let user_counts = db
+        .list_feature_flags()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|flag| (flag.flag, flag.user_count))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        user_counts,
+        &[(TRIAL_FLAG.to_string(), 1), (PERMANENT_FLAG.to_string(), 1)]
+    );
+
+    db.purge_expired_user_flags().await.unwrap();
+    assert_eq!(db.get_user_flags(user).await.unwrap(), &[PERMANENT_FLAG]);
+    let user_counts = db
+        .list_feature_flags()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|flag| (flag.flag, flag.user_count))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        user_counts,
+        &[(TRIAL_FLAG.to_string(), 0), (PERMANENT_FLAG.to_string(), 1)]
+    );
+}
+
+test_both_dbs!(
+    test_flag_audit_log,
+    test_flag_audit_log_postgres,
+    test_flag_audit_log_sqlite
+);
+
+async fn test_flag_audit_log(db: &Arc<Database>) {
+    let admin = db
+        .create_user(
+            "admin@example.com",
+            true,
+            NewUserParams {
+                github_login: "admin".to_string(),
+                github_user_id: 1,
+            },
+        )
+        .await
+        .unwrap()
+        .user_id;
+
+    let user = db
+        .create_user(
+            "user@example.com",
+            false,
+            NewUserParams {
+                github_login: "user".to_string(),
+                github_user_id: 2,
+            },
+        )
+        .await
+        .unwrap()
+        .user_id;
+
+    let flag = db
+        .create_user_flag("cool-feature", false, false)
+        .await
+        .unwrap();
+
+    db.add_user_flag(user, flag, None, Some(admin))
+        .await
+        .unwrap();
+    // Granting a flag the user already has fails, and must not leave an audit entry behind.
+    assert!(db
+        .add_user_flag(user, flag, None, Some(admin))
+        .await
+        .is_err());
+    db.remove_user_flag(user, flag, Some(admin)).await.unwrap();
+    // Revoking a flag the user doesn't have is a no-op.
+    db.remove_user_flag(user, flag, Some(admin)).await.unwrap();
+
+    let log = db
+        .get_flag_audit_log(flag, 10, None)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|entry| (entry.user_id, entry.action, entry.actor_id))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        log,
+        &[
+            (user, FeatureFlagAuditAction::Revoked, Some(admin)),
+            (user, FeatureFlagAuditAction::Granted, Some(admin)),
+        ]
+    );
+
+    let first_page = db.get_flag_audit_log(flag, 1, None).await.unwrap();
+    assert_eq!(first_page.len(), 1);
+    assert_eq!(first_page[0].action, FeatureFlagAuditAction::Revoked);
+    let second_page = db
+        .get_flag_audit_log(flag, 1, Some(first_page[0].id))
+        .await
+        .unwrap();
+    assert_eq!(second_page.len(), 1);
+    assert_eq!(second_page[0].action, FeatureFlagAuditAction::Granted);
+    let third_page = db
+        .get_flag_audit_log(flag, 1, Some(second_page[0].id))
+        .await
+        .unwrap();
+    assert!(third_page.is_empty());
+}
+
+test_both_dbs!(
+    test_flag_audit_log_outlives_deletions,
+    test_flag_audit_log_outlives_deletions_postgres,
+    test_flag_audit_log_outlives_deletions_sqlite
+);
+
+async fn test_flag_audit_log_outlives_deletions(db: &Arc<Database>) {
+    let mut users = Vec::new();
+    for i in 0..3 {
+        let user = db
+            .create_user(
+                &format!("user{i}@example.com"),
+                false,
+                NewUserParams {
+                    github_login: format!("user{i}"),
+                    github_user_id: i,
+                },
+            )
+            .await
+            .unwrap()
+            .user_id;
+        users.push(user);
+    }
+    let (expired_user, deleted_user, user) = (users[0], users[1], users[2]);
+    let flag = db
+        .create_user_flag("cool-feature", false, false)
+        .await
+        .unwrap();
+
+    db.add_user_flag(
+        expired_user,
+        flag,
+        Some(Utc::now().naive_utc() - Duration::days(1)),
+        None,
+    )
+    .await
+    .unwrap();
+    db.add_user_flag(deleted_user, flag, None, None)
+        .await
+        .unwrap();
+    db.add_user_flag(user, flag, None, None).await.unwrap();
+
+    // Grants that are removed along the way are recorded as revoked.
+    db.purge_expired_user_flags().await.unwrap();
+    db.destroy_user(deleted_user).await.unwrap();
+    db.delete_feature_flag(flag).await.unwrap();
+
+    let log = db.get_flag_audit_log(flag, 10, None).await.unwrap();
+    assert!(log.iter().all(|entry| entry.flag == "cool-feature"));
+    assert_eq!(
+        log.into_iter()
+            .map(|entry| (entry.user_id, entry.action))
+            .collect::<Vec<_>>(),
+        &[
+            (user, FeatureFlagAuditAction::Revoked),
+            (deleted_user, FeatureFlagAuditAction::Revoked),
+            (expired_user, FeatureFlagAuditAction::Revoked),
+            (user, FeatureFlagAuditAction::Granted),
+            (deleted_user, FeatureFlagAuditAction::Granted),
+            (expired_user, FeatureFlagAuditAction::Granted),
+        ]
+    );
+}
+
+test_both_dbs!(
+    test_bulk_user_flags,
+    test_bulk_user_flags_postgres,
+    test_bulk_user_flags_sqlite
+);
+
+async fn test_bulk_user_flags(db: &Arc<Database>) {
+    let mut user_ids = Vec::new();
+    for (i, (github_login, admin)) in [
+        ("acme-alice", false),
+        ("acme-bob", true),
+        ("acme-carol", false),
+        ("dave", false),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let user_id = db
+            .create_user(
+                &format!("{github_login}@example.com"),
+                admin,
+                NewUserParams {
+                    github_login: github_login.to_string(),
+                    github_user_id: i as i32,
+                },
+            )
+            .await
+            .unwrap()
+            .user_id;
+        user_ids.push(user_id);
+    }
+    let [alice, bob, carol, dave] = user_ids[..] else {
+        unreachable!()
+    };
+
+    let flag = db
+        .create_user_flag("cool-feature", false, false)
+        .await
+        .unwrap();
+    db.add_user_flag(alice, flag, None, None).await.unwrap();
+
+    let acme_users = UserFilter {
+        github_login_prefix: Some("acme-".to_string()),
+        ..Default::default()
+    };
+    // Alice already has the flag, so it's only newly granted to Bob and Carol.
+    assert_eq!(
+        db.add_flag_to_users_matching(flag, &acme_users, Some(dave))
+            .await
+            .unwrap(),
+        &[bob, carol]
+    );
+    assert_eq!(
+        db.add_flag_to_users_matching(flag, &acme_users, Some(dave))
+            .await
+            .unwrap(),
+        Vec::<UserId>::new()
+    );
+    for user in [alice, bob, carol] {
+        assert_eq!(db.get_user_flags(user).await.unwrap(), &["cool-feature"]);
+    }
+    assert!(db.get_user_flags(dave).await.unwrap().is_empty());
+
+    let mut granted = db
+        .get_flag_audit_log(flag, 10, None)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|entry| entry.actor_id == Some(dave))
+        .map(|entry| (entry.user_id, entry.action))
+        .collect::<Vec<_>>();
+    granted.sort_by_key(|(user_id, _)| *user_id);
+    assert_eq!(
+        granted,
+        &[
+            (bob, FeatureFlagAuditAction::Granted),
+            (carol, FeatureFlagAuditAction::Granted),
+        ]
+    );
+
+    let acme_staff = UserFilter {
+        is_staff: Some(true),
+        ..acme_users.clone()
+    };
+    assert_eq!(
+        db.remove_flag_from_users_matching(flag, &acme_staff, None)
+            .await
+            .unwrap(),
+        &[bob]
+    );
+    assert!(db.get_user_flags(bob).await.unwrap().is_empty());
+    assert_eq!(db.get_user_flags(alice).await.unwrap(), &["cool-feature"]);
+    assert_eq!(
+        db.remove_flag_from_users_matching(flag, &acme_staff, None)
+            .await
+            .unwrap(),
+        Vec::<UserId>::new()
+    );
+}
+
+test_both_dbs!(
+    test_dump_flag_assignments,
+    test_dump_flag_assignments_postgres,
+    test_dump_flag_assignments_sqlite
+);
+
+async fn test_dump_flag_assignments(db: &Arc<Database>) {
+    let mut user_ids = Vec::new();
+    for i in 0..5 {
+        let user_id = db
+            .create_user(
+                &format!("user{i}@example.com"),
+                false,
+                NewUserParams {
+                    github_login: format!("user{i}"),
+                    github_user_id: i,
+                },
+            )
+            .await
+            .unwrap()
+            .user_id;
+        user_ids.push(user_id);
+    }
+    let [user_0, user_1, user_2, user_3, user_4] = user_ids[..] else {
+        unreachable!()
+    };
+
+    let flag_1 = db
+        .create_user_flag("feature-1", false, false)
+        .await
+        .unwrap();
+    let flag_2 = db
+        .create_user_flag("feature-2", false, false)
+        .await
+        .unwrap();
+    db.set_flag_rollout(flag_2, 25).await.unwrap();
+    for user in [user_1, user_2, user_3] {
+        db.add_user_flag(user, flag_1, None, None).await.unwrap();
+    }
+    let expires_at = (Utc::now() + Duration::days(1)).naive_utc();
+    db.add_user_flag(user_2, flag_2, Some(expires_at), None)
+        .await
+        .unwrap();
+
+    let first_page = db.dump_flag_assignments(None, 2).await.unwrap();
+    assert_eq!(
+        first_page
+            .iter()
+            .map(|assignment| (assignment.flag.as_str(), assignment.github_login.as_str()))
+            .collect::<Vec<_>>(),
+        [("feature-1", "user1"), ("feature-1", "user2")]
+    );
+
+    // Assignments made between pages only show up if they sort after the cursor.
+    db.add_user_flag(user_0, flag_1, None, None).await.unwrap();
+    db.add_user_flag(user_4, flag_1, None, None).await.unwrap();
+
+    let mut assignments = first_page;
+    loop {
+        let last = assignments.last().unwrap();
+        let page = db
+            .dump_flag_assignments(Some((last.flag_id, last.user_id)), 2)
+            .await
+            .unwrap();
+        if page.is_empty() {
+            break;
+        }
+        assignments.extend(page);
+    }
+    assert_eq!(
+        assignments
+            .iter()
+            .map(|assignment| (assignment.flag_id, assignment.user_id))
+            .collect::<Vec<_>>(),
+        [
+            (flag_1, user_1),
+            (flag_1, user_2),
+            (flag_1, user_3),
+            (flag_1, user_4),
+            (flag_2, user_2),
+        ]
+    );
+
+    let last = assignments.last().unwrap();
+    assert_eq!(last.flag, "feature-2");
+    assert_eq!(last.rollout_percentage, 25);
+    assert!(last.expires_at.is_some());
+}
+
+test_both_dbs!(
+    test_flag_dependencies,
+    test_flag_dependencies_postgres,
+    test_flag_dependencies_sqlite
+);
+
+async fn test_flag_dependencies(db: &Arc<Database>) {
+    let user_1 = db
+        .create_user(
+            "user1@example.com",
+            false,
+            NewUserParams {
+                github_login: "user1".to_string(),
+                github_user_id: 1,
+            },
+        )
+        .await
+        .unwrap()
+        .user_id;
+    let user_2 = db
+        .create_user(
+            "user2@example.com",
+            false,
+            NewUserParams {
+                github_login: "user2".to_string(),
+                github_user_id: 2,
+            },
+        )
+        .await
+        .unwrap()
+        .user_id;
+
+    let base = db.create_user_flag("base", false, false).await.unwrap();
+    let middle = db.create_user_flag("middle", true, false).await.unwrap();
+    let top = db.create_user_flag("top", false, false).await.unwrap();
+    db.set_flag_dependency(middle, Some(base)).await.unwrap();
+    db.set_flag_dependency(top, Some(middle)).await.unwrap();
+    db.set_flag_rollout(top, 100).await.unwrap();
+    db.add_user_flag(user_1, base, None, None).await.unwrap();
+
+    // Each flag is only active while the whole chain beneath it is.
+    assert_eq!(
+        db.get_user_flags(user_1).await.unwrap(),
+        &["base", "middle", "top"]
+    );
+    assert_eq!(
+        db.get_user_flags(user_2).await.unwrap(),
+        Vec::<String>::new()
+    );
+
+    db.set_flag_dependency(middle, None).await.unwrap();
+    assert_eq!(db.get_user_flags(user_2).await.unwrap(), &["middle", "top"]);
+    db.set_flag_dependency(middle, Some(base)).await.unwrap();
+
+    assert!(db.set_flag_dependency(base, Some(top)).await.is_err());
+    assert!(db.set_flag_dependency(base, Some(base)).await.is_err());
+
+    let error = db.delete_feature_flag(base).await.unwrap_err();
+    assert!(error.to_string().contains("middle"));
+    db.delete_feature_flag(top).await.unwrap();
+    db.set_flag_dependency(middle, None).await.unwrap();
+    db.delete_feature_flag(base).await.unwrap();
+    assert_eq!(db.get_user_flags(user_1).await.unwrap(), &["middle"]);
+}
+
+test_both_dbs!(
+    test_staff_only_flags,
+    test_staff_only_flags_postgres,
+    test_staff_only_flags_sqlite
+);
+
+async fn test_staff_only_flags(db: &Arc<Database>) {
+    let staff = db
+        .create_user(
+            "staff@example.com",
+            true,
+            NewUserParams {
+                github_login: "staff".to_string(),
+                github_user_id: 1,
+            },
+        )
+        .await
+        .unwrap()
+        .user_id;
+    let user = db
+        .create_user(
+            "user@example.com",
+            false,
+            NewUserParams {
+                github_login: "user".to_string(),
+                github_user_id: 2,
+            },
+        )
+        .await
+        .unwrap()
+        .user_id;
+
+    let flag = db
+        .create_user_flag("staff-feature", false, true)
+        .await
+        .unwrap();
+
+    // Staff have the flag without it being granted to them.
+    let flags = db.get_user_flags_with_version(staff).await.unwrap().flags;
+    assert_eq!(
+        flags,
+        [UserFlag {
+            flag: "staff-feature".to_string(),
+            source: UserFlagSource::Staff,
+            minimum_client_version: None,
+        }]
+    );
+    assert_eq!(db.get_user_flags(user).await.unwrap(), Vec::<String>::new());
+    assert_eq!(db.get_flag_users(flag).await.unwrap(), [staff]);
+
+    // The flag can still be granted to other users.
+    db.add_user_flag(user, flag, None, None).await.unwrap();
+    assert_eq!(db.get_user_flags(user).await.unwrap(), ["staff-feature"]);
+    assert_eq!(db.get_flag_users(flag).await.unwrap(), [staff, user]);
+
+    // Demoted users lose the flag.
+    db.set_user_is_staff(staff, false).await.unwrap();
+    assert_eq!(
+        db.get_user_flags(staff).await.unwrap(),
+        Vec::<String>::new()
+    );
+    assert_eq!(db.get_flag_users(flag).await.unwrap(), [user]);
+
+    // Turning off staff-only keeps explicit grants.
+    db.set_user_is_staff(staff, true).await.unwrap();
+    db.set_flag_staff_only(flag, false).await.unwrap();
+    assert_eq!(
+        db.get_user_flags(staff).await.unwrap(),
+        Vec::<String>::new()
+    );
+    assert_eq!(db.get_user_flags(user).await.unwrap(), ["staff-feature"]);
+    assert_eq!(db.get_flag_users(flag).await.unwrap(), [user]);
+
+    // Deleting a staff-only flag changes the flags of all staff.
+    db.set_flag_staff_only(flag, true).await.unwrap();
+    assert_eq!(
+        db.delete_feature_flag(flag).await.unwrap(),
+        FlagInvalidation::Users(vec![staff, user])
+    );
+
+    // Deleting a flag that's enabled for all changes everyone's flags.
+    let flag = db
+        .create_user_flag("everyone-feature", true, false)
+        .await
+        .unwrap();
+    assert_eq!(
+        db.delete_feature_flag(flag).await.unwrap(),
+        FlagInvalidation::AllUsers
+    );
+}
+
+test_both_dbs!(
+    test_typed_flag_values,
+    test_typed_flag_values_postgres,
+    test_typed_flag_values_sqlite
+);
+
+async fn test_typed_flag_values(db: &Arc<Database>) {
+    let mut users = Vec::new();
+    for i in 0..2 {
+        let user = db
+            .create_user(
+                &format!("user{i}@example.com"),
+                false,
+                NewUserParams {
+                    github_login: format!("user{i}"),
+                    github_user_id: i,
+                },
+            )
+            .await
+            .unwrap()
+            .user_id;
+        users.push(user);
+    }
+    let (user_1, user_2) = (users[0], users[1]);
+
+    let provider = db
+        .create_user_flag_with_default(
+            "completion-provider-default",
+            FlagValue::String("anthropic".into()),
+        )
+        .await
+        .unwrap();
+    let retries = db
+        .create_user_flag_with_default("max-retries", FlagValue::Int(3))
+        .await
+        .unwrap();
+    let everyone = db
+        .create_user_flag_with_default("everyone-feature", FlagValue::Bool(true))
+        .await
+        .unwrap();
+    db.create_user_flag("granted-feature", false, false)
+        .await
+        .unwrap();
+
+    // Users without an override get the default value.
+    let default_values = vec![
+        (
+            "completion-provider-default".to_string(),
+            FlagValue::String("anthropic".into()),
+        ),
+        ("everyone-feature".to_string(), FlagValue::Bool(true)),
+        ("max-retries".to_string(), FlagValue::Int(3)),
+    ];
+    assert_eq!(
+        db.get_user_flag_values(user_1).await.unwrap(),
+        default_values
+    );
+
+    // Overrides only apply to the user they were set for.
+    db.set_user_flag_value(
+        user_1,
+        provider,
+        Some(FlagValue::String("openai".into())),
+        None,
+    )
+    .await
+    .unwrap();
+    db.set_user_flag_value(user_1, retries, Some(FlagValue::Int(5)), None)
+        .await
+        .unwrap();
+    assert_eq!(
+        db.get_user_flag_values(user_1).await.unwrap(),
+        [
+            (
+                "completion-provider-default".to_string(),
+                FlagValue::String("openai".into())
+            ),
+            ("everyone-feature".to_string(), FlagValue::Bool(true)),
+            ("max-retries".to_string(), FlagValue::Int(5)),
+        ]
+    );
+    assert_eq!(
+        db.get_user_flag_values(user_2).await.unwrap(),
+        default_values
+    );
+
+    // Non-boolean flags aren't reported as active flags, even when they're granted.
+    assert_eq!(
+        db.get_user_flags(user_1).await.unwrap(),
+        ["everyone-feature"]
+    );
+
+    // Clearing an override falls back to the default value.
+    db.set_user_flag_value(user_1, retries, None, None)
+        .await
+        .unwrap();
+    assert_eq!(
+        db.get_user_flag_values(user_1).await.unwrap()[2],
+        ("max-retries".to_string(), FlagValue::Int(3))
+    );
+
+    // Overrides must match the flag's type.
+    assert!(db
+        .set_user_flag_value(user_2, retries, Some(FlagValue::String("5".into())), None)
+        .await
+        .is_err());
+    assert!(db
+        .set_user_flag_value(user_2, provider, Some(FlagValue::Int(1)), None)
+        .await
+        .is_err());
+    assert!(db
+        .set_user_flag_value(user_2, everyone, Some(FlagValue::Bool(false)), None)
+        .await
+        .is_err());
+    assert_eq!(
+        db.get_user_flag_values(user_2).await.unwrap(),
+        default_values
+    );
+
+    let flags = db.list_feature_flags().await.unwrap();
+    assert_eq!(flags[0].value_type, feature_flag::FlagValueType::String);
+    assert_eq!(flags[0].default_value.as_deref(), Some("anthropic"));
+    assert_eq!(flags[0].user_count, 1);
+}
+
+test_both_dbs!(
+    test_cached_user_flags,
+    test_cached_user_flags_postgres,
+    test_cached_user_flags_sqlite
+);
+
+async fn test_cached_user_flags(db: &Arc<Database>) {
+    let mut users = Vec::new();
+    for i in 0..2 {
+        let user = db
+            .create_user(
+                &format!("user{i}@example.com"),
+                false,
+                NewUserParams {
+                    github_login: format!("user{i}"),
+                    github_user_id: i,
+                },
+            )
+            .await
+            .unwrap()
+            .user_id;
+        users.push(user);
+    }
+    let (user_1, user_2) = (users[0], users[1]);
+    let flag_1 = db.create_user_flag("flag-1", false, false).await.unwrap();
+    let flag_2 = db.create_user_flag("flag-2", false, false).await.unwrap();
+    db.add_user_flag(user_1, flag_1, None, None).await.unwrap();
+    let other_node = db.another_node();
+
+    // The user's second connection within the TTL is served from the cache, without querying
+    // the database.
+    assert_eq!(
+        flag_names(db.get_user_flags_cached(user_1).await.unwrap()),
+        ["flag-1"]
+    );
+    let query_count = db.query_count();
+    assert_eq!(
+        flag_names(db.get_user_flags_cached(user_1).await.unwrap()),
+        ["flag-1"]
+    );
+    assert_eq!(db.query_count(), query_count);
+    assert_eq!(
+        flag_names(other_node.get_user_flags_cached(user_1).await.unwrap()),
+        ["flag-1"]
+    );
+
+    // Changes that don't go through the flag mutations aren't noticed, even after polling.
+    delete_user_features_behind_cache(db).await;
+    db.poll_flag_cache_versions().await.unwrap();
+    let query_count = db.query_count();
+    assert_eq!(
+        flag_names(db.get_user_flags_cached(user_1).await.unwrap()),
+        ["flag-1"]
+    );
+    assert_eq!(db.query_count(), query_count);
+
+    // The admin endpoints bypass the cache.
+    assert!(db.get_user_flags(user_1).await.unwrap().is_empty());
+
+    // A grant is seen right away by the node that made it, and by other nodes once they poll.
+    db.add_user_flag(user_1, flag_2, None, None).await.unwrap();
+    assert_eq!(
+        flag_names(db.get_user_flags_cached(user_1).await.unwrap()),
+        ["flag-2"]
+    );
+    assert_eq!(
+        flag_names(other_node.get_user_flags_cached(user_1).await.unwrap()),
+        ["flag-1"]
+    );
+    other_node.poll_flag_cache_versions().await.unwrap();
+    assert_eq!(
+        flag_names(other_node.get_user_flags_cached(user_1).await.unwrap()),
+        ["flag-2"]
+    );
+
+    // As is a change affecting all users.
+    other_node
+        .create_user_flag("flag-3", true, false)
+        .await
+        .unwrap();
+    assert_eq!(
+        flag_names(other_node.get_user_flags_cached(user_1).await.unwrap()),
+        ["flag-2", "flag-3"]
+    );
+    assert_eq!(
+        flag_names(db.get_user_flags_cached(user_1).await.unwrap()),
+        ["flag-2"]
+    );
+    db.poll_flag_cache_versions().await.unwrap();
+    assert_eq!(
+        flag_names(db.get_user_flags_cached(user_1).await.unwrap()),
+        ["flag-2", "flag-3"]
+    );
+
+    // The cache only holds the most recently used users.
+    db.add_user_flag(user_2, flag_1, None, None).await.unwrap();
+    let cache = UserFlagCache::new(1, std::time::Duration::from_secs(60));
+    assert_eq!(
+        flag_names(db.get_user_flags_through(&cache, user_1).await.unwrap()),
+        ["flag-2", "flag-3"]
+    );
+    assert_eq!(
+        flag_names(db.get_user_flags_through(&cache, user_2).await.unwrap()),
+        ["flag-1", "flag-3"]
+    );
+    delete_user_features_behind_cache(db).await;
+    assert_eq!(
+        flag_names(db.get_user_flags_through(&cache, user_2).await.unwrap()),
+        ["flag-1", "flag-3"]
+    );
+    assert_eq!(
+        flag_names(db.get_user_flags_through(&cache, user_1).await.unwrap()),
+        ["flag-3"]
+    );
+}
+
+/// Revokes every flag grant without invalidating any cached flags.
+async fn delete_user_features_behind_cache(db: &Database) {
+    db.transaction(|tx| async move {
+        user_feature::Entity::delete_many().exec(&*tx).await?;
+        Ok(())
+    })
+    .await
+    .unwrap();
+}
+
+fn flag_names(flags: Vec<UserFlag>) -> Vec<String> {
+    flags.into_iter().map(|flag| flag.flag).collect()
+}
+
+test_both_dbs!(
+    test_flag_minimum_client_version,
+    test_flag_minimum_client_version_postgres,
+    test_flag_minimum_client_version_sqlite
+);
+
+async fn test_flag_minimum_client_version(db: &Arc<Database>) {
+    let user = db
+        .create_user(
+            "user@example.com",
+            false,
+            NewUserParams {
+                github_login: "user".to_string(),
+                github_user_id: 1,
+            },
+        )
+        .await
+        .unwrap()
+        .user_id;
+    let old_flag = db
+        .create_user_flag("old-feature", true, false)
+        .await
+        .unwrap();
+    let new_flag = db
+        .create_user_flag("new-feature", true, false)
+        .await
+        .unwrap();
+
+    assert!(db
+        .set_flag_minimum_client_version(new_flag, Some("0.150"))
+        .await
+        .is_err());
+    assert!(db
+        .set_flag_minimum_client_version(new_flag, Some("latest"))
+        .await
+        .is_err());
+    db.set_flag_minimum_client_version(new_flag, Some("0.150.0"))
+        .await
+        .unwrap();
+    let flags = db.get_user_flags_cached(user).await.unwrap();
+    assert_eq!(
+        flags_for_client_version(&flags, SemanticVersion::new(0, 149, 2)),
+        ["old-feature"]
+    );
+    assert_eq!(
+        flags_for_client_version(&flags, SemanticVersion::new(0, 150, 0)),
+        ["new-feature", "old-feature"]
+    );
+
+    db.set_flag_minimum_client_version(new_flag, None)
+        .await
+        .unwrap();
+    db.set_flag_minimum_client_version(old_flag, Some("1.0.0"))
+        .await
+        .unwrap();
+    let flags = db.get_user_flags_cached(user).await.unwrap();
+    assert_eq!(
+        flags_for_client_version(&flags, SemanticVersion::new(0, 149, 2)),
+        ["new-feature"]
+    );
+
+    // Flags whose minimum version can't be parsed are omitted for every client.
+    let flag = UserFlag {
+        flag: "broken-feature".into(),
+        source: UserFlagSource::EnabledForAll,
+        minimum_client_version: Some("not a version".into()),
+    };
+    assert!(flags_for_client_version(&[flag], SemanticVersion::new(9, 9, 9)).is_empty());
+}
+
+test_both_dbs!(
+    test_flag_schedule,
+    test_flag_schedule_postgres,
+    test_flag_schedule_sqlite
+);
+
+async fn test_flag_schedule(db: &Arc<Database>) {
+    let user = db
+        .create_user(
+            "user@example.com",
+            false,
+            NewUserParams {
+                github_login: "user".to_string(),
+                github_user_id: 1,
+            },
+        )
+        .await
+        .unwrap()
+        .user_id;
+    let flag = db
+        .create_user_flag("scheduled-feature", false, false)
+        .await
+        .unwrap();
+    let dependent_flag = db
+        .create_user_flag("dependent-feature", true, false)
+        .await
+        .unwrap();
+    db.set_flag_dependency(dependent_flag, Some(flag))
+        .await
+        .unwrap();
+    db.add_user_flag(user, flag, None, None).await.unwrap();
+
+    let activate_at = NaiveDate::from_ymd_opt(2024, 9, 1)
+        .unwrap()
+        .and_hms_opt(12, 0, 0)
+        .unwrap();
+    let deactivate_at = activate_at + Duration::days(7);
+
+    // The window must open before it closes.
+    assert!(db
+        .set_flag_schedule(flag, Some(deactivate_at), Some(activate_at))
+        .await
+        .is_err());
+    assert!(db
+        .set_flag_schedule(flag, Some(activate_at), Some(activate_at))
+        .await
+        .is_err());
+    db.set_flag_schedule(flag, Some(activate_at), Some(deactivate_at))
+        .await
+        .unwrap();
+
+    // Outside of the window, the flag is inactive despite being granted, and so are the flags
+    // that depend on it.
+    let before = activate_at - Duration::seconds(1);
+    assert_eq!(
+        db.get_user_flags_at(user, before).await.unwrap(),
+        Vec::<String>::new()
+    );
+    assert_eq!(
+        db.get_user_flags_at(user, activate_at).await.unwrap(),
+        ["dependent-feature", "scheduled-feature"]
+    );
+    assert_eq!(
+        db.get_user_flags_at(user, deactivate_at - Duration::seconds(1))
+            .await
+            .unwrap(),
+        ["dependent-feature", "scheduled-feature"]
+    );
+    assert_eq!(
+        db.get_user_flags_at(user, deactivate_at).await.unwrap(),
+        Vec::<String>::new()
+    );
+
+    // Schedule boundaries are reported once they've passed.
+    assert!(!db
+        .flag_schedules_changed_between(before - Duration::days(1), before)
+        .await
+        .unwrap());
+    assert!(db
+        .flag_schedules_changed_between(before, activate_at)
+        .await
+        .unwrap());
+    assert!(!db
+        .flag_schedules_changed_between(activate_at, deactivate_at - Duration::seconds(1))
+        .await
+        .unwrap());
+    assert!(db
+        .flag_schedules_changed_between(deactivate_at - Duration::seconds(1), deactivate_at)
+        .await
+        .unwrap());
+
+    // Either side of the window can be left open.
+    db.set_flag_schedule(flag, None, Some(deactivate_at))
+        .await
+        .unwrap();
+    assert_eq!(
+        db.get_user_flags_at(user, before).await.unwrap(),
+        ["dependent-feature", "scheduled-feature"]
+    );
+    db.set_flag_schedule(flag, None, None).await.unwrap();
+    assert_eq!(
+        db.get_user_flags_at(user, deactivate_at).await.unwrap(),
+        ["dependent-feature", "scheduled-feature"]
+    );
+    assert_eq!(
+        db.get_user_flags(user).await.unwrap(),
+        ["dependent-feature", "scheduled-feature"]
+    );
+}
+
+test_both_dbs!(
+    test_flag_stats,
+    test_flag_stats_postgres,
+    test_flag_stats_sqlite
+);
+
+async fn test_flag_stats(db: &Arc<Database>) {
+    let served_flag = db
+        .create_user_flag("served-feature", false, false)
+        .await
+        .unwrap();
+    let other_flag = db
+        .create_user_flag("other-feature", false, false)
+        .await
+        .unwrap();
+    let unserved_flag = db
+        .create_user_flag("unserved-feature", false, false)
+        .await
+        .unwrap();
+    let today = Utc::now().date_naive();
+
+    for _ in 0..3 {
+        db.record_flags_served(["served-feature", "other-feature"]);
+    }
+    db.record_flags_served(["served-feature"]);
+    db.flush_flag_stats().await.unwrap();
+    assert_eq!(served_counts(db, served_flag, today).await, [(today, 4)]);
+    assert_eq!(served_counts(db, other_flag, today).await, [(today, 3)]);
+
+    // Later flushes add to the day's counts, and counts for unknown flags are dropped.
+    db.record_flags_served(["served-feature", "deleted-feature"]);
+    db.flush_flag_stats().await.unwrap();
+    db.flush_flag_stats().await.unwrap();
+    assert_eq!(served_counts(db, served_flag, today).await, [(today, 5)]);
+    assert_eq!(served_counts(db, other_flag, today).await, [(today, 3)]);
+    assert!(served_counts(db, unserved_flag, today).await.is_empty());
+    assert!(served_counts(db, served_flag, today + Duration::days(1))
+        .await
+        .is_empty());
+
+    let last_served_on = db
+        .list_feature_flags()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|flag| (flag.flag, flag.last_served_on))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        last_served_on,
+        &[
+            ("served-feature".to_string(), Some(today)),
+            ("other-feature".to_string(), Some(today)),
+            ("unserved-feature".to_string(), None),
+        ]
+    );
+}
+
+async fn served_counts(
+    db: &Arc<Database>,
+    flag: FlagId,
+    since: NaiveDate,
+) -> Vec<(NaiveDate, i64)> {
+    db.get_flag_stats(flag, since)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|stats| (stats.date, stats.served_count))
+        .collect()
+}
+
+#[gpui::test]
+async fn test_flag_reads_use_read_replica(cx: &mut gpui::TestAppContext) {
+    let test_db = TestDb::sqlite_with_read_replica(cx.executor().clone());
+    let db = test_db.db();
+    let user = db
+        .create_user(
+            "user@example.com",
+            false,
+            NewUserParams {
+                github_login: "user".to_string(),
+                github_user_id: 1,
+            },
+        )
+        .await
+        .unwrap()
+        .user_id;
+    db.create_user_flag("everyone-feature", true, false)
+        .await
+        .unwrap();
+
+    // The replica never receives the flag, so reads routed to it don't see it.
+    assert!(db.list_feature_flags().await.unwrap().is_empty());
+    assert_eq!(db.get_user_flags(user).await.unwrap(), Vec::<String>::new());
+    assert!(db
+        .get_user_flags_with_version(user)
+        .await
+        .unwrap()
+        .flags
+        .is_empty());
+    assert!(db.get_user_flag_values(user).await.unwrap().is_empty());
+
+    // Cached flags are read from the primary while the replica lags behind its flag versions.
+    assert_eq!(
+        flag_names(db.get_user_flags_cached(user).await.unwrap()),
+        ["everyone-feature"]
+    );
+
+    // Once the replica has caught up with the primary's flag versions, cached flags, such as
+    // the ones `get_private_user_info` serves, are read from it. The replica is given a flag
+    // the primary doesn't have, to show where the flags were read from.
+    let read_replica = db.read_replica.clone().unwrap();
+    db.runtime.as_ref().unwrap().block_on(async {
+        flag_cache_version::Entity::insert(flag_cache_version::ActiveModel {
+            user_id: ActiveValue::set(flag_cache_version::ALL_USERS),
+            version: ActiveValue::set(1),
+        })
+        .exec(&read_replica)
+        .await
+        .unwrap();
+        feature_flag::Entity::insert(feature_flag::ActiveModel {
+            flag: ActiveValue::set("replica-feature".to_string()),
+            enabled_for_all: ActiveValue::set(true),
+            ..Default::default()
+        })
+        .exec(&read_replica)
+        .await
+        .unwrap();
+    });
+    let cache = UserFlagCache::new(1, std::time::Duration::from_secs(60));
+    assert_eq!(
+        flag_names(db.get_user_flags_through(&cache, user).await.unwrap()),
+        ["replica-feature"]
+    );
+
+    // Reads in the transaction that granted a flag see the grant.
+    let granted_flag = db
+        .create_user_flag("granted-feature", false, false)
+        .await
+        .unwrap();
+    let flags = db
+        .transaction(|tx| async move {
+            user_feature::Entity::insert(user_feature::ActiveModel {
+                user_id: ActiveValue::set(user),
+                feature_id: ActiveValue::set(granted_flag),
+                ..Default::default()
+            })
+            .exec(&*tx)
+            .await?;
+            db.user_flags_with_sources(user, Utc::now().naive_utc(), &tx)
+                .await
+        })
+        .await
+        .unwrap();
+    assert_eq!(flag_names(flags), ["everyone-feature", "granted-feature"]);
+
+    // Reads fall back to the primary once the replica can't be reached.
+    db.runtime
+        .as_ref()
+        .unwrap()
+        .block_on(read_replica.close())
+        .unwrap();
+    assert_eq!(db.list_feature_flags().await.unwrap().len(), 2);
+    assert_eq!(
+        db.get_user_flags(user).await.unwrap(),
+        ["everyone-feature", "granted-feature"]
+    );
+}
+
+test_both_dbs!(
+    test_flag_opt_in,
+    test_flag_opt_in_postgres,
+    test_flag_opt_in_sqlite
+);
+
+async fn test_flag_opt_in(db: &Arc<Database>) {
+    let user = db
+        .create_user(
+            "user@example.com",
+            false,
+            NewUserParams {
+                github_login: "user".to_string(),
+                github_user_id: 1,
+            },
+        )
+        .await
+        .unwrap()
+        .user_id;
+    let beta_flag = db
+        .create_user_flag("public-beta", false, false)
+        .await
+        .unwrap();
+    db.set_flag_opt_in(beta_flag, true, Some("Try the new thing"))
+        .await
+        .unwrap();
+    let internal_flag = db
+        .create_user_flag("internal-feature", false, false)
+        .await
+        .unwrap();
+    assert_eq!(
+        db.get_opt_in_flags(user).await.unwrap(),
+        &[OptInFlag {
+            id: beta_flag,
+            flag: "public-beta".to_string(),
+            description: Some("Try the new thing".to_string()),
+            opted_in: false,
+        }]
+    );
+
+    // Opting in grants the flag, and the cached flags reflect it right away.
+    assert!(db.get_user_flags_cached(user).await.unwrap().is_empty());
+    db.set_user_flag_opt_in(user, beta_flag, true)
+        .await
+        .unwrap();
+    db.set_user_flag_opt_in(user, beta_flag, true)
+        .await
+        .unwrap();
+    assert_eq!(
+        db.get_user_flags_cached(user).await.unwrap(),
+        &[UserFlag {
+            flag: "public-beta".to_string(),
+            source: UserFlagSource::Granted,
+            minimum_client_version: None,
+        }]
+    );
+    assert!(db.get_opt_in_flags(user).await.unwrap()[0].opted_in);
+
+    // Opting out revokes it.
+    db.set_user_flag_opt_in(user, beta_flag, false)
+        .await
+        .unwrap();
+    assert!(db.get_user_flags_cached(user).await.unwrap().is_empty());
+    assert!(!db.get_opt_in_flags(user).await.unwrap()[0].opted_in);
+
+    // Each change is attributed to the user, and repeating one isn't recorded again.
+    let log = db
+        .get_flag_audit_log(beta_flag, 10, None)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|entry| (entry.user_id, entry.action, entry.actor_id))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        log,
+        &[
+            (user, FeatureFlagAuditAction::Revoked, Some(user)),
+            (user, FeatureFlagAuditAction::Granted, Some(user)),
+        ]
+    );
+
+    // Flags that aren't opt-in can't be granted this way.
+    assert!(db
+        .set_user_flag_opt_in(user, internal_flag, true)
+        .await
+        .is_err());
+    assert!(db.get_user_flags(user).await.unwrap().is_empty());
+
+    // Nor can flags that stop being opt-in.
+    db.set_flag_opt_in(beta_flag, false, None).await.unwrap();
+    assert!(db
+        .set_user_flag_opt_in(user, beta_flag, true)
+        .await
+        .is_err());
+    assert!(db.get_opt_in_flags(user).await.unwrap().is_empty());
+
+    // Reopening the flag keeps its description.
+    db.set_flag_opt_in(beta_flag, true, None).await.unwrap();
+    assert_eq!(
+        db.get_opt_in_flags(user).await.unwrap()[0]
+            .description
+            .as_deref(),
+        Some("Try the new thing")
+    );
+}
+
+test_both_dbs!(
+    test_get_users_for_flag,
+    test_get_users_for_flag_postgres,
+    test_get_users_for_flag_sqlite
+);
+
+async fn test_get_users_for_flag(db: &Arc<Database>) {
+    let mut users = Vec::new();
+    for (i, github_login) in ["carol", "Aaron", "bob", "alina", "dave", "x_ray", "maxim"]
+        .into_iter()
+        .enumerate()
+    {
+        let user_id = db
+            .create_user(
+                &format!("{github_login}@example.com"),
+                false,
+                NewUserParams {
+                    github_login: github_login.into(),
+                    github_user_id: i as i32,
+                },
+            )
+            .await
+            .unwrap()
+            .user_id;
+        users.push(user_id);
+    }
+    let dave = users[4];
+
+    let flag = db
+        .create_user_flag("listed-feature", false, false)
+        .await
+        .unwrap();
+    let other_flag = db
+        .create_user_flag("other-feature", false, false)
+        .await
+        .unwrap();
+    for user in &users {
+        if *user != dave {
+            db.add_user_flag(*user, flag, None, None).await.unwrap();
+        }
+    }
+    // Neither grants of other flags nor expired grants are listed.
+    db.add_user_flag(dave, other_flag, None, None)
+        .await
+        .unwrap();
+    db.add_user_flag(
+        dave,
+        flag,
+        Some(Utc::now().naive_utc() - Duration::days(1)),
+        None,
+    )
+    .await
+    .unwrap();
+
+    async fn page_logins(
+        db: &Database,
+        flag: FlagId,
+        query: Option<&str>,
+        page: u64,
+        page_size: u64,
+    ) -> (u64, Vec<String>) {
+        let page = db
+            .get_users_for_flag(flag, query, page, page_size)
+            .await
+            .unwrap();
+        (
+            page.total_count,
+            page.users
+                .into_iter()
+                .map(|user| user.github_login)
+                .collect(),
+        )
+    }
+
+    // Pages are ordered by login, the last one may be partial, and pages beyond it are empty.
+    assert_eq!(
+        page_logins(db, flag, None, 0, 4).await,
+        (
+            6,
+            vec!["Aaron".into(), "alina".into(), "bob".into(), "carol".into()]
+        )
+    );
+    assert_eq!(
+        page_logins(db, flag, None, 1, 4).await,
+        (6, vec!["maxim".into(), "x_ray".into()])
+    );
+    assert_eq!(page_logins(db, flag, None, 2, 4).await, (6, vec![]));
+
+    // The search is case-insensitive and matches any part of the login.
+    assert_eq!(
+        page_logins(db, flag, Some("AAR"), 0, 10).await,
+        (1, vec!["Aaron".into()])
+    );
+    assert_eq!(
+        page_logins(db, flag, Some("r"), 0, 2).await,
+        (3, vec!["Aaron".into(), "carol".into()])
+    );
+    assert_eq!(
+        page_logins(db, flag, Some("r"), 1, 2).await,
+        (3, vec!["x_ray".into()])
+    );
+    // Wildcards in the query are matched literally.
+    assert_eq!(
+        page_logins(db, flag, Some("x_"), 0, 10).await,
+        (1, vec!["x_ray".into()])
+    );
+    assert_eq!(page_logins(db, flag, Some("zed"), 0, 10).await, (0, vec![]));
+
+    // Each user is marked with whether the rollout would give them the flag without the grant.
+    db.set_flag_rollout(flag, 50).await.unwrap();
+    let page = db.get_users_for_flag(flag, None, 0, 10).await.unwrap();
+    assert_eq!(page.users.len(), 6);
+    for user in page.users {
+        assert_eq!(
+            user.in_rollout,
+            feature_flag::rollout_bucket(user.user_id, "listed-feature") < 50
+        );
+    }
+
+    assert!(db
+        .get_users_for_flag(FlagId(i32::MAX), None, 0, 10)
+        .await
+        .is_err());
+}
+
+test_both_dbs!(
+    test_destroying_user_removes_flag_assignments,
+    test_destroying_user_removes_flag_assignments_postgres,
+    test_destroying_user_removes_flag_assignments_sqlite
+);
+
+async fn test_destroying_user_removes_flag_assignments(db: &Arc<Database>) {
+    let mut users = Vec::new();
+    for (i, github_login) in ["deleted-user", "kept-user"].into_iter().enumerate() {
+        let user_id = db
+            .create_user(
+                &format!("{github_login}@example.com"),
+                false,
+                NewUserParams {
+                    github_login: github_login.into(),
+                    github_user_id: i as i32,
+                },
+            )
+            .await
+            .unwrap()
+            .user_id;
+        users.push(user_id);
+    }
+    let (deleted_user, kept_user) = (users[0], users[1]);
+
+    let flag = db
+        .create_user_flag("assigned-feature", false, false)
+        .await
+        .unwrap();
+    for user in [deleted_user, kept_user] {
+        db.add_user_flag(user, flag, None, None).await.unwrap();
+    }
+    assert_eq!(
+        db.get_flag_users(flag).await.unwrap(),
+        &[deleted_user, kept_user]
+    );
+
+    db.destroy_user(deleted_user).await.unwrap();
+
+    assert_eq!(db.get_flag_users(flag).await.unwrap(), &[kept_user]);
+    let page = db.get_users_for_flag(flag, None, 0, 10).await.unwrap();
+    assert_eq!(page.total_count, 1);
+    assert_eq!(
+        page.users
+            .into_iter()
+            .map(|user| user.user_id)
+            .collect::<Vec<_>>(),
+        &[kept_user]
+    );
+    assert_eq!(
+        db.dump_flag_assignments(None, 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|assignment| assignment.user_id)
+            .collect::<Vec<_>>(),
+        &[kept_user]
+    );
+    assert_eq!(
+        db.list_feature_flags()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|flag| (flag.flag, flag.user_count))
+            .collect::<Vec<_>>(),
+        &[("assigned-feature".to_string(), 1)]
+    );
+}
+
+test_both_dbs!(
+    test_flag_config,
+    test_flag_config_postgres,
+    test_flag_config_sqlite
+);
+
+async fn test_flag_config(db: &Arc<Database>) {
+    let user = db
+        .create_user(
+            "user@example.com",
+            false,
+            NewUserParams {
+                github_login: "user".to_string(),
+                github_user_id: 1,
+            },
+        )
+        .await
+        .unwrap()
+        .user_id;
+
+    let activate_at = NaiveDate::from_ymd_opt(2024, 1, 1)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap();
+    let beta = db.create_user_flag("beta", false, true).await.unwrap();
+    let ai = db.create_user_flag("ai", false, false).await.unwrap();
+    db.set_flag_dependency(ai, Some(beta)).await.unwrap();
+    db.set_flag_rollout(ai, 25).await.unwrap();
+    db.set_flag_schedule(ai, Some(activate_at), None)
+        .await
+        .unwrap();
+    db.set_flag_opt_in(ai, true, Some("AI features"))
+        .await
+        .unwrap();
+    db.create_user_flag_with_default("theme", FlagValue::String("dark".into()))
+        .await
+        .unwrap();
+    db.add_user_flag(user, beta, None, None).await.unwrap();
+
+    // The export is sorted by name, refers to dependencies by name, and leaves out grants.
+    let config = db.export_flag_config().await.unwrap();
+    assert_eq!(
+        config,
+        FlagConfig {
+            flags: vec![
+                FlagDefinition {
+                    flag: "ai".into(),
+                    rollout_percentage: 25,
+                    depends_on: Some("beta".into()),
+                    activate_at: Some(activate_at),
+                    opt_in: true,
+                    description: Some("AI features".into()),
+                    ..Default::default()
+                },
+                FlagDefinition {
+                    flag: "beta".into(),
+                    staff_only: true,
+                    ..Default::default()
+                },
+                FlagDefinition {
+                    flag: "theme".into(),
+                    value_type: FlagValueType::String,
+                    default_value: Some("dark".into()),
+                    ..Default::default()
+                },
+            ],
+        }
+    );
+
+    // The config round-trips through JSON, and applying it unchanged changes nothing.
+    let json = serde_json::to_string(&config).unwrap();
+    assert_eq!(serde_json::from_str::<FlagConfig>(&json).unwrap(), config);
+    assert_eq!(
+        db.apply_flag_config(&config, false).await.unwrap(),
+        FlagConfigDiff::default()
+    );
+
+    // Flags can depend on flags that are created by the same config.
+    let mut modified = config.clone();
+    modified.flags[0].rollout_percentage = 50;
+    modified.flags[0].description = Some("AI-powered features".into());
+    modified.flags.remove(2);
+    modified.flags.push(FlagDefinition {
+        flag: "new-ui".into(),
+        depends_on: Some("new-ui-base".into()),
+        ..Default::default()
+    });
+    modified.flags.push(FlagDefinition {
+        flag: "new-ui-base".into(),
+        enabled_for_all: true,
+        ..Default::default()
+    });
+    let expected_diff = FlagConfigDiff {
+        created: vec!["new-ui".into(), "new-ui-base".into()],
+        updated: vec![FlagConfigUpdate {
+            flag: "ai".into(),
+            attributes: vec!["rollout_percentage", "description"],
+        }],
+        missing_from_config: vec!["theme".into()],
+    };
+
+    // A dry run reports the changes without making them.
+    assert_eq!(
+        db.apply_flag_config(&modified, true).await.unwrap(),
+        expected_diff
+    );
+    assert_eq!(db.export_flag_config().await.unwrap(), config);
+
+    assert_eq!(
+        db.apply_flag_config(&modified, false).await.unwrap(),
+        expected_diff
+    );
+    let mut expected_config = modified.clone();
+    expected_config.flags.push(config.flags[2].clone());
+    expected_config.flags.sort_by(|a, b| a.flag.cmp(&b.flag));
+    assert_eq!(db.export_flag_config().await.unwrap(), expected_config);
+    assert_eq!(db.get_flag_users(beta).await.unwrap(), &[user]);
+    assert_eq!(
+        db.apply_flag_config(&modified, true).await.unwrap(),
+        FlagConfigDiff {
+            missing_from_config: vec!["theme".into()],
+            ..Default::default()
+        }
+    );
+
+    // Invalid configs are rejected without changing anything.
+    let mut cycle = expected_config.clone();
+    cycle.flags[1].depends_on = Some("ai".into());
+    cycle.flags[1].rollout_percentage = 10;
+    db.apply_flag_config(&cycle, false).await.unwrap_err();
+    let mut missing_dependency = expected_config.clone();
+    missing_dependency.flags[1].depends_on = Some("no-such-flag".into());
+    db.apply_flag_config(&missing_dependency, false)
+        .await
+        .unwrap_err();
+    let mut changed_type = expected_config.clone();
+    changed_type.flags[4].value_type = FlagValueType::Int;
+    changed_type.flags[4].default_value = Some("1".into());
+    db.apply_flag_config(&changed_type, false)
+        .await
+        .unwrap_err();
+    assert_eq!(db.export_flag_config().await.unwrap(), expected_config);
+}
+
+test_both_dbs!(
+    test_flag_max_users,
+    test_flag_max_users_postgres,
+    test_flag_max_users_sqlite
+);
+
+async fn test_flag_max_users(db: &Arc<Database>) {
+    let mut user_ids = Vec::new();
+    for (i, github_login) in ["acme-alice", "acme-bob", "acme-carol", "acme-dave", "erin"]
+        .into_iter()
+        .enumerate()
+    {
+        let user_id = db
+            .create_user(
+                &format!("{github_login}@example.com"),
+                false,
+                NewUserParams {
+                    github_login: github_login.to_string(),
+                    github_user_id: i as i32,
+                },
+            )
+            .await
+            .unwrap()
+            .user_id;
+        user_ids.push(user_id);
+    }
+    let [alice, bob, carol, dave, erin] = user_ids[..] else {
+        unreachable!()
+    };
+
+    // Grants that would take a flag past its cap are rejected, whichever way they're made.
+    let capped_flag = db
+        .create_user_flag("capped-feature", false, false)
+        .await
+        .unwrap();
+    db.set_flag_max_users(capped_flag, Some(-1))
+        .await
+        .unwrap_err();
+    db.set_flag_max_users(capped_flag, Some(2)).await.unwrap();
+    db.add_user_flag(alice, capped_flag, None, None)
+        .await
+        .unwrap();
+    db.add_user_flag(bob, capped_flag, None, None)
+        .await
+        .unwrap();
+    let error = db
+        .add_user_flag(carol, capped_flag, None, None)
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("past its limit of 2"), "{error}");
+    db.set_flag_opt_in(capped_flag, true, None).await.unwrap();
+    db.set_user_flag_opt_in(carol, capped_flag, true)
+        .await
+        .unwrap_err();
+    assert!(db.get_user_flags(carol).await.unwrap().is_empty());
+    assert_eq!(
+        db.get_flag_audit_log(capped_flag, 10, None)
+            .await
+            .unwrap()
+            .len(),
+        2
+    );
+
+    // A bulk grant that would take a flag past its cap grants the flag to nobody.
+    let bulk_flag = db
+        .create_user_flag("bulk-feature", false, false)
+        .await
+        .unwrap();
+    db.set_flag_max_users(bulk_flag, Some(3)).await.unwrap();
+    db.add_user_flag(alice, bulk_flag, None, None)
+        .await
+        .unwrap();
+    let acme_users = UserFilter {
+        github_login_prefix: Some("acme-".to_string()),
+        ..Default::default()
+    };
+    db.add_flag_to_users_matching(bulk_flag, &acme_users, None)
+        .await
+        .unwrap_err();
+    assert_eq!(db.get_flag_users(bulk_flag).await.unwrap(), &[alice]);
+    assert_eq!(
+        db.get_flag_audit_log(bulk_flag, 10, None)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+    db.set_flag_max_users(bulk_flag, Some(4)).await.unwrap();
+    assert_eq!(
+        db.add_flag_to_users_matching(bulk_flag, &acme_users, None)
+            .await
+            .unwrap()
+            .len(),
+        3
+    );
+
+    // Flags are over the threshold once they reach more than 90% of their cap. Rollouts are
+    // estimated against the total number of users.
+    let roomy_flag = db
+        .create_user_flag("roomy-feature", false, false)
+        .await
+        .unwrap();
+    db.set_flag_max_users(roomy_flag, Some(100)).await.unwrap();
+    db.add_user_flag(erin, roomy_flag, None, None)
+        .await
+        .unwrap();
+    let rolled_out_flag = db
+        .create_user_flag("rolled-out-feature", false, false)
+        .await
+        .unwrap();
+    db.set_flag_max_users(rolled_out_flag, Some(5))
+        .await
+        .unwrap();
+    db.set_flag_rollout(rolled_out_flag, 80).await.unwrap();
+    db.add_user_flag(dave, rolled_out_flag, None, None)
+        .await
+        .unwrap();
+    let flags_over_threshold = db
+        .get_flags_over_threshold()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|flag| (flag.flag, flag.max_users, flag.estimated_user_count))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        flags_over_threshold,
+        &[
+            ("bulk-feature".to_string(), 4, 4),
+            ("capped-feature".to_string(), 2, 2),
+        ]
+    );
+
+    // The rolled out flag reaches one user it's granted to, plus 80% of the four others.
+    db.set_flag_max_users(rolled_out_flag, Some(4))
+        .await
+        .unwrap();
+    assert_eq!(
+        db.get_flags_over_threshold()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|flag| (flag.flag, flag.estimated_user_count))
+            .collect::<Vec<_>>(),
+        &[
+            ("bulk-feature".to_string(), 4),
+            ("capped-feature".to_string(), 2),
+            ("rolled-out-feature".to_string(), 4),
+        ]
+    );
+}
@@ -0,0 +1,160 @@
+use super::{UserFlag, UserId};
+use collections::{BTreeMap, HashMap};
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+
+/// The number of users whose flags are cached before the least recently used are evicted.
+pub const USER_FLAG_CACHE_CAPACITY: usize = 10_000;
+/// How long cached flags are used for. This bounds how stale a user's flags can be when they
+/// change without an invalidation, such as when a grant expires.
+pub const USER_FLAG_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// A change to feature flags that makes cached flags stale.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlagInvalidation {
+    Users(Vec<UserId>),
+    AllUsers,
+}
+
+/// The versions of a user's flags at some point, as stored in the `flag_cache_versions` table.
+///
+/// Flag mutations bump the versions in the same transaction as the change. Each collab node
+/// polls the versions of the users it has cached, and drops the flags whose versions changed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FlagVersions {
+    /// Bumped by changes to this user's flags.
+    pub user: i64,
+    /// Bumped by changes that affect every user's flags.
+    pub all_users: i64,
+}
+
+/// A bounded cache of users' active flags.
+///
+/// Each entry remembers the [`FlagVersions`] its flags were fetched at, so that
+/// [`Self::retain_current`] can drop it once they change on any node. Changes made on this node
+/// are applied right away with [`Self::invalidate`].
+pub struct UserFlagCache {
+    state: Mutex<UserFlagCacheState>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+#[derive(Default)]
+struct UserFlagCacheState {
+    entries: HashMap<UserId, CachedUserFlags>,
+    /// The cached users, from least to most recently used.
+    recency: BTreeMap<u64, UserId>,
+    next_use: u64,
+}
+
+struct CachedUserFlags {
+    flags: Vec<UserFlag>,
+    versions: FlagVersions,
+    fetched_at: Instant,
+    last_use: u64,
+}
+
+impl UserFlagCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            state: Mutex::default(),
+            capacity,
+            ttl,
+        }
+    }
+
+    /// Returns the user's cached flags, if they were fetched within the TTL.
+    pub fn get(&self, user: UserId) -> Option<Vec<UserFlag>> {
+        let mut state = self.state.lock();
+        let entry = state.entries.get(&user)?;
+        if entry.fetched_at.elapsed() >= self.ttl {
+            state.remove(user);
+            return None;
+        }
+
+        let last_use = entry.last_use;
+        let next_use = state.next_use;
+        state.next_use += 1;
+        state.recency.remove(&last_use);
+        state.recency.insert(next_use, user);
+        let entry = state.entries.get_mut(&user).unwrap();
+        entry.last_use = next_use;
+        Some(entry.flags.clone())
+    }
+
+    /// Caches the user's flags, which were fetched after reading the given versions.
+    pub fn insert(&self, user: UserId, flags: Vec<UserFlag>, versions: FlagVersions) {
+        let mut state = self.state.lock();
+        state.remove(user);
+        while state.entries.len() >= self.capacity {
+            let Some((_, least_recently_used)) = state.recency.pop_first() else {
+                break;
+            };
+            state.entries.remove(&least_recently_used);
+        }
+
+        let last_use = state.next_use;
+        state.next_use += 1;
+        state.recency.insert(last_use, user);
+        state.entries.insert(
+            user,
+            CachedUserFlags {
+                flags,
+                versions,
+                fetched_at: Instant::now(),
+                last_use,
+            },
+        );
+    }
+
+    /// Drops the flags that the invalidation makes stale.
+    pub fn invalidate(&self, invalidation: &FlagInvalidation) {
+        let mut state = self.state.lock();
+        match invalidation {
+            FlagInvalidation::Users(users) => {
+                for user in users {
+                    state.remove(*user);
+                }
+            }
+            FlagInvalidation::AllUsers => {
+                state.entries.clear();
+                state.recency.clear();
+            }
+        }
+    }
+
+    /// The users whose flags are cached.
+    pub fn cached_users(&self) -> Vec<UserId> {
+        self.state.lock().entries.keys().copied().collect()
+    }
+
+    /// Drops the flags that were fetched at versions other than the current ones, given the
+    /// version that covers all users and the versions of the cached users. Users missing from
+    /// `user_versions` have never had their flags changed.
+    pub fn retain_current(&self, all_users_version: i64, user_versions: &HashMap<UserId, i64>) {
+        let mut state = self.state.lock();
+        let stale_users = state
+            .entries
+            .iter()
+            .filter(|(user, entry)| {
+                entry.versions
+                    != FlagVersions {
+                        user: user_versions.get(user).copied().unwrap_or_default(),
+                        all_users: all_users_version,
+                    }
+            })
+            .map(|(user, _)| *user)
+            .collect::<Vec<_>>();
+        for user in stale_users {
+            state.remove(user);
+        }
+    }
+}
+
+impl UserFlagCacheState {
+    fn remove(&mut self, user: UserId) {
+        if let Some(entry) = self.entries.remove(&user) {
+            self.recency.remove(&entry.last_use);
+        }
+    }
+}
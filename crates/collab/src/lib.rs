@@ -141,6 +141,8 @@ impl std::error::Error for Error {}
 pub struct Config {
     pub http_port: u16,
     pub database_url: String,
+    /// A read replica of the database, which read-only feature flag queries are routed to.
+    pub database_replica_url: Option<String>,
     pub migrations_path: Option<PathBuf>,
     pub seed_path: Option<PathBuf>,
     pub database_max_connections: u32,
@@ -200,6 +202,7 @@ impl Config {
         Self {
             http_port: 0,
             database_url: "".into(),
+            database_replica_url: None,
             database_max_connections: 0,
             api_token: "".into(),
             invite_link_prefix: "".into(),
@@ -282,6 +285,11 @@ impl AppState {
         let mut db_options = db::ConnectOptions::new(config.database_url.clone());
         db_options.max_connections(config.database_max_connections);
         let mut db = Database::new(db_options, Executor::Production).await?;
+        if let Some(database_replica_url) = config.database_replica_url.clone() {
+            let mut replica_options = db::ConnectOptions::new(database_replica_url);
+            replica_options.max_connections(config.database_max_connections);
+            db.connect_read_replica(replica_options).await?;
+        }
         db.initialize_notification_kinds().await?;
 
         let live_kit_client = if let Some(((server, key), secret)) = config
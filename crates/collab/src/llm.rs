@@ -349,6 +349,7 @@ async fn perform_completion(
                 &state.http_client,
                 open_ai::OPEN_AI_API_URL,
                 api_key,
+                &open_ai::ApiOptions::default(),
                 serde_json::from_str(params.provider_request.get())?,
                 None,
             )
@@ -415,6 +416,7 @@ async fn perform_completion(
                 &state.http_client,
                 api_url,
                 api_key,
+                &open_ai::ApiOptions::default(),
                 serde_json::from_str(params.provider_request.get())?,
                 None,
             )
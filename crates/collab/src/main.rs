@@ -6,12 +6,17 @@ use axum::{
     routing::get,
     Extension, Router,
 };
+use collab::api::feature_flags::{
+    flush_flag_stats_periodically, poll_flag_cache_versions_periodically,
+    sweep_flag_schedules_periodically, warn_about_flags_over_threshold_periodically,
+};
 use collab::api::CloudflareIpCountryHeader;
 use collab::llm::{db::LlmDatabase, log_usage_periodically};
 use collab::migrations::run_database_migrations;
 use collab::user_backfiller::spawn_user_backfiller;
 use collab::{api::billing::poll_stripe_events_periodically, llm::LlmState, ServiceMode};
 use collab::{
+    api::feature_flags::purge_expired_user_flags_periodically,
     api::fetch_extensions_from_blob_store_periodically, db, env, executor::Executor,
     rpc::ResultExt, AppState, Config, RateLimiter, Result,
 };
@@ -92,6 +97,7 @@ async fn main() -> Result<()> {
                 .expect("failed to bind TCP listener");
 
             let mut on_shutdown = None;
+            let mut flag_stats_db = None;
 
             if mode.is_llm() {
                 setup_llm_database(&config).await?;
@@ -109,6 +115,9 @@ async fn main() -> Result<()> {
                 setup_app_database(&config).await?;
 
                 let state = AppState::new(config, Executor::Production).await?;
+                flush_flag_stats_periodically(state.clone());
+                poll_flag_cache_versions_periodically(state.clone());
+                flag_stats_db = Some(state.db.clone());
 
                 if mode.is_collab() {
                     state.db.purge_old_embeddings().await.trace_err();
@@ -123,6 +132,7 @@ async fn main() -> Result<()> {
                         .await?;
                     let rpc_server = collab::rpc::Server::new(epoch, state.clone());
                     rpc_server.start().await?;
+                    sweep_flag_schedules_periodically(state.clone(), rpc_server.clone());
 
                     app = app
                         .merge(collab::api::routes(rpc_server.clone()))
@@ -134,6 +144,8 @@ async fn main() -> Result<()> {
                 if mode.is_api() {
                     poll_stripe_events_periodically(state.clone());
                     fetch_extensions_from_blob_store_periodically(state.clone());
+                    purge_expired_user_flags_periodically(state.clone());
+                    warn_about_flags_over_threshold_periodically(state.clone());
                     spawn_user_backfiller(state.clone());
 
                     app = app
@@ -221,6 +233,11 @@ async fn main() -> Result<()> {
                 })
                 .await
                 .map_err(|e| anyhow!(e))?;
+
+            // Write the flag stats recorded since the last periodic flush before exiting.
+            if let Some(db) = flag_stats_db {
+                db.flush_flag_stats().await.trace_err();
+            }
         }
         _ => {
             Err(anyhow!(
@@ -5,11 +5,12 @@ use crate::llm::LlmTokenClaims;
 use crate::{
     auth,
     db::{
-        self, dev_server, BufferId, Capability, Channel, ChannelId, ChannelRole, ChannelsForUser,
-        CreatedChannelMessage, Database, DevServerId, DevServerProjectId, InviteMemberResult,
-        MembershipUpdated, MessageId, NotificationId, PrincipalId, Project, ProjectId,
-        RejoinedProject, RemoveChannelMemberResult, ReplicaId, RespondToChannelInvite, RoomId,
-        ServerId, UpdatedChannelMessage, User, UserId,
+        self, dev_server, flags_for_client_version, BufferId, Capability, Channel, ChannelId,
+        ChannelRole, ChannelsForUser, CreatedChannelMessage, Database, DevServerId,
+        DevServerProjectId, FlagId, InviteMemberResult, MembershipUpdated, MessageId,
+        NotificationId, OptInFlag, PrincipalId, Project, ProjectId, RejoinedProject,
+        RemoveChannelMemberResult, ReplicaId, RespondToChannelInvite, RoomId, ServerId,
+        UpdatedChannelMessage, User, UserId,
     },
     executor::Executor,
     AppState, Config, Error, RateLimit, Result,
@@ -144,6 +145,7 @@ struct Session {
     /// The GeoIP country code for the user.
     #[allow(unused)]
     geoip_country_code: Option<String>,
+    zed_version: ZedVersion,
     _executor: Executor,
 }
 
@@ -608,6 +610,8 @@ impl Server {
             .add_request_handler(user_handler(get_private_user_info))
             .add_request_handler(user_handler(get_llm_api_token))
             .add_request_handler(user_handler(accept_terms_of_service))
+            .add_request_handler(user_handler(get_opt_in_feature_flags))
+            .add_request_handler(user_handler(set_feature_flag_opt_in))
             .add_message_handler(user_message_handler(acknowledge_channel_message))
             .add_message_handler(user_message_handler(acknowledge_buffer_version))
             .add_request_handler(user_handler(get_supermaven_api_key))
@@ -979,6 +983,7 @@ impl Server {
                 app_state: this.app_state.clone(),
                 http_client,
                 geoip_country_code,
+                zed_version,
                 _executor: executor.clone(),
                 supermaven_client,
             };
@@ -1214,6 +1219,50 @@ impl Server {
         Ok(())
     }
 
+    /// Sends the user's complete, current list of feature flags to each of their connections,
+    /// omitting flags that the connection's client is too old for.
+    pub async fn user_flags_updated(self: &Arc<Self>, user_id: UserId) -> Result<()> {
+        let flags = self.app_state.db.get_user_flags_cached(user_id).await?;
+        let mut pool = self.connection_pool.lock();
+        let connection_ids = pool.user_connection_ids(user_id).collect::<Vec<_>>();
+        for connection_id in connection_ids {
+            let Some(connection) = pool.connection(connection_id) else {
+                continue;
+            };
+            // A connection that can't be sent to shouldn't keep the others from being updated.
+            self.peer
+                .send(
+                    connection_id,
+                    proto::UpdateUserFlags {
+                        flags: flags_for_client_version(&flags, connection.zed_version.0),
+                    },
+                )
+                .trace_err();
+        }
+        Ok(())
+    }
+
+    /// Sends every connected user their complete, current list of feature flags.
+    ///
+    /// This happens in the background, since each user's flags are fetched in turn.
+    pub fn flags_updated_for_all_users(self: &Arc<Self>) {
+        let user_ids = self
+            .connection_pool
+            .lock()
+            .connections()
+            .filter_map(|connection| match connection.principal_id {
+                PrincipalId::UserId(user_id) => Some(user_id),
+                PrincipalId::DevServerId(_) => None,
+            })
+            .collect::<HashSet<_>>();
+        let this = self.clone();
+        self.app_state.executor.spawn_detached(async move {
+            for user_id in user_ids {
+                this.user_flags_updated(user_id).await.trace_err();
+            }
+        });
+    }
+
     pub async fn snapshot<'a>(self: &'a Arc<Self>) -> ServerSnapshot<'a> {
         ServerSnapshot {
             connection_pool: ConnectionPoolGuard {
@@ -4884,7 +4933,9 @@ async fn get_private_user_info(
         .get_user_by_id(session.user_id())
         .await?
         .ok_or_else(|| anyhow!("user not found"))?;
-    let flags = db.get_user_flags(session.user_id()).await?;
+    let flags = db.get_user_flags_cached(session.user_id()).await?;
+    let flags = flags_for_client_version(&flags, session.zed_version.0);
+    db.record_flags_served(flags.iter().map(String::as_str));
 
     response.send(proto::GetPrivateUserInfoResponse {
         metrics_id,
@@ -4895,6 +4946,73 @@ async fn get_private_user_info(
     Ok(())
 }
 
+/// Returns the feature flags that the current user can opt into themselves.
+async fn get_opt_in_feature_flags(
+    _request: proto::GetOptInFeatureFlags,
+    response: Response<proto::GetOptInFeatureFlags>,
+    session: UserSession,
+) -> Result<()> {
+    let db = session.db().await;
+    let flags = db.get_opt_in_flags(session.user_id()).await?;
+    response.send(proto::GetOptInFeatureFlagsResponse {
+        flags: flags.into_iter().map(opt_in_flag_to_proto).collect(),
+    })?;
+    Ok(())
+}
+
+/// Opts the current user into or out of a feature flag, responding with their updated flags and
+/// sending those flags to the user's other connections.
+async fn set_feature_flag_opt_in(
+    request: proto::SetFeatureFlagOptIn,
+    response: Response<proto::SetFeatureFlagOptIn>,
+    session: UserSession,
+) -> Result<()> {
+    let db = session.db().await;
+    db.set_user_flag_opt_in(
+        session.user_id(),
+        FlagId::from_proto(request.flag_id),
+        request.enabled,
+    )
+    .await?;
+    let flags = db.get_user_flags_cached(session.user_id()).await?;
+    let opt_in_flags = db.get_opt_in_flags(session.user_id()).await?;
+
+    response.send(proto::SetFeatureFlagOptInResponse {
+        flags: flags_for_client_version(&flags, session.zed_version.0),
+        opt_in_flags: opt_in_flags.into_iter().map(opt_in_flag_to_proto).collect(),
+    })?;
+
+    let pool = session.connection_pool().await;
+    for connection_id in pool.user_connection_ids(session.user_id()) {
+        if connection_id == session.connection_id {
+            continue;
+        }
+        let Some(connection) = pool.connection(connection_id) else {
+            continue;
+        };
+        // A connection that can't be sent to shouldn't keep the others from being updated.
+        session
+            .peer
+            .send(
+                connection_id,
+                proto::UpdateUserFlags {
+                    flags: flags_for_client_version(&flags, connection.zed_version.0),
+                },
+            )
+            .trace_err();
+    }
+    Ok(())
+}
+
+fn opt_in_flag_to_proto(flag: OptInFlag) -> proto::OptInFeatureFlag {
+    proto::OptInFeatureFlag {
+        id: flag.id.to_proto(),
+        name: flag.flag,
+        description: flag.description,
+        opted_in: flag.opted_in,
+    }
+}
+
 /// Accept the terms of service (tos) on behalf of the current user
 async fn accept_terms_of_service(
     _request: proto::AcceptTermsOfService,
@@ -4923,7 +5041,12 @@ async fn get_llm_api_token(
 ) -> Result<()> {
     let db = session.db().await;
 
-    let flags = db.get_user_flags(session.user_id()).await?;
+    let flags = db
+        .get_user_flags_cached(session.user_id())
+        .await?
+        .into_iter()
+        .map(|flag| flag.flag)
+        .collect::<Vec<_>>();
     let has_language_models_feature_flag = flags.iter().any(|flag| flag == "language-models");
     let has_llm_closed_beta_feature_flag = flags.iter().any(|flag| flag == "llm-closed-beta");
 
@@ -60,7 +60,7 @@ pub async fn seed(config: &Config, db: &Database, force: bool) -> anyhow::Result
         }
 
         let flag = db
-            .create_user_flag(flag_name, false)
+            .create_user_flag(flag_name, false, false)
             .await
             .unwrap_or_else(|err| panic!("failed to create flag: '{flag_name}': {err}"));
         flags.push(flag);
@@ -90,7 +90,7 @@ pub async fn seed(config: &Config, db: &Database, force: bool) -> anyhow::Result
         }
 
         for flag in &flags {
-            db.add_user_flag(user.user_id, *flag)
+            db.add_user_flag(user.user_id, *flag, None, None)
                 .await
                 .context(format!(
                     "Unable to enable flag '{}' for user '{}'",
@@ -136,10 +136,12 @@ pub async fn seed(config: &Config, db: &Database, force: bool) -> anyhow::Result
             .expect("failed to insert user");
 
         for flag in &flags {
-            db.add_user_flag(user.id, *flag).await.context(format!(
-                "Unable to enable flag '{}' for user '{}'",
-                flag, user.id
-            ))?;
+            db.add_user_flag(user.id, *flag, None, None)
+                .await
+                .context(format!(
+                    "Unable to enable flag '{}' for user '{}'",
+                    flag, user.id
+                ))?;
         }
     }
 
@@ -10,6 +10,7 @@ mod channel_message_tests;
 mod channel_tests;
 mod dev_server_tests;
 mod editor_tests;
+mod feature_flag_api_tests;
 mod following_tests;
 mod integration_tests;
 mod notification_tests;
@@ -0,0 +1,164 @@
+use crate::{
+    api::feature_flags,
+    db::{NewUserParams, UserId},
+    tests::TestServer,
+};
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+    Extension,
+};
+use gpui::BackgroundExecutor;
+use serde_json::json;
+use tower::ServiceExt as _;
+
+fn get_user_flags_request(user_id: UserId, if_none_match: Option<&str>) -> Request<Body> {
+    let mut request = Request::get(format!("/users/{user_id}/flags"));
+    if let Some(etag) = if_none_match {
+        request = request.header(header::IF_NONE_MATCH, etag);
+    }
+    request.body(Body::empty()).unwrap()
+}
+
+#[gpui::test]
+async fn test_get_user_flags_with_etag(executor: BackgroundExecutor) {
+    let server = TestServer::start(executor.clone()).await;
+    let db = server.app_state.db.clone();
+    let router = feature_flags::router().layer(Extension(server.app_state.clone()));
+
+    let user = db
+        .create_user(
+            "user@example.com",
+            false,
+            NewUserParams {
+                github_login: "user".into(),
+                github_user_id: 1,
+            },
+        )
+        .await
+        .unwrap()
+        .user_id;
+    let granted_flag = db
+        .create_user_flag("granted-feature", false, false)
+        .await
+        .unwrap();
+    db.create_user_flag("everyone-feature", true, false)
+        .await
+        .unwrap();
+    db.add_user_flag(user, granted_flag, None, None)
+        .await
+        .unwrap();
+
+    let response = router
+        .clone()
+        .oneshot(get_user_flags_request(user, None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let etag = response.headers()[header::ETAG]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let body: serde_json::Value =
+        serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap())
+            .unwrap();
+    assert_eq!(
+        body["flags"],
+        json!([
+            { "flag": "everyone-feature", "source": "enabled_for_all" },
+            { "flag": "granted-feature", "source": "granted" },
+        ])
+    );
+
+    // Polling with the current tag is answered without a body.
+    let response = router
+        .clone()
+        .oneshot(get_user_flags_request(user, Some(&etag)))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers()[header::ETAG], etag.as_str());
+
+    // Once the flags change, the old tag no longer matches.
+    db.remove_user_flag(user, granted_flag, None).await.unwrap();
+    let response = router
+        .clone()
+        .oneshot(get_user_flags_request(user, Some(&etag)))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_ne!(response.headers()[header::ETAG], etag.as_str());
+    let body: serde_json::Value =
+        serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap())
+            .unwrap();
+    assert_eq!(
+        body["flags"],
+        json!([{ "flag": "everyone-feature", "source": "enabled_for_all" }])
+    );
+
+    let response = router
+        .oneshot(get_user_flags_request(UserId(user.0 + 1000), None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[gpui::test]
+async fn test_export_flag_assignments(executor: BackgroundExecutor) {
+    let server = TestServer::start(executor.clone()).await;
+    let db = server.app_state.db.clone();
+    let router = feature_flags::router().layer(Extension(server.app_state.clone()));
+
+    let flag = db
+        .create_user_flag("cool-feature", false, false)
+        .await
+        .unwrap();
+    for (i, github_login) in ["alice", "bob"].into_iter().enumerate() {
+        let user = db
+            .create_user(
+                &format!("{github_login}@example.com"),
+                false,
+                NewUserParams {
+                    github_login: github_login.into(),
+                    github_user_id: i as i32,
+                },
+            )
+            .await
+            .unwrap()
+            .user_id;
+        db.add_user_flag(user, flag, None, None).await.unwrap();
+    }
+
+    let response = router
+        .oneshot(
+            Request::get("/feature_flag_assignments/export")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[header::CONTENT_TYPE],
+        "application/x-ndjson"
+    );
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let assignments = body
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| serde_json::from_slice::<serde_json::Value>(line).unwrap())
+        .map(|assignment| {
+            (
+                assignment["flag"].as_str().unwrap().to_string(),
+                assignment["github_login"].as_str().unwrap().to_string(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        assignments,
+        [
+            ("cool-feature".to_string(), "alice".to_string()),
+            ("cool-feature".to_string(), "bob".to_string()),
+        ]
+    );
+}
@@ -1,5 +1,6 @@
 use crate::{
-    rpc::{CLEANUP_TIMEOUT, RECONNECT_TIMEOUT},
+    db::UserId,
+    rpc::{ZedVersion, CLEANUP_TIMEOUT, RECONNECT_TIMEOUT},
     tests::{
         channel_id, following_tests::join_channel, room_participants, rust_lang, RoomParticipants,
         TestClient, TestServer,
@@ -10,6 +11,7 @@ use assistant::{ContextStore, PromptBuilder};
 use call::{room, ActiveCall, ParticipantLocation, Room};
 use client::{User, RECEIVE_TIMEOUT};
 use collections::{HashMap, HashSet};
+use feature_flags::{FeatureFlag, FeatureFlagAppExt as _, OptInFeatureFlag};
 use fs::{FakeFs, Fs as _, RemoveOptions};
 use futures::{channel::mpsc, StreamExt as _};
 use git::repository::GitFileStatus;
@@ -24,6 +26,12 @@ use language::{
     tree_sitter_rust, Diagnostic, DiagnosticEntry, FakeLspAdapter, Language, LanguageConfig,
     LanguageMatcher, LineEnding, OffsetRangeExt, Point, Rope,
 };
+use language_model::{
+    provider::cloud::{CloudLanguageModelProvider, DisconnectedError},
+    settings::AllLanguageModelSettings,
+    LanguageModelProvider as _, LanguageModelRequest, LanguageModelRequestMessage, ProviderStatus,
+    Role,
+};
 use live_kit_client::MacOSDisplay;
 use lsp::LanguageServerId;
 use parking_lot::Mutex;
@@ -32,8 +40,9 @@ use project::{
     HoverBlockKind, Project, ProjectPath,
 };
 use rand::prelude::*;
+use semantic_version::SemanticVersion;
 use serde_json::json;
-use settings::SettingsStore;
+use settings::{Settings as _, SettingsStore};
 use std::{
     cell::{Cell, RefCell},
     env, future, mem,
@@ -6570,3 +6579,277 @@ async fn test_context_collaboration_with_reconnect(
         assert!(context.buffer().read(cx).read_only());
     });
 }
+
+#[gpui::test]
+async fn test_feature_flags_updated_mid_session(
+    executor: BackgroundExecutor,
+    cx_a: &mut TestAppContext,
+    cx_b: &mut TestAppContext,
+) {
+    struct CoolFeature;
+    impl FeatureFlag for CoolFeature {
+        const NAME: &'static str = "cool-feature";
+
+        fn enabled_for_staff() -> bool {
+            false
+        }
+    }
+
+    let mut server = TestServer::start(executor.clone()).await;
+    let _client_a = server.create_client(cx_a, "user_a").await;
+    let client_b = server.create_client(cx_b, "user_b").await;
+    executor.run_until_parked();
+    assert!(!cx_b.update(|cx| cx.has_flag::<CoolFeature>()));
+
+    let db = server.app_state.db.clone();
+    let user_b = UserId::from_proto(client_b.user_id().unwrap());
+    let flag = db
+        .create_user_flag(CoolFeature::NAME, false, false)
+        .await
+        .unwrap();
+
+    db.add_user_flag(user_b, flag, None, None).await.unwrap();
+    server.notify_user_flags_updated(user_b).await;
+    executor.run_until_parked();
+    assert!(cx_b.update(|cx| cx.has_flag::<CoolFeature>()));
+    assert!(!cx_a.update(|cx| cx.has_flag::<CoolFeature>()));
+
+    db.remove_user_flag(user_b, flag, None).await.unwrap();
+    server.notify_user_flags_updated(user_b).await;
+    executor.run_until_parked();
+    assert!(!cx_b.update(|cx| cx.has_flag::<CoolFeature>()));
+}
+
+#[gpui::test]
+async fn test_feature_flags_for_client_version(
+    executor: BackgroundExecutor,
+    cx_a: &mut TestAppContext,
+    cx_b: &mut TestAppContext,
+) {
+    struct NewFeature;
+    impl FeatureFlag for NewFeature {
+        const NAME: &'static str = "new-feature";
+
+        fn enabled_for_staff() -> bool {
+            false
+        }
+    }
+
+    let mut server = TestServer::start(executor.clone()).await;
+    let db = server.app_state.db.clone();
+    let flag = db
+        .create_user_flag(NewFeature::NAME, true, false)
+        .await
+        .unwrap();
+    db.set_flag_minimum_client_version(flag, Some("0.150.0"))
+        .await
+        .unwrap();
+
+    // The same user connects from an old client and a new one.
+    let client_a = server
+        .create_client_with_version(cx_a, "user_a", ZedVersion(SemanticVersion::new(0, 149, 0)))
+        .await;
+    let client_b = server
+        .create_client_with_version(cx_b, "user_a", ZedVersion(SemanticVersion::new(0, 150, 1)))
+        .await;
+    executor.run_until_parked();
+    assert_eq!(client_a.user_id(), client_b.user_id());
+    assert!(!cx_a.update(|cx| cx.has_flag::<NewFeature>()));
+    assert!(cx_b.update(|cx| cx.has_flag::<NewFeature>()));
+
+    // Flags sent mid-session are filtered the same way.
+    db.set_flag_minimum_client_version(flag, Some("0.149.0"))
+        .await
+        .unwrap();
+    server
+        .notify_user_flags_updated(UserId::from_proto(client_a.user_id().unwrap()))
+        .await;
+    executor.run_until_parked();
+    assert!(cx_a.update(|cx| cx.has_flag::<NewFeature>()));
+    assert!(cx_b.update(|cx| cx.has_flag::<NewFeature>()));
+}
+
+#[gpui::test]
+async fn test_feature_flag_opt_in(
+    executor: BackgroundExecutor,
+    cx_a: &mut TestAppContext,
+    cx_b: &mut TestAppContext,
+) {
+    struct PublicBeta;
+    impl FeatureFlag for PublicBeta {
+        const NAME: &'static str = "public-beta";
+
+        fn enabled_for_staff() -> bool {
+            false
+        }
+    }
+
+    let mut server = TestServer::start(executor.clone()).await;
+    let db = server.app_state.db.clone();
+    let beta_flag = db
+        .create_user_flag(PublicBeta::NAME, false, false)
+        .await
+        .unwrap();
+    db.set_flag_opt_in(beta_flag, true, Some("Try the new thing"))
+        .await
+        .unwrap();
+    let internal_flag = db
+        .create_user_flag("internal-feature", false, false)
+        .await
+        .unwrap();
+
+    // The same user is connected from two clients.
+    let client_a = server.create_client(cx_a, "user_a").await;
+    let _client_b = server.create_client(cx_b, "user_a").await;
+    executor.run_until_parked();
+
+    client_a
+        .user_store()
+        .update(cx_a, |store, cx| store.fetch_opt_in_feature_flags(cx))
+        .await
+        .unwrap();
+    assert_eq!(
+        cx_a.update(|cx| cx.opt_in_flags().to_vec()),
+        [OptInFeatureFlag {
+            id: beta_flag.to_proto(),
+            name: PublicBeta::NAME.to_string(),
+            description: Some("Try the new thing".to_string()),
+            opted_in: false,
+        }]
+    );
+
+    // Opting in updates the client's flags from the response, and pushes them to the user's
+    // other clients.
+    client_a
+        .user_store()
+        .update(cx_a, |store, cx| {
+            store.set_feature_flag_opt_in(beta_flag.to_proto(), true, cx)
+        })
+        .await
+        .unwrap();
+    assert!(cx_a.update(|cx| cx.has_flag::<PublicBeta>()));
+    assert!(cx_a.update(|cx| cx.opt_in_flags()[0].opted_in));
+    executor.run_until_parked();
+    assert!(cx_b.update(|cx| cx.has_flag::<PublicBeta>()));
+
+    client_a
+        .user_store()
+        .update(cx_a, |store, cx| {
+            store.set_feature_flag_opt_in(beta_flag.to_proto(), false, cx)
+        })
+        .await
+        .unwrap();
+    assert!(!cx_a.update(|cx| cx.has_flag::<PublicBeta>()));
+    assert!(!cx_a.update(|cx| cx.opt_in_flags()[0].opted_in));
+    executor.run_until_parked();
+    assert!(!cx_b.update(|cx| cx.has_flag::<PublicBeta>()));
+
+    // Flags that aren't opt-in are rejected, whatever the client sends.
+    let result = client_a
+        .user_store()
+        .update(cx_a, |store, cx| {
+            store.set_feature_flag_opt_in(internal_flag.to_proto(), true, cx)
+        })
+        .await;
+    assert!(result.is_err());
+    let user = UserId::from_proto(client_a.user_id().unwrap());
+    assert!(db.get_user_flags(user).await.unwrap().is_empty());
+}
+
+#[gpui::test]
+async fn test_queue_completion_while_disconnected(
+    executor: BackgroundExecutor,
+    cx_a: &mut TestAppContext,
+) {
+    let mut server = TestServer::start(executor.clone()).await;
+    let client_a = server.create_client(cx_a, "user_a").await;
+    cx_a.update(|cx| {
+        AllLanguageModelSettings::register(cx);
+        SettingsStore::update_global(cx, |store, cx| {
+            store
+                .set_user_settings(
+                    r#"{"language_models": {"zed.dev": {"queue_while_disconnected": true}}}"#,
+                    cx,
+                )
+                .unwrap();
+        });
+    });
+    let provider = cx_a.update(|cx| {
+        CloudLanguageModelProvider::new(
+            client_a.user_store().clone(),
+            client_a.client().clone(),
+            cx,
+        )
+    });
+    let model = cx_a.update(|cx| provider.provided_models(cx)).remove(0);
+    let request = LanguageModelRequest {
+        messages: vec![LanguageModelRequestMessage {
+            role: Role::User,
+            content: vec!["Hello".into()],
+            cache: false,
+            attachments: Vec::new(),
+        }],
+        ..Default::default()
+    };
+
+    // While the connection is lost, the completion waits for it to be restored.
+    server.forbid_connections();
+    server.disconnect_client(client_a.peer_id().unwrap());
+    executor.advance_clock(RECEIVE_TIMEOUT);
+    let completion = executor.spawn(model.stream_completion(request.clone(), &cx_a.to_async()));
+    executor.run_until_parked();
+    assert_eq!(
+        cx_a.update(|cx| provider.check_status(cx)).await,
+        ProviderStatus::Queued
+    );
+
+    // Once the connection is restored, the request is sent.
+    server.allow_connections();
+    executor.advance_clock(client::MAX_RECONNECTION_DELAY);
+    executor.run_until_parked();
+    assert!(client_a.client().status().borrow().is_connected());
+    if let Err(error) = completion.await {
+        assert!(error.downcast_ref::<DisconnectedError>().is_none());
+    }
+    assert_eq!(
+        cx_a.update(|cx| provider.check_status(cx)).await,
+        ProviderStatus::Ready
+    );
+
+    // Cancelling queued completions takes them out of the queue.
+    server.forbid_connections();
+    server.disconnect_client(client_a.peer_id().unwrap());
+    executor.advance_clock(RECEIVE_TIMEOUT);
+    let completions = (0..3)
+        .map(|_| executor.spawn(model.stream_completion(request.clone(), &cx_a.to_async())))
+        .collect::<Vec<_>>();
+    executor.run_until_parked();
+    assert_eq!(
+        cx_a.update(|cx| provider.check_status(cx)).await,
+        ProviderStatus::Queued
+    );
+    drop(completions);
+    executor.run_until_parked();
+    assert_eq!(
+        cx_a.update(|cx| provider.check_status(cx)).await,
+        ProviderStatus::Ready
+    );
+
+    // If the connection isn't restored in time, the completion fails with the request that
+    // couldn't be sent.
+    let completion = executor.spawn(model.stream_completion(request.clone(), &cx_a.to_async()));
+    executor.run_until_parked();
+    executor.advance_clock(Duration::from_secs(30));
+    let Err(error) = completion.await else {
+        panic!("completion should fail while disconnected");
+    };
+    assert_eq!(
+        error.downcast_ref::<DisconnectedError>().unwrap().request,
+        request
+    );
+    executor.run_until_parked();
+    assert_eq!(
+        cx_a.update(|cx| provider.check_status(cx)).await,
+        ProviderStatus::Ready
+    );
+}
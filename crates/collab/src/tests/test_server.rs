@@ -157,6 +157,18 @@ impl TestServer {
     }
 
     pub async fn create_client(&mut self, cx: &mut TestAppContext, name: &str) -> TestClient {
+        self.create_client_with_version(cx, name, ZedVersion(SemanticVersion::new(1, 0, 0)))
+            .await
+    }
+
+    /// Creates a client that reports the given version when it connects. Clients with the
+    /// same name connect as the same user.
+    pub async fn create_client_with_version(
+        &mut self,
+        cx: &mut TestAppContext,
+        name: &str,
+        zed_version: ZedVersion,
+    ) -> TestClient {
         let fs = FakeFs::new(cx.executor());
 
         cx.update(|cx| {
@@ -243,7 +255,7 @@ impl TestServer {
                                 server_conn,
                                 client_name,
                                 Principal::User(user),
-                                ZedVersion(SemanticVersion::new(1, 0, 0)),
+                                zed_version,
                                 None,
                                 Some(connection_id_tx),
                                 Executor::Deterministic(cx.background_executor().clone()),
@@ -469,6 +481,10 @@ impl TestServer {
         deterministic.run_until_parked();
     }
 
+    pub async fn notify_user_flags_updated(&self, user_id: UserId) {
+        self.server.user_flags_updated(user_id).await.unwrap();
+    }
+
     pub fn forbid_connections(&self) {
         self.forbid_connections.store(true, SeqCst);
     }
@@ -644,6 +660,7 @@ impl TestServer {
             config: Config {
                 http_port: 0,
                 database_url: "".into(),
+                database_replica_url: None,
                 database_max_connections: 0,
                 api_token: "".into(),
                 invite_link_prefix: "".into(),
@@ -10,6 +10,16 @@ use std::{
 struct FeatureFlags {
     flags: Vec<String>,
     staff: bool,
+    opt_in_flags: Vec<OptInFeatureFlag>,
+}
+
+/// A feature flag that users can opt into themselves, such as a public beta.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OptInFeatureFlag {
+    pub id: u64,
+    pub name: String,
+    pub description: Option<String>,
+    pub opted_in: bool,
 }
 
 impl FeatureFlags {
@@ -92,6 +102,9 @@ where
 pub trait FeatureFlagAppExt {
     fn wait_for_flag<T: FeatureFlag>(&mut self) -> WaitForFlag;
     fn update_flags(&mut self, staff: bool, flags: Vec<String>);
+    fn update_opt_in_flags(&mut self, flags: Vec<OptInFeatureFlag>);
+    /// The flags that the user can opt into, as of the last time they were fetched.
+    fn opt_in_flags(&self) -> &[OptInFeatureFlag];
     fn set_staff(&mut self, staff: bool);
     fn has_flag<T: FeatureFlag>(&self) -> bool;
     fn is_staff(&self) -> bool;
@@ -108,6 +121,16 @@ impl FeatureFlagAppExt for AppContext {
         feature_flags.flags = flags;
     }
 
+    fn update_opt_in_flags(&mut self, flags: Vec<OptInFeatureFlag>) {
+        let feature_flags = self.default_global::<FeatureFlags>();
+        feature_flags.opt_in_flags = flags;
+    }
+
+    fn opt_in_flags(&self) -> &[OptInFeatureFlag] {
+        self.try_global::<FeatureFlags>()
+            .map_or(&[], |flags| flags.opt_in_flags.as_slice())
+    }
+
     fn set_staff(&mut self, staff: bool) {
         let feature_flags = self.default_global::<FeatureFlags>();
         feature_flags.staff = staff;
@@ -0,0 +1,305 @@
+use crate::{
+    BatchCompletions, LanguageModel, LanguageModelCacheConfiguration, LanguageModelCompletionEvent,
+    LanguageModelId, LanguageModelName, LanguageModelProviderId, LanguageModelProviderName,
+    LanguageModelRequest, LanguageModelRequestMessage, MessageContent,
+};
+use anyhow::Result;
+use futures::{future::BoxFuture, stream::BoxStream};
+use gpui::{AppContext, AsyncAppContext};
+use serde::{Deserialize, Serialize};
+use std::{ops::Range, sync::Arc};
+use ui::IconName;
+
+/// Context attached to a message, which is rendered into the prompt text just before the
+/// request is sent.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Hash)]
+pub enum MessageAttachment {
+    File {
+        path: String,
+        text: String,
+        language: Option<String>,
+    },
+    Selection {
+        path: String,
+        /// The selected rows, starting from zero, excluding `range.end`.
+        range: Range<u32>,
+        text: String,
+    },
+    Terminal {
+        text: String,
+    },
+    /// An attachment whose body was omitted to make the request fit, leaving only a marker.
+    Omitted {
+        marker: String,
+    },
+}
+
+impl MessageAttachment {
+    /// Renders the attachment as prompt text, with a header and its body in a fenced code block.
+    pub fn render(&self) -> String {
+        match self {
+            MessageAttachment::File {
+                path,
+                text,
+                language,
+            } => format!("{path}\n{}", fenced(text, language.as_deref())),
+            MessageAttachment::Selection { path, range, text } => format!(
+                "{path} (lines {}-{})\n{}",
+                range.start + 1,
+                range.end,
+                fenced(text, None)
+            ),
+            MessageAttachment::Terminal { text } => {
+                format!("Terminal output:\n{}", fenced(text, None))
+            }
+            MessageAttachment::Omitted { marker } => marker.clone(),
+        }
+    }
+
+    /// Returns the marker that stands in for this attachment once its body is omitted.
+    pub fn omitted(&self) -> MessageAttachment {
+        let marker = match self {
+            MessageAttachment::File { path, .. } => format!("[file omitted: {path}]"),
+            MessageAttachment::Selection { path, .. } => format!("[selection omitted: {path}]"),
+            MessageAttachment::Terminal { .. } => "[terminal output omitted]".to_string(),
+            MessageAttachment::Omitted { marker } => marker.clone(),
+        };
+        MessageAttachment::Omitted { marker }
+    }
+}
+
+/// Wraps the text in a code fence that's longer than any run of backticks inside it.
+fn fenced(text: &str, language: Option<&str>) -> String {
+    let longest_backtick_run = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest_backtick_run.max(2) + 1);
+    let mut block = format!("{fence}{}\n{text}", language.unwrap_or_default());
+    if !text.ends_with('\n') {
+        block.push('\n');
+    }
+    block.push_str(&fence);
+    block
+}
+
+impl LanguageModelRequestMessage {
+    /// Renders the message's attachments, in order, into text ahead of the message's content.
+    pub fn expand_attachments(&mut self) {
+        if self.attachments.is_empty() {
+            return;
+        }
+        let mut text = String::new();
+        for attachment in self.attachments.drain(..) {
+            text.push_str(&attachment.render());
+            text.push_str("\n\n");
+        }
+        self.content.insert(0, MessageContent::Text(text));
+    }
+}
+
+impl LanguageModelRequest {
+    /// Renders the attachments of every message into text.
+    pub fn expand_attachments(&mut self) {
+        for message in &mut self.messages {
+            message.expand_attachments();
+        }
+    }
+}
+
+/// A [`LanguageModel`] that renders the attachments of each request into text before passing
+/// it on, so the wrapped model never sees them.
+pub struct AttachmentExpandingLanguageModel {
+    model: Arc<dyn LanguageModel>,
+}
+
+impl AttachmentExpandingLanguageModel {
+    pub fn new(model: Arc<dyn LanguageModel>) -> Self {
+        Self { model }
+    }
+}
+
+impl LanguageModel for AttachmentExpandingLanguageModel {
+    fn id(&self) -> LanguageModelId {
+        self.model.id()
+    }
+
+    fn name(&self) -> LanguageModelName {
+        self.model.name()
+    }
+
+    fn icon(&self) -> Option<IconName> {
+        self.model.icon()
+    }
+
+    fn provider_id(&self) -> LanguageModelProviderId {
+        self.model.provider_id()
+    }
+
+    fn provider_name(&self) -> LanguageModelProviderName {
+        self.model.provider_name()
+    }
+
+    fn telemetry_id(&self) -> String {
+        self.model.telemetry_id()
+    }
+
+    fn availability(&self) -> crate::LanguageModelAvailability {
+        self.model.availability()
+    }
+
+    fn max_token_count(&self) -> usize {
+        self.model.max_token_count()
+    }
+
+    fn max_output_tokens(&self) -> Option<u32> {
+        self.model.max_output_tokens()
+    }
+
+    fn count_tokens(
+        &self,
+        mut request: LanguageModelRequest,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<usize>> {
+        request.expand_attachments();
+        self.model.count_tokens(request, cx)
+    }
+
+    fn stream_completion(
+        &self,
+        mut request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        request.expand_attachments();
+        self.model.stream_completion(request, cx)
+    }
+
+    fn complete_batch(
+        &self,
+        mut request: LanguageModelRequest,
+        n: usize,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BatchCompletions>> {
+        request.expand_attachments();
+        self.model.complete_batch(request, n, cx)
+    }
+
+    fn use_any_tool(
+        &self,
+        mut request: LanguageModelRequest,
+        name: String,
+        description: String,
+        schema: serde_json::Value,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        request.expand_attachments();
+        self.model
+            .use_any_tool(request, name, description, schema, cx)
+    }
+
+    fn cache_configuration(&self) -> Option<LanguageModelCacheConfiguration> {
+        self.model.cache_configuration()
+    }
+
+    #[cfg(any(test, feature = "test-support"))]
+    fn as_fake(&self) -> &crate::provider::fake::FakeLanguageModel {
+        self.model.as_fake()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{provider::fake::FakeLanguageModel, Role};
+    use gpui::TestAppContext;
+    use unindent::Unindent as _;
+
+    fn message_with_attachments(
+        attachments: Vec<MessageAttachment>,
+    ) -> LanguageModelRequestMessage {
+        LanguageModelRequestMessage {
+            role: Role::User,
+            content: vec!["Why does this fail?".into()],
+            cache: false,
+            attachments,
+        }
+    }
+
+    #[test]
+    fn test_expand_attachments() {
+        let mut message = message_with_attachments(vec![
+            MessageAttachment::File {
+                path: "src/main.rs".into(),
+                text: "fn main() {}\n".into(),
+                language: Some("rust".into()),
+            },
+            MessageAttachment::Selection {
+                path: "README.md".into(),
+                range: 2..4,
+                text: "Run:\n```\ncargo run\n```".into(),
+            },
+            MessageAttachment::Terminal {
+                text: "error: oops".into(),
+            },
+            MessageAttachment::File {
+                path: "src/big.rs".into(),
+                text: "...".into(),
+                language: None,
+            }
+            .omitted(),
+        ]);
+        message.expand_attachments();
+
+        assert!(message.attachments.is_empty());
+        assert_eq!(
+            message.string_contents(),
+            "
+            src/main.rs
+            ```rust
+            fn main() {}
+            ```
+
+            README.md (lines 3-4)
+            ````
+            Run:
+            ```
+            cargo run
+            ```
+            ````
+
+            Terminal output:
+            ```
+            error: oops
+            ```
+
+            [file omitted: src/big.rs]
+
+            Why does this fail?"
+                .unindent()
+        );
+    }
+
+    #[gpui::test]
+    async fn test_models_see_expanded_attachments(cx: &mut TestAppContext) {
+        let fake_model = Arc::new(FakeLanguageModel::default());
+        let model = AttachmentExpandingLanguageModel::new(fake_model.clone());
+        let request = LanguageModelRequest {
+            messages: vec![message_with_attachments(vec![
+                MessageAttachment::Terminal {
+                    text: "error: oops".into(),
+                },
+            ])],
+            ..Default::default()
+        };
+        let mut expanded_request = request.clone();
+        expanded_request.expand_attachments();
+
+        // "Terminal output:", two fences, "error: oops", and the question.
+        let token_count = cx
+            .update(|cx| model.count_tokens(request.clone(), cx))
+            .await
+            .unwrap();
+        assert_eq!(token_count, 10);
+
+        let _events = model.stream_completion(request, &cx.to_async());
+        cx.run_until_parked();
+        assert_eq!(fake_model.pending_completions(), [expanded_request]);
+    }
+}
@@ -0,0 +1,245 @@
+use crate::LanguageModelCompletionEvent;
+use anyhow::Result;
+use futures::{
+    future::{self, Either},
+    stream::BoxStream,
+    Future, Stream, StreamExt,
+};
+use std::{collections::VecDeque, pin::Pin, time::Duration};
+
+/// How long [`coalesce_chunks`] holds text back for by default.
+pub const DEFAULT_COALESCE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// An item of a completion stream that [`coalesce_chunks`] can merge with the items after it.
+pub trait CoalescibleChunk {
+    /// The item's text, or `None` if it isn't text and must be passed on as it is.
+    fn text_mut(&mut self) -> Option<&mut String>;
+}
+
+impl CoalescibleChunk for Result<String> {
+    fn text_mut(&mut self) -> Option<&mut String> {
+        self.as_mut().ok()
+    }
+}
+
+impl CoalescibleChunk for Result<LanguageModelCompletionEvent> {
+    fn text_mut(&mut self) -> Option<&mut String> {
+        match self {
+            Ok(LanguageModelCompletionEvent::Text(text)) => Some(text),
+            _ => None,
+        }
+    }
+}
+
+/// Merges text that arrives in quick succession, so that consumers that re-render on every chunk
+/// aren't woken for every token.
+///
+/// Text is passed on right away if none was passed on in the last `interval`. Otherwise it's
+/// held back until the interval elapses, or until a newline arrives, whichever comes first.
+/// Held text is passed on as soon as the stream ends, errors, or produces anything other than
+/// text, so nothing is lost or reordered.
+///
+/// `timer` returns a future that resolves after the given duration.
+pub fn coalesce_chunks<T, F>(
+    chunks: impl Stream<Item = T> + Send + 'static,
+    interval: Duration,
+    timer: impl Fn(Duration) -> F + Send + 'static,
+) -> BoxStream<'static, T>
+where
+    T: CoalescibleChunk + Send + 'static,
+    F: Future<Output = ()> + Send + 'static,
+{
+    let state = Coalescer {
+        chunks: chunks.boxed(),
+        timer,
+        interval,
+        pending: None,
+        cooldown: None,
+        ready: VecDeque::new(),
+        done: false,
+    };
+    futures::stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(chunk) = state.ready.pop_front() {
+                return Some((chunk, state));
+            }
+            if state.done {
+                return None;
+            }
+
+            let next = match state.cooldown.as_mut() {
+                Some(cooldown) => match future::select(state.chunks.next(), cooldown).await {
+                    Either::Left((next, _)) => Some(next),
+                    Either::Right(_) => None,
+                },
+                None => Some(state.chunks.next().await),
+            };
+            let Some(next) = next else {
+                state.cooldown = None;
+                if let Some(pending) = state.pending.take() {
+                    state.emit(pending);
+                }
+                continue;
+            };
+
+            let Some(mut chunk) = next else {
+                state.done = true;
+                state.ready.extend(state.pending.take());
+                continue;
+            };
+            let Some(text) = chunk.text_mut() else {
+                state.ready.extend(state.pending.take());
+                state.ready.push_back(chunk);
+                continue;
+            };
+            let ends_line = text.contains('\n');
+            if state.cooldown.is_none() {
+                state.emit(chunk);
+            } else {
+                match state
+                    .pending
+                    .as_mut()
+                    .and_then(|pending| pending.text_mut())
+                {
+                    Some(pending) => pending.push_str(text),
+                    None => state.pending = Some(chunk),
+                }
+                if ends_line {
+                    if let Some(pending) = state.pending.take() {
+                        state.emit(pending);
+                    }
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+struct Coalescer<T, F, Timer> {
+    chunks: BoxStream<'static, T>,
+    timer: Timer,
+    interval: Duration,
+    /// The text received since text was last passed on, merged into the first chunk of it.
+    pending: Option<T>,
+    /// Resolves once the interval since text was last passed on has elapsed.
+    cooldown: Option<Pin<Box<F>>>,
+    ready: VecDeque<T>,
+    done: bool,
+}
+
+impl<T, F, Timer> Coalescer<T, F, Timer>
+where
+    F: Future<Output = ()>,
+    Timer: Fn(Duration) -> F,
+{
+    fn emit(&mut self, chunk: T) {
+        self.ready.push_back(chunk);
+        self.cooldown = Some(Box::pin((self.timer)(self.interval)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use futures::{
+        channel::{mpsc, oneshot},
+        FutureExt,
+    };
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    /// A timer that only fires when the test says so.
+    #[derive(Clone, Default)]
+    struct ManualTimer {
+        pending: Arc<Mutex<Vec<oneshot::Sender<()>>>>,
+    }
+
+    impl ManualTimer {
+        fn timer(&self) -> impl Fn(Duration) -> future::BoxFuture<'static, ()> {
+            let pending = self.pending.clone();
+            move |_| {
+                let (tx, rx) = oneshot::channel();
+                pending.lock().push(tx);
+                rx.map(|_| ()).boxed()
+            }
+        }
+
+        fn fire(&self) {
+            for tx in self.pending.lock().drain(..) {
+                tx.send(()).ok();
+            }
+        }
+    }
+
+    /// Returns the chunks the stream has ready, without waiting for more.
+    fn ready_chunks(output: &mut BoxStream<'static, Result<String>>) -> Vec<String> {
+        let mut chunks = Vec::new();
+        while let Some(Some(chunk)) = output.next().now_or_never() {
+            chunks.push(chunk.unwrap());
+        }
+        chunks
+    }
+
+    #[test]
+    fn test_coalescing_rapid_chunks() {
+        let timer = ManualTimer::default();
+        let (tx, rx) = mpsc::unbounded();
+        let mut output = coalesce_chunks(rx, DEFAULT_COALESCE_INTERVAL, timer.timer());
+
+        let mut input = String::new();
+        let mut emitted = Vec::new();
+        for ix in 0..100 {
+            let chunk = format!("t{ix} ");
+            input.push_str(&chunk);
+            tx.unbounded_send(Ok(chunk)).unwrap();
+            emitted.extend(ready_chunks(&mut output));
+            if ix % 10 == 9 {
+                timer.fire();
+                emitted.extend(ready_chunks(&mut output));
+            }
+        }
+        drop(tx);
+        emitted.extend(ready_chunks(&mut output));
+
+        // The first chunk is passed on right away, and the rest once per interval.
+        assert_eq!(emitted.len(), 11);
+        assert_eq!(emitted[0], "t0 ");
+        assert_eq!(emitted.concat(), input);
+    }
+
+    #[test]
+    fn test_coalescing_flushes_on_newline_end_and_error() {
+        let timer = ManualTimer::default();
+        let (tx, rx) = mpsc::unbounded();
+        let mut output = coalesce_chunks(rx, DEFAULT_COALESCE_INTERVAL, timer.timer());
+
+        tx.unbounded_send(Ok("a".to_string())).unwrap();
+        assert_eq!(ready_chunks(&mut output), ["a"]);
+        tx.unbounded_send(Ok("b".to_string())).unwrap();
+        tx.unbounded_send(Ok("c\nd".to_string())).unwrap();
+        assert_eq!(ready_chunks(&mut output), ["bc\nd"]);
+        tx.unbounded_send(Ok("e".to_string())).unwrap();
+        assert_eq!(ready_chunks(&mut output), Vec::<String>::new());
+        timer.fire();
+        assert_eq!(ready_chunks(&mut output), ["e"]);
+
+        // With nothing held back when the interval elapses, the next chunk is passed on right away.
+        timer.fire();
+        tx.unbounded_send(Ok("f".to_string())).unwrap();
+        assert_eq!(ready_chunks(&mut output), ["f"]);
+
+        tx.unbounded_send(Ok("g".to_string())).unwrap();
+        tx.unbounded_send(Err(anyhow!("overloaded"))).unwrap();
+        tx.unbounded_send(Ok("h".to_string())).unwrap();
+        drop(tx);
+        let mut rest = Vec::new();
+        while let Some(Some(chunk)) = output.next().now_or_never() {
+            rest.push(chunk.map_err(|error| error.to_string()));
+        }
+        assert_eq!(
+            rest,
+            [Ok("g".into()), Err("overloaded".into()), Ok("h".into())]
+        );
+    }
+}
@@ -0,0 +1,596 @@
+use crate::{
+    BatchCompletions, LanguageModel, LanguageModelCacheConfiguration, LanguageModelCompletionEvent,
+    LanguageModelId, LanguageModelName, LanguageModelProviderId, LanguageModelProviderName,
+    LanguageModelRequest, MessageContent, RequestMetricsRecorder, StopReason, TokenUsage,
+};
+use anyhow::Result;
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt, StreamExt};
+use gpui::{AppContext, AsyncAppContext, BackgroundExecutor};
+use project::{Fs, RenameOptions};
+use serde::Serialize;
+use std::{
+    ffi::OsString,
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use ui::IconName;
+use util::ResultExt;
+
+/// How requests and responses are written to the debug log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DebugLogSettings {
+    /// Whether to log the text of messages and responses, rather than just their length.
+    pub log_message_content: bool,
+    /// The size in bytes after which the log is rotated.
+    pub max_file_size: usize,
+    /// How many log files to keep, including the current one.
+    pub max_files: usize,
+}
+
+/// An NDJSON log of the requests sent to language models and the responses they produced.
+///
+/// The log only sees [`LanguageModelRequest`]s, so credentials and headers, which providers add
+/// when sending the request, never reach it.
+pub struct DebugLog {
+    fs: Arc<dyn Fs>,
+    path: PathBuf,
+    settings: DebugLogSettings,
+    /// Held while appending, so that concurrent completions don't interleave their writes.
+    write_lock: smol::lock::Mutex<()>,
+}
+
+/// A single line of the [`DebugLog`].
+#[derive(Debug, Serialize)]
+struct DebugLogEntry {
+    timestamp_ms: u64,
+    provider: String,
+    model: String,
+    request: serde_json::Value,
+    response: String,
+    stop_reason: Option<StopReason>,
+    usage: Option<TokenUsage>,
+    duration_ms: u64,
+    queue_time_ms: Option<u64>,
+    time_to_first_chunk_ms: Option<u64>,
+    chunk_count: usize,
+    bytes_received: usize,
+    error: Option<String>,
+    /// For a batch completion, the response of each completion in index order, or the error it
+    /// failed with. `response` is left empty, and `usage` covers the whole batch.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    batch_responses: Vec<Result<String, String>>,
+}
+
+impl DebugLog {
+    pub fn new(fs: Arc<dyn Fs>, path: PathBuf, settings: DebugLogSettings) -> Self {
+        Self {
+            fs,
+            path,
+            settings,
+            write_lock: Default::default(),
+        }
+    }
+
+    pub fn settings(&self) -> &DebugLogSettings {
+        &self.settings
+    }
+
+    fn start_entry(
+        &self,
+        model: &dyn LanguageModel,
+        request: &LanguageModelRequest,
+    ) -> DebugLogEntry {
+        let request = if self.settings.log_message_content {
+            serde_json::to_value(request).unwrap_or_default()
+        } else {
+            redact_request(request)
+        };
+        DebugLogEntry {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_millis() as u64),
+            provider: model.provider_id().0.to_string(),
+            model: model.id().0.to_string(),
+            request,
+            response: String::new(),
+            stop_reason: None,
+            usage: None,
+            duration_ms: 0,
+            queue_time_ms: None,
+            time_to_first_chunk_ms: None,
+            chunk_count: 0,
+            bytes_received: 0,
+            error: None,
+            batch_responses: Vec::new(),
+        }
+    }
+
+    async fn append(&self, mut entry: DebugLogEntry) -> Result<()> {
+        if !self.settings.log_message_content {
+            entry.response = format!("<{} characters>", entry.response.chars().count());
+            for response in entry.batch_responses.iter_mut().flatten() {
+                *response = format!("<{} characters>", response.chars().count());
+            }
+        }
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+
+        let _lock = self.write_lock.lock().await;
+        if let Some(dir) = self.path.parent() {
+            self.fs.create_dir(dir).await?;
+        }
+        let mut contents = if self.fs.is_file(&self.path).await {
+            self.fs.load(&self.path).await?
+        } else {
+            String::new()
+        };
+        if !contents.is_empty() && contents.len() + line.len() > self.settings.max_file_size {
+            self.rotate().await?;
+            contents.clear();
+        }
+        contents.push_str(&line);
+        self.fs.atomic_write(self.path.clone(), contents).await
+    }
+
+    /// Shifts each log file to the next suffix, dropping the oldest once there are `max_files`.
+    async fn rotate(&self) -> Result<()> {
+        for ix in (1..self.settings.max_files).rev() {
+            let source = self.rotated_path(ix - 1);
+            if self.fs.is_file(&source).await {
+                self.fs
+                    .rename(
+                        &source,
+                        &self.rotated_path(ix),
+                        RenameOptions {
+                            overwrite: true,
+                            ignore_if_exists: false,
+                        },
+                    )
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn rotated_path(&self, ix: usize) -> PathBuf {
+        if ix == 0 {
+            return self.path.clone();
+        }
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(format!(".{ix}"));
+        PathBuf::from(path)
+    }
+}
+
+/// Describes the request without the content of its messages.
+fn redact_request(request: &LanguageModelRequest) -> serde_json::Value {
+    let messages = request
+        .messages
+        .iter()
+        .map(|message| {
+            let content = message
+                .content
+                .iter()
+                .map(|content| match content {
+                    MessageContent::Text(text) => {
+                        format!("<text: {} characters>", text.chars().count())
+                    }
+                    MessageContent::Image(_) => "<image>".to_string(),
+                    MessageContent::ToolUse(tool_use) => format!("<tool use: {}>", tool_use.name),
+                    MessageContent::ToolResult(_) => "<tool result>".to_string(),
+                })
+                .collect::<Vec<_>>();
+            serde_json::json!({
+                "role": message.role,
+                "content": content,
+                "cache": message.cache,
+            })
+        })
+        .collect::<Vec<_>>();
+    let tools = request
+        .tools
+        .iter()
+        .map(|tool| tool.name.clone())
+        .collect::<Vec<_>>();
+    serde_json::json!({
+        "messages": messages,
+        "tools": tools,
+        "stop": request.stop,
+        "temperature": request.temperature,
+        "top_p": request.top_p,
+        "max_tokens": request.max_tokens,
+        "response_format": request.response_format,
+    })
+}
+
+/// A [`LanguageModel`] that writes each completion of the wrapped model to a [`DebugLog`].
+///
+/// A completion is logged once its stream ends, fails, or is dropped.
+pub struct LoggingLanguageModel {
+    model: Arc<dyn LanguageModel>,
+    log: Arc<DebugLog>,
+}
+
+impl LoggingLanguageModel {
+    pub fn new(model: Arc<dyn LanguageModel>, log: Arc<DebugLog>) -> Self {
+        Self { model, log }
+    }
+}
+
+impl LanguageModel for LoggingLanguageModel {
+    fn id(&self) -> LanguageModelId {
+        self.model.id()
+    }
+
+    fn name(&self) -> LanguageModelName {
+        self.model.name()
+    }
+
+    fn icon(&self) -> Option<IconName> {
+        self.model.icon()
+    }
+
+    fn provider_id(&self) -> LanguageModelProviderId {
+        self.model.provider_id()
+    }
+
+    fn provider_name(&self) -> LanguageModelProviderName {
+        self.model.provider_name()
+    }
+
+    fn telemetry_id(&self) -> String {
+        self.model.telemetry_id()
+    }
+
+    fn availability(&self) -> crate::LanguageModelAvailability {
+        self.model.availability()
+    }
+
+    fn max_token_count(&self) -> usize {
+        self.model.max_token_count()
+    }
+
+    fn max_output_tokens(&self) -> Option<u32> {
+        self.model.max_output_tokens()
+    }
+
+    fn count_tokens(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<usize>> {
+        self.model.count_tokens(request, cx)
+    }
+
+    fn stream_completion(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        let mut entry = PendingEntry {
+            entry: Some(self.log.start_entry(self.model.as_ref(), &request)),
+            metrics: RequestMetricsRecorder::start(cx.background_executor().clone()),
+            log: self.log.clone(),
+            executor: cx.background_executor().clone(),
+        };
+        let events = self.model.stream_completion(request, cx);
+        async move {
+            match events.await {
+                Ok(events) => Ok(log_events(events, entry)),
+                Err(error) => {
+                    entry.record_error(&error);
+                    Err(error)
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn complete_batch(
+        &self,
+        request: LanguageModelRequest,
+        n: usize,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BatchCompletions>> {
+        let mut entry = PendingEntry {
+            entry: Some(self.log.start_entry(self.model.as_ref(), &request)),
+            metrics: RequestMetricsRecorder::start(cx.background_executor().clone()),
+            log: self.log.clone(),
+            executor: cx.background_executor().clone(),
+        };
+        let batch = self.model.complete_batch(request, n, cx);
+        async move {
+            match batch.await {
+                Ok(batch) => {
+                    entry.record_batch(&batch);
+                    Ok(batch)
+                }
+                Err(error) => {
+                    entry.record_error(&error);
+                    Err(error)
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn use_any_tool(
+        &self,
+        request: LanguageModelRequest,
+        name: String,
+        description: String,
+        schema: serde_json::Value,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        self.model
+            .use_any_tool(request, name, description, schema, cx)
+    }
+
+    fn cache_configuration(&self) -> Option<LanguageModelCacheConfiguration> {
+        self.model.cache_configuration()
+    }
+
+    #[cfg(any(test, feature = "test-support"))]
+    fn as_fake(&self) -> &crate::provider::fake::FakeLanguageModel {
+        self.model.as_fake()
+    }
+}
+
+/// A completion that's still in progress, which is written to the log when dropped.
+struct PendingEntry {
+    entry: Option<DebugLogEntry>,
+    metrics: RequestMetricsRecorder,
+    log: Arc<DebugLog>,
+    executor: BackgroundExecutor,
+}
+
+impl PendingEntry {
+    fn entry(&mut self) -> &mut DebugLogEntry {
+        self.entry.as_mut().expect("entry is only taken on drop")
+    }
+
+    fn record_event(&mut self, event: &LanguageModelCompletionEvent) {
+        self.metrics.record_event(event);
+        let entry = self.entry();
+        match event {
+            LanguageModelCompletionEvent::Text(text) => entry.response.push_str(text),
+            LanguageModelCompletionEvent::Stop(reason) => entry.stop_reason = Some(reason.clone()),
+            LanguageModelCompletionEvent::UsageUpdate(usage) => entry.usage = Some(*usage),
+            LanguageModelCompletionEvent::ToolUse(_)
+            | LanguageModelCompletionEvent::FellBack { .. }
+            | LanguageModelCompletionEvent::Incomplete
+            | LanguageModelCompletionEvent::Queued(_) => {}
+        }
+    }
+
+    fn record_error(&mut self, error: &anyhow::Error) {
+        self.entry().error = Some(error.to_string());
+    }
+
+    fn record_batch(&mut self, batch: &BatchCompletions) {
+        let entry = self.entry();
+        entry.usage = batch.usage;
+        entry.batch_responses = batch
+            .completions
+            .iter()
+            .map(|completion| match &completion.result {
+                Ok(text) => Ok(text.clone()),
+                Err(error) => Err(error.to_string()),
+            })
+            .collect();
+    }
+}
+
+impl Drop for PendingEntry {
+    fn drop(&mut self) {
+        if let Some(mut entry) = self.entry.take() {
+            let metrics = self.metrics.metrics();
+            entry.duration_ms = metrics.total_duration.as_millis() as u64;
+            entry.queue_time_ms = metrics.queue_time.map(|time| time.as_millis() as u64);
+            entry.time_to_first_chunk_ms = metrics
+                .time_to_first_chunk
+                .map(|time| time.as_millis() as u64);
+            entry.chunk_count = metrics.chunk_count;
+            entry.bytes_received = metrics.bytes_received;
+            let log = self.log.clone();
+            self.executor
+                .spawn(async move { log.append(entry).await.log_err() })
+                .detach();
+        }
+    }
+}
+
+fn log_events(
+    events: BoxStream<'static, Result<LanguageModelCompletionEvent>>,
+    entry: PendingEntry,
+) -> BoxStream<'static, Result<LanguageModelCompletionEvent>> {
+    futures::stream::unfold((events, entry), |(mut events, mut entry)| async move {
+        let event = events.next().await?;
+        match &event {
+            Ok(event) => entry.record_event(event),
+            Err(error) => entry.record_error(error),
+        }
+        Some((event, (events, entry)))
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        provider::fake::FakeLanguageModel, LanguageModelRegistry, LanguageModelRequestMessage, Role,
+    };
+    use gpui::TestAppContext;
+    use project::FakeFs;
+    use std::time::Duration;
+
+    fn request(text: &str) -> LanguageModelRequest {
+        LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![text.into()],
+                cache: false,
+                attachments: Vec::new(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[gpui::test]
+    async fn test_debug_log(cx: &mut TestAppContext) {
+        let fs = FakeFs::new(cx.executor());
+        cx.update(LanguageModelRegistry::test);
+        cx.update(|cx| {
+            LanguageModelRegistry::global(cx).update(cx, |registry, _| {
+                registry.set_debug_log(
+                    fs.clone(),
+                    Some(DebugLogSettings {
+                        log_message_content: false,
+                        max_file_size: 1024 * 1024,
+                        max_files: 3,
+                    }),
+                )
+            })
+        });
+        let model = cx.update(|cx| {
+            LanguageModelRegistry::read_global(cx)
+                .active_model()
+                .unwrap()
+        });
+
+        let response = model.stream_completion_text(request("What is the secret?"), &cx.to_async());
+        let response = cx.executor().spawn(async move {
+            response
+                .await
+                .unwrap()
+                .map(|chunk| chunk.unwrap())
+                .collect::<String>()
+                .await
+        });
+        cx.run_until_parked();
+        cx.executor().advance_clock(Duration::from_millis(200));
+        model
+            .as_fake()
+            .stream_last_completion_response("The secret is 42.".into());
+        cx.run_until_parked();
+        cx.executor().advance_clock(Duration::from_millis(300));
+        model.as_fake().end_last_completion_stream();
+        assert_eq!(response.await, "The secret is 42.");
+        cx.run_until_parked();
+
+        let log = fs.load(paths::assistant_debug_log_file()).await.unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1);
+        let entry: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(entry["provider"], "fake");
+        assert_eq!(entry["model"], "fake");
+        assert_eq!(
+            entry["request"]["messages"][0]["content"][0],
+            "<text: 19 characters>"
+        );
+        assert_eq!(entry["response"], "<17 characters>");
+        assert_eq!(entry["duration_ms"], 500);
+        assert_eq!(entry["time_to_first_chunk_ms"], 200);
+        assert!(entry["queue_time_ms"].is_null());
+        assert_eq!(entry["chunk_count"], 1);
+        assert_eq!(entry["bytes_received"], 17);
+        assert!(entry["timestamp_ms"].is_u64());
+        assert!(entry["error"].is_null());
+        assert!(!log.contains("secret"));
+    }
+
+    #[gpui::test]
+    async fn test_debug_log_batch(cx: &mut TestAppContext) {
+        let fs = FakeFs::new(cx.executor());
+        cx.update(LanguageModelRegistry::test);
+        cx.update(|cx| {
+            LanguageModelRegistry::global(cx).update(cx, |registry, _| {
+                registry.set_debug_log(
+                    fs.clone(),
+                    Some(DebugLogSettings {
+                        log_message_content: true,
+                        max_file_size: 1024 * 1024,
+                        max_files: 3,
+                    }),
+                )
+            })
+        });
+        let model = cx.update(|cx| {
+            LanguageModelRegistry::read_global(cx)
+                .active_model()
+                .unwrap()
+        });
+
+        let batch = model.complete_batch(request("Suggest a name"), 2, &cx.to_async());
+        cx.run_until_parked();
+        let fake_model = model.as_fake();
+        fake_model.stream_nth_completion_response(0, "Ada".into());
+        fake_model.send_nth_completion_event(
+            0,
+            LanguageModelCompletionEvent::UsageUpdate(TokenUsage {
+                prompt_tokens: 5,
+                completion_tokens: 1,
+            }),
+        );
+        fake_model.end_nth_completion_stream(0);
+        fake_model.send_nth_completion_error(0, anyhow::anyhow!("overloaded"));
+        fake_model.end_nth_completion_stream(0);
+        batch.await.unwrap();
+        cx.run_until_parked();
+
+        // The batch is logged as a single entry.
+        let log = fs.load(paths::assistant_debug_log_file()).await.unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1);
+        let entry: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(
+            entry["batch_responses"],
+            serde_json::json!([{"Ok": "Ada"}, {"Err": "overloaded"}])
+        );
+        assert_eq!(
+            entry["usage"],
+            serde_json::json!({"prompt_tokens": 5, "completion_tokens": 1})
+        );
+    }
+
+    #[gpui::test]
+    async fn test_debug_log_rotation(cx: &mut TestAppContext) {
+        let fs = FakeFs::new(cx.executor());
+        let log = Arc::new(DebugLog::new(
+            fs.clone(),
+            PathBuf::from("/logs/assistant.ndjson"),
+            DebugLogSettings {
+                log_message_content: true,
+                max_file_size: 700,
+                max_files: 2,
+            },
+        ));
+        let model: Arc<dyn LanguageModel> = Arc::new(LoggingLanguageModel::new(
+            Arc::new(FakeLanguageModel::default()),
+            log,
+        ));
+
+        for ix in 0..4 {
+            let response =
+                model.stream_completion(request(&format!("request {ix}")), &cx.to_async());
+            cx.run_until_parked();
+            model
+                .as_fake()
+                .send_last_completion_error(anyhow::anyhow!("failure {ix}"));
+            model.as_fake().end_last_completion_stream();
+            response.await.unwrap().collect::<Vec<_>>().await;
+            cx.run_until_parked();
+        }
+
+        // Each file holds two entries, and only the newest two files are kept.
+        let current = fs.load("/logs/assistant.ndjson".as_ref()).await.unwrap();
+        let previous = fs.load("/logs/assistant.ndjson.1".as_ref()).await.unwrap();
+        assert_eq!(current.lines().count(), 2);
+        assert!(current.contains("request 3") && current.contains("failure 3"));
+        assert_eq!(previous.lines().count(), 2);
+        assert!(previous.contains("request 1"));
+        assert!(!fs.is_file("/logs/assistant.ndjson.2".as_ref()).await);
+    }
+}
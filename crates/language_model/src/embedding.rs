@@ -0,0 +1,158 @@
+use anyhow::{anyhow, Context as _, Result};
+use std::{future::Future, ops::Range};
+
+/// The most texts OpenAI accepts in a single embeddings request.
+pub(crate) const OPEN_AI_MAX_EMBEDDING_BATCH_SIZE: usize = 2048;
+/// The most tokens OpenAI accepts across all texts of a single embeddings request.
+pub(crate) const OPEN_AI_MAX_EMBEDDING_BATCH_TOKENS: usize = 300_000;
+
+/// Counts the tokens of each text, as seen by OpenAI's embedding models.
+pub(crate) fn count_open_ai_embedding_tokens(texts: &[String]) -> Vec<usize> {
+    let bpe = tiktoken_rs::cl100k_base_singleton();
+    let bpe = bpe.lock();
+    texts
+        .iter()
+        .map(|text| bpe.encode_ordinary(text).len())
+        .collect()
+}
+
+/// Splits texts into consecutive batches of at most `max_batch_size` texts and
+/// `max_batch_tokens` tokens.
+///
+/// A text that exceeds `max_batch_tokens` on its own is placed in a batch by itself.
+pub(crate) fn embedding_batches(
+    token_counts: &[usize],
+    max_batch_size: usize,
+    max_batch_tokens: usize,
+) -> Vec<Range<usize>> {
+    let mut batches = Vec::new();
+    let mut batch_start = 0;
+    let mut batch_tokens = 0;
+    for (ix, token_count) in token_counts.iter().copied().enumerate() {
+        let batch_len = ix - batch_start;
+        if batch_len > 0
+            && (batch_len == max_batch_size || batch_tokens + token_count > max_batch_tokens)
+        {
+            batches.push(batch_start..ix);
+            batch_start = ix;
+            batch_tokens = 0;
+        }
+        batch_tokens += token_count;
+    }
+    if batch_start < token_counts.len() {
+        batches.push(batch_start..token_counts.len());
+    }
+    batches
+}
+
+/// Embeds each batch of texts in turn, returning the embeddings in the order of `texts`.
+///
+/// Fails as soon as any batch fails, naming the batch in the error.
+pub(crate) async fn embed_in_batches<F, Fut>(
+    texts: Vec<String>,
+    batches: Vec<Range<usize>>,
+    mut embed_batch: F,
+) -> Result<Vec<Vec<f32>>>
+where
+    F: FnMut(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<Vec<f32>>>>,
+{
+    let batch_count = batches.len();
+    let mut embeddings = Vec::with_capacity(texts.len());
+    for (batch_ix, range) in batches.into_iter().enumerate() {
+        let batch_context = || {
+            format!(
+                "failed to embed batch {} of {batch_count} (texts {}..{})",
+                batch_ix + 1,
+                range.start,
+                range.end
+            )
+        };
+        let batch_embeddings = embed_batch(texts[range.clone()].to_vec())
+            .await
+            .with_context(batch_context)?;
+        if batch_embeddings.len() != range.len() {
+            return Err(anyhow!(
+                "expected {} embeddings, but got {}",
+                range.len(),
+                batch_embeddings.len()
+            ))
+            .with_context(batch_context);
+        }
+        embeddings.extend(batch_embeddings);
+    }
+    Ok(embeddings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use std::cell::RefCell;
+
+    #[test]
+    fn test_embedding_batches() {
+        assert_eq!(embedding_batches(&[], 2, 10), Vec::<Range<usize>>::new());
+
+        // Batches are cut by count...
+        assert_eq!(
+            embedding_batches(&[1, 1, 1, 1, 1], 2, 10),
+            vec![0..2, 2..4, 4..5]
+        );
+        // ...and by tokens, where filling a batch exactly doesn't start a new one.
+        assert_eq!(
+            embedding_batches(&[4, 6, 1, 9, 2], 10, 10),
+            vec![0..2, 2..4, 4..5]
+        );
+        // A text that's too large on its own gets a batch of its own.
+        assert_eq!(
+            embedding_batches(&[3, 15, 3], 10, 10),
+            vec![0..1, 1..2, 2..3]
+        );
+    }
+
+    #[test]
+    fn test_embed_in_batches() {
+        let texts = ["a", "b", "c", "d", "e"].map(String::from).to_vec();
+        let requested_batches = RefCell::new(Vec::new());
+        let embed_batch = |batch: Vec<String>| {
+            requested_batches.borrow_mut().push(batch.clone());
+            async move {
+                if batch.contains(&"d".to_string()) {
+                    Err(anyhow!("rate limited"))
+                } else {
+                    Ok(batch
+                        .iter()
+                        .map(|text| vec![text.as_bytes()[0] as f32])
+                        .collect())
+                }
+            }
+        };
+
+        let embeddings = embed_in_batches(texts[..3].to_vec(), vec![0..2, 2..3], embed_batch)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(embeddings, vec![vec![97.], vec![98.], vec![99.]]);
+
+        let error = embed_in_batches(texts.clone(), vec![0..2, 2..4, 4..5], embed_batch)
+            .now_or_never()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "failed to embed batch 2 of 3 (texts 2..4)"
+        );
+        // Batches after the failed one aren't requested.
+        assert_eq!(requested_batches.borrow().len(), 4);
+
+        // Empty input doesn't request anything.
+        requested_batches.borrow_mut().clear();
+        let embeddings = embed_in_batches(Vec::new(), embedding_batches(&[], 2, 10), embed_batch)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert!(embeddings.is_empty());
+        assert!(requested_batches.borrow().is_empty());
+    }
+}
@@ -0,0 +1,604 @@
+use crate::{
+    BatchCompletions, LanguageModel, LanguageModelCacheConfiguration, LanguageModelCompletionEvent,
+    LanguageModelId, LanguageModelName, LanguageModelProviderId, LanguageModelProviderName,
+    LanguageModelRegistry, LanguageModelRequest, ProviderStatus,
+};
+use anyhow::{anyhow, Result};
+use collections::HashMap;
+use futures::{
+    future::{self, BoxFuture},
+    stream::{self, BoxStream},
+    FutureExt, StreamExt,
+};
+use gpui::{AppContext, AsyncAppContext};
+use open_ai::OpenAiError;
+use parking_lot::Mutex;
+use std::{iter, sync::Arc};
+use ui::IconName;
+
+/// The health of the providers in fallback chains, as of each one's latest request.
+#[derive(Clone, Default)]
+pub struct FallbackHealth(Arc<Mutex<HashMap<LanguageModelProviderId, ProviderStatus>>>);
+
+impl FallbackHealth {
+    fn get(&self, provider: &LanguageModelProviderId) -> ProviderStatus {
+        self.0.lock().get(provider).cloned().unwrap_or_default()
+    }
+
+    fn set(&self, provider: LanguageModelProviderId, status: ProviderStatus) {
+        self.0.lock().insert(provider, status);
+    }
+}
+
+/// A [`LanguageModel`] that retries a request with the next provider in a chain when a
+/// provider fails before its response starts streaming.
+///
+/// The wrapped model's provider comes first in the chain. Each fallback provider serves its
+/// model with the same ID as the wrapped model, or else its first model, and providers that
+/// aren't authenticated are skipped. Once a response has started streaming, errors are
+/// propagated as-is, so partial output is never duplicated.
+pub struct FallbackLanguageModel {
+    model: Arc<dyn LanguageModel>,
+    fallback_providers: Vec<LanguageModelProviderId>,
+    health: FallbackHealth,
+}
+
+/// A model to try, in the order of the chain.
+struct Candidate {
+    provider_id: LanguageModelProviderId,
+    provider_name: LanguageModelProviderName,
+    model: Arc<dyn LanguageModel>,
+    is_primary: bool,
+}
+
+impl FallbackLanguageModel {
+    pub fn new(
+        model: Arc<dyn LanguageModel>,
+        fallback_providers: Vec<LanguageModelProviderId>,
+        health: FallbackHealth,
+    ) -> Self {
+        Self {
+            model,
+            fallback_providers,
+            health,
+        }
+    }
+
+    /// The providers in the chain, in the order they're tried.
+    fn chain(&self) -> Vec<LanguageModelProviderId> {
+        chain(&self.model, &self.fallback_providers)
+    }
+
+    /// Whether any provider in the chain is authenticated.
+    pub fn is_authenticated(&self, cx: &AppContext) -> bool {
+        let registry = LanguageModelRegistry::read_global(cx);
+        self.chain().iter().any(|provider_id| {
+            registry
+                .provider(provider_id)
+                .map_or(false, |provider| provider.is_authenticated(cx))
+        })
+    }
+
+    /// The health of each provider in the chain, in order, as of its latest request.
+    pub fn status(&self) -> Vec<(LanguageModelProviderId, ProviderStatus)> {
+        self.chain()
+            .into_iter()
+            .map(|provider_id| {
+                let status = self.health.get(&provider_id);
+                (provider_id, status)
+            })
+            .collect()
+    }
+}
+
+fn chain(
+    model: &Arc<dyn LanguageModel>,
+    fallback_providers: &[LanguageModelProviderId],
+) -> Vec<LanguageModelProviderId> {
+    let primary = model.provider_id();
+    iter::once(primary.clone())
+        .chain(
+            fallback_providers
+                .iter()
+                .filter(|provider_id| **provider_id != primary)
+                .cloned(),
+        )
+        .collect()
+}
+
+/// Returns the model that each authenticated provider in the chain serves, in order, and
+/// marks the providers that aren't authenticated.
+fn resolve_candidates(
+    model: &Arc<dyn LanguageModel>,
+    fallback_providers: &[LanguageModelProviderId],
+    health: &FallbackHealth,
+    cx: &AppContext,
+) -> Vec<Candidate> {
+    let registry = LanguageModelRegistry::read_global(cx);
+    let mut candidates = Vec::new();
+    for provider_id in chain(model, fallback_providers) {
+        let is_primary = provider_id == model.provider_id();
+        let provider = registry.provider(&provider_id);
+        if let Some(provider) = provider.as_ref() {
+            if !provider.is_authenticated(cx) {
+                health.set(provider_id, ProviderStatus::Unauthenticated);
+                continue;
+            }
+        }
+
+        let (provider_name, candidate_model) = if is_primary {
+            (model.provider_name(), Some(model.clone()))
+        } else {
+            let Some(provider) = provider else {
+                continue;
+            };
+            let models = provider.provided_models(cx);
+            let candidate_model = models
+                .iter()
+                .find(|candidate| candidate.id() == model.id())
+                .or(models.first())
+                .cloned();
+            (provider.name(), candidate_model)
+        };
+        if let Some(candidate_model) = candidate_model {
+            candidates.push(Candidate {
+                provider_id,
+                provider_name,
+                model: candidate_model,
+                is_primary,
+            });
+        }
+    }
+    candidates
+}
+
+/// Whether another provider might succeed where this error occurred. OpenAI's errors are only
+/// retried elsewhere if they're transient, since a request it rejects is likely to be rejected
+/// everywhere.
+fn should_fall_back(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<OpenAiError>()
+        .map_or(true, |error| error.is_retryable())
+}
+
+/// Starts a stream with each candidate in turn until one yields an item for which `started`
+/// returns true, returning the index of that candidate along with its stream.
+///
+/// Items before that, such as the time a request spent queued, are replayed at the start of the
+/// returned stream, and are dropped along with the candidate if it fails instead.
+async fn start_stream<T: Send + 'static>(
+    candidates: &[Candidate],
+    health: &FallbackHealth,
+    started: impl Fn(&T) -> bool,
+    mut start: impl FnMut(
+        &Arc<dyn LanguageModel>,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<T>>>>,
+) -> Result<(usize, BoxStream<'static, Result<T>>)> {
+    let mut last_error = None;
+    for (ix, candidate) in candidates.iter().enumerate() {
+        let result = match start(&candidate.model).await {
+            Ok(mut stream) => {
+                let mut leading = Vec::new();
+                loop {
+                    match stream.next().await {
+                        Some(Ok(item)) => {
+                            let is_start = started(&item);
+                            leading.push(Ok(item));
+                            if is_start {
+                                break Ok(stream::iter(leading).chain(stream).boxed());
+                            }
+                        }
+                        Some(Err(error)) => break Err(error),
+                        None => break Ok(stream::iter(leading).boxed()),
+                    }
+                }
+            }
+            Err(error) => Err(error),
+        };
+
+        match result {
+            Ok(stream) => {
+                health.set(candidate.provider_id.clone(), ProviderStatus::Ready);
+                return Ok((ix, stream));
+            }
+            Err(error) => {
+                health.set(
+                    candidate.provider_id.clone(),
+                    ProviderStatus::Error(error.to_string().into()),
+                );
+                if !should_fall_back(&error) {
+                    return Err(error);
+                }
+                if let Some(next) = candidates.get(ix + 1) {
+                    log::warn!(
+                        "{} failed, falling back to {}: {error}",
+                        candidate.provider_name.0,
+                        next.provider_name.0
+                    );
+                }
+                last_error = Some(error);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow!("no provider in the fallback chain is authenticated")))
+}
+
+impl LanguageModel for FallbackLanguageModel {
+    fn id(&self) -> LanguageModelId {
+        self.model.id()
+    }
+
+    fn name(&self) -> LanguageModelName {
+        self.model.name()
+    }
+
+    fn icon(&self) -> Option<IconName> {
+        self.model.icon()
+    }
+
+    fn provider_id(&self) -> LanguageModelProviderId {
+        self.model.provider_id()
+    }
+
+    fn provider_name(&self) -> LanguageModelProviderName {
+        self.model.provider_name()
+    }
+
+    fn telemetry_id(&self) -> String {
+        self.model.telemetry_id()
+    }
+
+    fn availability(&self) -> crate::LanguageModelAvailability {
+        self.model.availability()
+    }
+
+    fn max_token_count(&self) -> usize {
+        self.model.max_token_count()
+    }
+
+    fn max_output_tokens(&self) -> Option<u32> {
+        self.model.max_output_tokens()
+    }
+
+    fn count_tokens(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<usize>> {
+        self.model.count_tokens(request, cx)
+    }
+
+    fn stream_completion(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        let model = self.model.clone();
+        let fallback_providers = self.fallback_providers.clone();
+        let health = self.health.clone();
+        // Falling back needs the app to start each request, so it happens on the main thread.
+        let task = cx.spawn(|cx| async move {
+            let candidates =
+                cx.update(|cx| resolve_candidates(&model, &fallback_providers, &health, cx))?;
+            let (ix, events) = start_stream(
+                &candidates,
+                &health,
+                |event| !matches!(event, LanguageModelCompletionEvent::Queued(_)),
+                |model| model.stream_completion(request.clone(), &cx),
+            )
+            .await?;
+
+            let candidate = &candidates[ix];
+            if candidate.is_primary {
+                return Ok(events);
+            }
+            let fell_back = LanguageModelCompletionEvent::FellBack {
+                provider_name: candidate.provider_name.0.to_string(),
+            };
+            Ok(stream::once(future::ready(Ok(fell_back)))
+                .chain(events)
+                .boxed())
+        });
+        async move { task.await }.boxed()
+    }
+
+    fn complete_batch(
+        &self,
+        request: LanguageModelRequest,
+        n: usize,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BatchCompletions>> {
+        let model = self.model.clone();
+        let fallback_providers = self.fallback_providers.clone();
+        let health = self.health.clone();
+        // Only a failure of the whole batch falls back, since completions that failed on their
+        // own are reported alongside the ones that succeeded.
+        let task = cx.spawn(|cx| async move {
+            let candidates =
+                cx.update(|cx| resolve_candidates(&model, &fallback_providers, &health, cx))?;
+            let (_, mut batch) = start_stream(
+                &candidates,
+                &health,
+                |_| true,
+                |model| {
+                    let batch = model.complete_batch(request.clone(), n, &cx);
+                    async move { Ok(stream::once(batch).boxed()) }.boxed()
+                },
+            )
+            .await?;
+            batch
+                .next()
+                .await
+                .ok_or_else(|| anyhow!("batch completion ended without a result"))?
+        });
+        async move { task.await }.boxed()
+    }
+
+    fn use_any_tool(
+        &self,
+        request: LanguageModelRequest,
+        name: String,
+        description: String,
+        schema: serde_json::Value,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        let model = self.model.clone();
+        let fallback_providers = self.fallback_providers.clone();
+        let health = self.health.clone();
+        let task = cx.spawn(|cx| async move {
+            let candidates =
+                cx.update(|cx| resolve_candidates(&model, &fallback_providers, &health, cx))?;
+            let (_, stream) = start_stream(
+                &candidates,
+                &health,
+                |_| true,
+                |model| {
+                    model.use_any_tool(
+                        request.clone(),
+                        name.clone(),
+                        description.clone(),
+                        schema.clone(),
+                        &cx,
+                    )
+                },
+            )
+            .await?;
+            Ok(stream)
+        });
+        async move { task.await }.boxed()
+    }
+
+    fn cache_configuration(&self) -> Option<LanguageModelCacheConfiguration> {
+        self.model.cache_configuration()
+    }
+
+    #[cfg(any(test, feature = "test-support"))]
+    fn as_fake(&self) -> &crate::provider::fake::FakeLanguageModel {
+        self.model.as_fake()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        provider::fake::{FakeLanguageModel, FakeLanguageModelProvider},
+        LanguageModelProvider, LanguageModelRequestMessage, Role,
+    };
+    use gpui::{Context as _, TestAppContext};
+    use std::time::Duration;
+
+    struct Chain {
+        primary: FakeLanguageModelProvider,
+        secondary: FakeLanguageModelProvider,
+        model: FallbackLanguageModel,
+    }
+
+    impl Chain {
+        fn primary_model(&self) -> Arc<FakeLanguageModel> {
+            self.primary.fake_model().unwrap()
+        }
+
+        fn secondary_model(&self) -> Arc<FakeLanguageModel> {
+            self.secondary.fake_model().unwrap()
+        }
+    }
+
+    fn chain(cx: &mut TestAppContext) -> Chain {
+        let primary = FakeLanguageModelProvider::with_id("primary");
+        let secondary = FakeLanguageModelProvider::with_id("secondary");
+        cx.update(|cx| {
+            LanguageModelRegistry::test(cx);
+            LanguageModelRegistry::global(cx).update(cx, |registry, cx| {
+                registry.register_provider(primary.clone(), cx);
+                registry.register_provider(secondary.clone(), cx);
+            });
+        });
+        let model = FallbackLanguageModel::new(
+            primary.fake_model().unwrap(),
+            vec![secondary.id()],
+            FallbackHealth::default(),
+        );
+        Chain {
+            primary,
+            secondary,
+            model,
+        }
+    }
+
+    fn request() -> LanguageModelRequest {
+        LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec!["Hello".into()],
+                cache: false,
+                attachments: Vec::new(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[gpui::test]
+    async fn test_fall_back_when_first_provider_fails(cx: &mut TestAppContext) {
+        let chain = chain(cx);
+        let events = chain.model.stream_completion(request(), &cx.to_async());
+        cx.run_until_parked();
+        chain
+            .primary_model()
+            .send_last_completion_error(anyhow!("connection refused"));
+        cx.run_until_parked();
+        chain
+            .secondary_model()
+            .stream_last_completion_response("Hi!".into());
+        chain.secondary_model().end_last_completion_stream();
+
+        let events = events
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            events,
+            [
+                LanguageModelCompletionEvent::FellBack {
+                    provider_name: "secondary".into()
+                },
+                LanguageModelCompletionEvent::Text("Hi!".into()),
+            ]
+        );
+        assert_eq!(
+            chain.model.status(),
+            [
+                (
+                    chain.primary.id(),
+                    ProviderStatus::Error("connection refused".into())
+                ),
+                (chain.secondary.id(), ProviderStatus::Ready),
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_fall_back_when_provider_fails_after_queueing(cx: &mut TestAppContext) {
+        let chain = chain(cx);
+        let events = chain.model.stream_completion(request(), &cx.to_async());
+        cx.run_until_parked();
+        chain
+            .primary_model()
+            .send_last_completion_event(LanguageModelCompletionEvent::Queued(
+                Duration::from_millis(200),
+            ));
+        chain
+            .primary_model()
+            .send_last_completion_error(anyhow!("connection refused"));
+        cx.run_until_parked();
+        chain
+            .secondary_model()
+            .send_last_completion_event(LanguageModelCompletionEvent::Queued(
+                Duration::from_millis(50),
+            ));
+        chain
+            .secondary_model()
+            .stream_last_completion_response("Hi!".into());
+        chain.secondary_model().end_last_completion_stream();
+
+        let events = events
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            events,
+            [
+                LanguageModelCompletionEvent::FellBack {
+                    provider_name: "secondary".into()
+                },
+                LanguageModelCompletionEvent::Queued(Duration::from_millis(50)),
+                LanguageModelCompletionEvent::Text("Hi!".into()),
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_all_providers_fail(cx: &mut TestAppContext) {
+        let chain = chain(cx);
+        let events = chain.model.stream_completion(request(), &cx.to_async());
+        cx.run_until_parked();
+        chain
+            .primary_model()
+            .send_last_completion_error(anyhow!("connection refused"));
+        cx.run_until_parked();
+        chain
+            .secondary_model()
+            .send_last_completion_error(anyhow!("rate limited"));
+
+        let error = events.await.err().unwrap();
+        assert_eq!(error.to_string(), "rate limited");
+        assert_eq!(
+            chain.model.status(),
+            [
+                (
+                    chain.primary.id(),
+                    ProviderStatus::Error("connection refused".into())
+                ),
+                (
+                    chain.secondary.id(),
+                    ProviderStatus::Error("rate limited".into())
+                ),
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_no_fallback_after_stream_starts(cx: &mut TestAppContext) {
+        let chain = chain(cx);
+        let events = chain.model.stream_completion(request(), &cx.to_async());
+        cx.run_until_parked();
+        chain
+            .primary_model()
+            .stream_last_completion_response("Hel".into());
+        let mut events = events.await.unwrap();
+        assert_eq!(
+            events.next().await.unwrap().unwrap(),
+            LanguageModelCompletionEvent::Text("Hel".into())
+        );
+
+        chain
+            .primary_model()
+            .send_last_completion_error(anyhow!("connection reset"));
+        let error = events.next().await.unwrap().unwrap_err();
+        assert_eq!(error.to_string(), "connection reset");
+        cx.run_until_parked();
+        assert_eq!(chain.secondary_model().completion_count(), 0);
+    }
+
+    #[gpui::test]
+    async fn test_unauthenticated_providers_are_skipped(cx: &mut TestAppContext) {
+        let chain = chain(cx);
+        chain.primary.set_authenticated(false);
+        assert!(cx.update(|cx| chain.model.is_authenticated(cx)));
+
+        let events = chain.model.stream_completion(request(), &cx.to_async());
+        cx.run_until_parked();
+        assert_eq!(chain.primary_model().completion_count(), 0);
+        chain
+            .secondary_model()
+            .stream_last_completion_response("Hi!".into());
+        let mut events = events.await.unwrap();
+        assert_eq!(
+            events.next().await.unwrap().unwrap(),
+            LanguageModelCompletionEvent::FellBack {
+                provider_name: "secondary".into()
+            }
+        );
+        assert_eq!(
+            chain.model.status()[0],
+            (chain.primary.id(), ProviderStatus::Unauthenticated)
+        );
+
+        chain.secondary.set_authenticated(false);
+        assert!(!cx.update(|cx| chain.model.is_authenticated(cx)));
+    }
+}
@@ -1,13 +1,28 @@
+mod attachments;
+mod coalesce;
+mod debug_log;
+mod embedding;
+mod fallback;
 mod model;
 pub mod provider;
 mod rate_limiter;
 mod registry;
 mod request;
+mod request_metrics;
+mod response_cache;
+mod resume;
 mod role;
 pub mod settings;
+mod stream_transform;
+mod usage_meter;
 
 use anyhow::Result;
+pub use attachments::*;
 use client::{Client, UserStore};
+pub use coalesce::*;
+pub use debug_log::*;
+pub(crate) use embedding::*;
+pub use fallback::*;
 use futures::FutureExt;
 use futures::{future::BoxFuture, stream::BoxStream, StreamExt, TryStreamExt as _};
 use gpui::{
@@ -19,11 +34,17 @@ use proto::Plan;
 pub(crate) use rate_limiter::*;
 pub use registry::*;
 pub use request::*;
+pub use request_metrics::*;
+pub use response_cache::*;
+pub use resume::*;
 pub use role::*;
 use schemars::JsonSchema;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::{future::Future, sync::Arc};
+use std::{future::Future, ops::ControlFlow, sync::Arc, time::Duration};
+pub use stream_transform::*;
+use thiserror::Error;
 use ui::IconName;
+pub use usage_meter::*;
 
 pub fn init(
     user_store: Model<UserStore>,
@@ -31,8 +52,8 @@ pub fn init(
     fs: Arc<dyn Fs>,
     cx: &mut AppContext,
 ) {
-    settings::init(fs, cx);
-    registry::init(user_store, client, cx);
+    settings::init(fs.clone(), cx);
+    registry::init(user_store, client, fs, cx);
 }
 
 /// The availability of a [`LanguageModel`].
@@ -58,14 +79,74 @@ pub enum LanguageModelCompletionEvent {
     Stop(StopReason),
     Text(String),
     ToolUse(LanguageModelToolUse),
+    /// The number of tokens used by the completion so far. Each update supersedes the previous one.
+    UsageUpdate(TokenUsage),
+    /// The providers ahead of this one in a fallback chain failed before their responses
+    /// started, so this provider is serving the completion instead. Sent before any other event.
+    FellBack {
+        provider_name: String,
+    },
+    /// The response was interrupted and couldn't be resumed, so it may be missing its end.
+    /// Sent just before the error that interrupted it.
+    Incomplete,
+    /// The request waited this long for a free slot in the provider's concurrency limit before
+    /// it was sent. Sent before any of the response's events.
+    Queued(Duration),
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+impl TokenUsage {
+    pub fn total_tokens(&self) -> u32 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+/// The completions generated by [`LanguageModel::complete_batch`].
+#[derive(Debug, Default)]
+pub struct BatchCompletions {
+    /// The completions, in index order.
+    pub completions: Vec<BatchCompletion>,
+    /// The tokens used by the whole batch, if the provider reported them.
+    pub usage: Option<TokenUsage>,
+}
+
+/// One of the completions generated by [`LanguageModel::complete_batch`].
+#[derive(Debug)]
+pub struct BatchCompletion {
+    /// The position of this completion in the batch, which is stable regardless of the order
+    /// in which the completions finished.
+    pub index: usize,
+    pub result: Result<String>,
+}
+
+/// The error returned by `complete_json` when the response still couldn't be
+/// parsed after asking the model to correct it.
+#[derive(Debug, Error)]
+#[error("failed to parse the model's response as JSON")]
+pub struct JsonCompletionError {
+    /// The model's last response, as it was received.
+    pub raw_text: String,
+    #[source]
+    pub error: serde_json::Error,
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum StopReason {
     EndTurn,
+    /// The response reached the model's or the request's output token limit, so it's cut off.
     MaxTokens,
     ToolUse,
+    /// The provider withheld the rest of the response, because it was flagged by a content
+    /// filter or the model refused to respond.
+    ContentFilter,
+    /// A reason the provider reported that isn't one of the above.
+    Unknown(String),
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
@@ -123,6 +204,10 @@ pub trait LanguageModel: Send + Sync {
                         Ok(LanguageModelCompletionEvent::Text(text)) => Some(Ok(text)),
                         Ok(LanguageModelCompletionEvent::Stop(_)) => None,
                         Ok(LanguageModelCompletionEvent::ToolUse(_)) => None,
+                        Ok(LanguageModelCompletionEvent::UsageUpdate(_)) => None,
+                        Ok(LanguageModelCompletionEvent::FellBack { .. }) => None,
+                        Ok(LanguageModelCompletionEvent::Incomplete) => None,
+                        Ok(LanguageModelCompletionEvent::Queued(_)) => None,
                         Err(err) => Some(Err(err)),
                     }
                 })
@@ -131,6 +216,20 @@ pub trait LanguageModel: Send + Sync {
         .boxed()
     }
 
+    /// Generates `n` alternative completions of the same request, such as several suggestions
+    /// to choose between. The completions are returned in index order, and each one can fail
+    /// without affecting the others. An error is only returned if the batch as a whole failed.
+    ///
+    /// By default, this makes `n` separate requests, which the provider's rate limiter bounds.
+    fn complete_batch(
+        &self,
+        request: LanguageModelRequest,
+        n: usize,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BatchCompletions>> {
+        stream_batch_completions(self, request, n, cx)
+    }
+
     fn use_any_tool(
         &self,
         request: LanguageModelRequest,
@@ -150,6 +249,53 @@ pub trait LanguageModel: Send + Sync {
     }
 }
 
+/// Generates a batch of completions by streaming each one in its own request.
+pub(crate) fn stream_batch_completions<M: LanguageModel + ?Sized>(
+    model: &M,
+    request: LanguageModelRequest,
+    n: usize,
+    cx: &AsyncAppContext,
+) -> BoxFuture<'static, Result<BatchCompletions>> {
+    let completions = (0..n)
+        .map(|index| {
+            let events = model.stream_completion(request.clone(), cx);
+            async move {
+                // Each update reports the usage of the whole completion so far.
+                let mut usage = None;
+                let result = async {
+                    let mut events = events.await?;
+                    let mut text = String::new();
+                    while let Some(event) = events.next().await {
+                        match event? {
+                            LanguageModelCompletionEvent::Text(chunk) => text.push_str(&chunk),
+                            LanguageModelCompletionEvent::UsageUpdate(update) => {
+                                usage = Some(update)
+                            }
+                            _ => {}
+                        }
+                    }
+                    Ok(text)
+                }
+                .await;
+                (BatchCompletion { index, result }, usage)
+            }
+        })
+        .collect::<Vec<_>>();
+    async move {
+        let mut batch = BatchCompletions::default();
+        for (completion, usage) in futures::future::join_all(completions).await {
+            batch.completions.push(completion);
+            if let Some(usage) = usage {
+                let total = batch.usage.get_or_insert_with(TokenUsage::default);
+                total.prompt_tokens += usage.prompt_tokens;
+                total.completion_tokens += usage.completion_tokens;
+            }
+        }
+        Ok(batch)
+    }
+    .boxed()
+}
+
 impl dyn LanguageModel {
     pub fn use_tool<T: LanguageModelTool>(
         &self,
@@ -175,6 +321,61 @@ impl dyn LanguageModel {
         let schema_json = serde_json::to_value(&schema).unwrap();
         self.use_any_tool(request, T::name(), T::description(), schema_json, cx)
     }
+
+    /// Asks for a JSON object and parses it, ignoring a code fence around it. If the response
+    /// can't be parsed, the model is shown the error and asked once to correct it.
+    pub fn complete_json<T: 'static + Send + DeserializeOwned>(
+        self: Arc<Self>,
+        mut request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<T>> {
+        request.response_format = Some(ResponseFormat::JsonObject);
+        let task = cx.spawn(|cx| async move {
+            let raw_text = self
+                .stream_completion_text(request.clone(), &cx)
+                .await?
+                .try_collect::<String>()
+                .await?;
+            let error = match parse_json_response(&raw_text) {
+                Ok(response) => return Ok(response),
+                Err(error) => error,
+            };
+
+            let correction = format!(
+                "Your response couldn't be parsed: {error}. Respond with the corrected JSON object."
+            );
+            request.messages.push(LanguageModelRequestMessage {
+                role: Role::Assistant,
+                content: vec![raw_text.into()],
+                cache: false,
+                attachments: Vec::new(),
+            });
+            request.messages.push(LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![correction.into()],
+                cache: false,
+                attachments: Vec::new(),
+            });
+            let raw_text = self
+                .stream_completion_text(request, &cx)
+                .await?
+                .try_collect::<String>()
+                .await?;
+            parse_json_response(&raw_text)
+                .map_err(|error| JsonCompletionError { raw_text, error }.into())
+        });
+        async move { task.await }.boxed()
+    }
+}
+
+/// Parses a JSON response, which the model may have put in a code fence.
+fn parse_json_response<T: DeserializeOwned>(text: &str) -> serde_json::Result<T> {
+    let mut strip_code_fences = StripCodeFences::new();
+    let mut json = String::new();
+    if strip_code_fences.push(text, &mut json) == ControlFlow::Continue(()) {
+        strip_code_fences.finish(&mut json);
+    }
+    serde_json::from_str(&json)
 }
 
 pub trait LanguageModelTool: 'static + DeserializeOwned + JsonSchema {
@@ -182,6 +383,21 @@ pub trait LanguageModelTool: 'static + DeserializeOwned + JsonSchema {
     fn description() -> String;
 }
 
+/// Whether a provider can currently serve completions.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ProviderStatus {
+    /// The provider hasn't been checked yet.
+    #[default]
+    Checking,
+    Ready,
+    /// The provider has no credentials, or they were rejected.
+    Unauthenticated,
+    /// The provider couldn't be reached.
+    Error(SharedString),
+    /// Completions are waiting for the connection to the provider to be restored.
+    Queued,
+}
+
 pub trait LanguageModelProvider: 'static {
     fn id(&self) -> LanguageModelProviderId;
     fn name(&self) -> LanguageModelProviderName;
@@ -200,6 +416,36 @@ pub trait LanguageModelProvider: 'static {
         None
     }
     fn reset_credentials(&self, cx: &mut AppContext) -> Task<Result<()>>;
+    /// A small, fast model for background requests such as titling contexts, if the provider
+    /// has one.
+    fn summary_model(&self, _cx: &AppContext) -> Option<Arc<dyn LanguageModel>> {
+        None
+    }
+    /// Checks whether the provider can serve completions, using a cheap request that doesn't
+    /// count against the provider's concurrency limit.
+    fn check_status(&self, cx: &mut AppContext) -> Task<ProviderStatus> {
+        Task::ready(if self.is_authenticated(cx) {
+            ProviderStatus::Ready
+        } else {
+            ProviderStatus::Unauthenticated
+        })
+    }
+    /// Computes an embedding for each of the texts, returned in the same order.
+    fn embed(
+        &self,
+        texts: Vec<String>,
+        _cx: &AppContext,
+    ) -> BoxFuture<'static, Result<Vec<Vec<f32>>>> {
+        let result = if texts.is_empty() {
+            Ok(Vec::new())
+        } else {
+            Err(anyhow::anyhow!(
+                "{} doesn't support embeddings",
+                self.name().0
+            ))
+        };
+        futures::future::ready(result).boxed()
+    }
 }
 
 pub trait LanguageModelProviderState: 'static {
@@ -254,3 +500,167 @@ impl From<String> for LanguageModelProviderName {
         Self(SharedString::from(value))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::fake::FakeLanguageModel;
+    use anyhow::anyhow;
+    use gpui::TestAppContext;
+
+    #[gpui::test]
+    async fn test_complete_batch(cx: &mut TestAppContext) {
+        let model = FakeLanguageModel::default();
+        let batch = model.complete_batch(LanguageModelRequest::default(), 3, &cx.to_async());
+        cx.run_until_parked();
+        assert_eq!(model.completion_count(), 3);
+
+        // Finish the completions out of order, failing the middle one.
+        model.stream_nth_completion_response(2, "third".into());
+        model.send_nth_completion_event(
+            2,
+            LanguageModelCompletionEvent::UsageUpdate(TokenUsage {
+                prompt_tokens: 10,
+                completion_tokens: 1,
+            }),
+        );
+        model.end_nth_completion_stream(2);
+        model.send_nth_completion_error(1, anyhow!("overloaded"));
+        model.stream_nth_completion_response(0, "fir".into());
+        model.send_nth_completion_event(
+            0,
+            LanguageModelCompletionEvent::UsageUpdate(TokenUsage {
+                prompt_tokens: 10,
+                completion_tokens: 1,
+            }),
+        );
+        model.stream_nth_completion_response(0, "st".into());
+        model.send_nth_completion_event(
+            0,
+            LanguageModelCompletionEvent::UsageUpdate(TokenUsage {
+                prompt_tokens: 10,
+                completion_tokens: 2,
+            }),
+        );
+        model.end_nth_completion_stream(0);
+
+        let batch = batch.await.unwrap();
+        // Only the last update of each completion counts, since it covers the ones before.
+        assert_eq!(
+            batch.usage,
+            Some(TokenUsage {
+                prompt_tokens: 20,
+                completion_tokens: 3,
+            })
+        );
+        let batch = batch.completions;
+        assert_eq!(
+            batch
+                .iter()
+                .map(|completion| completion.index)
+                .collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+        assert_eq!(batch[0].result.as_ref().unwrap(), "first");
+        assert_eq!(
+            batch[1].result.as_ref().unwrap_err().to_string(),
+            "overloaded"
+        );
+        assert_eq!(batch[2].result.as_ref().unwrap(), "third");
+    }
+
+    #[gpui::test]
+    async fn test_complete_empty_batch(cx: &mut TestAppContext) {
+        let model = FakeLanguageModel::default();
+        let batch = model
+            .complete_batch(LanguageModelRequest::default(), 0, &cx.to_async())
+            .await
+            .unwrap();
+        assert!(batch.completions.is_empty());
+        assert_eq!(batch.usage, None);
+        assert_eq!(model.completion_count(), 0);
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Summary {
+        title: String,
+        tags: Vec<String>,
+    }
+
+    #[gpui::test]
+    async fn test_complete_json(cx: &mut TestAppContext) {
+        let model = Arc::new(FakeLanguageModel::default());
+        let expected = Summary {
+            title: "Fix".into(),
+            tags: vec!["bug".into()],
+        };
+
+        let summary = (model.clone() as Arc<dyn LanguageModel>)
+            .complete_json::<Summary>(LanguageModelRequest::default(), &cx.to_async());
+        cx.run_until_parked();
+        let request = model.pending_completions().pop().unwrap();
+        assert_eq!(request.response_format, Some(ResponseFormat::JsonObject));
+        model.stream_last_completion_response(r#"{"title": "Fix", "#.into());
+        model.stream_last_completion_response(r#""tags": ["bug"]}"#.into());
+        model.end_last_completion_stream();
+        assert_eq!(summary.await.unwrap(), expected);
+
+        // A code fence around the object is ignored.
+        let summary = (model.clone() as Arc<dyn LanguageModel>)
+            .complete_json::<Summary>(LanguageModelRequest::default(), &cx.to_async());
+        cx.run_until_parked();
+        model.stream_last_completion_response("```json\n".into());
+        model.stream_last_completion_response(r#"{"title": "Fix", "tags": ["bug"]}"#.into());
+        model.stream_last_completion_response("\n```\n".into());
+        model.end_last_completion_stream();
+        assert_eq!(summary.await.unwrap(), expected);
+        assert_eq!(model.completion_count(), 0);
+    }
+
+    #[gpui::test]
+    async fn test_complete_json_retries_invalid_response(cx: &mut TestAppContext) {
+        let model = Arc::new(FakeLanguageModel::default());
+        let summary = (model.clone() as Arc<dyn LanguageModel>)
+            .complete_json::<Summary>(LanguageModelRequest::default(), &cx.to_async());
+        cx.run_until_parked();
+        model.stream_last_completion_response(r#"{"title": "Fix", "tags": "bug"}"#.into());
+        model.end_last_completion_stream();
+        cx.run_until_parked();
+
+        // The retry shows the model its response and the parse error.
+        let retry = model.pending_completions().pop().unwrap();
+        assert_eq!(retry.messages.len(), 2);
+        assert_eq!(retry.messages[0].role, Role::Assistant);
+        assert_eq!(
+            retry.messages[0].string_contents(),
+            r#"{"title": "Fix", "tags": "bug"}"#
+        );
+        assert_eq!(retry.messages[1].role, Role::User);
+        assert!(retry.messages[1]
+            .string_contents()
+            .contains("invalid type: string \"bug\", expected a sequence"));
+        model.stream_last_completion_response(r#"{"title": "Fix", "tags": ["bug"]}"#.into());
+        model.end_last_completion_stream();
+        assert_eq!(
+            summary.await.unwrap(),
+            Summary {
+                title: "Fix".into(),
+                tags: vec!["bug".into()],
+            }
+        );
+
+        // If the retry is invalid too, the error includes the raw response.
+        let summary = (model.clone() as Arc<dyn LanguageModel>)
+            .complete_json::<Summary>(LanguageModelRequest::default(), &cx.to_async());
+        cx.run_until_parked();
+        model.stream_last_completion_response("Sure!".into());
+        model.end_last_completion_stream();
+        cx.run_until_parked();
+        model.stream_last_completion_response("Sure, here it is.".into());
+        model.end_last_completion_stream();
+        let error = summary.await.unwrap_err();
+        let error = error.downcast_ref::<JsonCompletionError>().unwrap();
+        assert_eq!(error.raw_text, "Sure, here it is.");
+        assert_eq!(model.completion_count(), 0);
+    }
+}
@@ -4,5 +4,8 @@ pub mod copilot_chat;
 #[cfg(any(test, feature = "test-support"))]
 pub mod fake;
 pub mod google;
+pub mod local;
 pub mod ollama;
 pub mod open_ai;
+#[cfg(any(test, feature = "test-support"))]
+pub mod recording;
@@ -1,7 +1,8 @@
 use crate::{
-    settings::AllLanguageModelSettings, LanguageModel, LanguageModelCacheConfiguration,
-    LanguageModelId, LanguageModelName, LanguageModelProvider, LanguageModelProviderId,
-    LanguageModelProviderName, LanguageModelProviderState, LanguageModelRequest, RateLimiter, Role,
+    report_queue_time, settings::AllLanguageModelSettings, LanguageModel,
+    LanguageModelCacheConfiguration, LanguageModelId, LanguageModelName, LanguageModelProvider,
+    LanguageModelProviderId, LanguageModelProviderName, LanguageModelProviderState,
+    LanguageModelRequest, RateLimiter, Role,
 };
 use crate::{LanguageModelCompletionEvent, LanguageModelToolUse, StopReason};
 use anthropic::{AnthropicError, ContentDelta, Event, ResponseContent};
@@ -57,6 +58,7 @@ pub struct AvailableModel {
 pub struct AnthropicLanguageModelProvider {
     http_client: Arc<dyn HttpClient>,
     state: gpui::Model<State>,
+    request_limiter: RateLimiter,
 }
 
 const ANTHROPIC_API_KEY_VAR: &str = "ANTHROPIC_API_KEY";
@@ -145,7 +147,11 @@ impl AnthropicLanguageModelProvider {
             }),
         });
 
-        Self { http_client, state }
+        Self {
+            http_client,
+            state,
+            request_limiter: RateLimiter::for_provider(cx),
+        }
     }
 }
 
@@ -214,7 +220,7 @@ impl LanguageModelProvider for AnthropicLanguageModelProvider {
                     model,
                     state: self.state.clone(),
                     http_client: self.http_client.clone(),
-                    request_limiter: RateLimiter::new(4),
+                    request_limiter: self.request_limiter.clone(),
                 }) as Arc<dyn LanguageModel>
             })
             .collect()
@@ -236,6 +242,13 @@ impl LanguageModelProvider for AnthropicLanguageModelProvider {
     fn reset_credentials(&self, cx: &mut AppContext) -> Task<Result<()>> {
         self.state.update(cx, |state, cx| state.reset_api_key(cx))
     }
+
+    fn summary_model(&self, cx: &AppContext) -> Option<Arc<dyn LanguageModel>> {
+        let id = LanguageModelId::from(anthropic::Model::Claude3Haiku.id().to_string());
+        self.provided_models(cx)
+            .into_iter()
+            .find(|model| model.id() == id)
+    }
 }
 
 pub struct AnthropicModel {
@@ -387,7 +400,11 @@ impl LanguageModel for AnthropicModel {
             let response = request.await.map_err(|err| anyhow!(err))?;
             Ok(map_to_language_model_completion_events(response))
         });
-        async move { Ok(future.await?.boxed()) }.boxed()
+        async move {
+            let events = future.await?;
+            Ok(report_queue_time(events.queue_time(), events))
+        }
+        .boxed()
     }
 
     fn cache_configuration(&self) -> Option<LanguageModelCacheConfiguration> {
@@ -422,18 +439,19 @@ impl LanguageModel for AnthropicModel {
             input_schema,
         }];
 
+        // The completion holds its own slot of the request limiter until its stream ends, which
+        // the tool's arguments are streamed from.
         let response = self.stream_completion(request, cx);
-        self.request_limiter
-            .run(async move {
-                let response = response.await?;
-                Ok(anthropic::extract_tool_args_from_events(
-                    tool_name,
-                    Box::pin(response.map_err(|e| anyhow!(e))),
-                )
-                .await?
-                .boxed())
-            })
-            .boxed()
+        async move {
+            let response = response.await?;
+            Ok(anthropic::extract_tool_args_from_events(
+                tool_name,
+                Box::pin(response.map_err(|e| anyhow!(e))),
+            )
+            .await?
+            .boxed())
+        }
+        .boxed()
     }
 }
 
@@ -519,10 +537,11 @@ pub fn map_to_language_model_completion_events(
                         Event::MessageDelta { delta, .. } => {
                             if let Some(stop_reason) = delta.stop_reason.as_deref() {
                                 let stop_reason = match stop_reason {
-                                    "end_turn" => StopReason::EndTurn,
+                                    "end_turn" | "stop_sequence" => StopReason::EndTurn,
                                     "max_tokens" => StopReason::MaxTokens,
                                     "tool_use" => StopReason::ToolUse,
-                                    _ => StopReason::EndTurn,
+                                    "refusal" => StopReason::ContentFilter,
+                                    _ => StopReason::Unknown(stop_reason.to_string()),
                                 };
 
                                 return Some((
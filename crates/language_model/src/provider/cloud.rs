@@ -1,14 +1,18 @@
-use super::open_ai::count_open_ai_tokens;
+use super::open_ai::{
+    count_open_ai_tokens, map_to_language_model_completion_events as open_ai_events,
+};
 use crate::provider::anthropic::map_to_language_model_completion_events;
 use crate::{
+    count_open_ai_embedding_tokens, embed_in_batches, embedding_batches, report_queue_time,
     settings::AllLanguageModelSettings, CloudModel, LanguageModel, LanguageModelCacheConfiguration,
     LanguageModelId, LanguageModelName, LanguageModelProviderId, LanguageModelProviderName,
-    LanguageModelProviderState, LanguageModelRequest, RateLimiter, ZedModel,
+    LanguageModelProviderState, LanguageModelRequest, ProviderStatus, RateLimiter, ZedModel,
+    OPEN_AI_MAX_EMBEDDING_BATCH_SIZE, OPEN_AI_MAX_EMBEDDING_BATCH_TOKENS,
 };
 use anthropic::AnthropicError;
 use anyhow::{anyhow, Result};
 use client::{Client, PerformCompletionParams, UserStore, EXPIRED_LLM_TOKEN_HEADER_NAME};
-use collections::BTreeMap;
+use collections::{BTreeMap, HashMap};
 use feature_flags::{FeatureFlagAppExt, LlmClosedBeta, ZedPro};
 use futures::{
     future::BoxFuture, stream::BoxStream, AsyncBufReadExt, FutureExt, Stream, StreamExt,
@@ -16,7 +20,7 @@ use futures::{
 };
 use gpui::{
     AnyElement, AnyView, AppContext, AsyncAppContext, FontWeight, Model, ModelContext,
-    Subscription, Task,
+    Subscription, Task, WeakModel,
 };
 use http_client::{AsyncBody, HttpClient, Method, Response};
 use isahc::config::Configurable;
@@ -24,6 +28,7 @@ use schemars::JsonSchema;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::value::RawValue;
 use settings::{Settings, SettingsStore};
+use sha2::{Digest, Sha256};
 use smol::{
     io::{AsyncReadExt, BufReader},
     lock::{RwLock, RwLockUpgradableReadGuard, RwLockWriteGuard},
@@ -34,6 +39,7 @@ use std::{
     sync::{Arc, LazyLock},
 };
 use strum::IntoEnumIterator;
+use thiserror::Error;
 use ui::{prelude::*, TintColor};
 
 use crate::{LanguageModelAvailability, LanguageModelCompletionEvent, LanguageModelProvider};
@@ -42,6 +48,7 @@ use super::anthropic::count_anthropic_tokens;
 
 pub const PROVIDER_ID: &str = "zed.dev";
 pub const PROVIDER_NAME: &str = "Zed";
+const EMBEDDING_MODEL: &str = "openai/text-embedding-3-small";
 
 const ZED_CLOUD_PROVIDER_ADDITIONAL_MODELS_JSON: Option<&str> =
     option_env!("ZED_CLOUD_PROVIDER_ADDITIONAL_MODELS_JSON");
@@ -59,6 +66,8 @@ fn zed_cloud_provider_additional_models() -> &'static [AvailableModel] {
 pub struct ZedDotDevSettings {
     pub available_models: Vec<AvailableModel>,
     pub low_speed_timeout: Option<Duration>,
+    pub queue_while_disconnected: bool,
+    pub reconnect_timeout: Duration,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
@@ -95,6 +104,7 @@ pub struct CloudLanguageModelProvider {
     client: Arc<Client>,
     llm_api_token: LlmApiToken,
     state: gpui::Model<State>,
+    request_limiter: RateLimiter,
     _maintain_client_status: Task<()>,
 }
 
@@ -103,6 +113,8 @@ pub struct State {
     user_store: Model<UserStore>,
     status: client::Status,
     accept_terms: Option<Task<Result<()>>>,
+    /// The number of completions waiting for the connection to be restored.
+    queued_completions: usize,
     _subscription: Subscription,
 }
 
@@ -119,6 +131,14 @@ impl State {
         })
     }
 
+    fn sign_out(&self, cx: &mut ModelContext<Self>) -> Task<Result<()>> {
+        let client = self.client.clone();
+        cx.spawn(move |this, mut cx| async move {
+            client.sign_out(&cx).await;
+            this.update(&mut cx, |_, cx| cx.notify())
+        })
+    }
+
     fn has_accepted_terms_of_service(&self, cx: &AppContext) -> bool {
         self.user_store
             .read(cx)
@@ -150,6 +170,7 @@ impl CloudLanguageModelProvider {
             user_store,
             status,
             accept_terms: None,
+            queued_completions: 0,
             _subscription: cx.observe_global::<SettingsStore>(|_, cx| {
                 cx.notify();
             }),
@@ -175,6 +196,7 @@ impl CloudLanguageModelProvider {
             client,
             state,
             llm_api_token: LlmApiToken::default(),
+            request_limiter: RateLimiter::for_provider(cx),
             _maintain_client_status: maintain_client_status,
         }
     }
@@ -284,7 +306,8 @@ impl LanguageModelProvider for CloudLanguageModelProvider {
                     model,
                     llm_api_token: self.llm_api_token.clone(),
                     client: self.client.clone(),
-                    request_limiter: RateLimiter::new(4),
+                    state: self.state.clone(),
+                    request_limiter: self.request_limiter.clone(),
                 }) as Arc<dyn LanguageModel>
             })
             .collect()
@@ -294,6 +317,17 @@ impl LanguageModelProvider for CloudLanguageModelProvider {
         !self.state.read(cx).is_signed_out()
     }
 
+    fn check_status(&self, cx: &mut AppContext) -> Task<ProviderStatus> {
+        let state = self.state.read(cx);
+        Task::ready(if state.is_signed_out() {
+            ProviderStatus::Unauthenticated
+        } else if state.queued_completions > 0 {
+            ProviderStatus::Queued
+        } else {
+            ProviderStatus::Ready
+        })
+    }
+
     fn authenticate(&self, _cx: &mut AppContext) -> Task<Result<()>> {
         Task::ready(Ok(()))
     }
@@ -365,19 +399,110 @@ impl LanguageModelProvider for CloudLanguageModelProvider {
         }
     }
 
-    fn reset_credentials(&self, _cx: &mut AppContext) -> Task<Result<()>> {
-        Task::ready(Ok(()))
+    fn reset_credentials(&self, cx: &mut AppContext) -> Task<Result<()>> {
+        self.state.update(cx, |state, cx| state.sign_out(cx))
+    }
+
+    fn embed(
+        &self,
+        texts: Vec<String>,
+        _cx: &AppContext,
+    ) -> BoxFuture<'static, Result<Vec<Vec<f32>>>> {
+        let client = self.client.clone();
+        async move {
+            // The server computes embeddings with OpenAI, so the same limits apply.
+            let batches = embedding_batches(
+                &count_open_ai_embedding_tokens(&texts),
+                OPEN_AI_MAX_EMBEDDING_BATCH_SIZE,
+                OPEN_AI_MAX_EMBEDDING_BATCH_TOKENS,
+            );
+            embed_in_batches(texts, batches, |batch| {
+                let response = client.request(proto::ComputeEmbeddings {
+                    model: EMBEDDING_MODEL.into(),
+                    texts: batch.clone(),
+                });
+                async move {
+                    let mut embeddings_by_digest = response
+                        .await?
+                        .embeddings
+                        .into_iter()
+                        .map(|embedding| (embedding.digest, embedding.dimensions))
+                        .collect::<HashMap<_, _>>();
+                    // The server returns the embeddings keyed by the digest of their text.
+                    batch
+                        .iter()
+                        .map(|text| {
+                            let digest = Sha256::digest(text.as_bytes()).to_vec();
+                            embeddings_by_digest
+                                .get(&digest)
+                                .cloned()
+                                .ok_or_else(|| anyhow!("server did not return an embedding"))
+                        })
+                        .collect()
+                }
+            })
+            .await
+        }
+        .boxed()
     }
 }
 
+#[derive(Clone)]
 pub struct CloudLanguageModel {
     id: LanguageModelId,
     model: CloudModel,
     llm_api_token: LlmApiToken,
     client: Arc<Client>,
+    state: gpui::Model<State>,
     request_limiter: RateLimiter,
 }
 
+/// The connection to zed.dev was lost and wasn't restored in time, so a completion couldn't be
+/// sent.
+#[derive(Debug, Error)]
+#[error("Zed is disconnected from zed.dev, so the request couldn't be sent.")]
+pub struct DisconnectedError {
+    /// The request that wasn't sent, so that it can be retried.
+    pub request: LanguageModelRequest,
+}
+
+/// Counts a completion as queued until it's dropped.
+struct QueuedCompletion {
+    state: WeakModel<State>,
+    cx: AsyncAppContext,
+}
+
+impl QueuedCompletion {
+    fn new(state: &Model<State>, cx: &mut AsyncAppContext) -> Result<Self> {
+        state.update(cx, |state, cx| {
+            state.queued_completions += 1;
+            cx.notify();
+        })?;
+        Ok(Self {
+            state: state.downgrade(),
+            cx: cx.clone(),
+        })
+    }
+}
+
+impl Drop for QueuedCompletion {
+    fn drop(&mut self) {
+        let state = self.state.clone();
+        // A cancelled completion may be dropped while the app is being updated, so the count
+        // is updated afterwards.
+        self.cx
+            .spawn(|mut cx| async move {
+                state
+                    .update(&mut cx, |state, cx| {
+                        state.queued_completions -= 1;
+                        cx.notify();
+                    })
+                    .ok();
+            })
+            .detach();
+    }
+}
+
 #[derive(Clone, Default)]
 struct LlmApiToken(Arc<RwLock<Option<String>>>);
 
@@ -427,89 +552,8 @@ impl CloudLanguageModel {
 
         Ok(response)
     }
-}
-
-impl LanguageModel for CloudLanguageModel {
-    fn id(&self) -> LanguageModelId {
-        self.id.clone()
-    }
-
-    fn name(&self) -> LanguageModelName {
-        LanguageModelName::from(self.model.display_name().to_string())
-    }
-
-    fn icon(&self) -> Option<IconName> {
-        self.model.icon()
-    }
-
-    fn provider_id(&self) -> LanguageModelProviderId {
-        LanguageModelProviderId(PROVIDER_ID.into())
-    }
 
-    fn provider_name(&self) -> LanguageModelProviderName {
-        LanguageModelProviderName(PROVIDER_NAME.into())
-    }
-
-    fn telemetry_id(&self) -> String {
-        format!("zed.dev/{}", self.model.id())
-    }
-
-    fn availability(&self) -> LanguageModelAvailability {
-        self.model.availability()
-    }
-
-    fn max_token_count(&self) -> usize {
-        self.model.max_token_count()
-    }
-
-    fn cache_configuration(&self) -> Option<LanguageModelCacheConfiguration> {
-        match &self.model {
-            CloudModel::Anthropic(model) => {
-                model
-                    .cache_configuration()
-                    .map(|cache| LanguageModelCacheConfiguration {
-                        max_cache_anchors: cache.max_cache_anchors,
-                        should_speculate: cache.should_speculate,
-                        min_total_token: cache.min_total_token,
-                    })
-            }
-            CloudModel::OpenAi(_) | CloudModel::Google(_) | CloudModel::Zed(_) => None,
-        }
-    }
-
-    fn count_tokens(
-        &self,
-        request: LanguageModelRequest,
-        cx: &AppContext,
-    ) -> BoxFuture<'static, Result<usize>> {
-        match self.model.clone() {
-            CloudModel::Anthropic(_) => count_anthropic_tokens(request, cx),
-            CloudModel::OpenAi(model) => count_open_ai_tokens(request, model, cx),
-            CloudModel::Google(model) => {
-                let client = self.client.clone();
-                let request = request.into_google(model.id().into());
-                let request = google_ai::CountTokensRequest {
-                    contents: request.contents,
-                };
-                async move {
-                    let request = serde_json::to_string(&request)?;
-                    let response = client
-                        .request(proto::CountLanguageModelTokens {
-                            provider: proto::LanguageModelProvider::Google as i32,
-                            request,
-                        })
-                        .await?;
-                    Ok(response.token_count as usize)
-                }
-                .boxed()
-            }
-            CloudModel::Zed(_) => {
-                count_open_ai_tokens(request, open_ai::Model::ThreePointFiveTurbo, cx)
-            }
-        }
-    }
-
-    fn stream_completion(
+    fn start_completion(
         &self,
         request: LanguageModelRequest,
         cx: &AsyncAppContext,
@@ -544,7 +588,11 @@ impl LanguageModel for CloudLanguageModel {
                         response_lines(response).map_err(AnthropicError::Other),
                     )))
                 });
-                async move { Ok(future.await?.boxed()) }.boxed()
+                async move {
+                    let events = future.await?;
+                    Ok(report_queue_time(events.queue_time(), events))
+                }
+                .boxed()
             }
             CloudModel::OpenAi(model) => {
                 let client = self.client.clone();
@@ -564,13 +612,11 @@ impl LanguageModel for CloudLanguageModel {
                         openai_low_speed_timeout,
                     )
                     .await?;
-                    Ok(open_ai::extract_text_from_events(response_lines(response)))
+                    Ok(open_ai_events(Box::pin(response_lines(response))))
                 });
                 async move {
-                    Ok(future
-                        .await?
-                        .map(|result| result.map(LanguageModelCompletionEvent::Text))
-                        .boxed())
+                    let events = future.await?;
+                    Ok(report_queue_time(events.queue_time(), events))
                 }
                 .boxed()
             }
@@ -597,10 +643,12 @@ impl LanguageModel for CloudLanguageModel {
                     )))
                 });
                 async move {
-                    Ok(future
-                        .await?
-                        .map(|result| result.map(LanguageModelCompletionEvent::Text))
-                        .boxed())
+                    let events = future.await?;
+                    let queue_time = events.queue_time();
+                    Ok(report_queue_time(
+                        queue_time,
+                        events.map(|result| result.map(LanguageModelCompletionEvent::Text)),
+                    ))
                 }
                 .boxed()
             }
@@ -623,17 +671,146 @@ impl LanguageModel for CloudLanguageModel {
                         None,
                     )
                     .await?;
-                    Ok(open_ai::extract_text_from_events(response_lines(response)))
+                    Ok(open_ai_events(Box::pin(response_lines(response))))
                 });
                 async move {
-                    Ok(future
-                        .await?
-                        .map(|result| result.map(LanguageModelCompletionEvent::Text))
-                        .boxed())
+                    let events = future.await?;
+                    Ok(report_queue_time(events.queue_time(), events))
+                }
+                .boxed()
+            }
+        }
+    }
+}
+
+impl LanguageModel for CloudLanguageModel {
+    fn id(&self) -> LanguageModelId {
+        self.id.clone()
+    }
+
+    fn name(&self) -> LanguageModelName {
+        LanguageModelName::from(self.model.display_name().to_string())
+    }
+
+    fn icon(&self) -> Option<IconName> {
+        self.model.icon()
+    }
+
+    fn provider_id(&self) -> LanguageModelProviderId {
+        LanguageModelProviderId(PROVIDER_ID.into())
+    }
+
+    fn provider_name(&self) -> LanguageModelProviderName {
+        LanguageModelProviderName(PROVIDER_NAME.into())
+    }
+
+    fn telemetry_id(&self) -> String {
+        format!("zed.dev/{}", self.model.id())
+    }
+
+    fn availability(&self) -> LanguageModelAvailability {
+        self.model.availability()
+    }
+
+    fn max_token_count(&self) -> usize {
+        self.model.max_token_count()
+    }
+
+    fn cache_configuration(&self) -> Option<LanguageModelCacheConfiguration> {
+        match &self.model {
+            CloudModel::Anthropic(model) => {
+                model
+                    .cache_configuration()
+                    .map(|cache| LanguageModelCacheConfiguration {
+                        max_cache_anchors: cache.max_cache_anchors,
+                        should_speculate: cache.should_speculate,
+                        min_total_token: cache.min_total_token,
+                    })
+            }
+            CloudModel::OpenAi(_) | CloudModel::Google(_) | CloudModel::Zed(_) => None,
+        }
+    }
+
+    fn count_tokens(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<usize>> {
+        match self.model.clone() {
+            CloudModel::Anthropic(_) => count_anthropic_tokens(request, cx),
+            CloudModel::OpenAi(model) => count_open_ai_tokens(request, model, cx),
+            CloudModel::Google(model) => {
+                let client = self.client.clone();
+                let request = request.into_google(model.id().into());
+                let request = google_ai::CountTokensRequest {
+                    contents: request.contents,
+                };
+                async move {
+                    let request = serde_json::to_string(&request)?;
+                    let response = client
+                        .request(proto::CountLanguageModelTokens {
+                            provider: proto::LanguageModelProvider::Google as i32,
+                            request,
+                        })
+                        .await?;
+                    Ok(response.token_count as usize)
                 }
                 .boxed()
             }
+            CloudModel::Zed(_) => {
+                count_open_ai_tokens(request, open_ai::Model::ThreePointFiveTurbo, cx)
+            }
+        }
+    }
+
+    fn stream_completion(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        // If the connection to zed.dev was lost, hold on to the request until it's restored
+        // instead of failing right away.
+        let status = *self.client.status().borrow();
+        let (queue_while_disconnected, reconnect_timeout) =
+            AllLanguageModelSettings::try_read_global(cx, |settings| {
+                (
+                    settings.zed_dot_dev.queue_while_disconnected,
+                    settings.zed_dot_dev.reconnect_timeout,
+                )
+            })
+            .unwrap_or_default();
+        if status.is_connected() || status.is_signed_out() || !queue_while_disconnected {
+            return self.start_completion(request, cx);
         }
+
+        let this = self.clone();
+        let task = cx.spawn(|mut cx| async move {
+            let reconnected = {
+                let _queued = QueuedCompletion::new(&this.state, &mut cx)?;
+                let mut status = this.client.status();
+                let reconnected = async move {
+                    while let Some(status) = status.next().await {
+                        if status.is_connected() {
+                            return true;
+                        } else if status.is_signed_out() {
+                            return false;
+                        }
+                    }
+                    false
+                };
+                let timeout = cx.background_executor().timer(reconnect_timeout);
+                smol::future::or(reconnected, async move {
+                    timeout.await;
+                    false
+                })
+                .await
+            };
+            if !reconnected {
+                return Err(DisconnectedError { request }.into());
+            }
+            this.start_completion(request, &cx).await
+        });
+        async move { task.await }.boxed()
     }
 
     fn use_any_tool(
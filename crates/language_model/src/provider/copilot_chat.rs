@@ -25,7 +25,7 @@ use ui::{
 
 use crate::settings::AllLanguageModelSettings;
 use crate::{
-    LanguageModel, LanguageModelId, LanguageModelName, LanguageModelProvider,
+    report_queue_time, LanguageModel, LanguageModelId, LanguageModelName, LanguageModelProvider,
     LanguageModelProviderId, LanguageModelProviderName, LanguageModelRequest, RateLimiter, Role,
 };
 use crate::{LanguageModelCompletionEvent, LanguageModelProviderState};
@@ -42,6 +42,7 @@ pub struct CopilotChatSettings {
 
 pub struct CopilotChatLanguageModelProvider {
     state: Model<State>,
+    request_limiter: RateLimiter,
 }
 
 pub struct State {
@@ -70,7 +71,10 @@ impl CopilotChatLanguageModelProvider {
             }
         });
 
-        Self { state }
+        Self {
+            state,
+            request_limiter: RateLimiter::for_provider(cx),
+        }
     }
 }
 
@@ -100,7 +104,7 @@ impl LanguageModelProvider for CopilotChatLanguageModelProvider {
             .map(|model| {
                 Arc::new(CopilotChatLanguageModel {
                     model,
-                    request_limiter: RateLimiter::new(4),
+                    request_limiter: self.request_limiter.clone(),
                 }) as Arc<dyn LanguageModel>
             })
             .collect()
@@ -244,10 +248,12 @@ impl LanguageModel for CopilotChatLanguageModel {
         });
 
         async move {
-            Ok(future
-                .await?
-                .map(|result| result.map(LanguageModelCompletionEvent::Text))
-                .boxed())
+            let events = future.await?;
+            let queue_time = events.queue_time();
+            Ok(report_queue_time(
+                queue_time,
+                events.map(|result| result.map(LanguageModelCompletionEvent::Text)),
+            ))
         }
         .boxed()
     }
@@ -265,7 +271,8 @@ impl LanguageModel for CopilotChatLanguageModel {
 }
 
 impl CopilotChatLanguageModel {
-    pub fn to_copilot_chat_request(&self, request: LanguageModelRequest) -> CopilotChatRequest {
+    pub fn to_copilot_chat_request(&self, mut request: LanguageModelRequest) -> CopilotChatRequest {
+        request.emulate_response_format();
         CopilotChatRequest::new(
             self.model.clone(),
             request
@@ -1,14 +1,19 @@
+use super::recording::{Recording, ReplayTiming, Replayer};
 use crate::{
     LanguageModel, LanguageModelCompletionEvent, LanguageModelId, LanguageModelName,
     LanguageModelProvider, LanguageModelProviderId, LanguageModelProviderName,
-    LanguageModelProviderState, LanguageModelRequest,
+    LanguageModelProviderState, LanguageModelRequest, ProviderStatus,
 };
 use futures::{channel::mpsc, future::BoxFuture, stream::BoxStream, FutureExt, StreamExt};
 use gpui::{AnyView, AppContext, AsyncAppContext, Task};
 use http_client::Result;
 use parking_lot::Mutex;
 use serde::Serialize;
-use std::sync::Arc;
+use sha2::{Digest, Sha256};
+use std::sync::{
+    atomic::{AtomicBool, Ordering::SeqCst},
+    Arc,
+};
 use ui::WindowContext;
 
 pub fn language_model_id() -> LanguageModelId {
@@ -27,8 +32,28 @@ pub fn provider_name() -> LanguageModelProviderName {
     LanguageModelProviderName::from("Fake".to_string())
 }
 
-#[derive(Clone, Default)]
-pub struct FakeLanguageModelProvider;
+#[derive(Clone)]
+pub struct FakeLanguageModelProvider {
+    id: LanguageModelProviderId,
+    /// The model that's provided every time, if any. Otherwise, a new model is provided each
+    /// time.
+    model: Option<Arc<FakeLanguageModel>>,
+    authenticated: Arc<AtomicBool>,
+    status: Arc<Mutex<ProviderStatus>>,
+    summary_model: Arc<FakeLanguageModel>,
+}
+
+impl Default for FakeLanguageModelProvider {
+    fn default() -> Self {
+        Self {
+            id: provider_id(),
+            model: None,
+            authenticated: Arc::new(AtomicBool::new(true)),
+            status: Arc::new(Mutex::new(ProviderStatus::Ready)),
+            summary_model: Arc::new(FakeLanguageModel::default()),
+        }
+    }
+}
 
 impl LanguageModelProviderState for FakeLanguageModelProvider {
     type ObservableEntity = ();
@@ -40,19 +65,26 @@ impl LanguageModelProviderState for FakeLanguageModelProvider {
 
 impl LanguageModelProvider for FakeLanguageModelProvider {
     fn id(&self) -> LanguageModelProviderId {
-        provider_id()
+        self.id.clone()
     }
 
     fn name(&self) -> LanguageModelProviderName {
-        provider_name()
+        if self.id == provider_id() {
+            provider_name()
+        } else {
+            LanguageModelProviderName(self.id.0.clone())
+        }
     }
 
     fn provided_models(&self, _: &AppContext) -> Vec<Arc<dyn LanguageModel>> {
-        vec![Arc::new(FakeLanguageModel::default())]
+        match &self.model {
+            Some(model) => vec![model.clone()],
+            None => vec![Arc::new(FakeLanguageModel::default())],
+        }
     }
 
     fn is_authenticated(&self, _: &AppContext) -> bool {
-        true
+        self.authenticated.load(SeqCst)
     }
 
     fn authenticate(&self, _: &mut AppContext) -> Task<Result<()>> {
@@ -66,12 +98,82 @@ impl LanguageModelProvider for FakeLanguageModelProvider {
     fn reset_credentials(&self, _: &mut AppContext) -> Task<Result<()>> {
         Task::ready(Ok(()))
     }
+
+    fn summary_model(&self, _: &AppContext) -> Option<Arc<dyn LanguageModel>> {
+        Some(self.summary_model.clone())
+    }
+
+    fn check_status(&self, _: &mut AppContext) -> Task<ProviderStatus> {
+        Task::ready(self.status.lock().clone())
+    }
+
+    fn embed(
+        &self,
+        texts: Vec<String>,
+        _: &AppContext,
+    ) -> BoxFuture<'static, Result<Vec<Vec<f32>>>> {
+        let embeddings = texts.iter().map(|text| embedding(text)).collect();
+        futures::future::ready(Ok(embeddings)).boxed()
+    }
+}
+
+/// The embedding the fake provider computes for a text, derived from the text's digest so that
+/// it's stable across runs.
+pub fn embedding(text: &str) -> Vec<f32> {
+    Sha256::digest(text.as_bytes())
+        .iter()
+        .take(8)
+        .map(|byte| *byte as f32 / 255.)
+        .collect()
 }
 
 impl FakeLanguageModelProvider {
+    /// Creates a provider with the given ID that always provides the same model, so that tests
+    /// with several providers can respond to each one's requests.
+    pub fn with_id(id: &str) -> Self {
+        let id = LanguageModelProviderId::from(id.to_string());
+        Self {
+            model: Some(Arc::new(FakeLanguageModel {
+                provider_id: id.clone(),
+                ..Default::default()
+            })),
+            id,
+            ..Default::default()
+        }
+    }
+
+    /// The model this provider always provides, if it was created with [`Self::with_id`].
+    pub fn fake_model(&self) -> Option<Arc<FakeLanguageModel>> {
+        self.model.clone()
+    }
+
+    pub fn set_authenticated(&self, authenticated: bool) {
+        self.authenticated.store(authenticated, SeqCst);
+    }
+
     pub fn test_model(&self) -> FakeLanguageModel {
         FakeLanguageModel::default()
     }
+
+    /// The model that background requests, such as titling contexts, are sent to. It's separate
+    /// from the active model so that tests can respond to each kind of request.
+    pub fn fake_summary_model(&self) -> Arc<FakeLanguageModel> {
+        self.summary_model.clone()
+    }
+
+    /// Creates a provider whose model replays the completions in the recording instead of
+    /// waiting for the test to respond to them.
+    pub fn replaying(recording: Recording, timing: ReplayTiming) -> Self {
+        Self {
+            model: Some(Arc::new(FakeLanguageModel::replaying(recording, timing))),
+            ..Default::default()
+        }
+    }
+
+    /// Sets the status reported by subsequent status checks.
+    pub fn set_status(&self, status: ProviderStatus) {
+        *self.status.lock() = status;
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -82,13 +184,48 @@ pub struct ToolUseRequest {
     pub schema: serde_json::Value,
 }
 
-#[derive(Default)]
 pub struct FakeLanguageModel {
-    current_completion_txs: Mutex<Vec<(LanguageModelRequest, mpsc::UnboundedSender<String>)>>,
+    provider_id: LanguageModelProviderId,
+    max_token_count: usize,
+    current_completion_txs: Mutex<
+        Vec<(
+            LanguageModelRequest,
+            mpsc::UnboundedSender<Result<LanguageModelCompletionEvent>>,
+        )>,
+    >,
     current_tool_use_txs: Mutex<Vec<(ToolUseRequest, mpsc::UnboundedSender<String>)>>,
+    replayer: Option<Replayer>,
+}
+
+impl Default for FakeLanguageModel {
+    fn default() -> Self {
+        Self {
+            provider_id: provider_id(),
+            max_token_count: 1000000,
+            current_completion_txs: Default::default(),
+            current_tool_use_txs: Default::default(),
+            replayer: None,
+        }
+    }
 }
 
 impl FakeLanguageModel {
+    pub fn with_max_token_count(max_token_count: usize) -> Self {
+        Self {
+            max_token_count,
+            ..Default::default()
+        }
+    }
+
+    /// Creates a model that answers each request with the completion recorded for it, failing
+    /// the test with a diff against the closest recorded request if there's none.
+    pub fn replaying(recording: Recording, timing: ReplayTiming) -> Self {
+        Self {
+            replayer: Some(Replayer::new(recording, timing)),
+            ..Default::default()
+        }
+    }
+
     pub fn pending_completions(&self) -> Vec<LanguageModelRequest> {
         self.current_completion_txs
             .lock()
@@ -102,13 +239,36 @@ impl FakeLanguageModel {
     }
 
     pub fn stream_completion_response(&self, request: &LanguageModelRequest, chunk: String) {
+        self.send_completion_event(request, LanguageModelCompletionEvent::Text(chunk));
+    }
+
+    /// Sends an arbitrary event, such as a tool use or a stop reason, to the
+    /// completion stream opened for the given request.
+    pub fn send_completion_event(
+        &self,
+        request: &LanguageModelRequest,
+        event: LanguageModelCompletionEvent,
+    ) {
+        self.send_completion_result(request, Ok(event));
+    }
+
+    /// Fails the completion stream opened for the given request with the given error.
+    pub fn send_completion_error(&self, request: &LanguageModelRequest, error: anyhow::Error) {
+        self.send_completion_result(request, Err(error));
+    }
+
+    fn send_completion_result(
+        &self,
+        request: &LanguageModelRequest,
+        result: Result<LanguageModelCompletionEvent>,
+    ) {
         let current_completion_txs = self.current_completion_txs.lock();
         let tx = current_completion_txs
             .iter()
             .find(|(req, _)| req == request)
             .map(|(_, tx)| tx)
             .unwrap();
-        tx.unbounded_send(chunk).unwrap();
+        tx.unbounded_send(result).unwrap();
     }
 
     pub fn end_completion_stream(&self, request: &LanguageModelRequest) {
@@ -121,10 +281,43 @@ impl FakeLanguageModel {
         self.stream_completion_response(self.pending_completions().last().unwrap(), chunk);
     }
 
+    pub fn send_last_completion_event(&self, event: LanguageModelCompletionEvent) {
+        self.send_completion_event(self.pending_completions().last().unwrap(), event);
+    }
+
+    pub fn send_last_completion_error(&self, error: anyhow::Error) {
+        self.send_completion_error(self.pending_completions().last().unwrap(), error);
+    }
+
     pub fn end_last_completion_stream(&self) {
         self.end_completion_stream(self.pending_completions().last().unwrap());
     }
 
+    /// Sends a chunk to the `ix`th pending completion stream, which tells apart the streams
+    /// of identical requests, such as those of a batch.
+    pub fn stream_nth_completion_response(&self, ix: usize, chunk: String) {
+        self.send_nth_completion_result(ix, Ok(LanguageModelCompletionEvent::Text(chunk)));
+    }
+
+    pub fn send_nth_completion_event(&self, ix: usize, event: LanguageModelCompletionEvent) {
+        self.send_nth_completion_result(ix, Ok(event));
+    }
+
+    pub fn send_nth_completion_error(&self, ix: usize, error: anyhow::Error) {
+        self.send_nth_completion_result(ix, Err(error));
+    }
+
+    fn send_nth_completion_result(&self, ix: usize, result: Result<LanguageModelCompletionEvent>) {
+        let current_completion_txs = self.current_completion_txs.lock();
+        let (_, tx) = &current_completion_txs[ix];
+        tx.unbounded_send(result).unwrap();
+    }
+
+    /// Ends the `ix`th pending completion stream, shifting the streams after it down by one.
+    pub fn end_nth_completion_stream(&self, ix: usize) {
+        self.current_completion_txs.lock().remove(ix);
+    }
+
     pub fn respond_to_last_tool_use<T: Serialize>(&self, response: T) {
         let response = serde_json::to_string(&response).unwrap();
         let mut current_tool_call_txs = self.current_tool_use_txs.lock();
@@ -143,11 +336,15 @@ impl LanguageModel for FakeLanguageModel {
     }
 
     fn provider_id(&self) -> LanguageModelProviderId {
-        provider_id()
+        self.provider_id.clone()
     }
 
     fn provider_name(&self) -> LanguageModelProviderName {
-        provider_name()
+        if self.provider_id == provider_id() {
+            provider_name()
+        } else {
+            LanguageModelProviderName(self.provider_id.0.clone())
+        }
     }
 
     fn telemetry_id(&self) -> String {
@@ -155,30 +352,36 @@ impl LanguageModel for FakeLanguageModel {
     }
 
     fn max_token_count(&self) -> usize {
-        1000000
+        self.max_token_count
     }
 
+    /// Counts each whitespace-separated word in the request as a token.
     fn count_tokens(
         &self,
-        _: LanguageModelRequest,
+        request: LanguageModelRequest,
         _: &AppContext,
     ) -> BoxFuture<'static, Result<usize>> {
-        futures::future::ready(Ok(0)).boxed()
+        let token_count = request
+            .messages
+            .iter()
+            .map(|message| message.string_contents().split_whitespace().count())
+            .sum();
+        futures::future::ready(Ok(token_count)).boxed()
     }
 
     fn stream_completion(
         &self,
         request: LanguageModelRequest,
-        _: &AsyncAppContext,
+        cx: &AsyncAppContext,
     ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        if let Some(replayer) = &self.replayer {
+            let events = replayer.replay(&request, cx.background_executor().clone());
+            return futures::future::ready(Ok(events)).boxed();
+        }
+
         let (tx, rx) = mpsc::unbounded();
         self.current_completion_txs.lock().push((request, tx));
-        async move {
-            Ok(rx
-                .map(|text| Ok(LanguageModelCompletionEvent::Text(text)))
-                .boxed())
-        }
-        .boxed()
+        async move { Ok(rx.boxed()) }.boxed()
     }
 
     fn use_any_tool(
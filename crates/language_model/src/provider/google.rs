@@ -19,8 +19,8 @@ use util::ResultExt;
 
 use crate::LanguageModelCompletionEvent;
 use crate::{
-    settings::AllLanguageModelSettings, LanguageModel, LanguageModelId, LanguageModelName,
-    LanguageModelProvider, LanguageModelProviderId, LanguageModelProviderName,
+    report_queue_time, settings::AllLanguageModelSettings, LanguageModel, LanguageModelId,
+    LanguageModelName, LanguageModelProvider, LanguageModelProviderId, LanguageModelProviderName,
     LanguageModelProviderState, LanguageModelRequest, RateLimiter,
 };
 
@@ -44,6 +44,7 @@ pub struct AvailableModel {
 pub struct GoogleLanguageModelProvider {
     http_client: Arc<dyn HttpClient>,
     state: gpui::Model<State>,
+    rate_limiter: RateLimiter,
 }
 
 pub struct State {
@@ -127,7 +128,11 @@ impl GoogleLanguageModelProvider {
             }),
         });
 
-        Self { http_client, state }
+        Self {
+            http_client,
+            state,
+            rate_limiter: RateLimiter::for_provider(cx),
+        }
     }
 }
 
@@ -185,7 +190,7 @@ impl LanguageModelProvider for GoogleLanguageModelProvider {
                     model,
                     state: self.state.clone(),
                     http_client: self.http_client.clone(),
-                    rate_limiter: RateLimiter::new(4),
+                    rate_limiter: self.rate_limiter.clone(),
                 }) as Arc<dyn LanguageModel>
             })
             .collect()
@@ -216,6 +221,13 @@ impl LanguageModelProvider for GoogleLanguageModelProvider {
             })
         })
     }
+
+    fn summary_model(&self, cx: &AppContext) -> Option<Arc<dyn LanguageModel>> {
+        let id = LanguageModelId::from(google_ai::Model::Gemini15Flash.id().to_string());
+        self.provided_models(cx)
+            .into_iter()
+            .find(|model| model.id() == id)
+    }
 }
 
 pub struct GoogleLanguageModel {
@@ -316,10 +328,12 @@ impl LanguageModel for GoogleLanguageModel {
             Ok(google_ai::extract_text_from_events(events).boxed())
         });
         async move {
-            Ok(future
-                .await?
-                .map(|result| result.map(LanguageModelCompletionEvent::Text))
-                .boxed())
+            let events = future.await?;
+            let queue_time = events.queue_time();
+            Ok(report_queue_time(
+                queue_time,
+                events.map(|result| result.map(LanguageModelCompletionEvent::Text)),
+            ))
         }
         .boxed()
     }
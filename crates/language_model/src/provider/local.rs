@@ -0,0 +1,699 @@
+use anyhow::{anyhow, Result};
+use futures::{future::BoxFuture, stream, FutureExt, Stream, StreamExt};
+use gpui::{AnyView, AppContext, AsyncAppContext, ModelContext, Subscription, Task};
+use http_client::{HttpClient, Uri};
+use open_ai::{
+    ApiFlavor, ApiOptions, FunctionDefinition, OpenAiError, ResponseStreamEvent, ToolChoice,
+    ToolDefinition,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use settings::{Settings, SettingsStore};
+use std::{collections::BTreeMap, future, sync::Arc, time::Duration};
+use ui::{prelude::*, ButtonLike, Indicator};
+use util::ResultExt;
+
+use super::open_ai::{count_open_ai_tokens, map_to_language_model_completion_events};
+use crate::{
+    report_queue_time, settings::AllLanguageModelSettings, LanguageModel,
+    LanguageModelCompletionEvent, LanguageModelId, LanguageModelName, LanguageModelProvider,
+    LanguageModelProviderId, LanguageModelProviderName, LanguageModelProviderState,
+    LanguageModelRequest, ProviderStatus, RateLimitGuard, RateLimiter, StopReason,
+};
+
+const PROVIDER_ID: &str = "local";
+const PROVIDER_NAME: &str = "Local";
+
+/// The context length assumed for discovered models, as OpenAI-compatible servers don't report
+/// it. Models can be configured with their actual context length in the settings.
+const DEFAULT_MAX_TOKENS: usize = 8192;
+
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct LocalSettings {
+    pub api_url: String,
+    pub low_speed_timeout: Option<Duration>,
+    pub available_models: Vec<AvailableModel>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct AvailableModel {
+    /// The model name the server expects in requests (e.g. "qwen2.5-coder-7b-instruct")
+    pub name: String,
+    /// The model's name in Zed's UI, such as in the model selector dropdown menu in the assistant panel.
+    pub display_name: Option<String>,
+    /// The context length the server was started with.
+    pub max_tokens: usize,
+}
+
+pub struct LocalLanguageModelProvider {
+    http_client: Arc<dyn HttpClient>,
+    state: gpui::Model<State>,
+    request_limiter: RateLimiter,
+}
+
+pub struct State {
+    http_client: Arc<dyn HttpClient>,
+    available_models: Vec<String>,
+    /// Whether the last attempt to list the server's models reached it.
+    reachable: bool,
+    last_error: Option<SharedString>,
+    _subscription: Subscription,
+}
+
+impl State {
+    fn is_authenticated(&self) -> bool {
+        self.reachable
+    }
+
+    fn fetch_models(&mut self, cx: &mut ModelContext<Self>) -> Task<Result<()>> {
+        let settings = &AllLanguageModelSettings::get_global(cx).local;
+        let http_client = self.http_client.clone();
+        let api_url = settings.api_url.clone();
+
+        // Local servers take no credentials, so being able to list their models is what makes
+        // the provider "authenticated".
+        cx.spawn(|this, mut cx| async move {
+            let result = discover_models(http_client.as_ref(), &api_url).await;
+            this.update(&mut cx, |this, cx| {
+                match &result {
+                    Ok(models) => {
+                        this.available_models = models.clone();
+                        this.reachable = true;
+                        this.last_error = None;
+                    }
+                    Err(error) => {
+                        this.available_models.clear();
+                        this.reachable = false;
+                        this.last_error = Some(error.to_string().into());
+                    }
+                }
+                cx.notify();
+            })?;
+            result.map(|_| ())
+        })
+    }
+
+    fn authenticate(&mut self, cx: &mut ModelContext<Self>) -> Task<Result<()>> {
+        if self.is_authenticated() {
+            Task::ready(Ok(()))
+        } else {
+            self.fetch_models(cx)
+        }
+    }
+}
+
+/// Lists the models served at `api_url`, sorted by name.
+///
+/// Older versions of Ollama don't implement `/v1/models`, so for URLs ending in `/v1` this falls
+/// back to Ollama's own listing.
+async fn discover_models(http_client: &dyn HttpClient, api_url: &str) -> Result<Vec<String>> {
+    let mut models =
+        match open_ai::list_models(http_client, api_url, "", &local_api_options()).await {
+            Ok(models) => models.into_iter().map(|model| model.id).collect::<Vec<_>>(),
+            Err(error) => {
+                if let Some(OpenAiError::Connection(_)) = error.downcast_ref() {
+                    return Err(connection_error(api_url));
+                }
+                let Some(ollama_url) = api_url.trim_end_matches('/').strip_suffix("/v1") else {
+                    return Err(error);
+                };
+                let Ok(models) = ollama::get_models(http_client, ollama_url, None).await else {
+                    return Err(error);
+                };
+                models.into_iter().map(|model| model.name).collect()
+            }
+        };
+    models.sort();
+    Ok(models)
+}
+
+fn local_api_options() -> ApiOptions {
+    ApiOptions {
+        flavor: ApiFlavor::Local,
+        ..Default::default()
+    }
+}
+
+/// The error for a server that couldn't be reached, naming the address it was expected at, since
+/// the usual cause is that it isn't running.
+fn connection_error(api_url: &str) -> anyhow::Error {
+    let address = api_url
+        .parse::<Uri>()
+        .ok()
+        .and_then(|uri| {
+            let host = uri.host()?.to_string();
+            let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+                Some("https") => 443,
+                _ => 80,
+            });
+            Some(format!("{host}:{port}"))
+        })
+        .unwrap_or_else(|| api_url.to_string());
+    anyhow!("Cannot connect to {address}. Is the server running?")
+}
+
+impl LocalLanguageModelProvider {
+    pub fn new(http_client: Arc<dyn HttpClient>, cx: &mut AppContext) -> Self {
+        let this = Self {
+            http_client: http_client.clone(),
+            state: cx.new_model(|cx| State {
+                http_client,
+                available_models: Default::default(),
+                reachable: false,
+                last_error: None,
+                _subscription: cx.observe_global::<SettingsStore>(|this: &mut State, cx| {
+                    this.fetch_models(cx).detach();
+                    cx.notify();
+                }),
+            }),
+            request_limiter: RateLimiter::for_provider(cx),
+        };
+        this.state
+            .update(cx, |state, cx| state.fetch_models(cx).detach());
+        this
+    }
+}
+
+impl LanguageModelProviderState for LocalLanguageModelProvider {
+    type ObservableEntity = State;
+
+    fn observable_entity(&self) -> Option<gpui::Model<Self::ObservableEntity>> {
+        Some(self.state.clone())
+    }
+}
+
+impl LanguageModelProvider for LocalLanguageModelProvider {
+    fn id(&self) -> LanguageModelProviderId {
+        LanguageModelProviderId(PROVIDER_ID.into())
+    }
+
+    fn name(&self) -> LanguageModelProviderName {
+        LanguageModelProviderName(PROVIDER_NAME.into())
+    }
+
+    fn icon(&self) -> IconName {
+        IconName::Server
+    }
+
+    fn provided_models(&self, cx: &AppContext) -> Vec<Arc<dyn LanguageModel>> {
+        let mut models = BTreeMap::default();
+
+        // Add models listed by the server
+        for name in self.state.read(cx).available_models.iter() {
+            models.insert(
+                name.clone(),
+                open_ai::Model::Custom {
+                    name: name.clone(),
+                    display_name: None,
+                    max_tokens: DEFAULT_MAX_TOKENS,
+                    max_output_tokens: None,
+                    max_completion_tokens: None,
+                },
+            );
+        }
+
+        // Override with available models from settings
+        for model in AllLanguageModelSettings::get_global(cx)
+            .local
+            .available_models
+            .iter()
+        {
+            models.insert(
+                model.name.clone(),
+                open_ai::Model::Custom {
+                    name: model.name.clone(),
+                    display_name: model.display_name.clone(),
+                    max_tokens: model.max_tokens,
+                    max_output_tokens: None,
+                    max_completion_tokens: None,
+                },
+            );
+        }
+
+        models
+            .into_values()
+            .map(|model| {
+                Arc::new(LocalLanguageModel {
+                    id: LanguageModelId::from(model.id().to_string()),
+                    model,
+                    http_client: self.http_client.clone(),
+                    request_limiter: self.request_limiter.clone(),
+                }) as Arc<dyn LanguageModel>
+            })
+            .collect()
+    }
+
+    fn is_authenticated(&self, cx: &AppContext) -> bool {
+        self.state.read(cx).is_authenticated()
+    }
+
+    fn authenticate(&self, cx: &mut AppContext) -> Task<Result<()>> {
+        self.state.update(cx, |state, cx| state.authenticate(cx))
+    }
+
+    fn configuration_view(&self, cx: &mut WindowContext) -> AnyView {
+        let state = self.state.clone();
+        cx.new_view(|cx| ConfigurationView::new(state, cx)).into()
+    }
+
+    fn reset_credentials(&self, cx: &mut AppContext) -> Task<Result<()>> {
+        self.state.update(cx, |state, cx| state.fetch_models(cx))
+    }
+
+    fn check_status(&self, cx: &mut AppContext) -> Task<ProviderStatus> {
+        let fetch_models = self.state.update(cx, |state, cx| state.fetch_models(cx));
+        cx.background_executor().spawn(async move {
+            match fetch_models.await {
+                Ok(()) => ProviderStatus::Ready,
+                Err(error) => ProviderStatus::Error(error.to_string().into()),
+            }
+        })
+    }
+}
+
+pub struct LocalLanguageModel {
+    id: LanguageModelId,
+    model: open_ai::Model,
+    http_client: Arc<dyn HttpClient>,
+    request_limiter: RateLimiter,
+}
+
+impl LocalLanguageModel {
+    fn stream_completion(
+        &self,
+        request: open_ai::Request,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<
+        'static,
+        Result<RateLimitGuard<futures::stream::BoxStream<'static, Result<ResponseStreamEvent>>>>,
+    > {
+        let http_client = self.http_client.clone();
+        let Ok((api_url, low_speed_timeout)) = cx.update(|cx| {
+            let settings = &AllLanguageModelSettings::get_global(cx).local;
+            (settings.api_url.clone(), settings.low_speed_timeout)
+        }) else {
+            return futures::future::ready(Err(anyhow!("App state dropped"))).boxed();
+        };
+
+        let future = self.request_limiter.stream(async move {
+            open_ai::stream_completion(
+                http_client.as_ref(),
+                &api_url,
+                "",
+                &local_api_options(),
+                request,
+                low_speed_timeout,
+            )
+            .await
+            .map_err(|error| match error.downcast_ref::<OpenAiError>() {
+                Some(OpenAiError::Connection(_)) => connection_error(&api_url),
+                _ => error,
+            })
+        });
+
+        future.boxed()
+    }
+}
+
+impl LanguageModel for LocalLanguageModel {
+    fn id(&self) -> LanguageModelId {
+        self.id.clone()
+    }
+
+    fn name(&self) -> LanguageModelName {
+        LanguageModelName::from(self.model.display_name().to_string())
+    }
+
+    fn provider_id(&self) -> LanguageModelProviderId {
+        LanguageModelProviderId(PROVIDER_ID.into())
+    }
+
+    fn provider_name(&self) -> LanguageModelProviderName {
+        LanguageModelProviderName(PROVIDER_NAME.into())
+    }
+
+    fn telemetry_id(&self) -> String {
+        format!("local/{}", self.model.id())
+    }
+
+    fn max_token_count(&self) -> usize {
+        self.model.max_token_count()
+    }
+
+    fn count_tokens(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<usize>> {
+        count_open_ai_tokens(request, self.model.clone(), cx)
+    }
+
+    fn stream_completion(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<
+        'static,
+        Result<futures::stream::BoxStream<'static, Result<LanguageModelCompletionEvent>>>,
+    > {
+        let request = request.into_open_ai(self.model.id().into(), None);
+        let completions = self.stream_completion(request, cx);
+        async move {
+            let completions = completions.await?;
+            let queue_time = completions.queue_time();
+            let events = map_to_language_model_completion_events(completions.boxed());
+            Ok(report_queue_time(queue_time, ensure_stop_event(events)))
+        }
+        .boxed()
+    }
+
+    fn use_any_tool(
+        &self,
+        request: LanguageModelRequest,
+        tool_name: String,
+        tool_description: String,
+        schema: serde_json::Value,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<futures::stream::BoxStream<'static, Result<String>>>> {
+        let mut request = request.into_open_ai(self.model.id().into(), None);
+        request.tool_choice = Some(ToolChoice::Other(ToolDefinition::Function {
+            function: FunctionDefinition {
+                name: tool_name.clone(),
+                description: None,
+                parameters: None,
+            },
+        }));
+        request.tools = vec![ToolDefinition::Function {
+            function: FunctionDefinition {
+                name: tool_name.clone(),
+                description: Some(tool_description),
+                parameters: Some(schema),
+            },
+        }];
+
+        // The completion holds its own slot of the request limiter until its stream ends, which
+        // the tool's arguments are streamed from.
+        let response = self.stream_completion(request, cx);
+        async move {
+            let response = response.await?;
+            Ok(
+                open_ai::extract_tool_args_from_events(tool_name, Box::pin(response))
+                    .await?
+                    .boxed(),
+            )
+        }
+        .boxed()
+    }
+}
+
+/// Ends the stream with a stop event when the server closed it without sending a
+/// `finish_reason`, as some local servers do.
+fn ensure_stop_event(
+    events: impl Stream<Item = Result<LanguageModelCompletionEvent>>,
+) -> impl Stream<Item = Result<LanguageModelCompletionEvent>> {
+    events
+        .map(Some)
+        .chain(stream::once(future::ready(None)))
+        .scan(false, |stopped, event| {
+            let event = match event {
+                Some(event) => {
+                    *stopped |= matches!(event, Ok(LanguageModelCompletionEvent::Stop(_)) | Err(_));
+                    Some(event)
+                }
+                None => {
+                    (!*stopped).then(|| Ok(LanguageModelCompletionEvent::Stop(StopReason::EndTurn)))
+                }
+            };
+            future::ready(Some(event))
+        })
+        .filter_map(future::ready)
+}
+
+struct ConfigurationView {
+    state: gpui::Model<State>,
+    loading_models_task: Option<Task<()>>,
+}
+
+impl ConfigurationView {
+    pub fn new(state: gpui::Model<State>, cx: &mut ViewContext<Self>) -> Self {
+        let loading_models_task = Some(cx.spawn({
+            let state = state.clone();
+            |this, mut cx| async move {
+                if let Some(task) = state
+                    .update(&mut cx, |state, cx| state.authenticate(cx))
+                    .log_err()
+                {
+                    task.await.log_err();
+                }
+                this.update(&mut cx, |this, cx| {
+                    this.loading_models_task = None;
+                    cx.notify();
+                })
+                .log_err();
+            }
+        }));
+
+        Self {
+            state,
+            loading_models_task,
+        }
+    }
+
+    fn retry_connection(&self, cx: &mut WindowContext) {
+        self.state
+            .update(cx, |state, cx| state.fetch_models(cx))
+            .detach_and_log_err(cx);
+    }
+}
+
+impl Render for ConfigurationView {
+    fn render(&mut self, cx: &mut ViewContext<Self>) -> impl IntoElement {
+        let state = self.state.read(cx);
+        let is_authenticated = state.is_authenticated();
+        let last_error = state.last_error.clone();
+        let api_url = AllLanguageModelSettings::get_global(cx)
+            .local
+            .api_url
+            .clone();
+
+        let local_intro = "Use models served by LM Studio, llama.cpp, Ollama, or any other server with an OpenAI-compatible API.";
+        let local_reqs = "The server must be running with at least one model loaded to use it in the assistant. Change `language_models.local.api_url` in your settings to use a different server.";
+
+        let mut inline_code_bg = cx.theme().colors().editor_background;
+        inline_code_bg.fade_out(0.5);
+
+        if self.loading_models_task.is_some() {
+            div().child(Label::new("Loading models...")).into_any()
+        } else {
+            v_flex()
+                .size_full()
+                .gap_3()
+                .child(
+                    v_flex()
+                        .size_full()
+                        .gap_2()
+                        .p_1()
+                        .child(Label::new(local_intro))
+                        .child(Label::new(local_reqs))
+                        .child(
+                            h_flex()
+                                .gap_0p5()
+                                .child(Label::new("Connecting to "))
+                                .child(
+                                    div()
+                                        .bg(inline_code_bg)
+                                        .px_1p5()
+                                        .rounded_md()
+                                        .child(Label::new(api_url)),
+                                ),
+                        )
+                        .when_some(last_error, |this, error| {
+                            this.child(Label::new(error).color(Color::Error))
+                        }),
+                )
+                .child(
+                    h_flex()
+                        .w_full()
+                        .pt_2()
+                        .justify_end()
+                        .child(if is_authenticated {
+                            // This is only a button to ensure the spacing is correct
+                            // it should stay disabled
+                            ButtonLike::new("connected")
+                                .disabled(true)
+                                // Since this won't ever be clickable, we can use the arrow cursor
+                                .cursor_style(gpui::CursorStyle::Arrow)
+                                .child(
+                                    h_flex()
+                                        .gap_2()
+                                        .child(Indicator::dot().color(Color::Success))
+                                        .child(Label::new("Connected"))
+                                        .into_any_element(),
+                                )
+                                .into_any_element()
+                        } else {
+                            Button::new("retry_local_models", "Connect")
+                                .icon_position(IconPosition::Start)
+                                .icon(IconName::ArrowCircle)
+                                .on_click(cx.listener(move |this, _, cx| this.retry_connection(cx)))
+                                .into_any_element()
+                        }),
+                )
+                .into_any()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LanguageModelRequestMessage, MessageContent, Role};
+    use gpui::TestAppContext;
+    use http_client::{FakeHttpClient, Response};
+
+    fn init_test(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let store = SettingsStore::test(cx);
+            cx.set_global(store);
+            AllLanguageModelSettings::register(cx);
+        });
+    }
+
+    fn set_api_url(api_url: &str, cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            cx.update_global::<SettingsStore, _>(|store, cx| {
+                store
+                    .set_user_settings(
+                        &serde_json::json!({ "language_models": { "local": { "api_url": api_url } } })
+                            .to_string(),
+                        cx,
+                    )
+                    .unwrap();
+            })
+        });
+        cx.run_until_parked();
+    }
+
+    fn model_names(provider: &LocalLanguageModelProvider, cx: &mut TestAppContext) -> Vec<String> {
+        cx.update(|cx| {
+            provider
+                .provided_models(cx)
+                .iter()
+                .map(|model| model.id().0.to_string())
+                .collect()
+        })
+    }
+
+    #[gpui::test]
+    async fn test_discover_models(cx: &mut TestAppContext) {
+        init_test(cx);
+        // Port 1234 serves the OpenAI listing, port 11434 is an older Ollama that only serves its
+        // own, and nothing listens on any other port.
+        let http_client = FakeHttpClient::create(|request| async move {
+            let uri = request.uri();
+            match (uri.port_u16(), uri.path()) {
+                (Some(1234), "/v1/models") => Ok(Response::builder()
+                    .status(200)
+                    .body(
+                        r#"{"object":"list","data":[{"id":"qwen2.5-coder-7b","object":"model"},{"id":"llama-3.2-3b","object":"model"}]}"#
+                            .into(),
+                    )
+                    .unwrap()),
+                (Some(11434), "/api/tags") => Ok(Response::builder()
+                    .status(200)
+                    .body(
+                        r#"{"models":[{"name":"llama3.1:latest","modified_at":"2024-08-01T00:00:00Z","size":4661224676,"digest":"abc","details":{"format":"gguf","family":"llama","families":null,"parameter_size":"8.0B","quantization_level":"Q4_0"}}]}"#
+                            .into(),
+                    )
+                    .unwrap()),
+                (Some(1234 | 11434), _) => Ok(Response::builder()
+                    .status(404)
+                    .body("404 page not found".into())
+                    .unwrap()),
+                _ => Err(anyhow!("connection refused")),
+            }
+        });
+        let provider = cx.update(|cx| LocalLanguageModelProvider::new(http_client, cx));
+
+        set_api_url("http://localhost:1234/v1", cx);
+        assert!(cx.update(|cx| provider.is_authenticated(cx)));
+        assert_eq!(
+            model_names(&provider, cx),
+            ["llama-3.2-3b", "qwen2.5-coder-7b"]
+        );
+
+        set_api_url("http://localhost:11434/v1", cx);
+        assert!(cx.update(|cx| provider.is_authenticated(cx)));
+        assert_eq!(model_names(&provider, cx), ["llama3.1:latest"]);
+
+        set_api_url("http://localhost:8080/v1", cx);
+        assert!(!cx.update(|cx| provider.is_authenticated(cx)));
+        assert!(model_names(&provider, cx).is_empty());
+        let status = cx.update(|cx| provider.check_status(cx)).await;
+        assert_eq!(
+            status,
+            ProviderStatus::Error(
+                "Cannot connect to localhost:8080. Is the server running?".into()
+            )
+        );
+    }
+
+    #[gpui::test]
+    async fn test_stream_completion_without_finish_reason(cx: &mut TestAppContext) {
+        init_test(cx);
+        // A llama.cpp-style stream: keep-alive comments, `data:` without a space, no index, no
+        // finish reason and no `[DONE]` marker.
+        let http_client = FakeHttpClient::create(|request| async move {
+            match request.uri().path() {
+                "/v1/models" => Ok(Response::builder()
+                    .status(200)
+                    .body(r#"{"data":[{"id":"qwen2.5-coder-7b"}]}"#.into())
+                    .unwrap()),
+                "/v1/chat/completions" => {
+                    assert!(request.headers().get("Authorization").is_none());
+                    Ok(Response::builder()
+                        .status(200)
+                        .body(
+                            concat!(
+                                ": keep-alive\n\n",
+                                "data:{\"choices\":[{\"delta\":{\"content\":\"Hello\"}}]}\n\n",
+                                "\r\n",
+                                "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\" world\"},\"finish_reason\":null}]}\r\n\r\n",
+                            )
+                            .into(),
+                        )
+                        .unwrap())
+                }
+                _ => Err(anyhow!("connection refused")),
+            }
+        });
+        let provider = cx.update(|cx| LocalLanguageModelProvider::new(http_client, cx));
+        set_api_url("http://localhost:1234/v1", cx);
+
+        let model = cx.update(|cx| provider.provided_models(cx).pop().unwrap());
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Text("Hi".into())],
+                cache: false,
+                attachments: Vec::new(),
+            }],
+            ..Default::default()
+        };
+        let events = model
+            .stream_completion(request, &cx.to_async())
+            .await
+            .unwrap()
+            .map(|event| event.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            events,
+            [
+                LanguageModelCompletionEvent::Text("Hello".into()),
+                LanguageModelCompletionEvent::Text(" world".into()),
+                LanguageModelCompletionEvent::Stop(StopReason::EndTurn),
+            ]
+        );
+    }
+}
@@ -15,8 +15,8 @@ use util::ResultExt;
 
 use crate::LanguageModelCompletionEvent;
 use crate::{
-    settings::AllLanguageModelSettings, LanguageModel, LanguageModelId, LanguageModelName,
-    LanguageModelProvider, LanguageModelProviderId, LanguageModelProviderName,
+    report_queue_time, settings::AllLanguageModelSettings, LanguageModel, LanguageModelId,
+    LanguageModelName, LanguageModelProvider, LanguageModelProviderId, LanguageModelProviderName,
     LanguageModelProviderState, LanguageModelRequest, RateLimiter, Role,
 };
 
@@ -49,6 +49,7 @@ pub struct AvailableModel {
 pub struct OllamaLanguageModelProvider {
     http_client: Arc<dyn HttpClient>,
     state: gpui::Model<State>,
+    request_limiter: RateLimiter,
 }
 
 pub struct State {
@@ -110,6 +111,7 @@ impl OllamaLanguageModelProvider {
                     cx.notify();
                 }),
             }),
+            request_limiter: RateLimiter::for_provider(cx),
         };
         this.state
             .update(cx, |state, cx| state.fetch_models(cx).detach());
@@ -170,7 +172,7 @@ impl LanguageModelProvider for OllamaLanguageModelProvider {
                     id: LanguageModelId::from(model.name.clone()),
                     model: model.clone(),
                     http_client: self.http_client.clone(),
-                    request_limiter: RateLimiter::new(4),
+                    request_limiter: self.request_limiter.clone(),
                 }) as Arc<dyn LanguageModel>
             })
             .collect()
@@ -211,7 +213,8 @@ pub struct OllamaLanguageModel {
 }
 
 impl OllamaLanguageModel {
-    fn to_ollama_request(&self, request: LanguageModelRequest) -> ChatRequest {
+    fn to_ollama_request(&self, mut request: LanguageModelRequest) -> ChatRequest {
+        request.emulate_response_format();
         ChatRequest {
             model: self.model.name.clone(),
             messages: request
@@ -234,9 +237,10 @@ impl OllamaLanguageModel {
             stream: true,
             options: Some(ChatOptions {
                 num_ctx: Some(self.model.max_tokens),
+                num_predict: request.max_tokens.map(|max_tokens| max_tokens as isize),
                 stop: Some(request.stop),
                 temperature: request.temperature.or(Some(1.0)),
-                ..Default::default()
+                top_p: request.top_p,
             }),
             tools: vec![],
         }
@@ -339,10 +343,12 @@ impl LanguageModel for OllamaLanguageModel {
         });
 
         async move {
-            Ok(future
-                .await?
-                .map(|result| result.map(LanguageModelCompletionEvent::Text))
-                .boxed())
+            let events = future.await?;
+            let queue_time = events.queue_time();
+            Ok(report_queue_time(
+                queue_time,
+                events.map(|result| result.map(LanguageModelCompletionEvent::Text)),
+            ))
         }
         .boxed()
     }
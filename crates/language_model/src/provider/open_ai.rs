@@ -1,30 +1,38 @@
 use anyhow::{anyhow, Result};
 use collections::BTreeMap;
 use editor::{Editor, EditorElement, EditorStyle};
-use futures::{future::BoxFuture, FutureExt, StreamExt};
+use futures::{
+    future::{BoxFuture, LocalBoxFuture},
+    FutureExt, Stream, StreamExt,
+};
 use gpui::{
     AnyView, AppContext, AsyncAppContext, FontStyle, ModelContext, Subscription, Task, TextStyle,
     View, WhiteSpace,
 };
 use http_client::HttpClient;
 use open_ai::{
-    stream_completion, FunctionDefinition, ResponseStreamEvent, ToolChoice, ToolDefinition,
+    retry, ApiOptions, FunctionDefinition, OpenAiEmbeddingModel, OpenAiError, ResponseStreamEvent,
+    RetryPolicy, ToolChoice, ToolDefinition,
 };
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use settings::{Settings, SettingsStore};
-use std::{sync::Arc, time::Duration};
+use std::{mem, path::PathBuf, pin::Pin, str::FromStr, sync::Arc, time::Duration};
 use strum::IntoEnumIterator;
 use theme::ThemeSettings;
+use thiserror::Error;
 use ui::{prelude::*, Icon, IconName, Tooltip};
 use util::ResultExt;
 
-use crate::LanguageModelCompletionEvent;
 use crate::{
-    settings::AllLanguageModelSettings, LanguageModel, LanguageModelId, LanguageModelName,
-    LanguageModelProvider, LanguageModelProviderId, LanguageModelProviderName,
-    LanguageModelProviderState, LanguageModelRequest, RateLimiter, Role,
+    count_open_ai_embedding_tokens, embed_in_batches, embedding_batches, report_queue_time,
+    settings::AllLanguageModelSettings, stream_batch_completions, BatchCompletion,
+    BatchCompletions, LanguageModel, LanguageModelId, LanguageModelName, LanguageModelProvider,
+    LanguageModelProviderId, LanguageModelProviderName, LanguageModelProviderState,
+    LanguageModelRequest, ProviderStatus, RateLimitGuard, RateLimiter, Role,
+    OPEN_AI_MAX_EMBEDDING_BATCH_SIZE, OPEN_AI_MAX_EMBEDDING_BATCH_TOKENS,
 };
+use crate::{LanguageModelCompletionEvent, LanguageModelToolUse, StopReason, TokenUsage};
 
 const PROVIDER_ID: &str = "openai";
 const PROVIDER_NAME: &str = "OpenAI";
@@ -33,10 +41,28 @@ const PROVIDER_NAME: &str = "OpenAI";
 pub struct OpenAiSettings {
     pub api_url: String,
     pub low_speed_timeout: Option<Duration>,
+    pub max_retries: usize,
+    pub stream_batch_completions: bool,
+    pub provider_flavor: open_ai::ApiFlavor,
+    pub organization_id: Option<String>,
+    pub extra_headers: BTreeMap<String, String>,
+    pub api_version: Option<String>,
+    pub api_key_path: Option<PathBuf>,
     pub available_models: Vec<AvailableModel>,
     pub needs_setting_migration: bool,
 }
 
+impl OpenAiSettings {
+    pub fn api_options(&self) -> ApiOptions {
+        ApiOptions {
+            flavor: self.provider_flavor,
+            organization_id: self.organization_id.clone(),
+            extra_headers: self.extra_headers.clone(),
+            api_version: self.api_version.clone(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct AvailableModel {
     pub name: String,
@@ -49,14 +75,120 @@ pub struct AvailableModel {
 pub struct OpenAiLanguageModelProvider {
     http_client: Arc<dyn HttpClient>,
     state: gpui::Model<State>,
+    request_limiter: RateLimiter,
 }
 
 pub struct State {
     api_key: Option<String>,
-    api_key_from_env: bool,
+    api_key_source: Option<ApiKeySource>,
+    credential_store: Arc<dyn CredentialStore>,
+    read_env_var: fn(&str) -> Option<String>,
+    http_client: Arc<dyn HttpClient>,
     _subscription: Subscription,
 }
 
+/// Where the API key in use was found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApiKeySource {
+    /// The platform's credential store, such as the system keychain.
+    CredentialStore,
+    /// The `OPENAI_API_KEY` environment variable.
+    EnvironmentVariable,
+    /// The file at the `api_key_path` setting.
+    File,
+    /// The key was entered this session but couldn't be saved to the credential store, so it's
+    /// only kept in memory.
+    Memory,
+}
+
+/// Persists the API key, abstracting over the platform's credential store.
+trait CredentialStore: Send + Sync {
+    fn read_api_key<'a>(
+        &'a self,
+        url: &'a str,
+        cx: &'a AsyncAppContext,
+    ) -> LocalBoxFuture<'a, Result<Option<String>>>;
+
+    fn write_api_key<'a>(
+        &'a self,
+        url: &'a str,
+        api_key: &'a str,
+        cx: &'a AsyncAppContext,
+    ) -> LocalBoxFuture<'a, Result<()>>;
+
+    fn delete_api_key<'a>(
+        &'a self,
+        url: &'a str,
+        cx: &'a AsyncAppContext,
+    ) -> LocalBoxFuture<'a, Result<()>>;
+}
+
+/// A credential store that keeps the API key in the system keychain.
+struct KeychainCredentialStore;
+
+impl CredentialStore for KeychainCredentialStore {
+    fn read_api_key<'a>(
+        &'a self,
+        url: &'a str,
+        cx: &'a AsyncAppContext,
+    ) -> LocalBoxFuture<'a, Result<Option<String>>> {
+        async move {
+            let Some((_, api_key)) = cx.update(|cx| cx.read_credentials(url))?.await? else {
+                return Ok(None);
+            };
+            Ok(Some(String::from_utf8(api_key)?))
+        }
+        .boxed_local()
+    }
+
+    fn write_api_key<'a>(
+        &'a self,
+        url: &'a str,
+        api_key: &'a str,
+        cx: &'a AsyncAppContext,
+    ) -> LocalBoxFuture<'a, Result<()>> {
+        async move {
+            cx.update(|cx| cx.write_credentials(url, "Bearer", api_key.as_bytes()))?
+                .await
+        }
+        .boxed_local()
+    }
+
+    fn delete_api_key<'a>(
+        &'a self,
+        url: &'a str,
+        cx: &'a AsyncAppContext,
+    ) -> LocalBoxFuture<'a, Result<()>> {
+        async move { cx.update(|cx| cx.delete_credentials(url))?.await }.boxed_local()
+    }
+}
+
+/// Why a candidate API key could not be used.
+#[derive(Debug, Error)]
+pub enum AuthenticationError {
+    #[error("The API key was rejected by OpenAI.")]
+    InvalidApiKey,
+    #[error("Could not reach OpenAI: {0}")]
+    NetworkError(anyhow::Error),
+    #[error("Failed to validate the API key: {0}")]
+    Other(anyhow::Error),
+}
+
+impl From<OpenAiError> for AuthenticationError {
+    fn from(error: OpenAiError) -> Self {
+        match error {
+            OpenAiError::Connection(error) => Self::NetworkError(error),
+            OpenAiError::Api { status, .. }
+                if status == http_client::StatusCode::UNAUTHORIZED
+                    || status == http_client::StatusCode::FORBIDDEN =>
+            {
+                Self::InvalidApiKey
+            }
+            error => Self::Other(error.into()),
+        }
+    }
+}
+
 const OPENAI_API_KEY_VAR: &str = "OPENAI_API_KEY";
 
 impl State {
@@ -64,54 +196,111 @@ impl State {
         self.api_key.is_some()
     }
 
+    /// Forgets the API key, deleting it from the credential store if it came from there.
+    ///
+    /// Keys from the environment or from a file are kept, since they'd be found again on the
+    /// next authentication.
     fn reset_api_key(&self, cx: &mut ModelContext<Self>) -> Task<Result<()>> {
-        let settings = &AllLanguageModelSettings::get_global(cx).openai;
-        let delete_credentials = cx.delete_credentials(&settings.api_url);
+        let api_url = AllLanguageModelSettings::get_global(cx)
+            .openai
+            .api_url
+            .clone();
+        let api_key_source = self.api_key_source;
+        let credential_store = self.credential_store.clone();
         cx.spawn(|this, mut cx| async move {
-            delete_credentials.await.log_err();
+            match api_key_source {
+                Some(ApiKeySource::EnvironmentVariable) | Some(ApiKeySource::File) => {
+                    return Ok(());
+                }
+                Some(ApiKeySource::CredentialStore) => {
+                    credential_store
+                        .delete_api_key(&api_url, &cx)
+                        .await
+                        .log_err();
+                }
+                Some(ApiKeySource::Memory) | None => {}
+            }
             this.update(&mut cx, |this, cx| {
                 this.api_key = None;
-                this.api_key_from_env = false;
+                this.api_key_source = None;
                 cx.notify();
             })
         })
     }
 
+    /// Validates the candidate API key against the API, only storing it if it's accepted.
+    ///
+    /// On failure, the returned error can be downcast to an [`AuthenticationError`]. When the
+    /// key is accepted but can't be saved to the credential store, it's kept in memory for the
+    /// rest of the session instead.
     fn set_api_key(&mut self, api_key: String, cx: &mut ModelContext<Self>) -> Task<Result<()>> {
         let settings = &AllLanguageModelSettings::get_global(cx).openai;
-        let write_credentials =
-            cx.write_credentials(&settings.api_url, "Bearer", api_key.as_bytes());
+        let api_url = settings.api_url.clone();
+        let api_options = settings.api_options();
+        let http_client = self.http_client.clone();
+        let credential_store = self.credential_store.clone();
 
         cx.spawn(|this, mut cx| async move {
-            write_credentials.await?;
+            open_ai::validate_api_key(http_client.as_ref(), &api_url, &api_key, &api_options)
+                .await
+                .map_err(AuthenticationError::from)?;
+            let api_key_source = match credential_store
+                .write_api_key(&api_url, &api_key, &cx)
+                .await
+            {
+                Ok(()) => ApiKeySource::CredentialStore,
+                Err(error) => {
+                    log::warn!(
+                        "failed to save the OpenAI API key, keeping it for this session only: {error:#}"
+                    );
+                    ApiKeySource::Memory
+                }
+            };
             this.update(&mut cx, |this, cx| {
                 this.api_key = Some(api_key);
+                this.api_key_source = Some(api_key_source);
                 cx.notify();
             })
         })
     }
 
+    /// Looks for an API key in the credential store, then in the `OPENAI_API_KEY` environment
+    /// variable, then in the file at the `api_key_path` setting.
     fn authenticate(&self, cx: &mut ModelContext<Self>) -> Task<Result<()>> {
         if self.is_authenticated() {
             Task::ready(Ok(()))
         } else {
-            let api_url = AllLanguageModelSettings::get_global(cx)
-                .openai
-                .api_url
-                .clone();
+            let settings = &AllLanguageModelSettings::get_global(cx).openai;
+            let api_url = settings.api_url.clone();
+            let api_key_path = settings.api_key_path.clone();
+            let credential_store = self.credential_store.clone();
+            let read_env_var = self.read_env_var;
             cx.spawn(|this, mut cx| async move {
-                let (api_key, from_env) = if let Ok(api_key) = std::env::var(OPENAI_API_KEY_VAR) {
-                    (api_key, true)
+                let stored_api_key = credential_store
+                    .read_api_key(&api_url, &cx)
+                    .await
+                    .log_err()
+                    .flatten();
+                let (api_key, api_key_source) = if let Some(api_key) = stored_api_key {
+                    (api_key, ApiKeySource::CredentialStore)
+                } else if let Some(api_key) = read_env_var(OPENAI_API_KEY_VAR) {
+                    (api_key, ApiKeySource::EnvironmentVariable)
+                } else if let Some(api_key_path) = api_key_path {
+                    let api_key = cx
+                        .background_executor()
+                        .spawn(async move { std::fs::read_to_string(api_key_path) })
+                        .await?;
+                    let api_key = api_key.trim();
+                    if api_key.is_empty() {
+                        Err(anyhow!("the API key file is empty"))?;
+                    }
+                    (api_key.to_string(), ApiKeySource::File)
                 } else {
-                    let (_, api_key) = cx
-                        .update(|cx| cx.read_credentials(&api_url))?
-                        .await?
-                        .ok_or_else(|| anyhow!("credentials not found"))?;
-                    (String::from_utf8(api_key)?, false)
+                    Err(anyhow!("credentials not found"))?
                 };
                 this.update(&mut cx, |this, cx| {
                     this.api_key = Some(api_key);
-                    this.api_key_from_env = from_env;
+                    this.api_key_source = Some(api_key_source);
                     cx.notify();
                 })
             })
@@ -121,15 +310,36 @@ impl State {
 
 impl OpenAiLanguageModelProvider {
     pub fn new(http_client: Arc<dyn HttpClient>, cx: &mut AppContext) -> Self {
+        Self::with_key_sources(
+            http_client,
+            Arc::new(KeychainCredentialStore),
+            |name| std::env::var(name).ok(),
+            cx,
+        )
+    }
+
+    fn with_key_sources(
+        http_client: Arc<dyn HttpClient>,
+        credential_store: Arc<dyn CredentialStore>,
+        read_env_var: fn(&str) -> Option<String>,
+        cx: &mut AppContext,
+    ) -> Self {
         let state = cx.new_model(|cx| State {
             api_key: None,
-            api_key_from_env: false,
+            api_key_source: None,
+            credential_store,
+            read_env_var,
+            http_client: http_client.clone(),
             _subscription: cx.observe_global::<SettingsStore>(|_this: &mut State, cx| {
                 cx.notify();
             }),
         });
 
-        Self { http_client, state }
+        Self {
+            http_client,
+            state,
+            request_limiter: RateLimiter::for_provider(cx),
+        }
     }
 }
 
@@ -189,7 +399,7 @@ impl LanguageModelProvider for OpenAiLanguageModelProvider {
                     model,
                     state: self.state.clone(),
                     http_client: self.http_client.clone(),
-                    request_limiter: RateLimiter::new(4),
+                    request_limiter: self.request_limiter.clone(),
                 }) as Arc<dyn LanguageModel>
             })
             .collect()
@@ -211,6 +421,76 @@ impl LanguageModelProvider for OpenAiLanguageModelProvider {
     fn reset_credentials(&self, cx: &mut AppContext) -> Task<Result<()>> {
         self.state.update(cx, |state, cx| state.reset_api_key(cx))
     }
+
+    fn summary_model(&self, cx: &AppContext) -> Option<Arc<dyn LanguageModel>> {
+        let id = LanguageModelId::from(open_ai::Model::FourOmniMini.id().to_string());
+        self.provided_models(cx)
+            .into_iter()
+            .find(|model| model.id() == id)
+    }
+
+    fn check_status(&self, cx: &mut AppContext) -> Task<ProviderStatus> {
+        let Some(api_key) = self.state.read(cx).api_key.clone() else {
+            return Task::ready(ProviderStatus::Unauthenticated);
+        };
+        let settings = &AllLanguageModelSettings::get_global(cx).openai;
+        let api_url = settings.api_url.clone();
+        let api_options = settings.api_options();
+        let http_client = self.http_client.clone();
+        cx.background_executor().spawn(async move {
+            let result =
+                open_ai::validate_api_key(http_client.as_ref(), &api_url, &api_key, &api_options)
+                    .await;
+            match result.map_err(AuthenticationError::from) {
+                Ok(()) => ProviderStatus::Ready,
+                Err(AuthenticationError::InvalidApiKey) => ProviderStatus::Unauthenticated,
+                Err(error) => ProviderStatus::Error(error.to_string().into()),
+            }
+        })
+    }
+
+    fn embed(
+        &self,
+        texts: Vec<String>,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<Vec<Vec<f32>>>> {
+        let api_key = self.state.read(cx).api_key.clone();
+        let api_url = AllLanguageModelSettings::get_global(cx)
+            .openai
+            .api_url
+            .clone();
+        let http_client = self.http_client.clone();
+        async move {
+            let batches = embedding_batches(
+                &count_open_ai_embedding_tokens(&texts),
+                OPEN_AI_MAX_EMBEDDING_BATCH_SIZE,
+                OPEN_AI_MAX_EMBEDDING_BATCH_TOKENS,
+            );
+            if batches.is_empty() {
+                return Ok(Vec::new());
+            }
+            let api_key = api_key.ok_or_else(|| anyhow!("missing api key"))?;
+            embed_in_batches(texts, batches, |batch| {
+                let response = open_ai::embed(
+                    http_client.as_ref(),
+                    &api_url,
+                    &api_key,
+                    OpenAiEmbeddingModel::TextEmbedding3Small,
+                    batch.iter().map(|text| text.as_str()),
+                );
+                async move {
+                    Ok(response
+                        .await?
+                        .data
+                        .into_iter()
+                        .map(|data| data.embedding)
+                        .collect())
+                }
+            })
+            .await
+        }
+        .boxed()
+    }
 }
 
 pub struct OpenAiLanguageModel {
@@ -226,34 +506,51 @@ impl OpenAiLanguageModel {
         &self,
         request: open_ai::Request,
         cx: &AsyncAppContext,
-    ) -> BoxFuture<'static, Result<futures::stream::BoxStream<'static, Result<ResponseStreamEvent>>>>
-    {
+    ) -> BoxFuture<
+        'static,
+        Result<RateLimitGuard<futures::stream::BoxStream<'static, Result<ResponseStreamEvent>>>>,
+    > {
         let http_client = self.http_client.clone();
-        let Ok((api_key, api_url, low_speed_timeout)) = cx.read_model(&self.state, |state, cx| {
-            let settings = &AllLanguageModelSettings::get_global(cx).openai;
-            (
-                state.api_key.clone(),
-                settings.api_url.clone(),
-                settings.low_speed_timeout,
-            )
-        }) else {
+        let executor = cx.background_executor().clone();
+        let Ok((api_key, api_url, api_options, low_speed_timeout, max_retries)) =
+            cx.read_model(&self.state, |state, cx| {
+                let settings = &AllLanguageModelSettings::get_global(cx).openai;
+                (
+                    state.api_key.clone(),
+                    settings.api_url.clone(),
+                    settings.api_options(),
+                    settings.low_speed_timeout,
+                    settings.max_retries,
+                )
+            })
+        else {
             return futures::future::ready(Err(anyhow!("App state dropped"))).boxed();
         };
 
-        let future = self.request_limiter.stream(async move {
+        // Each attempt waits for its own slot, so that no slot is held while backing off.
+        let request_limiter = self.request_limiter.clone();
+        async move {
             let api_key = api_key.ok_or_else(|| anyhow!("Missing OpenAI API Key"))?;
-            let request = stream_completion(
-                http_client.as_ref(),
-                &api_url,
-                &api_key,
-                request,
-                low_speed_timeout,
-            );
-            let response = request.await?;
-            Ok(response)
-        });
-
-        async move { Ok(future.await?.boxed()) }.boxed()
+            retry(
+                RetryPolicy {
+                    max_retries,
+                    ..Default::default()
+                },
+                |delay| executor.timer(delay),
+                || {
+                    request_limiter.stream(open_ai::stream_completion(
+                        http_client.as_ref(),
+                        &api_url,
+                        &api_key,
+                        &api_options,
+                        request.clone(),
+                        low_speed_timeout,
+                    ))
+                },
+            )
+            .await
+        }
+        .boxed()
     }
 }
 
@@ -305,9 +602,71 @@ impl LanguageModel for OpenAiLanguageModel {
         let request = request.into_open_ai(self.model.id().into(), self.max_output_tokens());
         let completions = self.stream_completion(request, cx);
         async move {
-            Ok(open_ai::extract_text_from_events(completions.await?)
-                .map(|result| result.map(LanguageModelCompletionEvent::Text))
-                .boxed())
+            let completions = completions.await?;
+            let queue_time = completions.queue_time();
+            Ok(report_queue_time(
+                queue_time,
+                map_to_language_model_completion_events(completions.boxed()),
+            ))
+        }
+        .boxed()
+    }
+
+    fn complete_batch(
+        &self,
+        request: LanguageModelRequest,
+        n: usize,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BatchCompletions>> {
+        let http_client = self.http_client.clone();
+        let executor = cx.background_executor().clone();
+        let Ok((api_key, api_url, api_options, low_speed_timeout, max_retries, stream_batch)) = cx
+            .read_model(&self.state, |state, cx| {
+                let settings = &AllLanguageModelSettings::get_global(cx).openai;
+                (
+                    state.api_key.clone(),
+                    settings.api_url.clone(),
+                    settings.api_options(),
+                    settings.low_speed_timeout,
+                    settings.max_retries,
+                    settings.stream_batch_completions,
+                )
+            })
+        else {
+            return futures::future::ready(Err(anyhow!("App state dropped"))).boxed();
+        };
+        if stream_batch || n == 0 {
+            return stream_batch_completions(self, request, n, cx);
+        }
+
+        // Ask for every completion at once with the `n` parameter, which OpenAI only supports
+        // without streaming.
+        let mut request = request.into_open_ai(self.model.id().into(), self.max_output_tokens());
+        request.stream = false;
+        request.stream_options = None;
+        request.n = Some(n as u32);
+        let request_limiter = self.request_limiter.clone();
+        async move {
+            let api_key = api_key.ok_or_else(|| anyhow!("Missing OpenAI API Key"))?;
+            let response = retry(
+                RetryPolicy {
+                    max_retries,
+                    ..Default::default()
+                },
+                |delay| executor.timer(delay),
+                || {
+                    request_limiter.run(open_ai::complete(
+                        http_client.as_ref(),
+                        &api_url,
+                        &api_key,
+                        &api_options,
+                        request.clone(),
+                        low_speed_timeout,
+                    ))
+                },
+            )
+            .await?;
+            Ok(batch_from_response(response, n))
         }
         .boxed()
     }
@@ -336,18 +695,165 @@ impl LanguageModel for OpenAiLanguageModel {
             },
         }];
 
+        // The completion holds its own slot of the request limiter until its stream ends, which
+        // the tool's arguments are streamed from.
         let response = self.stream_completion(request, cx);
-        self.request_limiter
-            .run(async move {
-                let response = response.await?;
-                Ok(
-                    open_ai::extract_tool_args_from_events(tool_name, Box::pin(response))
-                        .await?
-                        .boxed(),
-                )
+        async move {
+            let response = response.await?;
+            Ok(
+                open_ai::extract_tool_args_from_events(tool_name, Box::pin(response))
+                    .await?
+                    .boxed(),
+            )
+        }
+        .boxed()
+    }
+}
+
+/// Assigns each choice of a response to its index in the batch, failing the indices the
+/// response didn't include a choice for.
+fn batch_from_response(response: open_ai::Response, n: usize) -> BatchCompletions {
+    let mut texts = (0..n)
+        .map(|index| Err(anyhow!("no completion was generated at index {index}")))
+        .collect::<Vec<_>>();
+    for choice in response.choices {
+        let Some(text) = texts.get_mut(choice.index as usize) else {
+            continue;
+        };
+        *text = match choice.message {
+            open_ai::RequestMessage::Assistant { content, .. } => Ok(content.unwrap_or_default()),
+            _ => Err(anyhow!(
+                "completion at index {} isn't from the assistant",
+                choice.index
+            )),
+        };
+    }
+    BatchCompletions {
+        completions: texts
+            .into_iter()
+            .enumerate()
+            .map(|(index, result)| BatchCompletion { index, result })
+            .collect(),
+        usage: Some(TokenUsage {
+            prompt_tokens: response.usage.prompt_tokens,
+            completion_tokens: response.usage.completion_tokens,
+        }),
+    }
+}
+
+pub fn map_to_language_model_completion_events(
+    events: Pin<Box<dyn Send + Stream<Item = Result<ResponseStreamEvent>>>>,
+) -> impl Stream<Item = Result<LanguageModelCompletionEvent>> {
+    #[derive(Default)]
+    struct RawToolCall {
+        id: String,
+        name: String,
+        arguments: String,
+    }
+
+    struct State {
+        events: Pin<Box<dyn Send + Stream<Item = Result<ResponseStreamEvent>>>>,
+        tool_calls_by_index: BTreeMap<usize, RawToolCall>,
+        ended: bool,
+    }
+
+    fn take_tool_uses(
+        tool_calls_by_index: &mut BTreeMap<usize, RawToolCall>,
+    ) -> Vec<Result<LanguageModelCompletionEvent>> {
+        mem::take(tool_calls_by_index)
+            .into_values()
+            .map(|tool_call| -> Result<_> {
+                Ok(LanguageModelCompletionEvent::ToolUse(
+                    LanguageModelToolUse {
+                        id: tool_call.id,
+                        name: tool_call.name,
+                        input: serde_json::Value::from_str(&tool_call.arguments)
+                            .map_err(|err| anyhow!(err))?,
+                    },
+                ))
             })
-            .boxed()
+            .collect()
     }
+
+    futures::stream::unfold(
+        State {
+            events,
+            tool_calls_by_index: BTreeMap::default(),
+            ended: false,
+        },
+        |mut state| async move {
+            if state.ended {
+                return None;
+            }
+            let Some(event) = state.events.next().await else {
+                // Some servers end the stream without a `finish_reason`, so the tool calls
+                // streamed so far are reported once it ends.
+                state.ended = true;
+                if state.tool_calls_by_index.is_empty() {
+                    return None;
+                }
+                let mut completion_events = take_tool_uses(&mut state.tool_calls_by_index);
+                completion_events.push(Ok(LanguageModelCompletionEvent::Stop(StopReason::ToolUse)));
+                return Some((completion_events, state));
+            };
+            let mut completion_events: Vec<Result<LanguageModelCompletionEvent>> = Vec::new();
+            match event {
+                Ok(event) => {
+                    if let Some(usage) = event.usage {
+                        completion_events.push(Ok(LanguageModelCompletionEvent::UsageUpdate(
+                            TokenUsage {
+                                prompt_tokens: usage.prompt_tokens,
+                                completion_tokens: usage.completion_tokens,
+                            },
+                        )));
+                    }
+
+                    let Some(choice) = event.choices.into_iter().next() else {
+                        return Some((completion_events, state));
+                    };
+
+                    if let Some(content) = choice.delta.content {
+                        completion_events.push(Ok(LanguageModelCompletionEvent::Text(content)));
+                    }
+
+                    for tool_call in choice.delta.tool_calls.unwrap_or_default() {
+                        let entry = state
+                            .tool_calls_by_index
+                            .entry(tool_call.index)
+                            .or_default();
+                        if let Some(id) = tool_call.id {
+                            entry.id = id;
+                        }
+                        if let Some(function) = tool_call.function {
+                            if let Some(name) = function.name {
+                                entry.name = name;
+                            }
+                            if let Some(arguments) = function.arguments {
+                                entry.arguments.push_str(&arguments);
+                            }
+                        }
+                    }
+
+                    if let Some(finish_reason) = choice.finish_reason.as_deref() {
+                        let stop_reason = match finish_reason {
+                            "stop" => StopReason::EndTurn,
+                            "tool_calls" | "function_call" => StopReason::ToolUse,
+                            "length" => StopReason::MaxTokens,
+                            "content_filter" => StopReason::ContentFilter,
+                            _ => StopReason::Unknown(finish_reason.to_string()),
+                        };
+
+                        completion_events.extend(take_tool_uses(&mut state.tool_calls_by_index));
+                        completion_events.push(Ok(LanguageModelCompletionEvent::Stop(stop_reason)));
+                    }
+                }
+                Err(error) => completion_events.push(Err(error)),
+            }
+
+            Some((completion_events, state))
+        },
+    )
+    .flat_map(futures::stream::iter)
 }
 
 pub fn count_open_ai_tokens(
@@ -388,6 +894,7 @@ struct ConfigurationView {
     api_key_editor: View<Editor>,
     state: gpui::Model<State>,
     load_credentials_task: Option<Task<()>>,
+    api_key_error: Option<SharedString>,
 }
 
 impl ConfigurationView {
@@ -426,6 +933,7 @@ impl ConfigurationView {
             api_key_editor,
             state,
             load_credentials_task,
+            api_key_error: None,
         }
     }
 
@@ -435,11 +943,22 @@ impl ConfigurationView {
             return;
         }
 
+        self.api_key_error = None;
         let state = self.state.clone();
-        cx.spawn(|_, mut cx| async move {
-            state
+        cx.spawn(|this, mut cx| async move {
+            let result = state
                 .update(&mut cx, |state, cx| state.set_api_key(api_key, cx))?
-                .await
+                .await;
+            this.update(&mut cx, |this, cx| {
+                this.api_key_error =
+                    result
+                        .err()
+                        .map(|error| match error.downcast_ref::<AuthenticationError>() {
+                            Some(error) => error.to_string().into(),
+                            None => format!("Failed to save the API key: {error}").into(),
+                        });
+                cx.notify();
+            })
         })
         .detach_and_log_err(cx);
 
@@ -504,7 +1023,7 @@ impl Render for ConfigurationView {
             " - Paste your API key below and hit enter to start using the assistant",
         ];
 
-        let env_var_set = self.state.read(cx).api_key_from_env;
+        let api_key_source = self.state.read(cx).api_key_source;
 
         if self.load_credentials_task.is_some() {
             div().child(Label::new("Loading credentials...")).into_any()
@@ -535,6 +1054,11 @@ impl Render for ConfigurationView {
                         .rounded_md()
                         .child(self.render_api_key_editor(cx)),
                 )
+                .children(self.api_key_error.clone().map(|error| {
+                    Label::new(error)
+                        .size(LabelSize::Small)
+                        .color(Color::Error)
+                }))
                 .child(
                     Label::new(
                         format!("You can also assign the {OPENAI_API_KEY_VAR} environment variable and restart Zed."),
@@ -556,10 +1080,17 @@ impl Render for ConfigurationView {
                     h_flex()
                         .gap_1()
                         .child(Icon::new(IconName::Check).color(Color::Success))
-                        .child(Label::new(if env_var_set {
-                            format!("API key set in {OPENAI_API_KEY_VAR} environment variable.")
-                        } else {
-                            "API key configured.".to_string()
+                        .child(Label::new(match api_key_source {
+                            Some(ApiKeySource::EnvironmentVariable) => {
+                                format!("API key set in {OPENAI_API_KEY_VAR} environment variable.")
+                            }
+                            Some(ApiKeySource::File) => "API key read from api_key_path.".to_string(),
+                            Some(ApiKeySource::Memory) => {
+                                "API key configured for this session only. It couldn't be saved to the system keychain.".to_string()
+                            }
+                            Some(ApiKeySource::CredentialStore) | None => {
+                                "API key configured.".to_string()
+                            }
                         })),
                 )
                 .child(
@@ -567,13 +1098,527 @@ impl Render for ConfigurationView {
                         .icon(Some(IconName::Trash))
                         .icon_size(IconSize::Small)
                         .icon_position(IconPosition::Start)
-                        .disabled(env_var_set)
-                        .when(env_var_set, |this| {
+                        .disabled(matches!(
+                            api_key_source,
+                            Some(ApiKeySource::EnvironmentVariable | ApiKeySource::File)
+                        ))
+                        .when(api_key_source == Some(ApiKeySource::EnvironmentVariable), |this| {
                             this.tooltip(|cx| Tooltip::text(format!("To reset your API key, unset the {OPENAI_API_KEY_VAR} environment variable."), cx))
                         })
+                        .when(api_key_source == Some(ApiKeySource::File), |this| {
+                            this.tooltip(|cx| Tooltip::text("To reset your API key, remove the api_key_path setting.", cx))
+                        })
                         .on_click(cx.listener(|this, _, cx| this.reset_api_key(cx))),
                 )
                 .into_any()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::AsyncReadExt;
+    use gpui::TestAppContext;
+    use http_client::{FakeHttpClient, Response};
+    use parking_lot::Mutex;
+    use serde_json::json;
+
+    /// A credential store that keeps the API key in memory, so tests can check what was saved.
+    #[derive(Clone, Default)]
+    struct FakeCredentialStore(Arc<Mutex<Option<String>>>);
+
+    impl FakeCredentialStore {
+        fn api_key(&self) -> Option<String> {
+            self.0.lock().clone()
+        }
+    }
+
+    impl CredentialStore for FakeCredentialStore {
+        fn read_api_key<'a>(
+            &'a self,
+            _: &'a str,
+            _: &'a AsyncAppContext,
+        ) -> LocalBoxFuture<'a, Result<Option<String>>> {
+            let api_key = self.api_key();
+            async move { Ok(api_key) }.boxed_local()
+        }
+
+        fn write_api_key<'a>(
+            &'a self,
+            _: &'a str,
+            api_key: &'a str,
+            _: &'a AsyncAppContext,
+        ) -> LocalBoxFuture<'a, Result<()>> {
+            *self.0.lock() = Some(api_key.to_string());
+            async { Ok(()) }.boxed_local()
+        }
+
+        fn delete_api_key<'a>(
+            &'a self,
+            _: &'a str,
+            _: &'a AsyncAppContext,
+        ) -> LocalBoxFuture<'a, Result<()>> {
+            *self.0.lock() = None;
+            async { Ok(()) }.boxed_local()
+        }
+    }
+
+    #[gpui::test]
+    async fn test_set_api_key_validates_key(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let store = SettingsStore::test(cx);
+            cx.set_global(store);
+            AllLanguageModelSettings::register(cx);
+        });
+        let http_client = FakeHttpClient::create(|request| async move {
+            let authorization = request.headers()["Authorization"].to_str().unwrap();
+            match authorization {
+                "Bearer sk-valid" => Ok(Response::builder()
+                    .status(200)
+                    .body(r#"{"object":"list","data":[]}"#.into())
+                    .unwrap()),
+                "Bearer sk-offline" => Err(anyhow!("connection refused")),
+                _ => Ok(Response::builder()
+                    .status(401)
+                    .body(r#"{"error":{"message":"Incorrect API key provided"}}"#.into())
+                    .unwrap()),
+            }
+        });
+        let credential_store = FakeCredentialStore::default();
+        *credential_store.0.lock() = Some("sk-saved".into());
+        let provider = cx.update(|cx| {
+            OpenAiLanguageModelProvider::with_key_sources(
+                http_client,
+                Arc::new(credential_store.clone()),
+                |_| None,
+                cx,
+            )
+        });
+        let state = provider.state.clone();
+
+        // Rejected keys aren't saved, and leave the previously saved key in place.
+        let error = state
+            .update(cx, |state, cx| state.set_api_key("sk-invalid".into(), cx))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<AuthenticationError>(),
+            Some(AuthenticationError::InvalidApiKey)
+        ));
+        assert!(!cx.update(|cx| provider.is_authenticated(cx)));
+        assert_eq!(credential_store.api_key(), Some("sk-saved".into()));
+
+        let error = state
+            .update(cx, |state, cx| state.set_api_key("sk-offline".into(), cx))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<AuthenticationError>(),
+            Some(AuthenticationError::NetworkError(_))
+        ));
+        assert!(!cx.update(|cx| provider.is_authenticated(cx)));
+        assert_eq!(credential_store.api_key(), Some("sk-saved".into()));
+
+        state
+            .update(cx, |state, cx| state.set_api_key("sk-valid".into(), cx))
+            .await
+            .unwrap();
+        assert!(cx.update(|cx| provider.is_authenticated(cx)));
+        assert_eq!(
+            state.read_with(cx, |state, _| state.api_key.clone()),
+            Some("sk-valid".into())
+        );
+        assert_eq!(credential_store.api_key(), Some("sk-valid".into()));
+
+        cx.update(|cx| provider.reset_credentials(cx))
+            .await
+            .unwrap();
+        assert!(!cx.update(|cx| provider.is_authenticated(cx)));
+        assert_eq!(credential_store.api_key(), None);
+    }
+
+    /// A credential store that fails like the keychain does on Linux without a secret service.
+    struct UnavailableCredentialStore;
+
+    impl CredentialStore for UnavailableCredentialStore {
+        fn read_api_key<'a>(
+            &'a self,
+            _: &'a str,
+            _: &'a AsyncAppContext,
+        ) -> LocalBoxFuture<'a, Result<Option<String>>> {
+            async { Err(anyhow!("no secret service")) }.boxed_local()
+        }
+
+        fn write_api_key<'a>(
+            &'a self,
+            _: &'a str,
+            _: &'a str,
+            _: &'a AsyncAppContext,
+        ) -> LocalBoxFuture<'a, Result<()>> {
+            async { Err(anyhow!("no secret service")) }.boxed_local()
+        }
+
+        fn delete_api_key<'a>(
+            &'a self,
+            _: &'a str,
+            _: &'a AsyncAppContext,
+        ) -> LocalBoxFuture<'a, Result<()>> {
+            async { Err(anyhow!("no secret service")) }.boxed_local()
+        }
+    }
+
+    #[gpui::test]
+    async fn test_authenticate_without_credential_store(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let store = SettingsStore::test(cx);
+            cx.set_global(store);
+            AllLanguageModelSettings::register(cx);
+        });
+        let http_client = FakeHttpClient::create(|request| async move {
+            let authorization = request.headers()["Authorization"].to_str().unwrap();
+            if authorization != "Bearer sk-from-env" && authorization != "Bearer sk-typed" {
+                return Ok(Response::builder()
+                    .status(401)
+                    .body(r#"{"error":{"message":"Incorrect API key provided"}}"#.into())
+                    .unwrap());
+            }
+            if request.uri().path().ends_with("/chat/completions") {
+                Ok(Response::builder()
+                    .status(200)
+                    .body(
+                        concat!(
+                            r#"data: {"created":0,"model":"gpt-4o","choices":[{"index":0,"delta":{"role":"assistant","content":"Hi"},"finish_reason":null}]}"#,
+                            "\n\n",
+                            "data: [DONE]\n",
+                        )
+                        .into(),
+                    )
+                    .unwrap())
+            } else {
+                Ok(Response::builder()
+                    .status(200)
+                    .body(r#"{"object":"list","data":[]}"#.into())
+                    .unwrap())
+            }
+        });
+        let provider = cx.update(|cx| {
+            OpenAiLanguageModelProvider::with_key_sources(
+                http_client,
+                Arc::new(UnavailableCredentialStore),
+                |name| (name == OPENAI_API_KEY_VAR).then(|| "sk-from-env".to_string()),
+                cx,
+            )
+        });
+        let state = provider.state.clone();
+
+        // The key is read from the environment when the credential store can't be read.
+        cx.update(|cx| provider.authenticate(cx)).await.unwrap();
+        assert!(cx.update(|cx| provider.is_authenticated(cx)));
+        assert_eq!(
+            state.read_with(cx, |state, _| state.api_key_source),
+            Some(ApiKeySource::EnvironmentVariable)
+        );
+
+        let model = cx
+            .update(|cx| provider.provided_models(cx))
+            .into_iter()
+            .next()
+            .unwrap();
+        let request = LanguageModelRequest {
+            messages: vec![crate::LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec!["Hello".into()],
+                cache: false,
+                attachments: Vec::new(),
+            }],
+            ..Default::default()
+        };
+        let text = model
+            .stream_completion_text(request, &cx.to_async())
+            .await
+            .unwrap()
+            .map(|chunk| chunk.unwrap())
+            .collect::<String>()
+            .await;
+        assert_eq!(text, "Hi");
+
+        // Keys from the environment can't be reset from Zed.
+        cx.update(|cx| provider.reset_credentials(cx))
+            .await
+            .unwrap();
+        assert!(cx.update(|cx| provider.is_authenticated(cx)));
+
+        // A key that can't be saved to the credential store is kept for the session.
+        state
+            .update(cx, |state, cx| state.set_api_key("sk-typed".into(), cx))
+            .await
+            .unwrap();
+        assert_eq!(
+            state.read_with(cx, |state, _| (state.api_key.clone(), state.api_key_source)),
+            (Some("sk-typed".into()), Some(ApiKeySource::Memory))
+        );
+        cx.update(|cx| provider.reset_credentials(cx))
+            .await
+            .unwrap();
+        assert!(!cx.update(|cx| provider.is_authenticated(cx)));
+    }
+
+    #[gpui::test]
+    async fn test_complete_batch_in_one_request(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let store = SettingsStore::test(cx);
+            cx.set_global(store);
+            AllLanguageModelSettings::register(cx);
+        });
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let requests = requests.clone();
+            move |mut request| {
+                let requests = requests.clone();
+                async move {
+                    if !request.uri().path().ends_with("/chat/completions") {
+                        return Ok(Response::builder()
+                            .status(200)
+                            .body(r#"{"object":"list","data":[]}"#.into())
+                            .unwrap());
+                    }
+                    let mut body = String::new();
+                    request.body_mut().read_to_string(&mut body).await?;
+                    requests
+                        .lock()
+                        .push(serde_json::from_str::<serde_json::Value>(&body)?);
+                    // The choices are out of order, and the one at index 1 is missing.
+                    let response = json!({
+                        "id": "chatcmpl-1",
+                        "object": "chat.completion",
+                        "created": 0,
+                        "model": "gpt-4o",
+                        "choices": [
+                            {"index": 2, "message": {"role": "assistant", "content": "third"}, "finish_reason": "stop"},
+                            {"index": 0, "message": {"role": "assistant", "content": "first"}, "finish_reason": "stop"},
+                        ],
+                        "usage": {"prompt_tokens": 1, "completion_tokens": 2, "total_tokens": 3},
+                    });
+                    Ok(Response::builder()
+                        .status(200)
+                        .body(response.to_string().into())
+                        .unwrap())
+                }
+            }
+        });
+        let provider = cx.update(|cx| {
+            OpenAiLanguageModelProvider::with_key_sources(
+                http_client,
+                Arc::new(UnavailableCredentialStore),
+                |name| (name == OPENAI_API_KEY_VAR).then(|| "sk-from-env".to_string()),
+                cx,
+            )
+        });
+        cx.update(|cx| provider.authenticate(cx)).await.unwrap();
+        let model = cx
+            .update(|cx| provider.provided_models(cx))
+            .into_iter()
+            .next()
+            .unwrap();
+
+        let batch = model
+            .complete_batch(LanguageModelRequest::default(), 3, &cx.to_async())
+            .await
+            .unwrap();
+        assert_eq!(
+            batch.usage,
+            Some(TokenUsage {
+                prompt_tokens: 1,
+                completion_tokens: 2,
+            })
+        );
+        assert_eq!(
+            batch
+                .completions
+                .iter()
+                .map(|completion| (
+                    completion.index,
+                    completion.result.as_ref().ok().map(String::as_str)
+                ))
+                .collect::<Vec<_>>(),
+            [(0, Some("first")), (1, None), (2, Some("third"))]
+        );
+
+        let requests = requests.lock();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0]["n"], 3);
+        assert_eq!(requests[0]["stream"], false);
+        assert!(requests[0].get("stream_options").is_none());
+    }
+
+    #[gpui::test]
+    async fn test_use_any_tool_with_one_concurrent_completion(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let store = SettingsStore::test(cx);
+            cx.set_global(store);
+            AllLanguageModelSettings::register(cx);
+            cx.update_global::<SettingsStore, _>(|store, cx| {
+                store
+                    .set_user_settings(
+                        r#"{"language_models": {"max_concurrent_completions": 1}}"#,
+                        cx,
+                    )
+                    .unwrap();
+            });
+        });
+        let http_client = FakeHttpClient::create(|request| async move {
+            if !request.uri().path().ends_with("/chat/completions") {
+                return Ok(Response::builder()
+                    .status(200)
+                    .body(r#"{"object":"list","data":[]}"#.into())
+                    .unwrap());
+            }
+            let events = [
+                json!({"created": 0, "model": "gpt-4o", "choices": [{"index": 0, "delta": {"tool_calls": [{"index": 0, "id": "call_1", "function": {"name": "get_weather", "arguments": "{\"loc"}}]}, "finish_reason": null}]}),
+                json!({"created": 0, "model": "gpt-4o", "choices": [{"index": 0, "delta": {"tool_calls": [{"index": 0, "function": {"arguments": "ation\":\"Paris\"}"}}]}, "finish_reason": null}]}),
+                json!({"created": 0, "model": "gpt-4o", "choices": [{"index": 0, "delta": {}, "finish_reason": "tool_calls"}]}),
+            ];
+            let body = events
+                .iter()
+                .map(|event| format!("data: {event}\n\n"))
+                .chain(["data: [DONE]\n".to_string()])
+                .collect::<String>();
+            Ok(Response::builder().status(200).body(body.into()).unwrap())
+        });
+        let provider = cx.update(|cx| {
+            OpenAiLanguageModelProvider::with_key_sources(
+                http_client,
+                Arc::new(UnavailableCredentialStore),
+                |name| (name == OPENAI_API_KEY_VAR).then(|| "sk-from-env".to_string()),
+                cx,
+            )
+        });
+        cx.update(|cx| provider.authenticate(cx)).await.unwrap();
+        let model = cx
+            .update(|cx| provider.provided_models(cx))
+            .into_iter()
+            .next()
+            .unwrap();
+
+        // Both calls finish, since each takes a single slot and frees it once its arguments have
+        // been streamed.
+        for _ in 0..2 {
+            let arguments = model
+                .use_any_tool(
+                    LanguageModelRequest::default(),
+                    "get_weather".into(),
+                    "Gets the weather".into(),
+                    json!({"type": "object"}),
+                    &cx.to_async(),
+                )
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .collect::<String>()
+                .await;
+            assert_eq!(arguments, r#"{"location":"Paris"}"#);
+        }
+    }
+
+    #[test]
+    fn test_map_tool_call_deltas_to_events() {
+        let events = [
+            json!({"created": 0, "model": "gpt-4o", "choices": [{"index": 0, "delta": {"role": "assistant", "content": "Let me check."}, "finish_reason": null}]}),
+            json!({"created": 0, "model": "gpt-4o", "choices": [{"index": 0, "delta": {"tool_calls": [{"index": 0, "id": "call_1", "function": {"name": "get_weather", "arguments": "{\"loc"}}]}, "finish_reason": null}]}),
+            json!({"created": 0, "model": "gpt-4o", "choices": [{"index": 0, "delta": {"tool_calls": [{"index": 0, "function": {"arguments": "ation\":\"Paris\"}"}}]}, "finish_reason": null}]}),
+            json!({"created": 0, "model": "gpt-4o", "choices": [{"index": 0, "delta": {}, "finish_reason": "tool_calls"}]}),
+        ]
+        .into_iter()
+        .map(|event| Ok(serde_json::from_value::<ResponseStreamEvent>(event).unwrap()))
+        .collect::<Vec<_>>();
+
+        let events = smol::block_on(
+            map_to_language_model_completion_events(futures::stream::iter(events).boxed())
+                .map(|event| event.unwrap())
+                .collect::<Vec<_>>(),
+        );
+
+        assert_eq!(
+            events,
+            vec![
+                LanguageModelCompletionEvent::Text("Let me check.".into()),
+                LanguageModelCompletionEvent::ToolUse(LanguageModelToolUse {
+                    id: "call_1".into(),
+                    name: "get_weather".into(),
+                    input: json!({"location": "Paris"}),
+                }),
+                LanguageModelCompletionEvent::Stop(StopReason::ToolUse),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_map_tool_calls_without_finish_reason() {
+        let events = [
+            json!({"created": 0, "model": "qwen2.5-coder-7b", "choices": [{"index": 0, "delta": {"tool_calls": [{"index": 0, "id": "call_1", "function": {"name": "search", "arguments": "{\"q\":"}}]}}]}),
+            json!({"created": 0, "model": "qwen2.5-coder-7b", "choices": [{"index": 0, "delta": {"tool_calls": [{"index": 0, "function": {"arguments": "\"zed\"}"}}]}}]}),
+        ]
+        .into_iter()
+        .map(|event| Ok(serde_json::from_value::<ResponseStreamEvent>(event).unwrap()))
+        .collect::<Vec<_>>();
+
+        let events = smol::block_on(
+            map_to_language_model_completion_events(futures::stream::iter(events).boxed())
+                .map(|event| event.unwrap())
+                .collect::<Vec<_>>(),
+        );
+
+        assert_eq!(
+            events,
+            vec![
+                LanguageModelCompletionEvent::ToolUse(LanguageModelToolUse {
+                    id: "call_1".into(),
+                    name: "search".into(),
+                    input: json!({"q": "zed"}),
+                }),
+                LanguageModelCompletionEvent::Stop(StopReason::ToolUse),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_map_finish_reasons_to_stop_reasons() {
+        let stop_reason = |finish_reason: &str| {
+            let event = json!({"created": 0, "model": "gpt-4o", "choices": [{"index": 0, "delta": {}, "finish_reason": finish_reason}]});
+            let events = vec![Ok(
+                serde_json::from_value::<ResponseStreamEvent>(event).unwrap()
+            )];
+            smol::block_on(
+                map_to_language_model_completion_events(futures::stream::iter(events).boxed())
+                    .map(|event| event.unwrap())
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        assert_eq!(
+            stop_reason("stop"),
+            [LanguageModelCompletionEvent::Stop(StopReason::EndTurn)]
+        );
+        assert_eq!(
+            stop_reason("length"),
+            [LanguageModelCompletionEvent::Stop(StopReason::MaxTokens)]
+        );
+        assert_eq!(
+            stop_reason("content_filter"),
+            [LanguageModelCompletionEvent::Stop(
+                StopReason::ContentFilter
+            )]
+        );
+        assert_eq!(
+            stop_reason("tool_calls"),
+            [LanguageModelCompletionEvent::Stop(StopReason::ToolUse)]
+        );
+        assert_eq!(
+            stop_reason("insufficient_system_resource"),
+            [LanguageModelCompletionEvent::Stop(StopReason::Unknown(
+                "insufficient_system_resource".into()
+            ))]
+        );
+    }
+}
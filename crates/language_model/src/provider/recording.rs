@@ -0,0 +1,542 @@
+use crate::{
+    LanguageModel, LanguageModelCacheConfiguration, LanguageModelCompletionEvent, LanguageModelId,
+    LanguageModelName, LanguageModelProviderId, LanguageModelProviderName, LanguageModelRequest,
+};
+use anyhow::{anyhow, Result};
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt, StreamExt};
+use gpui::{AppContext, AsyncAppContext, BackgroundExecutor};
+use parking_lot::Mutex;
+use project::Fs;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use similar::TextDiff;
+use std::{
+    path::Path,
+    sync::{Arc, LazyLock},
+    time::{Duration, Instant},
+};
+use ui::IconName;
+
+/// The version of the fixture format written by [`Recording::to_json`]. Fixtures with any other
+/// version are rejected rather than misread, so bump it whenever the format changes.
+pub const RECORDING_FORMAT_VERSION: u32 = 1;
+
+/// Completions captured from a language model, which a
+/// [`FakeLanguageModel`](super::fake::FakeLanguageModel) can replay in tests.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Recording {
+    pub version: u32,
+    pub completions: Vec<RecordedCompletion>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecordedCompletion {
+    /// The [`request_hash`] of `request`, which replayed requests are matched against.
+    pub request_hash: String,
+    pub request: LanguageModelRequest,
+    pub chunks: Vec<RecordedChunk>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecordedChunk {
+    /// The time between sending the request and receiving this chunk.
+    pub offset_ms: u64,
+    pub event: RecordedEvent,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordedEvent {
+    Event(LanguageModelCompletionEvent),
+    /// An error, which is replayed with the same message but loses its type.
+    Error(String),
+}
+
+impl Default for Recording {
+    fn default() -> Self {
+        Self {
+            version: RECORDING_FORMAT_VERSION,
+            completions: Vec::new(),
+        }
+    }
+}
+
+impl Recording {
+    pub fn from_json(json: &str) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Header {
+            version: u32,
+        }
+
+        let header: Header = serde_json::from_str(json)?;
+        if header.version != RECORDING_FORMAT_VERSION {
+            return Err(anyhow!(
+                "unsupported recording format version {}, expected {}",
+                header.version,
+                RECORDING_FORMAT_VERSION
+            ));
+        }
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub async fn load(fs: &dyn Fs, path: &Path) -> Result<Self> {
+        Self::from_json(&fs.load(path).await?)
+    }
+
+    pub async fn save(&self, fs: &dyn Fs, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs.create_dir(dir).await?;
+        }
+        fs.atomic_write(path.to_path_buf(), self.to_json()?).await
+    }
+
+    /// Explains why no recorded completion matches the request, with a diff against the recorded
+    /// request that's closest to it.
+    pub fn mismatch_message(&self, request: &LanguageModelRequest) -> String {
+        let actual = normalized_request(request);
+        let closest = self
+            .completions
+            .iter()
+            .map(|completion| normalized_request(&completion.request))
+            .max_by(|a, b| {
+                let a = TextDiff::from_lines(a.as_str(), actual.as_str()).ratio();
+                let b = TextDiff::from_lines(b.as_str(), actual.as_str()).ratio();
+                a.total_cmp(&b)
+            });
+        match closest {
+            Some(closest) => format!(
+                "no recorded completion matches the request, closest recording:\n{}",
+                TextDiff::from_lines(closest.as_str(), actual.as_str())
+                    .unified_diff()
+                    .header("recorded", "actual")
+            ),
+            None => format!("no completions were recorded, got request:\n{actual}"),
+        }
+    }
+}
+
+/// Identifies a request regardless of the timestamps in it, which differ between the session a
+/// recording was made in and the tests replaying it.
+pub fn request_hash(request: &LanguageModelRequest) -> String {
+    format!(
+        "{:x}",
+        Sha256::digest(normalized_request(request).as_bytes())
+    )
+}
+
+fn normalized_request(request: &LanguageModelRequest) -> String {
+    static TIMESTAMP: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(
+            r"\d{4}-\d{2}-\d{2}(?:[T ]\d{2}:\d{2}(?::\d{2}(?:\.\d+)?)?(?:Z|[+-]\d{2}:?\d{2})?)?|\b\d{1,2}:\d{2}(?::\d{2}(?:\.\d+)?)?\b",
+        )
+        .unwrap()
+    });
+
+    let json = serde_json::to_string_pretty(request).unwrap_or_default();
+    TIMESTAMP.replace_all(&json, "<timestamp>").into_owned()
+}
+
+/// How a replayed completion's chunks are spaced out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplayTiming {
+    /// Every chunk is sent right away.
+    Immediate,
+    /// Chunks are spaced out as they were recorded, but `speedup` times faster.
+    Compressed { speedup: u32 },
+}
+
+/// Serves completions from a [`Recording`], each of which is replayed at most once, in order,
+/// before repeating the last matching one.
+pub(crate) struct Replayer {
+    recording: Recording,
+    timing: ReplayTiming,
+    replayed: Mutex<Vec<bool>>,
+}
+
+impl Replayer {
+    pub(crate) fn new(recording: Recording, timing: ReplayTiming) -> Self {
+        let replayed = vec![false; recording.completions.len()];
+        Self {
+            recording,
+            timing,
+            replayed: Mutex::new(replayed),
+        }
+    }
+
+    /// Replays the completion recorded for the request, panicking with a diff if there's none so
+    /// that the test fails.
+    pub(crate) fn replay(
+        &self,
+        request: &LanguageModelRequest,
+        executor: BackgroundExecutor,
+    ) -> BoxStream<'static, Result<LanguageModelCompletionEvent>> {
+        let hash = request_hash(request);
+        let mut replayed = self.replayed.lock();
+        let matching = self
+            .recording
+            .completions
+            .iter()
+            .enumerate()
+            .filter(|(_, completion)| completion.request_hash == hash)
+            .map(|(ix, _)| ix)
+            .collect::<Vec<_>>();
+        let Some(ix) = matching
+            .iter()
+            .copied()
+            .find(|ix| !replayed[*ix])
+            .or(matching.last().copied())
+        else {
+            panic!("{}", self.recording.mismatch_message(request));
+        };
+        replayed[ix] = true;
+
+        let mut previous_offset_ms = 0;
+        let chunks = self.recording.completions[ix]
+            .chunks
+            .iter()
+            .map(|chunk| {
+                let delay = match self.timing {
+                    ReplayTiming::Immediate => None,
+                    ReplayTiming::Compressed { speedup } => Some(Duration::from_millis(
+                        chunk.offset_ms.saturating_sub(previous_offset_ms) / speedup.max(1) as u64,
+                    )),
+                };
+                previous_offset_ms = chunk.offset_ms;
+                (delay, chunk.event.clone())
+            })
+            .collect::<Vec<_>>();
+
+        futures::stream::iter(chunks)
+            .then(move |(delay, event)| {
+                let executor = executor.clone();
+                async move {
+                    if let Some(delay) = delay {
+                        executor.timer(delay).await;
+                    }
+                    match event {
+                        RecordedEvent::Event(event) => Ok(event),
+                        RecordedEvent::Error(message) => Err(anyhow!(message)),
+                    }
+                }
+            })
+            .boxed()
+    }
+}
+
+/// A [`LanguageModel`] that records each completion of the wrapped model, so that it can be
+/// saved as a fixture and replayed in tests.
+///
+/// Tool uses aren't recorded.
+pub struct RecordingLanguageModel {
+    model: Arc<dyn LanguageModel>,
+    recording: Arc<Mutex<Recording>>,
+}
+
+impl RecordingLanguageModel {
+    pub fn new(model: Arc<dyn LanguageModel>) -> Self {
+        Self {
+            model,
+            recording: Default::default(),
+        }
+    }
+
+    /// The completions recorded so far, in the order they were requested.
+    pub fn recording(&self) -> Recording {
+        self.recording.lock().clone()
+    }
+}
+
+impl LanguageModel for RecordingLanguageModel {
+    fn id(&self) -> LanguageModelId {
+        self.model.id()
+    }
+
+    fn name(&self) -> LanguageModelName {
+        self.model.name()
+    }
+
+    fn icon(&self) -> Option<IconName> {
+        self.model.icon()
+    }
+
+    fn provider_id(&self) -> LanguageModelProviderId {
+        self.model.provider_id()
+    }
+
+    fn provider_name(&self) -> LanguageModelProviderName {
+        self.model.provider_name()
+    }
+
+    fn telemetry_id(&self) -> String {
+        self.model.telemetry_id()
+    }
+
+    fn availability(&self) -> crate::LanguageModelAvailability {
+        self.model.availability()
+    }
+
+    fn max_token_count(&self) -> usize {
+        self.model.max_token_count()
+    }
+
+    fn max_output_tokens(&self) -> Option<u32> {
+        self.model.max_output_tokens()
+    }
+
+    fn count_tokens(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<usize>> {
+        self.model.count_tokens(request, cx)
+    }
+
+    fn stream_completion(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        let started_at = Instant::now();
+        let ix = {
+            let mut recording = self.recording.lock();
+            recording.completions.push(RecordedCompletion {
+                request_hash: request_hash(&request),
+                request: request.clone(),
+                chunks: Vec::new(),
+            });
+            recording.completions.len() - 1
+        };
+        let recorder = ChunkRecorder {
+            recording: self.recording.clone(),
+            ix,
+            started_at,
+        };
+        let events = self.model.stream_completion(request, cx);
+        async move {
+            match events.await {
+                Ok(events) => Ok(events
+                    .map(move |event| {
+                        recorder.record(&event);
+                        event
+                    })
+                    .boxed()),
+                Err(error) => {
+                    // Replay can't fail to open the stream, so the error ends it instead.
+                    recorder.record(&Err(anyhow!(error.to_string())));
+                    Err(error)
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn use_any_tool(
+        &self,
+        request: LanguageModelRequest,
+        name: String,
+        description: String,
+        schema: serde_json::Value,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        self.model
+            .use_any_tool(request, name, description, schema, cx)
+    }
+
+    fn cache_configuration(&self) -> Option<LanguageModelCacheConfiguration> {
+        self.model.cache_configuration()
+    }
+
+    fn as_fake(&self) -> &super::fake::FakeLanguageModel {
+        self.model.as_fake()
+    }
+}
+
+struct ChunkRecorder {
+    recording: Arc<Mutex<Recording>>,
+    ix: usize,
+    started_at: Instant,
+}
+
+impl ChunkRecorder {
+    fn record(&self, event: &Result<LanguageModelCompletionEvent>) {
+        let event = match event {
+            Ok(event) => RecordedEvent::Event(event.clone()),
+            Err(error) => RecordedEvent::Error(error.to_string()),
+        };
+        self.recording.lock().completions[self.ix]
+            .chunks
+            .push(RecordedChunk {
+                offset_ms: self.started_at.elapsed().as_millis() as u64,
+                event,
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{provider::fake::FakeLanguageModel, LanguageModelRequestMessage, Role, StopReason};
+    use gpui::TestAppContext;
+    use project::FakeFs;
+
+    fn request(text: &str) -> LanguageModelRequest {
+        LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![text.into()],
+                cache: false,
+                attachments: Vec::new(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    async fn collect_events(
+        events: BoxFuture<
+            'static,
+            Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>,
+        >,
+    ) -> Vec<RecordedEvent> {
+        events
+            .await
+            .unwrap()
+            .map(|event| match event {
+                Ok(event) => RecordedEvent::Event(event),
+                Err(error) => RecordedEvent::Error(error.to_string()),
+            })
+            .collect()
+            .await
+    }
+
+    #[gpui::test]
+    async fn test_record_and_replay(cx: &mut TestAppContext) {
+        let fs = FakeFs::new(cx.executor());
+        let fake_model = Arc::new(FakeLanguageModel::default());
+        let model = RecordingLanguageModel::new(fake_model.clone());
+
+        let first = model.stream_completion(request("Say hi at 09:41"), &cx.to_async());
+        let second = model.stream_completion(request("Then fail"), &cx.to_async());
+        cx.run_until_parked();
+        let [first_request, second_request] = fake_model.pending_completions().try_into().unwrap();
+        fake_model.stream_completion_response(&first_request, "Hi ".into());
+        fake_model.stream_completion_response(&first_request, "thére 👋".into());
+        fake_model.send_completion_event(
+            &first_request,
+            LanguageModelCompletionEvent::Stop(StopReason::EndTurn),
+        );
+        fake_model.end_completion_stream(&first_request);
+        fake_model.stream_completion_response(&second_request, "Partial".into());
+        fake_model.send_completion_error(&second_request, anyhow!("overloaded"));
+        fake_model.end_completion_stream(&second_request);
+        let recorded = [collect_events(first).await, collect_events(second).await];
+
+        let path = Path::new("/fixtures/assistant.json");
+        model.recording().save(fs.as_ref(), path).await.unwrap();
+        let recording = Recording::load(fs.as_ref(), path).await.unwrap();
+        assert_eq!(recording, model.recording());
+
+        // The replayed events serialize to the same bytes as the recorded ones, even though the
+        // timestamp in the request has changed.
+        let replaying = FakeLanguageModel::replaying(recording, ReplayTiming::Immediate);
+        let replayed = [
+            collect_events(replaying.stream_completion(request("Say hi at 17:05"), &cx.to_async()))
+                .await,
+            collect_events(replaying.stream_completion(request("Then fail"), &cx.to_async())).await,
+        ];
+        assert_eq!(
+            serde_json::to_string(&replayed).unwrap(),
+            serde_json::to_string(&recorded).unwrap()
+        );
+        assert_eq!(replaying.completion_count(), 0);
+    }
+
+    #[gpui::test]
+    async fn test_compressed_replay_timing(cx: &mut TestAppContext) {
+        let mut recording = Recording::default();
+        recording.completions.push(RecordedCompletion {
+            request_hash: request_hash(&request("Hello")),
+            request: request("Hello"),
+            chunks: [(100, "a"), (1100, "b")]
+                .into_iter()
+                .map(|(offset_ms, text)| RecordedChunk {
+                    offset_ms,
+                    event: RecordedEvent::Event(LanguageModelCompletionEvent::Text(text.into())),
+                })
+                .collect(),
+        });
+        let model =
+            FakeLanguageModel::replaying(recording, ReplayTiming::Compressed { speedup: 10 });
+
+        let text = Arc::new(Mutex::new(String::new()));
+        let events = model.stream_completion_text(request("Hello"), &cx.to_async());
+        cx.executor()
+            .spawn({
+                let text = text.clone();
+                async move {
+                    let mut chunks = events.await.unwrap();
+                    while let Some(chunk) = chunks.next().await {
+                        text.lock().push_str(&chunk.unwrap());
+                    }
+                }
+            })
+            .detach();
+
+        cx.executor().advance_clock(Duration::from_millis(9));
+        assert_eq!(*text.lock(), "");
+        cx.executor().advance_clock(Duration::from_millis(1));
+        assert_eq!(*text.lock(), "a");
+        cx.executor().advance_clock(Duration::from_millis(99));
+        assert_eq!(*text.lock(), "a");
+        cx.executor().advance_clock(Duration::from_millis(1));
+        assert_eq!(*text.lock(), "ab");
+    }
+
+    #[test]
+    fn test_recording_format() {
+        assert_eq!(
+            request_hash(&request("Today is 2024-08-30T10:15:00Z")),
+            request_hash(&request("Today is 2024-09-02T08:00:12Z"))
+        );
+        assert_ne!(
+            request_hash(&request("Today is Friday")),
+            request_hash(&request("Today is Monday"))
+        );
+
+        let mut recording = Recording::default();
+        recording.completions.push(RecordedCompletion {
+            request_hash: request_hash(&request("Today is Friday")),
+            request: request("Today is Friday"),
+            chunks: Vec::new(),
+        });
+        let message = recording.mismatch_message(&request("Today is Monday"));
+        assert!(
+            message
+                .lines()
+                .any(|line| line.starts_with('-') && line.contains("Today is Friday")),
+            "{message}"
+        );
+        assert!(
+            message
+                .lines()
+                .any(|line| line.starts_with('+') && line.contains("Today is Monday")),
+            "{message}"
+        );
+
+        let json = recording.to_json().unwrap();
+        assert_eq!(Recording::from_json(&json).unwrap(), recording);
+        let json = json.replacen(
+            &format!("\"version\": {RECORDING_FORMAT_VERSION}"),
+            "\"version\": 0",
+            1,
+        );
+        assert!(Recording::from_json(&json)
+            .unwrap_err()
+            .to_string()
+            .contains("unsupported recording format version 0"));
+    }
+}
@@ -1,21 +1,124 @@
+use crate::{settings::AllLanguageModelSettings, LanguageModelCompletionEvent};
 use anyhow::Result;
-use futures::Stream;
-use smol::lock::{Semaphore, SemaphoreGuardArc};
+use futures::{
+    channel::oneshot,
+    future,
+    stream::{self, BoxStream},
+    Stream, StreamExt,
+};
+use gpui::{AppContext, BackgroundExecutor};
+use parking_lot::Mutex;
+use settings::{Settings, SettingsStore};
 use std::{
+    collections::VecDeque,
     future::Future,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
+    time::Duration,
 };
 
+/// Limits how many requests run at once, queueing the rest in FIFO order.
+///
+/// The limit can be changed while requests are running. Lowering it never
+/// interrupts running requests; queued requests just wait until enough of
+/// them finish.
 #[derive(Clone)]
 pub struct RateLimiter {
-    semaphore: Arc<Semaphore>,
+    state: Arc<Mutex<RateLimiterState>>,
+    /// Measures how long queued requests wait.
+    executor: BackgroundExecutor,
+}
+
+struct RateLimiterState {
+    limit: usize,
+    running: usize,
+    queue: VecDeque<oneshot::Sender<()>>,
+}
+
+impl RateLimiterState {
+    fn start_queued(&mut self) {
+        while self.running < self.limit {
+            let Some(tx) = self.queue.pop_front() else {
+                break;
+            };
+            // A closed channel means the request was cancelled while queued.
+            if tx.send(()).is_ok() {
+                self.running += 1;
+            }
+        }
+    }
+}
+
+/// Allows a request to run until it's dropped.
+struct Permit {
+    state: Arc<Mutex<RateLimiterState>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        state.running -= 1;
+        state.start_queued();
+    }
+}
+
+/// Resolves to a [`Permit`] once the request is allowed to run.
+struct QueuedPermit {
+    state: Arc<Mutex<RateLimiterState>>,
+    permit: Option<Permit>,
+    /// Receives a message once a running request finishes and it's this
+    /// request's turn, if it couldn't run immediately.
+    rx: Option<oneshot::Receiver<()>>,
+}
+
+impl Future for QueuedPermit {
+    type Output = Permit;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Some(rx) = self.rx.as_mut() {
+            match Pin::new(rx).poll(cx) {
+                Poll::Ready(result) => {
+                    result.expect("the rate limiter never drops queued senders");
+                    self.rx = None;
+                    self.permit = Some(Permit {
+                        state: self.state.clone(),
+                    });
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(self.permit.take().expect("polled after completion"))
+    }
+}
+
+impl Drop for QueuedPermit {
+    fn drop(&mut self) {
+        let Some(mut rx) = self.rx.take() else {
+            return;
+        };
+        rx.close();
+        // If it became this request's turn just before it was cancelled, hand
+        // its slot on to the next queued request.
+        if let Ok(Some(())) = rx.try_recv() {
+            drop(Permit {
+                state: self.state.clone(),
+            });
+        }
+    }
 }
 
 pub struct RateLimitGuard<T> {
     inner: T,
-    _guard: SemaphoreGuardArc,
+    permit: Option<Permit>,
+    queue_time: Option<Duration>,
+}
+
+impl<T> RateLimitGuard<T> {
+    /// How long the request waited for a slot, or `None` if it started right away.
+    pub fn queue_time(&self) -> Option<Duration> {
+        self.queue_time
+    }
 }
 
 impl<T> Stream for RateLimitGuard<T>
@@ -25,14 +128,71 @@ where
     type Item = T::Item;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        unsafe { Pin::map_unchecked_mut(self, |this| &mut this.inner).poll_next(cx) }
+        // Safety: `inner` is never moved out of `self`, and `permit` isn't pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let item = unsafe { Pin::new_unchecked(&mut this.inner) }.poll_next(cx);
+        if let Poll::Ready(None) = item {
+            this.permit.take();
+        }
+        item
     }
 }
 
 impl RateLimiter {
-    pub fn new(limit: usize) -> Self {
+    pub fn new(limit: usize, executor: BackgroundExecutor) -> Self {
         Self {
-            semaphore: Arc::new(Semaphore::new(limit)),
+            state: Arc::new(Mutex::new(RateLimiterState {
+                limit: limit.max(1),
+                running: 0,
+                queue: VecDeque::new(),
+            })),
+            executor,
+        }
+    }
+
+    /// Creates a limiter sized by the `max_concurrent_completions` setting,
+    /// which is resized whenever the setting changes.
+    pub fn for_provider(cx: &mut AppContext) -> Self {
+        let limiter = Self::new(
+            AllLanguageModelSettings::get_global(cx).max_concurrent_completions,
+            cx.background_executor().clone(),
+        );
+        cx.observe_global::<SettingsStore>({
+            let limiter = limiter.clone();
+            move |cx| {
+                limiter
+                    .set_limit(AllLanguageModelSettings::get_global(cx).max_concurrent_completions)
+            }
+        })
+        .detach();
+        limiter
+    }
+
+    pub fn set_limit(&self, limit: usize) {
+        let mut state = self.state.lock();
+        state.limit = limit.max(1);
+        state.start_queued();
+    }
+
+    fn acquire(&self) -> QueuedPermit {
+        let mut state = self.state.lock();
+        if state.running < state.limit && state.queue.is_empty() {
+            state.running += 1;
+            QueuedPermit {
+                state: self.state.clone(),
+                permit: Some(Permit {
+                    state: self.state.clone(),
+                }),
+                rx: None,
+            }
+        } else {
+            let (tx, rx) = oneshot::channel();
+            state.queue.push_back(tx);
+            QueuedPermit {
+                state: self.state.clone(),
+                permit: None,
+                rx: Some(rx),
+            }
         }
     }
 
@@ -40,31 +200,184 @@ impl RateLimiter {
     where
         Fut: 'a + Future<Output = Result<T>>,
     {
-        let guard = self.semaphore.acquire_arc();
+        let permit = self.acquire();
         async move {
-            let guard = guard.await;
+            let permit = permit.await;
             let result = future.await?;
-            drop(guard);
+            drop(permit);
             Ok(result)
         }
     }
 
+    /// Waits for a free slot and starts the request, resolving once the
+    /// request has started. The slot is released when the returned stream
+    /// ends or is dropped.
     pub fn stream<'a, Fut, T>(
         &self,
         future: Fut,
-    ) -> impl 'a + Future<Output = Result<impl Stream<Item = T::Item>>>
+    ) -> impl 'a + Future<Output = Result<RateLimitGuard<T>>>
     where
         Fut: 'a + Future<Output = Result<T>>,
         T: Stream,
     {
-        let guard = self.semaphore.acquire_arc();
+        let permit = self.acquire();
+        let queued_at = permit.rx.is_some().then(|| self.executor.now());
+        let executor = self.executor.clone();
         async move {
-            let guard = guard.await;
+            let permit = permit.await;
+            let queue_time = queued_at.map(|queued_at| executor.now() - queued_at);
             let inner = future.await?;
             Ok(RateLimitGuard {
                 inner,
-                _guard: guard,
+                permit: Some(permit),
+                queue_time,
             })
         }
     }
 }
+
+/// Starts a completion's events with [`LanguageModelCompletionEvent::Queued`] if the request
+/// had to wait for a slot.
+pub fn report_queue_time(
+    queue_time: Option<Duration>,
+    events: impl Stream<Item = Result<LanguageModelCompletionEvent>> + Send + 'static,
+) -> BoxStream<'static, Result<LanguageModelCompletionEvent>> {
+    match queue_time {
+        Some(queue_time) => stream::once(future::ready(Ok(LanguageModelCompletionEvent::Queued(
+            queue_time,
+        ))))
+        .chain(events)
+        .boxed(),
+        None => events.boxed(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use futures::{channel::mpsc, StreamExt};
+    use gpui::{Task, TestAppContext};
+
+    /// Queues a request whose response streams until the returned sender is dropped.
+    fn start_request(
+        limiter: &RateLimiter,
+        id: usize,
+        started: &Arc<Mutex<Vec<usize>>>,
+        cx: &TestAppContext,
+    ) -> (mpsc::UnboundedSender<()>, Task<()>) {
+        let (tx, rx) = mpsc::unbounded();
+        let started = started.clone();
+        let request = limiter.stream(async move {
+            started.lock().push(id);
+            Ok(rx)
+        });
+        let task = cx.executor().spawn(async move {
+            if let Ok(stream) = request.await {
+                stream.collect::<Vec<_>>().await;
+            }
+        });
+        (tx, task)
+    }
+
+    #[gpui::test]
+    async fn test_requests_start_in_order(cx: &mut TestAppContext) {
+        let limiter = RateLimiter::new(2, cx.executor());
+        let started = Arc::new(Mutex::new(Vec::new()));
+        let (mut senders, _tasks): (Vec<_>, Vec<_>) = (0..4)
+            .map(|id| start_request(&limiter, id, &started, cx))
+            .unzip();
+
+        cx.run_until_parked();
+        assert_eq!(*started.lock(), [0, 1]);
+
+        // Finishing a response lets the oldest queued request start.
+        senders.remove(1);
+        cx.run_until_parked();
+        assert_eq!(*started.lock(), [0, 1, 2]);
+
+        senders.remove(0);
+        cx.run_until_parked();
+        assert_eq!(*started.lock(), [0, 1, 2, 3]);
+    }
+
+    #[gpui::test]
+    async fn test_failed_and_cancelled_requests_release_their_slot(cx: &mut TestAppContext) {
+        let limiter = RateLimiter::new(1, cx.executor());
+        let started = Arc::new(Mutex::new(Vec::new()));
+
+        let failed_request =
+            limiter.stream(async { Err::<mpsc::UnboundedReceiver<()>, _>(anyhow!("failed")) });
+        let (_sender_1, task_1) = start_request(&limiter, 1, &started, cx);
+        let (sender_2, _task_2) = start_request(&limiter, 2, &started, cx);
+        assert!(failed_request.await.is_err());
+        cx.run_until_parked();
+        assert_eq!(*started.lock(), [1]);
+
+        // Cancel the running request; the queued one takes its slot.
+        drop(task_1);
+        cx.run_until_parked();
+        assert_eq!(*started.lock(), [1, 2]);
+
+        // Cancel a request before it starts, which must not hold on to a slot.
+        let (_sender_3, task_3) = start_request(&limiter, 3, &started, cx);
+        let (_sender_4, _task_4) = start_request(&limiter, 4, &started, cx);
+        drop(task_3);
+        drop(sender_2);
+        cx.run_until_parked();
+        assert_eq!(*started.lock(), [1, 2, 4]);
+    }
+
+    #[gpui::test]
+    async fn test_queue_time(cx: &mut TestAppContext) {
+        let limiter = RateLimiter::new(1, cx.executor());
+        let (tx, rx) = mpsc::unbounded::<()>();
+        let first = limiter.stream(async move { Ok(rx) }).await.unwrap();
+        let second = cx
+            .executor()
+            .spawn(limiter.stream(async { Ok(futures::stream::empty::<()>()) }));
+        assert_eq!(first.queue_time(), None);
+
+        // The queued request only starts once the running one finishes.
+        cx.run_until_parked();
+        cx.executor().advance_clock(Duration::from_millis(250));
+        drop(tx);
+        drop(first);
+        let second = second.await.unwrap();
+        assert_eq!(second.queue_time(), Some(Duration::from_millis(250)));
+    }
+
+    #[gpui::test]
+    async fn test_changing_limit_while_running(cx: &mut TestAppContext) {
+        let limiter = RateLimiter::new(3, cx.executor());
+        let started = Arc::new(Mutex::new(Vec::new()));
+        let (mut senders, _tasks): (Vec<_>, Vec<_>) = (0..4)
+            .map(|id| start_request(&limiter, id, &started, cx))
+            .unzip();
+        cx.run_until_parked();
+        assert_eq!(*started.lock(), [0, 1, 2]);
+
+        // Lowering the limit doesn't interrupt running requests, but the queued
+        // request waits until fewer than the new limit are running.
+        limiter.set_limit(1);
+        senders.remove(0);
+        cx.run_until_parked();
+        assert_eq!(*started.lock(), [0, 1, 2]);
+        senders.remove(0);
+        cx.run_until_parked();
+        assert_eq!(*started.lock(), [0, 1, 2]);
+        senders.remove(0);
+        cx.run_until_parked();
+        assert_eq!(*started.lock(), [0, 1, 2, 3]);
+
+        // Raising the limit starts queued requests right away.
+        let (_more_senders, _more_tasks): (Vec<_>, Vec<_>) = (4..6)
+            .map(|id| start_request(&limiter, id, &started, cx))
+            .unzip();
+        cx.run_until_parked();
+        assert_eq!(*started.lock(), [0, 1, 2, 3]);
+        limiter.set_limit(3);
+        cx.run_until_parked();
+        assert_eq!(*started.lock(), [0, 1, 2, 3, 4, 5]);
+    }
+}
@@ -2,21 +2,47 @@ use crate::{
     provider::{
         anthropic::AnthropicLanguageModelProvider, cloud::CloudLanguageModelProvider,
         copilot_chat::CopilotChatLanguageModelProvider, google::GoogleLanguageModelProvider,
-        ollama::OllamaLanguageModelProvider, open_ai::OpenAiLanguageModelProvider,
+        local::LocalLanguageModelProvider, ollama::OllamaLanguageModelProvider,
+        open_ai::OpenAiLanguageModelProvider,
     },
     LanguageModel, LanguageModelId, LanguageModelProvider, LanguageModelProviderId,
-    LanguageModelProviderState,
+    LanguageModelProviderState, ProviderStatus,
 };
+use crate::{
+    settings::AllLanguageModelSettings, AttachmentExpandingLanguageModel, CachingLanguageModel,
+    DebugLog, DebugLogSettings, FallbackHealth, FallbackLanguageModel, LoggingLanguageModel,
+    MeteredLanguageModel, ResponseCache, ResumingLanguageModel, UsageMeter, UsageSinceStartup,
+};
+use anyhow::Result;
 use client::{Client, UserStore};
 use collections::BTreeMap;
-use gpui::{AppContext, EventEmitter, Global, Model, ModelContext};
-use std::sync::Arc;
+use gpui::{AppContext, EventEmitter, Global, Model, ModelContext, Task};
+use project::Fs;
+use settings::{Settings, SettingsStore};
+use std::{sync::Arc, time::Duration};
 use ui::Context;
 
-pub fn init(user_store: Model<UserStore>, client: Arc<Client>, cx: &mut AppContext) {
+/// How often the active provider's status is checked while it's reachable.
+const STATUS_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// How long to wait before checking again after the provider couldn't be reached. The delay
+/// doubles after each failed check, up to [`STATUS_CHECK_INTERVAL`].
+const STATUS_RETRY_DELAY: Duration = Duration::from_secs(15);
+const STATUS_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn init(
+    user_store: Model<UserStore>,
+    client: Arc<Client>,
+    fs: Arc<dyn Fs>,
+    cx: &mut AppContext,
+) {
     let registry = cx.new_model(|cx| {
         let mut registry = LanguageModelRegistry::default();
         register_language_model_providers(&mut registry, user_store, client, cx);
+        registry.update_response_cache(&fs, cx);
+        cx.observe_global::<SettingsStore>(move |registry, cx| {
+            registry.update_response_cache(&fs, cx);
+        })
+        .detach();
         registry
     });
     cx.set_global(GlobalLanguageModelRegistry(registry));
@@ -42,6 +68,10 @@ fn register_language_model_providers(
         OllamaLanguageModelProvider::new(client.http_client(), cx),
         cx,
     );
+    registry.register_provider(
+        LocalLanguageModelProvider::new(client.http_client(), cx),
+        cx,
+    );
     registry.register_provider(
         GoogleLanguageModelProvider::new(client.http_client(), cx),
         cx,
@@ -77,16 +107,30 @@ pub struct LanguageModelRegistry {
     active_model: Option<ActiveModel>,
     providers: BTreeMap<LanguageModelProviderId, Arc<dyn LanguageModelProvider>>,
     inline_alternatives: Vec<Arc<dyn LanguageModel>>,
+    /// The inline alternatives, wrapped like the active model.
+    wrapped_inline_alternatives: Vec<Arc<dyn LanguageModel>>,
+    fallback_providers: Vec<LanguageModelProviderId>,
+    fallback_health: FallbackHealth,
+    response_cache: Option<Arc<ResponseCache>>,
+    debug_log: Option<Arc<DebugLog>>,
+    usage_meter: UsageMeter,
+    provider_status: ProviderStatus,
+    check_provider_status: Option<Task<()>>,
 }
 
 pub struct ActiveModel {
     provider: Arc<dyn LanguageModelProvider>,
     model: Option<Arc<dyn LanguageModel>>,
+    /// The model, wrapped once whenever it or the wrappers' configuration changes, so that
+    /// every caller shares the same wrappers.
+    wrapped_model: Option<Arc<dyn LanguageModel>>,
 }
 
 pub enum Event {
     ActiveModelChanged,
     ProviderStateChanged,
+    /// The status of the active provider changed.
+    ProviderStatusChanged,
     AddedProvider(LanguageModelProviderId),
     RemovedProvider(LanguageModelProviderId),
 }
@@ -104,7 +148,7 @@ impl LanguageModelRegistry {
 
     #[cfg(any(test, feature = "test-support"))]
     pub fn test(cx: &mut AppContext) -> crate::provider::fake::FakeLanguageModelProvider {
-        let fake_provider = crate::provider::fake::FakeLanguageModelProvider;
+        let fake_provider = crate::provider::fake::FakeLanguageModelProvider::default();
         let registry = cx.new_model(|cx| {
             let mut registry = Self::default();
             registry.register_provider(fake_provider.clone(), cx);
@@ -123,8 +167,17 @@ impl LanguageModelRegistry {
     ) {
         let id = provider.id();
 
-        let subscription = provider.subscribe(cx, |_, cx| {
-            cx.emit(Event::ProviderStateChanged);
+        let subscription = provider.subscribe(cx, {
+            let id = id.clone();
+            move |registry, cx| {
+                cx.emit(Event::ProviderStateChanged);
+                if registry
+                    .active_provider()
+                    .map_or(false, |provider| provider.id() == id)
+                {
+                    registry.recheck_provider_status(cx);
+                }
+            }
         });
         if let Some(subscription) = subscription {
             subscription.detach();
@@ -194,11 +247,14 @@ impl LanguageModelRegistry {
         provider: Option<Arc<dyn LanguageModelProvider>>,
         cx: &mut ModelContext<Self>,
     ) {
+        let previous_provider_id = self.active_provider().map(|provider| provider.id());
         self.active_model = provider.map(|provider| ActiveModel {
             provider,
             model: None,
+            wrapped_model: None,
         });
         cx.emit(Event::ActiveModelChanged);
+        self.active_provider_changed(previous_provider_id, cx);
     }
 
     pub fn set_active_model(
@@ -206,11 +262,13 @@ impl LanguageModelRegistry {
         model: Option<Arc<dyn LanguageModel>>,
         cx: &mut ModelContext<Self>,
     ) {
+        let previous_provider_id = self.active_provider().map(|provider| provider.id());
         if let Some(model) = model {
             let provider_id = model.provider_id();
             if let Some(provider) = self.providers.get(&provider_id).cloned() {
                 self.active_model = Some(ActiveModel {
                     provider,
+                    wrapped_model: Some(self.wrap_model_with_fallbacks(model.clone())),
                     model: Some(model),
                 });
                 cx.emit(Event::ActiveModelChanged);
@@ -221,6 +279,81 @@ impl LanguageModelRegistry {
             self.active_model = None;
             cx.emit(Event::ActiveModelChanged);
         }
+        self.active_provider_changed(previous_provider_id, cx);
+    }
+
+    fn active_provider_changed(
+        &mut self,
+        previous_provider_id: Option<LanguageModelProviderId>,
+        cx: &mut ModelContext<Self>,
+    ) {
+        if self.active_provider().map(|provider| provider.id()) != previous_provider_id {
+            self.set_provider_status(ProviderStatus::Checking, cx);
+            self.recheck_provider_status(cx);
+        }
+    }
+
+    /// The model to use for background requests such as titling contexts: the active provider's
+    /// summary model, or the active model if the provider doesn't have one.
+    pub fn summary_model(&self, cx: &AppContext) -> Option<Arc<dyn LanguageModel>> {
+        let active_model = self.active_model()?;
+        match self.active_provider()?.summary_model(cx) {
+            Some(model) => Some(self.wrap_model(model)),
+            None => Some(active_model),
+        }
+    }
+
+    /// The status of the active provider, as of its latest check.
+    pub fn provider_status(&self) -> &ProviderStatus {
+        &self.provider_status
+    }
+
+    /// Checks the active provider's status right away, e.g. after a completion failed, and
+    /// then periodically.
+    ///
+    /// Checks are retried with an increasing delay while the provider can't be reached.
+    pub fn recheck_provider_status(&mut self, cx: &mut ModelContext<Self>) {
+        let Some(provider) = self.active_provider() else {
+            self.check_provider_status = None;
+            return;
+        };
+        self.check_provider_status = Some(cx.spawn(|this, mut cx| async move {
+            let mut retry_delay = STATUS_RETRY_DELAY;
+            loop {
+                let Ok(check) = cx.update(|cx| provider.check_status(cx)) else {
+                    return;
+                };
+                let timeout = cx.background_executor().timer(STATUS_CHECK_TIMEOUT);
+                let status = smol::future::or(check, async move {
+                    timeout.await;
+                    ProviderStatus::Error("Timed out while checking the provider.".into())
+                })
+                .await;
+
+                let delay = if matches!(status, ProviderStatus::Error(_)) {
+                    let delay = retry_delay;
+                    retry_delay = (retry_delay * 2).min(STATUS_CHECK_INTERVAL);
+                    delay
+                } else {
+                    retry_delay = STATUS_RETRY_DELAY;
+                    STATUS_CHECK_INTERVAL
+                };
+                if this
+                    .update(&mut cx, |this, cx| this.set_provider_status(status, cx))
+                    .is_err()
+                {
+                    return;
+                }
+                cx.background_executor().timer(delay).await;
+            }
+        }));
+    }
+
+    fn set_provider_status(&mut self, status: ProviderStatus, cx: &mut ModelContext<Self>) {
+        if self.provider_status != status {
+            self.provider_status = status;
+            cx.emit(Event::ProviderStatusChanged);
+        }
     }
 
     pub fn active_provider(&self) -> Option<Arc<dyn LanguageModelProvider>> {
@@ -228,7 +361,173 @@ impl LanguageModelRegistry {
     }
 
     pub fn active_model(&self) -> Option<Arc<dyn LanguageModel>> {
-        self.active_model.as_ref()?.model.clone()
+        self.active_model.as_ref()?.wrapped_model.clone()
+    }
+
+    /// Returns the provider's model with the given id, wrapped like the active model.
+    pub fn model(
+        &self,
+        provider_id: &LanguageModelProviderId,
+        model_id: &LanguageModelId,
+        cx: &AppContext,
+    ) -> Option<Arc<dyn LanguageModel>> {
+        let model = self.find_model(provider_id, model_id, cx)?;
+        Some(self.wrap_model_with_fallbacks(model))
+    }
+
+    /// Whether the provider of the model with the given id, or any provider it falls back to, is
+    /// authenticated.
+    pub fn is_model_authenticated(
+        &self,
+        provider_id: &LanguageModelProviderId,
+        model_id: &LanguageModelId,
+        cx: &AppContext,
+    ) -> bool {
+        self.find_model(provider_id, model_id, cx)
+            .map_or(false, |model| {
+                self.fallback_model(model).is_authenticated(cx)
+            })
+    }
+
+    fn find_model(
+        &self,
+        provider_id: &LanguageModelProviderId,
+        model_id: &LanguageModelId,
+        cx: &AppContext,
+    ) -> Option<Arc<dyn LanguageModel>> {
+        self.providers
+            .get(provider_id)?
+            .provided_models(cx)
+            .into_iter()
+            .find(|model| &model.id() == model_id)
+    }
+
+    /// Whether the active provider, or any provider it falls back to, is authenticated.
+    pub fn is_active_model_authenticated(&self, cx: &AppContext) -> bool {
+        let Some(model) = self
+            .active_model
+            .as_ref()
+            .and_then(|active| active.model.clone())
+        else {
+            return false;
+        };
+        self.fallback_model(model).is_authenticated(cx)
+    }
+
+    /// The health of the active provider and the providers it falls back to, in the order
+    /// they're tried, as of each one's latest request.
+    pub fn fallback_status(&self) -> Vec<(LanguageModelProviderId, ProviderStatus)> {
+        let Some(model) = self
+            .active_model
+            .as_ref()
+            .and_then(|active| active.model.clone())
+        else {
+            return Vec::new();
+        };
+        self.fallback_model(model).status()
+    }
+
+    /// Sets the providers, in order, that requests fall back to when the active provider fails
+    /// before its response starts streaming.
+    pub fn set_fallback_providers(&mut self, providers: Vec<LanguageModelProviderId>) {
+        self.fallback_providers = providers;
+        self.rewrap_models();
+    }
+
+    fn fallback_model(&self, model: Arc<dyn LanguageModel>) -> FallbackLanguageModel {
+        FallbackLanguageModel::new(
+            model,
+            self.fallback_providers.clone(),
+            self.fallback_health.clone(),
+        )
+    }
+
+    /// Wraps the active model and the inline alternatives again, after the wrappers'
+    /// configuration changed.
+    fn rewrap_models(&mut self) {
+        if let Some(model) = self
+            .active_model
+            .as_ref()
+            .and_then(|active| active.model.clone())
+        {
+            let wrapped_model = self.wrap_model_with_fallbacks(model);
+            if let Some(active) = self.active_model.as_mut() {
+                active.wrapped_model = Some(wrapped_model);
+            }
+        }
+        self.wrapped_inline_alternatives = self
+            .inline_alternatives
+            .iter()
+            .map(|model| self.wrap_model_with_fallbacks(model.clone()))
+            .collect();
+    }
+
+    fn wrap_model_with_fallbacks(&self, model: Arc<dyn LanguageModel>) -> Arc<dyn LanguageModel> {
+        if self.fallback_providers.is_empty() {
+            self.wrap_model(model)
+        } else {
+            self.wrap_model(Arc::new(self.fallback_model(model)))
+        }
+    }
+
+    /// Meters the model's usage, resumes its interrupted responses and, if enabled, caches its
+    /// responses and logs its completions. Attachments are expanded before any of these see the
+    /// request.
+    fn wrap_model(&self, model: Arc<dyn LanguageModel>) -> Arc<dyn LanguageModel> {
+        let mut model: Arc<dyn LanguageModel> =
+            Arc::new(MeteredLanguageModel::new(model, self.usage_meter.clone()));
+        model = Arc::new(ResumingLanguageModel::new(model));
+        if let Some(cache) = self.response_cache.as_ref() {
+            model = Arc::new(CachingLanguageModel::new(model, cache.clone()));
+        }
+        if let Some(log) = self.debug_log.as_ref() {
+            model = Arc::new(LoggingLanguageModel::new(model, log.clone()));
+        }
+        Arc::new(AttachmentExpandingLanguageModel::new(model))
+    }
+
+    /// Logs every completion to the debug log with the given settings, or stops logging if
+    /// `settings` is `None`.
+    pub fn set_debug_log(&mut self, fs: Arc<dyn Fs>, settings: Option<DebugLogSettings>) {
+        let current_settings = self.debug_log.as_ref().map(|log| log.settings());
+        if current_settings == settings.as_ref() {
+            return;
+        }
+        self.debug_log = settings.map(|settings| {
+            Arc::new(DebugLog::new(
+                fs,
+                paths::assistant_debug_log_file().clone(),
+                settings,
+            ))
+        });
+        self.rewrap_models();
+    }
+
+    fn update_response_cache(&mut self, fs: &Arc<dyn Fs>, cx: &mut ModelContext<Self>) {
+        let settings = &AllLanguageModelSettings::get_global(cx).response_cache;
+        self.response_cache = settings.enabled.then(|| {
+            Arc::new(ResponseCache::new(
+                fs.clone(),
+                paths::language_model_responses_dir().clone(),
+                settings.max_entries,
+                settings.max_age,
+            ))
+        });
+        self.rewrap_models();
+    }
+
+    /// Returns the tokens used by completions of the active model since startup.
+    pub fn usage_since_startup(&self) -> UsageSinceStartup {
+        self.usage_meter.usage_since_startup()
+    }
+
+    /// Removes all cached responses from disk.
+    pub fn clear_response_cache(&self, cx: &AppContext) -> Task<Result<()>> {
+        let Some(cache) = self.response_cache.clone() else {
+            return Task::ready(Ok(()));
+        };
+        cx.background_executor()
+            .spawn(async move { cache.clear().await })
     }
 
     /// Selects and sets the inline alternatives for language models based on
@@ -253,13 +552,14 @@ impl LanguageModelRegistry {
         }
 
         self.inline_alternatives = selected_alternatives;
+        self.rewrap_models();
     }
 
     /// The models to use for inline assists. Returns the union of the active
     /// model and all inline alternatives. When there are multiple models, the
     /// user will be able to cycle through results.
     pub fn inline_alternative_models(&self) -> &[Arc<dyn LanguageModel>] {
-        &self.inline_alternatives
+        &self.wrapped_inline_alternatives
     }
 }
 
@@ -267,13 +567,16 @@ impl LanguageModelRegistry {
 mod tests {
     use super::*;
     use crate::provider::fake::FakeLanguageModelProvider;
+    use futures::StreamExt;
+    use gpui::TestAppContext;
+    use parking_lot::Mutex;
 
     #[gpui::test]
     fn test_register_providers(cx: &mut AppContext) {
         let registry = cx.new_model(|_| LanguageModelRegistry::default());
 
         registry.update(cx, |registry, cx| {
-            registry.register_provider(FakeLanguageModelProvider, cx);
+            registry.register_provider(FakeLanguageModelProvider::default(), cx);
         });
 
         let providers = registry.read(cx).providers();
@@ -287,4 +590,108 @@ mod tests {
         let providers = registry.read(cx).providers();
         assert!(providers.is_empty());
     }
+
+    #[gpui::test]
+    async fn test_models_are_wrapped_once(cx: &mut TestAppContext) {
+        let active_provider = FakeLanguageModelProvider::with_id("fake-active");
+        let alternative_provider = FakeLanguageModelProvider::with_id("fake-alternative");
+        let registry = cx.new_model(|cx| {
+            let mut registry = LanguageModelRegistry::default();
+            registry.register_provider(active_provider.clone(), cx);
+            registry.register_provider(alternative_provider.clone(), cx);
+            let model = active_provider.provided_models(cx)[0].clone();
+            registry.set_active_model(Some(model), cx);
+            registry.select_inline_alternative_models(
+                [(
+                    alternative_provider.id(),
+                    crate::provider::fake::language_model_id(),
+                )],
+                cx,
+            );
+            registry
+        });
+
+        // Every caller shares the same wrappers around the active model.
+        let (first, second) = registry.read_with(cx, |registry, _| {
+            (
+                registry.active_model().unwrap(),
+                registry.active_model().unwrap(),
+            )
+        });
+        assert!(Arc::ptr_eq(&first, &second));
+
+        // Inline alternatives are wrapped like the active model, so their usage is metered.
+        let alternative = registry.read_with(cx, |registry, _| {
+            registry.inline_alternative_models()[0].clone()
+        });
+        let fake_alternative = alternative_provider.fake_model().unwrap();
+        let events = alternative.stream_completion(Default::default(), &cx.to_async());
+        cx.run_until_parked();
+        fake_alternative.stream_last_completion_response("Hello there".into());
+        fake_alternative.end_last_completion_stream();
+        events.await.unwrap().collect::<Vec<_>>().await;
+        cx.run_until_parked();
+        let usage = registry.read_with(cx, |registry, _| registry.usage_since_startup());
+        assert_eq!(usage.total.completion_tokens, 2);
+    }
+
+    #[gpui::test]
+    async fn test_provider_status_transitions(cx: &mut TestAppContext) {
+        let provider = FakeLanguageModelProvider::default();
+        let registry = cx.new_model(|cx| {
+            let mut registry = LanguageModelRegistry::default();
+            registry.register_provider(provider.clone(), cx);
+            registry
+        });
+        let transitions = Arc::new(Mutex::new(Vec::new()));
+        cx.update(|cx| {
+            let transitions = transitions.clone();
+            cx.subscribe(&registry, move |registry, event: &Event, cx| {
+                if let Event::ProviderStatusChanged = event {
+                    transitions
+                        .lock()
+                        .push(registry.read(cx).provider_status().clone());
+                }
+            })
+            .detach();
+        });
+
+        registry.update(cx, |registry, cx| {
+            let model = provider.provided_models(cx)[0].clone();
+            registry.set_active_model(Some(model), cx);
+        });
+        cx.run_until_parked();
+        assert_eq!(*transitions.lock(), [ProviderStatus::Ready]);
+
+        // Periodic checks that don't change the status aren't reported.
+        cx.executor().advance_clock(STATUS_CHECK_INTERVAL);
+        cx.run_until_parked();
+        assert_eq!(*transitions.lock(), [ProviderStatus::Ready]);
+
+        let offline = ProviderStatus::Error("offline".into());
+        provider.set_status(offline.clone());
+        cx.executor().advance_clock(STATUS_CHECK_INTERVAL);
+        cx.run_until_parked();
+        assert_eq!(
+            *transitions.lock(),
+            [ProviderStatus::Ready, offline.clone()]
+        );
+
+        // While offline, checks are retried sooner, with an increasing delay.
+        cx.executor().advance_clock(STATUS_RETRY_DELAY);
+        cx.run_until_parked();
+        provider.set_status(ProviderStatus::Ready);
+        cx.executor().advance_clock(STATUS_RETRY_DELAY);
+        cx.run_until_parked();
+        assert_eq!(
+            *transitions.lock(),
+            [ProviderStatus::Ready, offline.clone()]
+        );
+        cx.executor().advance_clock(STATUS_RETRY_DELAY);
+        cx.run_until_parked();
+        assert_eq!(
+            *transitions.lock(),
+            [ProviderStatus::Ready, offline, ProviderStatus::Ready]
+        );
+    }
 }
@@ -1,7 +1,7 @@
 use std::io::{Cursor, Write};
 
 use crate::role::Role;
-use crate::LanguageModelToolUse;
+use crate::{LanguageModelToolUse, MessageAttachment};
 use base64::write::EncoderWriter;
 use gpui::{point, size, AppContext, DevicePixels, Image, ObjectFit, RenderImage, Size, Task};
 use image::{codecs::png::PngEncoder, imageops::resize, DynamicImage, ImageDecoder};
@@ -193,6 +193,10 @@ pub struct LanguageModelRequestMessage {
     pub role: Role,
     pub content: Vec<MessageContent>,
     pub cache: bool,
+    /// Context attached to the message, which is rendered into its content before the request
+    /// reaches a provider.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<MessageAttachment>,
 }
 
 impl LanguageModelRequestMessage {
@@ -237,39 +241,197 @@ pub struct LanguageModelRequest {
     pub tools: Vec<LanguageModelRequestTool>,
     pub stop: Vec<String>,
     pub temperature: Option<f32>,
+    /// Only sample from the smallest set of tokens whose probabilities add up to `top_p`.
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    /// The maximum number of tokens to generate. When set, this lowers the model's default
+    /// limit but never raises it.
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    /// The format the response must be in. Providers that can't enforce a format are asked for
+    /// it in the prompt instead.
+    #[serde(default)]
+    pub response_format: Option<ResponseFormat>,
 }
 
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// A single JSON object.
+    JsonObject,
+}
+
+impl ResponseFormat {
+    /// The instruction that asks for this format, for providers that can't enforce it. OpenAI
+    /// also requires JSON to be asked for in the prompt when enforcing it.
+    fn instruction(&self) -> &'static str {
+        match self {
+            Self::JsonObject => "Respond with a single JSON object, without any other text.",
+        }
+    }
+}
+
+/// The highest temperature accepted by OpenAI and Google AI.
+const MAX_TEMPERATURE: f32 = 2.0;
+/// The highest temperature accepted by Anthropic.
+const MAX_ANTHROPIC_TEMPERATURE: f32 = 1.0;
+
 impl LanguageModelRequest {
-    pub fn into_open_ai(self, model: String, max_output_tokens: Option<u32>) -> open_ai::Request {
+    /// Clamps the sampling parameters into the ranges accepted by providers, logging a warning
+    /// for each one that was out of range.
+    pub fn clamp_sampling_parameters(&mut self, max_temperature: f32) {
+        if let Some(temperature) = self.temperature {
+            if !(0.0..=max_temperature).contains(&temperature) {
+                log::warn!(
+                    "temperature {temperature} is out of range, clamping it to 0-{max_temperature}"
+                );
+                self.temperature = Some(temperature.clamp(0.0, max_temperature));
+            }
+        }
+        if let Some(top_p) = self.top_p {
+            if !(0.0..=1.0).contains(&top_p) {
+                log::warn!("top_p {top_p} is out of range, clamping it to 0-1");
+                self.top_p = Some(top_p.clamp(0.0, 1.0));
+            }
+        }
+        if self.max_tokens == Some(0) {
+            log::warn!("max_tokens must be positive, clamping it to 1");
+            self.max_tokens = Some(1);
+        }
+    }
+
+    /// Moves the response format into the prompt, appending its instruction to the last user
+    /// message, for providers that can't be asked for a format directly.
+    pub(crate) fn emulate_response_format(&mut self) {
+        if let Some(response_format) = self.response_format.take() {
+            self.append_response_format_instruction(response_format);
+        }
+    }
+
+    /// Appends the response format's instruction to the last user message, or as a new user
+    /// message if the last message isn't from the user.
+    fn append_response_format_instruction(&mut self, response_format: ResponseFormat) {
+        let instruction = response_format.instruction();
+        match self.messages.last_mut() {
+            Some(message) if message.role == Role::User => {
+                message
+                    .content
+                    .push(MessageContent::Text(format!("\n\n{instruction}")));
+            }
+            _ => self.messages.push(LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![instruction.into()],
+                cache: false,
+                attachments: Vec::new(),
+            }),
+        }
+    }
+
+    /// Returns the request's `max_tokens`, limited to the model's maximum output tokens.
+    fn max_output_tokens(&self, model_max_output_tokens: Option<u32>) -> Option<u32> {
+        match (self.max_tokens, model_max_output_tokens) {
+            (Some(max_tokens), Some(model_max)) => Some(max_tokens.min(model_max)),
+            (max_tokens, model_max) => max_tokens.or(model_max),
+        }
+    }
+
+    pub fn into_open_ai(
+        mut self,
+        model: String,
+        max_output_tokens: Option<u32>,
+    ) -> open_ai::Request {
+        self.clamp_sampling_parameters(MAX_TEMPERATURE);
+        let max_tokens = self.max_output_tokens(max_output_tokens);
         let stream = !model.starts_with("o1-");
+        // OpenAI rejects requests for JSON unless the messages mention JSON.
+        let response_format = self.response_format.take();
+        if let Some(response_format) = response_format {
+            self.append_response_format_instruction(response_format);
+        }
+        let mut messages = Vec::new();
+        for message in self.messages {
+            match message.role {
+                Role::User => {
+                    let mut text = String::new();
+                    let mut has_tool_results = false;
+                    for content in message.content {
+                        match content {
+                            MessageContent::Text(chunk) => text.push_str(&chunk),
+                            MessageContent::ToolResult(tool_result) => {
+                                has_tool_results = true;
+                                messages.push(open_ai::RequestMessage::Tool {
+                                    content: tool_result.content,
+                                    tool_call_id: tool_result.tool_use_id,
+                                });
+                            }
+                            MessageContent::Image(_) | MessageContent::ToolUse(_) => {}
+                        }
+                    }
+                    if !has_tool_results || !text.is_empty() {
+                        messages.push(open_ai::RequestMessage::User { content: text });
+                    }
+                }
+                Role::Assistant => {
+                    let tool_calls = message
+                        .content
+                        .iter()
+                        .filter_map(|content| match content {
+                            MessageContent::ToolUse(tool_use) => Some(open_ai::ToolCall {
+                                id: tool_use.id.clone(),
+                                content: open_ai::ToolCallContent::Function {
+                                    function: open_ai::FunctionContent {
+                                        name: tool_use.name.clone(),
+                                        arguments: tool_use.input.to_string(),
+                                    },
+                                },
+                            }),
+                            _ => None,
+                        })
+                        .collect();
+                    messages.push(open_ai::RequestMessage::Assistant {
+                        content: Some(message.string_contents()),
+                        tool_calls,
+                    });
+                }
+                Role::System => messages.push(open_ai::RequestMessage::System {
+                    content: message.string_contents(),
+                }),
+            }
+        }
+
         open_ai::Request {
             model,
-            messages: self
-                .messages
+            messages,
+            stream,
+            n: None,
+            stop: self.stop,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens,
+            tools: self
+                .tools
                 .into_iter()
-                .map(|msg| match msg.role {
-                    Role::User => open_ai::RequestMessage::User {
-                        content: msg.string_contents(),
-                    },
-                    Role::Assistant => open_ai::RequestMessage::Assistant {
-                        content: Some(msg.string_contents()),
-                        tool_calls: Vec::new(),
-                    },
-                    Role::System => open_ai::RequestMessage::System {
-                        content: msg.string_contents(),
+                .map(|tool| open_ai::ToolDefinition::Function {
+                    function: open_ai::FunctionDefinition {
+                        name: tool.name,
+                        description: Some(tool.description),
+                        parameters: Some(tool.input_schema),
                     },
                 })
                 .collect(),
-            stream,
-            stop: self.stop,
-            temperature: self.temperature.unwrap_or(1.0),
-            max_tokens: max_output_tokens,
-            tools: Vec::new(),
             tool_choice: None,
+            stream_options: stream.then_some(open_ai::StreamOptions {
+                include_usage: true,
+            }),
+            response_format: response_format.map(|format| match format {
+                ResponseFormat::JsonObject => open_ai::ResponseFormat::JsonObject,
+            }),
         }
     }
 
-    pub fn into_google(self, model: String) -> google_ai::GenerateContentRequest {
+    pub fn into_google(mut self, model: String) -> google_ai::GenerateContentRequest {
+        self.clamp_sampling_parameters(MAX_TEMPERATURE);
+        self.emulate_response_format();
         google_ai::GenerateContentRequest {
             model,
             contents: self
@@ -288,10 +450,10 @@ impl LanguageModelRequest {
                 .collect(),
             generation_config: Some(google_ai::GenerationConfig {
                 candidate_count: Some(1),
-                stop_sequences: Some(self.stop),
-                max_output_tokens: None,
-                temperature: self.temperature.map(|t| t as f64).or(Some(1.0)),
-                top_p: None,
+                stop_sequences: (!self.stop.is_empty()).then_some(self.stop),
+                max_output_tokens: self.max_tokens.map(|max_tokens| max_tokens as usize),
+                temperature: self.temperature.map(|t| t as f64),
+                top_p: self.top_p.map(|top_p| top_p as f64),
                 top_k: None,
             }),
             safety_settings: None,
@@ -299,11 +461,16 @@ impl LanguageModelRequest {
     }
 
     pub fn into_anthropic(
-        self,
+        mut self,
         model: String,
         default_temperature: f32,
         max_output_tokens: u32,
     ) -> anthropic::Request {
+        self.clamp_sampling_parameters(MAX_ANTHROPIC_TEMPERATURE);
+        self.emulate_response_format();
+        let max_tokens = self
+            .max_output_tokens(Some(max_output_tokens))
+            .unwrap_or(max_output_tokens);
         let mut new_messages: Vec<anthropic::Message> = Vec::new();
         let mut system_message = String::new();
 
@@ -391,7 +558,7 @@ impl LanguageModelRequest {
         anthropic::Request {
             model,
             messages: new_messages,
-            max_tokens: max_output_tokens,
+            max_tokens,
             system: Some(system_message),
             tools: self
                 .tools
@@ -404,10 +571,10 @@ impl LanguageModelRequest {
                 .collect(),
             tool_choice: None,
             metadata: None,
-            stop_sequences: Vec::new(),
+            stop_sequences: self.stop,
             temperature: self.temperature.or(Some(default_temperature)),
             top_k: None,
-            top_p: None,
+            top_p: self.top_p,
         }
     }
 }
@@ -417,3 +584,203 @@ pub struct LanguageModelResponseMessage {
     pub role: Option<Role>,
     pub content: Option<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(
+        temperature: Option<f32>,
+        top_p: Option<f32>,
+        max_tokens: Option<u32>,
+        stop: &[&str],
+    ) -> LanguageModelRequest {
+        LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec!["Hello".into()],
+                cache: false,
+                attachments: Vec::new(),
+            }],
+            tools: Vec::new(),
+            stop: stop.iter().map(|stop| stop.to_string()).collect(),
+            temperature,
+            top_p,
+            max_tokens,
+            response_format: None,
+        }
+    }
+
+    #[test]
+    fn test_open_ai_request_body() {
+        let body = |request: LanguageModelRequest, max_output_tokens| {
+            serde_json::to_value(request.into_open_ai("gpt-4o".into(), max_output_tokens)).unwrap()
+        };
+
+        // Unset parameters are omitted rather than sent as nulls.
+        assert_eq!(
+            body(request(None, None, None, &[]), None),
+            json!({
+                "model": "gpt-4o",
+                "messages": [{"role": "user", "content": "Hello"}],
+                "stream": true,
+                "stream_options": {"include_usage": true},
+            })
+        );
+        assert_eq!(
+            body(request(Some(0.5), None, None, &["END"]), Some(4096)),
+            json!({
+                "model": "gpt-4o",
+                "messages": [{"role": "user", "content": "Hello"}],
+                "stream": true,
+                "max_tokens": 4096,
+                "stop": ["END"],
+                "temperature": 0.5,
+                "stream_options": {"include_usage": true},
+            })
+        );
+        assert_eq!(
+            body(request(None, Some(0.25), Some(100), &[]), Some(4096)),
+            json!({
+                "model": "gpt-4o",
+                "messages": [{"role": "user", "content": "Hello"}],
+                "stream": true,
+                "max_tokens": 100,
+                "top_p": 0.25,
+                "stream_options": {"include_usage": true},
+            })
+        );
+
+        // Out-of-range parameters are clamped, and max_tokens can't exceed the model's limit.
+        assert_eq!(
+            body(request(Some(3.), Some(1.5), Some(0), &[]), None),
+            json!({
+                "model": "gpt-4o",
+                "messages": [{"role": "user", "content": "Hello"}],
+                "stream": true,
+                "max_tokens": 1,
+                "temperature": 2.0,
+                "top_p": 1.0,
+                "stream_options": {"include_usage": true},
+            })
+        );
+        assert_eq!(
+            body(request(Some(-1.), None, Some(10_000), &[]), Some(4096)),
+            json!({
+                "model": "gpt-4o",
+                "messages": [{"role": "user", "content": "Hello"}],
+                "stream": true,
+                "max_tokens": 4096,
+                "temperature": 0.0,
+                "stream_options": {"include_usage": true},
+            })
+        );
+    }
+
+    #[test]
+    fn test_anthropic_request_body() {
+        let body = |request: LanguageModelRequest| {
+            let body =
+                serde_json::to_value(request.into_anthropic("claude-3-5-sonnet".into(), 1.0, 4096))
+                    .unwrap();
+            json!({
+                "max_tokens": body["max_tokens"],
+                "stop_sequences": body.get("stop_sequences"),
+                "temperature": body.get("temperature"),
+                "top_p": body.get("top_p"),
+            })
+        };
+
+        assert_eq!(
+            body(request(None, None, None, &[])),
+            json!({
+                "max_tokens": 4096,
+                "stop_sequences": null,
+                "temperature": 1.0,
+                "top_p": null,
+            })
+        );
+        assert_eq!(
+            body(request(Some(0.5), Some(0.25), Some(100), &["END"])),
+            json!({
+                "max_tokens": 100,
+                "stop_sequences": ["END"],
+                "temperature": 0.5,
+                "top_p": 0.25,
+            })
+        );
+
+        // Anthropic accepts temperatures of at most 1.
+        assert_eq!(
+            body(request(Some(1.5), None, Some(10_000), &[])),
+            json!({
+                "max_tokens": 4096,
+                "stop_sequences": null,
+                "temperature": 1.0,
+                "top_p": null,
+            })
+        );
+    }
+
+    #[test]
+    fn test_google_request_body() {
+        let generation_config = |request: LanguageModelRequest| {
+            serde_json::to_value(request.into_google("gemini-1.5-pro".into())).unwrap()
+                ["generationConfig"]
+                .clone()
+        };
+
+        assert_eq!(
+            generation_config(request(None, None, None, &[])),
+            json!({"candidateCount": 1})
+        );
+        assert_eq!(
+            generation_config(request(Some(0.5), Some(0.25), Some(100), &["END"])),
+            json!({
+                "candidateCount": 1,
+                "stopSequences": ["END"],
+                "maxOutputTokens": 100,
+                "temperature": 0.5,
+                "topP": 0.25,
+            })
+        );
+    }
+
+    #[test]
+    fn test_json_response_format() {
+        let mut json_request = request(None, None, None, &[]);
+        json_request.response_format = Some(ResponseFormat::JsonObject);
+
+        // OpenAI is asked for JSON directly, as well as in the prompt, which it requires.
+        let instruction = ResponseFormat::JsonObject.instruction();
+        let body =
+            serde_json::to_value(json_request.clone().into_open_ai("gpt-4o".into(), None)).unwrap();
+        assert_eq!(body["response_format"], json!({"type": "json_object"}));
+        assert_eq!(
+            body["messages"],
+            json!([{"role": "user", "content": format!("Hello\n\n{instruction}")}])
+        );
+
+        // Other providers are only asked for it in the prompt.
+        let body = serde_json::to_value(json_request.clone().into_google("gemini-1.5-pro".into()))
+            .unwrap();
+        assert_eq!(
+            body["contents"][0]["parts"],
+            json!([{"text": format!("Hello\n\n{instruction}")}])
+        );
+
+        let mut assistant_last = json_request;
+        assistant_last.messages.push(LanguageModelRequestMessage {
+            role: Role::Assistant,
+            content: vec!["Hi".into()],
+            cache: false,
+            attachments: Vec::new(),
+        });
+        assistant_last.emulate_response_format();
+        assert_eq!(assistant_last.response_format, None);
+        assert_eq!(assistant_last.messages.len(), 3);
+        assert_eq!(assistant_last.messages[2].role, Role::User);
+        assert_eq!(assistant_last.messages[2].string_contents(), instruction);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::LanguageModelCompletionEvent;
+use anyhow::Result;
+use futures::{
+    stream::{self, BoxStream},
+    Stream, StreamExt,
+};
+use gpui::BackgroundExecutor;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::Arc,
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+/// How long a completion took and how much it produced, for comparing providers and models.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RequestMetrics {
+    /// How long the request waited for a free slot in the provider's concurrency limit, if it
+    /// had to wait at all.
+    pub queue_time: Option<Duration>,
+    /// How long it took from starting the request until the first text or tool use arrived.
+    pub time_to_first_chunk: Option<Duration>,
+    /// How long it took from starting the request until its stream ended.
+    pub total_duration: Duration,
+    /// The number of text and tool use events received.
+    pub chunk_count: usize,
+    /// The length in bytes of the text and tool use inputs received.
+    pub bytes_received: usize,
+    /// The number of tokens generated, if the provider reported it.
+    pub output_tokens: Option<u32>,
+}
+
+/// Measures the [`RequestMetrics`] of a completion as its events arrive.
+///
+/// Time is read from the executor's clock rather than [`Instant::now`], so tests can advance it
+/// with `advance_clock`. Clones share the same measurements.
+#[derive(Clone)]
+pub struct RequestMetricsRecorder {
+    executor: BackgroundExecutor,
+    state: Arc<Mutex<RecorderState>>,
+}
+
+struct RecorderState {
+    started_at: Instant,
+    ended_at: Option<Instant>,
+    metrics: RequestMetrics,
+}
+
+impl RequestMetricsRecorder {
+    /// Starts measuring a request that's being sent now.
+    pub fn start(executor: BackgroundExecutor) -> Self {
+        let started_at = executor.now();
+        Self {
+            executor,
+            state: Arc::new(Mutex::new(RecorderState {
+                started_at,
+                ended_at: None,
+                metrics: RequestMetrics::default(),
+            })),
+        }
+    }
+
+    pub fn record_event(&self, event: &LanguageModelCompletionEvent) {
+        let now = self.executor.now();
+        let mut state = self.state.lock();
+        let bytes = match event {
+            LanguageModelCompletionEvent::Text(text) => text.len(),
+            LanguageModelCompletionEvent::ToolUse(tool_use) => tool_use.input.to_string().len(),
+            LanguageModelCompletionEvent::Queued(queue_time) => {
+                state.metrics.queue_time = Some(*queue_time);
+                return;
+            }
+            LanguageModelCompletionEvent::UsageUpdate(usage) => {
+                state.metrics.output_tokens = Some(usage.completion_tokens);
+                return;
+            }
+            LanguageModelCompletionEvent::Stop(_)
+            | LanguageModelCompletionEvent::FellBack { .. }
+            | LanguageModelCompletionEvent::Incomplete => return,
+        };
+        if state.metrics.time_to_first_chunk.is_none() {
+            state.metrics.time_to_first_chunk = Some(now - state.started_at);
+        }
+        state.metrics.chunk_count += 1;
+        state.metrics.bytes_received += bytes;
+    }
+
+    /// Marks the request as done, fixing its total duration.
+    pub fn finish(&self) {
+        let now = self.executor.now();
+        self.state.lock().ended_at.get_or_insert(now);
+    }
+
+    /// The metrics measured so far. Until the request is finished, its total duration is the
+    /// time since it started.
+    pub fn metrics(&self) -> RequestMetrics {
+        let state = self.state.lock();
+        let ended_at = state.ended_at.unwrap_or_else(|| self.executor.now());
+        RequestMetrics {
+            total_duration: ended_at - state.started_at,
+            ..state.metrics
+        }
+    }
+
+    /// Records each of the events as it passes through, finishing when the stream ends.
+    pub fn measure(
+        &self,
+        events: impl Stream<Item = Result<LanguageModelCompletionEvent>> + Send + 'static,
+    ) -> BoxStream<'static, Result<LanguageModelCompletionEvent>> {
+        let recorder = self.clone();
+        let finisher = self.clone();
+        events
+            .inspect(move |event| {
+                if let Ok(event) = event {
+                    recorder.record_event(event);
+                }
+            })
+            .chain(stream::poll_fn(move |_| {
+                finisher.finish();
+                Poll::Ready(None)
+            }))
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LanguageModelToolUse, TokenUsage};
+    use gpui::TestAppContext;
+
+    #[gpui::test]
+    async fn test_request_metrics(cx: &mut TestAppContext) {
+        let executor = cx.executor();
+        let recorder = RequestMetricsRecorder::start(executor.clone());
+
+        recorder.record_event(&LanguageModelCompletionEvent::Queued(
+            Duration::from_millis(300),
+        ));
+        executor.advance_clock(Duration::from_millis(500));
+        assert_eq!(
+            recorder.metrics(),
+            RequestMetrics {
+                queue_time: Some(Duration::from_millis(300)),
+                total_duration: Duration::from_millis(500),
+                ..Default::default()
+            }
+        );
+
+        recorder.record_event(&LanguageModelCompletionEvent::Text("Hello".into()));
+        executor.advance_clock(Duration::from_millis(100));
+        recorder.record_event(&LanguageModelCompletionEvent::ToolUse(
+            LanguageModelToolUse {
+                id: "tool-1".into(),
+                name: "search".into(),
+                input: serde_json::json!({"q": "é"}),
+            },
+        ));
+        recorder.record_event(&LanguageModelCompletionEvent::UsageUpdate(TokenUsage {
+            prompt_tokens: 10,
+            completion_tokens: 7,
+        }));
+        recorder.finish();
+        executor.advance_clock(Duration::from_millis(1000));
+        assert_eq!(
+            recorder.metrics(),
+            RequestMetrics {
+                queue_time: Some(Duration::from_millis(300)),
+                time_to_first_chunk: Some(Duration::from_millis(500)),
+                total_duration: Duration::from_millis(600),
+                chunk_count: 2,
+                bytes_received: "Hello".len() + r#"{"q":"é"}"#.len(),
+                output_tokens: Some(7),
+            }
+        );
+    }
+}
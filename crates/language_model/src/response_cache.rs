@@ -0,0 +1,379 @@
+use crate::{
+    BatchCompletions, LanguageModel, LanguageModelCacheConfiguration, LanguageModelCompletionEvent,
+    LanguageModelId, LanguageModelName, LanguageModelProviderId, LanguageModelProviderName,
+    LanguageModelRequest,
+};
+use anyhow::Result;
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt, StreamExt};
+use gpui::{AppContext, AsyncAppContext};
+use project::{Fs, RemoveOptions};
+use sha2::{Digest, Sha256};
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use ui::IconName;
+use util::ResultExt;
+
+/// An on-disk store of completed language model responses, keyed by a hash
+/// of the model and the request that produced them.
+pub struct ResponseCache {
+    fs: Arc<dyn Fs>,
+    dir: PathBuf,
+    max_entries: usize,
+    max_age: Duration,
+}
+
+impl ResponseCache {
+    pub fn new(fs: Arc<dyn Fs>, dir: PathBuf, max_entries: usize, max_age: Duration) -> Self {
+        Self {
+            fs,
+            dir,
+            max_entries,
+            max_age,
+        }
+    }
+
+    pub fn key(model: &dyn LanguageModel, request: &LanguageModelRequest) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(model.provider_id().0.as_bytes());
+        hasher.update([0]);
+        hasher.update(model.id().0.as_bytes());
+        hasher.update([0]);
+        hasher.update(serde_json::to_vec(request).unwrap_or_default());
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+
+    pub async fn load(&self, key: &str) -> Option<Vec<LanguageModelCompletionEvent>> {
+        let path = self.entry_path(key);
+        let metadata = self.fs.metadata(&path).await.ok()??;
+        if self.is_expired(metadata.mtime) {
+            return None;
+        }
+        let content = self.fs.load(&path).await.ok()?;
+        serde_json::from_str(&content).log_err()
+    }
+
+    pub async fn store(&self, key: &str, events: &[LanguageModelCompletionEvent]) -> Result<()> {
+        self.fs.create_dir(&self.dir).await?;
+        self.fs
+            .atomic_write(self.entry_path(key), serde_json::to_string(events)?)
+            .await?;
+        self.evict().await
+    }
+
+    /// Removes entries that are older than the maximum age, then the oldest
+    /// remaining entries until at most `max_entries` are left.
+    async fn evict(&self) -> Result<()> {
+        let mut entries = Vec::new();
+        let mut paths = self.fs.read_dir(&self.dir).await?;
+        while let Some(path) = paths.next().await {
+            let path = path?;
+            if let Some(metadata) = self.fs.metadata(&path).await? {
+                entries.push((metadata.mtime, path));
+            }
+        }
+        entries.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+
+        for (ix, (mtime, path)) in entries.into_iter().enumerate() {
+            if ix >= self.max_entries || self.is_expired(mtime) {
+                self.fs
+                    .remove_file(
+                        &path,
+                        RemoveOptions {
+                            recursive: false,
+                            ignore_if_not_exists: true,
+                        },
+                    )
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn clear(&self) -> Result<()> {
+        self.fs
+            .remove_dir(
+                &self.dir,
+                RemoveOptions {
+                    recursive: true,
+                    ignore_if_not_exists: true,
+                },
+            )
+            .await
+    }
+
+    fn is_expired(&self, mtime: SystemTime) -> bool {
+        mtime
+            .elapsed()
+            .map_or(false, |elapsed| elapsed > self.max_age)
+    }
+}
+
+/// A [`LanguageModel`] that serves repeated requests from a [`ResponseCache`]
+/// instead of hitting the wrapped model.
+///
+/// Only streams that complete successfully are cached.
+pub struct CachingLanguageModel {
+    model: Arc<dyn LanguageModel>,
+    cache: Arc<ResponseCache>,
+}
+
+impl CachingLanguageModel {
+    pub fn new(model: Arc<dyn LanguageModel>, cache: Arc<ResponseCache>) -> Self {
+        Self { model, cache }
+    }
+}
+
+impl LanguageModel for CachingLanguageModel {
+    fn id(&self) -> LanguageModelId {
+        self.model.id()
+    }
+
+    fn name(&self) -> LanguageModelName {
+        self.model.name()
+    }
+
+    fn icon(&self) -> Option<IconName> {
+        self.model.icon()
+    }
+
+    fn provider_id(&self) -> LanguageModelProviderId {
+        self.model.provider_id()
+    }
+
+    fn provider_name(&self) -> LanguageModelProviderName {
+        self.model.provider_name()
+    }
+
+    fn telemetry_id(&self) -> String {
+        self.model.telemetry_id()
+    }
+
+    fn availability(&self) -> crate::LanguageModelAvailability {
+        self.model.availability()
+    }
+
+    fn max_token_count(&self) -> usize {
+        self.model.max_token_count()
+    }
+
+    fn max_output_tokens(&self) -> Option<u32> {
+        self.model.max_output_tokens()
+    }
+
+    fn count_tokens(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<usize>> {
+        self.model.count_tokens(request, cx)
+    }
+
+    fn stream_completion(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        let key = ResponseCache::key(self.model.as_ref(), &request);
+        let model = self.model.clone();
+        let cache = self.cache.clone();
+        let task = cx.spawn(|cx| async move {
+            if let Some(events) = cache.load(&key).await {
+                return Ok(futures::stream::iter(events.into_iter().map(Ok)).boxed());
+            }
+
+            let events = model.stream_completion(request, &cx).await?;
+            anyhow::Ok(record_events(events, cache, key))
+        });
+        async move { task.await }.boxed()
+    }
+
+    fn complete_batch(
+        &self,
+        request: LanguageModelRequest,
+        n: usize,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BatchCompletions>> {
+        // Batches are never cached, as each of their completions is meant to differ.
+        self.model.complete_batch(request, n, cx)
+    }
+
+    fn use_any_tool(
+        &self,
+        request: LanguageModelRequest,
+        name: String,
+        description: String,
+        schema: serde_json::Value,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        self.model
+            .use_any_tool(request, name, description, schema, cx)
+    }
+
+    fn cache_configuration(&self) -> Option<LanguageModelCacheConfiguration> {
+        self.model.cache_configuration()
+    }
+
+    #[cfg(any(test, feature = "test-support"))]
+    fn as_fake(&self) -> &crate::provider::fake::FakeLanguageModel {
+        self.model.as_fake()
+    }
+}
+
+/// Passes events through unchanged, persisting them to the cache once the
+/// stream ends without an error.
+fn record_events(
+    events: BoxStream<'static, Result<LanguageModelCompletionEvent>>,
+    cache: Arc<ResponseCache>,
+    key: String,
+) -> BoxStream<'static, Result<LanguageModelCompletionEvent>> {
+    struct State {
+        events: BoxStream<'static, Result<LanguageModelCompletionEvent>>,
+        recorded: Vec<LanguageModelCompletionEvent>,
+        failed: bool,
+        cache: Arc<ResponseCache>,
+        key: String,
+    }
+
+    futures::stream::unfold(
+        State {
+            events,
+            recorded: Vec::new(),
+            failed: false,
+            cache,
+            key,
+        },
+        |mut state| async move {
+            match state.events.next().await {
+                Some(Ok(event)) => {
+                    match (state.recorded.last_mut(), &event) {
+                        (
+                            Some(LanguageModelCompletionEvent::Text(recorded)),
+                            LanguageModelCompletionEvent::Text(text),
+                        ) => recorded.push_str(text),
+                        // A cached response is served without waiting for the provider.
+                        (_, LanguageModelCompletionEvent::Queued(_)) => {}
+                        _ => state.recorded.push(event.clone()),
+                    }
+                    Some((Ok(event), state))
+                }
+                Some(Err(error)) => {
+                    state.failed = true;
+                    Some((Err(error), state))
+                }
+                None => {
+                    if !state.failed {
+                        state
+                            .cache
+                            .store(&state.key, &state.recorded)
+                            .await
+                            .log_err();
+                    }
+                    None
+                }
+            }
+        },
+    )
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{provider::fake::FakeLanguageModel, LanguageModelRequestMessage, Role};
+    use anyhow::anyhow;
+    use futures::channel::mpsc;
+    use gpui::TestAppContext;
+    use project::FakeFs;
+
+    fn cached_model(fs: Arc<FakeFs>) -> (Arc<FakeLanguageModel>, CachingLanguageModel) {
+        fs.set_next_mtime(SystemTime::now());
+        let fake_model = Arc::new(FakeLanguageModel::default());
+        let model = CachingLanguageModel::new(
+            fake_model.clone(),
+            Arc::new(ResponseCache::new(
+                fs,
+                PathBuf::from("/cache"),
+                10,
+                Duration::from_secs(60 * 60),
+            )),
+        );
+        (fake_model, model)
+    }
+
+    fn request(text: &str) -> LanguageModelRequest {
+        LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![text.into()],
+                cache: false,
+                attachments: Vec::new(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[gpui::test]
+    async fn test_cache_hit_skips_model(cx: &mut TestAppContext) {
+        let fs = FakeFs::new(cx.executor());
+        let (fake_model, model) = cached_model(fs);
+
+        let response = model.stream_completion_text(request("Hello"), &cx.to_async());
+        cx.run_until_parked();
+        fake_model.stream_last_completion_response("Hi ".into());
+        fake_model.stream_last_completion_response("there!".into());
+        fake_model.end_last_completion_stream();
+        let text = response
+            .await
+            .unwrap()
+            .map(|chunk| chunk.unwrap())
+            .collect::<String>()
+            .await;
+        assert_eq!(text, "Hi there!");
+
+        let response = model.stream_completion_text(request("Hello"), &cx.to_async());
+        cx.run_until_parked();
+        assert_eq!(fake_model.completion_count(), 0);
+        let text = response
+            .await
+            .unwrap()
+            .map(|chunk| chunk.unwrap())
+            .collect::<String>()
+            .await;
+        assert_eq!(text, "Hi there!");
+
+        let _response = model.stream_completion_text(request("Goodbye"), &cx.to_async());
+        cx.run_until_parked();
+        assert_eq!(fake_model.completion_count(), 1);
+    }
+
+    #[gpui::test]
+    async fn test_failed_stream_is_not_cached(cx: &mut TestAppContext) {
+        let fs = FakeFs::new(cx.executor());
+        fs.set_next_mtime(SystemTime::now());
+        let cache = ResponseCache::new(
+            fs.clone(),
+            PathBuf::from("/cache"),
+            10,
+            Duration::from_secs(60 * 60),
+        );
+        let (tx, rx) = mpsc::unbounded();
+        let events = record_events(rx.boxed(), Arc::new(cache), "key".into());
+
+        tx.unbounded_send(Ok(LanguageModelCompletionEvent::Text("partial".into())))
+            .unwrap();
+        tx.unbounded_send(Err(anyhow!("connection reset"))).unwrap();
+        drop(tx);
+        let results = events.collect::<Vec<_>>().await;
+        assert_eq!(results.len(), 2);
+        assert!(results[1].is_err());
+
+        assert!(!fs.is_file(&PathBuf::from("/cache/key.json")).await);
+    }
+}
@@ -0,0 +1,469 @@
+use crate::{
+    BatchCompletions, LanguageModel, LanguageModelCacheConfiguration, LanguageModelCompletionEvent,
+    LanguageModelId, LanguageModelName, LanguageModelProviderId, LanguageModelProviderName,
+    LanguageModelRequest, LanguageModelRequestMessage, Role, TokenUsage,
+};
+use anyhow::Result;
+use futures::{
+    channel::mpsc,
+    future::{self, BoxFuture},
+    stream::{self, BoxStream},
+    FutureExt, StreamExt,
+};
+use gpui::{AppContext, AsyncAppContext};
+use open_ai::OpenAiError;
+use std::sync::Arc;
+use ui::IconName;
+
+/// How many times an interrupted response is resumed before it's given up on.
+const MAX_RESUME_ATTEMPTS: usize = 2;
+/// The most text at the start of a continuation that's compared with the end of the response,
+/// in case the model repeats what it already wrote.
+const MAX_OVERLAP_LEN: usize = 200;
+/// Shorter overlaps are assumed to be coincidental, and are kept.
+const MIN_OVERLAP_LEN: usize = 3;
+/// Asks the model to continue a response that was cut off, which is sent after it.
+pub const CONTINUE_PROMPT: &str = "Your previous response was cut off. Continue it from exactly \
+    where it stopped, without repeating any of it or commenting on the interruption.";
+
+/// A [`LanguageModel`] that resumes responses that are interrupted partway through.
+///
+/// When a response fails after streaming some text, the model is asked to continue from the
+/// partial response, and the continuation is spliced onto it, minus any text it repeats. If
+/// the response still fails after [`MAX_RESUME_ATTEMPTS`], a
+/// [`LanguageModelCompletionEvent::Incomplete`] is sent before the error. Responses that have
+/// started a tool use aren't resumed, since a tool use can't be continued from the middle.
+pub struct ResumingLanguageModel {
+    model: Arc<dyn LanguageModel>,
+}
+
+impl ResumingLanguageModel {
+    pub fn new(model: Arc<dyn LanguageModel>) -> Self {
+        Self { model }
+    }
+}
+
+/// A response across the streams that were started to resume it.
+struct ResumingStream {
+    model: Arc<dyn LanguageModel>,
+    request: LanguageModelRequest,
+    events: BoxStream<'static, Result<LanguageModelCompletionEvent>>,
+    /// The text of the response so far.
+    response: String,
+    has_tool_use: bool,
+    resume_attempts: usize,
+    /// The usage of the streams before the current one.
+    previous_usage: TokenUsage,
+    /// The usage of the current stream.
+    usage: TokenUsage,
+    /// The start of the current continuation, which is held back until it can be compared with
+    /// the end of the response.
+    continuation_start: Option<String>,
+    done: bool,
+}
+
+impl ResumingStream {
+    fn new(
+        model: Arc<dyn LanguageModel>,
+        request: LanguageModelRequest,
+        events: BoxStream<'static, Result<LanguageModelCompletionEvent>>,
+    ) -> Self {
+        Self {
+            model,
+            request,
+            events,
+            response: String::new(),
+            has_tool_use: false,
+            resume_attempts: 0,
+            previous_usage: TokenUsage::default(),
+            usage: TokenUsage::default(),
+            continuation_start: None,
+            done: false,
+        }
+    }
+
+    /// Returns the next events of the response, resuming it if it's interrupted.
+    async fn next_events(
+        &mut self,
+        cx: &AsyncAppContext,
+    ) -> Option<Vec<Result<LanguageModelCompletionEvent>>> {
+        if self.done {
+            return None;
+        }
+
+        loop {
+            let mut events = Vec::new();
+            match self.events.next().await {
+                Some(Ok(LanguageModelCompletionEvent::Text(text))) => {
+                    if let Some(continuation_start) = self.continuation_start.as_mut() {
+                        continuation_start.push_str(&text);
+                        if continuation_start.len() < MAX_OVERLAP_LEN {
+                            continue;
+                        }
+                        events.extend(self.release_continuation_start());
+                    } else {
+                        self.response.push_str(&text);
+                        events.push(Ok(LanguageModelCompletionEvent::Text(text)));
+                    }
+                }
+                Some(Ok(LanguageModelCompletionEvent::UsageUpdate(usage))) => {
+                    events.extend(self.release_continuation_start());
+                    self.usage = usage;
+                    events.push(Ok(LanguageModelCompletionEvent::UsageUpdate(TokenUsage {
+                        prompt_tokens: self.previous_usage.prompt_tokens + usage.prompt_tokens,
+                        completion_tokens: self.previous_usage.completion_tokens
+                            + usage.completion_tokens,
+                    })));
+                }
+                // Falling back and queueing are only reported before the response starts.
+                Some(Ok(
+                    LanguageModelCompletionEvent::FellBack { .. }
+                    | LanguageModelCompletionEvent::Queued(_),
+                )) if self.resume_attempts > 0 => {}
+                Some(Ok(event)) => {
+                    if let LanguageModelCompletionEvent::ToolUse(_) = event {
+                        self.has_tool_use = true;
+                    }
+                    events.extend(self.release_continuation_start());
+                    events.push(Ok(event));
+                }
+                Some(Err(error)) => {
+                    events.extend(self.release_continuation_start());
+                    if self.response.is_empty()
+                        || self.has_tool_use
+                        || self.resume_attempts == MAX_RESUME_ATTEMPTS
+                        || !can_resume_after(&error)
+                    {
+                        if !self.response.is_empty() {
+                            events.push(Ok(LanguageModelCompletionEvent::Incomplete));
+                        }
+                        events.push(Err(error));
+                        self.done = true;
+                        return Some(events);
+                    }
+
+                    self.resume_attempts += 1;
+                    log::warn!(
+                        "response from {} was interrupted, resuming it (attempt {} of {}): {error}",
+                        self.model.name().0,
+                        self.resume_attempts,
+                        MAX_RESUME_ATTEMPTS
+                    );
+                    self.previous_usage.prompt_tokens += self.usage.prompt_tokens;
+                    self.previous_usage.completion_tokens += self.usage.completion_tokens;
+                    self.usage = TokenUsage::default();
+                    self.continuation_start = Some(String::new());
+                    self.events = match self
+                        .model
+                        .stream_completion(self.continuation_request(), cx)
+                        .await
+                    {
+                        Ok(events) => events,
+                        Err(error) => stream::once(future::ready(Err(error))).boxed(),
+                    };
+                }
+                None => {
+                    events.extend(self.release_continuation_start());
+                    self.done = true;
+                    return (!events.is_empty()).then_some(events);
+                }
+            }
+
+            if !events.is_empty() {
+                return Some(events);
+            }
+        }
+    }
+
+    /// The request to continue the response from where it was interrupted.
+    fn continuation_request(&self) -> LanguageModelRequest {
+        let mut request = self.request.clone();
+        request.messages.push(LanguageModelRequestMessage {
+            role: Role::Assistant,
+            content: vec![self.response.clone().into()],
+            cache: false,
+            attachments: Vec::new(),
+        });
+        request.messages.push(LanguageModelRequestMessage {
+            role: Role::User,
+            content: vec![CONTINUE_PROMPT.into()],
+            cache: false,
+            attachments: Vec::new(),
+        });
+        request
+    }
+
+    /// Passes on the held back start of the continuation, without the text it repeats from the
+    /// end of the response.
+    fn release_continuation_start(&mut self) -> Option<Result<LanguageModelCompletionEvent>> {
+        let continuation_start = self.continuation_start.take()?;
+        let text = &continuation_start[overlap_len(&self.response, &continuation_start)..];
+        if text.is_empty() {
+            return None;
+        }
+        self.response.push_str(text);
+        Some(Ok(LanguageModelCompletionEvent::Text(text.to_string())))
+    }
+}
+
+/// The length of the longest start of the continuation that the response ends with.
+fn overlap_len(response: &str, continuation: &str) -> usize {
+    (MIN_OVERLAP_LEN..=MAX_OVERLAP_LEN.min(continuation.len()))
+        .rev()
+        .find(|&len| continuation.is_char_boundary(len) && response.ends_with(&continuation[..len]))
+        .unwrap_or(0)
+}
+
+/// Whether the model might finish the response if it's asked again. OpenAI's errors are only
+/// retried if they're transient, since a request it rejects is likely to be rejected again.
+fn can_resume_after(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<OpenAiError>()
+        .map_or(true, |error| error.is_retryable())
+}
+
+impl LanguageModel for ResumingLanguageModel {
+    fn id(&self) -> LanguageModelId {
+        self.model.id()
+    }
+
+    fn name(&self) -> LanguageModelName {
+        self.model.name()
+    }
+
+    fn icon(&self) -> Option<IconName> {
+        self.model.icon()
+    }
+
+    fn provider_id(&self) -> LanguageModelProviderId {
+        self.model.provider_id()
+    }
+
+    fn provider_name(&self) -> LanguageModelProviderName {
+        self.model.provider_name()
+    }
+
+    fn telemetry_id(&self) -> String {
+        self.model.telemetry_id()
+    }
+
+    fn availability(&self) -> crate::LanguageModelAvailability {
+        self.model.availability()
+    }
+
+    fn max_token_count(&self) -> usize {
+        self.model.max_token_count()
+    }
+
+    fn max_output_tokens(&self) -> Option<u32> {
+        self.model.max_output_tokens()
+    }
+
+    fn count_tokens(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<usize>> {
+        self.model.count_tokens(request, cx)
+    }
+
+    fn stream_completion(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        let model = self.model.clone();
+        let events = self.model.stream_completion(request.clone(), cx);
+        // Resuming needs the app to start each continuation, so the response is streamed on the
+        // main thread.
+        let task = cx.spawn(|cx| async move {
+            let events = events.await?;
+            let mut stream = ResumingStream::new(model, request, events);
+            let (tx, rx) = mpsc::unbounded();
+            cx.spawn(|cx| async move {
+                while let Some(events) = stream.next_events(&cx).await {
+                    for event in events {
+                        if tx.unbounded_send(event).is_err() {
+                            return;
+                        }
+                    }
+                }
+            })
+            .detach();
+            Ok(rx.boxed())
+        });
+        async move { task.await }.boxed()
+    }
+
+    fn complete_batch(
+        &self,
+        request: LanguageModelRequest,
+        n: usize,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BatchCompletions>> {
+        self.model.complete_batch(request, n, cx)
+    }
+
+    fn use_any_tool(
+        &self,
+        request: LanguageModelRequest,
+        name: String,
+        description: String,
+        schema: serde_json::Value,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        self.model
+            .use_any_tool(request, name, description, schema, cx)
+    }
+
+    fn cache_configuration(&self) -> Option<LanguageModelCacheConfiguration> {
+        self.model.cache_configuration()
+    }
+
+    #[cfg(any(test, feature = "test-support"))]
+    fn as_fake(&self) -> &crate::provider::fake::FakeLanguageModel {
+        self.model.as_fake()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{provider::fake::FakeLanguageModel, StopReason};
+    use anyhow::anyhow;
+    use gpui::TestAppContext;
+
+    fn request() -> LanguageModelRequest {
+        LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec!["Describe the fox.".into()],
+                cache: false,
+                attachments: Vec::new(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[gpui::test]
+    async fn test_resume_interrupted_response(cx: &mut TestAppContext) {
+        let fake_model = Arc::new(FakeLanguageModel::default());
+        let model = ResumingLanguageModel::new(fake_model.clone());
+
+        let events = model.stream_completion(request(), &cx.to_async());
+        cx.run_until_parked();
+        fake_model.stream_last_completion_response("The quick brown fox jumps".into());
+        fake_model.send_last_completion_event(LanguageModelCompletionEvent::UsageUpdate(
+            TokenUsage {
+                prompt_tokens: 10,
+                completion_tokens: 5,
+            },
+        ));
+        fake_model.send_last_completion_error(anyhow!("connection reset"));
+        cx.run_until_parked();
+
+        let continuation_request = fake_model.pending_completions().pop().unwrap();
+        assert_eq!(continuation_request.messages.len(), 3);
+        assert_eq!(continuation_request.messages[1].role, Role::Assistant);
+        assert_eq!(
+            continuation_request.messages[1].string_contents(),
+            "The quick brown fox jumps"
+        );
+        assert_eq!(continuation_request.messages[2].role, Role::User);
+
+        // The continuation repeats the end of the response, which is left out.
+        fake_model.stream_last_completion_response("fox jumps over".into());
+        fake_model.stream_last_completion_response(" the lazy dog.".into());
+        fake_model.send_last_completion_event(LanguageModelCompletionEvent::UsageUpdate(
+            TokenUsage {
+                prompt_tokens: 20,
+                completion_tokens: 4,
+            },
+        ));
+        fake_model
+            .send_last_completion_event(LanguageModelCompletionEvent::Stop(StopReason::EndTurn));
+        fake_model.end_last_completion_stream();
+
+        let events = events
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            events,
+            [
+                LanguageModelCompletionEvent::Text("The quick brown fox jumps".into()),
+                LanguageModelCompletionEvent::UsageUpdate(TokenUsage {
+                    prompt_tokens: 10,
+                    completion_tokens: 5,
+                }),
+                LanguageModelCompletionEvent::Text(" over the lazy dog.".into()),
+                LanguageModelCompletionEvent::UsageUpdate(TokenUsage {
+                    prompt_tokens: 30,
+                    completion_tokens: 9,
+                }),
+                LanguageModelCompletionEvent::Stop(StopReason::EndTurn),
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_resume_attempts_are_capped(cx: &mut TestAppContext) {
+        let fake_model = Arc::new(FakeLanguageModel::default());
+        let model = ResumingLanguageModel::new(fake_model.clone());
+
+        let events = model.stream_completion(request(), &cx.to_async());
+        cx.run_until_parked();
+        fake_model.stream_last_completion_response("The quick".into());
+        for _ in 0..=MAX_RESUME_ATTEMPTS {
+            fake_model.send_last_completion_error(anyhow!("connection reset"));
+            cx.run_until_parked();
+        }
+        assert_eq!(fake_model.completion_count(), 1 + MAX_RESUME_ATTEMPTS);
+
+        let mut events = events.await.unwrap();
+        assert_eq!(
+            events.next().await.unwrap().unwrap(),
+            LanguageModelCompletionEvent::Text("The quick".into())
+        );
+        assert_eq!(
+            events.next().await.unwrap().unwrap(),
+            LanguageModelCompletionEvent::Incomplete
+        );
+        assert_eq!(
+            events.next().await.unwrap().unwrap_err().to_string(),
+            "connection reset"
+        );
+        assert!(events.next().await.is_none());
+    }
+
+    #[gpui::test]
+    async fn test_failures_before_output_are_not_resumed(cx: &mut TestAppContext) {
+        let fake_model = Arc::new(FakeLanguageModel::default());
+        let model = ResumingLanguageModel::new(fake_model.clone());
+
+        let events = model.stream_completion(request(), &cx.to_async());
+        cx.run_until_parked();
+        fake_model.send_last_completion_error(anyhow!("connection refused"));
+        cx.run_until_parked();
+        assert_eq!(fake_model.completion_count(), 1);
+
+        let mut events = events.await.unwrap();
+        assert_eq!(
+            events.next().await.unwrap().unwrap_err().to_string(),
+            "connection refused"
+        );
+        assert!(events.next().await.is_none());
+    }
+
+    #[test]
+    fn test_overlap_len() {
+        assert_eq!(overlap_len("The quick brown fox", "brown fox jumps"), 9);
+        assert_eq!(overlap_len("The quick brown fox", " jumps"), 0);
+        // Overlaps that are too short to be repetition are kept.
+        assert_eq!(overlap_len("one two", "two three"), 3);
+        assert_eq!(overlap_len("The end", "d of it"), 0);
+        assert_eq!(overlap_len("Grüße aus Köln", "Köln, und"), "Köln".len());
+    }
+}
@@ -1,6 +1,7 @@
-use std::{sync::Arc, time::Duration};
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::Result;
+use collections::BTreeMap;
 use gpui::AppContext;
 use project::Fs;
 use schemars::JsonSchema;
@@ -14,6 +15,7 @@ use crate::{
         cloud::{self, ZedDotDevSettings},
         copilot_chat::CopilotChatSettings,
         google::GoogleSettings,
+        local::LocalSettings,
         ollama::OllamaSettings,
         open_ai::OpenAiSettings,
     },
@@ -57,21 +59,55 @@ pub fn init(fs: Arc<dyn Fs>, cx: &mut AppContext) {
 pub struct AllLanguageModelSettings {
     pub anthropic: AnthropicSettings,
     pub ollama: OllamaSettings,
+    pub local: LocalSettings,
     pub openai: OpenAiSettings,
     pub zed_dot_dev: ZedDotDevSettings,
     pub google: GoogleSettings,
     pub copilot_chat: CopilotChatSettings,
+    pub response_cache: ResponseCacheSettings,
+    pub max_concurrent_completions: usize,
+}
+
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct ResponseCacheSettings {
+    pub enabled: bool,
+    pub max_entries: usize,
+    pub max_age: Duration,
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
 pub struct AllLanguageModelSettingsContent {
     pub anthropic: Option<AnthropicSettingsContent>,
     pub ollama: Option<OllamaSettingsContent>,
+    pub local: Option<LocalSettingsContent>,
     pub openai: Option<OpenAiSettingsContent>,
     #[serde(rename = "zed.dev")]
     pub zed_dot_dev: Option<ZedDotDevSettingsContent>,
     pub google: Option<GoogleSettingsContent>,
     pub copilot_chat: Option<CopilotChatSettingsContent>,
+    pub response_cache: Option<ResponseCacheSettingsContent>,
+    /// The maximum number of completions each provider streams at once.
+    /// Further requests are queued until one of them finishes.
+    ///
+    /// Default: 4
+    pub max_concurrent_completions: Option<usize>,
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct ResponseCacheSettingsContent {
+    /// Whether to serve repeated, identical requests from an on-disk cache
+    /// instead of sending them to the model again.
+    ///
+    /// Default: false
+    pub enabled: Option<bool>,
+    /// The maximum number of responses to keep in the cache.
+    ///
+    /// Default: 1000
+    pub max_entries: Option<usize>,
+    /// How long a cached response remains valid.
+    ///
+    /// Default: 168
+    pub max_age_in_hours: Option<u64>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
@@ -157,6 +193,15 @@ pub struct OllamaSettingsContent {
     pub available_models: Option<Vec<provider::ollama::AvailableModel>>,
 }
 
+#[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct LocalSettingsContent {
+    /// The URL of the server's OpenAI-compatible API, such as `http://localhost:1234/v1` for
+    /// LM Studio or `http://localhost:11434/v1` for Ollama.
+    pub api_url: Option<String>,
+    pub low_speed_timeout_in_seconds: Option<u64>,
+    pub available_models: Option<Vec<provider::local::AvailableModel>>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
 #[serde(untagged)]
 pub enum OpenAiSettingsContent {
@@ -171,6 +216,13 @@ impl OpenAiSettingsContent {
                 OpenAiSettingsContentV1 {
                     api_url: content.api_url,
                     low_speed_timeout_in_seconds: content.low_speed_timeout_in_seconds,
+                    max_retries: None,
+                    stream_batch_completions: None,
+                    provider_flavor: None,
+                    organization_id: None,
+                    extra_headers: None,
+                    api_version: None,
+                    api_key_path: None,
                     available_models: content.available_models.map(|models| {
                         models
                             .into_iter()
@@ -220,6 +272,26 @@ pub enum VersionedOpenAiSettingsContent {
 pub struct OpenAiSettingsContentV1 {
     pub api_url: Option<String>,
     pub low_speed_timeout_in_seconds: Option<u64>,
+    /// The maximum number of times a request is retried after a rate limit or
+    /// a transient server error.
+    pub max_retries: Option<usize>,
+    /// Whether a batch of completions is generated by streaming each one in its own request,
+    /// rather than by asking for all of them in a single request. Some servers that speak
+    /// the OpenAI API can only generate one choice per request.
+    pub stream_batch_completions: Option<bool>,
+    /// The dialect of the OpenAI API spoken by `api_url`. Use `azure` for Azure
+    /// OpenAI deployments.
+    pub provider_flavor: Option<open_ai::ApiFlavor>,
+    /// The organization to bill requests to, sent as the `OpenAI-Organization` header.
+    pub organization_id: Option<String>,
+    /// Additional headers to send with every request, e.g. for a proxy in front of the API.
+    pub extra_headers: Option<BTreeMap<String, String>>,
+    /// The Azure OpenAI API version, sent as the `api-version` query parameter when
+    /// `provider_flavor` is `azure`.
+    pub api_version: Option<String>,
+    /// A file containing the API key, read when neither the system keychain nor the
+    /// `OPENAI_API_KEY` environment variable has one.
+    pub api_key_path: Option<PathBuf>,
     pub available_models: Option<Vec<provider::open_ai::AvailableModel>>,
 }
 
@@ -234,6 +306,11 @@ pub struct GoogleSettingsContent {
 pub struct ZedDotDevSettingsContent {
     available_models: Option<Vec<cloud::AvailableModel>>,
     pub low_speed_timeout_in_seconds: Option<u64>,
+    /// Whether completions wait for the connection to zed.dev to be restored when it's lost,
+    /// instead of failing right away.
+    pub queue_while_disconnected: Option<bool>,
+    /// How long a completion waits for the connection to be restored before failing.
+    pub reconnect_timeout_in_seconds: Option<u64>,
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
@@ -304,6 +381,24 @@ impl settings::Settings for AllLanguageModelSettings {
                 ollama.as_ref().and_then(|s| s.available_models.clone()),
             );
 
+            // Local
+            let local = value.local.clone();
+
+            merge(
+                &mut settings.local.api_url,
+                local.as_ref().and_then(|s| s.api_url.clone()),
+            );
+            if let Some(low_speed_timeout_in_seconds) =
+                local.as_ref().and_then(|s| s.low_speed_timeout_in_seconds)
+            {
+                settings.local.low_speed_timeout =
+                    Some(Duration::from_secs(low_speed_timeout_in_seconds));
+            }
+            merge(
+                &mut settings.local.available_models,
+                local.as_ref().and_then(|s| s.available_models.clone()),
+            );
+
             // OpenAI
             let (openai, upgraded) = match value.openai.clone().map(|s| s.upgrade()) {
                 Some((content, upgraded)) => (Some(content), upgraded),
@@ -328,6 +423,29 @@ impl settings::Settings for AllLanguageModelSettings {
                 &mut settings.openai.available_models,
                 openai.as_ref().and_then(|s| s.available_models.clone()),
             );
+            if let Some(max_retries) = openai.as_ref().and_then(|s| s.max_retries) {
+                settings.openai.max_retries = max_retries;
+            }
+            merge(
+                &mut settings.openai.stream_batch_completions,
+                openai.as_ref().and_then(|s| s.stream_batch_completions),
+            );
+            merge(
+                &mut settings.openai.provider_flavor,
+                openai.as_ref().and_then(|s| s.provider_flavor),
+            );
+            if let Some(organization_id) = openai.as_ref().and_then(|s| s.organization_id.clone()) {
+                settings.openai.organization_id = Some(organization_id);
+            }
+            if let Some(extra_headers) = openai.as_ref().and_then(|s| s.extra_headers.clone()) {
+                settings.openai.extra_headers.extend(extra_headers);
+            }
+            if let Some(api_version) = openai.as_ref().and_then(|s| s.api_version.clone()) {
+                settings.openai.api_version = Some(api_version);
+            }
+            if let Some(api_key_path) = openai.as_ref().and_then(|s| s.api_key_path.clone()) {
+                settings.openai.api_key_path = Some(api_key_path);
+            }
 
             merge(
                 &mut settings.zed_dot_dev.available_models,
@@ -344,6 +462,21 @@ impl settings::Settings for AllLanguageModelSettings {
                 settings.zed_dot_dev.low_speed_timeout =
                     Some(Duration::from_secs(low_speed_timeout_in_seconds));
             }
+            merge(
+                &mut settings.zed_dot_dev.queue_while_disconnected,
+                value
+                    .zed_dot_dev
+                    .as_ref()
+                    .and_then(|s| s.queue_while_disconnected),
+            );
+            if let Some(reconnect_timeout_in_seconds) = value
+                .zed_dot_dev
+                .as_ref()
+                .and_then(|s| s.reconnect_timeout_in_seconds)
+            {
+                settings.zed_dot_dev.reconnect_timeout =
+                    Duration::from_secs(reconnect_timeout_in_seconds);
+            }
 
             merge(
                 &mut settings.google.api_url,
@@ -373,6 +506,25 @@ impl settings::Settings for AllLanguageModelSettings {
                 settings.copilot_chat.low_speed_timeout =
                     Some(Duration::from_secs(low_speed_timeout));
             }
+
+            // Response cache
+            let response_cache = value.response_cache.as_ref();
+            merge(
+                &mut settings.response_cache.enabled,
+                response_cache.and_then(|s| s.enabled),
+            );
+            merge(
+                &mut settings.response_cache.max_entries,
+                response_cache.and_then(|s| s.max_entries),
+            );
+            if let Some(max_age_in_hours) = response_cache.and_then(|s| s.max_age_in_hours) {
+                settings.response_cache.max_age = Duration::from_secs(max_age_in_hours * 60 * 60);
+            }
+
+            merge(
+                &mut settings.max_concurrent_completions,
+                value.max_concurrent_completions,
+            );
         }
 
         Ok(settings)
@@ -0,0 +1,367 @@
+use anyhow::Result;
+use futures::{stream::BoxStream, Stream, StreamExt};
+use std::ops::ControlFlow;
+
+const CODE_FENCE: &str = "```";
+
+/// Rewrites a streamed completion as it arrives.
+///
+/// Transforms see the response in arbitrary chunks, so they hold back any text whose fate
+/// depends on text that hasn't arrived yet.
+pub trait StreamTransform: Send {
+    /// Processes the next chunk of the response, appending the text to pass on to `output`.
+    ///
+    /// Returning [`ControlFlow::Break`] ends the response, after which the transform isn't
+    /// called again.
+    fn push(&mut self, chunk: &str, output: &mut String) -> ControlFlow<()>;
+
+    /// Appends any text that's still held back to `output`, once the response has ended.
+    fn finish(&mut self, output: &mut String);
+}
+
+/// Applies the transforms, in order, to a stream of completion text.
+pub fn apply_transforms(
+    chunks: impl Stream<Item = Result<String>> + Send + 'static,
+    transforms: Vec<Box<dyn StreamTransform>>,
+) -> BoxStream<'static, Result<String>> {
+    let state = Some((Pipeline { transforms }, chunks.boxed()));
+    futures::stream::unfold(state, |state| async move {
+        let (mut pipeline, mut chunks) = state?;
+        loop {
+            match chunks.next().await {
+                Some(Ok(chunk)) => match pipeline.push(chunk) {
+                    ControlFlow::Continue(text) if text.is_empty() => {}
+                    ControlFlow::Continue(text) => {
+                        return Some((Ok(text), Some((pipeline, chunks))));
+                    }
+                    ControlFlow::Break(text) => {
+                        return (!text.is_empty()).then(|| (Ok(text), None));
+                    }
+                },
+                Some(Err(error)) => return Some((Err(error), None)),
+                None => {
+                    let text = pipeline.finish_from(0, String::new());
+                    return (!text.is_empty()).then(|| (Ok(text), None));
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+struct Pipeline {
+    transforms: Vec<Box<dyn StreamTransform>>,
+}
+
+impl Pipeline {
+    /// Passes the chunk through every transform, breaking with the remaining text if one of them
+    /// ends the response.
+    fn push(&mut self, chunk: String) -> ControlFlow<String, String> {
+        let mut text = chunk;
+        for ix in 0..self.transforms.len() {
+            let mut output = String::new();
+            if self.transforms[ix].push(&text, &mut output).is_break() {
+                return ControlFlow::Break(self.finish_from(ix + 1, output));
+            }
+            text = output;
+        }
+        ControlFlow::Continue(text)
+    }
+
+    /// Passes the text through the transforms starting at `start`, finishing each in turn.
+    fn finish_from(&mut self, start: usize, mut text: String) -> String {
+        for transform in &mut self.transforms[start..] {
+            let mut output = String::new();
+            if transform.push(&text, &mut output).is_continue() {
+                transform.finish(&mut output);
+            }
+            text = output;
+        }
+        text
+    }
+}
+
+/// Removes the opening and closing fence lines when the response is a single fenced code block.
+#[derive(Default)]
+pub struct StripCodeFences {
+    state: StripCodeFencesState,
+}
+
+#[derive(Default)]
+enum StripCodeFencesState {
+    /// The first line hasn't been seen in full, so it's unknown whether it opens a code block.
+    #[default]
+    FirstLine {
+        buffer: String,
+    },
+    /// The response opened a code block, and `held` is a trailing fragment that may yet turn out
+    /// to be the closing fence.
+    Fenced {
+        held: String,
+        emitted_any: bool,
+    },
+    PassThrough,
+}
+
+impl StripCodeFences {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StreamTransform for StripCodeFences {
+    fn push(&mut self, chunk: &str, output: &mut String) -> ControlFlow<()> {
+        match &mut self.state {
+            StripCodeFencesState::FirstLine { buffer } => {
+                buffer.push_str(chunk);
+                let leading_whitespace = buffer.len() - buffer.trim_start().len();
+                let first_line = &buffer[leading_whitespace..];
+                if !could_be_fence(first_line.split('\n').next().unwrap_or_default()) {
+                    output.push_str(buffer);
+                    self.state = StripCodeFencesState::PassThrough;
+                } else if let Some(newline_ix) = first_line.find('\n') {
+                    let rest = first_line[newline_ix + 1..].to_string();
+                    self.state = StripCodeFencesState::Fenced {
+                        held: String::new(),
+                        emitted_any: false,
+                    };
+                    return self.push(&rest, output);
+                }
+            }
+            StripCodeFencesState::Fenced { held, emitted_any } => {
+                held.push_str(chunk);
+                let closing_fence_start = (!*emitted_any)
+                    .then_some(0)
+                    .into_iter()
+                    .chain(held.match_indices('\n').map(|(ix, _)| ix))
+                    .find(|&start| could_be_closing_fence(&held[start..]))
+                    .unwrap_or(held.len());
+                if closing_fence_start > 0 {
+                    output.extend(held.drain(..closing_fence_start));
+                    *emitted_any = true;
+                }
+            }
+            StripCodeFencesState::PassThrough => output.push_str(chunk),
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn finish(&mut self, output: &mut String) {
+        match &mut self.state {
+            StripCodeFencesState::FirstLine { buffer } => output.push_str(buffer),
+            StripCodeFencesState::Fenced { held, .. } => {
+                if held.trim() != CODE_FENCE {
+                    output.push_str(held);
+                }
+            }
+            StripCodeFencesState::PassThrough => {}
+        }
+    }
+}
+
+/// Returns whether the line is, or could still become, a code fence.
+fn could_be_fence(line: &str) -> bool {
+    line.starts_with(CODE_FENCE) || CODE_FENCE.starts_with(line)
+}
+
+/// Returns whether the text, which starts a line or is preceded by a newline, could still turn out
+/// to be a closing fence at the very end of the response.
+fn could_be_closing_fence(text: &str) -> bool {
+    let text = text
+        .trim_start_matches('\n')
+        .trim_start_matches([' ', '\t']);
+    match text.strip_prefix(CODE_FENCE) {
+        Some(rest) => rest.trim().is_empty(),
+        None => CODE_FENCE.starts_with(text),
+    }
+}
+
+/// Ends the response just before the first occurrence of any of the sequences.
+pub struct StopAtSequence {
+    sequences: Vec<String>,
+    held: String,
+}
+
+impl StopAtSequence {
+    pub fn new(sequences: Vec<String>) -> Self {
+        Self {
+            sequences: sequences
+                .into_iter()
+                .filter(|sequence| !sequence.is_empty())
+                .collect(),
+            held: String::new(),
+        }
+    }
+}
+
+impl StreamTransform for StopAtSequence {
+    fn push(&mut self, chunk: &str, output: &mut String) -> ControlFlow<()> {
+        self.held.push_str(chunk);
+        if let Some(stop_ix) = self
+            .sequences
+            .iter()
+            .filter_map(|sequence| self.held.find(sequence.as_str()))
+            .min()
+        {
+            output.push_str(&self.held[..stop_ix]);
+            self.held.clear();
+            return ControlFlow::Break(());
+        }
+
+        // Hold back the longest suffix that a later chunk could complete into a sequence.
+        let held_len = self
+            .sequences
+            .iter()
+            .flat_map(|sequence| {
+                sequence
+                    .char_indices()
+                    .skip(1)
+                    .map(|(ix, _)| &sequence[..ix])
+                    .filter(|prefix| self.held.ends_with(prefix))
+                    .map(|prefix| prefix.len())
+            })
+            .max()
+            .unwrap_or(0);
+        let emit_len = self.held.len() - held_len;
+        output.extend(self.held.drain(..emit_len));
+        ControlFlow::Continue(())
+    }
+
+    fn finish(&mut self, output: &mut String) {
+        output.push_str(&self.held);
+        self.held.clear();
+    }
+}
+
+/// Removes whitespace from the end of the response.
+#[derive(Default)]
+pub struct TrimTrailingWhitespace {
+    held: String,
+}
+
+impl TrimTrailingWhitespace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StreamTransform for TrimTrailingWhitespace {
+    fn push(&mut self, chunk: &str, output: &mut String) -> ControlFlow<()> {
+        self.held.push_str(chunk);
+        let emit_len = self.held.trim_end().len();
+        output.extend(self.held.drain(..emit_len));
+        ControlFlow::Continue(())
+    }
+
+    fn finish(&mut self, _output: &mut String) {
+        self.held.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{stream, FutureExt};
+
+    fn transform(chunks: &[&str], transforms: Vec<Box<dyn StreamTransform>>) -> Vec<String> {
+        let chunks = chunks
+            .iter()
+            .map(|chunk| Ok(chunk.to_string()))
+            .collect::<Vec<_>>();
+        apply_transforms(stream::iter(chunks), transforms)
+            .map(|chunk| chunk.unwrap())
+            .collect::<Vec<_>>()
+            .now_or_never()
+            .unwrap()
+    }
+
+    fn strip_code_fences(chunks: &[&str]) -> String {
+        transform(chunks, vec![Box::new(StripCodeFences::new())]).concat()
+    }
+
+    #[test]
+    fn test_strip_code_fences() {
+        // The closing backticks arrive in the final chunk.
+        assert_eq!(
+            strip_code_fences(&["```rust\nfn main() {\n", "}\n", "``", "`"]),
+            "fn main() {\n}"
+        );
+        assert_eq!(strip_code_fences(&["``", "`\nls -la\n```\n\n"]), "ls -la");
+        assert_eq!(strip_code_fences(&["```\n```"]), "");
+
+        // Responses that aren't a single code block are left alone.
+        assert_eq!(
+            strip_code_fences(&["Run `ls`:\n```\nls\n```"]),
+            "Run `ls`:\n```\nls\n```"
+        );
+        assert_eq!(
+            strip_code_fences(&["```\nls\n```\n", "Lists files."]),
+            "ls\n```\nLists files."
+        );
+        assert_eq!(strip_code_fences(&["```\nls\n``"]), "ls\n``");
+
+        // Text is passed on as soon as it can't be part of a fence.
+        assert_eq!(
+            transform(&["So", "rt"], vec![Box::new(StripCodeFences::new())]),
+            ["So", "rt"]
+        );
+        assert_eq!(
+            transform(
+                &["```sh\nls\n", "pwd\n", "```"],
+                vec![Box::new(StripCodeFences::new())]
+            ),
+            ["ls", "\npwd"]
+        );
+    }
+
+    #[test]
+    fn test_stop_at_sequence() {
+        let stop = || -> Vec<Box<dyn StreamTransform>> {
+            vec![Box::new(StopAtSequence::new(vec![
+                "<|end|>".into(),
+                "\n\n\n".into(),
+            ]))]
+        };
+
+        // The stop sequence straddles two chunks.
+        assert_eq!(
+            transform(&["Hello <|e", "nd|> world", "!"], stop()),
+            ["Hello "]
+        );
+        assert_eq!(transform(&["Hello\n", "\n", "\nworld"], stop()), ["Hello"]);
+        // A partial match that isn't completed is passed on.
+        assert_eq!(
+            transform(&["a <|e", "xample", " <|"], stop()),
+            ["a ", "<|example", " ", "<|"]
+        );
+    }
+
+    #[test]
+    fn test_trim_trailing_whitespace() {
+        assert_eq!(
+            transform(
+                &["a  ", "b \n", "\n "],
+                vec![Box::new(TrimTrailingWhitespace::new())]
+            ),
+            ["a", "  b"]
+        );
+    }
+
+    #[test]
+    fn test_apply_transforms_in_order() {
+        // Text held back by a transform that ends the response still reaches later transforms.
+        let transforms: Vec<Box<dyn StreamTransform>> = vec![
+            Box::new(StripCodeFences::new()),
+            Box::new(StopAtSequence::new(vec!["STOP".into()])),
+            Box::new(TrimTrailingWhitespace::new()),
+        ];
+        assert_eq!(
+            transform(
+                &["```\necho 1 \n", "echo 2  ST", "OP\necho 3\n```"],
+                transforms
+            )
+            .concat(),
+            "echo 1 \necho 2"
+        );
+    }
+}
@@ -0,0 +1,398 @@
+use crate::{
+    BatchCompletions, LanguageModel, LanguageModelCacheConfiguration, LanguageModelCompletionEvent,
+    LanguageModelId, LanguageModelName, LanguageModelProviderId, LanguageModelProviderName,
+    LanguageModelRequest, LanguageModelRequestMessage, MessageContent, Role, TokenUsage,
+};
+use anyhow::Result;
+use collections::BTreeMap;
+use futures::{
+    channel::oneshot,
+    future::{self, BoxFuture},
+    stream::{self, BoxStream},
+    FutureExt, StreamExt,
+};
+use gpui::{AppContext, AsyncAppContext};
+use parking_lot::Mutex;
+use std::{mem, sync::Arc};
+use ui::IconName;
+use util::ResultExt;
+
+/// The tokens used by completions since startup, in total and per model.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UsageSinceStartup {
+    pub total: TokenUsage,
+    pub by_model: BTreeMap<LanguageModelId, TokenUsage>,
+}
+
+/// Accumulates the usage reported by [`MeteredLanguageModel`]s.
+#[derive(Clone, Default)]
+pub struct UsageMeter(Arc<Mutex<UsageSinceStartup>>);
+
+impl UsageMeter {
+    pub fn usage_since_startup(&self) -> UsageSinceStartup {
+        self.0.lock().clone()
+    }
+
+    fn record(&self, model: &LanguageModelId, usage: TokenUsage) {
+        let mut totals = self.0.lock();
+        add_usage(&mut totals.total, usage);
+        add_usage(totals.by_model.entry(model.clone()).or_default(), usage);
+    }
+}
+
+fn add_usage(target: &mut TokenUsage, usage: TokenUsage) {
+    target.prompt_tokens += usage.prompt_tokens;
+    target.completion_tokens += usage.completion_tokens;
+}
+
+/// A [`LanguageModel`] that records the usage reported by the wrapped model's
+/// completions in a [`UsageMeter`].
+///
+/// Usage is recorded as it is reported, so a stream that fails partway
+/// through still counts the tokens reported before the failure. When a stream
+/// reports no usage at all, it is counted with the model's tokenizer from the
+/// request and the streamed completion once the stream ends.
+pub struct MeteredLanguageModel {
+    model: Arc<dyn LanguageModel>,
+    meter: UsageMeter,
+}
+
+impl MeteredLanguageModel {
+    pub fn new(model: Arc<dyn LanguageModel>, meter: UsageMeter) -> Self {
+        Self { model, meter }
+    }
+}
+
+impl LanguageModel for MeteredLanguageModel {
+    fn id(&self) -> LanguageModelId {
+        self.model.id()
+    }
+
+    fn name(&self) -> LanguageModelName {
+        self.model.name()
+    }
+
+    fn icon(&self) -> Option<IconName> {
+        self.model.icon()
+    }
+
+    fn provider_id(&self) -> LanguageModelProviderId {
+        self.model.provider_id()
+    }
+
+    fn provider_name(&self) -> LanguageModelProviderName {
+        self.model.provider_name()
+    }
+
+    fn telemetry_id(&self) -> String {
+        self.model.telemetry_id()
+    }
+
+    fn availability(&self) -> crate::LanguageModelAvailability {
+        self.model.availability()
+    }
+
+    fn max_token_count(&self) -> usize {
+        self.model.max_token_count()
+    }
+
+    fn max_output_tokens(&self) -> Option<u32> {
+        self.model.max_output_tokens()
+    }
+
+    fn count_tokens(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<usize>> {
+        self.model.count_tokens(request, cx)
+    }
+
+    fn stream_completion(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        let model_id = self.model.id();
+        let meter = self.meter.clone();
+        let streamed = Arc::new(Mutex::new(StreamedCompletion::default()));
+        let (ended_tx, ended_rx) = oneshot::channel::<()>();
+        cx.spawn({
+            let model = self.model.clone();
+            let model_id = model_id.clone();
+            let meter = meter.clone();
+            let request = request.clone();
+            let streamed = streamed.clone();
+            |cx| async move {
+                // The sender is dropped along with a stream that is dropped before it ends.
+                ended_rx.await.ok();
+                let streamed = mem::take(&mut *streamed.lock());
+                if streamed.started && streamed.reported.is_none() {
+                    if let Some(usage) = count_usage(&model, request, streamed.completion, &cx)
+                        .await
+                        .log_err()
+                    {
+                        meter.record(&model_id, usage);
+                    }
+                }
+            }
+        })
+        .detach();
+
+        let events = self.model.stream_completion(request, cx);
+        async move {
+            let events = events.await?;
+            streamed.lock().started = true;
+            let ended = stream::once(async move {
+                ended_tx.send(()).ok();
+            })
+            .filter_map(|()| future::ready(None));
+            Ok(events
+                .inspect(move |event| {
+                    let mut streamed = streamed.lock();
+                    match event {
+                        // Each update reports the usage of the whole completion so far, so
+                        // only record what has been added since the previous update.
+                        Ok(LanguageModelCompletionEvent::UsageUpdate(usage)) => {
+                            let reported = streamed.reported.unwrap_or_default();
+                            meter.record(
+                                &model_id,
+                                TokenUsage {
+                                    prompt_tokens: usage
+                                        .prompt_tokens
+                                        .saturating_sub(reported.prompt_tokens),
+                                    completion_tokens: usage
+                                        .completion_tokens
+                                        .saturating_sub(reported.completion_tokens),
+                                },
+                            );
+                            streamed.reported = Some(*usage);
+                        }
+                        Ok(LanguageModelCompletionEvent::Text(text)) => {
+                            streamed.completion.push_str(text)
+                        }
+                        Ok(LanguageModelCompletionEvent::ToolUse(tool_use)) => {
+                            streamed.completion.push_str(&tool_use.input.to_string())
+                        }
+                        _ => {}
+                    }
+                })
+                .chain(ended)
+                .boxed())
+        }
+        .boxed()
+    }
+
+    fn complete_batch(
+        &self,
+        request: LanguageModelRequest,
+        n: usize,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BatchCompletions>> {
+        let model_id = self.model.id();
+        let meter = self.meter.clone();
+        let batch = self.model.complete_batch(request, n, cx);
+        async move {
+            let batch = batch.await?;
+            if let Some(usage) = batch.usage {
+                meter.record(&model_id, usage);
+            }
+            Ok(batch)
+        }
+        .boxed()
+    }
+
+    fn use_any_tool(
+        &self,
+        request: LanguageModelRequest,
+        name: String,
+        description: String,
+        schema: serde_json::Value,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        self.model
+            .use_any_tool(request, name, description, schema, cx)
+    }
+
+    fn cache_configuration(&self) -> Option<LanguageModelCacheConfiguration> {
+        self.model.cache_configuration()
+    }
+
+    #[cfg(any(test, feature = "test-support"))]
+    fn as_fake(&self) -> &crate::provider::fake::FakeLanguageModel {
+        self.model.as_fake()
+    }
+}
+
+/// What a [`MeteredLanguageModel`] has seen of a completion's stream.
+#[derive(Default)]
+struct StreamedCompletion {
+    /// Whether the stream was started, as opposed to the request failing outright.
+    started: bool,
+    /// The latest usage reported by the stream.
+    reported: Option<TokenUsage>,
+    /// The text and tool inputs streamed so far.
+    completion: String,
+}
+
+/// Counts the usage of a completion with the model's tokenizer, for providers that don't
+/// report it.
+async fn count_usage(
+    model: &Arc<dyn LanguageModel>,
+    request: LanguageModelRequest,
+    completion: String,
+    cx: &AsyncAppContext,
+) -> Result<TokenUsage> {
+    let completion = LanguageModelRequest {
+        messages: vec![LanguageModelRequestMessage {
+            role: Role::Assistant,
+            content: vec![MessageContent::Text(completion)],
+            cache: false,
+            attachments: Vec::new(),
+        }],
+        ..Default::default()
+    };
+    let (prompt_tokens, completion_tokens) = cx.update(|cx| {
+        (
+            model.count_tokens(request, cx),
+            model.count_tokens(completion, cx),
+        )
+    })?;
+    Ok(TokenUsage {
+        prompt_tokens: prompt_tokens.await? as u32,
+        completion_tokens: completion_tokens.await? as u32,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::fake::{self, FakeLanguageModel};
+    use anyhow::anyhow;
+    use gpui::TestAppContext;
+
+    #[gpui::test]
+    async fn test_usage_is_accumulated(cx: &mut TestAppContext) {
+        let fake_model = Arc::new(FakeLanguageModel::default());
+        let meter = UsageMeter::default();
+        let model = MeteredLanguageModel::new(fake_model.clone(), meter.clone());
+
+        let events = model.stream_completion(LanguageModelRequest::default(), &cx.to_async());
+        cx.run_until_parked();
+        fake_model.stream_last_completion_response("Hello".into());
+        fake_model.send_last_completion_event(LanguageModelCompletionEvent::UsageUpdate(
+            TokenUsage {
+                prompt_tokens: 10,
+                completion_tokens: 1,
+            },
+        ));
+        fake_model.send_last_completion_event(LanguageModelCompletionEvent::UsageUpdate(
+            TokenUsage {
+                prompt_tokens: 10,
+                completion_tokens: 5,
+            },
+        ));
+        fake_model.end_last_completion_stream();
+        events.await.unwrap().collect::<Vec<_>>().await;
+
+        // A stream that fails still counts the usage reported before the failure.
+        let events = model.stream_completion(LanguageModelRequest::default(), &cx.to_async());
+        cx.run_until_parked();
+        fake_model.send_last_completion_event(LanguageModelCompletionEvent::UsageUpdate(
+            TokenUsage {
+                prompt_tokens: 20,
+                completion_tokens: 3,
+            },
+        ));
+        fake_model.send_last_completion_error(anyhow!("connection reset"));
+        fake_model.end_last_completion_stream();
+        let results = events.await.unwrap().collect::<Vec<_>>().await;
+        assert!(results.last().unwrap().is_err());
+
+        let expected = TokenUsage {
+            prompt_tokens: 30,
+            completion_tokens: 8,
+        };
+        assert_eq!(
+            meter.usage_since_startup(),
+            UsageSinceStartup {
+                total: expected,
+                by_model: BTreeMap::from_iter([(fake::language_model_id(), expected)]),
+            }
+        );
+    }
+
+    #[gpui::test]
+    async fn test_usage_is_counted_when_not_reported(cx: &mut TestAppContext) {
+        let fake_model = Arc::new(FakeLanguageModel::default());
+        let meter = UsageMeter::default();
+        let model = MeteredLanguageModel::new(fake_model.clone(), meter.clone());
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Text("one two three".into())],
+                cache: false,
+                attachments: Vec::new(),
+            }],
+            ..Default::default()
+        };
+
+        // The fake model counts each word as a token.
+        let events = model.stream_completion(request.clone(), &cx.to_async());
+        cx.run_until_parked();
+        fake_model.stream_last_completion_response("Hello there".into());
+        fake_model.end_last_completion_stream();
+        events.await.unwrap().collect::<Vec<_>>().await;
+        cx.run_until_parked();
+        let expected = TokenUsage {
+            prompt_tokens: 3,
+            completion_tokens: 2,
+        };
+        assert_eq!(meter.usage_since_startup().total, expected);
+
+        // A stream that is dropped partway through counts what was streamed before.
+        let events = model.stream_completion(request, &cx.to_async());
+        cx.run_until_parked();
+        fake_model.stream_last_completion_response("General Kenobi".into());
+        let mut events = events.await.unwrap();
+        events.next().await.unwrap().unwrap();
+        drop(events);
+        cx.run_until_parked();
+        assert_eq!(
+            meter.usage_since_startup().total,
+            TokenUsage {
+                prompt_tokens: 6,
+                completion_tokens: 4,
+            }
+        );
+    }
+
+    #[gpui::test]
+    async fn test_batch_usage_is_accumulated(cx: &mut TestAppContext) {
+        let fake_model = Arc::new(FakeLanguageModel::default());
+        let meter = UsageMeter::default();
+        let model = MeteredLanguageModel::new(fake_model.clone(), meter.clone());
+
+        let batch = model.complete_batch(LanguageModelRequest::default(), 2, &cx.to_async());
+        cx.run_until_parked();
+        for _ in 0..2 {
+            fake_model.send_nth_completion_event(
+                0,
+                LanguageModelCompletionEvent::UsageUpdate(TokenUsage {
+                    prompt_tokens: 10,
+                    completion_tokens: 2,
+                }),
+            );
+            fake_model.end_nth_completion_stream(0);
+        }
+        batch.await.unwrap();
+
+        assert_eq!(
+            meter.usage_since_startup().total,
+            TokenUsage {
+                prompt_tokens: 20,
+                completion_tokens: 4,
+            }
+        );
+    }
+}
@@ -6,22 +6,102 @@ use futures::{
     stream::{self, BoxStream},
     AsyncBufReadExt, AsyncReadExt, Stream, StreamExt,
 };
-use http_client::{AsyncBody, HttpClient, Method, Request as HttpRequest};
+use http_client::{
+    AsyncBody, HttpClient, Method, Request as HttpRequest, Response as HttpResponse, StatusCode,
+};
 use isahc::config::Configurable;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::{
+    collections::BTreeMap,
     convert::TryFrom,
     future::{self, Future},
     pin::Pin,
     time::Duration,
 };
 use strum::EnumIter;
+use thiserror::Error;
 
 pub use supported_countries::*;
 
 pub const OPEN_AI_API_URL: &str = "https://api.openai.com/v1";
 
+/// The dialect of the OpenAI API spoken by an endpoint.
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiFlavor {
+    /// Authenticates with an `Authorization: Bearer <key>` header.
+    #[default]
+    OpenAi,
+    /// Authenticates with an `api-key: <key>` header, as Azure OpenAI expects.
+    Azure,
+    /// Sends no credentials, as servers running models locally, such as LM Studio or
+    /// llama.cpp, expect.
+    Local,
+}
+
+/// How to talk to an OpenAI-compatible endpoint, such as an Azure deployment or a
+/// corporate proxy.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ApiOptions {
+    pub flavor: ApiFlavor,
+    /// Sent as the `OpenAI-Organization` header.
+    pub organization_id: Option<String>,
+    /// Additional headers to send with every request.
+    pub extra_headers: BTreeMap<String, String>,
+    /// Sent as the `api-version` query parameter to Azure deployments, defaulting to
+    /// [`AZURE_API_VERSION`]. Other flavors ignore it.
+    pub api_version: Option<String>,
+}
+
+/// The Azure OpenAI API version requested when [`ApiOptions::api_version`] isn't set.
+pub const AZURE_API_VERSION: &str = "2024-06-01";
+
+/// The URI of `path` under `api_url`, with the `api-version` query parameter Azure requires.
+fn endpoint_uri(api_url: &str, path: &str, options: &ApiOptions) -> String {
+    match options.flavor {
+        ApiFlavor::Azure => {
+            let api_version = options.api_version.as_deref().unwrap_or(AZURE_API_VERSION);
+            format!("{api_url}/{path}?api-version={api_version}")
+        }
+        ApiFlavor::OpenAi | ApiFlavor::Local => format!("{api_url}/{path}"),
+    }
+}
+
+/// The URI listing the models served at `api_url`. Azure serves the listing for the whole
+/// resource rather than for each deployment, so a deployment's URL is cut back to the resource.
+fn models_uri(api_url: &str, options: &ApiOptions) -> String {
+    let api_url = match options.flavor {
+        ApiFlavor::Azure => api_url
+            .split_once("/deployments/")
+            .map_or(api_url, |(resource_url, _)| resource_url),
+        ApiFlavor::OpenAi | ApiFlavor::Local => api_url,
+    };
+    endpoint_uri(api_url, "models", options)
+}
+
+fn request_builder(
+    method: Method,
+    uri: String,
+    api_key: &str,
+    options: &ApiOptions,
+) -> http_client::http::request::Builder {
+    let mut request_builder = HttpRequest::builder().method(method).uri(uri);
+    request_builder = match options.flavor {
+        ApiFlavor::OpenAi => request_builder.header("Authorization", format!("Bearer {api_key}")),
+        ApiFlavor::Azure => request_builder.header("api-key", api_key),
+        ApiFlavor::Local => request_builder,
+    };
+    if let Some(organization_id) = &options.organization_id {
+        request_builder = request_builder.header("OpenAI-Organization", organization_id);
+    }
+    for (name, value) in &options.extra_headers {
+        request_builder = request_builder.header(name, value);
+    }
+    request_builder
+}
+
 fn is_none_or_empty<T: AsRef<[U]>, U>(opt: &Option<T>) -> bool {
     opt.as_ref().map_or(true, |v| v.as_ref().is_empty())
 }
@@ -155,23 +235,47 @@ impl Model {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Request {
     pub model: String,
     pub messages: Vec<RequestMessage>,
     pub stream: bool,
+    /// How many alternative choices to generate for the same messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub max_tokens: Option<u32>,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub stop: Vec<String>,
-    pub temperature: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub tool_choice: Option<ToolChoice>,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub tools: Vec<ToolDefinition>,
+    /// Only sent to OpenAI itself, since other servers may reject it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// Constrains the response to a valid JSON object. The messages must mention JSON.
+    JsonObject,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StreamOptions {
+    /// Whether to send a final chunk reporting the token usage of the whole request.
+    pub include_usage: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum ToolChoice {
     Auto,
@@ -194,7 +298,7 @@ pub struct FunctionDefinition {
     pub parameters: Option<Value>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 #[serde(tag = "role", rename_all = "lowercase")]
 pub enum RequestMessage {
     Assistant {
@@ -214,26 +318,26 @@ pub enum RequestMessage {
     },
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct ToolCall {
     pub id: String,
     #[serde(flatten)]
     pub content: ToolCallContent,
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum ToolCallContent {
     Function { function: FunctionContent },
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct FunctionContent {
     pub name: String,
     pub arguments: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Default, Eq, PartialEq)]
 pub struct ResponseMessageDelta {
     pub role: Option<Role>,
     pub content: Option<String>,
@@ -267,7 +371,10 @@ pub struct Usage {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChoiceDelta {
+    // Some local servers omit the index, as they only ever stream a single choice.
+    #[serde(default)]
     pub index: u32,
+    #[serde(default)]
     pub delta: ResponseMessageDelta,
     pub finish_reason: Option<String>,
 }
@@ -281,8 +388,12 @@ pub enum ResponseStreamResult {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ResponseStreamEvent {
+    // Azure omits these from some events, such as its content filter results.
+    #[serde(default)]
     pub created: u32,
+    #[serde(default)]
     pub model: String,
+    #[serde(default)]
     pub choices: Vec<ChoiceDelta>,
     pub usage: Option<Usage>,
 }
@@ -308,15 +419,13 @@ pub async fn complete(
     client: &dyn HttpClient,
     api_url: &str,
     api_key: &str,
+    options: &ApiOptions,
     request: Request,
     low_speed_timeout: Option<Duration>,
 ) -> Result<Response> {
-    let uri = format!("{api_url}/chat/completions");
-    let mut request_builder = HttpRequest::builder()
-        .method(Method::POST)
-        .uri(uri)
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key));
+    let uri = endpoint_uri(api_url, "chat/completions", options);
+    let mut request_builder = request_builder(Method::POST, uri, api_key, options)
+        .header("Content-Type", "application/json");
     if let Some(low_speed_timeout) = low_speed_timeout {
         request_builder = request_builder.low_speed_timeout(100, low_speed_timeout);
     };
@@ -325,7 +434,10 @@ pub async fn complete(
     request_body.stream = false;
 
     let request = request_builder.body(AsyncBody::from(serde_json::to_string(&request_body)?))?;
-    let mut response = client.send(request).await?;
+    let mut response = client
+        .send(request)
+        .await
+        .map_err(OpenAiError::Connection)?;
 
     if response.status().is_success() {
         let mut body = String::new();
@@ -333,31 +445,7 @@ pub async fn complete(
         let response: Response = serde_json::from_str(&body)?;
         Ok(response)
     } else {
-        let mut body = String::new();
-        response.body_mut().read_to_string(&mut body).await?;
-
-        #[derive(Deserialize)]
-        struct OpenAiResponse {
-            error: OpenAiError,
-        }
-
-        #[derive(Deserialize)]
-        struct OpenAiError {
-            message: String,
-        }
-
-        match serde_json::from_str::<OpenAiResponse>(&body) {
-            Ok(response) if !response.error.message.is_empty() => Err(anyhow!(
-                "Failed to connect to OpenAI API: {}",
-                response.error.message,
-            )),
-
-            _ => Err(anyhow!(
-                "Failed to connect to OpenAI API: {} {}",
-                response.status(),
-                body,
-            )),
-        }
+        Err(OpenAiError::from_response(response).await.into())
     }
 }
 
@@ -396,77 +484,360 @@ pub async fn stream_completion(
     client: &dyn HttpClient,
     api_url: &str,
     api_key: &str,
-    request: Request,
+    options: &ApiOptions,
+    mut request: Request,
     low_speed_timeout: Option<Duration>,
 ) -> Result<BoxStream<'static, Result<ResponseStreamEvent>>> {
+    if options.flavor != ApiFlavor::OpenAi {
+        request.stream_options = None;
+    }
     if request.model == "o1-preview" || request.model == "o1-mini" {
-        let response = complete(client, api_url, api_key, request, low_speed_timeout).await;
+        let response = complete(
+            client,
+            api_url,
+            api_key,
+            options,
+            request,
+            low_speed_timeout,
+        )
+        .await;
         let response_stream_event = response.map(adapt_response_to_stream);
         return Ok(stream::once(future::ready(response_stream_event)).boxed());
     }
 
-    let uri = format!("{api_url}/chat/completions");
-    let mut request_builder = HttpRequest::builder()
-        .method(Method::POST)
-        .uri(uri)
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key));
+    let uri = endpoint_uri(api_url, "chat/completions", options);
+    let mut request_builder = request_builder(Method::POST, uri, api_key, options)
+        .header("Content-Type", "application/json");
 
     if let Some(low_speed_timeout) = low_speed_timeout {
         request_builder = request_builder.low_speed_timeout(100, low_speed_timeout);
     };
 
     let request = request_builder.body(AsyncBody::from(serde_json::to_string(&request)?))?;
-    let mut response = client.send(request).await?;
+    let response = client
+        .send(request)
+        .await
+        .map_err(OpenAiError::Connection)?;
     if response.status().is_success() {
         let reader = BufReader::new(response.into_body());
         Ok(reader
             .lines()
             .filter_map(|line| async move {
                 match line {
-                    Ok(line) => {
-                        let line = line.strip_prefix("data: ")?;
-                        if line == "[DONE]" {
-                            None
-                        } else {
-                            match serde_json::from_str(line) {
-                                Ok(ResponseStreamResult::Ok(response)) => Some(Ok(response)),
-                                Ok(ResponseStreamResult::Err { error }) => {
-                                    Some(Err(anyhow!(error)))
-                                }
-                                Err(error) => Some(Err(anyhow!(error))),
-                            }
-                        }
-                    }
+                    Ok(line) => parse_stream_line(&line),
                     Err(error) => Some(Err(anyhow!(error))),
                 }
             })
             .boxed())
     } else {
+        Err(OpenAiError::from_response(response).await.into())
+    }
+}
+
+/// Parses a line of a server-sent event stream, returning `None` for lines that carry no event.
+///
+/// Besides the `data: {...}` lines OpenAI sends, this skips the keep-alive comments, blank
+/// lines and `data:` lines without a space that llama.cpp-style servers emit.
+fn parse_stream_line(line: &str) -> Option<Result<ResponseStreamEvent>> {
+    let data = line.strip_prefix("data:")?.trim();
+    if data.is_empty() || data == "[DONE]" {
+        return None;
+    }
+    match serde_json::from_str(data) {
+        Ok(ResponseStreamResult::Ok(response)) => Some(Ok(response)),
+        Ok(ResponseStreamResult::Err { error }) => Some(Err(anyhow!(error))),
+        Err(error) => Some(Err(anyhow!(error))),
+    }
+}
+
+#[derive(Deserialize)]
+struct ListModelsResponse {
+    data: Vec<ListedModel>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ListedModel {
+    pub id: String,
+}
+
+/// Lists the models served at `api_url`.
+///
+/// Failing to reach the server or an error response can be downcast to an [`OpenAiError`].
+pub async fn list_models(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: &str,
+    options: &ApiOptions,
+) -> Result<Vec<ListedModel>> {
+    let uri = models_uri(api_url, options);
+    let request = request_builder(Method::GET, uri, api_key, options).body(AsyncBody::default())?;
+    let mut response = client
+        .send(request)
+        .await
+        .map_err(OpenAiError::Connection)?;
+    if response.status().is_success() {
         let mut body = String::new();
         response.body_mut().read_to_string(&mut body).await?;
+        let response: ListModelsResponse =
+            serde_json::from_str(&body).context("failed to parse the model listing")?;
+        Ok(response.data)
+    } else {
+        Err(OpenAiError::from_response(response).await.into())
+    }
+}
+
+/// Checks that the API key is accepted by issuing a cheap request to list the available models.
+/// For Azure, that is the listing of the resource the deployment at `api_url` belongs to.
+pub async fn validate_api_key(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: &str,
+    options: &ApiOptions,
+) -> Result<(), OpenAiError> {
+    let uri = models_uri(api_url, options);
+    let request = request_builder(Method::GET, uri, api_key, options)
+        .body(AsyncBody::default())
+        .map_err(|error| OpenAiError::Connection(error.into()))?;
+    let response = client
+        .send(request)
+        .await
+        .map_err(OpenAiError::Connection)?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(OpenAiError::from_response(response).await)
+    }
+}
+
+/// Controls how [`retry`], [`stream_completion_with_retry`] and [`complete_with_retry`] retry
+/// transient failures.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// The maximum number of retries after the initial attempt.
+    pub max_retries: usize,
+    /// The delay before the first retry. Each subsequent retry doubles it.
+    pub initial_delay: Duration,
+    /// The upper bound for the delay between two attempts.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the delay to wait before the given (zero-based) retry attempt.
+    ///
+    /// `jitter` is expected to be in the `0.0..=1.0` range and scales the
+    /// exponential delay down by up to half, so that concurrent clients
+    /// don't retry in lockstep.
+    pub fn delay_for_attempt(&self, attempt: usize, jitter: f64) -> Duration {
+        let exponent = attempt.min(16) as u32;
+        let delay = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(exponent))
+            .min(self.max_delay);
+        delay.mul_f64(1.0 - jitter.clamp(0.0, 1.0) / 2.0)
+    }
+}
+
+/// Opens a completion stream, retrying connection errors, rate limits and
+/// transient server errors with exponential backoff.
+///
+/// Retries only happen while establishing the stream: once a response has
+/// started streaming, errors are propagated as-is so partial output is never
+/// duplicated.
+#[allow(clippy::too_many_arguments)]
+pub async fn stream_completion_with_retry<F, Fut>(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: &str,
+    options: &ApiOptions,
+    request: Request,
+    low_speed_timeout: Option<Duration>,
+    policy: RetryPolicy,
+    sleep: F,
+) -> Result<BoxStream<'static, Result<ResponseStreamEvent>>>
+where
+    F: FnMut(Duration) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    retry(policy, sleep, || {
+        stream_completion(
+            client,
+            api_url,
+            api_key,
+            options,
+            request.clone(),
+            low_speed_timeout,
+        )
+    })
+    .await
+}
+
+/// Requests a completion without streaming it, retrying failures like
+/// [`stream_completion_with_retry`] does.
+#[allow(clippy::too_many_arguments)]
+pub async fn complete_with_retry<F, Fut>(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: &str,
+    options: &ApiOptions,
+    request: Request,
+    low_speed_timeout: Option<Duration>,
+    policy: RetryPolicy,
+    sleep: F,
+) -> Result<Response>
+where
+    F: FnMut(Duration) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    retry(policy, sleep, || {
+        complete(
+            client,
+            api_url,
+            api_key,
+            options,
+            request.clone(),
+            low_speed_timeout,
+        )
+    })
+    .await
+}
+
+/// Makes attempts until one succeeds, fails with an error that isn't worth
+/// retrying, or the policy's retries run out.
+///
+/// Use this directly rather than [`stream_completion_with_retry`] or
+/// [`complete_with_retry`] when each attempt needs to do more than send the
+/// request, such as waiting for a concurrency slot that shouldn't be held
+/// while sleeping between attempts.
+pub async fn retry<T, A, AFut, F, Fut>(
+    policy: RetryPolicy,
+    mut sleep: F,
+    mut attempt: A,
+) -> Result<T>
+where
+    A: FnMut() -> AFut,
+    AFut: Future<Output = Result<T>>,
+    F: FnMut(Duration) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut retries = 0;
+    loop {
+        let error = match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(error) => error,
+        };
+
+        let Some(retry_after) = error
+            .downcast_ref::<OpenAiError>()
+            .filter(|error| error.is_retryable())
+            .map(|error| error.retry_after())
+        else {
+            return Err(error);
+        };
+        if retries >= policy.max_retries {
+            return Err(error);
+        }
+
+        let delay = retry_after
+            .map(|retry_after| retry_after.min(policy.max_delay))
+            .unwrap_or_else(|| policy.delay_for_attempt(retries, rand::random()));
+        log::warn!(
+            "OpenAI request failed, retrying in {:?} (attempt {} of {}): {}",
+            delay,
+            retries + 1,
+            policy.max_retries,
+            error
+        );
+        sleep(delay).await;
+        retries += 1;
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum OpenAiError {
+    #[error("Failed to connect to OpenAI API: {0}")]
+    Connection(anyhow::Error),
+    #[error("Failed to connect to OpenAI API: {message}")]
+    Api {
+        status: StatusCode,
+        message: String,
+        retry_after: Option<Duration>,
+    },
+}
 
+impl OpenAiError {
+    async fn from_response(mut response: HttpResponse<AsyncBody>) -> Self {
         #[derive(Deserialize)]
         struct OpenAiResponse {
-            error: OpenAiError,
+            error: OpenAiResponseError,
         }
 
         #[derive(Deserialize)]
-        struct OpenAiError {
+        struct OpenAiResponseError {
             message: String,
         }
 
-        match serde_json::from_str::<OpenAiResponse>(&body) {
-            Ok(response) if !response.error.message.is_empty() => Err(anyhow!(
-                "Failed to connect to OpenAI API: {}",
-                response.error.message,
-            )),
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get("Retry-After")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .map(Duration::from_secs);
 
-            _ => Err(anyhow!(
-                "Failed to connect to OpenAI API: {} {}",
-                response.status(),
-                body,
-            )),
+        let mut body = String::new();
+        if let Err(error) = response.body_mut().read_to_string(&mut body).await {
+            return Self::Connection(error.into());
+        }
+
+        let message = match serde_json::from_str::<OpenAiResponse>(&body) {
+            Ok(response) if !response.error.message.is_empty() => response.error.message,
+            _ => format!("{} {}", status, body),
+        };
+
+        Self::Api {
+            status,
+            message,
+            retry_after,
+        }
+    }
+
+    /// Returns the HTTP status code returned by the API, if a response was received.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Self::Connection(_) => None,
+            Self::Api { status, .. } => Some(*status),
+        }
+    }
+
+    /// Returns how long the API asked us to wait before retrying, if it did.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::Connection(_) => None,
+            Self::Api { retry_after, .. } => *retry_after,
+        }
+    }
+
+    /// Returns whether the request that produced this error can safely be retried.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Connection(_) => true,
+            Self::Api { status, .. } => matches!(
+                *status,
+                StatusCode::TOO_MANY_REQUESTS
+                    | StatusCode::INTERNAL_SERVER_ERROR
+                    | StatusCode::BAD_GATEWAY
+                    | StatusCode::SERVICE_UNAVAILABLE
+            ),
         }
     }
 }
@@ -597,3 +968,509 @@ pub fn extract_text_from_events(
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_client::FakeHttpClient;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering::SeqCst},
+        Arc, Mutex,
+    };
+
+    fn test_request() -> Request {
+        Request {
+            model: "gpt-4o".into(),
+            messages: vec![RequestMessage::User {
+                content: "Hello".into(),
+            }],
+            stream: true,
+            n: None,
+            max_tokens: None,
+            stop: Vec::new(),
+            temperature: Some(1.0),
+            top_p: None,
+            tool_choice: None,
+            tools: Vec::new(),
+            stream_options: None,
+            response_format: None,
+        }
+    }
+
+    #[test]
+    fn test_retry_after_rate_limit() {
+        let request_count = Arc::new(AtomicUsize::new(0));
+        let client = FakeHttpClient::create({
+            let request_count = request_count.clone();
+            move |_| {
+                let request_count = request_count.clone();
+                async move {
+                    if request_count.fetch_add(1, SeqCst) == 0 {
+                        Ok(HttpResponse::builder()
+                            .status(429)
+                            .header("Retry-After", "2")
+                            .body(r#"{"error":{"message":"Rate limit reached"}}"#.into())
+                            .unwrap())
+                    } else {
+                        Ok(HttpResponse::builder()
+                            .status(200)
+                            .body(
+                                concat!(
+                                    r#"data: {"created":0,"model":"gpt-4o","choices":[{"index":0,"delta":{"role":"assistant","content":"Hi"},"finish_reason":null}]}"#,
+                                    "\n\n",
+                                    "data: [DONE]\n",
+                                )
+                                .into(),
+                            )
+                            .unwrap())
+                    }
+                }
+            }
+        });
+
+        let delays = Arc::new(Mutex::new(Vec::new()));
+        let text = futures::executor::block_on(async {
+            let stream = stream_completion_with_retry(
+                &*client,
+                OPEN_AI_API_URL,
+                "sk-test",
+                &ApiOptions::default(),
+                test_request(),
+                None,
+                RetryPolicy::default(),
+                |delay| {
+                    delays.lock().unwrap().push(delay);
+                    future::ready(())
+                },
+            )
+            .await
+            .unwrap();
+            extract_text_from_events(stream)
+                .map(|chunk| chunk.unwrap())
+                .collect::<String>()
+                .await
+        });
+
+        assert_eq!(text, "Hi");
+        assert_eq!(request_count.load(SeqCst), 2);
+        assert_eq!(*delays.lock().unwrap(), vec![Duration::from_secs(2)]);
+    }
+
+    #[test]
+    fn test_retry_after_is_capped_by_max_delay() {
+        let policy = RetryPolicy {
+            max_retries: 1,
+            ..Default::default()
+        };
+        let mut attempts = 0;
+        let delays = Arc::new(Mutex::new(Vec::new()));
+        let result = futures::executor::block_on(retry(
+            policy,
+            |delay| {
+                delays.lock().unwrap().push(delay);
+                future::ready(())
+            },
+            || {
+                attempts += 1;
+                future::ready(Err::<(), _>(
+                    OpenAiError::Api {
+                        status: StatusCode::TOO_MANY_REQUESTS,
+                        message: "Rate limit reached".into(),
+                        retry_after: Some(Duration::from_secs(3600)),
+                    }
+                    .into(),
+                ))
+            },
+        ));
+
+        assert!(result.is_err());
+        assert_eq!(attempts, 2);
+        assert_eq!(*delays.lock().unwrap(), vec![policy.max_delay]);
+    }
+
+    #[test]
+    fn test_complete_retries_server_errors() {
+        let request_count = Arc::new(AtomicUsize::new(0));
+        let client = FakeHttpClient::create({
+            let request_count = request_count.clone();
+            move |_| {
+                let request_count = request_count.clone();
+                async move {
+                    if request_count.fetch_add(1, SeqCst) == 0 {
+                        Ok(HttpResponse::builder()
+                            .status(503)
+                            .body(r#"{"error":{"message":"Service unavailable"}}"#.into())
+                            .unwrap())
+                    } else {
+                        Ok(HttpResponse::builder()
+                            .status(200)
+                            .body(
+                                r#"{"id":"chatcmpl-1","object":"chat.completion","created":0,"model":"gpt-4o","choices":[{"index":0,"message":{"role":"assistant","content":"Hi"},"finish_reason":"stop"}],"usage":{"prompt_tokens":1,"completion_tokens":1,"total_tokens":2}}"#
+                                    .into(),
+                            )
+                            .unwrap())
+                    }
+                }
+            }
+        });
+
+        let delays = Arc::new(Mutex::new(Vec::new()));
+        let response = futures::executor::block_on(complete_with_retry(
+            &*client,
+            OPEN_AI_API_URL,
+            "sk-test",
+            &ApiOptions::default(),
+            test_request(),
+            None,
+            RetryPolicy::default(),
+            |delay| {
+                delays.lock().unwrap().push(delay);
+                future::ready(())
+            },
+        ))
+        .unwrap();
+
+        assert_eq!(response.choices.len(), 1);
+        assert_eq!(request_count.load(SeqCst), 2);
+        assert_eq!(delays.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_no_retry_on_invalid_api_key() {
+        let request_count = Arc::new(AtomicUsize::new(0));
+        let client = FakeHttpClient::create({
+            let request_count = request_count.clone();
+            move |_| {
+                request_count.fetch_add(1, SeqCst);
+                async move {
+                    Ok(HttpResponse::builder()
+                        .status(401)
+                        .body(r#"{"error":{"message":"Incorrect API key provided"}}"#.into())
+                        .unwrap())
+                }
+            }
+        });
+
+        let error = futures::executor::block_on(stream_completion_with_retry(
+            &*client,
+            OPEN_AI_API_URL,
+            "sk-invalid",
+            &ApiOptions::default(),
+            test_request(),
+            None,
+            RetryPolicy::default(),
+            |_| async { panic!("should not retry") },
+        ))
+        .err()
+        .unwrap();
+
+        assert_eq!(request_count.load(SeqCst), 1);
+        let error = error.downcast_ref::<OpenAiError>().unwrap();
+        assert_eq!(error.status(), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(
+            error.to_string(),
+            "Failed to connect to OpenAI API: Incorrect API key provided"
+        );
+    }
+
+    #[test]
+    fn test_validate_api_key() {
+        let client = FakeHttpClient::create(|request| async move {
+            assert_eq!(request.uri().path(), "/v1/models");
+            let authorization = request.headers()["Authorization"].to_str().unwrap();
+            match authorization {
+                "Bearer sk-valid" => Ok(HttpResponse::builder()
+                    .status(200)
+                    .body(r#"{"object":"list","data":[]}"#.into())
+                    .unwrap()),
+                "Bearer sk-offline" => Err(anyhow!("connection refused")),
+                _ => Ok(HttpResponse::builder()
+                    .status(401)
+                    .body(r#"{"error":{"message":"Incorrect API key provided"}}"#.into())
+                    .unwrap()),
+            }
+        });
+
+        futures::executor::block_on(validate_api_key(
+            &*client,
+            OPEN_AI_API_URL,
+            "sk-valid",
+            &ApiOptions::default(),
+        ))
+        .unwrap();
+
+        let error = futures::executor::block_on(validate_api_key(
+            &*client,
+            OPEN_AI_API_URL,
+            "sk-invalid",
+            &ApiOptions::default(),
+        ))
+        .unwrap_err();
+        assert_eq!(error.status(), Some(StatusCode::UNAUTHORIZED));
+
+        let error = futures::executor::block_on(validate_api_key(
+            &*client,
+            OPEN_AI_API_URL,
+            "sk-offline",
+            &ApiOptions::default(),
+        ))
+        .unwrap_err();
+        assert!(matches!(error, OpenAiError::Connection(_)));
+    }
+
+    #[test]
+    fn test_validate_azure_api_key() {
+        let client = FakeHttpClient::create(|request| async move {
+            assert_eq!(request.uri().path(), "/openai/models");
+            assert_eq!(
+                request.uri().query(),
+                Some(format!("api-version={AZURE_API_VERSION}").as_str())
+            );
+            match request.headers()["api-key"].to_str().unwrap() {
+                "azure-valid" => Ok(HttpResponse::builder()
+                    .status(200)
+                    .body(r#"{"object":"list","data":[]}"#.into())
+                    .unwrap()),
+                _ => Ok(HttpResponse::builder()
+                    .status(401)
+                    .body(r#"{"error":{"code":"401","message":"Access denied due to invalid subscription key"}}"#.into())
+                    .unwrap()),
+            }
+        });
+        let options = ApiOptions {
+            flavor: ApiFlavor::Azure,
+            ..Default::default()
+        };
+
+        futures::executor::block_on(validate_api_key(
+            &*client,
+            "https://example.openai.azure.com/openai/deployments/gpt-4o",
+            "azure-valid",
+            &options,
+        ))
+        .unwrap();
+
+        let error = futures::executor::block_on(validate_api_key(
+            &*client,
+            "https://example.openai.azure.com/openai/deployments/gpt-4o",
+            "azure-invalid",
+            &options,
+        ))
+        .unwrap_err();
+        assert_eq!(error.status(), Some(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn test_request_headers_for_each_flavor() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let client = FakeHttpClient::create({
+            let requests = requests.clone();
+            move |request| {
+                let headers = request.headers();
+                let header = |name: &str| {
+                    headers
+                        .get(name)
+                        .map(|value| value.to_str().unwrap().to_string())
+                };
+                requests.lock().unwrap().push((
+                    request.uri().to_string(),
+                    header("Authorization"),
+                    header("api-key"),
+                    header("OpenAI-Organization"),
+                    header("X-Proxy-Token"),
+                ));
+                async move {
+                    Ok(HttpResponse::builder()
+                        .status(200)
+                        .body("data: [DONE]\n".into())
+                        .unwrap())
+                }
+            }
+        });
+
+        let openai_options = ApiOptions {
+            flavor: ApiFlavor::OpenAi,
+            organization_id: Some("org-123".into()),
+            extra_headers: BTreeMap::from_iter([("X-Proxy-Token".into(), "secret".into())]),
+            api_version: Some("ignored".into()),
+        };
+        futures::executor::block_on(stream_completion(
+            &*client,
+            "https://proxy.example.com/v1",
+            "sk-test",
+            &openai_options,
+            test_request(),
+            None,
+        ))
+        .unwrap();
+
+        let azure_options = ApiOptions {
+            flavor: ApiFlavor::Azure,
+            api_version: Some("2024-10-21".into()),
+            ..Default::default()
+        };
+        futures::executor::block_on(stream_completion(
+            &*client,
+            "https://example.openai.azure.com/openai/deployments/gpt-4o",
+            "azure-key",
+            &azure_options,
+            test_request(),
+            None,
+        ))
+        .unwrap();
+
+        let local_options = ApiOptions {
+            flavor: ApiFlavor::Local,
+            ..Default::default()
+        };
+        futures::executor::block_on(stream_completion(
+            &*client,
+            "http://localhost:1234/v1",
+            "",
+            &local_options,
+            test_request(),
+            None,
+        ))
+        .unwrap();
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                (
+                    "https://proxy.example.com/v1/chat/completions".to_string(),
+                    Some("Bearer sk-test".to_string()),
+                    None,
+                    Some("org-123".to_string()),
+                    Some("secret".to_string()),
+                ),
+                (
+                    "https://example.openai.azure.com/openai/deployments/gpt-4o/chat/completions?api-version=2024-10-21"
+                        .to_string(),
+                    None,
+                    Some("azure-key".to_string()),
+                    None,
+                    None,
+                ),
+                (
+                    "http://localhost:1234/v1/chat/completions".to_string(),
+                    None,
+                    None,
+                    None,
+                    None,
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stream_options_only_sent_to_open_ai() {
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let client = FakeHttpClient::create({
+            let bodies = bodies.clone();
+            move |mut request| {
+                let bodies = bodies.clone();
+                async move {
+                    let mut body = String::new();
+                    request.body_mut().read_to_string(&mut body).await?;
+                    let body = serde_json::from_str::<serde_json::Value>(&body)?;
+                    bodies
+                        .lock()
+                        .unwrap()
+                        .push(body.get("stream_options").cloned());
+                    Ok(HttpResponse::builder()
+                        .status(200)
+                        .body("data: [DONE]\n".into())
+                        .unwrap())
+                }
+            }
+        });
+
+        for flavor in [ApiFlavor::OpenAi, ApiFlavor::Azure, ApiFlavor::Local] {
+            let request = Request {
+                stream_options: Some(StreamOptions {
+                    include_usage: true,
+                }),
+                ..test_request()
+            };
+            futures::executor::block_on(stream_completion(
+                &*client,
+                "https://example.com/v1",
+                "key",
+                &ApiOptions {
+                    flavor,
+                    ..Default::default()
+                },
+                request,
+                None,
+            ))
+            .unwrap();
+        }
+
+        assert_eq!(
+            *bodies.lock().unwrap(),
+            vec![Some(serde_json::json!({"include_usage": true})), None, None]
+        );
+    }
+
+    #[test]
+    fn test_parse_azure_stream() {
+        let client = FakeHttpClient::create(|_| async move {
+            Ok(HttpResponse::builder()
+                .status(200)
+                .body(
+                    concat!(
+                        r#"data: {"choices":[],"created":0,"id":"","model":"","object":"","prompt_filter_results":[]}"#,
+                        "\n\n",
+                        r#"data: {"choices":[{"index":0,"delta":{"role":"assistant","content":"Hi"},"finish_reason":null}],"created":1,"id":"1"}"#,
+                        "\n\n",
+                        r#"data: {"choices":[{"index":0,"finish_reason":"content_filter"}],"created":1,"id":"1"}"#,
+                        "\n\n",
+                        "data: [DONE]\n",
+                    )
+                    .into(),
+                )
+                .unwrap())
+        });
+
+        let events = futures::executor::block_on(async {
+            stream_completion(
+                &*client,
+                "https://example.openai.azure.com/openai/deployments/gpt-4o",
+                "azure-key",
+                &ApiOptions {
+                    flavor: ApiFlavor::Azure,
+                    ..Default::default()
+                },
+                test_request(),
+                None,
+            )
+            .await
+            .unwrap()
+            .map(|event| event.unwrap())
+            .collect::<Vec<_>>()
+            .await
+        });
+
+        assert_eq!(events.len(), 3);
+        assert!(events[0].choices.is_empty());
+        assert_eq!(events[1].choices[0].delta.content.as_deref(), Some("Hi"));
+        assert_eq!(
+            events[2].choices[0].finish_reason.as_deref(),
+            Some("content_filter")
+        );
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+        };
+        assert_eq!(policy.delay_for_attempt(0, 0.), Duration::from_secs(1));
+        assert_eq!(policy.delay_for_attempt(1, 0.), Duration::from_secs(2));
+        assert_eq!(policy.delay_for_attempt(2, 0.), Duration::from_secs(4));
+        assert_eq!(policy.delay_for_attempt(3, 0.), Duration::from_secs(5));
+        assert_eq!(policy.delay_for_attempt(1, 1.), Duration::from_secs(1));
+    }
+}
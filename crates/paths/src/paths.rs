@@ -108,6 +108,12 @@ pub fn old_log_file() -> &'static PathBuf {
     OLD_LOG_FILE.get_or_init(|| logs_dir().join("Zed.log.old"))
 }
 
+/// Returns the path to the assistant's debug log of language model requests.
+pub fn assistant_debug_log_file() -> &'static PathBuf {
+    static ASSISTANT_DEBUG_LOG_FILE: OnceLock<PathBuf> = OnceLock::new();
+    ASSISTANT_DEBUG_LOG_FILE.get_or_init(|| logs_dir().join("assistant_requests.ndjson"))
+}
+
 /// Returns the path to the database directory.
 pub fn database_dir() -> &'static PathBuf {
     static DATABASE_DIR: OnceLock<PathBuf> = OnceLock::new();
@@ -231,6 +237,14 @@ pub fn embeddings_dir() -> &'static PathBuf {
     })
 }
 
+/// Returns the path to the language model response cache directory.
+///
+/// This is where cached language model responses are stored when response caching is enabled.
+pub fn language_model_responses_dir() -> &'static PathBuf {
+    static LANGUAGE_MODEL_RESPONSES_DIR: OnceLock<PathBuf> = OnceLock::new();
+    LANGUAGE_MODEL_RESPONSES_DIR.get_or_init(|| support_dir().join("language_model_responses"))
+}
+
 /// Returns the path to the languages directory.
 ///
 /// This is where language servers are downloaded to for languages built-in to Zed.
@@ -559,10 +559,14 @@ impl SummaryIndex {
                 role: Role::User,
                 content: vec![prompt.into()],
                 cache: use_cache,
+                attachments: Vec::new(),
             }],
             tools: Vec::new(),
             stop: Vec::new(),
             temperature: None,
+            top_p: None,
+            max_tokens: None,
+            response_format: None,
         };
 
         let code_len = code.len();